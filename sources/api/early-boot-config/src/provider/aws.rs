@@ -80,7 +80,7 @@ impl PlatformDataProvider for AwsDataProvider {
     ) -> std::result::Result<Vec<SettingsJson>, Box<dyn std::error::Error>> {
         let mut output = Vec::new();
 
-        let mut client = ImdsClient::new().await.context(error::ImdsClient)?;
+        let mut client = ImdsClient::new();
 
         // Instance identity doc first, so the user has a chance to override
         match Self::identity_document(&mut client).await? {
@@ -115,9 +115,6 @@ mod error {
         #[snafu(display("Instance identity document missing {}", missing))]
         IdentityDocMissingData { missing: String },
 
-        #[snafu(display("IMDS client failed: {}", source))]
-        ImdsClient { source: imdsclient::Error },
-
         #[snafu(display("Unable to read input file '{}': {}", path.display(), source))]
         InputFileRead { path: PathBuf, source: io::Error },
 
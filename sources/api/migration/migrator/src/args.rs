@@ -1,27 +1,84 @@
 //! This module handles argument parsing for the migrator binary.
+//!
+//! Arguments are parsed in two steps so that the parsing can be tested: `parse_args` turns the
+//! command line into `ParsedArgs` without touching the system, rejecting unknown, duplicate, and
+//! conflicting flags, and `Mode::from_env` checks the data store and fills in defaults.
 
+use crate::metrics::DEFAULT_METRICS_PATH;
+use crate::skip;
 use bottlerocket_release::BottlerocketRelease;
 use semver::Version;
 use simplelog::LevelFilter;
 use std::env;
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 use std::process;
 use std::str::FromStr;
 
+/// Every flag that migrator accepts, used to suggest a correction for unknown flags.
+const FLAGS: &[&str] = &[
+    "--datastore-path",
+    "--expected-from-version",
+    "--force-version-mismatch",
+    "--json",
+    "--keep-intermediate",
+    "--log-level",
+    "--metadata-directory",
+    "--metrics-path",
+    "--migrate-to-version",
+    "--migrate-to-version-from-os-release",
+    "--migration-directory",
+    "--no-sandbox",
+    "--no-source-guard",
+    "--root-path",
+    "--skip-migration",
+    "--status",
+];
+
 /// Informs the user about proper usage of the program and exits.
 fn usage() -> ! {
     let program_name = env::args().next().unwrap_or_else(|| "program".to_string());
     eprintln!(
         r"Usage: {}
+            --datastore-path PATH
+            --status [ --json ]
+            [ --log-level trace|debug|info|warn|error ]
+
+       {}
             --datastore-path PATH
             --migration-directory PATH
             --root-path PATH
             --metadata-directory PATH
-            (--migrate-to-version x.y | --migrate-to-version-from-os-release)
-            [ --no-color ]
-            [ --log-level trace|debug|info|warn|error ]",
-        program_name
+            (--migrate-to-version x.y.z | --migrate-to-version-from-os-release)
+            [ --expected-from-version x.y.z [ --force-version-mismatch ] ]
+            [ --keep-intermediate ]
+            [ --no-sandbox ]
+            [ --no-source-guard ]
+            [ --skip-migration NAME ... ]
+            [ --metrics-path PATH ]
+            [ --log-level trace|debug|info|warn|error ]
+
+Options:
+    --datastore-path PATH                   the data store to migrate, e.g. the 'current' link
+    --migration-directory PATH              where the cached migration binaries are
+    --root-path PATH                        the trusted root.json of the cached TUF repository
+    --metadata-directory PATH               where the cached TUF repository metadata is
+    --migrate-to-version x.y.z              the version to migrate the data store to
+    --migrate-to-version-from-os-release    migrate to the version in /etc/os-release
+    --expected-from-version x.y.z           refuse to migrate if the data store's version differs
+                                            from this by more than the patch level
+    --force-version-mismatch                with --expected-from-version, migrate anyway
+    --keep-intermediate                     keep the data stores made by all but the last migration
+    --no-sandbox                            don't run migrations in a mount namespace sandbox
+    --no-source-guard                       don't check that migrations leave their source alone
+    --skip-migration NAME                   don't run the migration NAME; may be repeated, and
+                                            also read from migrator.skip-migration=NAME on the
+                                            kernel command line
+    --metrics-path PATH                     where to write metrics (default: {})
+    --status                                print the state of the data store's version links
+    --json                                  with --status, print the state as JSON
+    --log-level LEVEL                       trace, debug, info, warn, or error (default: info)",
+        program_name, program_name, DEFAULT_METRICS_PATH
     );
     process::exit(2);
 }
@@ -35,121 +92,490 @@ fn usage_msg<S: AsRef<str>>(msg: S) -> ! {
 /// Stores user-supplied arguments.
 pub(crate) struct Args {
     pub(crate) datastore_path: PathBuf,
+    pub(crate) expected_from_version: Option<Version>,
+    pub(crate) force_version_mismatch: bool,
+    pub(crate) keep_intermediate: bool,
     pub(crate) log_level: LevelFilter,
     pub(crate) migration_directory: PathBuf,
     pub(crate) migrate_to_version: Version,
     pub(crate) root_path: PathBuf,
     pub(crate) metadata_directory: PathBuf,
+    pub(crate) metrics_path: PathBuf,
+    pub(crate) no_sandbox: bool,
+    pub(crate) no_source_guard: bool,
+    /// The migrations to skip, from `--skip-migration`.
+    pub(crate) skip_migrations: Vec<String>,
+    /// The migrations to skip, from the kernel command line.
+    pub(crate) cmdline_skip_migrations: Vec<String>,
+}
+
+/// Stores user-supplied arguments for `--status`.
+pub(crate) struct StatusArgs {
+    pub(crate) datastore_path: PathBuf,
+    pub(crate) json: bool,
+    pub(crate) log_level: LevelFilter,
+}
+
+/// What the user asked migrator to do.
+pub(crate) enum Mode {
+    /// Migrate the data store to a new version.
+    Migrate(Args),
+    /// Print the state of the data store's version links and exit.
+    Status(StatusArgs),
+}
+
+/// Where the version to migrate to comes from.
+#[derive(Debug, PartialEq)]
+enum TargetVersion {
+    /// Given with `--migrate-to-version`.
+    Given(Version),
+    /// Read from os-release, as requested by `--migrate-to-version-from-os-release`.
+    OsRelease,
+}
+
+/// The arguments as given on the command line, before any are checked against the system.
+#[derive(Debug, Default, PartialEq)]
+struct ParsedArgs {
+    datastore_path: Option<PathBuf>,
+    expected_from_version: Option<Version>,
+    force_version_mismatch: bool,
+    json: bool,
+    keep_intermediate: bool,
+    log_level: Option<LevelFilter>,
+    metadata_directory: Option<PathBuf>,
+    metrics_path: Option<PathBuf>,
+    migration_directory: Option<PathBuf>,
+    migrate_to_version: Option<TargetVersion>,
+    no_sandbox: bool,
+    no_source_guard: bool,
+    root_path: Option<PathBuf>,
+    skip_migrations: Vec<String>,
+    status: bool,
+}
+
+/// Sets `slot` to `value`, unless `flag` was already given.
+fn set_once<T>(slot: &mut Option<T>, flag: &str, value: T) -> Result<(), String> {
+    if slot.is_some() {
+        return Err(format!("{} was given more than once", flag));
+    }
+    *slot = Some(value);
+    Ok(())
+}
+
+/// Returns the value that follows `flag`.
+fn flag_value<I>(iter: &mut I, flag: &str) -> Result<String, String>
+where
+    I: Iterator<Item = String>,
+{
+    iter.next()
+        .ok_or_else(|| format!("Did not give argument to {}", flag))
+}
+
+/// Returns the number of single-character edits needed to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + if a_char == *b_char { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Returns the known flag closest to `arg`, if it's close enough to be a likely typo.
+fn suggest_flag(arg: &str) -> Option<&'static str> {
+    FLAGS
+        .iter()
+        .map(|flag| (edit_distance(arg, flag), *flag))
+        .filter(|(distance, _)| *distance <= 3)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, flag)| flag)
+}
+
+/// Parses the arguments, not including the program name, rejecting unknown flags, single-valued
+/// flags given more than once, and conflicting flags.  Returns a message for the user on failure.
+fn parse_args<I>(args: I) -> Result<ParsedArgs, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut parsed = ParsedArgs::default();
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_ref() {
+            "--datastore-path" => {
+                let path_str = flag_value(&mut iter, &arg)?;
+                trace!("Given --datastore-path: {}", path_str);
+                set_once(&mut parsed.datastore_path, &arg, PathBuf::from(path_str))?;
+            }
+
+            "--expected-from-version" => {
+                let version_str = flag_value(&mut iter, &arg)?;
+                trace!("Given --expected-from-version: {}", version_str);
+                let version = Version::from_str(&version_str)
+                    .map_err(|e| format!("Invalid argument to --expected-from-version: {}", e))?;
+                set_once(&mut parsed.expected_from_version, &arg, version)?;
+            }
+
+            "--force-version-mismatch" => {
+                trace!("Given --force-version-mismatch");
+                parsed.force_version_mismatch = true;
+            }
+
+            "--json" => {
+                trace!("Given --json");
+                parsed.json = true;
+            }
+
+            "--keep-intermediate" => {
+                trace!("Given --keep-intermediate");
+                parsed.keep_intermediate = true;
+            }
+
+            "--log-level" => {
+                let log_level_str = flag_value(&mut iter, &arg)?;
+                let log_level = LevelFilter::from_str(&log_level_str)
+                    .map_err(|_| format!("Invalid log level '{}'", log_level_str))?;
+                set_once(&mut parsed.log_level, &arg, log_level)?;
+            }
+
+            "--migration-directory" => {
+                let path_str = flag_value(&mut iter, &arg)?;
+                trace!("Given --migration-directory: {}", path_str);
+                set_once(
+                    &mut parsed.migration_directory,
+                    &arg,
+                    PathBuf::from(path_str),
+                )?;
+            }
+
+            "--migrate-to-version" => {
+                let version_str = flag_value(&mut iter, &arg)?;
+                trace!("Given --migrate-to-version: {}", version_str);
+                let version = Version::from_str(&version_str)
+                    .map_err(|e| format!("Invalid argument to --migrate-to-version: {}", e))?;
+                set_once(
+                    &mut parsed.migrate_to_version,
+                    "--migrate-to-version or --migrate-to-version-from-os-release",
+                    TargetVersion::Given(version),
+                )?;
+            }
+
+            "--migrate-to-version-from-os-release" => {
+                trace!("Given --migrate-to-version-from-os-release");
+                set_once(
+                    &mut parsed.migrate_to_version,
+                    "--migrate-to-version or --migrate-to-version-from-os-release",
+                    TargetVersion::OsRelease,
+                )?;
+            }
+
+            "--root-path" => {
+                let path_str = flag_value(&mut iter, &arg)?;
+                trace!("Given --root-path: {}", path_str);
+                set_once(&mut parsed.root_path, &arg, PathBuf::from(path_str))?;
+            }
+
+            "--metadata-directory" => {
+                let path_str = flag_value(&mut iter, &arg)?;
+                trace!("Given --metadata-directory: {}", path_str);
+                set_once(
+                    &mut parsed.metadata_directory,
+                    &arg,
+                    PathBuf::from(path_str),
+                )?;
+            }
+
+            "--metrics-path" => {
+                let path_str = flag_value(&mut iter, &arg)?;
+                trace!("Given --metrics-path: {}", path_str);
+                set_once(&mut parsed.metrics_path, &arg, PathBuf::from(path_str))?;
+            }
+
+            "--no-sandbox" => {
+                trace!("Given --no-sandbox");
+                parsed.no_sandbox = true;
+            }
+
+            "--no-source-guard" => {
+                trace!("Given --no-source-guard");
+                parsed.no_source_guard = true;
+            }
+
+            "--skip-migration" => {
+                let name = flag_value(&mut iter, &arg)?;
+                trace!("Given --skip-migration: {}", name);
+                parsed.skip_migrations.push(name);
+            }
+
+            "--status" => {
+                trace!("Given --status");
+                parsed.status = true;
+            }
+
+            _ => {
+                return Err(match suggest_flag(&arg) {
+                    Some(flag) => format!("Unknown argument '{}'; did you mean '{}'?", arg, flag),
+                    None => format!("Unable to parse input '{}'", arg),
+                })
+            }
+        }
+    }
+
+    if parsed.json && !parsed.status {
+        return Err("--json can only be used with --status".to_string());
+    }
+    if parsed.force_version_mismatch && parsed.expected_from_version.is_none() {
+        return Err(
+            "--force-version-mismatch can only be used with --expected-from-version".to_string(),
+        );
+    }
+    Ok(parsed)
 }
 
-impl Args {
-    /// Parses user arguments into an Args structure.
+impl Mode {
+    /// Parses user arguments into the requested Mode.
     pub(crate) fn from_env(args: env::Args) -> Self {
-        // Required parameters.
-        let mut datastore_path = None;
-        let mut log_level = None;
-        let mut migration_directory = None;
-        let mut migrate_to_version = None;
-        let mut root_path = None;
-        let mut metadata_path = None;
-
-        let mut iter = args.skip(1);
-        while let Some(arg) = iter.next() {
-            match arg.as_ref() {
-                "--datastore-path" => {
-                    let path_str = iter
-                        .next()
-                        .unwrap_or_else(|| usage_msg("Did not give argument to --datastore-path"));
-                    trace!("Given --datastore-path: {}", path_str);
-
-                    // On first boot, the data store won't exist yet, because storewolf runs after.
-                    if !Path::new(&path_str).exists() {
-                        eprintln!(
-                            "Data store does not exist at given path, exiting ({})",
-                            path_str
-                        );
-                        process::exit(0);
-                    }
-
-                    let canonical = fs::canonicalize(path_str).unwrap_or_else(|e| {
-                        usage_msg(format!(
-                            "Could not canonicalize given data store path: {}",
-                            e
-                        ))
-                    });
-                    trace!("Canonicalized data store path: {}", canonical.display());
-                    datastore_path = Some(canonical);
-                }
-
-                "--log-level" => {
-                    let log_level_str = iter
-                        .next()
-                        .unwrap_or_else(|| usage_msg("Did not give argument to --log-level"));
-                    log_level = Some(LevelFilter::from_str(&log_level_str).unwrap_or_else(|_| {
-                        usage_msg(format!("Invalid log level '{}'", log_level_str))
-                    }));
-                }
-
-                "--migration-directory" => {
-                    let path_str = iter.next().unwrap_or_else(|| {
-                        usage_msg("Did not give argument to --migration-directory")
-                    });
-                    trace!("Given --migration-directory: {}", path_str);
-                    migration_directory = Some(PathBuf::from(path_str));
-                }
-
-                "--migrate-to-version" => {
-                    let version_str = iter.next().unwrap_or_else(|| {
-                        usage_msg("Did not give argument to --migrate-to-version")
-                    });
-                    trace!("Given --migrate-to-version: {}", version_str);
-                    let version = Version::from_str(&version_str).unwrap_or_else(|e| {
-                        usage_msg(format!("Invalid argument to --migrate-to-version: {}", e))
-                    });
-                    migrate_to_version = Some(version)
-                }
-
-                "--migrate-to-version-from-os-release" => {
-                    let br = BottlerocketRelease::new().unwrap_or_else(|e| {
+        let parsed = parse_args(args.skip(1)).unwrap_or_else(|msg| usage_msg(msg));
+
+        let datastore_path = parsed
+            .datastore_path
+            .unwrap_or_else(|| usage_msg("--datastore-path must be specified"));
+        let log_level = parsed.log_level.unwrap_or(LevelFilter::Info);
+
+        // The status is reported from the links themselves, so we don't resolve them here; they
+        // may be broken.
+        if parsed.status {
+            return Mode::Status(StatusArgs {
+                datastore_path,
+                json: parsed.json,
+                log_level,
+            });
+        }
+
+        // On first boot, the data store won't exist yet, because storewolf runs after.
+        if !datastore_path.exists() {
+            eprintln!(
+                "Data store does not exist at given path, exiting ({})",
+                datastore_path.display()
+            );
+            process::exit(0);
+        }
+
+        let datastore_path = fs::canonicalize(datastore_path).unwrap_or_else(|e| {
+            usage_msg(format!(
+                "Could not canonicalize given data store path: {}",
+                e
+            ))
+        });
+        trace!(
+            "Canonicalized data store path: {}",
+            datastore_path.display()
+        );
+
+        let migrate_to_version = match parsed.migrate_to_version {
+            Some(TargetVersion::Given(version)) => version,
+            Some(TargetVersion::OsRelease) => {
+                BottlerocketRelease::new()
+                    .unwrap_or_else(|e| {
                         usage_msg(format!("Unable to get version from os-release: {}", e))
-                    });
-                    migrate_to_version = Some(br.version_id)
-                }
-
-                "--root-path" => {
-                    let path_str = iter
-                        .next()
-                        .unwrap_or_else(|| usage_msg("Did not give argument to --root-path"));
-                    trace!("Given --root-path: {}", path_str);
-                    root_path = Some(PathBuf::from(path_str));
-                }
-
-                "--metadata-directory" => {
-                    let path_str = iter.next().unwrap_or_else(|| {
-                        usage_msg("Did not give argument to --metadata-directory")
-                    });
-                    trace!("Given --metadata-directory: {}", path_str);
-                    metadata_path = Some(PathBuf::from(path_str));
-                }
-                _ => usage_msg(format!("Unable to parse input '{}'", arg)),
+                    })
+                    .version_id
             }
-        }
+            None => usage_msg(
+                "Desired version could not be determined; pass --migrate-to-version or \
+                --migrate-to-version-from-os-release",
+            ),
+        };
+
+        let cmdline_skip_migrations = skip::names_from_cmdline_file(skip::KERNEL_CMDLINE)
+            .unwrap_or_else(|e| {
+                usage_msg(format!(
+                    "Unable to read kernel command line '{}': {}",
+                    skip::KERNEL_CMDLINE,
+                    e
+                ))
+            });
 
-        Self {
-            datastore_path: datastore_path
-                .unwrap_or_else(|| usage_msg("--datastore-path must be specified")),
-            log_level: log_level.unwrap_or_else(|| LevelFilter::Info),
-            migration_directory: migration_directory
+        Mode::Migrate(Args {
+            datastore_path,
+            expected_from_version: parsed.expected_from_version,
+            force_version_mismatch: parsed.force_version_mismatch,
+            keep_intermediate: parsed.keep_intermediate,
+            log_level,
+            migration_directory: parsed
+                .migration_directory
                 .unwrap_or_else(|| usage_msg("--migration-directory must be specified")),
-            migrate_to_version: migrate_to_version.unwrap_or_else(|| {
-                usage_msg(
-                    "Desired version could not be determined; pass --migrate-to-version or \
-                    --migrate-to-version-from-os-release",
-                )
-            }),
-            root_path: root_path.unwrap_or_else(|| usage_msg("--root-path must be specified")),
-            metadata_directory: metadata_path
+            migrate_to_version,
+            root_path: parsed
+                .root_path
+                .unwrap_or_else(|| usage_msg("--root-path must be specified")),
+            metadata_directory: parsed
+                .metadata_directory
                 .unwrap_or_else(|| usage_msg("--metadata-directory must be specified")),
-        }
+            metrics_path: parsed
+                .metrics_path
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_METRICS_PATH)),
+            no_sandbox: parsed.no_sandbox,
+            no_source_guard: parsed.no_source_guard,
+            skip_migrations: parsed.skip_migrations,
+            cmdline_skip_migrations,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<ParsedArgs, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn happy_path() {
+        let parsed = parse(&[
+            "--datastore-path",
+            "/var/lib/bottlerocket/datastore/current",
+            "--migration-directory",
+            "/var/lib/bottlerocket-migrations",
+            "--root-path",
+            "/usr/share/updog/root.json",
+            "--metadata-directory",
+            "/var/cache/bottlerocket-metadata",
+            "--migrate-to-version",
+            "1.2.3",
+            "--expected-from-version",
+            "1.1.0",
+            "--force-version-mismatch",
+            "--keep-intermediate",
+            "--no-sandbox",
+            "--no-source-guard",
+            "--metrics-path",
+            "/tmp/migrator.prom",
+            "--skip-migration",
+            "migrate_v1.2.0_b",
+            "--skip-migration",
+            "migrate_v1.2.0_a",
+            "--log-level",
+            "debug",
+        ])
+        .unwrap();
+        assert_eq!(
+            parsed,
+            ParsedArgs {
+                datastore_path: Some(PathBuf::from("/var/lib/bottlerocket/datastore/current")),
+                expected_from_version: Some(Version::new(1, 1, 0)),
+                force_version_mismatch: true,
+                json: false,
+                keep_intermediate: true,
+                log_level: Some(LevelFilter::Debug),
+                metadata_directory: Some(PathBuf::from("/var/cache/bottlerocket-metadata")),
+                metrics_path: Some(PathBuf::from("/tmp/migrator.prom")),
+                migration_directory: Some(PathBuf::from("/var/lib/bottlerocket-migrations")),
+                migrate_to_version: Some(TargetVersion::Given(Version::new(1, 2, 3))),
+                no_sandbox: true,
+                no_source_guard: true,
+                root_path: Some(PathBuf::from("/usr/share/updog/root.json")),
+                skip_migrations: vec![
+                    "migrate_v1.2.0_b".to_string(),
+                    "migrate_v1.2.0_a".to_string()
+                ],
+                status: false,
+            }
+        );
+    }
+
+    #[test]
+    fn version_from_os_release() {
+        let parsed = parse(&["--migrate-to-version-from-os-release"]).unwrap();
+        assert_eq!(parsed.migrate_to_version, Some(TargetVersion::OsRelease));
+    }
+
+    #[test]
+    fn status() {
+        let parsed = parse(&["--datastore-path", "/tmp/current", "--status", "--json"]).unwrap();
+        assert!(parsed.status);
+        assert!(parsed.json);
+        assert_eq!(
+            parse(&["--json"]).unwrap_err(),
+            "--json can only be used with --status"
+        );
+    }
+
+    #[test]
+    fn force_needs_expected_version() {
+        assert_eq!(
+            parse(&["--force-version-mismatch"]).unwrap_err(),
+            "--force-version-mismatch can only be used with --expected-from-version"
+        );
+    }
+
+    #[test]
+    fn duplicates() {
+        assert_eq!(
+            parse(&["--root-path", "/a", "--root-path", "/b"]).unwrap_err(),
+            "--root-path was given more than once"
+        );
+        assert_eq!(
+            parse(&["--log-level", "info", "--log-level", "debug"]).unwrap_err(),
+            "--log-level was given more than once"
+        );
+        // boolean flags can be repeated harmlessly.
+        assert!(parse(&["--keep-intermediate", "--keep-intermediate"]).is_ok());
+    }
+
+    #[test]
+    fn conflicting_versions() {
+        let err = parse(&[
+            "--migrate-to-version",
+            "1.2.3",
+            "--migrate-to-version-from-os-release",
+        ])
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "--migrate-to-version or --migrate-to-version-from-os-release was given more than once"
+        );
+    }
+
+    #[test]
+    fn missing_value() {
+        assert_eq!(
+            parse(&["--datastore-path"]).unwrap_err(),
+            "Did not give argument to --datastore-path"
+        );
+        assert_eq!(
+            parse(&["--skip-migration"]).unwrap_err(),
+            "Did not give argument to --skip-migration"
+        );
+    }
+
+    #[test]
+    fn unknown_with_suggestion() {
+        assert_eq!(
+            parse(&["--keep-intermedaite"]).unwrap_err(),
+            "Unknown argument '--keep-intermedaite'; did you mean '--keep-intermediate'?"
+        );
+        assert_eq!(
+            parse(&["--datastore_path", "/tmp/current"]).unwrap_err(),
+            "Unknown argument '--datastore_path'; did you mean '--datastore-path'?"
+        );
+        assert_eq!(
+            parse(&["--frobnicate"]).unwrap_err(),
+            "Unable to parse input '--frobnicate'"
+        );
+    }
+
+    #[test]
+    fn distances() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("--json", "--json"), 0);
+        assert_eq!(edit_distance("--jsno", "--json"), 2);
+        assert_eq!(edit_distance("--status", "--stats"), 1);
+        assert_eq!(edit_distance("abc", ""), 3);
     }
 }
@@ -4,7 +4,8 @@ use semver::Version;
 use snafu::Snafu;
 use std::io;
 use std::path::PathBuf;
-use std::process::Output;
+use std::process::{ExitStatus, Output};
+use std::time::Duration;
 
 /// Error contains the errors that can happen during migration.
 #[derive(Debug, Snafu)]
@@ -22,14 +23,31 @@ pub(crate) enum Error {
     #[snafu(display("Data store link '{}' points to /", path.display()))]
     DataStoreLinkToRoot { path: PathBuf },
 
+    #[snafu(display("Data store version links in '{}' are broken", path.display()))]
+    DataStoreUnhealthy { path: PathBuf },
+
     #[snafu(display("Unable to create URL from path '{}'", path.display()))]
     DirectoryUrl { path: PathBuf },
 
+    #[snafu(display(
+        "No TUF repository metadata in '{}'; updog must populate the migration cache before migrator can run",
+        dir.display()
+    ))]
+    EmptyRepository { dir: PathBuf },
+
     #[snafu(display("Error finding migration: {}", source))]
     FindMigrations {
         source: update_metadata::error::Error,
     },
 
+    #[snafu(display(
+        "Data store is at version {} but version {} was expected; pass --force-version-mismatch \
+        to migrate anyway",
+        current,
+        expected
+    ))]
+    FromVersionMismatch { current: Version, expected: Version },
+
     #[snafu(display("Data store path '{}' contains invalid version: {}", path.display(), source))]
     InvalidDataStoreVersion {
         path: PathBuf,
@@ -51,6 +69,12 @@ pub(crate) enum Error {
     #[snafu(display("Unable to start migration command: {}", source))]
     StartMigration { source: std::io::Error },
 
+    #[snafu(display("Interrupted by SIGTERM; the data store version links were not changed"))]
+    Interrupted,
+
+    #[snafu(display("Unable to install SIGTERM handler: {}", source))]
+    SignalHandler { source: nix::Error },
+
     #[snafu(display("Migration returned '{}' - stderr: {}",
                     output.status.code()
                         .map(|i| i.to_string()).unwrap_or_else(|| "signal".to_string()),
@@ -67,8 +91,36 @@ pub(crate) enum Error {
     #[snafu(display("Failed to read symlink at {} to find version: {}", link.display(), source))]
     LinkRead { link: PathBuf, source: io::Error },
 
-    #[snafu(display("Failed listing migration directory '{}': {}", dir.display(), source))]
-    ListMigrations { dir: PathBuf, source: io::Error },
+    #[snafu(display("Failed listing post-migration hooks in '{}': {}", dir.display(), source))]
+    ListPostHooks { dir: PathBuf, source: io::Error },
+
+    #[snafu(display("Post-migration hook '{}' returned '{}'", hook.display(), status))]
+    PostHookFailure { hook: PathBuf, status: ExitStatus },
+
+    #[snafu(display(
+        "Post-migration hook '{}' didn't finish within {:?} and was killed",
+        hook.display(),
+        timeout
+    ))]
+    PostHookTimeout { hook: PathBuf, timeout: Duration },
+
+    #[snafu(display("Unable to start post-migration hook '{}': {}", hook.display(), source))]
+    StartPostHook { hook: PathBuf, source: io::Error },
+
+    #[snafu(display("Failed waiting for post-migration hook '{}': {}", hook.display(), source))]
+    WaitPostHook { hook: PathBuf, source: io::Error },
+
+    #[snafu(display("Failed to create repository directory '{}': {}", dir.display(), source))]
+    CreateRepoDirectory { dir: PathBuf, source: io::Error },
+
+    #[snafu(display("Failed listing repository directory '{}': {}", dir.display(), source))]
+    ListRepoDirectory { dir: PathBuf, source: io::Error },
+
+    #[snafu(display("Metrics path '{}' has no parent directory or file name", path.display()))]
+    MetricsPath { path: PathBuf },
+
+    #[snafu(display("Failed to write metrics to '{}': {}", path.display(), source))]
+    MetricsWrite { path: PathBuf, source: io::Error },
 
     #[snafu(display("Error loading migration '{}': {}", migration, source))]
     LoadMigration {
@@ -96,14 +148,41 @@ pub(crate) enum Error {
     #[snafu(display("Migration '{}' not found", migration))]
     MigrationNotFound { migration: String },
 
+    #[snafu(display(
+        "Migration '{}' is larger than the limit of {} bytes compressed",
+        migration,
+        max
+    ))]
+    MigrationTooLarge { migration: String, max: u64 },
+
+    #[snafu(display(
+        "Migration '{}' is larger than the limit of {} bytes decompressed",
+        migration,
+        max
+    ))]
+    MigrationDecompressedTooLarge { migration: String, max: u64 },
+
+    #[snafu(display(
+        "Manifest lists {} migrations for this update, more than the limit of {}",
+        count,
+        max
+    ))]
+    TooManyMigrations { count: usize, max: usize },
+
+    #[snafu(display(
+        "Asked to skip migrations that aren't in the plan: {}",
+        names.join(", ")
+    ))]
+    SkipMigrationNotInPlan { names: Vec<String> },
+
     #[snafu(display("Failed to open trusted root metadata file {}: {}", path.display(), source))]
     OpenRoot {
         path: PathBuf,
         source: std::io::Error,
     },
 
-    #[snafu(display("Failed reading migration directory entry: {}", source))]
-    ReadMigrationEntry { source: io::Error },
+    #[snafu(display("Failed reading entry of repository directory '{}': {}", dir.display(), source))]
+    ReadRepoDirectoryEntry { dir: PathBuf, source: io::Error },
 
     #[snafu(display("Failed to load TUF repo: {}", source))]
     RepoLoad { source: tough::error::Error },
@@ -111,11 +190,52 @@ pub(crate) enum Error {
     #[snafu(display("Failed reading metadata of '{}': {}", path.display(), source))]
     PathMetadata { path: PathBuf, source: io::Error },
 
+    #[snafu(display("Unable to create pipe for sandbox setup: {}", source))]
+    SandboxReportPipe { source: nix::Error },
+
+    #[snafu(display(
+        "Migrations can only be sandboxed when migrator runs as root; pass --no-sandbox to run them unsandboxed"
+    ))]
+    SandboxRequiresRoot,
+
+    #[snafu(display("Unable to create sandbox directory '{}': {}", path.display(), source))]
+    SandboxRoot { path: PathBuf, source: io::Error },
+
+    #[snafu(display(
+        "Unable to set up the sandbox for migration '{}', failed to {}: {}",
+        migration,
+        step,
+        source
+    ))]
+    SandboxSetup {
+        migration: String,
+        step: String,
+        source: io::Error,
+    },
+
+    #[snafu(display("Unable to create target data store '{}': {}", path.display(), source))]
+    SandboxTarget { path: PathBuf, source: io::Error },
+
     #[snafu(display("Failed setting permissions of '{}': {}", path.display(), source))]
     SetPermissions { path: PathBuf, source: io::Error },
 
     #[snafu(display("Migration path '{}' contains invalid UTF-8", path.display()))]
     MigrationNameNotUTF8 { path: PathBuf },
+
+    #[snafu(display(
+        "Migration '{}' modified its source data store '{}': {}",
+        migration,
+        datastore.display(),
+        paths.iter().map(|p| p.display().to_string()).collect::<Vec<_>>().join(", ")
+    ))]
+    SourceDatastoreModified {
+        migration: String,
+        datastore: PathBuf,
+        paths: Vec<PathBuf>,
+    },
+
+    #[snafu(display("Failed reading source data store at '{}': {}", path.display(), source))]
+    SourceDatastoreRead { path: PathBuf, source: io::Error },
 }
 
 /// Result alias containing our Error type.
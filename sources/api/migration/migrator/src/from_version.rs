@@ -0,0 +1,68 @@
+//! This module checks the version of the data store against the version the caller expected to
+//! migrate from, given with `--expected-from-version`.  If the `current` link was pointed at the
+//! wrong data store, e.g. by a half-finished earlier run, migrator would otherwise pick the wrong
+//! migrations and run them without complaint.  Patch releases don't change the data store's
+//! layout, so versions that differ only in the patch level agree.
+
+use crate::error::{self, Result};
+use semver::Version;
+use snafu::ensure;
+
+/// Returns whether `current` and `expected` differ by no more than the patch level.
+fn agree(current: &Version, expected: &Version) -> bool {
+    current.major == expected.major && current.minor == expected.minor
+}
+
+/// Checks that the data store's `current` version agrees with the `expected` one.  If they don't,
+/// returns a `FromVersionMismatch` error, unless `force` is set, in which case it only warns.
+pub(crate) fn check(current: &Version, expected: &Version, force: bool) -> Result<()> {
+    if agree(current, expected) {
+        return Ok(());
+    }
+    ensure!(
+        force,
+        error::FromVersionMismatch {
+            current: current.clone(),
+            expected: expected.clone(),
+        }
+    );
+    warn!(
+        "Data store is at version {} but version {} was expected; migrating anyway because of \
+        --force-version-mismatch",
+        current, expected
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::Error;
+
+    #[test]
+    fn versions_agree() {
+        let expected = Version::new(1, 2, 3);
+        assert!(check(&Version::new(1, 2, 3), &expected, false).is_ok());
+        // patch levels may differ.
+        assert!(check(&Version::new(1, 2, 0), &expected, false).is_ok());
+        assert!(check(&Version::new(1, 2, 9), &expected, false).is_ok());
+    }
+
+    #[test]
+    fn minor_disagrees() {
+        let result = check(&Version::new(1, 3, 0), &Version::new(1, 2, 3), false);
+        match result {
+            Err(Error::FromVersionMismatch { current, expected }) => {
+                assert_eq!(current, Version::new(1, 3, 0));
+                assert_eq!(expected, Version::new(1, 2, 3));
+            }
+            _ => panic!("expected FromVersionMismatch, got {:?}", result),
+        }
+        assert!(check(&Version::new(2, 2, 3), &Version::new(1, 2, 3), false).is_err());
+    }
+
+    #[test]
+    fn forced() {
+        assert!(check(&Version::new(1, 3, 0), &Version::new(1, 2, 3), true).is_ok());
+    }
+}
@@ -0,0 +1,219 @@
+//! This module handles SIGTERM, which systemd sends if the migrator unit times out.  Rather than
+//! dying and leaving a migration running detached, migrator forwards the signal to the running
+//! migration's process group, gives it a few seconds to exit, kills it if it hasn't, and then stops
+//! without flipping any version links, so the data store is left on the old version.  Post-migration
+//! hooks, which run after the links are flipped, are stopped the same way.
+
+use crate::error::{self, Result};
+use nix::errno::Errno;
+use nix::libc::c_int;
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd::{setpgid, Pid};
+use snafu::{ensure, ResultExt};
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The exit code when migrator stops because of SIGTERM: 128 plus the signal number, as a shell
+/// reports it.
+pub(crate) const EXIT_CODE: i32 = 128 + Signal::SIGTERM as i32;
+
+/// How long a migration has to exit after it's sent SIGTERM before it's killed.
+const GRACE_PERIOD: Duration = Duration::from_secs(5);
+/// How often the running migration is checked for an interrupt.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Set by the SIGTERM handler.
+static SIGTERM_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sigterm(_: c_int) {
+    SIGTERM_RECEIVED.store(true, Ordering::SeqCst);
+}
+
+/// A flag that tells migrator to stop.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Interrupt {
+    flag: &'static AtomicBool,
+}
+
+impl Interrupt {
+    /// Installs the SIGTERM handler, and returns an `Interrupt` that's set when SIGTERM arrives.
+    pub(crate) fn on_sigterm() -> Result<Self> {
+        let action = SigAction::new(
+            SigHandler::Handler(handle_sigterm),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        // Safe because the handler only stores to an atomic, which is async-signal-safe.
+        unsafe { signal::sigaction(Signal::SIGTERM, &action) }.context(error::SignalHandler)?;
+        Ok(Self {
+            flag: &SIGTERM_RECEIVED,
+        })
+    }
+
+    /// Returns an `Interrupt` that's only set by calling `set`, so tests don't share the flag.
+    #[cfg(test)]
+    pub(crate) fn new() -> Self {
+        Self {
+            flag: Box::leak(Box::new(AtomicBool::new(false))),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn set(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn is_set(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+
+    /// Returns an `Interrupted` error if the flag is set.
+    pub(crate) fn check(&self) -> Result<()> {
+        ensure!(!self.is_set(), error::Interrupted);
+        Ok(())
+    }
+}
+
+/// Spawns `command` as the leader of its own process group, so that it and anything it starts can
+/// be signaled together, and returns the child and the ID of the group.
+pub(crate) fn spawn_in_own_group(command: &mut Command) -> io::Result<(Child, Pid)> {
+    // Safe because setpgid is async-signal-safe and doesn't touch any state of ours.
+    unsafe {
+        command.pre_exec(|| {
+            setpgid(Pid::from_raw(0), Pid::from_raw(0)).map_err(|e| {
+                io::Error::from_raw_os_error(e.as_errno().unwrap_or(Errno::UnknownErrno) as i32)
+            })
+        });
+    }
+    let child = command.spawn()?;
+    // The child leads its own process group, so the group ID is its PID.
+    let pgid = Pid::from_raw(child.id() as i32);
+    Ok((child, pgid))
+}
+
+/// Forwards an interrupt to a process group from a background thread, until it's finished.
+pub(crate) struct Forwarder {
+    done: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl Forwarder {
+    /// Starts watching for `interrupt`.  Once it's set, the process group `pgid` is sent SIGTERM,
+    /// and SIGKILL if it's still running after the grace period.
+    pub(crate) fn start(interrupt: Interrupt, pgid: Pid) -> Self {
+        let done = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let done = Arc::clone(&done);
+            thread::spawn(move || forward_interrupt(interrupt, &done, pgid))
+        };
+        Self { done, thread }
+    }
+
+    /// Stops watching, once the leader of the process group has exited.
+    pub(crate) fn finish(self) {
+        self.done.store(true, Ordering::SeqCst);
+        // The forwarder doesn't panic, and if it did, the group has already exited anyway.
+        let _ = self.thread.join();
+    }
+}
+
+/// Runs `command` and collects its output like `Command::output`, but in its own process group.
+/// If `interrupt` is set while the command runs, the process group is sent SIGTERM, and SIGKILL if
+/// it's still running after the grace period; an `Interrupted` error is returned once it exits.
+pub(crate) fn output(command: &mut Command, interrupt: Interrupt) -> Result<Output> {
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let (child, pgid) = spawn_in_own_group(command).context(error::StartMigration)?;
+
+    let forwarder = Forwarder::start(interrupt, pgid);
+    let output = child.wait_with_output().context(error::StartMigration);
+    forwarder.finish();
+
+    let output = output?;
+    interrupt.check()?;
+    Ok(output)
+}
+
+/// Waits until the command is `done` or `interrupt` is set.  In the latter case, sends SIGTERM to
+/// the process group `pgid`, and SIGKILL if the command isn't done after the grace period.
+fn forward_interrupt(interrupt: Interrupt, done: &AtomicBool, pgid: Pid) {
+    while !interrupt.is_set() {
+        if done.load(Ordering::SeqCst) {
+            return;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    warn!("Received SIGTERM, stopping process group {}", pgid);
+    if let Err(e) = signal::killpg(pgid, Signal::SIGTERM) {
+        warn!("Unable to send SIGTERM to process group {}: {}", pgid, e);
+    }
+    let deadline = Instant::now() + GRACE_PERIOD;
+    while Instant::now() < deadline {
+        if done.load(Ordering::SeqCst) {
+            return;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    warn!(
+        "Process group {} didn't exit within {} seconds of SIGTERM, killing it",
+        pgid,
+        GRACE_PERIOD.as_secs()
+    );
+    if let Err(e) = signal::killpg(pgid, Signal::SIGKILL) {
+        warn!("Unable to send SIGKILL to process group {}: {}", pgid, e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn uninterrupted() {
+        let output = output(Command::new("echo").arg("hi"), Interrupt::new()).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"hi\n");
+    }
+
+    #[test]
+    fn interrupt_stops_command() {
+        let interrupt = Interrupt::new();
+        let setter = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            interrupt.set();
+        });
+        let started = Instant::now();
+        let result = output(Command::new("sleep").arg("60"), interrupt);
+        setter.join().unwrap();
+        assert!(matches!(result, Err(error::Error::Interrupted)));
+        // sleep exits on SIGTERM, so the grace period shouldn't be needed.
+        assert!(started.elapsed() < GRACE_PERIOD);
+    }
+
+    #[test]
+    fn interrupt_kills_command_that_ignores_sigterm() {
+        let interrupt = Interrupt::new();
+        // give the shell time to ignore SIGTERM before it's sent.
+        let setter = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(500));
+            interrupt.set();
+        });
+        let started = Instant::now();
+        let result = output(
+            Command::new("sh").args(&["-c", "trap '' TERM; sleep 60"]),
+            interrupt,
+        );
+        setter.join().unwrap();
+        assert!(matches!(result, Err(error::Error::Interrupted)));
+        assert!(started.elapsed() >= GRACE_PERIOD);
+        assert!(started.elapsed() < Duration::from_secs(30));
+    }
+}
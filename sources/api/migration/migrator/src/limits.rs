@@ -0,0 +1,120 @@
+//! This module bounds what the manifest and the migration targets can make migrator do.  Targets
+//! are signed, but a compromised targets role could still list a huge number of migrations, or
+//! huge migrations, to exhaust memory while they're decompressed and sealed, so migrator refuses
+//! any transition or migration that's larger than is reasonable.
+
+use std::io::{self, Read};
+
+/// The most migrations migrator runs for one transition between versions.
+pub(crate) const MAX_MIGRATIONS: usize = 256;
+/// The largest LZ4-compressed migration migrator reads, in bytes.
+pub(crate) const MAX_COMPRESSED_SIZE: u64 = 32 * 1024 * 1024;
+/// The largest migration binary migrator seals, after decompression, in bytes.
+pub(crate) const MAX_DECOMPRESSED_SIZE: u64 = 128 * 1024 * 1024;
+
+/// The limits that apply to a run of migrator.  Tests use smaller limits than the defaults.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Limits {
+    pub(crate) max_migrations: usize,
+    pub(crate) max_compressed_size: u64,
+    pub(crate) max_decompressed_size: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_migrations: MAX_MIGRATIONS,
+            max_compressed_size: MAX_COMPRESSED_SIZE,
+            max_decompressed_size: MAX_DECOMPRESSED_SIZE,
+        }
+    }
+}
+
+/// A reader that fails once more than `limit` bytes have been read from `inner`, and remembers
+/// that it did, so the caller can tell the failure apart from others.
+pub(crate) struct LimitedReader<R> {
+    inner: R,
+    limit: u64,
+    remaining: u64,
+    exceeded: bool,
+}
+
+impl<R: Read> LimitedReader<R> {
+    pub(crate) fn new(inner: R, limit: u64) -> Self {
+        Self {
+            inner,
+            limit,
+            remaining: limit,
+            exceeded: false,
+        }
+    }
+
+    pub(crate) fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Returns whether more than `limit` bytes were available.
+    pub(crate) fn exceeded(&self) -> bool {
+        self.exceeded
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.exceeded {
+            return Err(self.limit_error());
+        }
+        // Ask for one byte more than the limit allows, so that reading exactly `limit` bytes
+        // succeeds and anything more is noticed.
+        let max = buf.len().min(self.remaining.saturating_add(1) as usize);
+        let count = self.inner.read(&mut buf[..max])?;
+        if count as u64 > self.remaining {
+            self.exceeded = true;
+            return Err(self.limit_error());
+        }
+        self.remaining -= count as u64;
+        Ok(count)
+    }
+}
+
+impl<R> LimitedReader<R> {
+    fn limit_error(&self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!("more than {} bytes", self.limit),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_all(data: &[u8], limit: u64) -> (io::Result<Vec<u8>>, bool) {
+        let mut reader = LimitedReader::new(data, limit);
+        let mut out = Vec::new();
+        let result = reader.read_to_end(&mut out).map(|_| out);
+        (result, reader.exceeded())
+    }
+
+    #[test]
+    fn within_limit() {
+        let (result, exceeded) = read_all(b"migration", 100);
+        assert_eq!(result.unwrap(), b"migration");
+        assert!(!exceeded);
+    }
+
+    #[test]
+    fn exactly_at_limit() {
+        let (result, exceeded) = read_all(b"migration", 9);
+        assert_eq!(result.unwrap(), b"migration");
+        assert!(!exceeded);
+    }
+
+    #[test]
+    fn over_limit() {
+        let (result, exceeded) = read_all(b"migration", 8);
+        assert!(result.is_err());
+        assert!(exceeded);
+    }
+}
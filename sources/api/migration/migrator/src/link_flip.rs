@@ -0,0 +1,415 @@
+//! This module flips the data store's version links to a new version.  Each link is replaced by
+//! creating a symlink at a temporary path and renaming it over the link, which is atomic.
+//!
+//! Some storage stacks return transient errors like EBUSY or EIO for these calls, so each call is
+//! retried a few times with backoff.  If a flip still fails, the links that were already flipped
+//! are pointed back at their previous targets, recorded before each flip, so the chain never mixes
+//! the old and new versions.
+
+use crate::error::{self, Result};
+use nix::errno::Errno;
+use snafu::ResultExt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// How many times an operation is attempted before a transient error is treated as permanent.
+const MAX_ATTEMPTS: u32 = 4;
+/// How long to wait before the first retry; the wait doubles with each retry.
+pub(crate) const INITIAL_BACKOFF: Duration = Duration::from_millis(50);
+
+/// The filesystem operations used to flip links, so that tests can inject failures.
+pub(crate) trait LinkOps {
+    fn read_link(&self, link: &Path) -> io::Result<PathBuf>;
+    fn symlink(&self, target: &Path, link: &Path) -> io::Result<()>;
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn remove(&self, link: &Path) -> io::Result<()>;
+}
+
+/// Flips links on the real filesystem.
+pub(crate) struct RealFs;
+
+impl LinkOps for RealFs {
+    fn read_link(&self, link: &Path) -> io::Result<PathBuf> {
+        fs::read_link(link)
+    }
+
+    fn symlink(&self, target: &Path, link: &Path) -> io::Result<()> {
+        symlink(target, link)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove(&self, link: &Path) -> io::Result<()> {
+        fs::remove_file(link)
+    }
+}
+
+/// Returns true for errors that the storage stack may return briefly and that are worth retrying.
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error().map(Errno::from_i32),
+        Some(Errno::EBUSY) | Some(Errno::EIO) | Some(Errno::EAGAIN) | Some(Errno::EINTR)
+    )
+}
+
+/// Runs `op` until it succeeds, fails with an error that isn't transient, or has been attempted
+/// `MAX_ATTEMPTS` times, waiting `backoff` before the first retry and twice as long before each
+/// later one.  `what` describes the operation in log messages.
+pub(crate) fn retry<T, F>(what: &str, backoff: Duration, mut op: F) -> io::Result<T>
+where
+    F: FnMut() -> io::Result<T>,
+{
+    let mut delay = backoff;
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Err(e) if attempt < MAX_ATTEMPTS && is_transient(&e) => {
+                warn!(
+                    "{} failed on attempt {} of {}, retrying: {}",
+                    what, attempt, MAX_ATTEMPTS, e
+                );
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// The links that have been flipped, and what each pointed to before, so the flips can be undone.
+#[derive(Debug, Default)]
+pub(crate) struct Rollback {
+    /// Each flipped link and its previous target, oldest first; `None` if the link didn't exist.
+    flipped: Vec<(PathBuf, Option<PathBuf>)>,
+}
+
+impl Rollback {
+    /// Records that `link` was flipped away from `previous`.
+    pub(crate) fn record(&mut self, link: PathBuf, previous: Option<PathBuf>) {
+        self.flipped.push((link, previous));
+    }
+
+    /// Restores the recorded links, newest first, by pointing each back at its previous target or
+    /// removing it if it didn't exist before.  Restoring continues past failures so that as much of
+    /// the chain as possible is restored; returns the links that couldn't be restored.
+    pub(crate) fn restore<O: LinkOps>(
+        &self,
+        ops: &O,
+        temp_link: &Path,
+        backoff: Duration,
+    ) -> Vec<(PathBuf, io::Error)> {
+        let mut failures = Vec::new();
+        for (link, previous) in self.flipped.iter().rev() {
+            let result = match previous {
+                Some(target) => {
+                    info!(
+                        "Rolling back {} to point to {}",
+                        link.display(),
+                        target.display()
+                    );
+                    replace_link(ops, temp_link, target, link, backoff).map_err(|(_, e)| e)
+                }
+                None => {
+                    info!("Rolling back {} by removing it", link.display());
+                    retry("Removing link", backoff, || ops.remove(link))
+                }
+            };
+            if let Err(e) = result {
+                failures.push((link.clone(), e));
+            }
+        }
+        failures
+    }
+}
+
+/// The step of a flip that failed.
+#[derive(Debug, PartialEq)]
+enum Step {
+    Create,
+    Swap,
+}
+
+/// Atomically points `link` at `target`, by creating a symlink at `temp_link` and renaming it over
+/// `link`.  Returns the step that failed on error.
+fn replace_link<O: LinkOps>(
+    ops: &O,
+    temp_link: &Path,
+    target: &Path,
+    link: &Path,
+    backoff: Duration,
+) -> std::result::Result<(), (Step, io::Error)> {
+    retry("Creating link", backoff, || {
+        // an earlier attempt may have created the link before failing.
+        match ops.remove(temp_link) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        ops.symlink(target, temp_link)
+    })
+    .map_err(|e| (Step::Create, e))?;
+    retry("Swapping link", backoff, || ops.rename(temp_link, link)).map_err(|e| {
+        // don't leave the temporary link behind; it's harmless if this fails.
+        let _ = ops.remove(temp_link);
+        (Step::Swap, e)
+    })
+}
+
+/// Flips each link in `flips`, given as `(link, target)` in order, using `temp_link` as the
+/// temporary path.  If a flip fails, the links already flipped are restored before the error is
+/// returned.
+pub(crate) fn flip_links<O: LinkOps>(
+    ops: &O,
+    temp_link: &Path,
+    flips: &[(PathBuf, PathBuf)],
+    backoff: Duration,
+) -> Result<()> {
+    let mut rollback = Rollback::default();
+    for (link, target) in flips {
+        info!(
+            "Flipping {} to point to {}",
+            link.display(),
+            target.display()
+        );
+        let previous = match ops.read_link(link) {
+            Ok(previous) => Some(previous),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => {
+                roll_back(&rollback, ops, temp_link, backoff);
+                return Err(e).context(error::LinkRead { link });
+            }
+        };
+        if let Err((step, e)) = replace_link(ops, temp_link, target, link, backoff) {
+            roll_back(&rollback, ops, temp_link, backoff);
+            return match step {
+                Step::Create => Err(e).context(error::LinkCreate { path: temp_link }),
+                Step::Swap => Err(e).context(error::LinkSwap { link }),
+            };
+        }
+        rollback.record(link.clone(), previous);
+    }
+    Ok(())
+}
+
+/// Restores the links in `rollback`, logging any that couldn't be restored.
+fn roll_back<O: LinkOps>(rollback: &Rollback, ops: &O, temp_link: &Path, backoff: Duration) {
+    for (link, e) in rollback.restore(ops, temp_link, backoff) {
+        error!(
+            "Failed to roll back {}, the version links may be inconsistent: {}",
+            link.display(),
+            e
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::collections::BTreeMap;
+    use tempfile::TempDir;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Op {
+        Symlink,
+        Rename,
+    }
+
+    /// Fails `op` on `path` with `errno` the next `failures` times, and otherwise acts on the real
+    /// filesystem.  For symlinks, `path` is the target; for renames, it's the destination.
+    struct FailingFs {
+        op: Op,
+        path: PathBuf,
+        errno: Errno,
+        failures: Cell<u32>,
+        attempts: RefCell<Vec<(Op, PathBuf)>>,
+    }
+
+    impl FailingFs {
+        fn new(op: Op, path: PathBuf, errno: Errno, failures: u32) -> Self {
+            Self {
+                op,
+                path,
+                errno,
+                failures: Cell::new(failures),
+                attempts: RefCell::new(Vec::new()),
+            }
+        }
+
+        fn inject(&self, op: Op, path: &Path) -> io::Result<()> {
+            self.attempts.borrow_mut().push((op, path.to_path_buf()));
+            if op == self.op && path == self.path && self.failures.get() > 0 {
+                self.failures.set(self.failures.get() - 1);
+                return Err(io::Error::from_raw_os_error(self.errno as i32));
+            }
+            Ok(())
+        }
+
+        fn attempts(&self, op: Op, path: &Path) -> usize {
+            self.attempts
+                .borrow()
+                .iter()
+                .filter(|(o, p)| *o == op && p == path)
+                .count()
+        }
+    }
+
+    impl LinkOps for FailingFs {
+        fn read_link(&self, link: &Path) -> io::Result<PathBuf> {
+            RealFs.read_link(link)
+        }
+
+        fn symlink(&self, target: &Path, link: &Path) -> io::Result<()> {
+            self.inject(Op::Symlink, target)?;
+            RealFs.symlink(target, link)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            self.inject(Op::Rename, to)?;
+            RealFs.rename(from, to)
+        }
+
+        fn remove(&self, link: &Path) -> io::Result<()> {
+            RealFs.remove(link)
+        }
+    }
+
+    /// Creates a data store directory whose links point to 1.0.0.
+    fn datastore() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("v1.0.0_aaaa")).unwrap();
+        fs::create_dir(dir.path().join("v1.1.0_bbbb")).unwrap();
+        for (link, target) in &[
+            ("v1.0.0", "v1.0.0_aaaa"),
+            ("v1.0", "v1.0.0"),
+            ("v1", "v1.0"),
+            ("current", "v1"),
+        ] {
+            symlink(target, dir.path().join(link)).unwrap();
+        }
+        dir
+    }
+
+    /// The flips that migrator makes to move the data store to 1.1.0.
+    fn flips(dir: &Path) -> Vec<(PathBuf, PathBuf)> {
+        vec![
+            (dir.join("v1.1.0"), PathBuf::from("v1.1.0_bbbb")),
+            (dir.join("v1.1"), PathBuf::from("v1.1.0")),
+            (dir.join("v1"), PathBuf::from("v1.1")),
+            (dir.join("current"), PathBuf::from("v1")),
+        ]
+    }
+
+    /// Returns every link in `dir` and its target.
+    fn links(dir: &Path) -> BTreeMap<String, PathBuf> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| fs::symlink_metadata(path).unwrap().file_type().is_symlink())
+            .map(|path| {
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                (name, fs::read_link(&path).unwrap())
+            })
+            .collect()
+    }
+
+    fn run(ops: &FailingFs, dir: &Path) -> Result<()> {
+        flip_links(
+            ops,
+            &dir.join("temp"),
+            &flips(dir),
+            Duration::from_millis(0),
+        )
+    }
+
+    #[test]
+    fn flip_all() {
+        let dir = datastore();
+        let ops = FailingFs::new(Op::Rename, PathBuf::new(), Errno::EIO, 0);
+        run(&ops, dir.path()).unwrap();
+        let links = links(dir.path());
+        assert_eq!(links["v1.1.0"], PathBuf::from("v1.1.0_bbbb"));
+        assert_eq!(links["v1.1"], PathBuf::from("v1.1.0"));
+        assert_eq!(links["v1"], PathBuf::from("v1.1"));
+        assert_eq!(links["current"], PathBuf::from("v1"));
+        assert!(!links.contains_key("temp"));
+    }
+
+    #[test]
+    fn transient_failures_are_retried() {
+        let dir = datastore();
+        let v1 = dir.path().join("v1");
+        let ops = FailingFs::new(Op::Rename, v1.clone(), Errno::EBUSY, 2);
+        run(&ops, dir.path()).unwrap();
+        assert_eq!(ops.attempts(Op::Rename, &v1), 3);
+        assert_eq!(links(dir.path())["v1"], PathBuf::from("v1.1"));
+    }
+
+    #[test]
+    fn permanent_errors_are_not_retried() {
+        let dir = datastore();
+        let before = links(dir.path());
+        let target = PathBuf::from("v1.1");
+        let ops = FailingFs::new(Op::Symlink, target.clone(), Errno::EACCES, 1);
+        assert!(matches!(
+            run(&ops, dir.path()),
+            Err(error::Error::LinkCreate { .. })
+        ));
+        assert_eq!(ops.attempts(Op::Symlink, &target), 1);
+        assert_eq!(links(dir.path()), before);
+    }
+
+    /// A failure at any stage leaves the links as they were before the flip.
+    #[test]
+    fn failure_at_each_stage_rolls_back() {
+        for (link, target) in flips(Path::new("")) {
+            for op in &[Op::Symlink, Op::Rename] {
+                let dir = datastore();
+                let before = links(dir.path());
+                let path = match op {
+                    Op::Symlink => target.clone(),
+                    Op::Rename => dir.path().join(&link),
+                };
+                // more failures than attempts, so the retries give up.
+                let ops = FailingFs::new(*op, path.clone(), Errno::EIO, MAX_ATTEMPTS + 1);
+                let result = run(&ops, dir.path());
+                match op {
+                    Op::Symlink => assert!(matches!(result, Err(error::Error::LinkCreate { .. }))),
+                    Op::Rename => assert!(matches!(result, Err(error::Error::LinkSwap { .. }))),
+                }
+                assert_eq!(ops.attempts(*op, &path), MAX_ATTEMPTS as usize);
+                assert_eq!(
+                    links(dir.path()),
+                    before,
+                    "{:?} of {} failed",
+                    op,
+                    link.display()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rollback_restores_newest_first() {
+        let dir = datastore();
+        let before = links(dir.path());
+        let temp = dir.path().join("temp");
+        let backoff = Duration::from_millis(0);
+        let mut rollback = Rollback::default();
+        for (link, target) in flips(dir.path()).into_iter().take(3) {
+            let previous = fs::read_link(&link).ok();
+            replace_link(&RealFs, &temp, &target, &link, backoff).unwrap();
+            rollback.record(link, previous);
+        }
+        assert_eq!(links(dir.path())["v1"], PathBuf::from("v1.1"));
+
+        assert!(rollback.restore(&RealFs, &temp, backoff).is_empty());
+        assert_eq!(links(dir.path()), before);
+    }
+}
@@ -15,6 +15,64 @@
 //!   * just symlink to the old data store
 //! * do symlink flips so the new version takes the place of the original
 //!
+//! Transient errors like EBUSY or EIO while flipping the symlinks or syncing the directory are
+//! retried with backoff.  If a flip still fails, the links already flipped are pointed back at
+//! their previous targets, so the data store is never left on a mix of versions.
+//!
+//! Each migration runs in its own process group.  If migrator gets SIGTERM, e.g. because systemd
+//! timed out the unit, it forwards the signal to the running migration, kills it if it hasn't
+//! exited after a few seconds, and exits with code 143 without flipping any links.  The metrics
+//! record the run as interrupted.
+//!
+//! To bound what a compromised manifest can make it do, migrator refuses to run more than 256
+//! migrations for one update, or any migration larger than 32 MiB compressed or 128 MiB
+//! decompressed.  The sizes are enforced while each migration is read, before it's sealed.
+//!
+//! Intermediate data stores, i.e. the output of every migration except the last, are removed once
+//! all migrations succeed.  Pass `--keep-intermediate` to retain them for debugging.
+//!
+//! Migrations must not modify their source data store.  migrator checks the contents of the source
+//! before and after each migration and fails if they differ.  Pass `--no-source-guard` to skip this
+//! check.
+//!
+//! Each migration runs in a sandbox: a mount namespace of its own where only its source data store,
+//! read-only, its target data store, an empty `/tmp`, `/dev/null`, `/dev/zero`, `/dev/urandom`,
+//! and the read-only system directories needed to execute it, like `/usr`, are visible.  This keeps migrations from reading or changing
+//! anything else, like `/etc`, by mistake.  Setting up the sandbox requires root; pass
+//! `--no-sandbox` to run migrations without it, e.g. for debugging.
+//!
+//! The metadata and migration directories of the locally cached TUF repository are created if
+//! they're missing, which can happen on the first boot after some factory-reset flows.  migrator
+//! still fails if there's no repository metadata, because updog must populate the cache first.
+//!
+//! After each run, the outcome is written as Prometheus metrics to
+//! `/var/lib/metrics/migrator.prom`, or the path given with `--metrics-path`, for node-exporter's
+//! textfile collector: the time of the run, whether it succeeded, how many migrations completed or
+//! were skipped, and how long it took.
+//!
+//! Pass `--expected-from-version` with the version you expect the data store to be at, and
+//! migrator refuses to migrate if the version of the `current` link differs from it by more than
+//! the patch level, printing both.  Pass `--force-version-mismatch` to migrate anyway.
+//!
+//! `--status` prints each link in the data store's version chain (current, major, minor, patch),
+//! whether it's valid, the data store directory it resolves to, and the detected version, then
+//! exits without loading the TUF repository.  Add `--json` for machine-readable output.  The exit
+//! code is non-zero if any link is broken.
+//!
+//! After the links are flipped, the executables in `/usr/libexec/migrator/post-hooks.d` are run
+//! in order of their names, each with the versions migrated from and to as arguments, so variants
+//! can regenerate files derived from the data store before the first boot that uses it.  Each hook
+//! is killed if it runs for more than a minute.  Failed hooks are logged and skipped, except those
+//! named with a `.required` suffix, which fail the migration; the links stay flipped.
+//!
+//! Pass `--skip-migration NAME`, or `migrator.skip-migration=NAME` on the kernel command line, to
+//! leave the migration NAME out of the run, e.g. because it's known to be harmful for your
+//! configuration.  Either can be repeated, and the kernel parameter also takes a comma-separated
+//! list.  Names must match exactly.  Naming a migration that isn't part of the update with
+//! `--skip-migration` is an error; on the kernel command line, it's logged and ignored, so a
+//! parameter left in place doesn't fail later updates.  Skipped migrations are logged as warnings
+//! and counted in the metrics; the data store may need manual follow-up.
+//!
 //! To understand motivation and more about the overall process, look at the migration system
 //! documentation, one level up.
 
@@ -23,18 +81,23 @@
 #[macro_use]
 extern crate log;
 
-use args::Args;
+use args::{Args, Mode as RunMode};
 use direction::Direction;
 use error::Result;
+use interrupt::Interrupt;
+use limits::{LimitedReader, Limits};
+use metrics::RunMetrics;
 use nix::{dir::Dir, fcntl::OFlag, sys::stat::Mode, unistd::fsync};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use sandbox::Sandbox;
 use semver::Version;
 use simplelog::{Config as LogConfig, SimpleLogger};
 use snafu::{ensure, OptionExt, ResultExt};
+use source_guard::Snapshot;
 use std::collections::HashSet;
 use std::env;
 use std::fs::{self, File};
-use std::os::unix::fs::symlink;
+use std::io;
 use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::process;
@@ -45,6 +108,16 @@ use url::Url;
 mod args;
 mod direction;
 mod error;
+mod from_version;
+mod interrupt;
+mod limits;
+mod link_flip;
+mod metrics;
+mod post_hooks;
+mod sandbox;
+mod skip;
+mod source_guard;
+mod status;
 #[cfg(test)]
 mod test;
 
@@ -52,18 +125,51 @@ mod test;
 // we have nice Display representations of the error, so we wrap "main" (run) and print any error.
 // https://github.com/shepmaster/snafu/issues/110
 fn main() {
-    let args = Args::from_env(env::args());
+    let mode = RunMode::from_env(env::args());
+    let log_level = match &mode {
+        RunMode::Migrate(args) => args.log_level,
+        RunMode::Status(args) => args.log_level,
+    };
     // SimpleLogger will send errors to stderr and anything less to stdout.
-    if let Err(e) = SimpleLogger::init(args.log_level, LogConfig::default()) {
+    if let Err(e) = SimpleLogger::init(log_level, LogConfig::default()) {
         eprintln!("{}", e);
         process::exit(1);
     }
-    if let Err(e) = run(&args) {
+    let result = match &mode {
+        RunMode::Migrate(args) => {
+            let mut metrics = RunMetrics::start(&args.migrate_to_version);
+            let result = Interrupt::on_sigterm()
+                .and_then(|interrupt| run(args, &mut metrics, interrupt, Limits::default()));
+            metrics.interrupted = matches!(result, Err(error::Error::Interrupted));
+            metrics.finish(result.is_ok());
+            if let Err(e) = metrics.write(&args.metrics_path) {
+                warn!("{}", e);
+            }
+            result
+        }
+        RunMode::Status(args) => status::run(args),
+    };
+    if let Err(e) = result {
         eprintln!("{}", e);
-        process::exit(1);
+        process::exit(match e {
+            error::Error::Interrupted => interrupt::EXIT_CODE,
+            _ => 1,
+        });
     }
 }
 
+/// Reads the data store version link at `link`, returning the path it points to relative to
+/// `datastore_dir`.
+pub(crate) fn read_datastore_link<P1, P2>(datastore_dir: P1, link: P2) -> Result<PathBuf>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let link = link.as_ref();
+    let target = fs::read_link(link).context(error::LinkRead { link })?;
+    Ok(datastore_dir.as_ref().join(target))
+}
+
 fn get_current_version<P>(datastore_dir: P) -> Result<Version>
 where
     P: AsRef<Path>,
@@ -72,28 +178,37 @@ where
 
     // Find the current patch version link, which contains our full version number
     let current = datastore_dir.join("current");
-    let major =
-        datastore_dir.join(fs::read_link(&current).context(error::LinkRead { link: current })?);
-    let minor = datastore_dir.join(fs::read_link(&major).context(error::LinkRead { link: major })?);
-    let patch = datastore_dir.join(fs::read_link(&minor).context(error::LinkRead { link: minor })?);
+    let major = read_datastore_link(datastore_dir, current)?;
+    let minor = read_datastore_link(datastore_dir, major)?;
+    let patch = read_datastore_link(datastore_dir, minor)?;
+    version_from_link(&patch)
+}
 
+/// Parses the version from the name of the patch version link at `patch`, e.g. `v1.2.3`.
+pub(crate) fn version_from_link(patch: &Path) -> Result<Version> {
     // Pull out the basename of the path, which contains the version
     let version_os_str = patch
         .file_name()
-        .context(error::DataStoreLinkToRoot { path: &patch })?;
+        .context(error::DataStoreLinkToRoot { path: patch })?;
     let mut version_str = version_os_str
         .to_str()
-        .context(error::DataStorePathNotUTF8 { path: &patch })?;
+        .context(error::DataStorePathNotUTF8 { path: patch })?;
 
     // Allow 'v' at the start so the links have clearer names for humans
     if version_str.starts_with('v') {
         version_str = &version_str[1..];
     }
 
-    Version::parse(version_str).context(error::InvalidDataStoreVersion { path: &patch })
+    Version::parse(version_str).context(error::InvalidDataStoreVersion { path: patch })
 }
 
-pub(crate) fn run(args: &Args) -> Result<()> {
+/// Migrates the data store, recording the outcome in `metrics` as it goes.
+pub(crate) fn run(
+    args: &Args,
+    metrics: &mut RunMetrics,
+    interrupt: Interrupt,
+    limits: Limits,
+) -> Result<()> {
     // Get the directory we're working in.
     let datastore_dir = args
         .datastore_path
@@ -103,15 +218,32 @@ pub(crate) fn run(args: &Args) -> Result<()> {
         })?;
 
     let current_version = get_current_version(&datastore_dir)?;
-    let direction = Direction::from_versions(&current_version, &args.migrate_to_version)
-        .unwrap_or_else(|| {
+    metrics.from_version = Some(current_version.clone());
+    if let Some(expected) = &args.expected_from_version {
+        from_version::check(&current_version, expected, args.force_version_mismatch)?;
+    }
+    let direction = match Direction::from_versions(&current_version, &args.migrate_to_version) {
+        Some(direction) => direction,
+        None => {
             info!(
                 "Requested version {} matches version of given datastore at '{}'; nothing to do",
                 args.migrate_to_version,
                 args.datastore_path.display()
             );
-            process::exit(0);
-        });
+            return Ok(());
+        }
+    };
+
+    // Make sure the repository directories exist so we can give a clear error if updog hasn't
+    // populated them, rather than failing to load the repository.
+    let metadata_empty = prepare_repo_directory(&args.metadata_directory)?;
+    prepare_repo_directory(&args.migration_directory)?;
+    ensure!(
+        !metadata_empty,
+        error::EmptyRepository {
+            dir: &args.metadata_directory
+        }
+    );
 
     // create URLs from the metadata and targets directory paths
     let metadata_base_url = Url::from_directory_path(&args.metadata_directory).map_err(|_| {
@@ -159,12 +291,37 @@ pub(crate) fn run(args: &Args) -> Result<()> {
     let migrations =
         update_metadata::find_migrations(&current_version, &args.migrate_to_version, &manifest)
             .context(error::FindMigrations)?;
+    let (migrations, skipped) = skip::skip_migrations(
+        migrations,
+        &args.skip_migrations,
+        &args.cmdline_skip_migrations,
+    )?;
+    if !skipped.is_empty() {
+        warn!(
+            "SKIPPING {} migration(s) as requested: {}",
+            skipped.len(),
+            skipped.join(", ")
+        );
+        warn!(
+            "The data store at version {} may need manual follow-up for the skipped migrations",
+            args.migrate_to_version
+        );
+        metrics.migrations_skipped = skipped.len();
+    }
+    ensure!(
+        migrations.len() <= limits.max_migrations,
+        error::TooManyMigrations {
+            count: migrations.len(),
+            max: limits.max_migrations,
+        }
+    );
 
     if migrations.is_empty() {
         // Not all new OS versions need to change the data store format.  If there's been no
         // change, we can just link to the last version rather than making a copy.
         // (Note: we link to the fully resolved directory, args.datastore_path,  so we don't
         // have a chain of symlinks that could go past the maximum depth.)
+        interrupt.check()?;
         flip_to_new_version(&args.migrate_to_version, &args.datastore_path)?;
     } else {
         let copy_path = run_migrations(
@@ -173,12 +330,44 @@ pub(crate) fn run(args: &Args) -> Result<()> {
             &migrations,
             &args.datastore_path,
             &args.migrate_to_version,
+            args.keep_intermediate,
+            !args.no_sandbox,
+            !args.no_source_guard,
+            &mut metrics.migrations_run,
+            interrupt,
+            limits,
         )?;
+        // If we were interrupted after the last migration finished, the data store is complete,
+        // but we still stop, so that the links only change when the whole run finishes in time.
+        interrupt.check()?;
         flip_to_new_version(&args.migrate_to_version, &copy_path)?;
     }
+
+    // Now that the new data store is live, let the variant update anything derived from it.
+    post_hooks::run_post_hooks(
+        post_hooks::POST_HOOKS_DIR,
+        &current_version,
+        &args.migrate_to_version,
+        post_hooks::HOOK_TIMEOUT,
+        interrupt,
+    )?;
     Ok(())
 }
 
+/// Creates the repository directory `dir` if it doesn't exist, and returns whether it's empty.
+fn prepare_repo_directory(dir: &Path) -> Result<bool> {
+    if !dir.exists() {
+        info!("Creating missing repository directory {}", dir.display());
+        fs::create_dir_all(dir).context(error::CreateRepoDirectory { dir })?;
+    }
+    let mut entries = fs::read_dir(dir).context(error::ListRepoDirectory { dir })?;
+    let first_entry = entries
+        .next()
+        .transpose()
+        .context(error::ReadRepoDirectoryEntry { dir })?;
+    Ok(first_entry.is_none())
+}
+
 // =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
 /// Generates a random ID, affectionately known as a 'rando', that can be used to avoid timing
@@ -219,13 +408,27 @@ where
 /// migration so it knows which direction we're migrating.
 ///
 /// The given data store is used as a starting point; each migration is given the output of the
-/// previous migration, and the final output becomes the new data store.
+/// previous migration, and the final output becomes the new data store.  Intermediate data stores
+/// are removed at the end unless `keep_intermediate` is true.  If `sandbox` is true, each
+/// migration runs in a mount namespace where only its data stores are visible.  If `source_guard`
+/// is true, each migration's source data store is checked to make sure the migration didn't modify
+/// it.
+/// `migrations_run` is incremented as each migration completes.  If `interrupt` is set, the
+/// running migration is stopped and no more are started.  Migrations larger than `limits` allow
+/// aren't run.
+#[allow(clippy::too_many_arguments)]
 fn run_migrations<P, S>(
     repository: &tough::Repository,
     direction: Direction,
     migrations: &[S],
     source_datastore: P,
     new_version: &Version,
+    keep_intermediate: bool,
+    sandbox: bool,
+    source_guard: bool,
+    migrations_run: &mut usize,
+    interrupt: Interrupt,
+    limits: Limits,
 ) -> Result<PathBuf>
 where
     P: AsRef<Path>,
@@ -242,6 +445,7 @@ where
     let mut intermediate_datastores = HashSet::new();
 
     for migration in migrations {
+        interrupt.check()?;
         let migration = migration.as_ref();
         // get the migration from the repo
         let lz4_bytes = repository
@@ -249,12 +453,29 @@ where
             .context(error::LoadMigration { migration })?
             .context(error::MigrationNotFound { migration })?;
 
-        // Add an LZ4 decoder so the bytes will be deflated on read
-        let mut reader = lz4::Decoder::new(lz4_bytes).context(error::Lz4Decode { migration })?;
+        // Add an LZ4 decoder so the bytes will be deflated on read, limiting both the compressed
+        // and decompressed sizes so that a huge migration can't exhaust memory.
+        let compressed = LimitedReader::new(lz4_bytes, limits.max_compressed_size);
+        let decoder = lz4::Decoder::new(compressed).context(error::Lz4Decode { migration })?;
+        let mut reader = LimitedReader::new(decoder, limits.max_decompressed_size);
 
         // Create a sealed command with pentacle, so we can run the verified bytes from memory
-        let mut command =
-            pentacle::SealedCommand::new(&mut reader).context(error::SealMigration)?;
+        let sealed = pentacle::SealedCommand::new(&mut reader);
+        ensure!(
+            !reader.get_ref().reader().exceeded(),
+            error::MigrationTooLarge {
+                migration,
+                max: limits.max_compressed_size,
+            }
+        );
+        ensure!(
+            !reader.exceeded(),
+            error::MigrationDecompressedTooLarge {
+                migration,
+                max: limits.max_decompressed_size,
+            }
+        );
+        let mut command = sealed.context(error::SealMigration)?;
 
         // Point each migration in the right direction, and at the given data store.
         command.arg(direction.to_string());
@@ -263,6 +484,14 @@ where
             source_datastore.display().to_string(),
         ]);
 
+        // Record the state of the source so we can confirm the migration didn't change it.
+        let source_path = source_datastore.to_path_buf();
+        let source_snapshot = if source_guard {
+            Some(Snapshot::new(&source_path)?)
+        } else {
+            None
+        };
+
         // Create a new output location for this migration.
         target_datastore = new_datastore_location(&source_datastore, &new_version)?;
         intermediate_datastores.insert(target_datastore.clone());
@@ -272,9 +501,24 @@ where
             target_datastore.display().to_string(),
         ]);
 
+        // Only show the migration its data stores, unless we were asked not to.
+        let mut sandbox = if sandbox {
+            let sandbox = Sandbox::new(&source_path, &target_datastore)?;
+            sandbox.apply(&mut command);
+            Some(sandbox)
+        } else {
+            None
+        };
+
         info!("Running migration command: {:?}", command);
 
-        let output = command.output().context(error::StartMigration)?;
+        // Run the migration in its own process group, so that if we get SIGTERM, it can be
+        // stopped along with anything it started.
+        let output = interrupt::output(&mut command, interrupt);
+        let output = match sandbox.as_mut() {
+            Some(sandbox) => sandbox.check_setup(output, migration)?,
+            None => output?,
+        };
 
         if !output.stdout.is_empty() {
             debug!(
@@ -293,11 +537,46 @@ where
         }
 
         ensure!(output.status.success(), error::MigrationFailure { output });
+
+        if let Some(before) = source_snapshot {
+            let after = Snapshot::new(&source_path)?;
+            let changed_paths = before.changed_paths(&after);
+            ensure!(
+                changed_paths.is_empty(),
+                error::SourceDatastoreModified {
+                    migration,
+                    datastore: source_path,
+                    paths: changed_paths,
+                }
+            );
+        }
+
+        *migrations_run += 1;
         source_datastore = &target_datastore;
     }
 
-    // Remove the intermediate data stores
     intermediate_datastores.remove(&target_datastore);
+
+    // If requested, leave the intermediate data stores in place so they can be inspected.
+    if keep_intermediate {
+        let mut retained: Vec<_> = intermediate_datastores.into_iter().collect();
+        retained.sort();
+        for intermediate_datastore in &retained {
+            info!(
+                "Keeping intermediate data store at {}",
+                intermediate_datastore.display()
+            );
+        }
+        if !retained.is_empty() {
+            info!(
+                "Intermediate data stores are not cleaned up automatically; remove them with \
+                'rm -rf' when you're done inspecting them"
+            );
+        }
+        return Ok(target_datastore);
+    }
+
+    // Remove the intermediate data stores
     for intermediate_datastore in intermediate_datastores {
         // Even if we fail to remove an intermediate data store, we've still migrated
         // successfully, and we don't want to fail the upgrade - just let someone know for
@@ -396,78 +675,42 @@ where
 
     // =^..^=   =^..^=   =^..^=   =^..^=
 
-    info!(
-        "Flipping {} to point to {}",
-        patch_version_link.display(),
-        to_target.to_string_lossy(),
-    );
-
-    // Create a symlink from the patch version to the new data store.  We create it at a temporary
-    // path so we can atomically swap it into the real path with a rename call.
-    // This will point at, for example, /path/to/datastore/v1.5.2_0123456789abcdef
-    symlink(&to_target, &temp_link).context(error::LinkCreate { path: &temp_link })?;
-    // Atomically swap the link into place, so that the patch version link points to the new data
-    // store copy.
-    fs::rename(&temp_link, &patch_version_link).context(error::LinkSwap {
-        link: &patch_version_link,
-    })?;
-
-    // =^..^=   =^..^=   =^..^=   =^..^=
-
-    info!(
-        "Flipping {} to point to {}",
-        minor_version_link.display(),
-        patch_target.to_string_lossy(),
-    );
-
-    // Create a symlink from the minor version to the new patch version.
-    // This will point at, for example, /path/to/datastore/v1.5.2
-    symlink(&patch_target, &temp_link).context(error::LinkCreate { path: &temp_link })?;
-    // Atomically swap the link into place, so that the minor version link points to the new patch
-    // version.
-    fs::rename(&temp_link, &minor_version_link).context(error::LinkSwap {
-        link: &minor_version_link,
-    })?;
-
-    // =^..^=   =^..^=   =^..^=   =^..^=
-
-    info!(
-        "Flipping {} to point to {}",
-        major_version_link.display(),
-        minor_target.to_string_lossy(),
-    );
-
-    // Create a symlink from the major version to the new minor version.
-    // This will point at, for example, /path/to/datastore/v1.5
-    symlink(&minor_target, &temp_link).context(error::LinkCreate { path: &temp_link })?;
-    // Atomically swap the link into place, so that the major version link points to the new minor
-    // version.
-    fs::rename(&temp_link, &major_version_link).context(error::LinkSwap {
-        link: &major_version_link,
-    })?;
-
-    // =^..^=   =^..^=   =^..^=   =^..^=
-
-    info!(
-        "Flipping {} to point to {}",
-        current_version_link.display(),
-        major_target.to_string_lossy(),
-    );
-
-    // Create a symlink from 'current' to the new major version.
-    // This will point at, for example, /path/to/datastore/v1
-    symlink(&major_target, &temp_link).context(error::LinkCreate { path: &temp_link })?;
-    // Atomically swap the link into place, so that 'current' points to the new major version.
-    fs::rename(&temp_link, &current_version_link).context(error::LinkSwap {
-        link: &current_version_link,
-    })?;
+    // Flip each link by creating a symlink at a temporary path and atomically renaming it into
+    // place, from the most specific link to 'current'.  Transient failures are retried, and if a
+    // flip still fails, the links already flipped are restored so the chain stays consistent.
+    let flips = [
+        // This will point at, for example, /path/to/datastore/v1.5.2_0123456789abcdef
+        (patch_version_link.clone(), PathBuf::from(to_target)),
+        // This will point at, for example, /path/to/datastore/v1.5.2
+        (minor_version_link.clone(), PathBuf::from(patch_target)),
+        // This will point at, for example, /path/to/datastore/v1.5
+        (major_version_link.clone(), PathBuf::from(minor_target)),
+        // This will point at, for example, /path/to/datastore/v1
+        (current_version_link, PathBuf::from(major_target)),
+    ];
+    link_flip::flip_links(
+        &link_flip::RealFs,
+        &temp_link,
+        &flips,
+        link_flip::INITIAL_BACKOFF,
+    )?;
 
     // =^..^=   =^..^=   =^..^=   =^..^=
 
     // fsync the directory so the links point to the new version even if we crash right after
-    // this.  If fsync fails, warn but continue, because we likely can't swap the links back
-    // without hitting the same failure.
-    fsync(raw_dir.as_raw_fd()).unwrap_or_else(|e| {
+    // this.  Transient failures are retried; if fsync still fails, warn but continue, because we
+    // likely can't swap the links back without hitting the same failure.
+    link_flip::retry(
+        "fsync of data store directory",
+        link_flip::INITIAL_BACKOFF,
+        || {
+            fsync(raw_dir.as_raw_fd()).map_err(|e| match e.as_errno() {
+                Some(errno) => io::Error::from_raw_os_error(errno as i32),
+                None => io::Error::new(io::ErrorKind::Other, e),
+            })
+        },
+    )
+    .unwrap_or_else(|e| {
         warn!(
             "fsync of data store directory '{}' failed, update may disappear if we crash now: {}",
             to_dir.display(),
@@ -0,0 +1,275 @@
+//! This module writes the outcome of a migration run as Prometheus metrics, in the text exposition
+//! format read by node-exporter's textfile collector.
+//!
+//! The file is written to a temporary path in the same directory and renamed into place, so the
+//! collector never reads a partial file; it only reads files ending in `.prom`.
+
+use crate::error::{self, Result};
+use semver::Version;
+use snafu::{OptionExt, ResultExt};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Where the metrics are written unless `--metrics-path` is given.
+pub(crate) const DEFAULT_METRICS_PATH: &str = "/var/lib/metrics/migrator.prom";
+
+/// The outcome of a migration run.
+#[derive(Debug)]
+pub(crate) struct RunMetrics {
+    /// When the run started, in seconds since the epoch.
+    timestamp: u64,
+    started: Instant,
+    /// How long the run took; set by `finish`.
+    duration: Duration,
+    success: bool,
+    /// Whether the run was stopped by SIGTERM.
+    pub(crate) interrupted: bool,
+    /// The version of the data store we migrated from, once it's known.
+    pub(crate) from_version: Option<Version>,
+    /// The version we were asked to migrate to.
+    to_version: Version,
+    /// The number of migrations that completed successfully.
+    pub(crate) migrations_run: usize,
+    /// The number of migrations skipped because of `--skip-migration`.
+    pub(crate) migrations_skipped: usize,
+}
+
+impl RunMetrics {
+    /// Starts timing a run that migrates to `to_version`.
+    pub(crate) fn start(to_version: &Version) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        Self {
+            timestamp,
+            started: Instant::now(),
+            duration: Duration::default(),
+            success: false,
+            interrupted: false,
+            from_version: None,
+            to_version: to_version.clone(),
+            migrations_run: 0,
+            migrations_skipped: 0,
+        }
+    }
+
+    /// Records the end of the run and whether it succeeded.
+    pub(crate) fn finish(&mut self, success: bool) {
+        self.duration = self.started.elapsed();
+        self.success = success;
+    }
+
+    /// Returns the metrics in the text exposition format.
+    fn render(&self) -> String {
+        let mut labels = Vec::new();
+        let from_version = self.from_version.as_ref().map(|v| v.to_string());
+        if let Some(from_version) = &from_version {
+            labels.push(("from_version", from_version.as_str()));
+        }
+        let to_version = self.to_version.to_string();
+        labels.push(("to_version", to_version.as_str()));
+
+        let mut out = String::new();
+        write_gauge(
+            &mut out,
+            "bottlerocket_migration_last_run_timestamp",
+            "When migrator last ran, in seconds since the epoch.",
+            &[],
+            self.timestamp as f64,
+        );
+        write_gauge(
+            &mut out,
+            "bottlerocket_migration_last_run_success",
+            "Whether the last migrator run succeeded (1) or failed (0).",
+            &labels,
+            if self.success { 1.0 } else { 0.0 },
+        );
+        write_gauge(
+            &mut out,
+            "bottlerocket_migration_last_run_interrupted",
+            "Whether the last migrator run was stopped by SIGTERM (1) or not (0).",
+            &[],
+            if self.interrupted { 1.0 } else { 0.0 },
+        );
+        write_gauge(
+            &mut out,
+            "bottlerocket_migrations_run_total",
+            "The number of migrations that completed in the last migrator run.",
+            &[],
+            self.migrations_run as f64,
+        );
+        write_gauge(
+            &mut out,
+            "bottlerocket_migrations_skipped_total",
+            "The number of migrations skipped by request in the last migrator run.",
+            &[],
+            self.migrations_skipped as f64,
+        );
+        write_gauge(
+            &mut out,
+            "bottlerocket_migration_duration_seconds",
+            "How long the last migrator run took, in seconds.",
+            &[],
+            self.duration.as_secs_f64(),
+        );
+        out
+    }
+
+    /// Atomically writes the metrics to `path`, creating its directory if needed.
+    pub(crate) fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let parent = path.parent().context(error::MetricsPath { path })?;
+        let file_name = path.file_name().context(error::MetricsPath { path })?;
+        fs::create_dir_all(parent).context(error::MetricsWrite { path: parent })?;
+
+        // The collector ignores files that don't end in `.prom`, so it skips the temporary file.
+        let mut temp_name = file_name.to_os_string();
+        temp_name.push(".tmp");
+        let temp_path = parent.join(temp_name);
+        fs::write(&temp_path, self.render()).context(error::MetricsWrite { path: &temp_path })?;
+        fs::rename(&temp_path, path).context(error::MetricsWrite { path })
+    }
+}
+
+/// Appends a gauge with a single sample to `out`.
+fn write_gauge(out: &mut String, name: &str, help: &str, labels: &[(&str, &str)], value: f64) {
+    // Writing to a String can't fail.
+    let _ = writeln!(out, "# HELP {} {}", name, escape_help(help));
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    out.push_str(name);
+    if !labels.is_empty() {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(label, value)| format!("{}=\"{}\"", label, escape_label_value(value)))
+            .collect();
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(out, " {}", format_value(value));
+}
+
+/// Escapes backslashes and newlines, as required in HELP lines.
+fn escape_help(help: &str) -> String {
+    help.replace('\\', r"\\").replace('\n', r"\n")
+}
+
+/// Escapes backslashes, double quotes, and newlines, as required in label values.
+fn escape_label_value(value: &str) -> String {
+    escape_help(value).replace('"', "\\\"")
+}
+
+/// Formats a sample value; the exposition format spells infinities differently than Rust.
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn run_metrics(success: bool) -> RunMetrics {
+        RunMetrics {
+            timestamp: 1_600_000_000,
+            started: Instant::now(),
+            duration: Duration::from_millis(1500),
+            success,
+            interrupted: false,
+            from_version: Some(Version::new(1, 0, 5)),
+            to_version: Version::new(1, 1, 0),
+            migrations_run: 2,
+            migrations_skipped: 1,
+        }
+    }
+
+    #[test]
+    fn render() {
+        assert_eq!(
+            run_metrics(true).render(),
+            r#"# HELP bottlerocket_migration_last_run_timestamp When migrator last ran, in seconds since the epoch.
+# TYPE bottlerocket_migration_last_run_timestamp gauge
+bottlerocket_migration_last_run_timestamp 1600000000
+# HELP bottlerocket_migration_last_run_success Whether the last migrator run succeeded (1) or failed (0).
+# TYPE bottlerocket_migration_last_run_success gauge
+bottlerocket_migration_last_run_success{from_version="1.0.5",to_version="1.1.0"} 1
+# HELP bottlerocket_migration_last_run_interrupted Whether the last migrator run was stopped by SIGTERM (1) or not (0).
+# TYPE bottlerocket_migration_last_run_interrupted gauge
+bottlerocket_migration_last_run_interrupted 0
+# HELP bottlerocket_migrations_run_total The number of migrations that completed in the last migrator run.
+# TYPE bottlerocket_migrations_run_total gauge
+bottlerocket_migrations_run_total 2
+# HELP bottlerocket_migrations_skipped_total The number of migrations skipped by request in the last migrator run.
+# TYPE bottlerocket_migrations_skipped_total gauge
+bottlerocket_migrations_skipped_total 1
+# HELP bottlerocket_migration_duration_seconds How long the last migrator run took, in seconds.
+# TYPE bottlerocket_migration_duration_seconds gauge
+bottlerocket_migration_duration_seconds 1.5
+"#
+        );
+    }
+
+    #[test]
+    fn render_failure_without_from_version() {
+        let mut metrics = run_metrics(false);
+        metrics.from_version = None;
+        assert!(metrics
+            .render()
+            .contains("\nbottlerocket_migration_last_run_success{to_version=\"1.1.0\"} 0\n"));
+    }
+
+    #[test]
+    fn render_interrupted() {
+        let mut metrics = run_metrics(false);
+        metrics.interrupted = true;
+        assert!(metrics
+            .render()
+            .contains("\nbottlerocket_migration_last_run_interrupted 1\n"));
+    }
+
+    #[test]
+    fn escaping() {
+        let mut out = String::new();
+        write_gauge(
+            &mut out,
+            "test_metric",
+            "back\\slash and\nnewline",
+            &[("label", "a \"quoted\"\\path\nhere")],
+            0.25,
+        );
+        assert_eq!(
+            out,
+            r#"# HELP test_metric back\\slash and\nnewline
+# TYPE test_metric gauge
+test_metric{label="a \"quoted\"\\path\nhere"} 0.25
+"#
+        );
+    }
+
+    #[test]
+    fn special_values() {
+        assert_eq!(format_value(f64::NAN), "NaN");
+        assert_eq!(format_value(f64::INFINITY), "+Inf");
+        assert_eq!(format_value(f64::NEG_INFINITY), "-Inf");
+        assert_eq!(format_value(-3.0), "-3");
+    }
+
+    #[test]
+    fn write_replaces_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("metrics").join("migrator.prom");
+        run_metrics(false).write(&path).unwrap();
+        let metrics = run_metrics(true);
+        metrics.write(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), metrics.render());
+        // only the final file is left behind.
+        assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,310 @@
+//! This module runs the post-migration hooks: executables in `/usr/libexec/migrator/post-hooks.d`
+//! that variants can ship to regenerate files derived from the data store, like rendered config
+//! caches, as soon as the data store has been migrated, rather than on the first boot that uses it.
+//!
+//! Hooks run in the order of their file names, after the version links are flipped, and each is
+//! given the version migrated from and the version migrated to as arguments.  Each hook has a fixed
+//! amount of time to finish before it's killed.  A hook that fails or times out is logged and the
+//! next one is run, unless its name ends with `.required`, in which case migrator stops and fails.
+//! If migrator gets SIGTERM, the running hook is stopped like a migration would be, and no more
+//! hooks are run.  The links stay flipped either way, since the data store itself was migrated
+//! successfully.
+
+use crate::error::{self, Result};
+use crate::interrupt::{self, Forwarder, Interrupt};
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use semver::Version;
+use snafu::{ensure, ResultExt};
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Where the post-migration hooks are found.
+pub(crate) const POST_HOOKS_DIR: &str = "/usr/libexec/migrator/post-hooks.d";
+/// How long each hook has to finish before it's killed.
+pub(crate) const HOOK_TIMEOUT: Duration = Duration::from_secs(60);
+/// Hooks whose names end with this must succeed for the migration to succeed.
+const REQUIRED_SUFFIX: &str = ".required";
+/// How often a running hook is checked for having exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// An executable in the hooks directory.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Hook {
+    pub(crate) path: PathBuf,
+    /// Whether a failure of the hook fails the migration.
+    pub(crate) required: bool,
+}
+
+/// Returns the hooks in `dir`, sorted by file name.  Entries that aren't executable files, like
+/// READMEs or directories, are skipped.  There are no hooks if `dir` doesn't exist.
+pub(crate) fn find_hooks<P: AsRef<Path>>(dir: P) -> Result<Vec<Hook>> {
+    let dir = dir.as_ref();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context(error::ListPostHooks { dir }),
+    };
+
+    let mut hooks = Vec::new();
+    for entry in entries {
+        let path = entry.context(error::ListPostHooks { dir })?.path();
+        // Follow symlinks, so hooks can be linked in from elsewhere.
+        let metadata = fs::metadata(&path).context(error::PathMetadata { path: &path })?;
+        if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+            debug!(
+                "Skipping '{}', which isn't an executable file",
+                path.display()
+            );
+            continue;
+        }
+        let required = path
+            .file_name()
+            .map(|name| name.to_string_lossy().ends_with(REQUIRED_SUFFIX))
+            .unwrap_or(false);
+        hooks.push(Hook { path, required });
+    }
+    hooks.sort_by(|a, b| a.path.file_name().cmp(&b.path.file_name()));
+    Ok(hooks)
+}
+
+/// Runs the hooks in `dir` in order, passing each the versions migrated `from` and `to`.  Returns
+/// an error if a required hook fails or takes longer than `timeout`; other failures are logged.
+/// Returns an `Interrupted` error, without running the rest, if `interrupt` is set.
+pub(crate) fn run_post_hooks<P: AsRef<Path>>(
+    dir: P,
+    from: &Version,
+    to: &Version,
+    timeout: Duration,
+    interrupt: Interrupt,
+) -> Result<()> {
+    for hook in find_hooks(dir)? {
+        interrupt.check()?;
+        info!("Running post-migration hook '{}'", hook.path.display());
+        match run_hook(&hook.path, from, to, timeout, interrupt) {
+            Ok(()) => {}
+            Err(e @ error::Error::Interrupted) => return Err(e),
+            Err(e) if hook.required => return Err(e),
+            Err(e) => warn!("{}; continuing because the hook isn't required", e),
+        }
+    }
+    Ok(())
+}
+
+/// Runs the hook at `path` in its own process group, killing the group if the hook hasn't exited
+/// after `timeout`, or stopping it if `interrupt` is set.  The hook's output goes to migrator's, so
+/// it's logged with migrator's.
+fn run_hook(
+    path: &Path,
+    from: &Version,
+    to: &Version,
+    timeout: Duration,
+    interrupt: Interrupt,
+) -> Result<()> {
+    let mut command = Command::new(path);
+    command
+        .arg(from.to_string())
+        .arg(to.to_string())
+        .stdin(Stdio::null());
+    let (mut child, pgid) =
+        interrupt::spawn_in_own_group(&mut command).context(error::StartPostHook { hook: path })?;
+    let forwarder = Forwarder::start(interrupt, pgid);
+    let status = wait_for_hook(path, &mut child, pgid, timeout);
+    forwarder.finish();
+
+    let status = status?;
+    interrupt.check()?;
+    ensure!(
+        status.success(),
+        error::PostHookFailure { hook: path, status }
+    );
+    Ok(())
+}
+
+/// Waits for the hook at `path`, the leader of the process group `pgid`, to exit, and returns its
+/// status.  Kills the group if the hook hasn't exited after `timeout`.
+fn wait_for_hook(
+    path: &Path,
+    child: &mut Child,
+    pgid: Pid,
+    timeout: Duration,
+) -> Result<ExitStatus> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child
+            .try_wait()
+            .context(error::WaitPostHook { hook: path })?
+        {
+            return Ok(status);
+        }
+        if Instant::now() >= deadline {
+            if let Err(e) = signal::killpg(pgid, Signal::SIGKILL) {
+                warn!(
+                    "Unable to kill post-migration hook '{}': {}",
+                    path.display(),
+                    e
+                );
+            }
+            // Reap the hook so it doesn't linger as a zombie.
+            child.wait().context(error::WaitPostHook { hook: path })?;
+            return error::PostHookTimeout {
+                hook: path,
+                timeout,
+            }
+            .fail();
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Writes a shell script with the given body to `dir`, executable unless `executable` is false.
+    fn stub(dir: &Path, name: &str, body: &str, executable: bool) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        let mode = if executable { 0o755 } else { 0o644 };
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        path
+    }
+
+    fn versions() -> (Version, Version) {
+        (
+            Version::parse("1.0.0").unwrap(),
+            Version::parse("1.1.0").unwrap(),
+        )
+    }
+
+    #[test]
+    fn discovery_and_order() {
+        let dir = TempDir::new().unwrap();
+        let b = stub(dir.path(), "20-b", "true", true);
+        let a = stub(dir.path(), "10-a.required", "true", true);
+        stub(dir.path(), "15-not-executable", "true", false);
+        fs::create_dir(dir.path().join("05-directory")).unwrap();
+        assert_eq!(
+            find_hooks(dir.path()).unwrap(),
+            vec![
+                Hook {
+                    path: a,
+                    required: true
+                },
+                Hook {
+                    path: b,
+                    required: false
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_dir() {
+        let dir = TempDir::new().unwrap();
+        assert!(find_hooks(dir.path().join("post-hooks.d"))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn hooks_get_versions_in_order() {
+        let dir = TempDir::new().unwrap();
+        let hooks = dir.path().join("post-hooks.d");
+        fs::create_dir(&hooks).unwrap();
+        let log = dir.path().join("log");
+        let body = format!("echo \"$(basename \"$0\") $1 $2\" >> {}", log.display());
+        stub(&hooks, "2-second", &body, true);
+        stub(&hooks, "1-first", &body, true);
+        let (from, to) = versions();
+        run_post_hooks(&hooks, &from, &to, HOOK_TIMEOUT, Interrupt::new()).unwrap();
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            "1-first 1.0.0 1.1.0\n2-second 1.0.0 1.1.0\n"
+        );
+    }
+
+    #[test]
+    fn optional_failure_continues() {
+        let dir = TempDir::new().unwrap();
+        let marker = dir.path().join("ran");
+        stub(dir.path(), "1-fail", "exit 1", true);
+        stub(
+            dir.path(),
+            "2-ok",
+            &format!("touch {}", marker.display()),
+            true,
+        );
+        let (from, to) = versions();
+        run_post_hooks(dir.path(), &from, &to, HOOK_TIMEOUT, Interrupt::new()).unwrap();
+        assert!(marker.exists());
+    }
+
+    #[test]
+    fn required_failure_stops() {
+        let dir = TempDir::new().unwrap();
+        let marker = dir.path().join("ran");
+        stub(dir.path(), "1-fail.required", "exit 1", true);
+        stub(
+            dir.path(),
+            "2-ok",
+            &format!("touch {}", marker.display()),
+            true,
+        );
+        let (from, to) = versions();
+        let result = run_post_hooks(dir.path(), &from, &to, HOOK_TIMEOUT, Interrupt::new());
+        assert!(matches!(result, Err(error::Error::PostHookFailure { .. })));
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn timeout() {
+        let dir = TempDir::new().unwrap();
+        stub(dir.path(), "1-slow", "sleep 60", true);
+        let (from, to) = versions();
+        let timeout = Duration::from_millis(200);
+        let started = Instant::now();
+        // an optional hook that times out is only logged.
+        run_post_hooks(dir.path(), &from, &to, timeout, Interrupt::new()).unwrap();
+
+        fs::rename(
+            dir.path().join("1-slow"),
+            dir.path().join("1-slow.required"),
+        )
+        .unwrap();
+        let result = run_post_hooks(dir.path(), &from, &to, timeout, Interrupt::new());
+        assert!(matches!(result, Err(error::Error::PostHookTimeout { .. })));
+        assert!(started.elapsed() < Duration::from_secs(30));
+    }
+
+    #[test]
+    fn interrupt_stops_hooks() {
+        let dir = TempDir::new().unwrap();
+        let marker = dir.path().join("ran");
+        stub(dir.path(), "1-slow", "sleep 60", true);
+        stub(
+            dir.path(),
+            "2-ok",
+            &format!("touch {}", marker.display()),
+            true,
+        );
+        let (from, to) = versions();
+        let interrupt = Interrupt::new();
+        let setter = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            interrupt.set();
+        });
+        let started = Instant::now();
+        let result = run_post_hooks(dir.path(), &from, &to, HOOK_TIMEOUT, interrupt);
+        setter.join().unwrap();
+        assert!(matches!(result, Err(error::Error::Interrupted)));
+        assert!(!marker.exists());
+        assert!(started.elapsed() < Duration::from_secs(30));
+    }
+}
@@ -0,0 +1,460 @@
+//! This module runs migrations in a sandbox: a mount namespace of their own, whose root only
+//! contains the source data store, read-only, the target data store, read-write, an empty /tmp,
+//! a /dev with only a few harmless devices, like /dev/null, and the read-only system directories
+//! needed to execute the migration, like /usr.  Everything else, like /etc and other data stores,
+//! is hidden from the migration.
+//!
+//! The sandbox keeps migrations from depending on, or changing, anything outside the data stores
+//! by mistake.  It isn't a security boundary; migrations run as root and could undo it.
+//!
+//! The namespace is set up in the forked child, just before the migration is executed.  Only an
+//! errno makes it back from there, so the child also reports which step failed through a pipe.
+
+use crate::error::{self, Result};
+use crate::rando;
+use nix::errno::Errno;
+use nix::fcntl::{open, OFlag};
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::sched::{unshare, CloneFlags};
+use nix::sys::stat::Mode;
+use nix::unistd::{
+    chdir, close, geteuid, mkdir, pipe2, pivot_root, read, symlinkat, unlinkat, write,
+    UnlinkatFlags,
+};
+use snafu::{ensure, ResultExt};
+use std::collections::HashSet;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// The system directories that are mounted read-only in the sandbox, if they exist, so that
+/// migrations and the libraries they need can be executed.  Symlinks, like `/lib` on usrmerged
+/// systems, are recreated instead.
+const SYSTEM_PATHS: &[&str] = &["/usr", "/bin", "/sbin", "/lib", "/lib64"];
+
+/// The devices that are bind-mounted in the sandbox's /dev, if they exist, so that migrations can
+/// discard output and read zeros or random bytes as they would outside the sandbox.
+const DEVICES: &[&str] = &["/dev/null", "/dev/zero", "/dev/urandom"];
+
+/// Where the old root is mounted while the sandbox's root takes its place.
+const OLD_ROOT: &str = "/.old-root";
+
+/// One step of setting up the sandbox, done in the child before the migration is executed.
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Unshare,
+    MakePrivate,
+    MountTmpfs { target: PathBuf, mode: &'static str },
+    CreateDir { path: PathBuf },
+    CreateFile { path: PathBuf },
+    Symlink { target: PathBuf, link: PathBuf },
+    Bind { source: PathBuf, target: PathBuf },
+    RemountReadOnly { target: PathBuf },
+    MountProc { target: PathBuf },
+    PivotRoot { new_root: PathBuf, put_old: PathBuf },
+    DetachOldRoot,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Unshare => write!(f, "create a mount namespace"),
+            Step::MakePrivate => write!(f, "make the mounts private"),
+            Step::MountTmpfs { target, .. } => write!(f, "mount a tmpfs at '{}'", target.display()),
+            Step::CreateDir { path } => write!(f, "create directory '{}'", path.display()),
+            Step::CreateFile { path } => write!(f, "create file '{}'", path.display()),
+            Step::Symlink { target, link } => write!(
+                f,
+                "create symlink '{}' to '{}'",
+                link.display(),
+                target.display()
+            ),
+            Step::Bind { source, target } => write!(
+                f,
+                "bind-mount '{}' at '{}'",
+                source.display(),
+                target.display()
+            ),
+            Step::RemountReadOnly { target } => {
+                write!(f, "remount '{}' read-only", target.display())
+            }
+            Step::MountProc { target } => write!(f, "mount proc at '{}'", target.display()),
+            Step::PivotRoot { new_root, .. } => write!(f, "pivot to '{}'", new_root.display()),
+            Step::DetachOldRoot => write!(f, "detach the old root"),
+        }
+    }
+}
+
+impl Step {
+    /// Takes the step.  This runs between fork and exec, so it only makes system calls; nix
+    /// passes paths to them in stack buffers, without allocating.
+    fn apply(&self) -> nix::Result<()> {
+        const NONE: Option<&str> = None;
+        match self {
+            Step::Unshare => unshare(CloneFlags::CLONE_NEWNS),
+            // Keep our mounts from propagating back to the original namespace.
+            Step::MakePrivate => {
+                mount(NONE, "/", NONE, MsFlags::MS_REC | MsFlags::MS_PRIVATE, NONE)
+            }
+            Step::MountTmpfs { target, mode } => mount(
+                Some("tmpfs"),
+                target,
+                Some("tmpfs"),
+                MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+                Some(*mode),
+            ),
+            Step::CreateDir { path } => mkdir(path, Mode::from_bits_truncate(0o755)),
+            // A device can only be bind-mounted over an existing file.
+            Step::CreateFile { path } => open(
+                path,
+                OFlag::O_CREAT | OFlag::O_WRONLY | OFlag::O_CLOEXEC,
+                Mode::from_bits_truncate(0o644),
+            )
+            .and_then(close),
+            Step::Symlink { target, link } => symlinkat(target, None, link),
+            Step::Bind { source, target } => mount(
+                Some(source),
+                target,
+                NONE,
+                MsFlags::MS_BIND | MsFlags::MS_REC,
+                NONE,
+            ),
+            Step::RemountReadOnly { target } => mount(
+                NONE,
+                target,
+                NONE,
+                MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+                NONE,
+            ),
+            // The migration is executed from a sealed memfd through /proc/self/fd.
+            Step::MountProc { target } => mount(
+                Some("proc"),
+                target,
+                Some("proc"),
+                MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+                NONE,
+            ),
+            Step::PivotRoot { new_root, put_old } => {
+                pivot_root(new_root, put_old)?;
+                chdir("/")
+            }
+            Step::DetachOldRoot => {
+                umount2(OLD_ROOT, MntFlags::MNT_DETACH)?;
+                unlinkat(None, OLD_ROOT, UnlinkatFlags::RemoveDir)
+            }
+        }
+    }
+}
+
+/// Returns the steps that build a sandbox at `root` for a migration from `source` to `target`,
+/// with the given system directories and devices.
+fn plan(
+    root: &Path,
+    source: &Path,
+    target: &Path,
+    system_paths: &[&Path],
+    devices: &[&Path],
+) -> Vec<Step> {
+    // Returns where `path` is in the sandbox.
+    let inside = |path: &Path| root.join(path.strip_prefix("/").unwrap_or(path));
+    let mut steps = vec![
+        Step::Unshare,
+        Step::MakePrivate,
+        Step::MountTmpfs {
+            target: root.to_path_buf(),
+            mode: "mode=0755",
+        },
+        Step::CreateDir {
+            path: inside(Path::new("/tmp")),
+        },
+        Step::MountTmpfs {
+            target: inside(Path::new("/tmp")),
+            mode: "mode=1777",
+        },
+        Step::CreateDir {
+            path: inside(Path::new("/proc")),
+        },
+        Step::MountProc {
+            target: inside(Path::new("/proc")),
+        },
+        Step::CreateDir {
+            path: inside(Path::new("/dev")),
+        },
+    ];
+
+    // Writing to a device on a read-only mount still works; the mount only keeps the device
+    // nodes themselves from being changed.
+    for device in devices {
+        steps.extend(vec![
+            Step::CreateFile {
+                path: inside(device),
+            },
+            Step::Bind {
+                source: device.to_path_buf(),
+                target: inside(device),
+            },
+            Step::RemountReadOnly {
+                target: inside(device),
+            },
+        ]);
+    }
+
+    for path in system_paths {
+        match fs::read_link(path) {
+            Ok(link_target) => steps.push(Step::Symlink {
+                target: link_target,
+                link: inside(path),
+            }),
+            Err(_) => steps.extend(vec![
+                Step::CreateDir { path: inside(path) },
+                Step::Bind {
+                    source: path.to_path_buf(),
+                    target: inside(path),
+                },
+                Step::RemountReadOnly {
+                    target: inside(path),
+                },
+            ]),
+        }
+    }
+
+    // The data stores are mounted at their own paths, which may be under /tmp.
+    let mut created: HashSet<PathBuf> = [Path::new("/tmp"), Path::new("/proc"), Path::new("/dev")]
+        .iter()
+        .map(|path| inside(path))
+        .collect();
+    for (datastore, read_only) in &[(source, true), (target, false)] {
+        let mut ancestors: Vec<&Path> = datastore.ancestors().collect();
+        ancestors.reverse();
+        for dir in ancestors.into_iter().skip(1) {
+            if created.insert(inside(dir)) {
+                steps.push(Step::CreateDir { path: inside(dir) });
+            }
+        }
+        steps.push(Step::Bind {
+            source: datastore.to_path_buf(),
+            target: inside(datastore),
+        });
+        if *read_only {
+            steps.push(Step::RemountReadOnly {
+                target: inside(datastore),
+            });
+        }
+    }
+
+    steps.extend(vec![
+        Step::CreateDir {
+            path: inside(Path::new(OLD_ROOT)),
+        },
+        Step::PivotRoot {
+            new_root: root.to_path_buf(),
+            put_old: inside(Path::new(OLD_ROOT)),
+        },
+        Step::DetachOldRoot,
+    ]);
+    steps
+}
+
+/// A sandbox for one migration.  The target data store is created along with it, so that it can
+/// be mounted, and the directory the sandbox's root is built in is removed when it's dropped.
+pub(crate) struct Sandbox {
+    root: PathBuf,
+    steps: Vec<Step>,
+    /// The pipe through which the child reports the index of the step that failed.
+    report_read: RawFd,
+    report_write: Option<RawFd>,
+}
+
+impl Sandbox {
+    /// Prepares a sandbox for a migration from `source` to `target`.  Migrator must run as root
+    /// to create mount namespaces.
+    pub(crate) fn new(source: &Path, target: &Path) -> Result<Self> {
+        ensure!(geteuid().is_root(), error::SandboxRequiresRoot);
+        let root = env::temp_dir().join(format!("migrator-sandbox-{}", rando()));
+        fs::create_dir(&root).context(error::SandboxRoot { path: &root })?;
+        fs::create_dir(target).context(error::SandboxTarget { path: target })?;
+        let (report_read, report_write) =
+            pipe2(OFlag::O_CLOEXEC).context(error::SandboxReportPipe)?;
+        let existing = |paths: &[&'static str]| -> Vec<&'static Path> {
+            paths
+                .iter()
+                .map(|path| Path::new(*path))
+                .filter(|path| path.symlink_metadata().is_ok())
+                .collect()
+        };
+        Ok(Self {
+            steps: plan(
+                &root,
+                source,
+                target,
+                &existing(SYSTEM_PATHS),
+                &existing(DEVICES),
+            ),
+            root,
+            report_read,
+            report_write: Some(report_write),
+        })
+    }
+
+    /// Makes `command` set up the sandbox before it's executed.
+    pub(crate) fn apply(&self, command: &mut Command) {
+        let steps = self.steps.clone();
+        let report_write = self.report_write;
+        // Safe because the steps only make system calls, which are async-signal-safe.
+        unsafe {
+            command.pre_exec(move || {
+                for (index, step) in steps.iter().enumerate() {
+                    if let Err(e) = step.apply() {
+                        if let Some(fd) = report_write {
+                            let _ = write(fd, &(index as u32).to_ne_bytes());
+                        }
+                        return Err(io::Error::from_raw_os_error(
+                            e.as_errno().unwrap_or(Errno::UnknownErrno) as i32,
+                        ));
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+
+    /// Returns the `result` of running the migration, replacing a failure to start it with a
+    /// `SandboxSetup` error if it's because the sandbox couldn't be set up.
+    pub(crate) fn check_setup(
+        &mut self,
+        result: Result<Output>,
+        migration: &str,
+    ) -> Result<Output> {
+        match result {
+            Err(error::Error::StartMigration { source }) => match self.failed_step() {
+                Some(step) => Err(source).context(error::SandboxSetup { migration, step }),
+                None => Err(error::Error::StartMigration { source }),
+            },
+            other => other,
+        }
+    }
+
+    /// Returns the step the child reported as failed, if any.  The child has exited or executed
+    /// the migration by now, so once our end of the pipe is closed, reading it doesn't block.
+    fn failed_step(&mut self) -> Option<String> {
+        if let Some(fd) = self.report_write.take() {
+            let _ = close(fd);
+        }
+        let mut index = [0u8; 4];
+        match read(self.report_read, &mut index) {
+            Ok(4) => self
+                .steps
+                .get(u32::from_ne_bytes(index) as usize)
+                .map(Step::to_string),
+            _ => None,
+        }
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        if let Some(fd) = self.report_write.take() {
+            let _ = close(fd);
+        }
+        let _ = close(self.report_read);
+        // The mounts only existed in the migration's namespace, so the directory is empty.
+        if let Err(e) = fs::remove_dir(&self.root) {
+            warn!(
+                "Unable to remove sandbox directory '{}': {}",
+                self.root.display(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn descriptions(steps: &[Step]) -> Vec<String> {
+        steps.iter().map(Step::to_string).collect()
+    }
+
+    #[test]
+    fn sandbox_plan() {
+        let steps = plan(
+            Path::new("/tmp/sandbox"),
+            Path::new("/var/lib/datastore/v1.0.0_aaaa"),
+            Path::new("/var/lib/datastore/v1.0.1_bbbb"),
+            &[Path::new("/usr")],
+            &[Path::new("/dev/null")],
+        );
+        assert_eq!(
+            descriptions(&steps),
+            vec![
+                "create a mount namespace",
+                "make the mounts private",
+                "mount a tmpfs at '/tmp/sandbox'",
+                "create directory '/tmp/sandbox/tmp'",
+                "mount a tmpfs at '/tmp/sandbox/tmp'",
+                "create directory '/tmp/sandbox/proc'",
+                "mount proc at '/tmp/sandbox/proc'",
+                "create directory '/tmp/sandbox/dev'",
+                "create file '/tmp/sandbox/dev/null'",
+                "bind-mount '/dev/null' at '/tmp/sandbox/dev/null'",
+                "remount '/tmp/sandbox/dev/null' read-only",
+                "create directory '/tmp/sandbox/usr'",
+                "bind-mount '/usr' at '/tmp/sandbox/usr'",
+                "remount '/tmp/sandbox/usr' read-only",
+                "create directory '/tmp/sandbox/var'",
+                "create directory '/tmp/sandbox/var/lib'",
+                "create directory '/tmp/sandbox/var/lib/datastore'",
+                "create directory '/tmp/sandbox/var/lib/datastore/v1.0.0_aaaa'",
+                "bind-mount '/var/lib/datastore/v1.0.0_aaaa' at \
+                 '/tmp/sandbox/var/lib/datastore/v1.0.0_aaaa'",
+                "remount '/tmp/sandbox/var/lib/datastore/v1.0.0_aaaa' read-only",
+                "create directory '/tmp/sandbox/var/lib/datastore/v1.0.1_bbbb'",
+                "bind-mount '/var/lib/datastore/v1.0.1_bbbb' at \
+                 '/tmp/sandbox/var/lib/datastore/v1.0.1_bbbb'",
+                "create directory '/tmp/sandbox/.old-root'",
+                "pivot to '/tmp/sandbox'",
+                "detach the old root",
+            ]
+        );
+    }
+
+    #[test]
+    fn sandbox_plan_under_tmp() {
+        // data stores under /tmp, like those of the tests, are mounted on the sandbox's /tmp.
+        let steps = plan(
+            Path::new("/tmp/sandbox"),
+            Path::new("/tmp/test/v1.0.0_aaaa"),
+            Path::new("/tmp/test/v1.0.1_bbbb"),
+            &[],
+            &[],
+        );
+        let descriptions = descriptions(&steps);
+        let count = |description: &str| descriptions.iter().filter(|d| *d == description).count();
+        assert_eq!(count("create directory '/tmp/sandbox/tmp'"), 1);
+        assert_eq!(count("create directory '/tmp/sandbox/tmp/test'"), 1);
+    }
+
+    #[test]
+    fn system_symlinks_are_recreated() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let link = dir.path().join("lib");
+        std::os::unix::fs::symlink("usr/lib", &link).unwrap();
+        let steps = plan(
+            Path::new("/tmp/sandbox"),
+            Path::new("/a"),
+            Path::new("/b"),
+            &[&link],
+            &[],
+        );
+        assert!(steps.contains(&Step::Symlink {
+            target: PathBuf::from("usr/lib"),
+            link: Path::new("/tmp/sandbox").join(link.strip_prefix("/").unwrap()),
+        }));
+    }
+}
@@ -0,0 +1,168 @@
+//! This module removes migrations that the user asked to skip from the list migrator will run.
+//!
+//! Migrations are skipped by exact name, given with `--skip-migration NAME` or with
+//! `migrator.skip-migration=NAME` on the kernel command line.  Both can be repeated, and the
+//! kernel command line value can also list several names separated by commas, so a fleet can skip
+//! a harmful migration during an update without a new release.  Every name given with
+//! `--skip-migration` must be in the plan, so a typo fails the migration rather than silently
+//! running the migration it meant to skip.  Names from the kernel command line that aren't in the
+//! plan are logged and ignored instead, because the parameter may be left in place after the update
+//! it was meant for, and it mustn't fail the next one.
+
+use crate::error::{self, Result};
+use snafu::ensure;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Where the kernel command line is read from.
+pub(crate) const KERNEL_CMDLINE: &str = "/proc/cmdline";
+/// The kernel command line parameter that names migrations to skip.
+const CMDLINE_KEY: &str = "migrator.skip-migration";
+
+/// Returns the migrations named with `migrator.skip-migration` in `cmdline`, in order.
+pub(crate) fn names_from_cmdline(cmdline: &str) -> Vec<String> {
+    cmdline
+        .split_whitespace()
+        .filter_map(|param| {
+            let mut parts = param.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(CMDLINE_KEY), Some(value)) => Some(value),
+                _ => None,
+            }
+        })
+        .flat_map(|value| value.split(','))
+        .filter(|name| !name.is_empty())
+        .map(|name| name.to_string())
+        .collect()
+}
+
+/// Returns the migrations named on the kernel command line at `path`.  There are none if the file
+/// doesn't exist, e.g. in a container.
+pub(crate) fn names_from_cmdline_file<P: AsRef<Path>>(path: P) -> io::Result<Vec<String>> {
+    match fs::read_to_string(path) {
+        Ok(cmdline) => Ok(names_from_cmdline(&cmdline)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Removes the migrations named in `skip` or `cmdline_skip` from `migrations`, keeping the rest in
+/// order, and returns the remaining migrations and the skipped ones in plan order.  Returns an
+/// error, without skipping anything, if any name in `skip` isn't in `migrations`; names in
+/// `cmdline_skip` that aren't in `migrations` are logged and ignored.
+pub(crate) fn skip_migrations<S: AsRef<str>>(
+    migrations: Vec<String>,
+    skip: &[S],
+    cmdline_skip: &[S],
+) -> Result<(Vec<String>, Vec<String>)> {
+    let mut missing: Vec<String> = Vec::new();
+    for name in skip.iter().map(|name| name.as_ref()) {
+        if !migrations.iter().any(|migration| migration == name)
+            && !missing.iter().any(|missing| missing == name)
+        {
+            missing.push(name.to_string());
+        }
+    }
+    ensure!(
+        missing.is_empty(),
+        error::SkipMigrationNotInPlan { names: missing }
+    );
+    for name in cmdline_skip.iter().map(|name| name.as_ref()) {
+        if !migrations.iter().any(|migration| migration == name) {
+            warn!("Ignoring {}={}, which isn't in the plan", CMDLINE_KEY, name);
+        }
+    }
+
+    Ok(migrations.into_iter().partition(|migration| {
+        !skip
+            .iter()
+            .chain(cmdline_skip)
+            .any(|name| name.as_ref() == migration)
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn plan() -> Vec<String> {
+        vec!["migrate_b", "migrate_a", "migrate_c"]
+            .into_iter()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn skip_in_plan() {
+        let (remaining, skipped) = skip_migrations(plan(), &["migrate_a"], &[]).unwrap();
+        // the remaining migrations keep their order from the plan.
+        assert_eq!(remaining, vec!["migrate_b", "migrate_c"]);
+        assert_eq!(skipped, vec!["migrate_a"]);
+
+        let (remaining, skipped) =
+            skip_migrations(plan(), &["migrate_c", "migrate_b"], &[]).unwrap();
+        assert_eq!(remaining, vec!["migrate_a"]);
+        assert_eq!(skipped, vec!["migrate_b", "migrate_c"]);
+    }
+
+    #[test]
+    fn skip_nothing() {
+        let (remaining, skipped) = skip_migrations(plan(), &[] as &[&str], &[]).unwrap();
+        assert_eq!(remaining, plan());
+        assert!(skipped.is_empty());
+    }
+
+    #[test]
+    fn skip_not_in_plan() {
+        // names must match exactly.
+        let result = skip_migrations(plan(), &["migrate_a", "migrate", "migrate_A"], &[]);
+        match result {
+            Err(error::Error::SkipMigrationNotInPlan { names }) => {
+                assert_eq!(names, vec!["migrate", "migrate_A"])
+            }
+            _ => panic!("expected SkipMigrationNotInPlan, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn cmdline_not_in_plan() {
+        // a name left on the kernel command line from an earlier update is ignored, while the
+        // names that are in the plan are still skipped.
+        let (remaining, skipped) =
+            skip_migrations(plan(), &["migrate_b"], &["migrate_a", "migrate_old"]).unwrap();
+        assert_eq!(remaining, vec!["migrate_c"]);
+        assert_eq!(skipped, vec!["migrate_b", "migrate_a"]);
+
+        let (remaining, skipped) = skip_migrations(plan(), &[], &["migrate_old"]).unwrap();
+        assert_eq!(remaining, plan());
+        assert!(skipped.is_empty());
+
+        // the command line doesn't excuse a bad --skip-migration name.
+        assert!(matches!(
+            skip_migrations(plan(), &["migrate_old"], &["migrate_old"]),
+            Err(error::Error::SkipMigrationNotInPlan { .. })
+        ));
+    }
+
+    #[test]
+    fn cmdline() {
+        assert_eq!(
+            names_from_cmdline(
+                "BOOT_IMAGE=/vmlinuz migrator.skip-migration=migrate_a console=ttyS0 \
+                migrator.skip-migration=migrate_b,migrate_c migrator.skip-migration= \
+                migrator.skip-migrations=migrate_d migrator.skip-migration\n"
+            ),
+            vec!["migrate_a", "migrate_b", "migrate_c"]
+        );
+        assert!(names_from_cmdline("quiet").is_empty());
+    }
+
+    #[test]
+    fn missing_cmdline_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        assert!(names_from_cmdline_file(dir.path().join("cmdline"))
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,121 @@
+//! This module guards against migrations that modify their source data store.  Migrations are given
+//! a source data store to read and a target data store to write; if a migration writes to its
+//! source, we could lose the ability to roll back.  We snapshot the source before a migration runs
+//! and compare it to the source afterward.
+
+use crate::error::{self, Result};
+use snafu::ResultExt;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+/// A summary of the contents of a data store, keyed by each entry's path relative to the data
+/// store root.  The values are hashes of the entry's type and contents.  The hashes are only
+/// compared within a single run of migrator, so the stability of `DefaultHasher` across Rust
+/// releases doesn't matter here.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Snapshot {
+    entries: BTreeMap<PathBuf, u64>,
+}
+
+impl Snapshot {
+    /// Takes a snapshot of the data store at `root`.
+    pub(crate) fn new<P: AsRef<Path>>(root: P) -> Result<Self> {
+        let root = root.as_ref();
+        let mut entries = BTreeMap::new();
+        add_entries(root, root, &mut entries)?;
+        Ok(Self { entries })
+    }
+
+    /// Returns the relative paths of entries that were added, removed, or changed in `other`
+    /// compared to this snapshot, in sorted order.
+    pub(crate) fn changed_paths(&self, other: &Snapshot) -> Vec<PathBuf> {
+        let mut changed: Vec<PathBuf> = self
+            .entries
+            .iter()
+            .filter(|(path, hash)| other.entries.get(*path) != Some(hash))
+            .map(|(path, _)| path.clone())
+            .collect();
+        changed.extend(
+            other
+                .entries
+                .keys()
+                .filter(|path| !self.entries.contains_key(*path))
+                .cloned(),
+        );
+        changed.sort();
+        changed
+    }
+}
+
+/// Recursively hashes the entries in `dir`, adding them to `entries` keyed relative to `root`.
+fn add_entries(root: &Path, dir: &Path, entries: &mut BTreeMap<PathBuf, u64>) -> Result<()> {
+    for entry in fs::read_dir(dir).context(error::SourceDatastoreRead { path: dir })? {
+        let entry = entry.context(error::SourceDatastoreRead { path: dir })?;
+        let path = entry.path();
+        let file_type = entry
+            .file_type()
+            .context(error::SourceDatastoreRead { path: &path })?;
+        let relative = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+
+        let mut hasher = DefaultHasher::new();
+        if file_type.is_dir() {
+            "dir".hash(&mut hasher);
+            add_entries(root, &path, entries)?;
+        } else if file_type.is_symlink() {
+            "symlink".hash(&mut hasher);
+            fs::read_link(&path)
+                .context(error::SourceDatastoreRead { path: &path })?
+                .hash(&mut hasher);
+        } else {
+            "file".hash(&mut hasher);
+            fs::read(&path)
+                .context(error::SourceDatastoreRead { path: &path })?
+                .hash(&mut hasher);
+        }
+        entries.insert(relative, hasher.finish());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn unchanged() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("settings")).unwrap();
+        fs::write(dir.path().join("settings").join("motd"), "hi").unwrap();
+        let before = Snapshot::new(dir.path()).unwrap();
+        let after = Snapshot::new(dir.path()).unwrap();
+        assert_eq!(before, after);
+        assert!(before.changed_paths(&after).is_empty());
+    }
+
+    #[test]
+    fn changed() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("settings")).unwrap();
+        fs::write(dir.path().join("settings").join("motd"), "hi").unwrap();
+        fs::write(dir.path().join("settings").join("removed"), "bye").unwrap();
+        let before = Snapshot::new(dir.path()).unwrap();
+
+        fs::write(dir.path().join("settings").join("motd"), "hello").unwrap();
+        fs::remove_file(dir.path().join("settings").join("removed")).unwrap();
+        fs::write(dir.path().join("added"), "new").unwrap();
+        let after = Snapshot::new(dir.path()).unwrap();
+
+        assert_eq!(
+            before.changed_paths(&after),
+            vec![
+                PathBuf::from("added"),
+                PathBuf::from("settings/motd"),
+                PathBuf::from("settings/removed"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,248 @@
+//! This module implements `--status`, which reports on the data store's chain of version links
+//! (current -> major -> minor -> patch -> data store directory) without loading the TUF repository.
+
+use crate::args::StatusArgs;
+use crate::error::{self, Result};
+use crate::{read_datastore_link, version_from_link};
+use semver::Version;
+use serde_json::{json, Value};
+use snafu::{ensure, OptionExt};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// The links in the version chain, in the order they're followed.
+const LINK_NAMES: &[&str] = &["current", "major", "minor", "patch"];
+
+/// The state of one link in the version chain.
+#[derive(Debug)]
+struct LinkStatus {
+    /// The link's place in the chain, e.g. `major`.
+    name: &'static str,
+    /// The path of the link itself.
+    path: PathBuf,
+    /// Where the link points, if it could be read.
+    target: Option<PathBuf>,
+    /// Why the link is broken, if it is.
+    problem: Option<String>,
+}
+
+impl LinkStatus {
+    fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "path": self.path.display().to_string(),
+            "target": self.target.as_ref().map(|target| target.display().to_string()),
+            "valid": self.problem.is_none(),
+            "problem": self.problem,
+        })
+    }
+}
+
+/// The state of the version chain of a data store.  Links are followed until one is broken, so
+/// links after a broken link aren't included.
+#[derive(Debug)]
+pub(crate) struct DatastoreStatus {
+    links: Vec<LinkStatus>,
+    /// The version from the name of the patch link, if it could be determined.
+    version: Option<Version>,
+}
+
+impl DatastoreStatus {
+    /// Follows the version chain of the data store in `datastore_dir`, starting at its `current`
+    /// link.
+    pub(crate) fn new<P: AsRef<Path>>(datastore_dir: P) -> Self {
+        let datastore_dir = datastore_dir.as_ref();
+        let mut links = Vec::new();
+        let mut path = datastore_dir.join("current");
+        for (i, &name) in LINK_NAMES.iter().enumerate() {
+            let target = match read_datastore_link(datastore_dir, &path) {
+                Ok(target) => target,
+                Err(e) => {
+                    links.push(LinkStatus {
+                        name,
+                        path,
+                        target: None,
+                        problem: Some(e.to_string()),
+                    });
+                    break;
+                }
+            };
+
+            // The patch link points to the data store directory; the others point to the next
+            // link, which we check on the next pass.
+            let is_last = i == LINK_NAMES.len() - 1;
+            let problem = if is_last && !target.is_dir() {
+                Some(format!("{} is not a directory", target.display()))
+            } else if !is_last && target.symlink_metadata().is_err() {
+                Some(format!("{} does not exist", target.display()))
+            } else {
+                None
+            };
+            let broken = problem.is_some();
+            links.push(LinkStatus {
+                name,
+                path,
+                target: Some(target.clone()),
+                problem,
+            });
+            if broken {
+                break;
+            }
+            path = target;
+        }
+
+        let version = links
+            .iter()
+            .find(|link| link.name == "patch")
+            .and_then(|link| version_from_link(&link.path).ok());
+
+        Self { links, version }
+    }
+
+    /// Returns true if every link in the chain is valid.
+    pub(crate) fn is_healthy(&self) -> bool {
+        self.links.len() == LINK_NAMES.len() && self.links.iter().all(|l| l.problem.is_none())
+    }
+
+    /// Returns the data store directory at the end of the chain, if every link is valid.
+    pub(crate) fn datastore(&self) -> Option<&Path> {
+        if !self.is_healthy() {
+            return None;
+        }
+        self.links.last().and_then(|link| link.target.as_deref())
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "links": self.links.iter().map(LinkStatus::to_json).collect::<Vec<_>>(),
+            "datastore": self.datastore().map(|path| path.display().to_string()),
+            "version": self.version.as_ref().map(|version| version.to_string()),
+            "healthy": self.is_healthy(),
+        })
+    }
+}
+
+impl fmt::Display for DatastoreStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for link in &self.links {
+            write!(f, "{}: {}", link.name, link.path.display())?;
+            if let Some(target) = &link.target {
+                write!(f, " -> {}", target.display())?;
+            }
+            match &link.problem {
+                None => writeln!(f, " (valid)")?,
+                Some(problem) => writeln!(f, " (broken: {})", problem)?,
+            }
+        }
+        for name in LINK_NAMES.iter().skip(self.links.len()) {
+            writeln!(f, "{}: not checked", name)?;
+        }
+        match self.datastore() {
+            Some(datastore) => writeln!(f, "data store: {}", datastore.display())?,
+            None => writeln!(f, "data store: unknown")?,
+        }
+        match &self.version {
+            Some(version) => writeln!(f, "version: {}", version),
+            None => writeln!(f, "version: unknown"),
+        }
+    }
+}
+
+/// Prints the status of the data store given in `args`.  Returns an error if the chain is broken,
+/// after printing, so that the exit code reflects the data store's health.
+pub(crate) fn run(args: &StatusArgs) -> Result<()> {
+    let datastore_dir = args
+        .datastore_path
+        .parent()
+        .context(error::DataStoreLinkToRoot {
+            path: &args.datastore_path,
+        })?;
+    let status = DatastoreStatus::new(datastore_dir);
+    if args.json {
+        println!("{:#}", status.to_json());
+    } else {
+        print!("{}", status);
+    }
+    ensure!(
+        status.is_healthy(),
+        error::DataStoreUnhealthy {
+            path: datastore_dir
+        }
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    /// Creates a data store directory with a full chain of version links for v1.2.3.
+    fn healthy_chain() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("v1.2.3_abcd")).unwrap();
+        symlink("v1.2.3_abcd", dir.path().join("v1.2.3")).unwrap();
+        symlink("v1.2.3", dir.path().join("v1.2")).unwrap();
+        symlink("v1.2", dir.path().join("v1")).unwrap();
+        symlink("v1", dir.path().join("current")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn healthy() {
+        let dir = healthy_chain();
+        let status = DatastoreStatus::new(dir.path());
+        assert!(status.is_healthy());
+        assert_eq!(
+            status.datastore(),
+            Some(dir.path().join("v1.2.3_abcd").as_path())
+        );
+        assert_eq!(status.version, Some(Version::new(1, 2, 3)));
+
+        let text = status.to_string();
+        assert_eq!(text.matches("(valid)").count(), 4);
+        assert!(text.ends_with("version: 1.2.3\n"));
+
+        let json = status.to_json();
+        assert_eq!(json["healthy"], true);
+        assert_eq!(json["version"], "1.2.3");
+        assert_eq!(json["links"].as_array().unwrap().len(), 4);
+        assert_eq!(json["links"][3]["name"], "patch");
+        assert_eq!(json["links"][3]["valid"], true);
+    }
+
+    #[test]
+    fn broken() {
+        let dir = healthy_chain();
+        fs::remove_dir(dir.path().join("v1.2.3_abcd")).unwrap();
+        let status = DatastoreStatus::new(dir.path());
+        assert!(!status.is_healthy());
+        assert_eq!(status.datastore(), None);
+        // the version comes from the patch link's name, so it's still known.
+        assert_eq!(status.version, Some(Version::new(1, 2, 3)));
+
+        let json = status.to_json();
+        assert_eq!(json["healthy"], false);
+        assert_eq!(json["datastore"], Value::Null);
+        assert_eq!(json["links"][2]["valid"], true);
+        assert_eq!(json["links"][3]["valid"], false);
+    }
+
+    #[test]
+    fn missing_link() {
+        let dir = healthy_chain();
+        fs::remove_file(dir.path().join("v1.2")).unwrap();
+        let status = DatastoreStatus::new(dir.path());
+        assert!(!status.is_healthy());
+        assert_eq!(status.version, None);
+
+        let text = status.to_string();
+        assert!(text.contains("major: "));
+        assert!(text.contains("(broken: "));
+        assert!(text.contains("minor: not checked\n"));
+        assert!(text.contains("patch: not checked\n"));
+        assert!(text.ends_with("data store: unknown\nversion: unknown\n"));
+    }
+}
@@ -1,13 +1,26 @@
 //! Provides an end-to-end test of `migrator` via the `run` function. This module is conditionally
 //! compiled for cfg(test) only.
+//!
+//! Most tests run migrations without the sandbox, because the test migrations record their runs
+//! next to the data store, which the sandbox hides, and because setting it up requires root.  The
+//! sandbox itself is tested by `sandboxed_migration`, when the tests run as root.
 use crate::args::Args;
-use crate::run;
+use crate::error::Error;
+use crate::interrupt::Interrupt;
+use crate::limits::Limits;
+use crate::metrics::RunMetrics;
+use crate::{prepare_repo_directory, run};
 use chrono::{DateTime, Utc};
+use nix::errno::Errno;
+use nix::sys::signal::{self, Signal};
+use nix::unistd::{self, Pid};
 use semver::Version;
 use std::fs;
 use std::fs::File;
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 
 /// Provides the path to a folder where test data files reside.
@@ -38,9 +51,10 @@ const FIRST_MIGRATION: &str = "b-first-migration";
 const SECOND_MIGRATION: &str = "a-second-migration";
 
 /// Creates a script that will serve as a migration during testing. The script writes its migrations
-/// name to a file named `result.txt` in the parent directory of the datastore. `pentacle` does not
-/// retain the name of the executing binary or script, so we take the `migration_name` as input,
-/// and 'hardcode' it into the script.
+/// name to a file named `result.txt` in the parent directory of the datastore, and creates the
+/// target datastore directory so that tests can check which datastores remain afterward.
+/// `pentacle` does not retain the name of the executing binary or script, so we take the
+/// `migration_name` as input, and 'hardcode' it into the script.
 fn create_test_migration<S: AsRef<str>>(migration_name: S) -> String {
     format!(
         r#"#!/usr/bin/env bash
@@ -49,6 +63,7 @@ migration_name="{}"
 datastore_parent_dir="$(dirname "${{3}}")"
 outfile="${{datastore_parent_dir}}/result.txt"
 echo "${{migration_name}}:" "${{@}}" >> "${{outfile}}"
+mkdir -p "${{5}}"
 "#,
         migration_name.as_ref()
     )
@@ -95,6 +110,21 @@ fn compress(source: &[u8], destination: &Path) {
 /// Creates a test repository with a couple of versions defined in the manifest and a couple of
 /// migrations. See the test description for for more info.
 fn create_test_repo() -> TestRepo {
+    // Create an script that we can use as the 'migration' that migrator will run. This script will
+    // write its name and arguments to a file named result.txt in the directory that is the parent
+    // of --source-datastore. result.txt can then be used to see what migrations ran, and in what
+    // order. Note that tests are sensitive to the order and number of arguments passed. If
+    // --source-datastore is given at a different position then the tests will fail and the script
+    // will need to be updated.
+    create_test_repo_with_migrations(&[
+        (FIRST_MIGRATION, create_test_migration(FIRST_MIGRATION)),
+        (SECOND_MIGRATION, create_test_migration(SECOND_MIGRATION)),
+    ])
+}
+
+/// Creates a test repository with the given migrations, given as pairs of name and script, which
+/// run in the given order when migrating from 0.99.0 to 0.99.1.
+fn create_test_repo_with_migrations(migrations: &[(&str, String)]) -> TestRepo {
     // This is where the signed TUF repo will exist when we are done. It is the
     // root directory of the `TestRepo` we will return when we are done.
     let test_repo_dir = TempDir::new().unwrap();
@@ -113,22 +143,17 @@ fn create_test_repo() -> TestRepo {
     // implementations).
     manifest.migrations.insert(
         (Version::new(0, 99, 0), Version::new(0, 99, 1)),
-        vec![FIRST_MIGRATION.into(), SECOND_MIGRATION.into()],
+        migrations
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .collect(),
     );
     update_metadata::write_file(tuf_indir.join("manifest.json").as_path(), &manifest).unwrap();
 
-    // Create an script that we can use as the 'migration' that migrator will run. This script will
-    // write its name and arguments to a file named result.txt in the directory that is the parent
-    // of --source-datastore. result.txt can then be used to see what migrations ran, and in what
-    // order. Note that tests are sensitive to the order and number of arguments passed. If
-    // --source-datastore is given at a different position then the tests will fail and the script
-    // will need to be updated.
-    let migration_a = create_test_migration(FIRST_MIGRATION);
-    let migration_b = create_test_migration(SECOND_MIGRATION);
-
-    // Save lz4 compressed copies of the migration script into the tuftool_indir.
-    compress(migration_a.as_bytes(), &tuf_indir.join(FIRST_MIGRATION));
-    compress(migration_b.as_bytes(), &tuf_indir.join(SECOND_MIGRATION));
+    // Save lz4 compressed copies of the migration scripts into the tuftool_indir.
+    for (name, script) in migrations {
+        compress(script.as_bytes(), &tuf_indir.join(name));
+    }
 
     // Create and sign the TUF repository.
     let mut editor = tough::editor::RepositoryEditor::new(root()).unwrap();
@@ -204,13 +229,23 @@ fn migrate_forward() {
     let test_repo = create_test_repo();
     let args = Args {
         datastore_path: test_datastore.datastore.clone(),
+        expected_from_version: None,
+        force_version_mismatch: false,
+        keep_intermediate: false,
         log_level: log::LevelFilter::Info,
         migration_directory: test_repo.targets_path.clone(),
         migrate_to_version: to_version,
         root_path: root(),
         metadata_directory: test_repo.metadata_path.clone(),
+        metrics_path: test_datastore.tmp.path().join("migrator.prom"),
+        no_sandbox: true,
+        no_source_guard: false,
+        skip_migrations: Vec::new(),
+        cmdline_skip_migrations: Vec::new(),
     };
-    run(&args).unwrap();
+    let mut metrics = RunMetrics::start(&args.migrate_to_version);
+    run(&args, &mut metrics, Interrupt::new(), Limits::default()).unwrap();
+    assert_eq!(metrics.migrations_run, 2);
     // the migrations should write to a file named result.txt.
     let output_file = test_datastore.tmp.path().join("result.txt");
     let contents = std::fs::read_to_string(&output_file).unwrap();
@@ -236,13 +271,27 @@ fn migrate_backward() {
     let test_repo = create_test_repo();
     let args = Args {
         datastore_path: test_datastore.datastore.clone(),
+        expected_from_version: None,
+        force_version_mismatch: false,
+        keep_intermediate: false,
         log_level: log::LevelFilter::Info,
         migration_directory: test_repo.targets_path.clone(),
         migrate_to_version: to_version,
         root_path: root(),
         metadata_directory: test_repo.metadata_path.clone(),
+        metrics_path: test_datastore.tmp.path().join("migrator.prom"),
+        no_sandbox: true,
+        no_source_guard: false,
+        skip_migrations: Vec::new(),
+        cmdline_skip_migrations: Vec::new(),
     };
-    run(&args).unwrap();
+    run(
+        &args,
+        &mut RunMetrics::start(&args.migrate_to_version),
+        Interrupt::new(),
+        Limits::default(),
+    )
+    .unwrap();
     let output_file = test_datastore.tmp.path().join("result.txt");
     let contents = std::fs::read_to_string(&output_file).unwrap();
     let lines: Vec<&str> = contents.split('\n').collect();
@@ -256,3 +305,610 @@ fn migrate_backward() {
     let got: String = second_line.chars().take(want.len()).collect();
     assert_eq!(got, want);
 }
+
+/// Returns the data store directories in `dir` that were created for `version`, i.e. those named
+/// like `v0.99.1_0123456789abcdef`.
+fn datastores_for_version(dir: &Path, version: &Version) -> Vec<PathBuf> {
+    let prefix = format!("v{}_", version);
+    let mut datastores: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.is_dir()
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .map(|name| name.starts_with(&prefix))
+                    .unwrap_or(false)
+        })
+        .collect();
+    datastores.sort();
+    datastores
+}
+
+/// This test ensures that intermediate data stores are removed by default, and are retained when
+/// `keep_intermediate` is set.  See `migrate_forward` for a description of how these tests work.
+#[test]
+fn keep_intermediate() {
+    let from_version = Version::parse("0.99.0").unwrap();
+    let to_version = Version::parse("0.99.1").unwrap();
+
+    for &keep_intermediate in &[false, true] {
+        let test_datastore = TestDatastore::new(from_version.clone());
+        let test_repo = create_test_repo();
+        let args = Args {
+            datastore_path: test_datastore.datastore.clone(),
+            expected_from_version: None,
+            force_version_mismatch: false,
+            keep_intermediate,
+            log_level: log::LevelFilter::Info,
+            migration_directory: test_repo.targets_path.clone(),
+            migrate_to_version: to_version.clone(),
+            root_path: root(),
+            metadata_directory: test_repo.metadata_path.clone(),
+            metrics_path: test_datastore.tmp.path().join("migrator.prom"),
+            no_sandbox: true,
+            no_source_guard: false,
+            skip_migrations: Vec::new(),
+            cmdline_skip_migrations: Vec::new(),
+        };
+        run(
+            &args,
+            &mut RunMetrics::start(&args.migrate_to_version),
+            Interrupt::new(),
+            Limits::default(),
+        )
+        .unwrap();
+
+        // each of the two migrations creates a data store; the first is intermediate.
+        let datastores = datastores_for_version(test_datastore.tmp.path(), &to_version);
+        let expected_count = if keep_intermediate { 2 } else { 1 };
+        assert_eq!(datastores.len(), expected_count, "{:?}", datastores);
+
+        // the final data store is always kept, and the links point to it.
+        let current = fs::canonicalize(test_datastore.tmp.path().join("current")).unwrap();
+        assert!(datastores
+            .iter()
+            .any(|datastore| datastore.file_name() == current.file_name()));
+    }
+}
+
+/// The name of a test migration that writes into its source data store.
+const BAD_MIGRATION: &str = "c-bad-migration";
+
+/// Creates a script that behaves like a test migration but also writes a file into its source
+/// data store, which migrations must never do.
+fn create_bad_test_migration() -> String {
+    format!(
+        r#"{}echo "oops" > "${{3}}/oops"
+"#,
+        create_test_migration(BAD_MIGRATION)
+    )
+}
+
+/// This test ensures that a migration that modifies its source data store causes a failure, and
+/// that the check can be skipped with `no_source_guard`.
+#[test]
+fn source_guard() {
+    let from_version = Version::parse("0.99.0").unwrap();
+    let to_version = Version::parse("0.99.1").unwrap();
+
+    for &no_source_guard in &[false, true] {
+        let test_datastore = TestDatastore::new(from_version.clone());
+        let test_repo = create_test_repo_with_migrations(&[
+            (FIRST_MIGRATION, create_test_migration(FIRST_MIGRATION)),
+            (BAD_MIGRATION, create_bad_test_migration()),
+        ]);
+        let args = Args {
+            datastore_path: test_datastore.datastore.clone(),
+            expected_from_version: None,
+            force_version_mismatch: false,
+            keep_intermediate: false,
+            log_level: log::LevelFilter::Info,
+            migration_directory: test_repo.targets_path.clone(),
+            migrate_to_version: to_version.clone(),
+            root_path: root(),
+            metadata_directory: test_repo.metadata_path.clone(),
+            metrics_path: test_datastore.tmp.path().join("migrator.prom"),
+            no_sandbox: true,
+            no_source_guard,
+            skip_migrations: Vec::new(),
+            cmdline_skip_migrations: Vec::new(),
+        };
+        let result = run(
+            &args,
+            &mut RunMetrics::start(&args.migrate_to_version),
+            Interrupt::new(),
+            Limits::default(),
+        );
+        if no_source_guard {
+            result.unwrap();
+        } else {
+            match result.unwrap_err() {
+                Error::SourceDatastoreModified {
+                    migration, paths, ..
+                } => {
+                    assert_eq!(migration, BAD_MIGRATION);
+                    assert_eq!(paths, vec![PathBuf::from("oops")]);
+                }
+                e => panic!("unexpected error: {}", e),
+            }
+        }
+    }
+}
+
+/// This test ensures that missing repository directories are created, and that a missing
+/// repository produces a clear error rather than a failure to load it.
+#[test]
+fn missing_repo_directories() {
+    let from_version = Version::parse("0.99.0").unwrap();
+    let to_version = Version::parse("0.99.1").unwrap();
+    let test_datastore = TestDatastore::new(from_version);
+    let repo_dir = TempDir::new().unwrap();
+    let metadata_directory = repo_dir.path().join("metadata");
+    let migration_directory = repo_dir.path().join("migrations");
+    let args = Args {
+        datastore_path: test_datastore.datastore.clone(),
+        expected_from_version: None,
+        force_version_mismatch: false,
+        keep_intermediate: false,
+        log_level: log::LevelFilter::Info,
+        migration_directory: migration_directory.clone(),
+        migrate_to_version: to_version,
+        root_path: root(),
+        metadata_directory: metadata_directory.clone(),
+        metrics_path: repo_dir.path().join("migrator.prom"),
+        no_sandbox: true,
+        no_source_guard: false,
+        skip_migrations: Vec::new(),
+        cmdline_skip_migrations: Vec::new(),
+    };
+    match run(
+        &args,
+        &mut RunMetrics::start(&args.migrate_to_version),
+        Interrupt::new(),
+        Limits::default(),
+    )
+    .unwrap_err()
+    {
+        Error::EmptyRepository { dir } => assert_eq!(dir, metadata_directory),
+        e => panic!("unexpected error: {}", e),
+    }
+    assert!(metadata_directory.is_dir());
+    assert!(migration_directory.is_dir());
+}
+
+/// This test ensures that an existing repository directory is left alone and is reported as
+/// empty only when it has no entries.
+#[test]
+fn prepare_existing_repo_directory() {
+    let test_repo = create_test_repo();
+    assert!(!prepare_repo_directory(&test_repo.metadata_path).unwrap());
+    assert!(!prepare_repo_directory(&test_repo.targets_path).unwrap());
+
+    let empty = TempDir::new().unwrap();
+    assert!(prepare_repo_directory(empty.path()).unwrap());
+}
+
+/// The name of a test migration that runs until it's stopped.
+const SLOW_MIGRATION: &str = "slow-migration";
+
+/// Creates a script that behaves like a test migration, but records its PID in `migration.pid`
+/// next to the data store and then runs until it's stopped.
+fn create_slow_test_migration() -> String {
+    format!(
+        r#"{}echo "$$" > "${{datastore_parent_dir}}/migration.pid"
+exec sleep 60
+"#,
+        create_test_migration(SLOW_MIGRATION)
+    )
+}
+
+/// Returns every symlink in `dir` and its target, sorted.
+fn links(dir: &Path) -> Vec<(PathBuf, PathBuf)> {
+    let mut links: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| fs::symlink_metadata(path).unwrap().file_type().is_symlink())
+        .map(|path| {
+            let target = fs::read_link(&path).unwrap();
+            (path, target)
+        })
+        .collect();
+    links.sort();
+    links
+}
+
+/// This test ensures that SIGTERM stops a running migration, and that migrator then stops without
+/// changing the data store links.
+#[test]
+fn sigterm_stops_migration() {
+    let from_version = Version::parse("0.99.0").unwrap();
+    let to_version = Version::parse("0.99.1").unwrap();
+    let test_datastore = TestDatastore::new(from_version);
+    let test_repo =
+        create_test_repo_with_migrations(&[(SLOW_MIGRATION, create_slow_test_migration())]);
+    let args = Args {
+        datastore_path: test_datastore.datastore.clone(),
+        expected_from_version: None,
+        force_version_mismatch: false,
+        keep_intermediate: false,
+        log_level: log::LevelFilter::Info,
+        migration_directory: test_repo.targets_path.clone(),
+        migrate_to_version: to_version,
+        root_path: root(),
+        metadata_directory: test_repo.metadata_path.clone(),
+        metrics_path: test_datastore.tmp.path().join("migrator.prom"),
+        no_sandbox: true,
+        no_source_guard: false,
+        skip_migrations: Vec::new(),
+        cmdline_skip_migrations: Vec::new(),
+    };
+    let links_before = links(test_datastore.tmp.path());
+
+    // Send SIGTERM to this process once the migration has started.
+    let interrupt = Interrupt::on_sigterm().unwrap();
+    let pid_path = test_datastore.tmp.path().join("migration.pid");
+    let signaller = {
+        let pid_path = pid_path.clone();
+        thread::spawn(move || {
+            let deadline = Instant::now() + Duration::from_secs(30);
+            while !pid_path.exists() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(50));
+            }
+            signal::kill(unistd::getpid(), Signal::SIGTERM).unwrap();
+        })
+    };
+
+    let started = Instant::now();
+    let result = run(
+        &args,
+        &mut RunMetrics::start(&args.migrate_to_version),
+        interrupt,
+        Limits::default(),
+    );
+    signaller.join().unwrap();
+    assert!(matches!(result, Err(Error::Interrupted)));
+    assert!(started.elapsed() < Duration::from_secs(30));
+
+    // The migration was stopped and reaped, and the links weren't touched.
+    let pid: i32 = fs::read_to_string(&pid_path)
+        .unwrap()
+        .trim()
+        .parse()
+        .unwrap();
+    assert_eq!(
+        signal::kill(Pid::from_raw(pid), None),
+        Err(nix::Error::Sys(Errno::ESRCH))
+    );
+    assert_eq!(links(test_datastore.tmp.path()), links_before);
+}
+
+/// Returns `Args` that migrate the test data store from 0.99.0 to 0.99.1 with the test repo.
+fn forward_args(test_datastore: &TestDatastore, test_repo: &TestRepo) -> Args {
+    Args {
+        datastore_path: test_datastore.datastore.clone(),
+        expected_from_version: None,
+        force_version_mismatch: false,
+        keep_intermediate: false,
+        log_level: log::LevelFilter::Info,
+        migration_directory: test_repo.targets_path.clone(),
+        migrate_to_version: Version::parse("0.99.1").unwrap(),
+        root_path: root(),
+        metadata_directory: test_repo.metadata_path.clone(),
+        metrics_path: test_datastore.tmp.path().join("migrator.prom"),
+        no_sandbox: true,
+        no_source_guard: false,
+        skip_migrations: Vec::new(),
+        cmdline_skip_migrations: Vec::new(),
+    }
+}
+
+/// Returns a test migration padded with a comment of `len` bytes, made from `padding`.
+fn create_padded_test_migration<F>(name: &str, len: usize, padding: F) -> String
+where
+    F: FnMut(usize) -> char,
+{
+    let padding: String = (0..len).map(padding).collect();
+    format!("{}# {}\n", create_test_migration(name), padding)
+}
+
+/// Returns pseudo-random alphanumeric characters, which LZ4 can't compress much.  Each index is
+/// mixed with the splitmix64 finalizer, whose high bits pick the character, so the output has no
+/// repeats for LZ4 to find.
+fn noise(i: usize) -> char {
+    const CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+    let mut x = (i as u64).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^= x >> 31;
+    CHARS[((x >> 32) % CHARS.len() as u64) as usize] as char
+}
+
+/// This test ensures that migrator refuses a transition that lists more migrations than the limit,
+/// before running any of them.
+#[test]
+fn too_many_migrations() {
+    let test_datastore = TestDatastore::new(Version::parse("0.99.0").unwrap());
+    let names = ["m1", "m2", "m3"];
+    let migrations: Vec<(&str, String)> = names
+        .iter()
+        .map(|name| (*name, create_test_migration(name)))
+        .collect();
+    let test_repo = create_test_repo_with_migrations(&migrations);
+    let args = forward_args(&test_datastore, &test_repo);
+    let links_before = links(test_datastore.tmp.path());
+
+    let limits = Limits {
+        max_migrations: 2,
+        ..Limits::default()
+    };
+    let mut metrics = RunMetrics::start(&args.migrate_to_version);
+    let result = run(&args, &mut metrics, Interrupt::new(), limits);
+    assert!(matches!(
+        result,
+        Err(Error::TooManyMigrations { count: 3, max: 2 })
+    ));
+    assert_eq!(metrics.migrations_run, 0);
+    assert!(!test_datastore.tmp.path().join("result.txt").exists());
+    assert_eq!(links(test_datastore.tmp.path()), links_before);
+
+    // the same transition is fine within the limit.
+    let limits = Limits {
+        max_migrations: 3,
+        ..Limits::default()
+    };
+    run(&args, &mut metrics, Interrupt::new(), limits).unwrap();
+    assert_eq!(metrics.migrations_run, 3);
+}
+
+/// This test ensures that skipped migrations don't run, that the rest run in their listed order,
+/// and that asking to skip a migration that isn't in the plan fails before running any, unless
+/// the name comes from the kernel command line.
+#[test]
+fn skip_migrations() {
+    let test_datastore = TestDatastore::new(Version::parse("0.99.0").unwrap());
+    let names = ["m3", "m1", "m2"];
+    let migrations: Vec<(&str, String)> = names
+        .iter()
+        .map(|name| (*name, create_test_migration(name)))
+        .collect();
+    let test_repo = create_test_repo_with_migrations(&migrations);
+    let links_before = links(test_datastore.tmp.path());
+    let output_file = test_datastore.tmp.path().join("result.txt");
+
+    let args = Args {
+        skip_migrations: vec!["m1".to_string(), "m4".to_string()],
+        ..forward_args(&test_datastore, &test_repo)
+    };
+    let mut metrics = RunMetrics::start(&args.migrate_to_version);
+    let result = run(&args, &mut metrics, Interrupt::new(), Limits::default());
+    match result {
+        Err(Error::SkipMigrationNotInPlan { names }) => assert_eq!(names, vec!["m4"]),
+        _ => panic!("expected SkipMigrationNotInPlan, got {:?}", result),
+    }
+    assert_eq!(metrics.migrations_run, 0);
+    assert!(!output_file.exists());
+    assert_eq!(links(test_datastore.tmp.path()), links_before);
+
+    let args = Args {
+        skip_migrations: vec!["m1".to_string()],
+        ..forward_args(&test_datastore, &test_repo)
+    };
+    let mut metrics = RunMetrics::start(&args.migrate_to_version);
+    run(&args, &mut metrics, Interrupt::new(), Limits::default()).unwrap();
+    assert_eq!(metrics.migrations_run, 2);
+    assert_eq!(metrics.migrations_skipped, 1);
+    let ran: Vec<String> = fs::read_to_string(&output_file)
+        .unwrap()
+        .lines()
+        .map(|line| line.split(':').next().unwrap().to_string())
+        .collect();
+    assert_eq!(ran, vec!["m3", "m2"]);
+
+    // a name left on the kernel command line from an earlier update doesn't stop this one.
+    let test_datastore = TestDatastore::new(Version::parse("0.99.0").unwrap());
+    let output_file = test_datastore.tmp.path().join("result.txt");
+    let args = Args {
+        cmdline_skip_migrations: vec!["m2".to_string(), "m4".to_string()],
+        ..forward_args(&test_datastore, &test_repo)
+    };
+    let mut metrics = RunMetrics::start(&args.migrate_to_version);
+    run(&args, &mut metrics, Interrupt::new(), Limits::default()).unwrap();
+    assert_eq!(metrics.migrations_run, 2);
+    assert_eq!(metrics.migrations_skipped, 1);
+    let ran: Vec<String> = fs::read_to_string(&output_file)
+        .unwrap()
+        .lines()
+        .map(|line| line.split(':').next().unwrap().to_string())
+        .collect();
+    assert_eq!(ran, vec!["m3", "m1"]);
+}
+
+/// This test ensures that migrator refuses a migration whose compressed target is larger than the
+/// limit.
+#[test]
+fn oversized_migration() {
+    const MAX_COMPRESSED_SIZE: u64 = 4 * 1024;
+    let test_datastore = TestDatastore::new(Version::parse("0.99.0").unwrap());
+    let migration = create_padded_test_migration(FIRST_MIGRATION, 16 * 1024, noise);
+
+    // make sure the padding really doesn't compress below the limit, or the test proves nothing.
+    let compressed = TempDir::new().unwrap();
+    let compressed_path = compressed.path().join(FIRST_MIGRATION);
+    compress(migration.as_bytes(), &compressed_path);
+    let compressed_size = fs::metadata(&compressed_path).unwrap().len();
+    assert!(
+        compressed_size > MAX_COMPRESSED_SIZE,
+        "compressed migration is only {} bytes",
+        compressed_size
+    );
+
+    let test_repo = create_test_repo_with_migrations(&[(FIRST_MIGRATION, migration)]);
+    let args = forward_args(&test_datastore, &test_repo);
+    let limits = Limits {
+        max_compressed_size: MAX_COMPRESSED_SIZE,
+        ..Limits::default()
+    };
+    let result = run(
+        &args,
+        &mut RunMetrics::start(&args.migrate_to_version),
+        Interrupt::new(),
+        limits,
+    );
+    match result {
+        Err(Error::MigrationTooLarge { migration, max }) => {
+            assert_eq!(migration, FIRST_MIGRATION);
+            assert_eq!(max, MAX_COMPRESSED_SIZE);
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert!(!test_datastore.tmp.path().join("result.txt").exists());
+}
+
+/// This test ensures that migrator refuses a migration that's small when compressed but larger
+/// than the limit when decompressed.
+#[test]
+fn oversized_decompressed_migration() {
+    let test_datastore = TestDatastore::new(Version::parse("0.99.0").unwrap());
+    let test_repo = create_test_repo_with_migrations(&[(
+        FIRST_MIGRATION,
+        create_padded_test_migration(FIRST_MIGRATION, 64 * 1024, |_| 'a'),
+    )]);
+    let args = forward_args(&test_datastore, &test_repo);
+    let limits = Limits {
+        max_compressed_size: 4 * 1024,
+        max_decompressed_size: 16 * 1024,
+        ..Limits::default()
+    };
+    let result = run(
+        &args,
+        &mut RunMetrics::start(&args.migrate_to_version),
+        Interrupt::new(),
+        limits,
+    );
+    assert!(matches!(
+        result,
+        Err(Error::MigrationDecompressedTooLarge { max, .. }) if max == 16 * 1024
+    ));
+    assert!(!test_datastore.tmp.path().join("result.txt").exists());
+}
+
+/// Returns whether the tests run as root, which the sandbox requires, and notes it if they don't.
+fn running_as_root() -> bool {
+    let is_root = unistd::geteuid().is_root();
+    if !is_root {
+        eprintln!("Not running as root, skipping sandbox test");
+    }
+    is_root
+}
+
+/// The name of a test migration that checks what it can see.
+const SANDBOX_MIGRATION: &str = "d-sandbox-migration";
+
+/// Creates a script that fails unless it runs in the sandbox: it must not be able to read /etc or
+/// write its source data store, but must be able to read its source and write its target, and use
+/// the devices that are in the sandbox.
+fn create_sandbox_test_migration() -> String {
+    r#"#!/usr/bin/env bash
+set -eo pipefail
+if ls /etc > /dev/null 2>&1; then
+    echo "/etc is visible" >&2
+    exit 1
+fi
+if touch "${3}/probe" 2> /dev/null; then
+    echo "source data store is writable" >&2
+    exit 1
+fi
+ls "${3}" > /dev/null
+head -c 16 /dev/urandom > /tmp/random
+head -c 16 /dev/zero > /tmp/zeros
+[[ "$(stat -c %s /tmp/random /tmp/zeros)" == $'16\n16' ]]
+echo "sandboxed" > "${5}/sandbox.txt"
+"#
+    .to_string()
+}
+
+/// This test ensures that a migration only sees its data stores in the sandbox, and that the usual
+/// migration flow still succeeds.  The same migration fails without the sandbox.
+#[test]
+fn sandboxed_migration() {
+    if !running_as_root() {
+        return;
+    }
+    let to_version = Version::parse("0.99.1").unwrap();
+    for &no_sandbox in &[false, true] {
+        let test_datastore = TestDatastore::new(Version::parse("0.99.0").unwrap());
+        let test_repo = create_test_repo_with_migrations(&[(
+            SANDBOX_MIGRATION,
+            create_sandbox_test_migration(),
+        )]);
+        let args = Args {
+            no_sandbox,
+            ..forward_args(&test_datastore, &test_repo)
+        };
+        let mut metrics = RunMetrics::start(&args.migrate_to_version);
+        let result = run(&args, &mut metrics, Interrupt::new(), Limits::default());
+        if no_sandbox {
+            assert!(matches!(result, Err(Error::MigrationFailure { .. })));
+            continue;
+        }
+        result.unwrap();
+        assert_eq!(metrics.migrations_run, 1);
+        let datastores = datastores_for_version(test_datastore.tmp.path(), &to_version);
+        assert_eq!(datastores.len(), 1);
+        assert_eq!(
+            fs::read_to_string(datastores[0].join("sandbox.txt")).unwrap(),
+            "sandboxed\n"
+        );
+        // the mounts only existed in the migration's namespace, so nothing was left mounted over
+        // the source data store.
+        assert!(!test_datastore.datastore.join("probe").exists());
+    }
+}
+
+/// This test ensures that migrator refuses to migrate a data store whose version doesn't agree with
+/// the expected one, before running any migrations.
+#[test]
+fn expected_from_version_mismatch() {
+    let test_datastore = TestDatastore::new(Version::parse("0.99.0").unwrap());
+    let test_repo = create_test_repo();
+    let args = Args {
+        expected_from_version: Some(Version::parse("0.98.0").unwrap()),
+        ..forward_args(&test_datastore, &test_repo)
+    };
+    let result = run(
+        &args,
+        &mut RunMetrics::start(&args.migrate_to_version),
+        Interrupt::new(),
+        Limits::default(),
+    );
+    assert!(matches!(result, Err(Error::FromVersionMismatch { .. })));
+    assert!(!test_datastore.tmp.path().join("result.txt").exists());
+}
+
+/// This test ensures that migrator explains that the sandbox requires root, rather than failing to
+/// set it up, when it doesn't run as root.
+#[test]
+fn sandbox_requires_root() {
+    if unistd::geteuid().is_root() {
+        return;
+    }
+    let test_datastore = TestDatastore::new(Version::parse("0.99.0").unwrap());
+    let test_repo = create_test_repo_with_migrations(&[(
+        FIRST_MIGRATION,
+        create_test_migration(FIRST_MIGRATION),
+    )]);
+    let args = Args {
+        no_sandbox: false,
+        ..forward_args(&test_datastore, &test_repo)
+    };
+    let result = run(
+        &args,
+        &mut RunMetrics::start(&args.migrate_to_version),
+        Interrupt::new(),
+        Limits::default(),
+    );
+    assert!(matches!(result, Err(Error::SandboxRequiresRoot)));
+    assert!(!test_datastore.tmp.path().join("result.txt").exists());
+}
@@ -1,4 +1,8 @@
-pub(super) use inner::{get_aws_k8s_info, Error};
+use crate::proxy::ProxySettings;
+use async_trait::async_trait;
+pub(super) use inner::{
+    get_aws_k8s_info, get_cluster_dns_ip, get_proxy_settings, missing_setting_error, Error,
+};
 
 /// The result type for the [`api`] module.
 pub(super) type Result<T> = std::result::Result<T, Error>;
@@ -9,12 +13,88 @@ pub(crate) struct AwsK8sInfo {
     pub(crate) cluster_name: String,
 }
 
+/// The longest cluster name that EKS accepts.
+const MAX_CLUSTER_NAME_LEN: usize = 100;
+
+/// Returns the cluster name that EKS expects for the `cluster-name` setting `name`, or `None` if
+/// it can't be a valid EKS cluster name.  Surrounding whitespace is removed, and if `name` is a
+/// cluster ARN like `arn:aws:eks:us-west-2:111122223333:cluster/my-cluster`, the name is taken
+/// from it.  Valid names match EKS's pattern: up to 100 ASCII letters, digits, hyphens, and
+/// underscores, starting with a letter or digit.
+// The settings are only read from the API in aws-k8s variants.
+#[cfg_attr(not(aws_k8s_variant), allow(dead_code))]
+pub(crate) fn normalize_cluster_name(name: &str) -> Option<String> {
+    let name = name.trim();
+    let name = if name.starts_with("arn:") {
+        // arn:partition:eks:region:account:cluster/name
+        let resource = name.splitn(6, ':').nth(5)?;
+        let mut parts = resource.splitn(2, '/');
+        match (parts.next(), parts.next()) {
+            (Some("cluster"), Some(name)) => name,
+            _ => return None,
+        }
+    } else {
+        name
+    };
+
+    let mut chars = name.chars();
+    let valid = name.len() <= MAX_CLUSTER_NAME_LEN
+        && chars.next().map_or(false, |c| c.is_ascii_alphanumeric())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Some(name.to_string())
+    } else {
+        None
+    }
+}
+
+/// Returns the value at the dotted `key_path` in `settings`, a response from the `/settings` API,
+/// or `None` if there's nothing there, e.g. `"my-cluster"` at `kubernetes.cluster-name` in
+/// `{"kubernetes": {"cluster-name": "my-cluster"}}`.  A null value counts as nothing.
+// The settings are only read from the API in aws-k8s variants.
+#[cfg_attr(not(aws_k8s_variant), allow(dead_code))]
+fn setting_value<'a>(
+    settings: &'a serde_json::Value,
+    key_path: &str,
+) -> Option<&'a serde_json::Value> {
+    key_path
+        .split('.')
+        .try_fold(settings, |value, key| value.get(key))
+        .filter(|value| !value.is_null())
+}
+
+/// A source of the settings pluto needs from the Bottlerocket API. This allows tests to supply the
+/// settings without an API server.
+#[async_trait]
+pub(crate) trait SettingsSource {
+    /// Returns the info that we need to know about the EKS cluster.
+    async fn aws_k8s_info(&self) -> Result<AwsK8sInfo>;
+
+    /// Returns the `cluster-dns-ip` setting, or `None` if it isn't set.
+    async fn cluster_dns_ip(&self) -> Result<Option<String>>;
+}
+
+/// Gets settings from the Bottlerocket API.
+pub(crate) struct ApiSettings;
+
+#[async_trait]
+impl SettingsSource for ApiSettings {
+    async fn aws_k8s_info(&self) -> Result<AwsK8sInfo> {
+        get_aws_k8s_info().await
+    }
+
+    async fn cluster_dns_ip(&self) -> Result<Option<String>> {
+        get_cluster_dns_ip().await
+    }
+}
+
 /// This code is the 'actual' implementation compiled when the `sources` workspace is being compiled
 /// for `aws-k8s-*` variants.
 // TODO - find a better way https://github.com/bottlerocket-os/bottlerocket/issues/1260
 #[cfg(aws_k8s_variant)]
 mod inner {
     use super::*;
+    use serde::de::DeserializeOwned;
     use snafu::{OptionExt, ResultExt, Snafu};
 
     // FIXME Get these from configuration in the future
@@ -29,45 +109,94 @@ mod inner {
             uri: String,
         },
 
+        #[snafu(display(
+            "The cluster-name setting '{}' is not a valid EKS cluster name or cluster ARN",
+            name
+        ))]
+        InvalidClusterName { name: String },
+
         #[snafu(display("The '{}' setting is missing", setting))]
         Missing { setting: String },
 
         #[snafu(display("Unable to deserialize Bottlerocket settings: {}", source))]
         SettingsJson { source: serde_json::Error },
+
+        #[snafu(display("The '{}' setting is not of the expected type: {}", setting, source))]
+        WrongType {
+            setting: String,
+            source: serde_json::Error,
+        },
     }
 
-    /// Gets the Bottlerocket settings from the API and deserializes them into a struct.
-    async fn get_settings() -> Result<model::Settings> {
+    /// Gets the setting at the dotted `key_path` under `settings`, e.g. `kubernetes.cluster-name`,
+    /// from the Bottlerocket API and deserializes it.
+    async fn get_setting<T: DeserializeOwned>(key_path: &str) -> Result<T> {
+        let uri = format!("{}?keys=settings.{}", SETTINGS_URI, key_path);
         let (_status, response_body) =
-            apiclient::raw_request(DEFAULT_API_SOCKET, SETTINGS_URI, "GET", None)
+            apiclient::raw_request(DEFAULT_API_SOCKET, &uri, "GET", None)
                 .await
-                .context(ApiClient { uri: SETTINGS_URI })?;
+                .context(ApiClient { uri: &uri })?;
+        let settings: serde_json::Value =
+            serde_json::from_str(&response_body).context(SettingsJson)?;
+        let value = setting_value(&settings, key_path).context(Missing { setting: key_path })?;
+        serde_json::from_value(value.clone()).context(WrongType { setting: key_path })
+    }
+
+    /// Like `get_setting`, but returns `None` if the setting isn't set.
+    async fn get_optional_setting<T: DeserializeOwned>(key_path: &str) -> Result<Option<T>> {
+        match get_setting(key_path).await {
+            Ok(value) => Ok(Some(value)),
+            Err(Error::Missing { .. }) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
 
-        serde_json::from_str(&response_body).context(SettingsJson)
+    /// Gets the `kubernetes.cluster-name` setting from the Bottlerocket API.
+    async fn get_cluster_name() -> Result<String> {
+        get_setting("kubernetes.cluster-name").await
     }
 
     /// Gets the info that we need to know about the EKS cluster from the Bottlerocket API.
     pub(crate) async fn get_aws_k8s_info() -> Result<AwsK8sInfo> {
-        let settings = get_settings().await?;
+        let setting_name = get_cluster_name().await?;
+        // Catch names that EKS would reject with an opaque validation error.
+        let cluster_name = normalize_cluster_name(&setting_name).context(InvalidClusterName {
+            name: &setting_name,
+        })?;
+        if cluster_name != setting_name {
+            eprintln!(
+                "Using cluster name '{}' for the cluster-name setting '{}'",
+                cluster_name, setting_name
+            );
+        }
         Ok(AwsK8sInfo {
-            region: settings
-                .aws
-                .context(Missing { setting: "aws" })?
-                .region
-                .context(Missing { setting: "region" })?
-                .into(),
-            cluster_name: settings
-                .kubernetes
-                .context(Missing {
-                    setting: "kubernetes",
-                })?
-                .cluster_name
-                .context(Missing {
-                    setting: "cluster-name",
-                })?
-                .into(),
+            region: get_setting("aws.region").await?,
+            cluster_name,
+        })
+    }
+
+    /// Gets the `cluster-dns-ip` setting from the Bottlerocket API, if it's set.
+    pub(crate) async fn get_cluster_dns_ip() -> Result<Option<String>> {
+        get_optional_setting("kubernetes.cluster-dns-ip").await
+    }
+
+    /// Gets the `network.https-proxy` and `network.no-proxy` settings from the Bottlerocket API.
+    pub(crate) async fn get_proxy_settings() -> Result<ProxySettings> {
+        Ok(ProxySettings {
+            https_proxy: get_optional_setting("network.https-proxy").await?,
+            no_proxy: get_optional_setting("network.no-proxy")
+                .await?
+                .unwrap_or_default(),
         })
     }
+
+    /// Returns the error for a missing setting, for other sources of settings, like tests that mock
+    /// the API and metadata snapshots.
+    pub(crate) fn missing_setting_error(setting: &str) -> Error {
+        Error::Missing {
+            setting: setting.to_string(),
+        }
+    }
 }
 
 /// This dummy code is compiled when the `sources` workspace is being compiled for non `aws-k8s-*`
@@ -89,4 +218,125 @@ mod inner {
     pub(crate) async fn get_aws_k8s_info() -> Result<AwsK8sInfo> {
         WrongVariant.fail()
     }
+
+    pub(crate) async fn get_cluster_dns_ip() -> Result<Option<String>> {
+        WrongVariant.fail()
+    }
+
+    pub(crate) async fn get_proxy_settings() -> Result<ProxySettings> {
+        WrongVariant.fail()
+    }
+
+    /// Returns the error for a missing setting, for other sources of settings, like tests that mock
+    /// the API and metadata snapshots.  Settings are never available from the API in these
+    /// variants, so this is the same error as for any other setting.
+    pub(crate) fn missing_setting_error(_setting: &str) -> Error {
+        Error::WrongVariant
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn setting_values() {
+        let settings = json!({
+            "kubernetes": {"cluster-name": "my-cluster", "cluster-dns-ip": null},
+            "network": {"no-proxy": ["localhost", ".amazonaws.com"]},
+            "aws": {"region": "us-west-2"},
+        });
+        assert_eq!(
+            setting_value(&settings, "kubernetes.cluster-name"),
+            Some(&json!("my-cluster"))
+        );
+        assert_eq!(
+            setting_value(&settings, "network.no-proxy"),
+            Some(&json!(["localhost", ".amazonaws.com"]))
+        );
+        assert_eq!(
+            setting_value(&settings, "aws"),
+            Some(&json!({"region": "us-west-2"}))
+        );
+    }
+
+    #[test]
+    fn missing_setting_values() {
+        let settings = json!({
+            "kubernetes": {"cluster-name": "my-cluster", "cluster-dns-ip": null},
+        });
+        for key_path in &[
+            "kubernetes.cluster-dns-ip",
+            "kubernetes.max-pods",
+            "network.https-proxy",
+            // a path can't go through a value that isn't an object.
+            "kubernetes.cluster-name.length",
+            "kubernetes.",
+            "",
+        ] {
+            assert_eq!(setting_value(&settings, key_path), None, "{}", key_path);
+        }
+        assert_eq!(setting_value(&json!({}), "kubernetes.cluster-name"), None);
+    }
+
+    #[test]
+    fn plain_cluster_names() {
+        for name in &["my-cluster", "My_Cluster-2", "0cluster", "c"] {
+            assert_eq!(normalize_cluster_name(name).as_deref(), Some(*name));
+        }
+    }
+
+    #[test]
+    fn padded_cluster_names() {
+        assert_eq!(
+            normalize_cluster_name("  my-cluster\n").as_deref(),
+            Some("my-cluster")
+        );
+        assert_eq!(
+            normalize_cluster_name("\tmy-cluster ").as_deref(),
+            Some("my-cluster")
+        );
+    }
+
+    #[test]
+    fn cluster_arns() {
+        assert_eq!(
+            normalize_cluster_name("arn:aws:eks:us-west-2:111122223333:cluster/my-cluster")
+                .as_deref(),
+            Some("my-cluster")
+        );
+        assert_eq!(
+            normalize_cluster_name(" arn:aws-cn:eks:cn-north-1:111122223333:cluster/prod_1 ")
+                .as_deref(),
+            Some("prod_1")
+        );
+        for arn in &[
+            "arn:aws:eks:us-west-2:111122223333:nodegroup/my-cluster/ng/abc",
+            "arn:aws:eks:us-west-2:111122223333:cluster/",
+            "arn:aws:eks:us-west-2:111122223333",
+        ] {
+            assert_eq!(normalize_cluster_name(arn), None, "{}", arn);
+        }
+    }
+
+    #[test]
+    fn invalid_cluster_names() {
+        let too_long = "a".repeat(MAX_CLUSTER_NAME_LEN + 1);
+        for name in &[
+            "",
+            "   ",
+            "-cluster",
+            "_cluster",
+            "my cluster",
+            "my.cluster",
+            "my/cluster",
+            "clüster",
+            too_long.as_str(),
+        ] {
+            assert_eq!(normalize_cluster_name(name), None, "{:?}", name);
+        }
+        let longest = "a".repeat(MAX_CLUSTER_NAME_LEN);
+        assert_eq!(normalize_cluster_name(&longest), Some(longest));
+    }
 }
@@ -0,0 +1,133 @@
+//! Provides `GeneratorContext`, which holds the sources of information that the setting generators
+//! share, so that each is set up in one place.  The IMDS client and the region and cluster name
+//! from the Bottlerocket API are fetched the first time they're needed and then reused.  In offline
+//! mode, every source is a metadata snapshot instead.
+
+use crate::api::{AwsK8sInfo, SettingsSource};
+use crate::ec2::{Ec2Api, InstanceTypeSource};
+use crate::eks::ClusterCidrSource;
+use crate::error;
+use crate::imds::ImdsSource;
+use crate::snapshot::MetadataSnapshot;
+use crate::Result;
+use imdsclient::ImdsClient;
+use snafu::ResultExt;
+use std::collections::HashMap;
+
+pub(crate) struct GeneratorContext<'a> {
+    imds: Option<Box<dyn ImdsSource + 'a>>,
+    settings: &'a dyn SettingsSource,
+    eks: &'a dyn ClusterCidrSource,
+    ec2: &'a dyn InstanceTypeSource,
+    /// The maximum number of pods by instance type, if it doesn't come from the eni-max-pods file.
+    max_pods: Option<&'a HashMap<String, u32>>,
+    aws_k8s_info: Option<AwsK8sInfo>,
+}
+
+impl<'a> GeneratorContext<'a> {
+    /// Creates a context that gets settings from `settings` and service CIDRs from `eks`.  The
+    /// IMDS client is created when it's first needed, and instance type limits come from EC2.
+    pub(crate) fn new(settings: &'a dyn SettingsSource, eks: &'a dyn ClusterCidrSource) -> Self {
+        Self {
+            imds: None,
+            settings,
+            eks,
+            ec2: &Ec2Api,
+            max_pods: None,
+            aws_k8s_info: None,
+        }
+    }
+
+    /// Creates a context that gets everything from `snapshot`, without IMDS, EKS, or the API.
+    pub(crate) fn from_snapshot(snapshot: &'a MetadataSnapshot) -> Self {
+        Self {
+            imds: Some(Box::new(snapshot)),
+            ec2: snapshot,
+            max_pods: Some(snapshot.max_pods()),
+            ..Self::new(snapshot, snapshot)
+        }
+    }
+
+    /// Creates a context that uses an existing IMDS client, e.g. one pointed at a mock server.
+    #[cfg(test)]
+    pub(crate) fn with_imds(
+        imds: ImdsClient,
+        settings: &'a dyn SettingsSource,
+        eks: &'a dyn ClusterCidrSource,
+    ) -> Self {
+        Self {
+            imds: Some(Box::new(imds)),
+            ..Self::new(settings, eks)
+        }
+    }
+
+    /// Returns the source of instance metadata, creating an IMDS client and connecting to IMDS the
+    /// first time if there's no other source.
+    pub(crate) async fn imds(&mut self) -> Result<&mut (dyn ImdsSource + 'a)> {
+        let source: Box<dyn ImdsSource + 'a> = match self.imds.take() {
+            Some(source) => source,
+            None => {
+                let client = ImdsClient::new();
+                client.connect().await.context(error::ImdsClient)?;
+                Box::new(client)
+            }
+        };
+        Ok(self.imds.get_or_insert(source).as_mut())
+    }
+
+    /// Returns the maximum number of pods by instance type, or `None` if it should be read from
+    /// the eni-max-pods file.
+    pub(crate) fn max_pods(&self) -> Option<&'a HashMap<String, u32>> {
+        self.max_pods
+    }
+
+    /// Uses `ec2` for instance type limits rather than EC2 itself.
+    #[cfg(test)]
+    pub(crate) fn with_ec2(self, ec2: &'a dyn InstanceTypeSource) -> Self {
+        Self { ec2, ..self }
+    }
+
+    /// Returns the source of instance type limits.
+    pub(crate) fn ec2(&self) -> &'a dyn InstanceTypeSource {
+        self.ec2
+    }
+
+    /// Returns the source of service CIDRs.
+    pub(crate) fn eks(&self) -> &'a dyn ClusterCidrSource {
+        self.eks
+    }
+
+    /// Returns the region, fetching it and the cluster name from the Bottlerocket API the first
+    /// time either is needed.
+    pub(crate) async fn region(&mut self) -> Result<String> {
+        Ok(self.aws_k8s_info().await?.region.clone())
+    }
+
+    /// Returns the cluster name, fetching it and the region from the Bottlerocket API the first
+    /// time either is needed.
+    pub(crate) async fn cluster_name(&mut self) -> Result<String> {
+        Ok(self.aws_k8s_info().await?.cluster_name.clone())
+    }
+
+    /// Returns the `cluster-dns-ip` setting from the Bottlerocket API, or `None` if it isn't set.
+    pub(crate) async fn cluster_dns_ip_setting(&self) -> Result<Option<String>> {
+        self.settings
+            .cluster_dns_ip()
+            .await
+            .context(error::ClusterDnsIpSetting)
+    }
+
+    /// Returns the cluster info from the Bottlerocket API.  Failures aren't remembered, so a later
+    /// call tries the API again.
+    async fn aws_k8s_info(&mut self) -> Result<&AwsK8sInfo> {
+        let info = match self.aws_k8s_info.take() {
+            Some(info) => info,
+            None => self
+                .settings
+                .aws_k8s_info()
+                .await
+                .context(error::AwsK8sInfo)?,
+        };
+        Ok(self.aws_k8s_info.get_or_insert(info))
+    }
+}
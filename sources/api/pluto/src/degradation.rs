@@ -0,0 +1,130 @@
+//! Collects the fallbacks that pluto uses while generating a setting, so that they can be
+//! summarized in a single stderr line at exit.  The individual warnings are still printed as they
+//! happen, but when several fallbacks fire, the summary ties them to the value that was finally
+//! generated.
+
+use std::fmt;
+
+/// A fallback that was used because a source of information failed.
+#[derive(Debug)]
+struct Degradation {
+    /// The fallback that was used instead, e.g. `IMDS-CIDR`.
+    fallback: String,
+    /// The source that failed, e.g. `eks`.
+    source: String,
+    /// Why the source failed.
+    reason: String,
+}
+
+impl fmt::Display for Degradation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "used {} fallback ({}: {})",
+            self.fallback, self.source, self.reason
+        )
+    }
+}
+
+/// The fallbacks used while generating one setting, and the value that was generated, if any.
+#[derive(Debug, Default)]
+pub(crate) struct DegradationReport {
+    setting: String,
+    events: Vec<Degradation>,
+    value: Option<String>,
+}
+
+impl DegradationReport {
+    pub(crate) fn new<S: Into<String>>(setting: S) -> Self {
+        Self {
+            setting: setting.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Records that `fallback` was used because `source` failed with `reason`.
+    pub(crate) fn record<S1, S2, R>(&mut self, fallback: S1, source: S2, reason: R)
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+        R: fmt::Display,
+    {
+        self.events.push(Degradation {
+            fallback: fallback.into(),
+            source: source.into(),
+            reason: reason.to_string(),
+        });
+    }
+
+    /// Records the value that was generated for the setting.
+    pub(crate) fn set_value<S: Into<String>>(&mut self, value: S) {
+        self.value = Some(value.into());
+    }
+
+    /// Returns true if no fallbacks were used.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Returns the summary line, or `None` if no fallbacks were used.
+    pub(crate) fn summary(&self) -> Option<String> {
+        if self.is_empty() {
+            return None;
+        }
+        let events: Vec<String> = self.events.iter().map(|event| event.to_string()).collect();
+        let value = match &self.value {
+            Some(value) => format!("value={}", value),
+            None => "no value generated".to_string(),
+        };
+        Some(format!(
+            "{}: {}; {}",
+            self.setting,
+            events.join("; "),
+            value
+        ))
+    }
+
+    /// Prints the summary line to stderr if any fallbacks were used.  It must never go to stdout,
+    /// where sundog reads the generated setting.
+    pub(crate) fn print_summary(&self) {
+        if let Some(summary) = self.summary() {
+            eprintln!("{}", summary);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn no_fallbacks() {
+        let mut report = DegradationReport::new("cluster-dns-ip");
+        report.set_value("10.100.0.10");
+        assert!(report.is_empty());
+        assert_eq!(report.summary(), None);
+    }
+
+    #[test]
+    fn one_fallback() {
+        let mut report = DegradationReport::new("cluster-dns-ip");
+        report.record("IMDS-CIDR", "eks", "timeout after 10s");
+        report.set_value("172.20.0.10");
+        assert_eq!(
+            report.summary().unwrap(),
+            "cluster-dns-ip: used IMDS-CIDR fallback (eks: timeout after 10s); value=172.20.0.10"
+        );
+    }
+
+    #[test]
+    fn several_fallbacks_without_value() {
+        let mut report = DegradationReport::new("cluster-dns-ips");
+        report.record("IMDS-CIDR", "eks", "timeout after 10s");
+        report.record("IMDS", "identity-document", "file is malformed");
+        assert_eq!(
+            report.summary().unwrap(),
+            "cluster-dns-ips: used IMDS-CIDR fallback (eks: timeout after 10s); \
+             used IMDS fallback (identity-document: file is malformed); no value generated"
+        );
+    }
+}
@@ -0,0 +1,144 @@
+use async_trait::async_trait;
+use rusoto_core::region::ParseRegionError;
+use rusoto_core::{Region, RusotoError};
+use rusoto_ec2::{DescribeInstanceTypesError, Ec2, Ec2Client};
+use snafu::{OptionExt, ResultExt, Snafu};
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+#[derive(Debug, Snafu)]
+pub(super) enum Error {
+    #[snafu(display("Error describing instance type: {}", source))]
+    DescribeInstanceTypes {
+        source: RusotoError<DescribeInstanceTypesError>,
+    },
+
+    #[snafu(display("Missing field '{}' EC2 response", field))]
+    Missing { field: &'static str },
+
+    #[snafu(display("Invalid '{}' in EC2 response: {}", field, value))]
+    Invalid { field: &'static str, value: i64 },
+
+    #[snafu(display("Unable to parse '{}' as a region: {}", region, source))]
+    RegionParse {
+        region: String,
+        source: ParseRegionError,
+    },
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// The network interface limits of an instance type, which bound how many pods can get an address
+/// from the VPC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct EniLimits {
+    pub(super) max_enis: u32,
+    pub(super) ipv4_addresses_per_eni: u32,
+}
+
+impl EniLimits {
+    /// Returns the maximum number of pods, by the same formula as the eni-max-pods file:
+    /// `# of ENI * (# of IPv4 per ENI - 1) + 2`.  Each ENI's primary address isn't available to
+    /// pods, and two host-network pods are allowed for.
+    pub(super) fn max_pods(&self) -> u32 {
+        self.max_enis * self.ipv4_addresses_per_eni.saturating_sub(1) + 2
+    }
+}
+
+/// A source of the network interface limits of instance types. This allows tests to supply the
+/// limits without calling EC2.
+#[async_trait]
+pub(super) trait InstanceTypeSource {
+    /// Returns the network interface limits of `instance_type` in `region`.
+    async fn eni_limits(&self, region: &str, instance_type: &str) -> Result<EniLimits>;
+}
+
+/// Gets network interface limits by calling the EC2 API.
+pub(super) struct Ec2Api;
+
+#[async_trait]
+impl InstanceTypeSource for Ec2Api {
+    async fn eni_limits(&self, region: &str, instance_type: &str) -> Result<EniLimits> {
+        get_eni_limits(region, instance_type).await
+    }
+}
+
+/// Returns the network interface limits of `instance_type` by calling the EC2
+/// [DescribeInstanceTypes] API.
+/// (https://docs.aws.amazon.com/AWSEC2/latest/APIReference/API_DescribeInstanceTypes.html)
+async fn get_eni_limits(region: &str, instance_type: &str) -> Result<EniLimits> {
+    let parsed_region = Region::from_str(region).context(RegionParse { region })?;
+    let client = Ec2Client::new(parsed_region);
+    let describe_instance_types = rusoto_ec2::DescribeInstanceTypesRequest {
+        instance_types: Some(vec![instance_type.to_owned()]),
+        ..Default::default()
+    };
+    let network_info = client
+        .describe_instance_types(describe_instance_types)
+        .await
+        .context(DescribeInstanceTypes {})?
+        .instance_types
+        .and_then(|instance_types| instance_types.into_iter().next())
+        .context(Missing {
+            field: "instance_types",
+        })?
+        .network_info
+        .context(Missing {
+            field: "network_info",
+        })?;
+    Ok(EniLimits {
+        max_enis: positive(
+            "maximum_network_interfaces",
+            network_info.maximum_network_interfaces,
+        )?,
+        ipv4_addresses_per_eni: positive(
+            "ipv_4_addresses_per_interface",
+            network_info.ipv_4_addresses_per_interface,
+        )?,
+    })
+}
+
+/// Returns `value`, the `field` of an EC2 response, if it's present and positive.
+fn positive(field: &'static str, value: Option<i64>) -> Result<u32> {
+    let value = value.context(Missing { field })?;
+    match u32::try_from(value) {
+        Ok(value) if value > 0 => Ok(value),
+        _ => Invalid { field, value }.fail(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn max_pods_formula() {
+        // m5.large: 3 ENIs with 10 addresses each, as in eni-max-pods.
+        let limits = EniLimits {
+            max_enis: 3,
+            ipv4_addresses_per_eni: 10,
+        };
+        assert_eq!(limits.max_pods(), 29);
+        // c6i.32xlarge: 15 ENIs with 50 addresses each.
+        let limits = EniLimits {
+            max_enis: 15,
+            ipv4_addresses_per_eni: 50,
+        };
+        assert_eq!(limits.max_pods(), 737);
+    }
+
+    #[test]
+    fn positive_fields() {
+        assert_eq!(positive("field", Some(4)).unwrap(), 4);
+        assert!(matches!(
+            positive("field", None),
+            Err(Error::Missing { .. })
+        ));
+        for value in &[0, -1, i64::from(u32::MAX) + 1] {
+            assert!(matches!(
+                positive("field", Some(*value)),
+                Err(Error::Invalid { .. })
+            ));
+        }
+    }
+}
@@ -1,11 +1,35 @@
+use crate::api;
+use crate::proxy::ProxySettings;
+use async_trait::async_trait;
+use http::Uri;
+use hyper_proxy::{Intercept, Proxy, ProxyConnector};
+use hyper_rustls::HttpsConnector;
+use rusoto_core::credential::{CredentialsError, DefaultCredentialsProvider};
 use rusoto_core::region::ParseRegionError;
-use rusoto_core::{Region, RusotoError};
-use rusoto_eks::{DescribeClusterError, Eks, EksClient};
+use rusoto_core::request::{BufferedHttpResponse, HttpClient};
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::{Client, Region, RusotoError};
+use rusoto_eks::DescribeClusterError;
+use serde::Deserialize;
 use snafu::{OptionExt, ResultExt, Snafu};
+use std::env;
 use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Overrides the endpoint of the EKS API, e.g. to point pluto at a local mock.  For testing only.
+pub(super) const EKS_ENDPOINT_ENV: &str = "PLUTO_EKS_ENDPOINT";
+
+/// How many times DescribeCluster is called before a transient failure is given up on.
+const DESCRIBE_CLUSTER_ATTEMPTS: u32 = 3;
+/// The delay before the first retry of DescribeCluster; each later retry waits twice as long.  Up
+/// to half again is added to each delay as jitter, so the retries wait 3 to 4.5 seconds in all.
+const DESCRIBE_CLUSTER_BACKOFF: Duration = Duration::from_secs(1);
 
 #[derive(Debug, Snafu)]
 pub(super) enum Error {
+    #[snafu(display("Unable to load AWS credentials: {}", source))]
+    Credentials { source: CredentialsError },
+
     #[snafu(display("Error describing cluster: {}", source))]
     DescribeCluster {
         source: RusotoError<DescribeClusterError>,
@@ -14,6 +38,21 @@ pub(super) enum Error {
     #[snafu(display("Missing field '{}' EKS response", field))]
     Missing { field: &'static str },
 
+    #[snafu(display("Unable to set up a connection through proxy '{}': {}", proxy, source))]
+    ProxySetup {
+        proxy: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Unable to parse '{}' as a proxy URI: {}", proxy, source))]
+    ProxyUri {
+        proxy: String,
+        source: http::uri::InvalidUri,
+    },
+
+    #[snafu(display("Unable to parse EKS response: {}", source))]
+    ResponseParse { source: serde_json::Error },
+
     #[snafu(display("Unable to parse '{}' as a region: {}", region, source))]
     RegionParse {
         region: String,
@@ -23,26 +62,474 @@ pub(super) enum Error {
 
 type Result<T> = std::result::Result<T, Error>;
 
-/// Returns the cluster's [serviceIPv4CIDR] DNS IP by calling the EKS API.
-/// (https://docs.aws.amazon.com/eks/latest/APIReference/API_KubernetesNetworkConfigRequest.html)
-pub(super) async fn get_cluster_cidr(region: &str, cluster: &str) -> Result<String> {
-    let parsed_region = Region::from_str(region).context(RegionParse { region })?;
-    let client = EksClient::new(parsed_region);
-    let describe_cluster = rusoto_eks::DescribeClusterRequest {
-        name: cluster.to_owned(),
+/// The service CIDRs of a cluster. IPv4 clusters have an IPv4 service CIDR, IPv6 clusters have an
+/// IPv6 one, and dual-stack clusters have both.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(super) struct ServiceCidrs {
+    pub(super) ipv4: Option<String>,
+    pub(super) ipv6: Option<String>,
+}
+
+/// The service CIDR that a cluster's DNS IP comes from, which decides how it's derived.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum ServiceCidr {
+    Ipv4(String),
+    Ipv6(String),
+}
+
+/// A source of the service CIDRs of a cluster. This allows tests to supply the CIDRs without
+/// calling EKS.
+#[async_trait]
+pub(super) trait ClusterCidrSource {
+    /// Returns the service CIDRs of `cluster` in `region`.
+    async fn cluster_cidrs(&self, region: &str, cluster: &str) -> Result<ServiceCidrs>;
+}
+
+/// Gets service CIDRs by calling the EKS API, through the proxy in the network settings if there is
+/// one, retrying transient failures.
+pub(super) struct EksApi;
+
+#[async_trait]
+impl ClusterCidrSource for EksApi {
+    async fn cluster_cidrs(&self, region: &str, cluster: &str) -> Result<ServiceCidrs> {
+        // without the settings, try EKS directly, which works unless a proxy is required.
+        let proxy = api::get_proxy_settings().await.unwrap_or_else(|e| {
+            eprintln!("Unable to get proxy settings, calling EKS directly: {}", e);
+            ProxySettings::default()
+        });
+        cluster_cidrs_with_retries(
+            &DescribeClusterOnce { proxy },
+            region,
+            cluster,
+            DESCRIBE_CLUSTER_BACKOFF,
+        )
+        .await
+    }
+}
+
+/// Gets service CIDRs with a single call to the EKS API.
+struct DescribeClusterOnce {
+    proxy: ProxySettings,
+}
+
+#[async_trait]
+impl ClusterCidrSource for DescribeClusterOnce {
+    async fn cluster_cidrs(&self, region: &str, cluster: &str) -> Result<ServiceCidrs> {
+        get_cluster_cidrs(region, cluster, &self.proxy).await
+    }
+}
+
+/// Asks `source` for the service CIDRs of `cluster` until it succeeds, fails with an error that
+/// isn't transient, or has been asked `DESCRIBE_CLUSTER_ATTEMPTS` times, waiting `backoff` plus
+/// jitter before the first retry and twice as long before each later one.
+async fn cluster_cidrs_with_retries<S>(
+    source: &S,
+    region: &str,
+    cluster: &str,
+    backoff: Duration,
+) -> Result<ServiceCidrs>
+where
+    S: ClusterCidrSource + Sync + ?Sized,
+{
+    let mut delay = backoff;
+    let mut attempt = 1;
+    loop {
+        match source.cluster_cidrs(region, cluster).await {
+            Err(e) if attempt < DESCRIBE_CLUSTER_ATTEMPTS && is_transient(&e) => {
+                let wait = delay + jitter(delay);
+                eprintln!(
+                    "Warning: DescribeCluster failed on attempt {} of {}, retrying in {:?}: {}",
+                    attempt, DESCRIBE_CLUSTER_ATTEMPTS, wait, e
+                );
+                tokio::time::sleep(wait).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Returns a delay of up to half of `delay`, so that the retries of nodes that failed together are
+/// spread out.  The clock's nanoseconds are random enough for that.
+fn jitter(delay: Duration) -> Duration {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.subsec_nanos())
+        .unwrap_or_default();
+    delay.mul_f64(f64::from(nanos) / 2e9)
+}
+
+/// Returns whether `e` may go away on its own: the request couldn't be sent, EKS had a problem of
+/// its own, or the request was throttled.  Other errors, like access being denied or the cluster
+/// not existing, won't, so they aren't retried.
+fn is_transient(e: &Error) -> bool {
+    match e {
+        Error::DescribeCluster { source } => match source {
+            RusotoError::HttpDispatch(_) => true,
+            RusotoError::Service(DescribeClusterError::Server(_))
+            | RusotoError::Service(DescribeClusterError::ServiceUnavailable(_)) => true,
+            RusotoError::Unknown(response) => is_transient_response(response),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn is_transient_response(response: &BufferedHttpResponse) -> bool {
+    is_transient_status(response.status.as_u16(), response.body_as_str())
+}
+
+/// Returns whether an error response with `status` and `body` that rusoto didn't recognize is
+/// transient: a server error, or throttling, which EKS reports as a 400 `ThrottlingException` or
+/// a 429.
+fn is_transient_status(status: u16, body: &str) -> bool {
+    status >= 500 || status == 429 || (status == 400 && body.contains("Throttling"))
+}
+
+/// Returns the CIDR that the cluster's DNS IP comes from: the IPv4 service CIDR, or the IPv6 one
+/// if the cluster has no IPv4 service CIDR.
+pub(super) fn get_cluster_cidr(cidrs: &ServiceCidrs) -> Result<ServiceCidr> {
+    match (&cidrs.ipv4, &cidrs.ipv6) {
+        (Some(ipv4), _) => Ok(ServiceCidr::Ipv4(ipv4.clone())),
+        (None, Some(ipv6)) => Ok(ServiceCidr::Ipv6(ipv6.clone())),
+        (None, None) => Missing {
+            field: "service_ipv_4_cidr",
+        }
+        .fail(),
+    }
+}
+
+/// Returns the region to give a rusoto client.  If `endpoint` is set, requests go there instead of
+/// to the region's usual endpoint, but are still signed for `region`.
+fn client_region(region: &str, endpoint: Option<String>) -> Result<Region> {
+    match endpoint.filter(|endpoint| !endpoint.is_empty()) {
+        Some(endpoint) => Ok(Region::Custom {
+            name: region.to_owned(),
+            endpoint,
+        }),
+        None => Region::from_str(region).context(RegionParse { region }),
+    }
+}
+
+/// The parts of the [DescribeCluster] response that pluto reads.
+/// (https://docs.aws.amazon.com/eks/latest/APIReference/API_DescribeCluster.html)
+#[derive(Debug, Deserialize)]
+struct DescribeClusterResponse {
+    cluster: Option<Cluster>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Cluster {
+    kubernetes_network_config: Option<KubernetesNetworkConfig>,
+}
+
+/// (https://docs.aws.amazon.com/eks/latest/APIReference/API_KubernetesNetworkConfigResponse.html)
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KubernetesNetworkConfig {
+    service_ipv_4_cidr: Option<String>,
+    service_ipv_6_cidr: Option<String>,
+}
+
+/// Returns the cluster's [serviceIpv4Cidr] and [serviceIpv6Cidr], whichever it has, by calling the
+/// EKS API.
+///
+/// The rusoto EKS model predates IPv6 clusters and drops serviceIpv6Cidr, so the DescribeCluster
+/// request is signed and sent with rusoto's client, but its response is parsed here.  The request
+/// goes through the proxy in `proxy` unless EKS's host is one of its exceptions.
+async fn get_cluster_cidrs(
+    region: &str,
+    cluster: &str,
+    proxy: &ProxySettings,
+) -> Result<ServiceCidrs> {
+    let parsed_region = client_region(region, env::var(EKS_ENDPOINT_ENV).ok())?;
+    let mut request = SignedRequest::new(
+        "GET",
+        "eks",
+        &parsed_region,
+        &format!("/clusters/{}", cluster),
+    );
+    request.set_content_type("application/x-amz-json-1.1".to_owned());
+    let client = match proxy.proxy_for(&request.hostname()) {
+        Some(proxy) => proxy_client(&proxy)?,
+        None => Client::shared(),
     };
-    client
-        .describe_cluster(describe_cluster)
+    let response = client
+        .sign_and_dispatch(request)
         .await
+        .map_err(RusotoError::from)
         .context(DescribeCluster {})?
+        .buffer()
+        .await
+        .map_err(RusotoError::HttpDispatch)
+        .context(DescribeCluster {})?;
+    if !response.status.is_success() {
+        return Err(DescribeClusterError::from_response(response)).context(DescribeCluster {});
+    }
+    parse_cluster_cidrs(&response.body)
+}
+
+/// Returns a rusoto client that sends requests through the proxy at `proxy`, with the usual
+/// credentials.
+fn proxy_client(proxy: &str) -> Result<Client> {
+    let uri = proxy.parse::<Uri>().context(ProxyUri { proxy })?;
+    let connector = ProxyConnector::from_proxy(
+        HttpsConnector::with_native_roots(),
+        Proxy::new(Intercept::All, uri),
+    )
+    .context(ProxySetup { proxy })?;
+    let credentials = DefaultCredentialsProvider::new().context(Credentials)?;
+    Ok(Client::new_with(
+        credentials,
+        HttpClient::from_connector(connector),
+    ))
+}
+
+/// Returns the service CIDRs from the body of a DescribeCluster response.
+fn parse_cluster_cidrs(body: &[u8]) -> Result<ServiceCidrs> {
+    let network_config = serde_json::from_slice::<DescribeClusterResponse>(body)
+        .context(ResponseParse)?
         .cluster
         .context(Missing { field: "cluster" })?
         .kubernetes_network_config
         .context(Missing {
             field: "kubernetes_network_config",
-        })?
-        .service_ipv_4_cidr
-        .context(Missing {
-            field: "service_ipv_4_cidr",
+        })?;
+    Ok(ServiceCidrs {
+        ipv4: network_config.service_ipv_4_cidr,
+        ipv6: network_config.service_ipv_6_cidr,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rusoto_core::request::HttpDispatchError;
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    /// Returns scripted results, in order, and counts the calls.
+    struct ScriptedEks {
+        results: Mutex<VecDeque<Result<ServiceCidrs>>>,
+    }
+
+    impl ScriptedEks {
+        fn new(results: Vec<Result<ServiceCidrs>>) -> Self {
+            Self {
+                results: Mutex::new(results.into()),
+            }
+        }
+
+        fn remaining(&self) -> usize {
+            self.results.lock().unwrap().len()
+        }
+    }
+
+    #[async_trait]
+    impl ClusterCidrSource for ScriptedEks {
+        async fn cluster_cidrs(&self, _region: &str, _cluster: &str) -> Result<ServiceCidrs> {
+            self.results
+                .lock()
+                .unwrap()
+                .pop_front()
+                .expect("DescribeCluster called too many times")
+        }
+    }
+
+    fn cidrs() -> ServiceCidrs {
+        ServiceCidrs {
+            ipv4: Some("10.100.0.0/16".to_string()),
+            ipv6: None,
+        }
+    }
+
+    fn dispatch_error() -> Result<ServiceCidrs> {
+        Err(Error::DescribeCluster {
+            source: RusotoError::HttpDispatch(HttpDispatchError::new("timed out".to_string())),
+        })
+    }
+
+    fn service_error(error: DescribeClusterError) -> Result<ServiceCidrs> {
+        Err(Error::DescribeCluster {
+            source: RusotoError::Service(error),
         })
+    }
+
+    async fn with_retries(eks: &ScriptedEks) -> Result<ServiceCidrs> {
+        cluster_cidrs_with_retries(eks, "us-west-2", "c", Duration::from_millis(0)).await
+    }
+
+    #[tokio::test]
+    async fn retry_until_success() {
+        let eks = ScriptedEks::new(vec![
+            dispatch_error(),
+            service_error(DescribeClusterError::ServiceUnavailable("busy".to_string())),
+            Ok(cidrs()),
+        ]);
+        assert_eq!(with_retries(&eks).await.unwrap(), cidrs());
+        assert_eq!(eks.remaining(), 0);
+    }
+
+    #[tokio::test]
+    async fn retries_are_bounded() {
+        let eks = ScriptedEks::new(vec![
+            dispatch_error(),
+            service_error(DescribeClusterError::Server("oops".to_string())),
+            dispatch_error(),
+            Ok(cidrs()),
+        ]);
+        assert!(matches!(
+            with_retries(&eks).await,
+            Err(Error::DescribeCluster {
+                source: RusotoError::HttpDispatch(_)
+            })
+        ));
+        // the last result is never asked for.
+        assert_eq!(eks.remaining(), 1);
+    }
+
+    #[tokio::test]
+    async fn permanent_errors_fail_fast() {
+        for result in [
+            service_error(DescribeClusterError::ResourceNotFound("c".to_string())),
+            service_error(DescribeClusterError::Client("denied".to_string())),
+            Err(Error::Missing { field: "cluster" }),
+        ] {
+            let eks = ScriptedEks::new(vec![result, Ok(cidrs())]);
+            assert!(with_retries(&eks).await.is_err());
+            assert_eq!(eks.remaining(), 1);
+        }
+    }
+
+    #[test]
+    fn transient_statuses() {
+        assert!(is_transient_status(429, ""));
+        assert!(is_transient_status(503, ""));
+        assert!(is_transient_status(
+            400,
+            r#"{"__type":"ThrottlingException","message":"Rate exceeded"}"#
+        ));
+        assert!(!is_transient_status(
+            403,
+            r#"{"__type":"AccessDeniedException","message":"not authorized"}"#
+        ));
+        assert!(!is_transient_status(
+            400,
+            r#"{"__type":"InvalidParameterException"}"#
+        ));
+    }
+
+    #[test]
+    fn jitter_is_at_most_half() {
+        let delay = Duration::from_secs(2);
+        assert!(jitter(delay) <= Duration::from_secs(1));
+        assert_eq!(jitter(Duration::from_millis(0)), Duration::from_millis(0));
+    }
+
+    #[test]
+    fn region_without_override() {
+        assert_eq!(client_region("us-west-2", None).unwrap(), Region::UsWest2);
+        // an empty override is the same as none.
+        assert_eq!(
+            client_region("us-west-2", Some(String::new())).unwrap(),
+            Region::UsWest2
+        );
+    }
+
+    #[test]
+    fn region_with_override() {
+        assert_eq!(
+            client_region("us-west-2", Some("http://localhost:4566".to_string())).unwrap(),
+            Region::Custom {
+                name: "us-west-2".to_string(),
+                endpoint: "http://localhost:4566".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn override_keeps_unknown_region() {
+        // the region is only used for signing, so one that rusoto doesn't know is kept as is.
+        assert_eq!(
+            client_region("local-1", Some("http://localhost:4566".to_string())).unwrap(),
+            Region::Custom {
+                name: "local-1".to_string(),
+                endpoint: "http://localhost:4566".to_string(),
+            }
+        );
+        assert!(matches!(
+            client_region("local-1", None),
+            Err(Error::RegionParse { .. })
+        ));
+    }
+
+    #[test]
+    fn parse_ipv4_cluster() {
+        let body = br#"{"cluster": {"name": "c", "kubernetesNetworkConfig": {
+            "serviceIpv4Cidr": "10.100.0.0/16", "ipFamily": "ipv4"}}}"#;
+        let cidrs = parse_cluster_cidrs(body).unwrap();
+        assert_eq!(
+            cidrs,
+            ServiceCidrs {
+                ipv4: Some("10.100.0.0/16".to_string()),
+                ipv6: None,
+            }
+        );
+        assert_eq!(
+            get_cluster_cidr(&cidrs).unwrap(),
+            ServiceCidr::Ipv4("10.100.0.0/16".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_ipv6_cluster() {
+        let body = br#"{"cluster": {"kubernetesNetworkConfig": {
+            "serviceIpv6Cidr": "fd30:1c53:5f8a::/108", "ipFamily": "ipv6"}}}"#;
+        let cidrs = parse_cluster_cidrs(body).unwrap();
+        assert_eq!(cidrs.ipv4, None);
+        assert_eq!(
+            get_cluster_cidr(&cidrs).unwrap(),
+            ServiceCidr::Ipv6("fd30:1c53:5f8a::/108".to_string())
+        );
+    }
+
+    #[test]
+    fn cluster_cidr_prefers_ipv4() {
+        let cidrs = ServiceCidrs {
+            ipv4: Some("172.20.0.0/16".to_string()),
+            ipv6: Some("fd30:1c53:5f8a::/108".to_string()),
+        };
+        assert_eq!(
+            get_cluster_cidr(&cidrs).unwrap(),
+            ServiceCidr::Ipv4("172.20.0.0/16".to_string())
+        );
+        assert!(matches!(
+            get_cluster_cidr(&ServiceCidrs::default()),
+            Err(Error::Missing { .. })
+        ));
+    }
+
+    #[test]
+    fn parse_incomplete_responses() {
+        assert!(matches!(
+            parse_cluster_cidrs(br#"{}"#),
+            Err(Error::Missing { field: "cluster" })
+        ));
+        assert!(matches!(
+            parse_cluster_cidrs(br#"{"cluster": {}}"#),
+            Err(Error::Missing {
+                field: "kubernetes_network_config"
+            })
+        ));
+        assert!(matches!(
+            parse_cluster_cidrs(b"not json"),
+            Err(Error::ResponseParse { .. })
+        ));
+        // a cluster without either CIDR parses, but has no CIDR to derive a DNS IP from.
+        let cidrs = parse_cluster_cidrs(br#"{"cluster": {"kubernetesNetworkConfig": {}}}"#);
+        assert_eq!(cidrs.unwrap(), ServiceCidrs::default());
+    }
 }
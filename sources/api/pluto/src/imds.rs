@@ -0,0 +1,87 @@
+//! Provides `ImdsSource`, the instance metadata that the setting generators need, and its
+//! implementation for IMDS itself.  Tests use an `ImdsClient` pointed at a mock server, and offline
+//! runs use a metadata snapshot instead.
+
+use crate::error;
+use crate::Result;
+use async_trait::async_trait;
+use imdsclient::{ErrorKind, IdentityDocument, ImdsClient};
+use snafu::ResultExt;
+
+/// A source of the instance metadata that pluto needs. This allows tests, and offline runs with a
+/// metadata snapshot, to supply the metadata without IMDS.
+#[async_trait]
+pub(crate) trait ImdsSource: Send {
+    /// Returns the instance identity document, which has the region and instance type.
+    async fn identity_document(&mut self) -> Result<IdentityDocument>;
+
+    /// Returns the MAC addresses of the network interfaces, the primary interface's first.
+    async fn mac_addresses(&mut self) -> Result<Vec<String>>;
+
+    /// Returns the VPC IPV4 CIDR blocks of the interface with `mac`, or `None` if the VPC has
+    /// none, e.g. because it's IPV6-only.
+    async fn cidr_blocks_for_mac(&mut self, mac: &str) -> Result<Option<Vec<String>>>;
+
+    /// Returns the VPC IPV6 CIDR blocks of the interface with `mac`.
+    async fn ipv6_cidr_blocks_for_mac(&mut self, mac: &str) -> Result<Vec<String>>;
+
+    /// Returns the private IPV4 address of the primary interface.
+    async fn local_ipv4_address(&mut self) -> Result<String>;
+
+    /// Returns the name of the instance's availability zone, e.g. `us-west-2a`.
+    async fn availability_zone(&mut self) -> Result<String>;
+
+    /// Returns the ID of the instance, e.g. `i-0123456789abcdef0`.
+    async fn instance_id(&mut self) -> Result<String>;
+}
+
+/// Gets instance metadata from IMDS.
+#[async_trait]
+impl ImdsSource for ImdsClient {
+    /// The override file at `IDENTITY_DOCUMENT_FILE` is used if present, otherwise IMDS is queried.
+    async fn identity_document(&mut self) -> Result<IdentityDocument> {
+        if let Some(identity_document) =
+            crate::identity_document_from_file(crate::IDENTITY_DOCUMENT_FILE)
+        {
+            return Ok(identity_document);
+        }
+        self.fetch_identity_document()
+            .await
+            .context(error::ImdsRequest)
+    }
+
+    async fn mac_addresses(&mut self) -> Result<Vec<String>> {
+        self.fetch_mac_addresses().await.context(error::ImdsRequest)
+    }
+
+    async fn cidr_blocks_for_mac(&mut self, mac: &str) -> Result<Option<Vec<String>>> {
+        match self.fetch_cidr_blocks_for_mac(mac).await {
+            Ok(cidr_blocks) => Ok(Some(cidr_blocks)),
+            // IMDS returns 404 for the IPV4 CIDR blocks in an IPV6-only VPC.
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).context(error::ImdsRequest),
+        }
+    }
+
+    async fn ipv6_cidr_blocks_for_mac(&mut self, mac: &str) -> Result<Vec<String>> {
+        self.fetch_ipv6_cidr_blocks_for_mac(mac)
+            .await
+            .context(error::ImdsRequest)
+    }
+
+    async fn local_ipv4_address(&mut self) -> Result<String> {
+        self.fetch_local_ipv4_address()
+            .await
+            .context(error::ImdsRequest)
+    }
+
+    async fn availability_zone(&mut self) -> Result<String> {
+        self.fetch_availability_zone()
+            .await
+            .context(error::ImdsRequest)
+    }
+
+    async fn instance_id(&mut self) -> Result<String> {
+        self.fetch_instance_id().await.context(error::ImdsRequest)
+    }
+}
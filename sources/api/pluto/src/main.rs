@@ -11,15 +11,37 @@ It uses IMDS to get information such as:
 
 - Instance Type
 - Node IP
+- Availability Zone and Instance ID
+
+If the file `/etc/early-boot-config/identity-document` exists, it is used in place of the IMDS
+instance identity document, just like in early-boot-config.
+
+Max pods is looked up by instance type in the eni-max-pods file. If the instance type isn't found
+there, pluto checks the per-family overrides embedded from `data/max-pods-overrides.toml`, and then
+calculates it from the instance type's network interface limits in EC2, with the same formula as
+the file.  The source that was used is printed to stderr.
 
 It uses EKS to get information such as:
 
 - Service IPV4 CIDR
+- Service IPV6 CIDR, for IPV6 and dual-stack clusters
+
+It uses EC2 to get information such as:
+
+- Network interface limits of instance types that pluto doesn't know
 
 It uses the Bottlerocket API to get information such as:
 
 - Kubernetes Cluster Name
 - AWS Region
+- Kubernetes Cluster DNS IP, if it's set
+
+For testing against a local mock, e.g. localstack, the `PLUTO_EKS_ENDPOINT` environment variable
+overrides the endpoint that EKS requests are sent to.  Requests are still signed for the region
+from the API.  This is only meant for testing.
+
+Surrounding whitespace is removed from the cluster name, and if it's a cluster ARN, the name is
+taken from it. A cluster name that EKS wouldn't accept is reported as an error before EKS is called.
 
 # Interface
 
@@ -28,18 +50,86 @@ argument.
 It returns the generated setting to stdout as a JSON document.
 Any other output is returned to stderr.
 
-Pluto returns a special exit code of 2 to inform `sundog` that a setting should be skipped. For
-example, if `max-pods` cannot be generated, we want `sundog` to skip it without failing since a
-reasonable default is available.
+`cluster-dns-ip` returns the `settings.kubernetes.cluster-dns-ip` setting from the Bottlerocket
+API if it's set, so that clusters whose EKS API can't be reached, like fully private ones, can
+give the address directly.  A setting that isn't an IP address is ignored with a warning.
+Otherwise it returns the cluster DNS IP derived from the service IPV4
+CIDR by setting the last octet to `10`, or, for IPV6 clusters, which have no service IPV4 CIDR,
+from the service IPV6 CIDR by appending `a` to the network address, as the EKS AMI does.
+EKS is called through the proxy in the `network.https-proxy` setting, if there is one, unless its
+host matches an entry of `network.no-proxy`.
+DescribeCluster is retried twice, with backoff, if it fails in a way that may be transient, like
+throttling.  If EKS can't be reached, a default address is chosen from the VPC IPV4 CIDR of the
+primary network interface.  If the VPC only has IPV6 CIDR blocks, no IPV4 default can work, so
+pluto skips the setting with exit code 2 and explains why on stderr.
+`cluster-dns-ips` returns a JSON array of cluster DNS IPs for kubelets that accept more than one.
+The IPV4-derived address comes first, followed by the IPV6-derived address if the cluster has a
+service IPV6 CIDR.
+
+`node-taints` returns a JSON array of default kubelet node taints for the instance type, e.g.
+`["nvidia.com/gpu=true:NoSchedule"]` for GPU instances, so that pods that don't tolerate them aren't
+scheduled onto accelerated nodes.  The taints come from the per-family rules embedded from
+`data/node-taints.toml`, and the array is empty for ordinary instance types.  If IMDS can't be
+reached, the setting is skipped with exit code 2.
+
+`provider-id` returns the provider ID that kubelet takes as `--provider-id`, in the form
+`aws:///<availability-zone>/<instance-id>`, from IMDS.  If IMDS can't be reached, the setting is
+skipped with exit code 2.
+
+`node-labels` returns a JSON map of default kubelet node labels, with the instance's zone as
+`topology.kubernetes.io/zone`, its region as `topology.kubernetes.io/region`, and its instance type
+as `node.kubernetes.io/instance-type`, from IMDS.  A label whose value can't be determined is left
+out of the map rather than failing the setting.  If IMDS can't be reached, the setting is skipped
+with exit code 2.
+
+If pluto falls back to another source of information while generating a setting, it prints a
+warning when that happens, and a single summary line to stderr at exit that ties the fallbacks to
+the generated value, e.g.
+`cluster-dns-ip: used IMDS-CIDR fallback (eks: <error>); value=172.20.0.10`.
+
+Pluto returns a special exit code of 2 to inform `sundog` that a setting should be skipped, and
+prints the reason to stderr. For example, if `max-pods` cannot be generated, we want `sundog` to
+skip it without failing since a reasonable default is available. The exit codes come from the
+`setting-generator` library, which encodes the contract that `sundog` expects.
+
+# Offline mode
+
+Pipelines that validate images can't reach IMDS or EKS, so they can pass
+`--metadata-snapshot <path>` before the setting name to have pluto read everything from a JSON
+file of canned values instead: the region, instance type or identity document, local IPV4
+address, availability zone, instance ID, MAC addresses and their CIDR blocks, a max-pods table,
+the cluster name, and the service CIDRs.  Every setting can be generated this way, and keys that
+pluto doesn't know are ignored.
+See the snapshot module for the format.
 */
 
 mod api;
+mod context;
+mod degradation;
+mod ec2;
 mod eks;
+mod imds;
+mod max_pods;
+mod node_taints;
+mod proxy;
+mod snapshot;
 
-use imdsclient::ImdsClient;
-use snafu::{ensure, OptionExt, ResultExt};
-use std::fs::File;
+use api::ApiSettings;
+use context::GeneratorContext;
+use degradation::DegradationReport;
+use eks::EksApi;
+use imdsclient::IdentityDocument;
+use max_pods::MaxPodsOverrides;
+use node_taints::NodeTaintRules;
+use serde::Serialize;
+use setting_generator::SettingGeneratorOutcome;
+use snafu::{OptionExt, ResultExt};
+use snapshot::MetadataSnapshot;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
 use std::io::{BufRead, BufReader};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
 use std::string::String;
 use std::{env, process};
 
@@ -50,9 +140,18 @@ const DEFAULT_10_RANGE_DNS_CLUSTER_IP: &str = "172.20.0.10";
 
 const ENI_MAX_PODS_PATH: &str = "/usr/share/eks/eni-max-pods";
 
+// This is deliberately the same override file that early-boot-config checks before querying IMDS
+// for the identity document, so that test rigs only need to supply the file once.
+const IDENTITY_DOCUMENT_FILE: &str = "/etc/early-boot-config/identity-document";
+
 mod error {
+    use crate::api;
+    use crate::ec2;
     use crate::eks;
+    use crate::max_pods;
+    use crate::node_taints;
     use snafu::Snafu;
+    use std::path::PathBuf;
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility = "pub(super)")]
@@ -69,6 +168,12 @@ mod error {
         #[snafu(display("IMDS request failed: No '{}' found", what))]
         ImdsNone { what: String },
 
+        #[snafu(display(
+            "The primary network interface only has IPV6 CIDR blocks ({}); skipping the setting because a default IPV4 cluster DNS IP can't work",
+            cidr_blocks.join(", ")
+        ))]
+        Ipv6Only { cidr_blocks: Vec<String> },
+
         #[snafu(display("Error deserializing response into JSON from {}: {}", uri, source))]
         ImdsJson {
             uri: String,
@@ -76,24 +181,35 @@ mod error {
         },
 
         #[snafu(display(
-            "Error serializing to JSON from command output '{}': {}",
-            output,
+            "Unable to get region and cluster name from Bottlerocket API: {}",
             source
         ))]
-        OutputJson {
-            output: String,
-            source: serde_json::error::Error,
-        },
+        AwsK8sInfo { source: api::Error },
+
+        #[snafu(display(
+            "Unable to get cluster-dns-ip setting from Bottlerocket API: {}",
+            source
+        ))]
+        ClusterDnsIpSetting { source: api::Error },
+
+        #[snafu(display("{}", source))]
+        Ec2Error { source: ec2::Error },
 
         #[snafu(display("{}", source))]
         EksError { source: eks::Error },
 
-        #[snafu(display("Failed to open eni-max-pods file at {}: {}", path, source))]
+        #[snafu(display("Failed to open eni-max-pods file at {}: {}", path.display(), source))]
         EniMaxPodsFile {
-            path: &'static str,
+            path: PathBuf,
             source: std::io::Error,
         },
 
+        #[snafu(display("{}", source))]
+        MaxPodsOverrides { source: max_pods::Error },
+
+        #[snafu(display("{}", source))]
+        NodeTaintRules { source: node_taints::Error },
+
         #[snafu(display("Failed to parse setting {} as u32: {}", setting, source))]
         ParseToU32 {
             setting: String,
@@ -108,6 +224,24 @@ mod error {
             instance_type
         ))]
         NoInstanceTypeMaxPods { instance_type: String },
+
+        #[snafu(display("Unable to build identity document from metadata snapshot: {}", source))]
+        SnapshotIdentityDocument { source: serde_json::Error },
+
+        #[snafu(display("Metadata snapshot has no '{}'", key))]
+        SnapshotMissing { key: String },
+
+        #[snafu(display("Unable to parse metadata snapshot '{}': {}", path.display(), source))]
+        SnapshotParse {
+            path: PathBuf,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Unable to read metadata snapshot '{}': {}", path.display(), source))]
+        SnapshotRead {
+            path: PathBuf,
+            source: std::io::Error,
+        },
     }
 }
 
@@ -115,20 +249,33 @@ use error::PlutoError;
 
 type Result<T> = std::result::Result<T, PlutoError>;
 
-async fn get_max_pods(client: &mut ImdsClient) -> Result<String> {
-    let instance_type = client
-        .fetch_identity_document()
-        .await
-        .context(error::ImdsRequest)?
-        .instance_type()
-        .to_string();
+/// Reads the identity document from the override file at `path`, if there is one. Returns `None`
+/// if the file does not exist or cannot be parsed, so that the caller can fall back to IMDS.
+fn identity_document_from_file<P>(path: P) -> Option<IdentityDocument>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    if !path.exists() {
+        return None;
+    }
+    let data = fs::read_to_string(path)
+        .map_err(|e| eprintln!("Unable to read {}, using IMDS: {}", path.display(), e))
+        .ok()?;
+    serde_json::from_str(&data)
+        .map_err(|e| eprintln!("Unable to parse {}, using IMDS: {}", path.display(), e))
+        .ok()
+}
 
-    // Find the corresponding maximum number of pods supported by this instance type
-    let file = BufReader::new(
-        File::open(ENI_MAX_PODS_PATH).context(error::EniMaxPodsFile {
-            path: ENI_MAX_PODS_PATH,
-        })?,
-    );
+/// Returns the identity document, which contains information such as region and instance type.
+async fn get_identity_document(ctx: &mut GeneratorContext<'_>) -> Result<IdentityDocument> {
+    ctx.imds().await?.identity_document().await
+}
+
+/// Returns the maximum number of pods for `instance_type` from the eni-max-pods file at `path`, or
+/// `None` if the file doesn't list it.
+fn get_eni_max_pods(path: &Path, instance_type: &str) -> Result<Option<String>> {
+    let file = BufReader::new(File::open(path).context(error::EniMaxPodsFile { path })?);
     for line in file.lines() {
         let line = line.context(error::IoReadLine)?;
         // Skip the comments in the file
@@ -137,48 +284,219 @@ async fn get_max_pods(client: &mut ImdsClient) -> Result<String> {
         }
         let tokens: Vec<_> = line.split_whitespace().collect();
         if tokens.len() == 2 && tokens[0] == instance_type {
-            return Ok(tokens[1].to_string());
+            return Ok(Some(tokens[1].to_string()));
         }
     }
-    error::NoInstanceTypeMaxPods { instance_type }.fail()
+    Ok(None)
 }
 
-/// Returns the cluster's DNS IPV4 address. First it attempts to call EKS describe-cluster to find
-/// the `serviceIPv4CIDR`. If that works, it returns the expected cluster DNS IP address which is
-/// obtained by substituting `10` for the last octet. If the EKS call is not successful, it falls
-/// back to using IMDS MAC CIDR blocks to return one of two default addresses.
-async fn get_cluster_dns_ip(client: &mut ImdsClient) -> Result<String> {
-    // try calling eks describe-cluster to figure out the dns cluster ip
-    if let Some(dns_ip) = get_dns_from_eks().await {
-        // we were able to calculate the dns ip from the cidr range we received from eks
-        return Ok(dns_ip);
+async fn get_max_pods(ctx: &mut GeneratorContext<'_>) -> Result<String> {
+    let instance_type = get_identity_document(ctx)
+        .await?
+        .instance_type()
+        .to_string();
+    get_max_pods_for(ctx, Path::new(ENI_MAX_PODS_PATH), &instance_type).await
+}
+
+/// Returns the maximum number of pods for `instance_type`, looking in the eni-max-pods file at
+/// `eni_max_pods_path`, then in the embedded overrides, and then calculating it from EC2.
+async fn get_max_pods_for(
+    ctx: &mut GeneratorContext<'_>,
+    eni_max_pods_path: &Path,
+    instance_type: &str,
+) -> Result<String> {
+    // Find the corresponding maximum number of pods supported by this instance type, in the
+    // snapshot's table in offline mode.
+    let max_pods = match ctx.max_pods() {
+        Some(table) => table
+            .get(instance_type)
+            .map(|max_pods| max_pods.to_string()),
+        None => get_eni_max_pods(eni_max_pods_path, instance_type)?,
+    };
+    if let Some(max_pods) = max_pods {
+        eprintln!("Using max-pods for {} from eni-max-pods", instance_type);
+        return Ok(max_pods);
     }
 
-    // we were unable to obtain or parse the cidr range from eks, fallback to one of two default
-    // values based on the cidr range of our primary network interface
-    get_cluster_dns_from_imds_mac(client).await
+    // The eni-max-pods file can lag behind new instance launches, so fall back to the overrides
+    // embedded in pluto.  See the max_pods module for the full lookup precedence.
+    let overrides = MaxPodsOverrides::embedded().context(error::MaxPodsOverrides)?;
+    if let Some(max_pods) = overrides.get(instance_type) {
+        eprintln!(
+            "Using max-pods for {} from the embedded overrides",
+            instance_type
+        );
+        return Ok(max_pods.to_string());
+    }
+
+    // Newly launched instance families can be missing from both, so as a last resort, calculate
+    // max-pods from the instance type's network interface limits.
+    match get_max_pods_from_ec2(ctx, instance_type).await {
+        Ok(max_pods) => {
+            eprintln!("Using max-pods for {} calculated from EC2", instance_type);
+            return Ok(max_pods.to_string());
+        }
+        Err(e) => eprintln!(
+            "Unable to calculate max-pods for {} from EC2: {}",
+            instance_type, e
+        ),
+    }
+    error::NoInstanceTypeMaxPods { instance_type }.fail()
+}
+
+/// Calculates the maximum number of pods for `instance_type` from its network interface limits,
+/// which come from EC2 DescribeInstanceTypes in the region from the Bottlerocket API.
+async fn get_max_pods_from_ec2(ctx: &mut GeneratorContext<'_>, instance_type: &str) -> Result<u32> {
+    let region = ctx.region().await?;
+    let limits = ctx
+        .ec2()
+        .eni_limits(&region, instance_type)
+        .await
+        .context(error::Ec2Error)?;
+    Ok(limits.max_pods())
+}
+
+/// Returns the default node taints for the instance type, from the rules embedded in pluto.
+async fn get_node_taints(ctx: &mut GeneratorContext<'_>) -> Result<Vec<String>> {
+    let identity_document = get_identity_document(ctx).await?;
+    let rules = NodeTaintRules::embedded().context(error::NodeTaintRules)?;
+    Ok(rules.taints(identity_document.instance_type()))
 }
 
-/// Gets the Service IPV4 CIDR setting from EKS and parses it to calculate the cluster DNS IP.
-/// Prints the error and returns `None` if anything goes wrong.
-async fn get_dns_from_eks() -> Option<String> {
-    let aws_k8s_info = match api::get_aws_k8s_info().await {
-        Ok(value) => value,
+/// Returns the cluster's DNS IP addresses, IPV4 first. If the cluster has a service IPV6 CIDR,
+/// the IPV6 address derived from it follows the IPV4 address. If the EKS call is not successful,
+/// falls back to the single default address that `get_cluster_dns_ip` would return.
+async fn get_cluster_dns_ips(
+    ctx: &mut GeneratorContext<'_>,
+    report: &mut DegradationReport,
+) -> Result<Vec<String>> {
+    match get_dns_ips_from_eks(ctx).await {
+        Ok(dns_ips) => return Ok(dns_ips),
+        Err(e) => {
+            eprintln!("Unable to get DNS IP from EKS, using default DNS IP: {}", e);
+            report.record("IMDS-CIDR", "eks", e);
+        }
+    }
+    Ok(vec![get_cluster_dns_from_imds_mac(ctx).await?])
+}
+
+/// Returns the cluster's DNS IPV4 address. If the `cluster-dns-ip` setting is set in the
+/// Bottlerocket API and is an IP address, it's returned without asking EKS or IMDS. Otherwise it
+/// attempts to call EKS describe-cluster to find the `serviceIPv4CIDR`. If that works, it returns
+/// the expected cluster DNS IP address which is obtained by substituting `10` for the last octet.
+/// If the EKS call is not successful, it falls back to using IMDS MAC CIDR blocks to return one of
+/// two default addresses.
+///
+/// The settings and EKS sources come from `ctx` so that tests can exercise the whole fallback
+/// order.  Fallbacks are recorded in `report`.
+async fn get_cluster_dns_ip(
+    ctx: &mut GeneratorContext<'_>,
+    report: &mut DegradationReport,
+) -> Result<String> {
+    // a user-provided address wins; if the API can't be reached, or the address is invalid, carry
+    // on without it
+    match ctx.cluster_dns_ip_setting().await {
+        Ok(Some(dns_ip)) if dns_ip.parse::<IpAddr>().is_ok() => return Ok(dns_ip),
+        Ok(Some(dns_ip)) => {
+            eprintln!(
+                "Ignoring cluster-dns-ip setting '{}', which is not an IP address, using EKS",
+                dns_ip
+            );
+            report.record(
+                "EKS",
+                "api",
+                format!("invalid cluster-dns-ip setting '{}'", dns_ip),
+            );
+        }
+        Ok(None) => {}
         Err(e) => {
             eprintln!(
-                "Unable to get region and cluster name from Bottlerocket API, using default DNS IP: {}",
+                "Unable to check for a cluster-dns-ip setting, using EKS: {}",
                 e
             );
-            return None;
+            report.record("EKS", "api", e);
         }
-    };
+    }
+
+    // try calling eks describe-cluster to figure out the dns cluster ip
+    match get_dns_from_eks(ctx).await {
+        // we were able to calculate the dns ip from the cidr range we received from eks
+        Ok(dns_ip) => return Ok(dns_ip),
+        Err(e) => {
+            eprintln!("Unable to get DNS IP from EKS, using default DNS IP: {}", e);
+            report.record("IMDS-CIDR", "eks", e);
+        }
+    }
 
-    eks::get_cluster_cidr(&aws_k8s_info.region, &aws_k8s_info.cluster_name)
+    // we were unable to obtain or parse the cidr range from eks, fallback to one of two default
+    // values based on the cidr range of our primary network interface
+    get_cluster_dns_from_imds_mac(ctx).await
+}
+
+/// Gets the service CIDR from EKS, IPV4 if the cluster has one and IPV6 otherwise, and parses it to
+/// calculate the cluster DNS IP.
+async fn get_dns_from_eks(ctx: &mut GeneratorContext<'_>) -> Result<String> {
+    let cidrs = get_cidrs_from_eks(ctx).await?;
+    let cidr = eks::get_cluster_cidr(&cidrs).context(error::EksError)?;
+    get_dns_from_cidr(&cidr)
+}
+
+/// Gets the service CIDRs from EKS and parses them to calculate the cluster DNS IPs.
+async fn get_dns_ips_from_eks(ctx: &mut GeneratorContext<'_>) -> Result<Vec<String>> {
+    let cidrs = get_cidrs_from_eks(ctx).await?;
+    get_dns_ips_from_cidrs(&cidrs)
+}
+
+/// Gets the service CIDRs of the cluster from EKS, using the region and cluster name from the
+/// Bottlerocket API.
+async fn get_cidrs_from_eks(ctx: &mut GeneratorContext<'_>) -> Result<eks::ServiceCidrs> {
+    let region = ctx.region().await?;
+    let cluster_name = ctx.cluster_name().await?;
+    ctx.eks()
+        .cluster_cidrs(&region, &cluster_name)
         .await
         .context(error::EksError)
-        .and_then(|cidr| get_dns_from_cidr(&cidr))
-        .map_err(|e| eprintln!("Unable to parse CIDR from EKS, using default DNS IP: {}", e))
-        .ok()
+}
+
+/// Calculates the cluster DNS IPs from the service CIDRs, IPV4 first.
+fn get_dns_ips_from_cidrs(cidrs: &eks::ServiceCidrs) -> Result<Vec<String>> {
+    // a cluster without any service CIDR fails the same way as when only one DNS IP is wanted.
+    eks::get_cluster_cidr(cidrs).context(error::EksError)?;
+    let mut dns_ips = Vec::new();
+    if let Some(ipv4_cidr) = &cidrs.ipv4 {
+        dns_ips.push(get_dns_from_ipv4_cidr(ipv4_cidr)?);
+    }
+    if let Some(ipv6_cidr) = &cidrs.ipv6 {
+        dns_ips.push(get_dns_from_ipv6_cidr(ipv6_cidr)?);
+    }
+    Ok(dns_ips)
+}
+
+/// Calculates the cluster DNS IP from a service CIDR of either family.
+fn get_dns_from_cidr(cidr: &eks::ServiceCidr) -> Result<String> {
+    match cidr {
+        eks::ServiceCidr::Ipv4(cidr) => get_dns_from_ipv4_cidr(cidr),
+        eks::ServiceCidr::Ipv6(cidr) => get_dns_from_ipv6_cidr(cidr),
+    }
+}
+
+/// Splits `cidr` into its network address and prefix length, checking that the prefix length is
+/// a number no greater than `max_prefix`.
+fn split_cidr(cidr: &str, max_prefix: u8) -> Result<&str> {
+    let mut parts = cidr.splitn(2, '/');
+    let network = parts.next().unwrap_or_default();
+    let prefix = parts.next().context(error::CidrParse {
+        cidr,
+        reason: "missing prefix length",
+    })?;
+    match prefix.parse::<u8>() {
+        Ok(prefix) if prefix <= max_prefix => Ok(network),
+        _ => error::CidrParse {
+            cidr,
+            reason: format!("invalid prefix length '{}'", prefix),
+        }
+        .fail(),
+    }
 }
 
 /// Replicates [this] logic from the EKS AMI:
@@ -187,27 +505,49 @@ async fn get_dns_from_eks() -> Option<String> {
 /// DNS_CLUSTER_IP=${SERVICE_IPV4_CIDR%.*}.10
 /// ```
 /// [this]: https://github.com/awslabs/amazon-eks-ami/blob/732b6b2/files/bootstrap.sh#L335
-fn get_dns_from_cidr(cidr: &str) -> Result<String> {
-    let mut split: Vec<&str> = cidr.split('.').collect();
-    ensure!(
-        split.len() == 4,
-        error::CidrParse {
+fn get_dns_from_ipv4_cidr(cidr: &str) -> Result<String> {
+    let network = split_cidr(cidr, 32)?;
+    let network = network
+        .parse::<Ipv4Addr>()
+        .map_err(|e| e.to_string())
+        .or_else(|reason| error::CidrParse { cidr, reason }.fail())?;
+    let [a, b, c, _] = network.octets();
+    Ok(Ipv4Addr::new(a, b, c, 10).to_string())
+}
+
+/// Replicates [this] logic from the EKS AMI for IPV6 service CIDRs, which appends `a` to the last
+/// hextet of the network address:
+///
+/// ```sh
+/// DNS_CLUSTER_IP=${SERVICE_IPV6_CIDR%/*}a
+/// ```
+/// [this]: https://github.com/awslabs/amazon-eks-ami/blob/master/files/bootstrap.sh
+fn get_dns_from_ipv6_cidr(cidr: &str) -> Result<String> {
+    let network = split_cidr(cidr, 128)?;
+    if let Err(e) = network.parse::<Ipv6Addr>() {
+        return error::CidrParse {
             cidr,
-            reason: format!("expected 4 components but found {}", split.len())
+            reason: e.to_string(),
         }
-    );
-    split[3] = "10";
-    Ok(split.join("."))
+        .fail();
+    }
+    // Appending to a last hextet that already has four digits doesn't make an address.
+    format!("{}a", network)
+        .parse::<Ipv6Addr>()
+        .map(|dns_ip| dns_ip.to_string())
+        .map_err(|e| e.to_string())
+        .or_else(|reason| error::CidrParse { cidr, reason }.fail())
 }
 
 /// Gets gets the the first VPC IPV4 CIDR block from IMDS. If it starts with `10`, returns
-/// `10.100.0.10`, otherwise returns `172.20.0.10`
-async fn get_cluster_dns_from_imds_mac(client: &mut ImdsClient) -> Result<String> {
+/// `10.100.0.10`, otherwise returns `172.20.0.10`.  If the VPC has no IPV4 CIDR blocks but has IPV6
+/// CIDR blocks, returns an `Ipv6Only` error, because neither default can work.
+async fn get_cluster_dns_from_imds_mac(ctx: &mut GeneratorContext<'_>) -> Result<String> {
+    let client = ctx.imds().await?;
     // Take the first (primary) MAC address. Others may exist from attached ENIs.
     let mac = client
-        .fetch_mac_addresses()
-        .await
-        .context(error::ImdsRequest)?
+        .mac_addresses()
+        .await?
         .first()
         .context(error::ImdsNone {
             what: "mac addresses",
@@ -215,10 +555,19 @@ async fn get_cluster_dns_from_imds_mac(client: &mut ImdsClient) -> Result<String
         .clone();
 
     // Take the first CIDR block for the primary MAC.
-    let cidr_block = client
-        .fetch_cidr_blocks_for_mac(&mac)
-        .await
-        .context(error::ImdsRequest)?
+    let cidr_blocks = match client.cidr_blocks_for_mac(&mac).await? {
+        Some(cidr_blocks) => cidr_blocks,
+        None => {
+            if let Ok(cidr_blocks) = client.ipv6_cidr_blocks_for_mac(&mac).await {
+                return error::Ipv6Only { cidr_blocks }.fail();
+            }
+            return error::ImdsNone {
+                what: "CIDR blocks",
+            }
+            .fail();
+        }
+    };
+    let cidr_block = cidr_blocks
         .first()
         .context(error::ImdsNone {
             what: "CIDR blocks",
@@ -235,86 +584,1116 @@ async fn get_cluster_dns_from_imds_mac(client: &mut ImdsClient) -> Result<String
     Ok(dns)
 }
 
-async fn get_node_ip(client: &mut ImdsClient) -> Result<String> {
-    client
-        .fetch_local_ipv4_address()
+async fn get_node_ip(ctx: &mut GeneratorContext<'_>) -> Result<String> {
+    ctx.imds().await?.local_ipv4_address().await
+}
+
+/// Returns the provider ID that kubelet expects for the instance with `instance_id` in
+/// `availability_zone`, e.g. `aws:///us-west-2a/i-0123456789abcdef0`.
+fn format_provider_id(availability_zone: &str, instance_id: &str) -> String {
+    format!("aws:///{}/{}", availability_zone, instance_id)
+}
+
+async fn get_provider_id(ctx: &mut GeneratorContext<'_>) -> Result<String> {
+    let imds = ctx.imds().await?;
+    let availability_zone = imds.availability_zone().await?;
+    let instance_id = imds.instance_id().await?;
+    Ok(format_provider_id(&availability_zone, &instance_id))
+}
+
+const ZONE_LABEL: &str = "topology.kubernetes.io/zone";
+const REGION_LABEL: &str = "topology.kubernetes.io/region";
+const INSTANCE_TYPE_LABEL: &str = "node.kubernetes.io/instance-type";
+
+/// Returns the default node labels for the values that are known; a label without a value is left
+/// out.  The map is ordered so that the generated setting is stable.
+fn format_node_labels(
+    availability_zone: Option<String>,
+    region: Option<String>,
+    instance_type: Option<String>,
+) -> BTreeMap<&'static str, String> {
+    vec![
+        (ZONE_LABEL, availability_zone),
+        (REGION_LABEL, region),
+        (INSTANCE_TYPE_LABEL, instance_type),
+    ]
+    .into_iter()
+    .filter_map(|(label, value)| value.map(|value| (label, value)))
+    .collect()
+}
+
+async fn get_node_labels(ctx: &mut GeneratorContext<'_>) -> Result<BTreeMap<&'static str, String>> {
+    let imds = ctx.imds().await?;
+    let availability_zone = imds
+        .availability_zone()
         .await
-        .context(error::ImdsRequest)
+        .map_err(|e| {
+            eprintln!(
+                "Unable to get availability zone, omitting {}: {}",
+                ZONE_LABEL, e
+            )
+        })
+        .ok();
+    let (region, instance_type) = match imds.identity_document().await {
+        Ok(identity_document) => (
+            Some(identity_document.region().to_string()),
+            Some(identity_document.instance_type().to_string()),
+        ),
+        Err(e) => {
+            eprintln!(
+                "Unable to get identity document, omitting {} and {}: {}",
+                REGION_LABEL, INSTANCE_TYPE_LABEL, e
+            );
+            (None, None)
+        }
+    };
+    Ok(format_node_labels(availability_zone, region, instance_type))
 }
 
 /// Print usage message.
 fn usage() -> ! {
     let program_name = env::args().next().unwrap_or_else(|| "program".to_string());
     eprintln!(
-        r"Usage: {} [max-pods | cluster-dns-ip | node-ip]",
+        r"Usage: {} [--metadata-snapshot PATH]
+            [max-pods | cluster-dns-ip | cluster-dns-ips | node-ip | node-labels | node-taints
+            | provider-id]",
         program_name
     );
-    process::exit(1);
+    process::exit(setting_generator::FAIL_EXIT_CODE);
 }
 
-/// Parses args for the setting key name.
-fn parse_args(mut args: env::Args) -> String {
-    args.nth(1).unwrap_or_else(|| usage())
+/// Stores the args we receive on the command line.
+struct Args {
+    setting_name: String,
+    /// The metadata snapshot to use in place of IMDS, EKS, and the API, if any.
+    metadata_snapshot: Option<PathBuf>,
 }
 
-async fn run() -> Result<()> {
-    let setting_name = parse_args(env::args());
-    let mut client = ImdsClient::new().await.context(error::ImdsClient)?;
+/// Parses args for the setting key name and the metadata snapshot.
+fn parse_args(args: env::Args) -> Args {
+    let mut setting_name = None;
+    let mut metadata_snapshot = None;
+    let mut iter = args.skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_ref() {
+            "--metadata-snapshot" => {
+                metadata_snapshot = Some(PathBuf::from(iter.next().unwrap_or_else(|| usage())))
+            }
+            _ if setting_name.is_none() => setting_name = Some(arg),
+            _ => usage(),
+        }
+    }
+    Args {
+        setting_name: setting_name.unwrap_or_else(|| usage()),
+        metadata_snapshot,
+    }
+}
 
-    let setting = match setting_name.as_ref() {
-        "cluster-dns-ip" => get_cluster_dns_ip(&mut client).await,
-        "node-ip" => get_node_ip(&mut client).await,
-        // If we want to specify a reasonable default in a template, we can exit 2 to tell
-        // sundog to skip this setting.
-        "max-pods" => get_max_pods(&mut client)
-            .await
-            .map_err(|_| process::exit(2)),
+/// Returns the outcome for a setting generated with `result`.  There's no sensible default in an
+/// IPV6-only VPC, so that error skips the setting rather than failing.
+fn outcome<T: Serialize>(result: Result<T>) -> SettingGeneratorOutcome {
+    match result {
+        Ok(value) => SettingGeneratorOutcome::value(&value),
+        Err(e @ PlutoError::Ipv6Only { .. }) => SettingGeneratorOutcome::skip(e),
+        Err(e) => SettingGeneratorOutcome::fail(e),
+    }
+}
 
-        _ => usage(),
-    }?;
+async fn cluster_dns_ips(
+    ctx: &mut GeneratorContext<'_>,
+    report: &mut DegradationReport,
+) -> SettingGeneratorOutcome {
+    // 'cluster-dns-ips' is a list of addresses rather than a single string.
+    let result = get_cluster_dns_ips(ctx, report).await;
+    if let Ok(dns_ips) = &result {
+        report.set_value(dns_ips.join(","));
+    }
+    outcome(result)
+}
+
+async fn cluster_dns_ip(
+    ctx: &mut GeneratorContext<'_>,
+    report: &mut DegradationReport,
+) -> SettingGeneratorOutcome {
+    let result = get_cluster_dns_ip(ctx, report).await;
+    if let Ok(dns_ip) = &result {
+        report.set_value(dns_ip);
+    }
+    outcome(result)
+}
+
+async fn node_ip(ctx: &mut GeneratorContext<'_>) -> SettingGeneratorOutcome {
+    outcome(get_node_ip(ctx).await)
+}
+
+async fn max_pods(ctx: &mut GeneratorContext<'_>) -> SettingGeneratorOutcome {
+    // 'max_pods' setting is an unsigned integer, convert it to u32 before serializing to JSON
+    let result = get_max_pods(ctx).await.and_then(|setting| {
+        setting
+            .parse::<u32>()
+            .context(error::ParseToU32 { setting: &setting })
+    });
+    match result {
+        Ok(max_pods) => SettingGeneratorOutcome::value(&max_pods),
+        // A reasonable default for max-pods can be specified in a template, so tell sundog to
+        // skip the setting rather than failing.
+        Err(e) => SettingGeneratorOutcome::skip(e),
+    }
+}
+
+/// Returns the outcome for a setting generated from IMDS with `result`, like the node taints or the
+/// provider ID.  These are only defaults, so if IMDS can't be reached, the setting is skipped
+/// rather than failed.
+fn imds_outcome<T: Serialize>(result: Result<T>) -> SettingGeneratorOutcome {
+    match result {
+        Ok(value) => SettingGeneratorOutcome::value(&value),
+        Err(e @ PlutoError::ImdsClient { .. }) | Err(e @ PlutoError::ImdsRequest { .. }) => {
+            SettingGeneratorOutcome::skip(e)
+        }
+        Err(e) => SettingGeneratorOutcome::fail(e),
+    }
+}
+
+async fn node_taints(ctx: &mut GeneratorContext<'_>) -> SettingGeneratorOutcome {
+    imds_outcome(get_node_taints(ctx).await)
+}
+
+async fn provider_id(ctx: &mut GeneratorContext<'_>) -> SettingGeneratorOutcome {
+    imds_outcome(get_provider_id(ctx).await)
+}
+
+async fn node_labels(ctx: &mut GeneratorContext<'_>) -> SettingGeneratorOutcome {
+    imds_outcome(get_node_labels(ctx).await)
+}
+
+async fn run(report: &mut DegradationReport) -> SettingGeneratorOutcome {
+    let args = parse_args(env::args());
+    *report = DegradationReport::new(&args.setting_name);
+    let snapshot = match args.metadata_snapshot.map(MetadataSnapshot::from_file) {
+        Some(Ok(snapshot)) => Some(snapshot),
+        Some(Err(e)) => return SettingGeneratorOutcome::fail(e),
+        None => None,
+    };
+    let mut ctx = match &snapshot {
+        Some(snapshot) => GeneratorContext::from_snapshot(snapshot),
+        None => GeneratorContext::new(&ApiSettings, &EksApi),
+    };
+    generate(&args.setting_name, &mut ctx, report).await
+}
+
+/// Generates the setting `setting_name` from the sources in `ctx`.
+async fn generate(
+    setting_name: &str,
+    ctx: &mut GeneratorContext<'_>,
+    report: &mut DegradationReport,
+) -> SettingGeneratorOutcome {
+    // Every setting may need IMDS, so fail before generating anything if it can't be reached.
+    if let Err(e) = ctx.imds().await {
+        if ["node-labels", "node-taints", "provider-id"].contains(&setting_name) {
+            return imds_outcome::<()>(Err(e));
+        }
+        return SettingGeneratorOutcome::fail(e);
+    }
 
     // sundog expects JSON-serialized output so that many types can be represented, allowing the
     // API model to use more accurate types.
-
-    // 'max_pods' setting is an unsigned integer, convert 'settings' to u32 before serializing to JSON
-    if setting_name == "max-pods" {
-        let max_pods = serde_json::to_string(
-            &setting
-                .parse::<u32>()
-                .context(error::ParseToU32 { setting: &setting })?,
-        )
-        .context(error::OutputJson { output: &setting })?;
-        println!("{}", max_pods);
-    } else {
-        let output =
-            serde_json::to_string(&setting).context(error::OutputJson { output: &setting })?;
-        println!("{}", output);
+    match setting_name {
+        "cluster-dns-ips" => cluster_dns_ips(ctx, report).await,
+        "cluster-dns-ip" => cluster_dns_ip(ctx, report).await,
+        "node-ip" => node_ip(ctx).await,
+        "max-pods" => max_pods(ctx).await,
+        "node-taints" => node_taints(ctx).await,
+        "provider-id" => provider_id(ctx).await,
+        "node-labels" => node_labels(ctx).await,
+        _ => usage(),
     }
-    Ok(())
 }
 
-// Returning a Result from main makes it print a Debug representation of the error, but with Snafu
-// we have nice Display representations of the error, so we wrap "main" (run) and print any error.
-// https://github.com/shepmaster/snafu/issues/110
 #[tokio::main]
 async fn main() {
-    if let Err(e) = run().await {
-        eprintln!("{}", e);
-        process::exit(1);
-    }
+    let mut report = DegradationReport::default();
+    let outcome = run(&mut report).await;
+    report.print_summary();
+    outcome.exit_with();
 }
 
 #[test]
 fn test_get_dns_from_cidr_ok() {
-    let input = "123.456.789.0/123";
-    let expected = "123.456.789.10";
-    let actual = get_dns_from_cidr(input).unwrap();
+    let input = "123.45.67.0/24";
+    let expected = "123.45.67.10";
+    let actual = get_dns_from_ipv4_cidr(input).unwrap();
     assert_eq!(expected, actual);
 }
 
 #[test]
 fn test_get_dns_from_cidr_err() {
     let input = "123_456_789_0/123";
-    let result = get_dns_from_cidr(input);
+    let result = get_dns_from_ipv4_cidr(input);
     assert!(result.is_err());
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use api::SettingsSource;
+    use async_trait::async_trait;
+    use ec2::InstanceTypeSource;
+    use eks::ClusterCidrSource;
+    use httptest::{matchers::*, responders::*, Expectation, Server};
+    use imdsclient::ImdsClient;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tempfile::TempDir;
+
+    #[test]
+    fn identity_document_file_present() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("identity-document");
+        fs::write(
+            &path,
+            r#"{"region": "us-west-2", "instanceType": "m5.large", "accountId": "123"}"#,
+        )
+        .unwrap();
+        let identity_document = identity_document_from_file(&path).unwrap();
+        assert_eq!(identity_document.region(), "us-west-2");
+        assert_eq!(identity_document.instance_type(), "m5.large");
+    }
+
+    #[test]
+    fn identity_document_file_malformed() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("identity-document");
+        fs::write(&path, r#"{"instanceType": "m5.large"}"#).unwrap();
+        assert!(identity_document_from_file(&path).is_none());
+    }
+
+    #[test]
+    fn dns_from_ipv4_cidrs() {
+        for (cidr, dns_ip) in &[
+            ("10.100.0.0/16", "10.100.0.10"),
+            ("172.20.0.0/16", "172.20.0.10"),
+            ("192.168.4.0/22", "192.168.4.10"),
+            ("10.100.0.128/25", "10.100.0.10"),
+        ] {
+            assert_eq!(get_dns_from_ipv4_cidr(cidr).unwrap(), *dns_ip, "{}", cidr);
+        }
+    }
+
+    #[test]
+    fn dns_from_malformed_ipv4_cidrs() {
+        for cidr in &[
+            "",
+            "10.100.0.0",
+            "10.100.0.0/",
+            "10.100.0.0/33",
+            "10.100.0.0/-1",
+            "10.100.0/16",
+            "10.100.0.0.0/16",
+            "123.456.789.0/24",
+            "a.b.c.d/16",
+            "fd30:1c53:5f8a::/108",
+        ] {
+            assert!(
+                matches!(
+                    get_dns_from_ipv4_cidr(cidr),
+                    Err(error::PlutoError::CidrParse { .. })
+                ),
+                "{}",
+                cidr
+            );
+        }
+    }
+
+    #[test]
+    fn dns_from_ipv6_cidrs() {
+        for (cidr, dns_ip) in &[
+            ("fd30:1c53:5f8a::/108", "fd30:1c53:5f8a::a"),
+            ("fd00:ec2::/108", "fd00:ec2::a"),
+            ("fd30:1c53:5f8a:0:0:0:0:0/108", "fd30:1c53:5f8a::a"),
+            ("fd30:1c53:5f8a::10/124", "fd30:1c53:5f8a::10a"),
+        ] {
+            assert_eq!(get_dns_from_ipv6_cidr(cidr).unwrap(), *dns_ip, "{}", cidr);
+        }
+    }
+
+    #[test]
+    fn dns_from_malformed_ipv6_cidrs() {
+        for cidr in &[
+            "",
+            "fd30:1c53:5f8a::",
+            "fd30:1c53:5f8a::/",
+            "fd30:1c53:5f8a::/129",
+            "fd30:1c53:5f8a::/x",
+            "fd30:1c53:5f8a/108",
+            "fd30::5f8a::/108",
+            "fd30:1c53:5f8a::1234/112",
+            "not-an-address/108",
+            "10.100.0.0/16",
+        ] {
+            assert!(
+                matches!(
+                    get_dns_from_ipv6_cidr(cidr),
+                    Err(error::PlutoError::CidrParse { .. })
+                ),
+                "{}",
+                cidr
+            );
+        }
+    }
+
+    #[test]
+    fn dns_from_either_family() {
+        assert_eq!(
+            get_dns_from_cidr(&eks::ServiceCidr::Ipv4("10.100.0.0/16".to_string())).unwrap(),
+            "10.100.0.10"
+        );
+        assert_eq!(
+            get_dns_from_cidr(&eks::ServiceCidr::Ipv6("fd30:1c53:5f8a::/108".to_string())).unwrap(),
+            "fd30:1c53:5f8a::a"
+        );
+    }
+
+    #[test]
+    fn dns_ips_single_stack() {
+        let cidrs = eks::ServiceCidrs {
+            ipv4: Some("10.100.0.0/16".to_string()),
+            ipv6: None,
+        };
+        assert_eq!(get_dns_ips_from_cidrs(&cidrs).unwrap(), vec!["10.100.0.10"]);
+    }
+
+    #[test]
+    fn dns_ips_ipv6_only() {
+        let cidrs = eks::ServiceCidrs {
+            ipv4: None,
+            ipv6: Some("fd30:1c53:5f8a::/108".to_string()),
+        };
+        assert_eq!(
+            get_dns_ips_from_cidrs(&cidrs).unwrap(),
+            vec!["fd30:1c53:5f8a::a"]
+        );
+        assert!(get_dns_ips_from_cidrs(&eks::ServiceCidrs::default()).is_err());
+    }
+
+    #[test]
+    fn dns_ips_dual_stack() {
+        let cidrs = eks::ServiceCidrs {
+            ipv4: Some("172.20.0.0/16".to_string()),
+            ipv6: Some("fd30:1c53:5f8a::/108".to_string()),
+        };
+        assert_eq!(
+            get_dns_ips_from_cidrs(&cidrs).unwrap(),
+            vec!["172.20.0.10", "fd30:1c53:5f8a::a"]
+        );
+    }
+
+    #[test]
+    fn dns_ips_bad_ipv6() {
+        let cidrs = eks::ServiceCidrs {
+            ipv4: Some("172.20.0.0/16".to_string()),
+            ipv6: Some("not-an-address/108".to_string()),
+        };
+        assert!(get_dns_ips_from_cidrs(&cidrs).is_err());
+    }
+
+    #[test]
+    fn identity_document_file_absent() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("identity-document");
+        assert!(identity_document_from_file(&path).is_none());
+    }
+
+    /// Supplies the cluster name, or a missing-setting error if there isn't one.
+    struct MockSettings(Option<&'static str>);
+
+    #[async_trait]
+    impl SettingsSource for MockSettings {
+        async fn aws_k8s_info(&self) -> api::Result<api::AwsK8sInfo> {
+            match self.0 {
+                Some(cluster_name) => Ok(api::AwsK8sInfo {
+                    region: "us-west-2".to_string(),
+                    cluster_name: cluster_name.to_string(),
+                }),
+                None => Err(api::missing_setting_error("cluster-name")),
+            }
+        }
+
+        async fn cluster_dns_ip(&self) -> api::Result<Option<String>> {
+            Ok(None)
+        }
+    }
+
+    /// Supplies a cluster name, and the cluster-dns-ip setting: set, unset, or failing.
+    struct DnsIpSettings(std::result::Result<Option<&'static str>, ()>);
+
+    #[async_trait]
+    impl SettingsSource for DnsIpSettings {
+        async fn aws_k8s_info(&self) -> api::Result<api::AwsK8sInfo> {
+            Ok(api::AwsK8sInfo {
+                region: "us-west-2".to_string(),
+                cluster_name: "my-cluster".to_string(),
+            })
+        }
+
+        async fn cluster_dns_ip(&self) -> api::Result<Option<String>> {
+            match self.0 {
+                Ok(dns_ip) => Ok(dns_ip.map(str::to_string)),
+                Err(()) => Err(api::missing_setting_error("kubernetes")),
+            }
+        }
+    }
+
+    /// Supplies the service CIDR, IPV4 or IPV6 by its form, or an error as if the EKS call failed.
+    struct MockEks(Option<&'static str>);
+
+    #[async_trait]
+    impl ClusterCidrSource for MockEks {
+        async fn cluster_cidrs(
+            &self,
+            _region: &str,
+            _cluster: &str,
+        ) -> std::result::Result<eks::ServiceCidrs, eks::Error> {
+            match self.0 {
+                Some(cidr) if cidr.contains(':') => Ok(eks::ServiceCidrs {
+                    ipv4: None,
+                    ipv6: Some(cidr.to_string()),
+                }),
+                Some(cidr) => Ok(eks::ServiceCidrs {
+                    ipv4: Some(cidr.to_string()),
+                    ipv6: None,
+                }),
+                None => Err(eks::Error::Missing { field: "cluster" }),
+            }
+        }
+    }
+
+    /// Supplies network interface limits, or an error as if the EC2 call failed, and counts the
+    /// calls.
+    struct MockEc2(Option<ec2::EniLimits>, AtomicUsize);
+
+    impl MockEc2 {
+        fn new(limits: Option<ec2::EniLimits>) -> Self {
+            Self(limits, AtomicUsize::new(0))
+        }
+    }
+
+    #[async_trait]
+    impl InstanceTypeSource for MockEc2 {
+        async fn eni_limits(
+            &self,
+            region: &str,
+            _instance_type: &str,
+        ) -> std::result::Result<ec2::EniLimits, ec2::Error> {
+            assert_eq!(region, "us-west-2");
+            self.1.fetch_add(1, Ordering::SeqCst);
+            self.0.ok_or(ec2::Error::Missing {
+                field: "instance_types",
+            })
+        }
+    }
+
+    /// How the mock IMDS behaves when asked for the primary interface's CIDR blocks.
+    enum MockImds {
+        /// IMDS must not be asked for CIDR blocks; any request fails the test.
+        Unused,
+        /// IMDS returns this IPV4 CIDR block.
+        Cidr(&'static str),
+        /// IMDS returns this IPV4 CIDR block, and has an IPV6 CIDR block that isn't needed.
+        DualStack(&'static str),
+        /// IMDS has no IPV4 CIDR blocks, and returns this IPV6 CIDR block.
+        Ipv6Only(&'static str),
+        /// IMDS returns errors.
+        Down,
+    }
+
+    const MAC: &str = "06:aa:bb:cc:dd:ee";
+
+    fn imds_server(imds: &MockImds) -> Server {
+        let server = Server::run();
+        // the client only fetches a session token with its first request.
+        if !matches!(imds, MockImds::Unused) {
+            server.expect(
+                Expectation::matching(request::method_path("PUT", "/latest/api/token"))
+                    .times(1)
+                    .respond_with(status_code(200).body("some+token")),
+            );
+        }
+        let macs_path = "/2021-01-03/meta-data/network/interfaces/macs";
+        match imds {
+            MockImds::Unused => {}
+            MockImds::Cidr(cidr) | MockImds::DualStack(cidr) => {
+                server.expect(
+                    Expectation::matching(request::method_path("GET", macs_path))
+                        .times(1)
+                        .respond_with(status_code(200).body(MAC)),
+                );
+                server.expect(
+                    Expectation::matching(request::method_path(
+                        "GET",
+                        format!("{}/{}/vpc-ipv4-cidr-blocks", macs_path, MAC),
+                    ))
+                    .times(1)
+                    .respond_with(status_code(200).body(*cidr)),
+                );
+                if let MockImds::DualStack(_) = imds {
+                    // the IPV4 CIDR decides the default, so the IPV6 CIDR isn't requested.
+                    server.expect(
+                        Expectation::matching(request::method_path(
+                            "GET",
+                            format!("{}/{}/vpc-ipv6-cidr-blocks", macs_path, MAC),
+                        ))
+                        .times(0)
+                        .respond_with(status_code(200).body("2600:1f14:abc:de00::/56")),
+                    );
+                }
+            }
+            MockImds::Ipv6Only(cidr) => {
+                server.expect(
+                    Expectation::matching(request::method_path("GET", macs_path))
+                        .times(1)
+                        .respond_with(status_code(200).body(MAC)),
+                );
+                server.expect(
+                    Expectation::matching(request::method_path(
+                        "GET",
+                        format!("{}/{}/vpc-ipv4-cidr-blocks", macs_path, MAC),
+                    ))
+                    .times(1)
+                    .respond_with(status_code(404)),
+                );
+                server.expect(
+                    Expectation::matching(request::method_path(
+                        "GET",
+                        format!("{}/{}/vpc-ipv6-cidr-blocks", macs_path, MAC),
+                    ))
+                    .times(1)
+                    .respond_with(status_code(200).body(*cidr)),
+                );
+            }
+            MockImds::Down => {
+                server.expect(
+                    Expectation::matching(request::method_path("GET", macs_path))
+                        .times(1)
+                        .respond_with(status_code(500)),
+                );
+            }
+        }
+        server
+    }
+
+    /// An IPV6-only VPC produces the error that makes pluto skip the setting, rather than an IPV4
+    /// default that can't work.
+    #[tokio::test]
+    async fn ipv6_only_skips_setting() {
+        let server = imds_server(&MockImds::Ipv6Only("2600:1f14:abc:de00::/56"));
+        let base_uri = format!("http://localhost:{}", server.addr().port());
+        let client = ImdsClient::new_with_base_uri(base_uri);
+        let (settings, eks) = (MockSettings(None), MockEks(None));
+        let mut ctx = GeneratorContext::with_imds(client, &settings, &eks);
+        let mut report = DegradationReport::new("cluster-dns-ips");
+        let err = get_cluster_dns_ips(&mut ctx, &mut report)
+            .await
+            .unwrap_err();
+        match err {
+            PlutoError::Ipv6Only { cidr_blocks } => {
+                assert_eq!(cidr_blocks, vec!["2600:1f14:abc:de00::/56"])
+            }
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn outcomes() {
+        assert_eq!(
+            outcome(Ok("10.0.0.1".to_string())),
+            SettingGeneratorOutcome::Value(serde_json::json!("10.0.0.1"))
+        );
+        let ipv6_only: Result<String> = error::Ipv6Only {
+            cidr_blocks: vec!["2600:1f14:abc:de00::/56".to_string()],
+        }
+        .fail();
+        assert!(matches!(
+            outcome(ipv6_only),
+            SettingGeneratorOutcome::Skip(_)
+        ));
+        let missing: Result<String> = error::ImdsNone {
+            what: "mac addresses",
+        }
+        .fail();
+        assert!(matches!(outcome(missing), SettingGeneratorOutcome::Fail(_)));
+    }
+
+    /// Without IMDS, the instance type is unknown, so the default taints are skipped.
+    #[tokio::test]
+    async fn node_taints_skip_without_imds() {
+        // a port that nothing listens on, so connections to it are refused.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_uri = format!("http://localhost:{}", listener.local_addr().unwrap().port());
+        drop(listener);
+        let client = ImdsClient::new_with_base_uri(base_uri);
+        let (settings, eks) = (MockSettings(None), MockEks(None));
+        let mut ctx = GeneratorContext::with_imds(client, &settings, &eks);
+        assert!(matches!(
+            node_taints(&mut ctx).await,
+            SettingGeneratorOutcome::Skip(_)
+        ));
+    }
+
+    /// Returns a mock IMDS that hands out a session token and responds to `path` as given.
+    fn imds_target_server(path: &str, responder: impl Responder + 'static) -> Server {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("PUT", "/latest/api/token"))
+                .times(1)
+                .respond_with(status_code(200).body("some+token")),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/2021-01-03/{}", path)))
+                .times(1)
+                .respond_with(responder),
+        );
+        server
+    }
+
+    #[tokio::test]
+    async fn node_ip_from_imds() {
+        let server = imds_target_server(
+            "meta-data/local-ipv4",
+            status_code(200).body("192.168.1.23"),
+        );
+        let base_uri = format!("http://localhost:{}", server.addr().port());
+        let client = ImdsClient::new_with_base_uri(base_uri);
+        let (settings, eks) = (MockSettings(None), MockEks(None));
+        let mut ctx = GeneratorContext::with_imds(client, &settings, &eks);
+        assert_eq!(
+            node_ip(&mut ctx).await,
+            SettingGeneratorOutcome::Value(serde_json::json!("192.168.1.23"))
+        );
+    }
+
+    #[test]
+    fn provider_id_format() {
+        assert_eq!(
+            format_provider_id("us-west-2a", "i-0123456789abcdef0"),
+            "aws:///us-west-2a/i-0123456789abcdef0"
+        );
+        assert_eq!(
+            format_provider_id("cn-north-1b", "i-abc"),
+            "aws:///cn-north-1b/i-abc"
+        );
+    }
+
+    #[tokio::test]
+    async fn provider_id_from_imds() {
+        let server = imds_target_server(
+            "meta-data/placement/availability-zone",
+            status_code(200).body("us-west-2a"),
+        );
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                "/2021-01-03/meta-data/instance-id",
+            ))
+            .times(1)
+            .respond_with(status_code(200).body("i-0123456789abcdef0")),
+        );
+        let base_uri = format!("http://localhost:{}", server.addr().port());
+        let client = ImdsClient::new_with_base_uri(base_uri);
+        let (settings, eks) = (MockSettings(None), MockEks(None));
+        let mut ctx = GeneratorContext::with_imds(client, &settings, &eks);
+        assert_eq!(
+            provider_id(&mut ctx).await,
+            SettingGeneratorOutcome::Value(serde_json::json!(
+                "aws:///us-west-2a/i-0123456789abcdef0"
+            ))
+        );
+    }
+
+    #[test]
+    fn node_labels_full() {
+        let labels = format_node_labels(
+            Some("us-west-2a".to_string()),
+            Some("us-west-2".to_string()),
+            Some("m5.large".to_string()),
+        );
+        assert_eq!(
+            serde_json::to_string(&labels).unwrap(),
+            r#"{"node.kubernetes.io/instance-type":"m5.large","topology.kubernetes.io/region":"us-west-2","topology.kubernetes.io/zone":"us-west-2a"}"#
+        );
+    }
+
+    #[test]
+    fn node_labels_partial() {
+        let labels = format_node_labels(Some("us-west-2a".to_string()), None, None);
+        assert_eq!(
+            serde_json::to_string(&labels).unwrap(),
+            r#"{"topology.kubernetes.io/zone":"us-west-2a"}"#
+        );
+        let labels = format_node_labels(None, None, None);
+        assert_eq!(serde_json::to_string(&labels).unwrap(), "{}");
+    }
+
+    /// A label that IMDS can't provide is left out rather than failing the setting.
+    #[tokio::test]
+    async fn node_labels_omit_unknown() {
+        let server = imds_target_server(
+            "meta-data/placement/availability-zone",
+            status_code(200).body("us-west-2a"),
+        );
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                "/2021-01-03/dynamic/instance-identity/document",
+            ))
+            .respond_with(status_code(404)),
+        );
+        let base_uri = format!("http://localhost:{}", server.addr().port());
+        let client = ImdsClient::new_with_base_uri(base_uri);
+        let (settings, eks) = (MockSettings(None), MockEks(None));
+        let mut ctx = GeneratorContext::with_imds(client, &settings, &eks);
+        assert_eq!(
+            node_labels(&mut ctx).await,
+            SettingGeneratorOutcome::Value(serde_json::json!({
+                "topology.kubernetes.io/zone": "us-west-2a",
+            }))
+        );
+    }
+
+    /// Without IMDS, the provider ID is skipped, and sundog sees exit code 2.
+    #[tokio::test]
+    async fn provider_id_skips_without_imds() {
+        // a port that nothing listens on, so connections to it are refused.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_uri = format!("http://localhost:{}", listener.local_addr().unwrap().port());
+        drop(listener);
+        let client = ImdsClient::new_with_base_uri(base_uri);
+        let (settings, eks) = (MockSettings(None), MockEks(None));
+        let mut ctx = GeneratorContext::with_imds(client, &settings, &eks);
+        let mut report = DegradationReport::new("provider-id");
+        assert!(matches!(
+            generate("provider-id", &mut ctx, &mut report).await,
+            SettingGeneratorOutcome::Skip(_)
+        ));
+    }
+
+    /// A template can give max-pods a default, so a failure skips the setting rather than failing
+    /// it, and sundog sees exit code 2.
+    #[tokio::test]
+    async fn max_pods_skips_on_imds_failure() {
+        let server = imds_target_server("dynamic/instance-identity/document", status_code(500));
+        let base_uri = format!("http://localhost:{}", server.addr().port());
+        let client = ImdsClient::new_with_base_uri(base_uri);
+        let (settings, eks) = (MockSettings(None), MockEks(None));
+        let mut ctx = GeneratorContext::with_imds(client, &settings, &eks);
+        assert!(matches!(
+            max_pods(&mut ctx).await,
+            SettingGeneratorOutcome::Skip(_)
+        ));
+    }
+
+    #[test]
+    fn imds_outcomes() {
+        assert_eq!(
+            imds_outcome(Ok(vec!["nvidia.com/gpu=true:NoSchedule".to_string()])),
+            SettingGeneratorOutcome::Value(serde_json::json!(["nvidia.com/gpu=true:NoSchedule"]))
+        );
+        assert_eq!(
+            imds_outcome(Ok(Vec::<String>::new())),
+            SettingGeneratorOutcome::Value(serde_json::json!([]))
+        );
+        let missing: Result<Vec<String>> = error::ImdsNone {
+            what: "instance type",
+        }
+        .fail();
+        assert!(matches!(
+            imds_outcome(missing),
+            SettingGeneratorOutcome::Fail(_)
+        ));
+    }
+
+    /// The whole `get_cluster_dns_ip` decision tree: EKS first, then the IMDS MAC CIDR, with the
+    /// default address chosen from the CIDR.
+    #[tokio::test]
+    async fn cluster_dns_ip_fallback_order() {
+        let cases = vec![
+            (
+                "EKS success",
+                MockSettings(Some("my-cluster")),
+                MockEks(Some("10.100.0.0/16")),
+                MockImds::Unused,
+                Some("10.100.0.10"),
+            ),
+            (
+                "EKS success, IPV6 cluster",
+                MockSettings(Some("my-cluster")),
+                MockEks(Some("fd30:1c53:5f8a::/108")),
+                MockImds::Unused,
+                Some("fd30:1c53:5f8a::a"),
+            ),
+            (
+                "EKS failure, 10.x CIDR",
+                MockSettings(Some("my-cluster")),
+                MockEks(None),
+                MockImds::Cidr("10.0.0.0/16"),
+                Some(DEFAULT_10_RANGE_DNS_CLUSTER_IP),
+            ),
+            (
+                "EKS failure, 192.168.x CIDR",
+                MockSettings(Some("my-cluster")),
+                MockEks(None),
+                MockImds::Cidr("192.168.0.0/16"),
+                Some(DEFAULT_DNS_CLUSTER_IP),
+            ),
+            (
+                "EKS failure, dual-stack 10.x CIDR",
+                MockSettings(Some("my-cluster")),
+                MockEks(None),
+                MockImds::DualStack("10.0.0.0/16"),
+                Some(DEFAULT_10_RANGE_DNS_CLUSTER_IP),
+            ),
+            (
+                "EKS failure, IPV6-only",
+                MockSettings(Some("my-cluster")),
+                MockEks(None),
+                MockImds::Ipv6Only("2600:1f14:abc:de00::/56"),
+                None,
+            ),
+            (
+                "cluster name missing",
+                MockSettings(None),
+                MockEks(Some("10.100.0.0/16")),
+                MockImds::Cidr("192.168.0.0/16"),
+                Some(DEFAULT_DNS_CLUSTER_IP),
+            ),
+            (
+                "everything down",
+                MockSettings(None),
+                MockEks(None),
+                MockImds::Down,
+                None,
+            ),
+        ];
+
+        for (name, settings, eks, imds, expected) in cases {
+            let server = imds_server(&imds);
+            let base_uri = format!("http://localhost:{}", server.addr().port());
+            let client = ImdsClient::new_with_base_uri(base_uri);
+            let mut ctx = GeneratorContext::with_imds(client, &settings, &eks);
+            let mut report = DegradationReport::new("cluster-dns-ip");
+            let actual = get_cluster_dns_ip(&mut ctx, &mut report).await.ok();
+            assert_eq!(actual.as_deref(), expected, "case '{}'", name);
+            // every case but EKS success falls back to IMDS.
+            assert_eq!(
+                report.is_empty(),
+                matches!(imds, MockImds::Unused),
+                "case '{}'",
+                name
+            );
+        }
+    }
+
+    /// Every setting can be generated offline from the checked-in snapshot, without fallbacks.
+    #[tokio::test]
+    async fn snapshot_settings() {
+        let snapshot = MetadataSnapshot::from_file(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/data/metadata-snapshot.json"
+        ))
+        .unwrap();
+        let cases = vec![
+            ("cluster-dns-ip", serde_json::json!("10.100.0.10")),
+            (
+                "cluster-dns-ips",
+                serde_json::json!(["10.100.0.10", "fd30:1c53:5f8a::a"]),
+            ),
+            ("node-ip", serde_json::json!("192.168.1.23")),
+            ("max-pods", serde_json::json!(58)),
+            (
+                "provider-id",
+                serde_json::json!("aws:///us-west-2a/i-0123456789abcdef0"),
+            ),
+            (
+                "node-taints",
+                serde_json::json!(["nvidia.com/gpu=true:NoSchedule"]),
+            ),
+            (
+                "node-labels",
+                serde_json::json!({
+                    "node.kubernetes.io/instance-type": "g5.xlarge",
+                    "topology.kubernetes.io/region": "us-west-2",
+                    "topology.kubernetes.io/zone": "us-west-2a",
+                }),
+            ),
+        ];
+        for (setting_name, expected) in cases {
+            let mut ctx = GeneratorContext::from_snapshot(&snapshot);
+            let mut report = DegradationReport::new(setting_name);
+            assert_eq!(
+                generate(setting_name, &mut ctx, &mut report).await,
+                SettingGeneratorOutcome::Value(expected),
+                "{}",
+                setting_name
+            );
+            assert!(report.is_empty(), "{}", setting_name);
+        }
+    }
+
+    /// Without the service CIDRs, the cluster DNS IP falls back to the snapshot's CIDR blocks.
+    #[tokio::test]
+    async fn snapshot_cluster_dns_ip_fallback() {
+        let snapshot: MetadataSnapshot = serde_json::from_str(
+            r#"{
+                "region": "us-west-2",
+                "cluster-name": "my-cluster",
+                "macs": ["06:aa:bb:cc:dd:ee"],
+                "cidr-blocks": {"06:aa:bb:cc:dd:ee": ["10.0.0.0/16"]}
+            }"#,
+        )
+        .unwrap();
+        let mut ctx = GeneratorContext::from_snapshot(&snapshot);
+        let mut report = DegradationReport::new("cluster-dns-ip");
+        assert_eq!(
+            generate("cluster-dns-ip", &mut ctx, &mut report).await,
+            SettingGeneratorOutcome::Value(serde_json::json!(DEFAULT_10_RANGE_DNS_CLUSTER_IP))
+        );
+        assert!(!report.is_empty());
+
+        let snapshot: MetadataSnapshot = serde_json::from_str(
+            r#"{
+                "macs": ["06:aa:bb:cc:dd:ee"],
+                "ipv6-cidr-blocks": {"06:aa:bb:cc:dd:ee": ["2600:1f14:abc:de00::/56"]}
+            }"#,
+        )
+        .unwrap();
+        let mut ctx = GeneratorContext::from_snapshot(&snapshot);
+        let mut report = DegradationReport::new("cluster-dns-ip");
+        assert!(matches!(
+            generate("cluster-dns-ip", &mut ctx, &mut report).await,
+            SettingGeneratorOutcome::Skip(_)
+        ));
+    }
+
+    /// The cluster-dns-ip setting is used as is when it's set, without asking EKS or IMDS; when
+    /// it's unset, invalid, or can't be read, EKS is asked as before.
+    #[tokio::test]
+    async fn cluster_dns_ip_setting_first() {
+        let cases = vec![
+            ("setting set", Ok(Some("10.0.0.53")), "10.0.0.53", true),
+            ("IPV6 setting set", Ok(Some("fd00::a")), "fd00::a", true),
+            (
+                "setting invalid",
+                Ok(Some("10.0.0.530")),
+                "10.100.0.10",
+                false,
+            ),
+            (
+                "setting not an address",
+                Ok(Some("dns")),
+                "10.100.0.10",
+                false,
+            ),
+            ("setting unset", Ok(None), "10.100.0.10", true),
+            ("API failure", Err(()), "10.100.0.10", false),
+        ];
+
+        for (name, setting, expected, no_fallback) in cases {
+            // any request to IMDS fails the test.
+            let server = imds_server(&MockImds::Unused);
+            let base_uri = format!("http://localhost:{}", server.addr().port());
+            let client = ImdsClient::new_with_base_uri(base_uri);
+            let settings = DnsIpSettings(setting);
+            let eks = MockEks(Some("10.100.0.0/16"));
+            let mut ctx = GeneratorContext::with_imds(client, &settings, &eks);
+            let mut report = DegradationReport::new("cluster-dns-ip");
+            let actual = get_cluster_dns_ip(&mut ctx, &mut report).await.unwrap();
+            assert_eq!(actual, expected, "case '{}'", name);
+            assert_eq!(report.is_empty(), no_fallback, "case '{}'", name);
+        }
+    }
+
+    /// max-pods comes from the eni-max-pods file, then the embedded overrides, and only then from
+    /// EC2.
+    #[tokio::test]
+    async fn max_pods_sources() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("eni-max-pods");
+        fs::write(
+            &path,
+            "# Mapping is calculated from AWS EC2 API\nm5.large 29\n",
+        )
+        .unwrap();
+        // 4 * (15 - 1) + 2
+        let limits = ec2::EniLimits {
+            max_enis: 4,
+            ipv4_addresses_per_eni: 15,
+        };
+        let cases = vec![
+            ("file hit", "m5.large", Some(limits), Some("29"), 0),
+            ("override hit", "m6i.large", Some(limits), Some("29"), 0),
+            (
+                "file miss, EC2 success",
+                "zz9.xlarge",
+                Some(limits),
+                Some("58"),
+                1,
+            ),
+            ("both miss", "zz9.xlarge", None, None, 1),
+        ];
+
+        for (name, instance_type, limits, expected, ec2_calls) in cases {
+            let (settings, eks) = (MockSettings(Some("my-cluster")), MockEks(None));
+            let ec2 = MockEc2::new(limits);
+            let mut ctx = GeneratorContext::new(&settings, &eks).with_ec2(&ec2);
+            let result = get_max_pods_for(&mut ctx, &path, instance_type).await;
+            if expected.is_none() {
+                assert!(
+                    matches!(result, Err(PlutoError::NoInstanceTypeMaxPods { .. })),
+                    "case '{}'",
+                    name
+                );
+            }
+            assert_eq!(result.ok().as_deref(), expected, "case '{}'", name);
+            assert_eq!(ec2.1.load(Ordering::SeqCst), ec2_calls, "case '{}'", name);
+        }
+    }
+
+    /// Counts the calls to the Bottlerocket API, which always succeed.
+    #[derive(Default)]
+    struct CountingSettings(AtomicUsize);
+
+    #[async_trait]
+    impl SettingsSource for CountingSettings {
+        async fn aws_k8s_info(&self) -> api::Result<api::AwsK8sInfo> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(api::AwsK8sInfo {
+                region: "us-west-2".to_string(),
+                cluster_name: "my-cluster".to_string(),
+            })
+        }
+
+        async fn cluster_dns_ip(&self) -> api::Result<Option<String>> {
+            Ok(None)
+        }
+    }
+
+    /// The region and cluster name are fetched once, however many settings need them.
+    #[tokio::test]
+    async fn context_memoizes_cluster_info() {
+        let server = imds_server(&MockImds::Unused);
+        let base_uri = format!("http://localhost:{}", server.addr().port());
+        let client = ImdsClient::new_with_base_uri(base_uri);
+        let settings = CountingSettings::default();
+        let eks = MockEks(Some("10.100.0.0/16"));
+        let mut ctx = GeneratorContext::with_imds(client, &settings, &eks);
+
+        let mut report = DegradationReport::new("cluster-dns-ip");
+        let dns_ip = get_cluster_dns_ip(&mut ctx, &mut report).await.unwrap();
+        let mut report = DegradationReport::new("cluster-dns-ips");
+        let dns_ips = get_cluster_dns_ips(&mut ctx, &mut report).await.unwrap();
+
+        assert_eq!(dns_ip, "10.100.0.10");
+        assert_eq!(dns_ips, vec!["10.100.0.10"]);
+        assert_eq!(ctx.region().await.unwrap(), "us-west-2");
+        assert_eq!(ctx.cluster_name().await.unwrap(), "my-cluster");
+        assert_eq!(settings.0.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,110 @@
+//! Provides the max-pods overrides that are embedded in pluto from `data/max-pods-overrides.toml`.
+//!
+//! Lookup precedence for max-pods is:
+//! 1. the instance type's entry in the eni-max-pods file (handled by the caller)
+//! 2. the per-size entry for the instance type in the overrides, e.g. `m6i.sizes.large`
+//! 3. the family-level `max-pods` entry in the overrides, e.g. `m6i.max-pods`
+//!
+//! If none of these match, max-pods is calculated from the instance type's network interface limits
+//! in EC2, see the ec2 module.
+
+use serde::Deserialize;
+use snafu::{ResultExt, Snafu};
+use std::collections::HashMap;
+
+/// The overrides table, read at compile time.
+const OVERRIDES: &str = include_str!("../data/max-pods-overrides.toml");
+
+#[derive(Debug, Snafu)]
+pub(super) enum Error {
+    #[snafu(display("Unable to parse max-pods overrides: {}", source))]
+    Parse { source: toml::de::Error },
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// The overrides for a single instance family.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct FamilyOverride {
+    /// Applies to every size in the family that doesn't have its own entry in `sizes`.
+    max_pods: Option<u32>,
+    /// Per-size values, keyed by the part of the instance type after the family, e.g. `large`.
+    #[serde(default)]
+    sizes: HashMap<String, u32>,
+}
+
+/// Max-pods overrides keyed by instance family.
+#[derive(Debug, Deserialize)]
+#[serde(transparent)]
+pub(super) struct MaxPodsOverrides {
+    families: HashMap<String, FamilyOverride>,
+}
+
+impl MaxPodsOverrides {
+    /// Returns the overrides embedded in pluto.
+    pub(super) fn embedded() -> Result<Self> {
+        Self::from_toml(OVERRIDES)
+    }
+
+    fn from_toml(data: &str) -> Result<Self> {
+        toml::from_str(data).context(Parse)
+    }
+
+    /// Returns the max-pods override for `instance_type`, if there is one. A per-size entry takes
+    /// precedence over the family-level entry.
+    pub(super) fn get(&self, instance_type: &str) -> Option<u32> {
+        let mut split = instance_type.splitn(2, '.');
+        let family = self.families.get(split.next()?)?;
+        split
+            .next()
+            .and_then(|size| family.sizes.get(size).copied())
+            .or(family.max_pods)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TEST_OVERRIDES: &str = r#"
+        [abc]
+        max-pods = 10
+
+        [abc.sizes]
+        metal = 100
+
+        [xyz.sizes]
+        large = 20
+    "#;
+
+    #[test]
+    fn size_match() {
+        let overrides = MaxPodsOverrides::from_toml(TEST_OVERRIDES).unwrap();
+        assert_eq!(overrides.get("abc.metal"), Some(100));
+        assert_eq!(overrides.get("xyz.large"), Some(20));
+    }
+
+    #[test]
+    fn family_match() {
+        let overrides = MaxPodsOverrides::from_toml(TEST_OVERRIDES).unwrap();
+        assert_eq!(overrides.get("abc.large"), Some(10));
+        assert_eq!(overrides.get("abc"), Some(10));
+    }
+
+    #[test]
+    fn miss() {
+        let overrides = MaxPodsOverrides::from_toml(TEST_OVERRIDES).unwrap();
+        // family without a family-level entry and no matching size
+        assert_eq!(overrides.get("xyz.xlarge"), None);
+        // unknown family
+        assert_eq!(overrides.get("m5.large"), None);
+        assert_eq!(overrides.get(""), None);
+    }
+
+    #[test]
+    fn embedded_parses() {
+        let overrides = MaxPodsOverrides::embedded().unwrap();
+        assert_eq!(overrides.get("m6i.large"), Some(29));
+    }
+}
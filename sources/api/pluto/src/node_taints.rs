@@ -0,0 +1,120 @@
+//! Provides the default node taints that are embedded in pluto from `data/node-taints.toml`.
+//!
+//! Each rule in the table applies to a list of instance families, and an instance type matches a
+//! rule if its family, the part before the `.`, is in the list.  The taints of every matching rule
+//! are returned in table order, without duplicates.  Instance types that match no rule, like most
+//! general purpose types, get no taints.
+
+use serde::Deserialize;
+use snafu::{ResultExt, Snafu};
+
+/// The rule table, read at compile time.
+const RULES: &str = include_str!("../data/node-taints.toml");
+
+#[derive(Debug, Snafu)]
+pub(super) enum Error {
+    #[snafu(display("Unable to parse node taint rules: {}", source))]
+    Parse { source: toml::de::Error },
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// The taints for a group of instance families.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Rule {
+    families: Vec<String>,
+    taints: Vec<String>,
+}
+
+/// Default node taints by instance family.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(super) struct NodeTaintRules {
+    rules: Vec<Rule>,
+}
+
+impl NodeTaintRules {
+    /// Returns the rules embedded in pluto.
+    pub(super) fn embedded() -> Result<Self> {
+        Self::from_toml(RULES)
+    }
+
+    fn from_toml(data: &str) -> Result<Self> {
+        toml::from_str(data).context(Parse)
+    }
+
+    /// Returns the taints for `instance_type`, which are empty if no rule matches it.
+    pub(super) fn taints(&self, instance_type: &str) -> Vec<String> {
+        let family = instance_type.split('.').next().unwrap_or_default();
+        let mut taints: Vec<String> = Vec::new();
+        for rule in &self.rules {
+            if !rule.families.iter().any(|f| f == family) {
+                continue;
+            }
+            for taint in &rule.taints {
+                if !taints.contains(taint) {
+                    taints.push(taint.clone());
+                }
+            }
+        }
+        taints
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TEST_RULES: &str = r#"
+        [[rules]]
+        families = ["abc", "abcd"]
+        taints = ["example.com/accel=true:NoSchedule"]
+
+        [[rules]]
+        families = ["abcd"]
+        taints = ["example.com/special=true:NoExecute", "example.com/accel=true:NoSchedule"]
+    "#;
+
+    #[test]
+    fn family_match() {
+        let rules = NodeTaintRules::from_toml(TEST_RULES).unwrap();
+        assert_eq!(
+            rules.taints("abc.large"),
+            vec!["example.com/accel=true:NoSchedule"]
+        );
+        // every matching rule applies, and a taint that two rules share is only given once.
+        assert_eq!(
+            rules.taints("abcd.xlarge"),
+            vec![
+                "example.com/accel=true:NoSchedule",
+                "example.com/special=true:NoExecute"
+            ]
+        );
+    }
+
+    #[test]
+    fn miss() {
+        let rules = NodeTaintRules::from_toml(TEST_RULES).unwrap();
+        // families are matched whole, not by prefix.
+        assert!(rules.taints("ab.large").is_empty());
+        assert!(rules.taints("abcde.large").is_empty());
+        assert!(rules.taints("").is_empty());
+    }
+
+    #[test]
+    fn embedded_families() {
+        let rules = NodeTaintRules::embedded().unwrap();
+        let gpu = vec!["nvidia.com/gpu=true:NoSchedule"];
+        let neuron = vec!["aws.amazon.com/neuron=true:NoSchedule"];
+        assert_eq!(rules.taints("p4d.24xlarge"), gpu);
+        assert_eq!(rules.taints("g5.xlarge"), gpu);
+        assert_eq!(rules.taints("inf1.xlarge"), neuron);
+        assert_eq!(rules.taints("inf2.48xlarge"), neuron);
+        assert_eq!(rules.taints("trn1.32xlarge"), neuron);
+        assert!(rules.taints("m5.large").is_empty());
+        assert!(rules.taints("c6i.metal").is_empty());
+        // g4ad has AMD GPUs, which the NVIDIA device plugin doesn't advertise.
+        assert!(rules.taints("g4ad.xlarge").is_empty());
+    }
+}
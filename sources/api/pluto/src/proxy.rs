@@ -0,0 +1,177 @@
+//! Decides whether pluto's calls to AWS APIs go through the HTTPS proxy in the
+//! `settings.network.https-proxy` setting, which instances in VPCs without direct egress need to
+//! reach EKS.  Hosts that match an entry of `settings.network.no-proxy` are reached directly.
+//!
+//! A `no-proxy` entry matches a host if it's `*`, the host itself, or a domain that the host is
+//! in.  A leading `.` or `*.` is ignored, so `.amazonaws.com`, `*.amazonaws.com`, and
+//! `amazonaws.com` all match `eks.us-west-2.amazonaws.com`.  Matching ignores case and ports.  IP
+//! addresses only match exactly; CIDR ranges aren't supported.
+
+use std::net::IpAddr;
+
+/// The proxy settings from the Bottlerocket API.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ProxySettings {
+    pub(crate) https_proxy: Option<String>,
+    pub(crate) no_proxy: Vec<String>,
+}
+
+impl ProxySettings {
+    /// Returns the URI of the proxy that requests to `host` go through, or `None` if they go
+    /// directly.  A proxy without a scheme is taken to be `http://`, as other Bottlerocket
+    /// services do.
+    pub(crate) fn proxy_for(&self, host: &str) -> Option<String> {
+        let proxy = self
+            .https_proxy
+            .as_deref()
+            .map(str::trim)
+            .filter(|proxy| !proxy.is_empty())?;
+        if is_no_proxy(host, &self.no_proxy) {
+            return None;
+        }
+        if proxy.contains("://") {
+            Some(proxy.to_string())
+        } else {
+            Some(format!("http://{}", proxy))
+        }
+    }
+}
+
+/// Returns whether `host`, which may have a port, matches an entry of `no_proxy`.
+fn is_no_proxy<S: AsRef<str>>(host: &str, no_proxy: &[S]) -> bool {
+    let host = host.trim().to_ascii_lowercase();
+    let host = strip_port(&host).trim_end_matches('.');
+    no_proxy
+        .iter()
+        .any(|entry| matches_entry(host, &entry.as_ref().trim().to_ascii_lowercase()))
+}
+
+/// Returns whether `host`, without a port, matches the `no-proxy` entry `entry`.
+fn matches_entry(host: &str, entry: &str) -> bool {
+    if entry == "*" {
+        return true;
+    }
+    let entry = strip_port(entry).trim_end_matches('.');
+    let domain = entry.trim_start_matches("*.").trim_start_matches('.');
+    if domain.is_empty() {
+        return false;
+    }
+    if host == domain {
+        return true;
+    }
+    // an address isn't in a domain, and a domain can't contain an address.
+    if host.parse::<IpAddr>().is_ok() || domain.parse::<IpAddr>().is_ok() {
+        return false;
+    }
+    host.ends_with(&format!(".{}", domain))
+}
+
+/// Removes the port from a host or `no-proxy` entry, e.g. `example.com:443`, along with the
+/// brackets around an IPV6 address, e.g. `[fd00::1]:443`.  A bare IPV6 address is kept as is.
+fn strip_port(host: &str) -> &str {
+    if let Some(rest) = host.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    match host.rfind(':') {
+        Some(colon) if host.matches(':').count() == 1 => &host[..colon],
+        _ => host,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const EKS_HOST: &str = "eks.us-west-2.amazonaws.com";
+
+    fn settings(no_proxy: &[&str]) -> ProxySettings {
+        ProxySettings {
+            https_proxy: Some("proxy.example.com:3128".to_string()),
+            no_proxy: no_proxy.iter().map(|entry| entry.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn domain_entries() {
+        for entry in &[
+            "amazonaws.com",
+            ".amazonaws.com",
+            "*.amazonaws.com",
+            "us-west-2.amazonaws.com",
+            EKS_HOST,
+            "EKS.us-west-2.AmazonAWS.com",
+            "amazonaws.com:443",
+            "*",
+        ] {
+            assert!(is_no_proxy(EKS_HOST, &[entry]), "{}", entry);
+        }
+        for entry in &[
+            "aws.com",
+            "naws.com",
+            "s.amazonaws.com",
+            "us-east-1.amazonaws.com",
+            ".",
+            "*.",
+            "",
+            "eks.us-west-2.amazonaws.com.cn",
+        ] {
+            assert!(!is_no_proxy(EKS_HOST, &[entry]), "{}", entry);
+        }
+    }
+
+    #[test]
+    fn hosts_with_ports() {
+        assert!(is_no_proxy("localhost:4566", &["localhost"]));
+        assert!(is_no_proxy(
+            "eks.us-west-2.amazonaws.com.",
+            &["amazonaws.com"]
+        ));
+        assert!(is_no_proxy("[fd00::1]:443", &["fd00::1"]));
+    }
+
+    #[test]
+    fn address_entries() {
+        assert!(is_no_proxy("10.0.1.2", &["10.0.1.2"]));
+        assert!(is_no_proxy("fd00::1", &["[fd00::1]:443"]));
+        // addresses aren't matched by suffix, and CIDR ranges aren't supported.
+        assert!(!is_no_proxy("10.0.1.2", &["1.2"]));
+        assert!(!is_no_proxy("10.0.1.2", &["10.0.0.0/16"]));
+    }
+
+    #[test]
+    fn several_entries() {
+        assert!(is_no_proxy(EKS_HOST, &["localhost", "", ".amazonaws.com"]));
+        assert!(!is_no_proxy(EKS_HOST, &[] as &[&str]));
+    }
+
+    #[test]
+    fn proxy_for_host() {
+        assert_eq!(
+            settings(&["localhost"]).proxy_for(EKS_HOST).as_deref(),
+            Some("http://proxy.example.com:3128")
+        );
+        assert_eq!(
+            settings(&["localhost", ".amazonaws.com"]).proxy_for(EKS_HOST),
+            None
+        );
+
+        let with_scheme = ProxySettings {
+            https_proxy: Some("https://proxy.example.com".to_string()),
+            no_proxy: Vec::new(),
+        };
+        assert_eq!(
+            with_scheme.proxy_for(EKS_HOST).as_deref(),
+            Some("https://proxy.example.com")
+        );
+    }
+
+    #[test]
+    fn no_proxy_without_https_proxy() {
+        assert_eq!(ProxySettings::default().proxy_for(EKS_HOST), None);
+        let empty = ProxySettings {
+            https_proxy: Some(" ".to_string()),
+            no_proxy: Vec::new(),
+        };
+        assert_eq!(empty.proxy_for(EKS_HOST), None);
+    }
+}
@@ -0,0 +1,259 @@
+//! Provides `MetadataSnapshot`, which serves canned values in place of IMDS, EKS, and the
+//! Bottlerocket API, so that pipelines that validate images can run pluto without any of them by
+//! passing `--metadata-snapshot <path>`.
+//!
+//! The snapshot is a JSON object like this, where every key is optional:
+//!
+//! ```json
+//! {
+//!   "region": "us-west-2",
+//!   "instance-type": "g5.xlarge",
+//!   "identity-document": {"region": "us-west-2", "instanceType": "g5.xlarge"},
+//!   "local-ipv4": "192.168.1.23",
+//!   "availability-zone": "us-west-2a",
+//!   "instance-id": "i-0123456789abcdef0",
+//!   "macs": ["06:aa:bb:cc:dd:ee"],
+//!   "cidr-blocks": {"06:aa:bb:cc:dd:ee": ["192.168.0.0/16"]},
+//!   "ipv6-cidr-blocks": {"06:aa:bb:cc:dd:ee": ["2600:1f14:abc:de00::/56"]},
+//!   "max-pods": {"g5.xlarge": 58},
+//!   "cluster-name": "my-cluster",
+//!   "cluster-dns-ip": "10.100.0.10",
+//!   "service-ipv4-cidr": "172.20.0.0/16",
+//!   "service-ipv6-cidr": "fd30:1c53:5f8a::/108"
+//! }
+//! ```
+//!
+//! The identity document is built from `region` and `instance-type` unless it's given.  The
+//! `max-pods` table takes the place of the eni-max-pods file; the embedded overrides still apply
+//! to instance types that it doesn't list, but EC2 isn't asked.  Without either service CIDR, the
+//! EKS lookup fails and pluto falls back to the CIDR blocks, as it would online.  Keys that pluto
+//! doesn't know are ignored, so one snapshot can be shared with other tools.
+
+use crate::api::{self, AwsK8sInfo, SettingsSource};
+use crate::ec2::{self, EniLimits, InstanceTypeSource};
+use crate::eks::{self, ClusterCidrSource, ServiceCidrs};
+use crate::error;
+use crate::imds::ImdsSource;
+use crate::Result;
+use async_trait::async_trait;
+use imdsclient::IdentityDocument;
+use serde::Deserialize;
+use snafu::{OptionExt, ResultExt};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Canned metadata, read from a snapshot file.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct MetadataSnapshot {
+    region: Option<String>,
+    instance_type: Option<String>,
+    identity_document: Option<IdentityDocument>,
+    local_ipv4: Option<String>,
+    availability_zone: Option<String>,
+    instance_id: Option<String>,
+    #[serde(default)]
+    macs: Vec<String>,
+    #[serde(default)]
+    cidr_blocks: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    ipv6_cidr_blocks: HashMap<String, Vec<String>>,
+    /// The maximum number of pods by instance type.
+    #[serde(default)]
+    max_pods: HashMap<String, u32>,
+    cluster_name: Option<String>,
+    cluster_dns_ip: Option<String>,
+    service_ipv4_cidr: Option<String>,
+    service_ipv6_cidr: Option<String>,
+}
+
+impl MetadataSnapshot {
+    /// Reads the snapshot at `path`.
+    pub(crate) fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let data = fs::read_to_string(path).context(error::SnapshotRead { path })?;
+        Self::from_json(&data).context(error::SnapshotParse { path })
+    }
+
+    fn from_json(data: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(data)
+    }
+
+    /// Returns the maximum number of pods by instance type.
+    pub(crate) fn max_pods(&self) -> &HashMap<String, u32> {
+        &self.max_pods
+    }
+
+    fn region(&self) -> Option<&str> {
+        self.region.as_deref().or_else(|| {
+            self.identity_document
+                .as_ref()
+                .map(|identity_document| identity_document.region())
+        })
+    }
+}
+
+#[async_trait]
+impl<'a> ImdsSource for &'a MetadataSnapshot {
+    async fn identity_document(&mut self) -> Result<IdentityDocument> {
+        if let Some(identity_document) = &self.identity_document {
+            return Ok(identity_document.clone());
+        }
+        let region = self
+            .region
+            .as_ref()
+            .context(error::SnapshotMissing { key: "region" })?;
+        let instance_type = self
+            .instance_type
+            .as_ref()
+            .context(error::SnapshotMissing {
+                key: "instance-type",
+            })?;
+        serde_json::from_value(serde_json::json!({
+            "region": region,
+            "instanceType": instance_type,
+        }))
+        .context(error::SnapshotIdentityDocument)
+    }
+
+    async fn mac_addresses(&mut self) -> Result<Vec<String>> {
+        Ok(self.macs.clone())
+    }
+
+    async fn cidr_blocks_for_mac(&mut self, mac: &str) -> Result<Option<Vec<String>>> {
+        Ok(self.cidr_blocks.get(mac).cloned())
+    }
+
+    async fn ipv6_cidr_blocks_for_mac(&mut self, mac: &str) -> Result<Vec<String>> {
+        self.ipv6_cidr_blocks
+            .get(mac)
+            .cloned()
+            .context(error::SnapshotMissing {
+                key: format!("ipv6-cidr-blocks.{}", mac),
+            })
+    }
+
+    async fn local_ipv4_address(&mut self) -> Result<String> {
+        self.local_ipv4
+            .clone()
+            .context(error::SnapshotMissing { key: "local-ipv4" })
+    }
+
+    async fn availability_zone(&mut self) -> Result<String> {
+        self.availability_zone
+            .clone()
+            .context(error::SnapshotMissing {
+                key: "availability-zone",
+            })
+    }
+
+    async fn instance_id(&mut self) -> Result<String> {
+        self.instance_id
+            .clone()
+            .context(error::SnapshotMissing { key: "instance-id" })
+    }
+}
+
+#[async_trait]
+impl SettingsSource for MetadataSnapshot {
+    async fn aws_k8s_info(&self) -> api::Result<AwsK8sInfo> {
+        let region = self
+            .region()
+            .ok_or_else(|| api::missing_setting_error("region"))?;
+        let cluster_name = self
+            .cluster_name
+            .as_ref()
+            .ok_or_else(|| api::missing_setting_error("cluster-name"))?;
+        Ok(AwsK8sInfo {
+            region: region.to_string(),
+            cluster_name: cluster_name.clone(),
+        })
+    }
+
+    async fn cluster_dns_ip(&self) -> api::Result<Option<String>> {
+        Ok(self.cluster_dns_ip.clone())
+    }
+}
+
+#[async_trait]
+impl ClusterCidrSource for MetadataSnapshot {
+    async fn cluster_cidrs(
+        &self,
+        _region: &str,
+        _cluster: &str,
+    ) -> std::result::Result<ServiceCidrs, eks::Error> {
+        if self.service_ipv4_cidr.is_none() && self.service_ipv6_cidr.is_none() {
+            return Err(eks::Error::Missing {
+                field: "service-ipv4-cidr",
+            });
+        }
+        Ok(ServiceCidrs {
+            ipv4: self.service_ipv4_cidr.clone(),
+            ipv6: self.service_ipv6_cidr.clone(),
+        })
+    }
+}
+
+/// Instance type limits aren't in snapshots; the `max-pods` table takes their place.
+#[async_trait]
+impl InstanceTypeSource for MetadataSnapshot {
+    async fn eni_limits(
+        &self,
+        _region: &str,
+        _instance_type: &str,
+    ) -> std::result::Result<EniLimits, ec2::Error> {
+        Err(ec2::Error::Missing {
+            field: "instance_types",
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn identity_document_from_keys() {
+        let snapshot = MetadataSnapshot::from_json(
+            r#"{"region": "us-west-2", "instance-type": "m5.large", "unknown": [1, 2]}"#,
+        )
+        .unwrap();
+        let identity_document = (&snapshot).identity_document().await.unwrap();
+        assert_eq!(identity_document.region(), "us-west-2");
+        assert_eq!(identity_document.instance_type(), "m5.large");
+    }
+
+    #[tokio::test]
+    async fn identity_document_given() {
+        let snapshot = MetadataSnapshot::from_json(
+            r#"{
+                "instance-type": "m5.large",
+                "identity-document": {"region": "eu-west-1", "instanceType": "c5.xlarge"}
+            }"#,
+        )
+        .unwrap();
+        let identity_document = (&snapshot).identity_document().await.unwrap();
+        assert_eq!(identity_document.instance_type(), "c5.xlarge");
+        // without a region key, the region is the identity document's.
+        assert_eq!(snapshot.region(), Some("eu-west-1"));
+    }
+
+    #[tokio::test]
+    async fn missing_keys() {
+        let snapshot = MetadataSnapshot::from_json("{}").unwrap();
+        assert!(matches!(
+            (&snapshot).identity_document().await,
+            Err(error::PlutoError::SnapshotMissing { .. })
+        ));
+        assert!(matches!(
+            (&snapshot).local_ipv4_address().await,
+            Err(error::PlutoError::SnapshotMissing { .. })
+        ));
+        assert_eq!(
+            (&snapshot).cidr_blocks_for_mac("06:aa").await.unwrap(),
+            None
+        );
+        assert!(snapshot.cluster_cidrs("us-west-2", "c").await.is_err());
+    }
+}
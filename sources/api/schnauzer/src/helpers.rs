@@ -5,11 +5,15 @@
 use handlebars::{Context, Handlebars, Helper, Output, RenderContext, RenderError};
 use lazy_static::lazy_static;
 use num_cpus;
+use serde::Deserialize;
 use serde_json::value::Value;
 use snafu::{OptionExt, ResultExt};
 use std::borrow::Borrow;
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::Path;
 use url::Url;
 
 lazy_static! {
@@ -47,43 +51,98 @@ lazy_static! {
 const ECR_FALLBACK_REGION: &str = "us-east-1";
 const ECR_FALLBACK_REGISTRY: &str = "328549459982";
 
+/// The registry to pull pause container images from for each region, the registry for regions
+/// that aren't listed, and the version of the image, as TOML.  These are kept in a data file so
+/// that a new region or image version only needs a data change.
+const PAUSE_CONTAINER_ACCOUNTS: &str = include_str!("../pause-container-accounts.toml");
+
+/// An optional file on the host, in the same format as `PAUSE_CONTAINER_ACCOUNTS` but with every
+/// key optional.  Its region entries are added to the embedded ones, replacing the embedded entry
+/// for a region listed in both, and its fallback and version replace the embedded ones.
+const PAUSE_CONTAINER_ACCOUNTS_OVERRIDE: &str = "/usr/share/bottlerocket/ecr-accounts.toml";
+
 lazy_static! {
-    /// A map to tell us which registry to pull pause container images from for a given region.
-    static ref PAUSE_CONTAINER_MAP: HashMap<&'static str, &'static str> = {
-        let mut m = HashMap::new();
-        m.insert("af-south-1", "877085696533");
-        m.insert("ap-east-1", "800184023465");
-        m.insert("ap-northeast-1", "602401143452");
-        m.insert("ap-northeast-2", "602401143452");
-        m.insert("ap-northeast-3", "602401143452");
-        m.insert("ap-south-1", "602401143452");
-        m.insert("ap-southeast-1", "602401143452");
-        m.insert("ap-southeast-2", "602401143452");
-        m.insert("ca-central-1", "602401143452");
-        m.insert("cn-north-1", "918309763551");
-        m.insert("cn-northwest-1", "961992271922");
-        m.insert("eu-central-1", "602401143452");
-        m.insert("eu-north-1", "602401143452");
-        m.insert("eu-south-1", "590381155156");
-        m.insert("eu-west-1", "602401143452");
-        m.insert("eu-west-2", "602401143452");
-        m.insert("eu-west-3", "602401143452");
-        m.insert("me-south-1", "558608220178");
-        m.insert("sa-east-1", "602401143452");
-        m.insert("us-east-1", "602401143452");
-        m.insert("us-east-2", "602401143452");
-        m.insert("us-gov-east-1", "151742754352");
-        m.insert("us-gov-west-1", "013241004608");
-        m.insert("us-west-1", "602401143452");
-        m.insert("us-west-2", "602401143452");
-        m
-    };
+    /// Tells us which registry to pull pause container images from for a given region, and which
+    /// version of the image to pull.
+    static ref PAUSE_CONTAINER_INFO: PauseContainerInfo =
+        pause_container_info(PAUSE_CONTAINER_ACCOUNTS_OVERRIDE);
 }
 
-/// But if there is a region that does not exist in our map (for example a new
-/// region is created or being tested), then we will fall back to this.
-const PAUSE_FALLBACK_REGISTRY: &str = "602401143452";
-const PAUSE_FALLBACK_REGION: &str = "us-east-1";
+/// The format of the pause container data files.  Top-level string keys are regions, mapped to
+/// the account of the registry in that region.
+#[derive(Debug, Deserialize)]
+struct PauseContainerData {
+    /// The version of the pause image, i.e. its tag.
+    version: Option<String>,
+    /// The registry to use for regions that aren't listed.
+    fallback: Option<PauseContainerFallback>,
+    #[serde(flatten)]
+    accounts: HashMap<String, String>,
+}
+
+/// If there is a region that does not exist in our map (for example a new region is created or
+/// being tested), then we will fall back to this.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct PauseContainerFallback {
+    region: String,
+    account: String,
+}
+
+/// The pause container registries and image version, from the embedded data and the override file.
+#[derive(Debug, Clone, PartialEq)]
+struct PauseContainerInfo {
+    accounts: HashMap<String, String>,
+    fallback: PauseContainerFallback,
+    version: String,
+}
+
+/// Builds the pause container information from the embedded data and the override file at
+/// `override_path`, if it exists.  If the override file can't be read or parsed, it's ignored with
+/// a warning, so that a bad file can't break every template that uses the information.
+fn pause_container_info<P: AsRef<Path>>(override_path: P) -> PauseContainerInfo {
+    let override_path = override_path.as_ref();
+    // a unit test ensures that the embedded data parses and is complete.
+    let embedded: PauseContainerData = toml::from_str(PAUSE_CONTAINER_ACCOUNTS)
+        .expect("embedded pause container accounts are invalid");
+    let mut info = PauseContainerInfo {
+        accounts: embedded.accounts,
+        fallback: embedded
+            .fallback
+            .expect("embedded pause container accounts have no fallback"),
+        version: embedded
+            .version
+            .expect("embedded pause container accounts have no version"),
+    };
+    let data = match fs::read_to_string(override_path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return info,
+        Err(e) => {
+            warn!(
+                "Unable to read '{}', using built-in pause container registries: {}",
+                override_path.display(),
+                e
+            );
+            return info;
+        }
+    };
+    match toml::from_str::<PauseContainerData>(&data) {
+        Ok(overrides) => {
+            info.accounts.extend(overrides.accounts);
+            if let Some(fallback) = overrides.fallback {
+                info.fallback = fallback;
+            }
+            if let Some(version) = overrides.version {
+                info.version = version;
+            }
+        }
+        Err(e) => warn!(
+            "Unable to parse '{}', using built-in pause container registries: {}",
+            override_path.display(),
+            e
+        ),
+    }
+    info
+}
 
 /// The amount of CPU to reserve
 /// We are using these CPU ranges from GKE
@@ -825,6 +884,37 @@ pub fn kube_reserve_memory(
     Ok(())
 }
 
+/// The `pause-version` helper returns the version of the pause container image, i.e. its tag, from
+/// the same data as `pause-prefix`, so that the version can be changed with the data file rather
+/// than every template that names the image.
+///
+/// This helper takes no parameters.
+///
+/// # Example
+///
+/// `{{ pause-prefix settings.aws.region }}/eks/pause-{{ goarch os.arch }}:{{ pause-version }}`
+///
+/// This would result in something like:
+/// `602401143452.dkr.ecr.eu-central-1.amazonaws.com/eks/pause-amd64:3.1`
+pub fn pause_version(
+    helper: &Helper<'_, '_>,
+    _: &Handlebars,
+    _: &Context,
+    renderctx: &mut RenderContext<'_, '_>,
+    out: &mut dyn Output,
+) -> Result<(), RenderError> {
+    trace!("Starting pause version helper");
+    let template_name = template_name(renderctx);
+    check_param_count(helper, template_name, 0)?;
+
+    out.write(&PAUSE_CONTAINER_INFO.version)
+        .with_context(|| error::TemplateWrite {
+            template: template_name.to_owned(),
+        })?;
+
+    Ok(())
+}
+
 /// Get the amount of CPU to reserve for kubeReserved in millicores
 pub fn kube_reserve_cpu(
     helper: &Helper<'_, '_>,
@@ -926,10 +1016,17 @@ fn ecr_registry<S: AsRef<str>>(region: S) -> String {
 /// Constructs the fully qualified domain name for the pause container (pod infra
 /// container) for the given region. Returns a default if the region is not mapped.
 fn pause_registry<S: AsRef<str>>(region: S) -> String {
+    pause_registry_in(&PAUSE_CONTAINER_INFO, region)
+}
+
+fn pause_registry_in<S: AsRef<str>>(info: &PauseContainerInfo, region: S) -> String {
     // lookup the registry ID or fallback to the default region and id
-    let (region, registry_id) = match PAUSE_CONTAINER_MAP.borrow().get(region.as_ref()) {
-        None => (PAUSE_FALLBACK_REGION, PAUSE_FALLBACK_REGISTRY),
-        Some(registry_id) => (region.as_ref(), *registry_id),
+    let (region, registry_id) = match info.accounts.get(region.as_ref()) {
+        None => (
+            info.fallback.region.as_str(),
+            info.fallback.account.as_str(),
+        ),
+        Some(registry_id) => (region.as_ref(), registry_id.as_str()),
     };
     format!("{}.dkr.ecr.{}.amazonaws.com", registry_id, region)
 }
@@ -1259,6 +1356,151 @@ mod test_ecr_registry {
     }
 }
 
+#[cfg(test)]
+mod test_pause_container_map {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    /// The map as it was hard-coded before it moved to a data file.
+    const HARD_CODED: &[(&str, &str)] = &[
+        ("af-south-1", "877085696533"),
+        ("ap-east-1", "800184023465"),
+        ("ap-northeast-1", "602401143452"),
+        ("ap-northeast-2", "602401143452"),
+        ("ap-northeast-3", "602401143452"),
+        ("ap-south-1", "602401143452"),
+        ("ap-southeast-1", "602401143452"),
+        ("ap-southeast-2", "602401143452"),
+        ("ca-central-1", "602401143452"),
+        ("cn-north-1", "918309763551"),
+        ("cn-northwest-1", "961992271922"),
+        ("eu-central-1", "602401143452"),
+        ("eu-north-1", "602401143452"),
+        ("eu-south-1", "590381155156"),
+        ("eu-west-1", "602401143452"),
+        ("eu-west-2", "602401143452"),
+        ("eu-west-3", "602401143452"),
+        ("me-south-1", "558608220178"),
+        ("sa-east-1", "602401143452"),
+        ("us-east-1", "602401143452"),
+        ("us-east-2", "602401143452"),
+        ("us-gov-east-1", "151742754352"),
+        ("us-gov-west-1", "013241004608"),
+        ("us-west-1", "602401143452"),
+        ("us-west-2", "602401143452"),
+    ];
+
+    fn embedded_only() -> PauseContainerInfo {
+        let dir = TempDir::new().unwrap();
+        pause_container_info(dir.path().join("missing.toml"))
+    }
+
+    fn with_override(data: &str) -> PauseContainerInfo {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("ecr-accounts.toml");
+        fs::write(&path, data).unwrap();
+        pause_container_info(&path)
+    }
+
+    #[test]
+    fn hard_coded_regions_unchanged() {
+        let map = embedded_only().accounts;
+        assert_eq!(map.len(), HARD_CODED.len());
+        for (region, account) in HARD_CODED {
+            assert_eq!(
+                map.get(*region).map(String::as_str),
+                Some(*account),
+                "{}",
+                region
+            );
+        }
+    }
+
+    #[test]
+    fn hard_coded_registries_unchanged() {
+        for (region, account) in HARD_CODED {
+            assert_eq!(
+                pause_registry(region),
+                format!("{}.dkr.ecr.{}.amazonaws.com", account, region)
+            );
+        }
+    }
+
+    #[test]
+    fn hard_coded_fallback_and_version_unchanged() {
+        let info = embedded_only();
+        assert_eq!(
+            info.fallback,
+            PauseContainerFallback {
+                region: "us-east-1".to_string(),
+                account: "602401143452".to_string(),
+            }
+        );
+        assert_eq!(info.version, "3.1");
+        assert_eq!(
+            pause_registry_in(&info, "xy-ztown-1"),
+            "602401143452.dkr.ecr.us-east-1.amazonaws.com"
+        );
+    }
+
+    #[test]
+    fn override_extends() {
+        let info = with_override(
+            r#"
+"xy-ztown-1" = "111111111111"
+"eu-south-1" = "222222222222"
+"#,
+        );
+        let map = &info.accounts;
+        assert_eq!(map.len(), HARD_CODED.len() + 1);
+        assert_eq!(map["xy-ztown-1"], "111111111111");
+        assert_eq!(map["eu-south-1"], "222222222222");
+        assert_eq!(map["us-west-2"], "602401143452");
+        // a file with only regions keeps the embedded fallback and version.
+        assert_eq!(info.fallback, embedded_only().fallback);
+        assert_eq!(info.version, "3.1");
+    }
+
+    #[test]
+    fn override_fallback_and_version() {
+        let info = with_override(
+            r#"
+version = "3.5"
+"xy-ztown-1" = "111111111111"
+
+[fallback]
+region = "us-west-2"
+account = "333333333333"
+"#,
+        );
+        assert_eq!(info.version, "3.5");
+        assert_eq!(info.accounts.len(), HARD_CODED.len() + 1);
+        assert!(!info.accounts.contains_key("version"));
+        assert_eq!(
+            pause_registry_in(&info, "xy-ztown-2"),
+            "333333333333.dkr.ecr.us-west-2.amazonaws.com"
+        );
+        assert_eq!(
+            pause_registry_in(&info, "xy-ztown-1"),
+            "111111111111.dkr.ecr.xy-ztown-1.amazonaws.com"
+        );
+    }
+
+    #[test]
+    fn corrupt_override() {
+        for data in &[
+            "not toml",
+            r#""xy-ztown-1" = 111111111111"#,
+            "version = 3.5",
+            "[fallback]\nregion = \"us-west-2\"",
+            "[fallbak]\nregion = \"us-west-2\"\naccount = \"333333333333\"",
+        ] {
+            assert_eq!(with_override(data), embedded_only(), "{}", data);
+        }
+    }
+}
+
 #[cfg(test)]
 mod test_pause_registry {
     use super::*;
@@ -1274,6 +1516,7 @@ mod test_pause_registry {
     {
         let mut registry = Handlebars::new();
         registry.register_helper("pause-prefix", Box::new(pause_prefix));
+        registry.register_helper("pause-version", Box::new(pause_version));
 
         registry.render_template(tmpl, data)
     }
@@ -1318,6 +1561,20 @@ mod test_pause_registry {
         .unwrap();
         assert_eq!(result, EXPECTED_URL_XY_ZTOWN_1);
     }
+
+    #[test]
+    fn version() {
+        let result = setup_and_render_template(
+            "{{ pause-prefix settings.aws.region }}/eks/pause-amd64:{{ pause-version }}",
+            &json!({"settings": {"aws": {"region": "eu-central-1"}}}),
+        )
+        .unwrap();
+        assert_eq!(
+            result,
+            "602401143452.dkr.ecr.eu-central-1.amazonaws.com/eks/pause-amd64:3.1"
+        );
+        assert!(setup_and_render_template("{{ pause-version 1 }}", &json!({})).is_err());
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,172 @@
+/*!
+# Background
+
+sundog runs setting generators, like pluto, and decides what to do with each setting from the
+generator's exit code:
+
+- `0`: the generator printed the setting's value to stdout as a JSON document, which sundog sets.
+- `1`: the generator failed, so sundog fails.
+- `2`: the setting should be skipped, e.g. because a reasonable default is available, so sundog
+  leaves it unset and continues with other generators.
+
+Any other exit code is unexpected and fails sundog.
+
+This library encodes that contract so that generators don't have to repeat it by convention.
+A generator returns a `SettingGeneratorOutcome` and calls `exit_with()` on it, which prints the
+value to stdout, or the reason for a skip or failure to stderr, and exits with the matching code.
+*/
+
+#![deny(rust_2018_idioms)]
+
+use serde::Serialize;
+use std::fmt;
+use std::process;
+
+/// The exit code that tells sundog the setting was generated.
+pub const SUCCESS_EXIT_CODE: i32 = 0;
+/// The exit code that tells sundog the generator failed.
+pub const FAIL_EXIT_CODE: i32 = 1;
+/// The exit code that tells sundog to skip the setting.
+pub const SKIP_EXIT_CODE: i32 = 2;
+
+/// The result of generating a setting.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SettingGeneratorOutcome {
+    /// The generated value of the setting.
+    Value(serde_json::Value),
+    /// The setting should be skipped, for the given reason.
+    Skip(String),
+    /// The setting couldn't be generated, because of the given error.
+    Fail(String),
+}
+
+/// What a generator prints, and the code it exits with, for an outcome.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exit {
+    pub stdout: Option<String>,
+    pub stderr: Option<String>,
+    pub code: i32,
+}
+
+impl SettingGeneratorOutcome {
+    /// Returns the outcome for a generated `value`.  If the value can't be serialized, that's a
+    /// failure.
+    pub fn value<T: Serialize>(value: &T) -> Self {
+        match serde_json::to_value(value) {
+            Ok(value) => SettingGeneratorOutcome::Value(value),
+            Err(e) => SettingGeneratorOutcome::Fail(format!(
+                "Unable to serialize setting value to JSON: {}",
+                e
+            )),
+        }
+    }
+
+    /// Returns the outcome for a setting that should be skipped for `reason`.
+    pub fn skip<R: fmt::Display>(reason: R) -> Self {
+        SettingGeneratorOutcome::Skip(reason.to_string())
+    }
+
+    /// Returns the outcome for a setting that couldn't be generated because of `error`.
+    pub fn fail<E: fmt::Display>(error: E) -> Self {
+        SettingGeneratorOutcome::Fail(error.to_string())
+    }
+
+    /// Returns what should be printed for this outcome, and the exit code that sundog expects.
+    pub fn exit(&self) -> Exit {
+        match self {
+            SettingGeneratorOutcome::Value(value) => Exit {
+                stdout: Some(value.to_string()),
+                stderr: None,
+                code: SUCCESS_EXIT_CODE,
+            },
+            SettingGeneratorOutcome::Skip(reason) => Exit {
+                stdout: None,
+                stderr: Some(format!("Skipping setting: {}", reason)),
+                code: SKIP_EXIT_CODE,
+            },
+            SettingGeneratorOutcome::Fail(error) => Exit {
+                stdout: None,
+                stderr: Some(error.clone()),
+                code: FAIL_EXIT_CODE,
+            },
+        }
+    }
+
+    /// Prints the value to stdout, or the reason for a skip or failure to stderr, and exits with
+    /// the code that sundog expects.
+    pub fn exit_with(self) -> ! {
+        let exit = self.exit();
+        if let Some(stdout) = exit.stdout {
+            println!("{}", stdout);
+        }
+        if let Some(stderr) = exit.stderr {
+            eprintln!("{}", stderr);
+        }
+        process::exit(exit.code);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn value() {
+        assert_eq!(
+            SettingGeneratorOutcome::value(&"10.0.0.1").exit(),
+            Exit {
+                stdout: Some(r#""10.0.0.1""#.to_string()),
+                stderr: None,
+                code: 0,
+            }
+        );
+        assert_eq!(
+            SettingGeneratorOutcome::value(&vec!["10.100.0.10", "fd00::a"]).exit(),
+            Exit {
+                stdout: Some(r#"["10.100.0.10","fd00::a"]"#.to_string()),
+                stderr: None,
+                code: 0,
+            }
+        );
+        assert_eq!(
+            SettingGeneratorOutcome::value(&29u32),
+            SettingGeneratorOutcome::Value(json!(29))
+        );
+    }
+
+    #[test]
+    fn skip() {
+        assert_eq!(
+            SettingGeneratorOutcome::skip("no default").exit(),
+            Exit {
+                stdout: None,
+                stderr: Some("Skipping setting: no default".to_string()),
+                code: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn fail() {
+        assert_eq!(
+            SettingGeneratorOutcome::fail("IMDS request failed").exit(),
+            Exit {
+                stdout: None,
+                stderr: Some("IMDS request failed".to_string()),
+                code: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn unserializable_value_fails() {
+        use std::collections::HashMap;
+        // JSON object keys must be strings.
+        let mut map = HashMap::new();
+        map.insert(vec![1], 1);
+        let exit = SettingGeneratorOutcome::value(&map).exit();
+        assert_eq!(exit.code, FAIL_EXIT_CODE);
+        assert!(exit.stdout.is_none());
+    }
+}
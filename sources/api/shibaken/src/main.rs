@@ -6,16 +6,32 @@ shibaken is called by sundog as a setting generator.
 shibaken will fetch and populate the admin container's user-data with authorized ssh keys from the
 AWS instance metadata service (IMDS).
 
+The keys come from the instance's key pairs, at `meta-data/public-keys`, followed by any keys pushed
+for the admin container's user, `ec2-user`, with EC2 Instance Connect, at
+`meta-data/managed-ssh-keys/active-keys/ec2-user`.  A key that's in both, or listed twice, is only
+authorized once, in the place it was first seen; keys are compared by type and key data, so their
+comments don't matter.
+
+If IMDS is disabled on the instance, or the token hop limit keeps shibaken from reaching it, there
+are no keys to fetch, so the user-data is generated with an empty list of authorized keys rather
+than failing.  The same goes for any failure to connect to IMDS, or a request to it that times out;
+a warning is logged in each case.
+
+By default the user-data is printed as a JSON string of its base64 encoding, which is what the admin
+container's user-data setting expects.  With `--output-format json`, the user-data JSON is printed
+as is, which is easier to debug and suits consumers that take plain JSON.
+
 (The name "shibaken" comes from the fact that Shiba are small, but agile, hunting dogs.)
 */
 
 #![deny(rust_2018_idioms)]
 
-use imdsclient::ImdsClient;
-use log::{debug, info};
+use imdsclient::{ErrorKind, ImdsClient};
+use log::{debug, info, warn};
 use serde::Serialize;
 use simplelog::{ColorChoice, Config as LogConfig, LevelFilter, TermLogger, TerminalMode};
 use snafu::{OptionExt, ResultExt};
+use std::collections::HashSet;
 use std::str::FromStr;
 use std::{env, process};
 
@@ -39,19 +55,114 @@ impl UserData {
     }
 }
 
-/// Returns a list of public keys.
+// shibaken only needs public keys, so its client can't fetch anything else, like credentials.
+const IMDS_ALLOWED_PREFIXES: &[&str] = &["meta-data/public-keys", "meta-data/managed-ssh-keys"];
+
+/// The keys pushed with EC2 Instance Connect for the admin container's user, under `meta-data`.
+const MANAGED_SSH_KEYS_TARGET: &str = "managed-ssh-keys/active-keys/ec2-user";
+
+/// Returns a list of public keys, which is empty if IMDS can't be reached.
 async fn fetch_public_keys_from_imds() -> Result<Vec<String>> {
+    fetch_public_keys(ImdsClient::new()).await
+}
+
+/// Returns a list of public keys fetched with `client`, which is empty if IMDS can't be reached.
+async fn fetch_public_keys(client: ImdsClient) -> Result<Vec<String>> {
     info!("Connecting to IMDS");
-    let mut client = ImdsClient::new().await.context(error::ImdsClient)?;
-    client
-        .fetch_public_ssh_keys()
-        .await
-        .context(error::ImdsClient)
+    let mut client = client.allowed_prefixes(IMDS_ALLOWED_PREFIXES);
+    let public_keys = match client.fetch_public_ssh_keys().await {
+        Ok(public_keys) => public_keys,
+        Err(e) if is_unreachable(&e) => {
+            warn!(
+                "Unable to reach IMDS to fetch public keys, using none: {}",
+                e
+            );
+            return Ok(Vec::new());
+        }
+        Err(e) => return Err(e).context(error::ImdsRequest),
+    };
+    let managed_keys = fetch_managed_keys(&mut client).await?;
+    Ok(collect_public_keys(&public_keys, &managed_keys))
+}
+
+/// Returns whether `e` means IMDS couldn't be reached, rather than that a request to it failed:
+/// it's disabled or blocked by the hop limit, or the connection failed or timed out.
+fn is_unreachable(e: &imdsclient::Error) -> bool {
+    matches!(e.kind(), ErrorKind::Disabled | ErrorKind::Transport)
+}
+
+/// Returns the text of the keys pushed with EC2 Instance Connect, one per line, which is empty if
+/// there are none.
+async fn fetch_managed_keys(client: &mut ImdsClient) -> Result<String> {
+    info!("Fetching managed public keys from IMDS");
+    match client.fetch_metadata(MANAGED_SSH_KEYS_TARGET).await {
+        Ok(body) => Ok(String::from_utf8_lossy(&body).into_owned()),
+        // this is OK, it just means that no keys were pushed
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            debug!("no managed public keys");
+            Ok(String::new())
+        }
+        Err(e) if is_unreachable(&e) => {
+            warn!(
+                "Unable to reach IMDS to fetch managed public keys, using none: {}",
+                e
+            );
+            Ok(String::new())
+        }
+        Err(e) => Err(e).context(error::ImdsRequest),
+    }
+}
+
+/// Returns the keys in `public_keys`, followed by those in `managed_keys`, without duplicates.
+/// Each key is kept in the place it was first seen.  Keys are compared by type and key data, so a
+/// key pair and a managed key with different comments are the same key.
+fn collect_public_keys(public_keys: &[String], managed_keys: &str) -> Vec<String> {
+    let managed_keys = managed_keys
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'));
+
+    let mut seen = HashSet::new();
+    let mut keys = Vec::new();
+    for key in public_keys.iter().map(|key| key.trim()).chain(managed_keys) {
+        let identity: Vec<&str> = key.split_whitespace().take(2).collect();
+        if seen.insert(identity) {
+            keys.push(key.to_string());
+        } else {
+            debug!("Skipping duplicate key '{}'", key);
+        }
+    }
+    keys
+}
+
+/// How the user-data is printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    /// A JSON string of the base64-encoded user-data, for the admin container's user-data setting.
+    Base64,
+    /// The user-data JSON itself.
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "base64" => Ok(OutputFormat::Base64),
+            "json" => Ok(OutputFormat::Json),
+            _ => error::Usage {
+                message: format!("Invalid output format '{}', expected base64 or json", s),
+            }
+            .fail(),
+        }
+    }
 }
 
 /// Store the args we receive on the command line.
 struct Args {
     log_level: LevelFilter,
+    output_format: OutputFormat,
 }
 
 /// Print a usage message in the event a bad arg is passed
@@ -59,16 +170,18 @@ fn usage() {
     let program_name = env::args().next().unwrap_or_else(|| "program".to_string());
     eprintln!(
         r"Usage: {}
-            [ --log-level trace|debug|info|warn|error ]",
+            [ --log-level trace|debug|info|warn|error ]
+            [ --output-format base64|json ]",
         program_name
     );
 }
 
 /// Parse the args to the program and return an Args struct
-fn parse_args(args: env::Args) -> Result<Args> {
+fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Args> {
     let mut log_level = None;
+    let mut output_format = None;
 
-    let mut iter = args.skip(1);
+    let mut iter = args.into_iter().skip(1);
     while let Some(arg) = iter.next() {
         match arg.as_ref() {
             "--log-level" => {
@@ -81,6 +194,13 @@ fn parse_args(args: env::Args) -> Result<Args> {
                 );
             }
 
+            "--output-format" => {
+                let output_format_str = iter.next().context(error::Usage {
+                    message: "Did not give argument to --output-format",
+                })?;
+                output_format = Some(OutputFormat::from_str(&output_format_str)?);
+            }
+
             x => {
                 return error::Usage {
                     message: format!("unexpected argument '{}'", x),
@@ -92,9 +212,31 @@ fn parse_args(args: env::Args) -> Result<Args> {
 
     Ok(Args {
         log_level: log_level.unwrap_or(LevelFilter::Info),
+        output_format: output_format.unwrap_or(OutputFormat::Base64),
     })
 }
 
+/// Returns the user-data as it's printed in `output_format`.
+fn render_user_data(user_data: &UserData, output_format: OutputFormat) -> Result<String> {
+    // Serialize user_data to a JSON string that can be read by the admin container.
+    let user_data_json = serde_json::to_string(user_data).context(error::SerializeJson)?;
+    debug!("{}", &user_data_json);
+
+    if output_format == OutputFormat::Json {
+        return Ok(user_data_json);
+    }
+
+    info!("Encoding user-data");
+    // admin container user-data must be base64-encoded to be passed through to the admin container
+    // using a setting, rather than another arbitrary storage mechanism. This approach allows the
+    // user to bypass shibaken and use their own user-data if desired.
+    let user_data_base64 = base64::encode(&user_data_json);
+
+    // sundog expects JSON-serialized output so that many types can be represented, allowing the
+    // API model to use more accurate types.
+    serde_json::to_string(&user_data_base64).context(error::SerializeJson)
+}
+
 async fn run() -> Result<()> {
     let args = parse_args(env::args())?;
 
@@ -115,21 +257,9 @@ async fn run() -> Result<()> {
     let user_data = UserData::new(public_keys);
 
     info!("Generating user-data");
-    // Serialize user_data to a JSON string that can be read by the admin container.
-    let user_data_json = serde_json::to_string(&user_data).context(error::SerializeJson)?;
-    debug!("{}", &user_data_json);
-
-    info!("Encoding user-data");
-    // admin container user-data must be base64-encoded to be passed through to the admin container
-    // using a setting, rather than another arbitrary storage mechanism. This approach allows the
-    // user to bypass shibaken and use their own user-data if desired.
-    let user_data_base64 = base64::encode(&user_data_json);
-
-    info!("Outputting base64-encoded user-data");
-    // sundog expects JSON-serialized output so that many types can be represented, allowing the
-    // API model to use more accurate types.
-    let output = serde_json::to_string(&user_data_base64).context(error::SerializeJson)?;
+    let output = render_user_data(&user_data, args.output_format)?;
 
+    info!("Outputting user-data");
     println!("{}", output);
 
     Ok(())
@@ -166,16 +296,6 @@ mod error {
         #[snafu(display("IMDS request failed: {}", source))]
         ImdsRequest { source: imdsclient::Error },
 
-        #[snafu(display("IMDS client failed: {}", source))]
-        ImdsClient { source: imdsclient::Error },
-
-        #[snafu(display(
-            "IMDS client failed: Response '404' while fetching '{}' from '{}'",
-            target,
-            target_type,
-        ))]
-        ImdsData { target: String, target_type: String },
-
         #[snafu(display("Logger setup error: {}", source))]
         Logger { source: log::SetLoggerError },
 
@@ -194,3 +314,129 @@ mod error {
 }
 use error::Error;
 type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    #[test]
+    fn key_pairs_only() {
+        let public_keys = keys(&["ssh-rsa AAAA1 my-key", "ssh-ed25519 AAAA2 other-key"]);
+        assert_eq!(collect_public_keys(&public_keys, ""), public_keys);
+    }
+
+    #[test]
+    fn managed_keys_follow_key_pairs() {
+        let public_keys = keys(&["ssh-rsa AAAA1 my-key"]);
+        let managed_keys = "ecdsa-sha2-nistp256 AAAA3\n\n# a comment\nssh-ed25519 AAAA2 pushed\n";
+        assert_eq!(
+            collect_public_keys(&public_keys, managed_keys),
+            keys(&[
+                "ssh-rsa AAAA1 my-key",
+                "ecdsa-sha2-nistp256 AAAA3",
+                "ssh-ed25519 AAAA2 pushed",
+            ])
+        );
+        assert_eq!(
+            collect_public_keys(&[], managed_keys),
+            keys(&["ecdsa-sha2-nistp256 AAAA3", "ssh-ed25519 AAAA2 pushed"])
+        );
+    }
+
+    #[test]
+    fn duplicates_keep_first_seen() {
+        let public_keys = keys(&[
+            "ssh-rsa AAAA1 my-key",
+            "ssh-ed25519 AAAA2 other-key",
+            "ssh-rsa AAAA1 my-key",
+        ]);
+        // the same key pushed with Instance Connect, with another comment, is still a duplicate.
+        let managed_keys = "ssh-ed25519 AAAA2\nssh-rsa AAAA1 instance-connect\nssh-rsa AAAA4\n";
+        assert_eq!(
+            collect_public_keys(&public_keys, managed_keys),
+            keys(&[
+                "ssh-rsa AAAA1 my-key",
+                "ssh-ed25519 AAAA2 other-key",
+                "ssh-rsa AAAA4",
+            ])
+        );
+    }
+
+    #[test]
+    fn same_data_different_type() {
+        let public_keys = keys(&["ssh-rsa AAAA1", "ssh-dss AAAA1"]);
+        assert_eq!(collect_public_keys(&public_keys, ""), public_keys);
+    }
+
+    #[test]
+    fn output_format_args() {
+        let parsed = parse_args(args(&["shibaken"])).unwrap();
+        assert_eq!(parsed.output_format, OutputFormat::Base64);
+        let parsed = parse_args(args(&["shibaken", "--output-format", "json"])).unwrap();
+        assert_eq!(parsed.output_format, OutputFormat::Json);
+        let parsed = parse_args(args(&["shibaken", "--output-format", "base64"])).unwrap();
+        assert_eq!(parsed.output_format, OutputFormat::Base64);
+    }
+
+    #[test]
+    fn invalid_output_format() {
+        let err = parse_args(args(&["shibaken", "--output-format", "yaml"]))
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::Usage { .. }));
+        assert_eq!(
+            err.to_string(),
+            "Invalid output format 'yaml', expected base64 or json"
+        );
+        let err = parse_args(args(&["shibaken", "--output-format"]))
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "Did not give argument to --output-format");
+    }
+
+    #[test]
+    fn json_output() {
+        let user_data = UserData::new(keys(&["ssh-rsa AAAA1 my-key"]));
+        assert_eq!(
+            render_user_data(&user_data, OutputFormat::Json).unwrap(),
+            r#"{"ssh":{"authorized-keys":["ssh-rsa AAAA1 my-key"]}}"#
+        );
+    }
+
+    #[test]
+    fn base64_output() {
+        let user_data = UserData::new(keys(&["ssh-rsa AAAA1 my-key"]));
+        let output = render_user_data(&user_data, OutputFormat::Base64).unwrap();
+        let encoded: String = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            base64::decode(encoded).unwrap(),
+            br#"{"ssh":{"authorized-keys":["ssh-rsa AAAA1 my-key"]}}"#.to_vec()
+        );
+    }
+
+    #[tokio::test]
+    async fn imds_unreachable() {
+        // nothing listens on the port once the listener is dropped, so connections are refused.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let client = ImdsClient::new_with_base_uri(format!("http://localhost:{}", port));
+
+        let public_keys = fetch_public_keys(client).await.unwrap();
+        assert!(public_keys.is_empty());
+        let output = render_user_data(&UserData::new(public_keys), OutputFormat::Base64).unwrap();
+        let encoded: String = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            base64::decode(encoded).unwrap(),
+            br#"{"ssh":{"authorized-keys":[]}}"#.to_vec()
+        );
+    }
+}
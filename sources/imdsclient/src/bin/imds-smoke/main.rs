@@ -0,0 +1,347 @@
+/*!
+`imds-smoke` exercises each public helper of the imdsclient library against the real IMDS and
+prints a table of the results, to validate new instance types and IMDS schema changes quickly.
+It's only built with the `smoke` feature, and isn't included in Bottlerocket images.
+
+Each check has a timeout, 10 seconds by default.  Optional categories, like spot interruptions
+and instance tags, may be absent; the program exits non-zero if any other check doesn't pass.
+Pass `--json` to print the results as JSON instead of a table.
+*/
+
+#![deny(rust_2018_idioms)]
+
+mod report;
+
+use imdsclient::ImdsClient;
+use report::{Check, CheckResult, Requirement};
+use std::future::Future;
+use std::time::Duration;
+use std::{env, process};
+use tokio::time;
+
+const DEFAULT_TIMEOUT_SECONDS: u64 = 10;
+
+/// Prints a usage message and exits.
+fn usage() -> ! {
+    let program_name = env::args().next().unwrap_or_else(|| "program".to_string());
+    eprintln!(
+        r"Usage: {}
+            [ --timeout SECONDS ]   how long each check may take (default: {})
+            [ --json ]              print the results as JSON",
+        program_name, DEFAULT_TIMEOUT_SECONDS
+    );
+    process::exit(2);
+}
+
+/// Prints a more specific message before exiting through usage().
+fn usage_msg(msg: &str) -> ! {
+    eprintln!("{}\n", msg);
+    usage();
+}
+
+struct Args {
+    timeout: Duration,
+    json: bool,
+}
+
+fn parse_args(args: env::Args) -> Args {
+    let mut timeout = Duration::from_secs(DEFAULT_TIMEOUT_SECONDS);
+    let mut json = false;
+    let mut iter = args.skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_ref() {
+            "--timeout" => {
+                let seconds = iter
+                    .next()
+                    .unwrap_or_else(|| usage_msg("Did not give argument to --timeout"));
+                match seconds.parse::<u64>() {
+                    Ok(seconds) if seconds > 0 => timeout = Duration::from_secs(seconds),
+                    _ => usage_msg("--timeout must be a positive number of seconds"),
+                }
+            }
+            "--json" => json = true,
+            _ => usage(),
+        }
+    }
+    Args { timeout, json }
+}
+
+/// Runs one check with a timeout and classifies its result, using `describe` to summarize a
+/// successful response.  Also returns the response, for checks that later checks depend on.
+async fn check<T, F, D>(
+    name: &str,
+    requirement: Requirement,
+    timeout: Duration,
+    fut: F,
+    describe: D,
+) -> (Check, Option<T>)
+where
+    F: Future<Output = imdsclient::Result<T>>,
+    D: FnOnce(&T) -> String,
+{
+    let (result, response) = match time::timeout(timeout, fut).await {
+        Ok(Ok(response)) => (CheckResult::Ok(describe(&response)), Some(response)),
+        Ok(Err(e)) => (
+            CheckResult::Err {
+                kind: e.kind(),
+                message: e.to_string(),
+            },
+            None,
+        ),
+        Err(_) => (CheckResult::TimedOut, None),
+    };
+    (Check::classify(name, requirement, result), response)
+}
+
+/// Runs every check.  If a session token can't be obtained, that's the only check reported.
+async fn run_checks(timeout: Duration) -> Vec<Check> {
+    use Requirement::{Optional, Required};
+
+    let (session, client) = check(
+        "session-token",
+        Required,
+        timeout,
+        async {
+            let client = ImdsClient::new();
+            client.connect().await.map(|()| client)
+        },
+        |_| String::new(),
+    )
+    .await;
+    let mut checks = vec![session];
+    let mut client = match client {
+        Some(client) => client,
+        None => return checks,
+    };
+
+    checks.push(
+        check(
+            "identity-document",
+            Required,
+            timeout,
+            client.fetch_identity_document(),
+            |document| format!("{} in {}", document.instance_type(), document.region()),
+        )
+        .await
+        .0,
+    );
+    // checks the PKCS7 reader and the bundled certificates against what IMDS really serves.
+    checks.push(
+        check(
+            "verified-identity-document",
+            Required,
+            timeout,
+            client.fetch_verified_identity_document(),
+            |document| format!("{} in {}", document.instance_type(), document.region()),
+        )
+        .await
+        .0,
+    );
+    checks.push(
+        check(
+            "availability-zone",
+            Required,
+            timeout,
+            client.fetch_availability_zone(),
+            String::clone,
+        )
+        .await
+        .0,
+    );
+    checks.push(
+        check(
+            "zone-id",
+            Required,
+            timeout,
+            client.fetch_zone_id(),
+            String::clone,
+        )
+        .await
+        .0,
+    );
+    let (macs_check, macs) = check(
+        "mac-addresses",
+        Required,
+        timeout,
+        client.fetch_mac_addresses(),
+        |list| list.join(", "),
+    )
+    .await;
+    checks.push(macs_check);
+    // IPV6-only subnets have no IPV4 CIDR blocks, and IPV4-only VPCs have no IPV6 CIDR blocks.
+    if let Some(mac) = macs.as_ref().and_then(|macs| macs.first()) {
+        checks.push(
+            check(
+                "vpc-ipv4-cidr-blocks",
+                Optional,
+                timeout,
+                client.fetch_cidr_blocks_for_mac(mac),
+                |list| list.join(", "),
+            )
+            .await
+            .0,
+        );
+        checks.push(
+            check(
+                "vpc-ipv6-cidr-blocks",
+                Optional,
+                timeout,
+                client.fetch_ipv6_cidr_blocks_for_mac(mac),
+                |list| list.join(", "),
+            )
+            .await
+            .0,
+        );
+        // an interface without IPV6 addresses gives an empty list rather than an error.
+        checks.push(
+            check(
+                "ipv6-addresses",
+                Required,
+                timeout,
+                client.fetch_ipv6_addresses_for_mac(mac),
+                |list| list.join(", "),
+            )
+            .await
+            .0,
+        );
+    }
+    // instances in IPV6-only subnets have no IPV4 address.
+    checks.push(
+        check(
+            "local-ipv4",
+            Optional,
+            timeout,
+            client.fetch_local_ipv4_address(),
+            String::clone,
+        )
+        .await
+        .0,
+    );
+    checks.push(
+        check(
+            "public-ssh-keys",
+            Required,
+            timeout,
+            client.fetch_public_ssh_keys(),
+            |keys| format!("{} key(s)", keys.len()),
+        )
+        .await
+        .0,
+    );
+    checks.push(
+        check(
+            "user-data",
+            Optional,
+            timeout,
+            client.fetch_userdata(),
+            |body| format!("{} bytes", body.len()),
+        )
+        .await
+        .0,
+    );
+    checks.push(
+        check(
+            "instance-type",
+            Required,
+            timeout,
+            client.fetch_metadata("instance-type"),
+            |body| String::from_utf8_lossy(body).into_owned(),
+        )
+        .await
+        .0,
+    );
+    checks.push(
+        check(
+            "instance-identity-signature",
+            Required,
+            timeout,
+            client.fetch_dynamic("instance-identity/signature"),
+            |body| format!("{} bytes", body.len()),
+        )
+        .await
+        .0,
+    );
+    checks.push(
+        check(
+            "fetch-many",
+            Required,
+            timeout,
+            client.fetch_many(&[
+                ("meta-data/ami-id", "ami id"),
+                ("meta-data/hostname", "hostname"),
+            ]),
+            |responses| {
+                let found = responses.values().filter(|r| r.is_some()).count();
+                format!("{} of {} found", found, responses.len())
+            },
+        )
+        .await
+        .0,
+    );
+    // `exists` reports an absent category as `false` rather than an error, so this passes on
+    // instances that aren't spot instances too.
+    checks.push(
+        check(
+            "spot-instance-action",
+            Optional,
+            timeout,
+            client.exists("spot/instance-action"),
+            |scheduled| if *scheduled { "scheduled" } else { "none" }.to_string(),
+        )
+        .await
+        .0,
+    );
+    checks.push(
+        check(
+            "metadata-options",
+            Required,
+            timeout,
+            client.fetch_metadata_options(),
+            |options| {
+                format!(
+                    "tokens {}, hop limit {}",
+                    options.http_tokens.as_deref().unwrap_or("unknown"),
+                    options
+                        .http_put_response_hop_limit
+                        .map(|limit| limit.to_string())
+                        .unwrap_or_else(|| "unknown".to_string())
+                )
+            },
+        )
+        .await
+        .0,
+    );
+    // tags are only in IMDS if the instance allows it.
+    checks.push(
+        check(
+            "tags",
+            Optional,
+            timeout,
+            client.fetch_metadata("tags/instance"),
+            |body| format!("{} tag(s)", String::from_utf8_lossy(body).lines().count()),
+        )
+        .await
+        .0,
+    );
+    checks
+}
+
+#[tokio::main]
+async fn main() {
+    let args = parse_args(env::args());
+    let checks = run_checks(args.timeout).await;
+    if args.json {
+        match report::render_json(&checks) {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                eprintln!("Unable to serialize results: {}", e);
+                process::exit(1);
+            }
+        }
+    } else {
+        print!("{}", report::render_table(&checks));
+    }
+    if !report::all_ok(&checks) {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,246 @@
+//! Classifies the results of the smoke checks and renders them as a table or as JSON.
+
+use imdsclient::ErrorKind;
+use serde::Serialize;
+use std::fmt;
+
+/// Whether a failed check fails the smoke test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Requirement {
+    /// The check must pass.
+    Required,
+    /// The category only exists on some instances, e.g. spot instances or instances with tags in
+    /// metadata enabled, so it may be absent.
+    Optional,
+}
+
+/// What a check returned before it was classified.
+#[derive(Debug)]
+pub(crate) enum CheckResult {
+    /// The helper succeeded; the detail summarizes the response.
+    Ok(String),
+    /// The helper failed.
+    Err { kind: ErrorKind, message: String },
+    /// The helper didn't finish within the per-check timeout.
+    TimedOut,
+}
+
+/// The classified result of a check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Outcome {
+    Pass,
+    /// An optional category that doesn't exist on this instance.
+    Absent,
+    Fail,
+    Timeout,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Outcome::Pass => "PASS",
+            Outcome::Absent => "ABSENT",
+            Outcome::Fail => "FAIL",
+            Outcome::Timeout => "TIMEOUT",
+        };
+        f.write_str(s)
+    }
+}
+
+/// A classified check, as it's reported.
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct Check {
+    pub(crate) name: String,
+    pub(crate) requirement: Requirement,
+    pub(crate) outcome: Outcome,
+    pub(crate) detail: String,
+}
+
+impl Check {
+    /// Classifies `result`.  A target that isn't found is only acceptable for optional checks.
+    pub(crate) fn classify<S>(name: S, requirement: Requirement, result: CheckResult) -> Self
+    where
+        S: Into<String>,
+    {
+        let (outcome, detail) = match result {
+            CheckResult::Ok(detail) => (Outcome::Pass, detail),
+            CheckResult::Err {
+                kind: ErrorKind::NotFound,
+                message,
+            } if requirement == Requirement::Optional => (Outcome::Absent, message),
+            CheckResult::Err { message, .. } => (Outcome::Fail, message),
+            CheckResult::TimedOut => (Outcome::Timeout, String::new()),
+        };
+        Self {
+            name: name.into(),
+            requirement,
+            outcome,
+            detail,
+        }
+    }
+
+    /// Returns whether this check lets the smoke test pass.
+    pub(crate) fn is_ok(&self) -> bool {
+        matches!(self.outcome, Outcome::Pass | Outcome::Absent)
+    }
+}
+
+/// Returns whether every check lets the smoke test pass.
+pub(crate) fn all_ok(checks: &[Check]) -> bool {
+    checks.iter().all(Check::is_ok)
+}
+
+/// Renders the checks as a table with aligned columns.  Details are cut to their first line so
+/// that each check takes one row.
+pub(crate) fn render_table(checks: &[Check]) -> String {
+    let rows: Vec<[String; 4]> = checks
+        .iter()
+        .map(|check| {
+            [
+                check.name.clone(),
+                match check.requirement {
+                    Requirement::Required => "required",
+                    Requirement::Optional => "optional",
+                }
+                .to_string(),
+                check.outcome.to_string(),
+                check.detail.lines().next().unwrap_or_default().to_string(),
+            ]
+        })
+        .collect();
+    let header = [
+        "CHECK".to_string(),
+        "REQUIREMENT".to_string(),
+        "RESULT".to_string(),
+        "DETAIL".to_string(),
+    ];
+
+    let mut widths = [0; 4];
+    for row in std::iter::once(&header).chain(&rows) {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut table = String::new();
+    for row in std::iter::once(&header).chain(&rows) {
+        let mut line = String::new();
+        for (i, cell) in row.iter().enumerate() {
+            if i == row.len() - 1 {
+                line.push_str(cell);
+            } else {
+                line.push_str(&format!("{:width$}  ", cell, width = widths[i]));
+            }
+        }
+        table.push_str(line.trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+/// Renders the checks as a JSON array.
+pub(crate) fn render_json(checks: &[Check]) -> serde_json::Result<String> {
+    serde_json::to_string_pretty(checks)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn not_found() -> CheckResult {
+        CheckResult::Err {
+            kind: ErrorKind::NotFound,
+            message: "404 file not found".to_string(),
+        }
+    }
+
+    #[test]
+    fn classification() {
+        let cases = vec![
+            (
+                Requirement::Required,
+                CheckResult::Ok("ok".to_string()),
+                Outcome::Pass,
+            ),
+            (Requirement::Required, not_found(), Outcome::Fail),
+            (Requirement::Optional, not_found(), Outcome::Absent),
+            (
+                Requirement::Optional,
+                CheckResult::Err {
+                    kind: ErrorKind::Transport,
+                    message: "timed out".to_string(),
+                },
+                Outcome::Fail,
+            ),
+            (
+                Requirement::Optional,
+                CheckResult::TimedOut,
+                Outcome::Timeout,
+            ),
+        ];
+        for (requirement, result, expected) in cases {
+            let check = Check::classify("check", requirement, result);
+            assert_eq!(check.outcome, expected, "{:?}", requirement);
+        }
+    }
+
+    #[test]
+    fn overall_result() {
+        let absent = Check::classify("tags", Requirement::Optional, not_found());
+        let pass = Check::classify(
+            "local-ipv4",
+            Requirement::Required,
+            CheckResult::Ok("10.0.0.1".to_string()),
+        );
+        let timeout = Check::classify("spot", Requirement::Optional, CheckResult::TimedOut);
+        assert!(all_ok(&[]));
+        assert!(all_ok(&[absent, pass]));
+        assert!(!all_ok(&[timeout]));
+    }
+
+    #[test]
+    fn table() {
+        let checks = vec![
+            Check::classify(
+                "local-ipv4",
+                Requirement::Required,
+                CheckResult::Ok("10.0.0.1".to_string()),
+            ),
+            Check::classify(
+                "tags",
+                Requirement::Optional,
+                CheckResult::Err {
+                    kind: ErrorKind::NotFound,
+                    message: "404 file not found\nsecond line".to_string(),
+                },
+            ),
+            Check::classify("spot", Requirement::Optional, CheckResult::TimedOut),
+        ];
+        assert_eq!(
+            render_table(&checks),
+            "\
+CHECK       REQUIREMENT  RESULT   DETAIL
+local-ipv4  required     PASS     10.0.0.1
+tags        optional     ABSENT   404 file not found
+spot        optional     TIMEOUT
+"
+        );
+    }
+
+    #[test]
+    fn json() {
+        let checks = vec![Check::classify("tags", Requirement::Optional, not_found())];
+        let json: serde_json::Value = serde_json::from_str(&render_json(&checks).unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([{
+                "name": "tags",
+                "requirement": "optional",
+                "outcome": "absent",
+                "detail": "404 file not found",
+            }])
+        );
+    }
+}
@@ -0,0 +1,104 @@
+//! Provides the optional wall-clock budget of a client's high-level helpers.  A helper like
+//! `fetch_public_ssh_keys` can send many requests, each retried several times, so the time it takes
+//! can add up to far more than boot allows.  Each call to a helper starts a `Deadline` from the
+//! client's budget, and every request the call sends, including retries, has to finish before it.
+
+use crate::{error, Result};
+use snafu::ensure;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::time;
+
+/// The point by which a call to a helper must finish, if the client has a budget.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Deadline {
+    budget: Option<Duration>,
+    started: Instant,
+}
+
+impl Deadline {
+    /// Starts a deadline `budget` from now, or an unlimited one if `budget` is `None`.
+    pub(crate) fn start(budget: Option<Duration>) -> Self {
+        Self {
+            budget,
+            started: Instant::now(),
+        }
+    }
+
+    /// Returns how much of the budget is left, or `None` if it's unlimited.
+    pub(crate) fn remaining(&self) -> Option<Duration> {
+        self.budget
+            .map(|budget| budget.saturating_sub(self.started.elapsed()))
+    }
+
+    /// Returns a `BudgetExceeded` error if the budget is used up, so that no new request is
+    /// started.
+    pub(crate) fn check(&self) -> Result<()> {
+        if let Some(budget) = self.budget {
+            ensure!(
+                self.started.elapsed() < budget,
+                error::BudgetExceeded { budget }
+            );
+        }
+        Ok(())
+    }
+
+    /// Runs `future`, returning a `BudgetExceeded` error if the budget is used up before it
+    /// finishes.
+    pub(crate) async fn run<F, T>(&self, future: F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        match (self.budget, self.remaining()) {
+            (Some(budget), Some(remaining)) => time::timeout(remaining, future)
+                .await
+                .unwrap_or_else(|_| error::BudgetExceeded { budget }.fail()),
+            _ => future.await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn unlimited() {
+        let deadline = Deadline::start(None);
+        assert_eq!(deadline.remaining(), None);
+        assert!(deadline.check().is_ok());
+        let value = deadline
+            .run(async {
+                time::sleep(Duration::from_millis(10)).await;
+                Ok(1)
+            })
+            .await
+            .unwrap();
+        assert_eq!(value, 1);
+    }
+
+    #[tokio::test]
+    async fn within_budget() {
+        let deadline = Deadline::start(Some(Duration::from_secs(10)));
+        assert!(deadline.remaining().unwrap() <= Duration::from_secs(10));
+        assert!(deadline.check().is_ok());
+        assert_eq!(deadline.run(async { Ok(1) }).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn exceeded() {
+        let deadline = Deadline::start(Some(Duration::from_millis(50)));
+        let result = deadline
+            .run(async {
+                time::sleep(Duration::from_secs(10)).await;
+                Ok(1)
+            })
+            .await;
+        assert!(matches!(result, Err(error::Error::BudgetExceeded { .. })));
+        assert_eq!(deadline.remaining(), Some(Duration::from_secs(0)));
+        assert!(matches!(
+            deadline.check(),
+            Err(error::Error::BudgetExceeded { .. })
+        ));
+    }
+}
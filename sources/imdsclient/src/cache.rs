@@ -0,0 +1,159 @@
+//! Provides an optional cache of IMDS responses. Whether, and for how long, a response may be
+//! cached depends on the category of the target, which is looked up in a static policy table.
+//! Immutable categories like the identity document can be cached for the life of the client, while
+//! mutable categories like public keys may only be cached briefly, and are never negatively cached,
+//! so that keys or network interfaces attached after boot aren't hidden by an earlier 404.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long responses for mutable categories may be cached.
+const MUTABLE_TTL: Duration = Duration::from_secs(5);
+
+/// Describes how responses for a category of targets may be cached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CachePolicy {
+    /// Responses are never cached.
+    Never,
+    /// Responses, including 404s, are cached for the life of the client.
+    Indefinite,
+    /// Successful responses are cached for the given duration; 404s are never cached.
+    Mutable(Duration),
+}
+
+/// The cache policy for each category of target, matched by prefix. The first matching entry is
+/// used, so more specific prefixes must come first. Targets that don't match any entry are never
+/// cached.
+const CACHE_POLICIES: &[(&str, CachePolicy)] = &[
+    (
+        "dynamic/instance-identity/document",
+        CachePolicy::Indefinite,
+    ),
+    ("dynamic/instance-identity/rsa2048", CachePolicy::Indefinite),
+    ("meta-data/ami-id", CachePolicy::Indefinite),
+    ("meta-data/instance-type", CachePolicy::Indefinite),
+    (
+        "meta-data/network/interfaces/macs",
+        CachePolicy::Mutable(MUTABLE_TTL),
+    ),
+    ("meta-data/placement", CachePolicy::Indefinite),
+    ("meta-data/public-keys", CachePolicy::Mutable(MUTABLE_TTL)),
+    ("meta-data/spot", CachePolicy::Never),
+];
+
+/// Returns the cache policy for `target`, e.g. `meta-data/instance-type`.
+pub(crate) fn policy(target: &str) -> CachePolicy {
+    CACHE_POLICIES
+        .iter()
+        .find(|(prefix, _)| target.starts_with(prefix))
+        .map(|(_, policy)| *policy)
+        .unwrap_or(CachePolicy::Never)
+}
+
+/// A cached IMDS response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum CachedResponse {
+    Found(Vec<u8>),
+    NotFound,
+}
+
+#[derive(Debug)]
+struct Entry {
+    response: CachedResponse,
+    inserted: Instant,
+    policy: CachePolicy,
+}
+
+impl Entry {
+    fn is_fresh(&self) -> bool {
+        match self.policy {
+            CachePolicy::Never => false,
+            CachePolicy::Indefinite => true,
+            CachePolicy::Mutable(ttl) => self.inserted.elapsed() < ttl,
+        }
+    }
+}
+
+/// Holds cached responses keyed by schema version and target.
+#[derive(Debug, Default)]
+pub(crate) struct ResponseCache {
+    entries: HashMap<String, Entry>,
+}
+
+impl ResponseCache {
+    /// Returns the cached response for `target` under `schema_version`, if there is a fresh one.
+    pub(crate) fn get(&self, schema_version: &str, target: &str) -> Option<&CachedResponse> {
+        self.entries
+            .get(&key(schema_version, target))
+            .filter(|entry| entry.is_fresh())
+            .map(|entry| &entry.response)
+    }
+
+    /// Caches `response` for `target` under `schema_version`, if the target's policy allows it.
+    pub(crate) fn insert(&mut self, schema_version: &str, target: &str, response: CachedResponse) {
+        let policy = policy(target);
+        let cacheable = match (policy, &response) {
+            (CachePolicy::Never, _) => false,
+            (CachePolicy::Indefinite, _) => true,
+            (CachePolicy::Mutable(_), CachedResponse::Found(_)) => true,
+            (CachePolicy::Mutable(_), CachedResponse::NotFound) => false,
+        };
+        if cacheable {
+            self.entries.insert(
+                key(schema_version, target),
+                Entry {
+                    response,
+                    inserted: Instant::now(),
+                    policy,
+                },
+            );
+        }
+    }
+}
+
+fn key(schema_version: &str, target: &str) -> String {
+    format!("{}/{}", schema_version, target)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn policies() {
+        assert_eq!(
+            policy("dynamic/instance-identity/document"),
+            CachePolicy::Indefinite
+        );
+        assert_eq!(policy("meta-data/instance-type"), CachePolicy::Indefinite);
+        assert_eq!(
+            policy("meta-data/public-keys/0/openssh-key"),
+            CachePolicy::Mutable(MUTABLE_TTL)
+        );
+        assert_eq!(policy("meta-data/spot/instance-action"), CachePolicy::Never);
+        assert_eq!(policy("user-data"), CachePolicy::Never);
+    }
+
+    #[test]
+    fn negative_caching() {
+        let mut cache = ResponseCache::default();
+        cache.insert("latest", "meta-data/ami-id", CachedResponse::NotFound);
+        cache.insert("latest", "meta-data/public-keys", CachedResponse::NotFound);
+        assert_eq!(
+            cache.get("latest", "meta-data/ami-id"),
+            Some(&CachedResponse::NotFound)
+        );
+        assert_eq!(cache.get("latest", "meta-data/public-keys"), None);
+    }
+
+    #[test]
+    fn positive_caching() {
+        let mut cache = ResponseCache::default();
+        let found = CachedResponse::Found(b"0=my-key".to_vec());
+        cache.insert("latest", "meta-data/public-keys", found.clone());
+        cache.insert("latest", "user-data", found.clone());
+        assert_eq!(cache.get("latest", "meta-data/public-keys"), Some(&found));
+        assert_eq!(cache.get("other", "meta-data/public-keys"), None);
+        assert_eq!(cache.get("latest", "user-data"), None);
+    }
+}
@@ -0,0 +1,558 @@
+//! Verifies the signature of the instance identity document.
+//!
+//! IMDS serves the document, signed with the key of the AWS partition, as a PKCS7 `SignedData`
+//! blob at `dynamic/instance-identity/rsa2048`.  The blob holds the document itself, and is
+//! base64-encoded without PEM armor.  IMDS streams it with BER indefinite lengths, so the reader
+//! below accepts those as well as DER.
+//!
+//! The signature is checked against the public keys of the AWS certificates for each partition,
+//! which are bundled from `data/identity-certificates`.  Only SHA-256 digests and RSA signatures
+//! are accepted, which is what IMDS uses for `rsa2048`.  A blob that can't be read is a parse
+//! error; a blob that reads fine but doesn't match its content, or isn't signed by any of the
+//! keys, is an `IdentitySignatureInvalid` error, so callers can tell a forged document from a
+//! garbled one.
+//!
+//! None of the crates already in the dependency tree parse PKCS7; `ring` only checks signatures
+//! and `webpki` only reads certificates.  The `openssl` crate would link OpenSSL into every program
+//! that uses the client, so the few fields needed are read here instead.  The tests check the
+//! reader against blobs signed by `openssl cms`, in the indefinite-length form that IMDS serves as
+//! well as in DER with the certificate included and without signed attributes.
+
+use crate::{error, Result};
+use ring::{digest, signature};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::fmt;
+
+/// The bundled certificates of each partition, in PEM.
+const BUNDLED_CERTIFICATES: &[(Partition, &str)] = &[
+    (
+        Partition::Aws,
+        include_str!("../data/identity-certificates/aws.pem"),
+    ),
+    (
+        Partition::AwsCn,
+        include_str!("../data/identity-certificates/aws-cn.pem"),
+    ),
+    (
+        Partition::AwsUsGov,
+        include_str!("../data/identity-certificates/aws-us-gov.pem"),
+    ),
+];
+
+/// How deeply values with indefinite lengths may be nested, which bounds the recursion needed to
+/// find where one ends.  A `SignedData` from IMDS nests far less than this.
+const MAX_INDEFINITE_DEPTH: usize = 32;
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OCTET_STRING_CONSTRUCTED: u8 = 0x24;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+/// A context-specific, constructed tag with number 0, e.g. `[0] IMPLICIT signedAttrs`.
+const TAG_CONTEXT_0: u8 = 0xa0;
+/// A context-specific tag with number 1, which may be primitive or constructed.
+const TAG_CONTEXT_1: u8 = 0xa1;
+
+// The DER encodings of the object identifiers we look for.
+/// 1.2.840.113549.1.7.1, pkcs7-data
+const OID_DATA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x01];
+/// 1.2.840.113549.1.7.2, pkcs7-signedData
+const OID_SIGNED_DATA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x02];
+/// 1.2.840.113549.1.9.4, messageDigest
+const OID_MESSAGE_DIGEST: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x04];
+/// 2.16.840.1.101.3.4.2.1, sha256
+const OID_SHA256: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+/// 1.2.840.113549.1.1.1, rsaEncryption
+const OID_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+/// 1.2.840.113549.1.1.11, sha256WithRSAEncryption
+const OID_SHA256_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+
+/// An AWS partition, which has its own keys for signing identity documents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Partition {
+    /// The commercial regions, `aws`.
+    Aws,
+    /// The China regions, `aws-cn`.
+    AwsCn,
+    /// The GovCloud (US) regions, `aws-us-gov`.
+    AwsUsGov,
+}
+
+impl Partition {
+    /// Returns the partition of `region`, e.g. `aws-cn` for `cn-north-1`.
+    pub fn from_region(region: &str) -> Self {
+        if region.starts_with("cn-") {
+            Partition::AwsCn
+        } else if region.starts_with("us-gov-") {
+            Partition::AwsUsGov
+        } else {
+            Partition::Aws
+        }
+    }
+}
+
+impl fmt::Display for Partition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Partition::Aws => write!(f, "aws"),
+            Partition::AwsCn => write!(f, "aws-cn"),
+            Partition::AwsUsGov => write!(f, "aws-us-gov"),
+        }
+    }
+}
+
+/// The public keys that identity documents are checked against, by partition.
+#[derive(Debug, Clone, Default)]
+pub struct IdentityCertificates {
+    /// Each key is an RSA public key in the PKCS#1 form that `ring` expects.
+    keys: Vec<(Partition, Vec<u8>)>,
+}
+
+impl IdentityCertificates {
+    /// Returns the certificates bundled with the client.
+    pub fn bundled() -> Result<Self> {
+        BUNDLED_CERTIFICATES
+            .iter()
+            .try_fold(Self::empty(), |certificates, (partition, pem)| {
+                certificates.with_pem(*partition, pem)
+            })
+    }
+
+    /// Returns an empty set, which verifies nothing until certificates are added.
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    /// Adds the RSA certificates in `pem` for `partition`.  Text outside the `CERTIFICATE` blocks
+    /// is ignored, so the PEM can carry comments about where the certificates came from.
+    pub fn with_pem(mut self, partition: Partition, pem: &str) -> Result<Self> {
+        for der in pem_blocks(pem, "CERTIFICATE")? {
+            let key = rsa_public_key(&der)
+                .or_else(|reason| error::IdentityCertificate { reason }.fail())?;
+            self.keys.push((partition, key));
+        }
+        Ok(self)
+    }
+
+    /// Returns whether there are no certificates to verify against.
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+/// Checks the PKCS7 `signature` served by IMDS against `certificates`, and returns the partition
+/// whose key signed it and the signed content, i.e. the identity document.
+pub(crate) fn verify(
+    signature: &str,
+    certificates: &IdentityCertificates,
+) -> Result<(Partition, Vec<u8>)> {
+    ensure!(!certificates.is_empty(), error::NoIdentityCertificates);
+    let der =
+        base64::decode(strip_whitespace(signature)).context(error::IdentitySignatureBase64)?;
+    let signed = SignedData::parse(&der)
+        .or_else(|reason| error::IdentitySignatureParse { reason }.fail())?;
+
+    // when there are signed attributes, the signature covers them instead of the content, and
+    // they in turn hold the digest of the content.
+    let message = match &signed.signed_attributes {
+        Some(attributes) => {
+            let expected = message_digest(attributes)
+                .or_else(|reason| error::IdentitySignatureParse { reason }.fail())?;
+            let actual = digest::digest(&digest::SHA256, &signed.content);
+            ensure!(actual.as_ref() == expected, error::IdentitySignatureInvalid);
+            attributes.to_vec()
+        }
+        None => signed.content.clone(),
+    };
+
+    certificates
+        .keys
+        .iter()
+        .find(|(_, key)| {
+            signature::UnparsedPublicKey::new(&signature::RSA_PKCS1_2048_8192_SHA256, key)
+                .verify(&message, &signed.signature)
+                .is_ok()
+        })
+        .map(|(partition, _)| (*partition, signed.content))
+        .context(error::IdentitySignatureInvalid)
+}
+
+/// Returns `s` without any whitespace, e.g. the line breaks in base64 text.
+fn strip_whitespace(s: &str) -> String {
+    s.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+/// Returns the decoded contents of each `-----BEGIN {label}-----` block in `pem`.
+fn pem_blocks(pem: &str, label: &str) -> Result<Vec<Vec<u8>>> {
+    let begin = format!("-----BEGIN {}-----", label);
+    let end = format!("-----END {}-----", label);
+    let mut blocks = Vec::new();
+    let mut block: Option<String> = None;
+    for line in pem.lines().map(str::trim) {
+        if line == begin {
+            block = Some(String::new());
+        } else if line == end {
+            if let Some(text) = block.take() {
+                blocks.push(base64::decode(&text).context(error::IdentityCertificateBase64)?);
+            }
+        } else if let Some(text) = block.as_mut() {
+            text.push_str(line);
+        }
+    }
+    Ok(blocks)
+}
+
+/// Returns the RSA public key of the X.509 certificate `der`.
+fn rsa_public_key(der: &[u8]) -> std::result::Result<Vec<u8>, &'static str> {
+    let mut certificate = Reader::new(der).expect(TAG_SEQUENCE)?.children();
+    let mut tbs = certificate.expect(TAG_SEQUENCE)?.children();
+    if tbs.peek_tag() == Some(TAG_CONTEXT_0) {
+        tbs.read()?; // version
+    }
+    tbs.expect(TAG_INTEGER)?; // serial number
+    tbs.expect(TAG_SEQUENCE)?; // signature algorithm
+    tbs.expect(TAG_SEQUENCE)?; // issuer
+    tbs.expect(TAG_SEQUENCE)?; // validity
+    tbs.expect(TAG_SEQUENCE)?; // subject
+    let mut key_info = tbs.expect(TAG_SEQUENCE)?.children();
+    let mut algorithm = key_info.expect(TAG_SEQUENCE)?.children();
+    if algorithm.expect(TAG_OID)?.contents != OID_RSA_ENCRYPTION {
+        return Err("certificate key is not RSA");
+    }
+    // the first byte of a bit string is the number of unused bits, which is 0 for a key.
+    match key_info.expect(TAG_BIT_STRING)?.contents.split_first() {
+        Some((0, key)) => Ok(key.to_vec()),
+        _ => Err("certificate key is not a whole number of bytes"),
+    }
+}
+
+/// Returns the `messageDigest` value among the signed `attributes`.
+fn message_digest(attributes: &[u8]) -> std::result::Result<Vec<u8>, &'static str> {
+    let mut attributes = Reader::new(attributes).expect(TAG_SET)?.children();
+    while !attributes.is_empty() {
+        let mut attribute = attributes.expect(TAG_SEQUENCE)?.children();
+        if attribute.expect(TAG_OID)?.contents == OID_MESSAGE_DIGEST {
+            let mut values = attribute.expect(TAG_SET)?.children();
+            return Ok(values.expect(TAG_OCTET_STRING)?.contents.to_vec());
+        }
+    }
+    Err("signed attributes have no message digest")
+}
+
+/// The parts of a PKCS7 `SignedData` with one signer that are needed to verify it.
+#[derive(Debug)]
+struct SignedData {
+    /// The signed content.
+    content: Vec<u8>,
+    /// The signed attributes, re-tagged as the `SET OF` that the signature covers.
+    signed_attributes: Option<Vec<u8>>,
+    signature: Vec<u8>,
+}
+
+impl SignedData {
+    fn parse(der: &[u8]) -> std::result::Result<Self, &'static str> {
+        let mut content_info = Reader::new(der).expect(TAG_SEQUENCE)?.children();
+        if content_info.expect(TAG_OID)?.contents != OID_SIGNED_DATA {
+            return Err("not PKCS7 signed data");
+        }
+        let mut explicit = content_info.expect(TAG_CONTEXT_0)?.children();
+        let mut signed_data = explicit.expect(TAG_SEQUENCE)?.children();
+        signed_data.expect(TAG_INTEGER)?; // version
+        signed_data.expect(TAG_SET)?; // digest algorithms
+
+        let mut encapsulated = signed_data.expect(TAG_SEQUENCE)?.children();
+        if encapsulated.expect(TAG_OID)?.contents != OID_DATA {
+            return Err("signed content is not data");
+        }
+        if encapsulated.is_empty() {
+            return Err("signed content is detached");
+        }
+        let content = octets(encapsulated.expect(TAG_CONTEXT_0)?.children().read()?)?;
+
+        // skip the certificates and CRLs; the signer's key has to come from a trusted certificate.
+        while matches!(
+            signed_data.peek_tag(),
+            Some(TAG_CONTEXT_0) | Some(TAG_CONTEXT_1)
+        ) {
+            signed_data.read()?;
+        }
+        let mut signer_infos = signed_data.expect(TAG_SET)?.children();
+        let mut signer = signer_infos.expect(TAG_SEQUENCE)?.children();
+        if !signer_infos.is_empty() {
+            return Err("more than one signer");
+        }
+        signer.expect(TAG_INTEGER)?; // version
+        signer.read()?; // issuer and serial number, or subject key identifier
+        let mut digest_algorithm = signer.expect(TAG_SEQUENCE)?.children();
+        if digest_algorithm.expect(TAG_OID)?.contents != OID_SHA256 {
+            return Err("digest algorithm is not SHA-256");
+        }
+        let signed_attributes = if signer.peek_tag() == Some(TAG_CONTEXT_0) {
+            let attributes = signer.read()?;
+            if attributes.indefinite {
+                return Err("signed attributes have an indefinite length");
+            }
+            let mut set = attributes.raw.to_vec();
+            set[0] = TAG_SET;
+            Some(set)
+        } else {
+            None
+        };
+        let mut signature_algorithm = signer.expect(TAG_SEQUENCE)?.children();
+        let oid = signature_algorithm.expect(TAG_OID)?.contents;
+        if oid != OID_RSA_ENCRYPTION && oid != OID_SHA256_WITH_RSA {
+            return Err("signature algorithm is not RSA");
+        }
+        let signature = octets(signer.read()?)?;
+
+        Ok(Self {
+            content,
+            signed_attributes,
+            signature,
+        })
+    }
+}
+
+/// Returns the bytes of an octet string, joining the chunks of a constructed one.
+fn octets(tlv: Tlv<'_>) -> std::result::Result<Vec<u8>, &'static str> {
+    match tlv.tag {
+        TAG_OCTET_STRING => Ok(tlv.contents.to_vec()),
+        TAG_OCTET_STRING_CONSTRUCTED => {
+            let mut chunks = tlv.children();
+            let mut bytes = Vec::new();
+            while !chunks.is_empty() {
+                bytes.extend(octets(chunks.read()?)?);
+            }
+            Ok(bytes)
+        }
+        _ => Err("expected an octet string"),
+    }
+}
+
+/// One encoded value: its tag, its contents, and the whole encoding including the header.
+#[derive(Debug, Clone, Copy)]
+struct Tlv<'a> {
+    tag: u8,
+    contents: &'a [u8],
+    raw: &'a [u8],
+    /// Whether the value had an indefinite length, so `raw` ends with an end-of-contents marker.
+    indefinite: bool,
+}
+
+impl<'a> Tlv<'a> {
+    /// Returns a reader over the values inside a constructed value.
+    fn children(&self) -> Reader<'a> {
+        Reader::new(self.contents)
+    }
+}
+
+/// Reads a sequence of BER encoded values.  Only single-byte tags are supported, which covers
+/// everything in a `SignedData` or certificate.
+#[derive(Debug, Clone, Copy)]
+struct Reader<'a> {
+    data: &'a [u8],
+    /// How many values with indefinite lengths the data is nested in.
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, depth: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn peek_tag(&self) -> Option<u8> {
+        self.data.first().copied()
+    }
+
+    /// Reads the next value, which must have the tag `tag`.
+    fn expect(&mut self, tag: u8) -> std::result::Result<Tlv<'a>, &'static str> {
+        let tlv = self.read()?;
+        if tlv.tag != tag {
+            return Err("unexpected tag");
+        }
+        Ok(tlv)
+    }
+
+    /// Reads the next value.
+    fn read(&mut self) -> std::result::Result<Tlv<'a>, &'static str> {
+        let data = self.data;
+        let (&tag, rest) = data.split_first().ok_or("unexpected end of data")?;
+        if tag & 0x1f == 0x1f {
+            return Err("multi-byte tags are not supported");
+        }
+        let (&first, rest) = rest.split_first().ok_or("unexpected end of data")?;
+        let header_len = data.len() - rest.len();
+
+        if first == 0x80 {
+            // an indefinite length, which only constructed values may have; the contents are the
+            // values up to the end-of-contents marker, two zero bytes.
+            if tag & 0x20 == 0 {
+                return Err("primitive value with indefinite length");
+            }
+            if self.depth >= MAX_INDEFINITE_DEPTH {
+                return Err("values with indefinite lengths are nested too deeply");
+            }
+            let mut children = Reader {
+                data: rest,
+                depth: self.depth + 1,
+            };
+            while !children.data.starts_with(&[0, 0]) {
+                children.read()?;
+            }
+            let contents_len = rest.len() - children.data.len();
+            let total = header_len + contents_len + 2;
+            self.data = &data[total..];
+            return Ok(Tlv {
+                tag,
+                contents: &rest[..contents_len],
+                raw: &data[..total],
+                indefinite: true,
+            });
+        }
+
+        let (len, rest) = if first & 0x80 == 0 {
+            (first as usize, rest)
+        } else {
+            let count = (first & 0x7f) as usize;
+            if count > 4 || rest.len() < count {
+                return Err("invalid length");
+            }
+            let len = rest[..count]
+                .iter()
+                .fold(0usize, |len, byte| (len << 8) | *byte as usize);
+            (len, &rest[count..])
+        };
+        if rest.len() < len {
+            return Err("value is longer than the data");
+        }
+        let header_len = data.len() - rest.len();
+        let total = header_len + len;
+        self.data = &data[total..];
+        Ok(Tlv {
+            tag,
+            contents: &rest[..len],
+            raw: &data[..total],
+            indefinite: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Error;
+
+    const DOCUMENT: &str = include_str!("../tests/data/identity-document.json");
+    const SIGNATURE: &str = include_str!("../tests/data/identity-rsa2048");
+    /// Signed with definite lengths, and with the signer's certificate included.
+    const SIGNATURE_DER: &str = include_str!("../tests/data/identity-rsa2048-der");
+    /// Signed without signed attributes, so the signature covers the content directly.
+    const SIGNATURE_NO_ATTRIBUTES: &str = include_str!("../tests/data/identity-rsa2048-noattr");
+    const SIGNER: &str = include_str!("../tests/data/identity-signer.pem");
+    const OTHER_SIGNER: &str = include_str!("../tests/data/other-signer.pem");
+
+    fn certificates(pem: &str) -> IdentityCertificates {
+        IdentityCertificates::empty()
+            .with_pem(Partition::Aws, pem)
+            .unwrap()
+    }
+
+    #[test]
+    fn verified() {
+        let (partition, content) = verify(SIGNATURE, &certificates(SIGNER)).unwrap();
+        assert_eq!(partition, Partition::Aws);
+        assert_eq!(content, DOCUMENT.as_bytes());
+    }
+
+    #[test]
+    fn verified_other_encodings() {
+        for signature in &[SIGNATURE_DER, SIGNATURE_NO_ATTRIBUTES] {
+            let (partition, content) = verify(signature, &certificates(SIGNER)).unwrap();
+            assert_eq!(partition, Partition::Aws);
+            assert_eq!(content, DOCUMENT.as_bytes());
+        }
+    }
+
+    #[test]
+    fn other_signer_invalid() {
+        let error = verify(SIGNATURE, &certificates(OTHER_SIGNER)).unwrap_err();
+        assert!(matches!(error, Error::IdentitySignatureInvalid));
+    }
+
+    #[test]
+    fn tampered_content_invalid() {
+        let mut der = base64::decode(strip_whitespace(SIGNATURE)).unwrap();
+        let at = der
+            .windows(8)
+            .position(|window| window == b"m5.large")
+            .unwrap();
+        der[at..at + 8].copy_from_slice(b"m5.metal");
+        let error = verify(&base64::encode(&der), &certificates(SIGNER)).unwrap_err();
+        assert!(matches!(error, Error::IdentitySignatureInvalid));
+    }
+
+    #[test]
+    fn tampered_content_without_attributes_invalid() {
+        let mut der = base64::decode(strip_whitespace(SIGNATURE_NO_ATTRIBUTES)).unwrap();
+        let at = der
+            .windows(8)
+            .position(|window| window == b"m5.large")
+            .unwrap();
+        der[at..at + 8].copy_from_slice(b"m5.metal");
+        let error = verify(&base64::encode(&der), &certificates(SIGNER)).unwrap_err();
+        assert!(matches!(error, Error::IdentitySignatureInvalid));
+    }
+
+    #[test]
+    fn garbled_signature_is_parse_error() {
+        let error = verify("bm90IGEgc2lnbmF0dXJl", &certificates(SIGNER)).unwrap_err();
+        assert!(matches!(error, Error::IdentitySignatureParse { .. }));
+        let error = verify("not base64!", &certificates(SIGNER)).unwrap_err();
+        assert!(matches!(error, Error::IdentitySignatureBase64 { .. }));
+    }
+
+    #[test]
+    fn deeply_nested_signature_is_parse_error() {
+        // sequences with indefinite lengths, each holding the next, deep enough that following
+        // them all would overflow the stack.
+        let mut der = [TAG_SEQUENCE, 0x80].repeat(100_000);
+        der.extend([0u8, 0].repeat(100_000));
+        let error = verify(&base64::encode(&der), &certificates(SIGNER)).unwrap_err();
+        assert!(matches!(error, Error::IdentitySignatureParse { .. }));
+
+        let nested = |depth| {
+            let mut der = [TAG_SEQUENCE, 0x80].repeat(depth);
+            der.extend([0u8, 0].repeat(depth));
+            der
+        };
+        assert!(Reader::new(&nested(MAX_INDEFINITE_DEPTH)).read().is_ok());
+        assert!(Reader::new(&nested(MAX_INDEFINITE_DEPTH + 1))
+            .read()
+            .is_err());
+    }
+
+    #[test]
+    fn no_certificates() {
+        let error = verify(SIGNATURE, &IdentityCertificates::empty()).unwrap_err();
+        assert!(matches!(error, Error::NoIdentityCertificates));
+    }
+
+    #[test]
+    fn partitions() {
+        assert_eq!(Partition::from_region("us-west-2"), Partition::Aws);
+        assert_eq!(Partition::from_region("cn-northwest-1"), Partition::AwsCn);
+        assert_eq!(Partition::from_region("us-gov-east-1"), Partition::AwsUsGov);
+        assert_eq!(Partition::AwsUsGov.to_string(), "aws-us-gov");
+    }
+
+    #[test]
+    fn bundled_certificates_parse() {
+        IdentityCertificates::bundled().unwrap();
+    }
+}
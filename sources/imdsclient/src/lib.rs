@@ -4,34 +4,206 @@ The high-level methods provided are [`fetch_dynamic`], [`fetch_metadata`], and [
 
 For more control, and to query IMDS without high-level wrappers, there is also a [`fetch_imds`] method.
 This method is useful for specifying things like a pinned date for the IMDS schema version.
+
+The description of a target used in log messages is derived from the last two segments of its path,
+e.g. `interfaces macs` for `meta-data/network/interfaces/macs`.  Callers who want a different
+description can use [`fetch_metadata_with_description`] or [`fetch_dynamic_with_description`].
+
+Several unrelated targets can be fetched concurrently with [`fetch_many`], which keeps at most four
+requests in flight to stay within IMDS throttling limits.
+
+Callers that only need to know whether a metadata category exists, e.g. whether a spot
+interruption is scheduled, can use [`exists`], which checks the response status without
+downloading the body.
+
+Responses can optionally be cached by calling [`ImdsClient::with_cache`].  Whether a response is
+cached, and for how long, depends on its category: immutable data like the identity document is
+cached for the life of the client, while mutable data like public keys is cached only briefly and
+404 responses for it are never cached.
+
+Compliance checks can use [`fetch_metadata_options`] to read how IMDS itself is protected, e.g.
+whether session tokens are required and the hop limit for token requests.  Options that older
+platforms don't offer are `None`.
+
+The identity document is unverified by default.  Callers that need to trust it can use
+[`fetch_verified_identity_document`], which fetches the PKCS7-signed copy of the document from
+`dynamic/instance-identity/rsa2048` and checks its signature against the AWS certificates bundled
+in `data/identity-certificates` for the partition of the document's region before deserializing
+it.  A signature that doesn't match fails with an error of kind [`ErrorKind::InvalidSignature`], so
+callers can decide whether to fall back to the unverified document.  Other certificates can be
+given with [`fetch_identity_document_verified_by`].
+
+Errors carry their underlying cause, available through `std::error::Error::source`.  The error
+type is non-exhaustive, so callers that need to react to a particular failure, like a missing
+target or a rejected session token, should match on [`Error::kind`] rather than on its variants.
+
+Failures that say something about IMDS as a whole have their own kinds.  A refused connection or a
+403 response, which is what an instance with IMDS disabled or its token hop limit exceeded gets,
+is of kind [`ErrorKind::Disabled`], so callers can carry on without metadata rather than retrying.
+Throttling and 5xx responses are of kind [`ErrorKind::Transient`], since they may succeed later.
+
+The `imds-smoke` binary, built with the `smoke` feature, runs each of these helpers against the
+real IMDS and prints a table of the results, to validate new instance types and IMDS schema
+changes.  Run it on an instance with `--json` for machine-readable output; it exits non-zero if a
+required check fails.
+
+Requests use the IMDS schema version `2021-01-03`.  For IMDS implementations that only offer older
+versions, like some local stacks, call [`ImdsClient::with_schema_negotiation`] when building the
+client; it picks the newest dated version at or before `2021-01-03` from the listing returned by
+[`fetch_available_versions`], and uses it for every later request.
+
+Components that only need a few categories of metadata can restrict their client to them with
+[`ImdsClient::allowed_prefixes`], e.g. `&["meta-data/public-keys"]`, so that they can't fetch
+anything else, like IAM credentials, even if they're compromised.  Requests for any other target
+fail with an error of kind [`ErrorKind::NotAllowed`] before anything is sent.  Clients are
+unrestricted by default.
+
+Each request is retried a few times, so a helper that sends many requests, like
+[`fetch_public_ssh_keys`], can take much longer than a single request.  To bound that, call
+[`ImdsClient::with_budget`] when building the client; each call to a helper then has that much
+wall-clock time, from its first request, for all of its requests and retries.  A call that runs
+out fails with a `BudgetExceeded` error right away, even with a request in flight, rather than
+starting more requests.  Calls are unlimited by default.
+
+Session tokens are requested with a lifetime of 60 seconds, or up to six hours with
+[`ImdsClient::with_token_ttl`].  The client tracks each token's age and refreshes it shortly before
+it expires, rather than waiting for IMDS to reject a request with it; a rejected token is still
+refreshed and the request retried.
+
+[`ImdsClient::new`] uses the IPv4 endpoint, `169.254.169.254`, and falls back to the IPv6 endpoint,
+`fd00:ec2::254`, if it can't connect, e.g. in an IPv6-only subnet.  To use one endpoint only, call
+[`ImdsClient::new_with_endpoint`] with an [`ImdsEndpoint`].  The endpoint in use is logged at info
+level.
+
+To help with bug reports about metadata, a client built with [`ImdsClient::record_responses`]
+writes the body of each response it fetches to a file in the given directory, named for the
+target, e.g. `meta-data_instance-type`.  Each file holds at most 64 KiB.  Targets that hold
+secrets, like IAM credentials and user data, are never recorded, and neither are session tokens.
+Recording is best-effort, and never changes the result of a fetch.
+
+Creating a client doesn't send anything: the endpoint is picked, and the session token fetched,
+with the client's first request, and concurrent first requests share one token.  Callers that want
+to know early whether IMDS is reachable can call [`ImdsClient::connect`].
 */
 
 #![deny(rust_2018_idioms)]
 
+mod budget;
+mod cache;
+mod identity;
+mod recorder;
+
+use budget::Deadline;
+use cache::{CachedResponse, ResponseCache};
+use futures::stream::{self, StreamExt, TryStreamExt};
 use http::StatusCode;
 use log::{debug, info, trace, warn};
-use reqwest::Client;
+use recorder::ResponseRecorder;
+use reqwest::{Client, Response};
 use serde::{Deserialize, Serialize};
-use snafu::{ensure, ResultExt};
-use std::time::Duration;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
 use tokio::time;
 
 const BASE_URI: &str = "http://169.254.169.254";
+/// The IMDS endpoint for instances in IPv6-only subnets.
+const BASE_URI_IPV6: &str = "http://[fd00:ec2::254]";
+/// The preferred schema version, which is used unless the client negotiates an older one.
 const PINNED_SCHEMA: &str = "2021-01-03";
 
 // Currently only able to get fetch session tokens from `latest`
 const SESSION_TARGET: &str = "latest/api/token";
+/// The lifetime of session tokens unless the client is given another one.
+const DEFAULT_TOKEN_TTL: Duration = Duration::from_secs(60);
+/// The longest lifetime that IMDS allows for a session token, six hours.
+const MAX_TOKEN_TTL: Duration = Duration::from_secs(21600);
+/// A session token is refreshed when it has less than this left to live, so that a request sent
+/// with it doesn't arrive after it expired.
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(1);
+
+// The most requests `fetch_many` keeps in flight, so that we stay within IMDS throttling limits.
+const MAX_CONCURRENT_FETCHES: usize = 4;
 
 /// A client for making IMDSv2 queries.
-/// It obtains a session token when it is first instantiated and is reused between helper functions.
+/// It obtains a session token when it sends its first request, or when `connect` is called, and the
+/// token is reused between helper functions.
+/// The session and the cache are behind locks so that requests can be made concurrently.
 pub struct ImdsClient {
     client: Client,
-    imds_base_uri: String,
-    session_token: String,
+    /// The base URIs of the endpoints the client may use, in order of preference.
+    base_uris: Vec<String>,
+    /// The schema version used in request paths: `PINNED_SCHEMA`, unless an older one was
+    /// negotiated.
+    schema_version: String,
+    /// The endpoint in use and its session token, or `None` before the first request.
+    session: RwLock<Option<Session>>,
+    /// The lifetime requested for new session tokens.
+    token_ttl: Duration,
+    cache: Option<Mutex<ResponseCache>>,
+    /// The prefixes of the targets the client may fetch, or `None` if it's unrestricted.
+    allowed_prefixes: Option<Vec<String>>,
+    /// Where fetched responses are recorded, or `None` if they aren't.
+    recorder: Option<ResponseRecorder>,
+    /// How long each call to a helper may take, across all of its requests, or `None` if it's
+    /// unlimited.
+    budget: Option<Duration>,
+    /// How many bytes of response bodies have been read, so tests can check that `exists` doesn't
+    /// read any.
+    #[cfg(test)]
+    body_bytes_read: std::sync::atomic::AtomicUsize,
+}
+
+/// The address at which a client reaches IMDS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImdsEndpoint {
+    /// `169.254.169.254`, which is available unless the instance is in an IPv6-only subnet.
+    IpV4,
+    /// `fd00:ec2::254`, which is available on Nitro instances if the IPv6 endpoint is enabled.
+    IpV6,
+}
+
+impl ImdsEndpoint {
+    fn base_uri(self) -> &'static str {
+        match self {
+            ImdsEndpoint::IpV4 => BASE_URI,
+            ImdsEndpoint::IpV6 => BASE_URI_IPV6,
+        }
+    }
+}
+
+/// The endpoint a client settled on, and its current session token.
+#[derive(Debug, Clone)]
+struct Session {
+    base_uri: String,
+    token: SessionToken,
+}
+
+/// An IMDSv2 session token, with what's needed to tell when it expires.
+#[derive(Debug, Clone)]
+struct SessionToken {
+    value: String,
+    fetched: Instant,
+    ttl: Duration,
+}
+
+impl SessionToken {
+    /// Returns true if the token expires within `TOKEN_EXPIRY_MARGIN`, or already has.
+    fn is_expiring(&self) -> bool {
+        self.fetched.elapsed() + TOKEN_EXPIRY_MARGIN >= self.ttl
+    }
 }
 
 /// This is the return type when querying for the IMDS identity document, which contains information
 /// such as region and instance_type. We only include the fields that we are using in Bottlerocket.
+///
+/// A document can also be parsed from a string or bytes, for example from a cached copy on disk.
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct IdentityDocument {
@@ -49,40 +221,247 @@ impl IdentityDocument {
     }
 }
 
+/// Only the fields we keep are printed. The rest of the document, e.g. the account ID, is dropped
+/// during deserialization and is marked as redacted so that it's clear the output is partial.
+impl fmt::Debug for IdentityDocument {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IdentityDocument")
+            .field("region", &self.region)
+            .field("instance_type", &self.instance_type)
+            .field("other_fields", &"<redacted>")
+            .finish()
+    }
+}
+
+impl FromStr for IdentityDocument {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        serde_json::from_str(s).context(error::Serde)
+    }
+}
+
+impl TryFrom<&[u8]> for IdentityDocument {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        serde_json::from_slice(bytes).context(error::Serde)
+    }
+}
+
+/// The metadata options of the instance, which describe how IMDS itself is protected.  Each field
+/// is `None` if IMDS doesn't offer it, as on older platforms.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MetadataOptions {
+    /// `required` if session tokens, i.e. IMDSv2, are enforced, or `optional`.
+    pub http_tokens: Option<String>,
+    /// How many network hops the response to a token request may take.  A limit of 1 keeps
+    /// containers that don't use host networking from getting tokens.
+    pub http_put_response_hop_limit: Option<u8>,
+    /// `enabled` or `disabled`.
+    pub http_endpoint: Option<String>,
+}
+
+impl MetadataOptions {
+    /// Returns whether IMDS is known to require session tokens.
+    pub fn tokens_required(&self) -> bool {
+        self.http_tokens.as_deref() == Some("required")
+    }
+}
+
+impl Default for ImdsClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ImdsClient {
-    pub async fn new() -> Result<Self> {
-        Self::new_impl(BASE_URI.to_string()).await
+    /// Creates a client for the IPv4 endpoint, or for the IPv6 endpoint if the IPv4 one can't be
+    /// connected to, e.g. in an IPv6-only subnet.  Nothing is sent until the first request.
+    pub fn new() -> Self {
+        Self::new_with_fallback(vec![
+            ImdsEndpoint::IpV4.base_uri().to_string(),
+            ImdsEndpoint::IpV6.base_uri().to_string(),
+        ])
     }
 
-    async fn new_impl(imds_base_uri: String) -> Result<Self> {
-        let client = Client::new();
-        let session_token = fetch_token(&client, &imds_base_uri).await?;
-        Ok(Self {
-            client,
-            imds_base_uri,
-            session_token,
-        })
+    /// Creates a client for `endpoint`, without falling back to the other one.
+    pub fn new_with_endpoint(endpoint: ImdsEndpoint) -> Self {
+        Self::new_impl(endpoint.base_uri().to_string())
+    }
+
+    /// Creates a client that sends requests to `imds_base_uri` rather than IMDS, e.g. to a mock
+    /// server in tests.
+    pub fn new_with_base_uri<S>(imds_base_uri: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self::new_impl(imds_base_uri.into())
+    }
+
+    fn new_impl(imds_base_uri: String) -> Self {
+        Self::new_with_fallback(vec![imds_base_uri])
+    }
+
+    /// Creates a client that uses the first of `base_uris` that a session token can be fetched
+    /// from.  The next is only tried if the connection to one fails.
+    fn new_with_fallback(base_uris: Vec<String>) -> Self {
+        Self {
+            client: Client::new(),
+            base_uris,
+            schema_version: PINNED_SCHEMA.to_string(),
+            session: RwLock::new(None),
+            token_ttl: DEFAULT_TOKEN_TTL,
+            cache: None,
+            allowed_prefixes: None,
+            recorder: None,
+            budget: None,
+            #[cfg(test)]
+            body_bytes_read: Default::default(),
+        }
+    }
+
+    /// Picks the endpoint and fetches a session token now, rather than on the first request, so
+    /// that callers can find out early whether IMDS is reachable.
+    pub async fn connect(&self) -> Result<()> {
+        self.session(self.start_deadline()).await.map(|_| ())
+    }
+
+    /// Enables caching of responses according to each target's cache policy.
+    pub fn with_cache(mut self) -> Self {
+        self.cache = Some(Mutex::new(ResponseCache::default()));
+        self
+    }
+
+    /// Restricts the client to targets under `prefixes`, e.g. `meta-data/public-keys`, which allows
+    /// `meta-data/public-keys` and `meta-data/public-keys/0/openssh-key` but not
+    /// `meta-data/public-keys-other`.  Requests for any other target fail with a
+    /// `TargetNotAllowed` error without being sent.
+    pub fn allowed_prefixes(mut self, prefixes: &[&str]) -> Self {
+        self.allowed_prefixes = Some(
+            prefixes
+                .iter()
+                .map(|prefix| prefix.trim_matches('/').to_string())
+                .collect(),
+        );
+        self
+    }
+
+    /// Records the body of each response the client fetches in a file in `dir`, named for its
+    /// target with characters other than ASCII letters, digits, `-`, and `_` replaced by `_`, e.g.
+    /// `meta-data_instance-type`.  Only the first 64 KiB of a response is recorded.  Targets that
+    /// hold secrets, like IAM credentials and user data, are never recorded, and neither are
+    /// session tokens.  A response that can't be recorded is logged and returned as usual.
+    pub fn record_responses<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.recorder = Some(ResponseRecorder::new(dir));
+        self
+    }
+
+    /// Limits each call to a helper, e.g. `fetch_public_ssh_keys`, to `budget` of wall-clock time
+    /// across all of its requests and their retries, starting from its first request.  Once the
+    /// budget is used up, the call fails with a `BudgetExceeded` error, including while a request
+    /// is in flight, rather than starting new requests.  Calls are unlimited by default.
+    pub fn with_budget(mut self, budget: Duration) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Requests session tokens that live for `ttl`, in whole seconds up to the IMDS maximum of six
+    /// hours, rather than 60 seconds.  A token that was already fetched keeps its lifetime; tokens
+    /// are refreshed shortly before they expire, so the next one has the new lifetime.
+    pub fn with_token_ttl(mut self, ttl: Duration) -> Result<Self> {
+        ensure!(
+            ttl.subsec_nanos() == 0 && ttl.as_secs() >= 1 && ttl <= MAX_TOKEN_TTL,
+            error::TokenTtl { ttl }
+        );
+        self.token_ttl = ttl;
+        Ok(self)
+    }
+
+    /// Negotiates the schema version with IMDS, see [`negotiate_schema_version`], so that the
+    /// client works with IMDS implementations that don't offer the preferred version.  Without
+    /// this, requests always use the preferred version.
+    ///
+    /// [`negotiate_schema_version`]: ImdsClient::negotiate_schema_version
+    pub async fn with_schema_negotiation(mut self) -> Result<Self> {
+        self.negotiate_schema_version().await?;
+        Ok(self)
+    }
+
+    /// Returns the schema version used in requests.
+    pub fn schema_version(&self) -> &str {
+        &self.schema_version
+    }
+
+    /// Gets the schema versions that IMDS offers, from the listing at its root, e.g.
+    /// `2021-01-03` or `latest`.
+    pub async fn fetch_available_versions(&mut self) -> Result<Vec<String>> {
+        self.available_versions(self.start_deadline()).await
+    }
+
+    /// Picks the newest dated schema version that IMDS offers at or before the preferred version,
+    /// and uses it for later requests.  Returns the chosen version.
+    pub async fn negotiate_schema_version(&mut self) -> Result<&str> {
+        let available = self.available_versions(self.start_deadline()).await?;
+        let version =
+            select_schema_version(&available, PINNED_SCHEMA).context(error::NoSchemaVersion {
+                preferred: PINNED_SCHEMA,
+                available,
+            })?;
+        if version != PINNED_SCHEMA {
+            info!(
+                "IMDS doesn't offer schema version {}, using {}",
+                PINNED_SCHEMA, version
+            );
+        }
+        self.schema_version = version;
+        Ok(&self.schema_version)
     }
 
     /// Gets `user-data` from IMDS. The user-data may be either a UTF-8 string or compressed bytes.
     pub async fn fetch_userdata(&mut self) -> Result<Vec<u8>> {
-        self.fetch_imds(PINNED_SCHEMA, "user-data").await
+        self.fetch_imds(&self.schema_version, "user-data", self.start_deadline())
+            .await
     }
 
     /// Returns the 'identity document' with fields like region and instance_type.
     pub async fn fetch_identity_document(&mut self) -> Result<IdentityDocument> {
         let target = "dynamic/instance-identity/document";
-        let response = self.fetch_bytes(target).await?;
-        let identity_document: IdentityDocument =
-            serde_json::from_slice(&response).context(error::Serde)?;
-        Ok(identity_document)
+        let response = self.fetch_bytes(target, self.start_deadline()).await?;
+        IdentityDocument::try_from(response.as_slice())
+    }
+
+    /// Returns the 'identity document' after checking its signature against the AWS certificates
+    /// bundled with the client.  The document is read from the signed blob, so nothing is
+    /// deserialized before it's verified.  A signature that doesn't match fails with an error of
+    /// kind [`ErrorKind::InvalidSignature`].
+    pub async fn fetch_verified_identity_document(&mut self) -> Result<IdentityDocument> {
+        let certificates = IdentityCertificates::bundled()?;
+        self.fetch_identity_document_verified_by(&certificates)
+            .await
+    }
+
+    /// Returns the 'identity document' after checking its signature against `certificates`, and
+    /// checking that it was signed by a certificate of the partition of its region.
+    pub async fn fetch_identity_document_verified_by(
+        &mut self,
+        certificates: &IdentityCertificates,
+    ) -> Result<IdentityDocument> {
+        let target = "dynamic/instance-identity/rsa2048";
+        let signature = self.fetch_string(target, self.start_deadline()).await?;
+        let (partition, content) = identity::verify(&signature, certificates)?;
+        let document = IdentityDocument::try_from(content.as_slice())?;
+        ensure!(
+            Partition::from_region(document.region()) == partition,
+            error::IdentitySignatureInvalid
+        );
+        Ok(document)
     }
 
     /// Returns the list of network interface mac addresses.
     pub async fn fetch_mac_addresses(&mut self) -> Result<Vec<String>> {
-        let macs_target = "meta-data/network/interfaces/macs";
-        let macs = self.fetch_string(&macs_target).await?;
-        Ok(macs.split('\n').map(|s| s.to_string()).collect())
+        self.mac_addresses(self.start_deadline()).await
     }
 
     /// Gets the list of CIDR blocks for a given network interface `mac` address.
@@ -92,21 +471,121 @@ impl ImdsClient {
             "meta-data/network/interfaces/macs/{}/vpc-ipv4-cidr-blocks",
             mac
         );
-        let cidr_blocks = self.fetch_string(&mac_cidr_blocks_target).await?;
+        let cidr_blocks = self
+            .fetch_string(&mac_cidr_blocks_target, self.start_deadline())
+            .await?;
+        Ok(cidr_blocks.split('\n').map(|s| s.to_string()).collect())
+    }
+
+    /// Gets the list of IPV6 CIDR blocks for a given network interface `mac` address.  IMDS
+    /// returns 404, and so this returns an error of kind `NotFound`, if the VPC has none.
+    pub async fn fetch_ipv6_cidr_blocks_for_mac(&mut self, mac: &str) -> Result<Vec<String>> {
+        let mac_cidr_blocks_target = format!(
+            "meta-data/network/interfaces/macs/{}/vpc-ipv6-cidr-blocks",
+            mac
+        );
+        let cidr_blocks = self
+            .fetch_string(&mac_cidr_blocks_target, self.start_deadline())
+            .await?;
         Ok(cidr_blocks.split('\n').map(|s| s.to_string()).collect())
     }
 
+    /// Gets the list of IPV6 addresses of a given network interface `mac` address.  IMDS returns
+    /// 404 if the interface has none, and so this returns an empty list.
+    pub async fn fetch_ipv6_addresses_for_mac(&mut self, mac: &str) -> Result<Vec<String>> {
+        self.ipv6_addresses_for_mac(mac, self.start_deadline())
+            .await
+    }
+
+    /// Gets the first IPV6 address of the first network interface listed by
+    /// `fetch_mac_addresses`, or `None` if it has none.
+    pub async fn fetch_primary_ipv6_address(&mut self) -> Result<Option<String>> {
+        let deadline = self.start_deadline();
+        let macs = self.mac_addresses(deadline).await?;
+        let mac = match macs.first() {
+            Some(mac) if !mac.is_empty() => mac,
+            _ => return Ok(None),
+        };
+        let addresses = self.ipv6_addresses_for_mac(mac, deadline).await?;
+        Ok(addresses.into_iter().next())
+    }
+
     /// Gets the local IPV4 address from instance metadata.
     pub async fn fetch_local_ipv4_address(&mut self) -> Result<String> {
         let node_ip_target = "meta-data/local-ipv4";
-        self.fetch_string(&node_ip_target).await
+        self.fetch_string(&node_ip_target, self.start_deadline())
+            .await
+    }
+
+    /// Gets the name of the availability zone of the instance, e.g. `us-west-2a`.  Returns an error
+    /// if IMDS gives an empty response.
+    pub async fn fetch_availability_zone(&mut self) -> Result<String> {
+        self.fetch_nonempty_string(
+            "meta-data/placement/availability-zone",
+            self.start_deadline(),
+        )
+        .await
+    }
+
+    /// Gets the ID of the availability zone of the instance, e.g. `usw2-az1`.  Unlike the zone's
+    /// name, its ID refers to the same zone in every account.  Returns an error if IMDS gives an
+    /// empty response.
+    pub async fn fetch_zone_id(&mut self) -> Result<String> {
+        self.fetch_nonempty_string(
+            "meta-data/placement/availability-zone-id",
+            self.start_deadline(),
+        )
+        .await
+    }
+
+    /// Gets the ID of the instance, e.g. `i-0123456789abcdef0`.  Returns an error if IMDS gives an
+    /// empty response.
+    pub async fn fetch_instance_id(&mut self) -> Result<String> {
+        self.fetch_nonempty_string("meta-data/instance-id", self.start_deadline())
+            .await
+    }
+
+    /// Gets the metadata options of the instance from `meta-data/metadata-options`.  The options
+    /// are fetched concurrently, and any that IMDS doesn't offer are `None`.  Returns an error if
+    /// the hop limit isn't a number.
+    pub async fn fetch_metadata_options(&mut self) -> Result<MetadataOptions> {
+        let http_tokens = "meta-data/metadata-options/http-tokens";
+        let hop_limit = "meta-data/metadata-options/http-put-response-hop-limit";
+        let http_endpoint = "meta-data/metadata-options/http-endpoint";
+        let mut responses = self
+            .fetch_many(&[
+                (http_tokens, "http tokens"),
+                (hop_limit, "hop limit"),
+                (http_endpoint, "http endpoint"),
+            ])
+            .await?;
+        let mut option = |target: &str| {
+            responses
+                .remove(target)
+                .flatten()
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+        let http_tokens = option(http_tokens);
+        let http_endpoint = option(http_endpoint);
+        let http_put_response_hop_limit = match option(hop_limit) {
+            Some(value) => Some(value.parse().context(error::HopLimit { value })?),
+            None => None,
+        };
+        Ok(MetadataOptions {
+            http_tokens,
+            http_put_response_hop_limit,
+            http_endpoint,
+        })
     }
 
-    /// Returns a list of public ssh keys skipping any keys that do not start with 'ssh'.
+    /// Returns a list of public ssh keys skipping any keys that do not start with 'ssh'.  The keys
+    /// are fetched concurrently, at most four at a time, and returned in index order.
     pub async fn fetch_public_ssh_keys(&mut self) -> Result<Vec<String>> {
         info!("Fetching list of available public keys from IMDS");
+        let deadline = self.start_deadline();
         // Returns a list of available public keys as '0=my-public-key'
-        let public_key_list = match self.fetch_string("meta-data/public-keys").await {
+        let public_key_list = match self.fetch_string("meta-data/public-keys", deadline).await {
             Err(error::Error::NotFound { uri: _ }) => {
                 // this is OK, it just means there are no keys
                 debug!("no available public keys");
@@ -122,17 +601,21 @@ impl ImdsClient {
         info!("Generating targets to fetch text of available public keys");
         let public_key_targets = build_public_key_targets(&public_key_list);
 
-        let mut public_keys = Vec::new();
-        let target_count: u32 = 0;
-        for target in &public_key_targets {
-            let target_count = target_count + 1;
-            info!(
-                "Fetching public key ({}/{})",
-                target_count,
-                &public_key_targets.len()
-            );
+        // the keys are fetched concurrently, and `buffered` keeps them in the order of their
+        // indexes.
+        let client = &*self;
+        let key_count = public_key_targets.len();
+        let public_key_texts: Vec<String> = stream::iter(public_key_targets.iter().enumerate())
+            .map(|(i, target)| async move {
+                info!("Fetching public key ({}/{})", i + 1, key_count);
+                client.fetch_string(target, deadline).await
+            })
+            .buffered(MAX_CONCURRENT_FETCHES)
+            .try_collect()
+            .await?;
 
-            let public_key_text = self.fetch_string(&target).await?;
+        let mut public_keys = Vec::new();
+        for public_key_text in &public_key_texts {
             let public_key = public_key_text.trim_end();
             // Simple check to see if the text is probably an ssh key.
             if public_key.starts_with("ssh") {
@@ -152,36 +635,343 @@ impl ImdsClient {
         Ok(public_keys)
     }
 
-    /// Helper to fetch bytes from IMDS using the pinned schema version.
-    async fn fetch_bytes<S>(&mut self, end_target: S) -> Result<Vec<u8>>
+    /// Gets `meta-data/<end_target>` from IMDS using the client's schema version.
+    pub async fn fetch_metadata<S>(&mut self, end_target: S) -> Result<Vec<u8>>
+    where
+        S: AsRef<str>,
+    {
+        let target = format!("meta-data/{}", end_target.as_ref());
+        self.fetch_imds(&self.schema_version, target, self.start_deadline())
+            .await
+    }
+
+    /// Gets `meta-data/<end_target>` from IMDS using the client's schema version, using
+    /// `description` for the target in log messages.
+    pub async fn fetch_metadata_with_description<S1, S2>(
+        &mut self,
+        end_target: S1,
+        description: S2,
+    ) -> Result<Vec<u8>>
+    where
+        S1: AsRef<str>,
+        S2: AsRef<str>,
+    {
+        let target = format!("meta-data/{}", end_target.as_ref());
+        self.fetch_imds_described(
+            &self.schema_version,
+            target,
+            description,
+            self.start_deadline(),
+        )
+        .await
+    }
+
+    /// Gets `dynamic/<end_target>` from IMDS using the client's schema version.
+    pub async fn fetch_dynamic<S>(&mut self, end_target: S) -> Result<Vec<u8>>
+    where
+        S: AsRef<str>,
+    {
+        let target = format!("dynamic/{}", end_target.as_ref());
+        self.fetch_imds(&self.schema_version, target, self.start_deadline())
+            .await
+    }
+
+    /// Gets `dynamic/<end_target>` from IMDS using the client's schema version, using `description`
+    /// for the target in log messages.
+    pub async fn fetch_dynamic_with_description<S1, S2>(
+        &mut self,
+        end_target: S1,
+        description: S2,
+    ) -> Result<Vec<u8>>
+    where
+        S1: AsRef<str>,
+        S2: AsRef<str>,
+    {
+        let target = format!("dynamic/{}", end_target.as_ref());
+        self.fetch_imds_described(
+            &self.schema_version,
+            target,
+            description,
+            self.start_deadline(),
+        )
+        .await
+    }
+
+    /// Returns whether `meta-data/<end_target>` exists in IMDS, using the client's schema version,
+    /// e.g. `spot/instance-action` to check whether a spot interruption is scheduled. The response
+    /// body isn't downloaded. IMDS returns 404 for targets that don't exist; any other status
+    /// besides 200 is an error, after the usual retries.
+    pub async fn exists<S>(&mut self, end_target: S) -> Result<bool>
+    where
+        S: AsRef<str>,
+    {
+        let target = format!("meta-data/{}", end_target.as_ref());
+        self.check_target_allowed(&target)?;
+        if let Some(cached) = self.cached_response(&self.schema_version, &target) {
+            let uri = self.cached_uri(&self.schema_version, &target).await;
+            debug!("Using cached response for {}", &uri);
+            return Ok(matches!(cached, CachedResponse::Found(_)));
+        }
+        self.exists_unclassified(&target, self.start_deadline())
+            .await
+            .map_err(error::Error::classified)
+    }
+
+    /// Requests `target` from IMDS and returns whether it exists, returning errors as they
+    /// happened.
+    async fn exists_unclassified(&self, target: &str, deadline: Deadline) -> Result<bool> {
+        let uri = format!(
+            "{}/{}/{}",
+            self.base_uri(deadline).await?,
+            self.schema_version,
+            target
+        );
+        debug!("Checking whether {} exists", &uri);
+        let response = self.send_request(&uri, deadline).await?;
+        match response.status() {
+            // the body isn't read; dropping the response closes the connection.
+            StatusCode::OK => Ok(true),
+            StatusCode::NOT_FOUND => {
+                self.cache_response(&self.schema_version, target, CachedResponse::NotFound);
+                Ok(false)
+            }
+            _ => self.error_response(response, &uri, deadline).await,
+        }
+    }
+
+    /// Gets several targets from IMDS concurrently using the client's schema version. Each entry of
+    /// `targets` is a target, e.g. `meta-data/instance-type`, and its description for log messages.
+    /// Returns the response for each target, keyed by target, which is `None` if the target wasn't
+    /// found. Any other error fails the whole call. The client's budget, if any, covers all of the
+    /// targets together.
+    pub async fn fetch_many(
+        &self,
+        targets: &[(&str, &str)],
+    ) -> Result<HashMap<String, Option<String>>> {
+        let deadline = self.start_deadline();
+        stream::iter(targets)
+            .map(|(target, description)| async move {
+                let response = match self
+                    .fetch_imds_described(&self.schema_version, target, description, deadline)
+                    .await
+                {
+                    Ok(response_body) => {
+                        Some(String::from_utf8(response_body).context(error::NonUtf8Response)?)
+                    }
+                    Err(error::Error::NotFound { .. }) => None,
+                    Err(e) => return Err(e),
+                };
+                Ok((target.to_string(), response))
+            })
+            .buffer_unordered(MAX_CONCURRENT_FETCHES)
+            .try_collect()
+            .await
+    }
+
+    /// Gets the list of network interface mac addresses within `deadline`.
+    async fn mac_addresses(&self, deadline: Deadline) -> Result<Vec<String>> {
+        let macs_target = "meta-data/network/interfaces/macs";
+        let macs = self.fetch_string(&macs_target, deadline).await?;
+        Ok(macs.split('\n').map(|s| s.to_string()).collect())
+    }
+
+    /// Gets the list of IPV6 addresses of the network interface `mac` within `deadline`, which is
+    /// empty if IMDS returns 404.
+    async fn ipv6_addresses_for_mac(&self, mac: &str, deadline: Deadline) -> Result<Vec<String>> {
+        let ipv6s_target = format!("meta-data/network/interfaces/macs/{}/ipv6s", mac);
+        match self.fetch_string(&ipv6s_target, deadline).await {
+            Ok(ipv6s) => Ok(ipv6s.split('\n').map(|s| s.to_string()).collect()),
+            Err(error::Error::NotFound { .. }) => {
+                debug!("no IPV6 addresses for {}", mac);
+                Ok(Vec::new())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Gets the schema versions that IMDS offers, from the listing at its root, within
+    /// `deadline`.
+    async fn available_versions(&self, deadline: Deadline) -> Result<Vec<String>> {
+        let uri = format!("{}/", self.base_uri(deadline).await?);
+        debug!("Requesting schema versions from {}", &uri);
+        let response = self.send_request(&uri, deadline).await?;
+        match response.status() {
+            StatusCode::OK => {
+                let response_body = self.read_body(response, &uri, deadline).await?;
+                let listing = String::from_utf8(response_body).context(error::NonUtf8Response)?;
+                Ok(listing
+                    .lines()
+                    .map(str::trim)
+                    .filter(|version| !version.is_empty())
+                    .map(str::to_string)
+                    .collect())
+            }
+            _ => self.error_response(response, &uri, deadline).await,
+        }
+    }
+
+    /// Helper to fetch bytes from IMDS using the client's schema version.
+    async fn fetch_bytes<S>(&self, end_target: S, deadline: Deadline) -> Result<Vec<u8>>
     where
         S: AsRef<str>,
     {
-        self.fetch_imds(PINNED_SCHEMA, end_target.as_ref()).await
+        self.fetch_imds(&self.schema_version, end_target.as_ref(), deadline)
+            .await
     }
 
-    /// Helper to fetch a string from IMDS using the pinned schema version.
-    async fn fetch_string<S>(&mut self, end_target: S) -> Result<String>
+    /// Helper to fetch a string from IMDS using the client's schema version.
+    async fn fetch_string<S>(&self, end_target: S, deadline: Deadline) -> Result<String>
     where
         S: AsRef<str>,
     {
-        let response_body = self.fetch_imds(PINNED_SCHEMA, end_target).await?;
+        let response_body = self
+            .fetch_imds(&self.schema_version, end_target, deadline)
+            .await?;
         Ok(String::from_utf8(response_body).context(error::NonUtf8Response)?)
     }
 
-    /// Fetch data from IMDS.
-    async fn fetch_imds<S1, S2>(&mut self, schema_version: S1, target: S2) -> Result<Vec<u8>>
+    /// Helper to fetch a string from IMDS using the client's schema version, with surrounding
+    /// whitespace removed, which fails if the string is empty.
+    async fn fetch_nonempty_string<S>(&self, end_target: S, deadline: Deadline) -> Result<String>
+    where
+        S: AsRef<str>,
+    {
+        let response = self.fetch_string(end_target.as_ref(), deadline).await?;
+        let value = response.trim();
+        ensure!(
+            !value.is_empty(),
+            error::EmptyResponse {
+                target: end_target.as_ref()
+            }
+        );
+        Ok(value.to_string())
+    }
+
+    /// Fetch data from IMDS, describing the target in log messages by the last two segments of its
+    /// path.
+    async fn fetch_imds<S1, S2>(
+        &self,
+        schema_version: S1,
+        target: S2,
+        deadline: Deadline,
+    ) -> Result<Vec<u8>>
+    where
+        S1: AsRef<str>,
+        S2: AsRef<str>,
+    {
+        let description = describe_target(target.as_ref());
+        self.fetch_imds_described(schema_version, target, description, deadline)
+            .await
+    }
+
+    /// Fetch data from IMDS, using `description` for the target in log messages.  Failures that
+    /// show IMDS is disabled, or that may be transient, are returned as their own errors; see
+    /// `Error::classified`.
+    async fn fetch_imds_described<S1, S2, S3>(
+        &self,
+        schema_version: S1,
+        target: S2,
+        description: S3,
+        deadline: Deadline,
+    ) -> Result<Vec<u8>>
+    where
+        S1: AsRef<str>,
+        S2: AsRef<str>,
+        S3: AsRef<str>,
+    {
+        self.fetch_imds_unclassified(schema_version, target, description, deadline)
+            .await
+            .map_err(error::Error::classified)
+    }
+
+    /// Fetch data from IMDS, using `description` for the target in log messages, returning errors
+    /// as they happened.
+    async fn fetch_imds_unclassified<S1, S2, S3>(
+        &self,
+        schema_version: S1,
+        target: S2,
+        description: S3,
+        deadline: Deadline,
+    ) -> Result<Vec<u8>>
     where
         S1: AsRef<str>,
         S2: AsRef<str>,
+        S3: AsRef<str>,
     {
+        self.check_target_allowed(target.as_ref())?;
+        if let Some(cached) = self.cached_response(schema_version.as_ref(), target.as_ref()) {
+            let uri = self
+                .cached_uri(schema_version.as_ref(), target.as_ref())
+                .await;
+            debug!("Using cached response for {}", &uri);
+            return match cached {
+                CachedResponse::Found(response_body) => Ok(response_body),
+                CachedResponse::NotFound => Err(error::Error::NotFound { uri }),
+            };
+        }
         let uri = format!(
             "{}/{}/{}",
-            self.imds_base_uri,
+            self.base_uri(deadline).await?,
             schema_version.as_ref(),
             target.as_ref()
         );
-        debug!("Requesting {}", &uri);
+        debug!("Requesting {} from {}", description.as_ref(), &uri);
+        let response = self.send_request(&uri, deadline).await?;
+        match response.status() {
+            StatusCode::OK => {
+                info!("Received {}", description.as_ref());
+                let response_body = self.read_body(response, &uri, deadline).await?;
+
+                let response_str = printable_string(&response_body);
+                trace!("Response: {:?}", response_str);
+
+                self.cache_response(
+                    schema_version.as_ref(),
+                    target.as_ref(),
+                    CachedResponse::Found(response_body.clone()),
+                );
+                if let Some(recorder) = &self.recorder {
+                    recorder.record(target.as_ref(), &response_body);
+                }
+                Ok(response_body)
+            }
+
+            // IMDS returns 404 if no user data is given, or if IMDS is disabled
+            StatusCode::NOT_FOUND => {
+                self.cache_response(
+                    schema_version.as_ref(),
+                    target.as_ref(),
+                    CachedResponse::NotFound,
+                );
+                Err(error::Error::NotFound { uri })
+            }
+
+            _ => self.error_response(response, &uri, deadline).await,
+        }
+    }
+
+    /// Starts the deadline of a call to a helper, from the client's budget.
+    fn start_deadline(&self) -> Deadline {
+        Deadline::start(self.budget)
+    }
+
+    /// Returns a `TargetNotAllowed` error if the client is restricted and `target` isn't under one
+    /// of its allowed prefixes.
+    fn check_target_allowed(&self, target: &str) -> Result<()> {
+        if let Some(prefixes) = &self.allowed_prefixes {
+            ensure!(
+                is_target_allowed(prefixes, target),
+                error::TargetNotAllowed { target }
+            );
+        }
+        Ok(())
+    }
+
+    /// Sends a GET request for `uri`, refreshing the session token and retrying as needed, and
+    /// returns the response without reading its body. Every status other than 401 and 408, which
+    /// are retried, is returned to the caller to handle. No attempt is started, and an attempt in
+    /// flight is abandoned, once `deadline` has passed.
+    async fn send_request(&self, uri: &str, deadline: Deadline) -> Result<Response> {
         let mut attempt: u8 = 0;
         let max_attempts: u8 = 3;
         loop {
@@ -190,44 +980,27 @@ impl ImdsClient {
                 time::sleep(Duration::from_millis(100)).await;
             }
             ensure!(attempt <= max_attempts, error::FailedFetch { attempt });
-            let response = self
-                .client
-                .get(&uri)
-                .header("X-aws-ec2-metadata-token", &self.session_token)
-                .send()
-                .await
-                .context(error::Request {
-                    method: "GET",
-                    uri: &uri,
-                })?;
+            deadline.check()?;
+            let session_token = self.current_token(deadline).await?;
+            let response = deadline
+                .run(async {
+                    self.client
+                        .get(uri)
+                        .header("X-aws-ec2-metadata-token", &session_token)
+                        .send()
+                        .await
+                        .context(error::Request { method: "GET", uri })
+                })
+                .await?;
             trace!("IMDS response: {:?}", &response);
 
             match response.status() {
-                code @ StatusCode::OK => {
-                    info!("Received {}", target.as_ref());
-                    let response_body = response
-                        .bytes()
-                        .await
-                        .context(error::ResponseBody {
-                            method: "GET",
-                            uri: &uri,
-                            code,
-                        })?
-                        .to_vec();
-
-                    let response_str = printable_string(&response_body);
-                    trace!("Response: {:?}", response_str);
-
-                    return Ok(response_body);
-                }
-
-                // IMDS returns 404 if no user data is given, or if IMDS is disabled
-                StatusCode::NOT_FOUND => return Err(error::Error::NotFound { uri }),
-
                 // IMDS returns 401 if the session token is expired or invalid
                 StatusCode::UNAUTHORIZED => {
                     info!("Session token is invalid or expired");
-                    self.refresh_token().await?;
+                    deadline
+                        .run(self.refresh_token(Some(&session_token)))
+                        .await?;
                     info!("Refreshed session token");
                     continue;
                 }
@@ -237,84 +1010,264 @@ impl ImdsClient {
                     continue;
                 }
 
-                code => {
-                    let response_body = response
-                        .bytes()
-                        .await
-                        .context(error::ResponseBody {
-                            method: "GET",
-                            uri: &uri,
-                            code,
-                        })?
-                        .to_vec();
-
-                    let response_str = printable_string(&response_body);
-
-                    trace!("Response: {:?}", response_str);
-
-                    return error::Response {
-                        method: "GET",
-                        uri: &uri,
-                        code,
-                        response_body: response_str,
-                    }
-                    .fail();
-                }
+                _ => return Ok(response),
             }
         }
     }
 
-    /// Fetches a new session token and adds it to the current ImdsClient.
-    async fn refresh_token(&mut self) -> Result<()> {
-        self.session_token = fetch_token(&self.client, &self.imds_base_uri).await?;
-        Ok(())
+    /// Reads the body of `response`, a response to a request for `uri`, within `deadline`.
+    async fn read_body(
+        &self,
+        response: Response,
+        uri: &str,
+        deadline: Deadline,
+    ) -> Result<Vec<u8>> {
+        let code = response.status();
+        let response_body = deadline
+            .run(async {
+                response.bytes().await.context(error::ResponseBody {
+                    method: "GET",
+                    uri,
+                    code,
+                })
+            })
+            .await?
+            .to_vec();
+        #[cfg(test)]
+        self.body_bytes_read
+            .fetch_add(response_body.len(), std::sync::atomic::Ordering::SeqCst);
+        Ok(response_body)
     }
-}
 
-/// Converts `bytes` to a `String` if it is a UTF-8 encoded string.
-/// Truncates the string if it is too long for printing.
-fn printable_string(bytes: &[u8]) -> String {
-    if let Ok(s) = String::from_utf8(bytes.into()) {
-        if s.len() < 2048 {
-            s
-        } else {
-            format!("{}<truncated...>", &s[0..2034])
+    /// Returns the error for an unexpected `response` to a request for `uri`, including its body.
+    async fn error_response<T>(
+        &self,
+        response: Response,
+        uri: &str,
+        deadline: Deadline,
+    ) -> Result<T> {
+        let code = response.status();
+        let response_body = self.read_body(response, uri, deadline).await?;
+
+        let response_str = printable_string(&response_body);
+
+        trace!("Response: {:?}", response_str);
+
+        error::Response {
+            method: "GET",
+            uri,
+            code,
+            response_body: response_str,
         }
-    } else {
-        "<binary>".to_string()
+        .fail()
     }
-}
 
-/// Returns a list of public keys available in IMDS. Since IMDS returns the list of keys as
-/// '0=my-public-key', we need to strip the index and insert it into the public key target.
-fn build_public_key_targets(public_key_list: &str) -> Vec<String> {
-    let mut public_key_targets = Vec::new();
-    for available_key in public_key_list.lines() {
-        let f: Vec<&str> = available_key.split('=').collect();
-        // If f[0] isn't a number, then it isn't a valid index.
-        if f[0].parse::<u32>().is_ok() {
-            let public_key_target = format!("meta-data/public-keys/{}/openssh-key", f[0]);
-            public_key_targets.push(public_key_target);
-        } else {
-            warn!(
-                "'{}' does not appear to be a valid index. Skipping...",
-                &f[0]
+    /// Returns the cached response for `target`, if caching is enabled and there is one.
+    fn cached_response(&self, schema_version: &str, target: &str) -> Option<CachedResponse> {
+        self.cache.as_ref().and_then(|cache| {
+            cache
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get(schema_version, target)
+                .cloned()
+        })
+    }
+
+    /// Caches `response` for `target` if caching is enabled.
+    fn cache_response(&self, schema_version: &str, target: &str, response: CachedResponse) {
+        if let Some(cache) = self.cache.as_ref() {
+            cache.lock().unwrap_or_else(PoisonError::into_inner).insert(
+                schema_version,
+                target,
+                response,
             );
-            continue;
         }
     }
-    if public_key_targets.is_empty() {
+
+    /// Returns the session, first connecting if there's none yet, or refreshing its token if it's
+    /// about to expire, so that requests don't have to be rejected with a 401 before it's
+    /// refreshed.
+    async fn session(&self, deadline: Deadline) -> Result<Session> {
+        let stale_token = match &*self.session.read().await {
+            Some(session) if !session.token.is_expiring() => return Ok(session.clone()),
+            Some(session) => {
+                debug!("Session token is about to expire");
+                Some(session.token.value.clone())
+            }
+            None => None,
+        };
+        deadline
+            .run(self.refresh_token(stale_token.as_deref()))
+            .await?;
+        match &*self.session.read().await {
+            Some(session) => Ok(session.clone()),
+            // a session is always set once the refresh succeeds.
+            None => error::NoEndpoint.fail(),
+        }
+    }
+
+    /// Returns the URI of `target` for messages about its cached response, without connecting.  A
+    /// response is only cached once there's a session, so its endpoint is normally the one in use.
+    async fn cached_uri(&self, schema_version: &str, target: &str) -> String {
+        let base_uri = match &*self.session.read().await {
+            Some(session) => session.base_uri.clone(),
+            None => self.base_uris.first().cloned().unwrap_or_default(),
+        };
+        format!("{}/{}/{}", base_uri, schema_version, target)
+    }
+
+    /// Returns the base URI of the endpoint in use, connecting first if needed.
+    async fn base_uri(&self, deadline: Deadline) -> Result<String> {
+        Ok(self.session(deadline).await?.base_uri)
+    }
+
+    /// Returns the session token to send, see `session`.
+    async fn current_token(&self, deadline: Deadline) -> Result<String> {
+        Ok(self.session(deadline).await?.token.value)
+    }
+
+    /// Fetches a new session token to replace `stale_token`, or connects if `stale_token` is `None`
+    /// and there's no session.  The lock is held while the token is fetched, so concurrent requests
+    /// don't each fetch one; if one of them already replaced the token, it's kept.
+    async fn refresh_token(&self, stale_token: Option<&str>) -> Result<()> {
+        let mut session = self.session.write().await;
+        match session.as_mut() {
+            Some(current)
+                if Some(current.token.value.as_str()) != stale_token
+                    && !current.token.is_expiring() => {}
+            Some(current) => {
+                current.token =
+                    fetch_token(&self.client, &current.base_uri, self.token_ttl).await?;
+                debug!("Refreshed session token");
+            }
+            None => *session = Some(self.connect_session().await?),
+        }
+        Ok(())
+    }
+
+    /// Fetches a session token from the first endpoint that can be connected to, and returns the
+    /// session.  The next endpoint is only tried if the connection to one fails; any other error is
+    /// returned.
+    async fn connect_session(&self) -> Result<Session> {
+        let last = self.base_uris.len().saturating_sub(1);
+        for (i, base_uri) in self.base_uris.iter().enumerate() {
+            match fetch_token(&self.client, base_uri, self.token_ttl).await {
+                Ok(token) => {
+                    info!("Using IMDS endpoint {}", base_uri);
+                    return Ok(Session {
+                        base_uri: base_uri.clone(),
+                        token,
+                    });
+                }
+                Err(e) if i < last && e.is_connect() => {
+                    warn!("Unable to connect to IMDS at {}: {}", base_uri, e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        error::NoEndpoint.fail()
+    }
+}
+
+/// Returns the newest of the `available` schema versions that is at or before `preferred`.  Only
+/// dated versions like `2021-01-03` are considered, because `latest` can change under us and the
+/// oldest versions like `1.0` lack most of the metadata we use.
+fn select_schema_version(available: &[String], preferred: &str) -> Option<String> {
+    available
+        .iter()
+        .filter(|version| is_dated_version(version))
+        // dates in this format sort the same as strings.
+        .filter(|version| version.as_str() <= preferred)
+        .max()
+        .cloned()
+}
+
+/// Returns true if `version` looks like a dated schema version, `YYYY-MM-DD`.
+fn is_dated_version(version: &str) -> bool {
+    version.len() == 10
+        && version.chars().enumerate().all(|(i, c)| match i {
+            4 | 7 => c == '-',
+            _ => c.is_ascii_digit(),
+        })
+}
+
+/// Returns a human description of `target` for log messages, made from the last two segments of its
+/// path with hyphens replaced by spaces, e.g. `instance identity document` for
+/// `dynamic/instance-identity/document`.
+fn describe_target(target: &str) -> String {
+    let segments: Vec<&str> = target
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    let start = segments.len().saturating_sub(2);
+    segments[start..].join(" ").replace('-', " ")
+}
+
+/// Returns whether `target` is one of `prefixes` or under one of them.  Targets with relative
+/// segments like `..` are never allowed, so they can't escape their prefix.
+fn is_target_allowed<S: AsRef<str>>(prefixes: &[S], target: &str) -> bool {
+    let target = target.trim_matches('/');
+    if target
+        .split('/')
+        .any(|segment| segment == "." || segment == "..")
+    {
+        return false;
+    }
+    prefixes.iter().any(|prefix| {
+        let prefix = prefix.as_ref();
+        target == prefix || (target.starts_with(prefix) && target[prefix.len()..].starts_with('/'))
+    })
+}
+
+/// Converts `bytes` to a `String` if it is a UTF-8 encoded string.
+/// Truncates the string if it is too long for printing.
+fn printable_string(bytes: &[u8]) -> String {
+    if let Ok(s) = String::from_utf8(bytes.into()) {
+        if s.len() < 2048 {
+            s
+        } else {
+            format!("{}<truncated...>", &s[0..2034])
+        }
+    } else {
+        "<binary>".to_string()
+    }
+}
+
+/// Returns a list of public keys available in IMDS. Since IMDS returns the list of keys as
+/// '0=my-public-key', we need to strip the index and insert it into the public key target.
+fn build_public_key_targets(public_key_list: &str) -> Vec<String> {
+    let mut public_key_targets = Vec::new();
+    for available_key in public_key_list.lines() {
+        let f: Vec<&str> = available_key.split('=').collect();
+        // If f[0] isn't a number, then it isn't a valid index.
+        if f[0].parse::<u32>().is_ok() {
+            let public_key_target = format!("meta-data/public-keys/{}/openssh-key", f[0]);
+            public_key_targets.push(public_key_target);
+        } else {
+            warn!(
+                "'{}' does not appear to be a valid index. Skipping...",
+                &f[0]
+            );
+            continue;
+        }
+    }
+    if public_key_targets.is_empty() {
         warn!("No valid key targets found");
     }
     public_key_targets
 }
 
-/// Helper to fetch an IMDSv2 session token that is valid for 60 seconds.
-async fn fetch_token(client: &Client, imds_base_uri: &str) -> Result<String> {
+/// Helper to fetch an IMDSv2 session token that is valid for `ttl`.
+async fn fetch_token(client: &Client, imds_base_uri: &str, ttl: Duration) -> Result<SessionToken> {
     let uri = format!("{}/{}", imds_base_uri, SESSION_TARGET);
+    // the token's age is counted from before the request, so it's never thought younger than it is.
+    let fetched = Instant::now();
     let response = client
         .put(&uri)
-        .header("X-aws-ec2-metadata-token-ttl-seconds", "60")
+        .header(
+            "X-aws-ec2-metadata-token-ttl-seconds",
+            ttl.as_secs().to_string(),
+        )
         .send()
         .await
         .context(error::Request {
@@ -324,10 +1277,15 @@ async fn fetch_token(client: &Client, imds_base_uri: &str) -> Result<String> {
         .error_for_status()
         .context(error::BadResponse { uri: &uri })?;
     let code = response.status();
-    response.text().await.context(error::ResponseBody {
+    let value = response.text().await.context(error::ResponseBody {
         method: "PUT",
         uri,
         code,
+    })?;
+    Ok(SessionToken {
+        value,
+        fetched,
+        ttl,
     })
 }
 
@@ -345,13 +1303,45 @@ mod error {
             .to_string()
     }
 
+    /// The errors returned by `ImdsClient`. New variants may be added, so downstream code that
+    /// needs to react to particular failures should match on `kind()` instead.
     #[derive(Debug, Snafu)]
     #[snafu(visibility = "pub(super)")]
-
+    #[non_exhaustive]
     pub enum Error {
         #[snafu(display("Response '{}' from '{}': {}", get_status_code(&source), uri, source))]
         BadResponse { uri: String, source: reqwest::Error },
 
+        #[snafu(display("IMDS requests took longer than the budget of {:?}", budget))]
+        BudgetExceeded { budget: std::time::Duration },
+
+        #[snafu(display("IMDS gave an empty response for '{}'", target))]
+        EmptyResponse { target: String },
+
+        #[snafu(display("IMDS is disabled or blocked at '{}': {}", uri, reason))]
+        ImdsDisabled { uri: String, reason: String },
+
+        #[snafu(display("Invalid hop limit '{}': {}", value, source))]
+        HopLimit {
+            value: String,
+            source: std::num::ParseIntError,
+        },
+
+        #[snafu(display("Invalid identity certificate: {}", reason))]
+        IdentityCertificate { reason: String },
+
+        #[snafu(display("Identity certificate is not valid base64: {}", source))]
+        IdentityCertificateBase64 { source: base64::DecodeError },
+
+        #[snafu(display("Identity document signature is not valid base64: {}", source))]
+        IdentitySignatureBase64 { source: base64::DecodeError },
+
+        #[snafu(display("Identity document signature does not match any trusted certificate"))]
+        IdentitySignatureInvalid,
+
+        #[snafu(display("Unable to read identity document signature: {}", reason))]
+        IdentitySignatureParse { reason: String },
+
         #[snafu(display("IMDS fetch failed after {} attempts", attempt))]
         FailedFetch { attempt: u8 },
 
@@ -361,6 +1351,22 @@ mod error {
         #[snafu(display("Response was not UTF-8: {}", source))]
         NonUtf8Response { source: std::string::FromUtf8Error },
 
+        #[snafu(display("No IMDS endpoint to connect to"))]
+        NoEndpoint,
+
+        #[snafu(display("No certificates to verify the identity document against"))]
+        NoIdentityCertificates,
+
+        #[snafu(display(
+            "IMDS offers no schema version at or before {}, only {:?}",
+            preferred,
+            available
+        ))]
+        NoSchemaVersion {
+            preferred: String,
+            available: Vec<String>,
+        },
+
         #[snafu(display("404 file not found fetching '{}'", uri))]
         NotFound { uri: String },
 
@@ -395,16 +1401,237 @@ mod error {
 
         #[snafu(display("Deserialization error: {}", source))]
         Serde { source: serde_json::Error },
+
+        #[snafu(display("IMDS throttled the request for '{}'", uri))]
+        Throttled { uri: String },
+
+        #[snafu(display("Target '{}' is not allowed for this client", target))]
+        TargetNotAllowed { target: String },
+
+        #[snafu(display(
+            "Session token TTL of {:?} must be whole seconds from 1 second to 6 hours",
+            ttl
+        ))]
+        TokenTtl { ttl: std::time::Duration },
+
+        #[snafu(display("Error {} from IMDS for '{}': {}", code, uri, response_body))]
+        TransientServer {
+            uri: String,
+            code: StatusCode,
+            response_body: String,
+        },
+    }
+
+    /// A coarse-grained classification of `Error` that downstream code can match on without
+    /// depending on the individual variants.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[non_exhaustive]
+    pub enum ErrorKind {
+        /// The target doesn't exist, e.g. there's no user data.
+        NotFound,
+        /// IMDS rejected the session token, or a token couldn't be obtained.
+        Unauthorized,
+        /// IMDS couldn't be reached, or the request didn't complete.
+        Transport,
+        /// The response couldn't be parsed.
+        Parse,
+        /// The client isn't allowed to fetch the target.
+        NotAllowed,
+        /// A signature didn't match the signed data or any trusted certificate.
+        InvalidSignature,
+        /// IMDS is disabled or blocked on this instance: it refused the connection, or forbade the
+        /// request, e.g. because the response to a token request took more hops than allowed.
+        Disabled,
+        /// IMDS throttled the request or had a server error, so it may succeed if tried later.
+        Transient,
+        /// Any other failure, e.g. an unexpected response code.
+        Other,
+    }
+
+    /// Classifies an HTTP status code that IMDS returned instead of a successful response.
+    fn status_kind(code: StatusCode, otherwise: ErrorKind) -> ErrorKind {
+        match code {
+            StatusCode::NOT_FOUND => ErrorKind::NotFound,
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ErrorKind::Unauthorized,
+            _ => otherwise,
+        }
+    }
+
+    impl Error {
+        /// Returns the kind of error.
+        pub fn kind(&self) -> ErrorKind {
+            match self {
+                Error::BadResponse { source, .. } => match source.status() {
+                    Some(code) => status_kind(code, ErrorKind::Transport),
+                    None => ErrorKind::Transport,
+                },
+                Error::BudgetExceeded { .. } => ErrorKind::Transport,
+                Error::EmptyResponse { .. } => ErrorKind::Parse,
+                Error::FailedFetch { .. } => ErrorKind::Transport,
+                Error::HopLimit { .. } => ErrorKind::Parse,
+                Error::ImdsDisabled { .. } => ErrorKind::Disabled,
+                Error::IdentityCertificate { .. } => ErrorKind::Parse,
+                Error::IdentityCertificateBase64 { .. } => ErrorKind::Parse,
+                Error::IdentitySignatureBase64 { .. } => ErrorKind::Parse,
+                Error::IdentitySignatureInvalid => ErrorKind::InvalidSignature,
+                Error::IdentitySignatureParse { .. } => ErrorKind::Parse,
+                Error::FailedSession { .. } => ErrorKind::Unauthorized,
+                Error::NonUtf8Response { .. } => ErrorKind::Parse,
+                Error::NoEndpoint => ErrorKind::Transport,
+                Error::NoIdentityCertificates => ErrorKind::Other,
+                Error::NoSchemaVersion { .. } => ErrorKind::Other,
+                Error::NotFound { .. } => ErrorKind::NotFound,
+                Error::Request { .. } => ErrorKind::Transport,
+                Error::Response { code, .. } => status_kind(*code, ErrorKind::Other),
+                Error::ResponseBody { .. } => ErrorKind::Transport,
+                Error::Serde { .. } => ErrorKind::Parse,
+                Error::TargetNotAllowed { .. } => ErrorKind::NotAllowed,
+                Error::Throttled { .. } => ErrorKind::Transient,
+                Error::TokenTtl { .. } => ErrorKind::Other,
+                Error::TransientServer { .. } => ErrorKind::Transient,
+            }
+        }
+
+        /// Returns the error as one of the variants that tell callers how to react, if it's one of
+        /// the failures they cover: a refused connection or a 403 becomes `ImdsDisabled`, a 429
+        /// `Throttled`, and a 5xx `TransientServer`.  Any other error is returned as it is.
+        pub(crate) fn classified(self) -> Self {
+            if let Error::Request { uri, source, .. } = &self {
+                if source.is_connect() {
+                    return Error::ImdsDisabled {
+                        uri: uri.clone(),
+                        reason: source.to_string(),
+                    };
+                }
+            }
+            let status = match &self {
+                Error::BadResponse { uri, source } => source
+                    .status()
+                    .map(|code| (uri.clone(), code, String::new())),
+                Error::Response {
+                    uri,
+                    code,
+                    response_body,
+                    ..
+                } => Some((uri.clone(), *code, response_body.clone())),
+                _ => None,
+            };
+            let (uri, code, response_body) = match status {
+                Some(status) => status,
+                None => return self,
+            };
+            match code {
+                StatusCode::FORBIDDEN => Error::ImdsDisabled {
+                    uri,
+                    reason: code.to_string(),
+                },
+                StatusCode::TOO_MANY_REQUESTS => Error::Throttled { uri },
+                code if code.is_server_error() => Error::TransientServer {
+                    uri,
+                    code,
+                    response_body,
+                },
+                _ => self,
+            }
+        }
+
+        /// Returns true if the error is a failure to connect to IMDS, rather than a failure of a
+        /// request that reached it.
+        pub(crate) fn is_connect(&self) -> bool {
+            matches!(self, Error::Request { source, .. } if source.is_connect())
+        }
     }
 }
 
-pub use error::Error;
+pub use error::{Error, ErrorKind};
+pub use identity::{IdentityCertificates, Partition};
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[cfg(test)]
 mod test {
     use super::*;
     use httptest::{matchers::*, responders::*, Expectation, Server};
+    use std::sync::atomic::Ordering;
+    use tempfile::TempDir;
+
+    /// Returns the base URI of a port that nothing listens on, so connections to it are refused.
+    fn refusing_base_uri() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        format!("http://localhost:{}", port)
+    }
+
+    #[tokio::test]
+    async fn endpoint_fallback() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("PUT", "/latest/api/token"))
+                .times(1)
+                .respond_with(status_code(200).body("some+token")),
+        );
+        let fallback_uri = format!("http://localhost:{}", server.addr().port());
+        let imds_client =
+            ImdsClient::new_with_fallback(vec![refusing_base_uri(), fallback_uri.clone()]);
+        imds_client.connect().await.unwrap();
+        let session = imds_client.session.read().await;
+        assert_eq!(session.as_ref().unwrap().base_uri, fallback_uri);
+    }
+
+    #[tokio::test]
+    async fn endpoint_first_preferred() {
+        let first = Server::run();
+        first.expect(
+            Expectation::matching(request::method_path("PUT", "/latest/api/token"))
+                .times(1)
+                .respond_with(status_code(200).body("some+token")),
+        );
+        // the second server expects no requests, so the test fails if it's asked for a token.
+        let second = Server::run();
+        let first_uri = format!("http://localhost:{}", first.addr().port());
+        let second_uri = format!("http://localhost:{}", second.addr().port());
+        let imds_client = ImdsClient::new_with_fallback(vec![first_uri.clone(), second_uri]);
+        imds_client.connect().await.unwrap();
+        let session = imds_client.session.read().await;
+        assert_eq!(session.as_ref().unwrap().base_uri, first_uri);
+    }
+
+    #[tokio::test]
+    async fn endpoint_no_fallback_on_http_error() {
+        // IMDS answered, so the other endpoint isn't tried.
+        let first = Server::run();
+        first.expect(
+            Expectation::matching(request::method_path("PUT", "/latest/api/token"))
+                .times(1)
+                .respond_with(status_code(403)),
+        );
+        let second = Server::run();
+        let first_uri = format!("http://localhost:{}", first.addr().port());
+        let second_uri = format!("http://localhost:{}", second.addr().port());
+        let error = ImdsClient::new_with_fallback(vec![first_uri, second_uri])
+            .connect()
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), ErrorKind::Unauthorized);
+    }
+
+    #[tokio::test]
+    async fn endpoint_none_reachable() {
+        let error = ImdsClient::new_with_fallback(vec![refusing_base_uri(), refusing_base_uri()])
+            .connect()
+            .await
+            .err()
+            .unwrap();
+        assert!(error.is_connect());
+        assert_eq!(error.kind(), ErrorKind::Transport);
+    }
+
+    #[test]
+    fn endpoint_base_uris() {
+        assert_eq!(ImdsEndpoint::IpV4.base_uri(), "http://169.254.169.254");
+        assert_eq!(ImdsEndpoint::IpV6.base_uri(), "http://[fd00:ec2::254]");
+    }
 
     #[tokio::test]
     async fn new_imds_client() {
@@ -421,8 +1648,115 @@ mod test {
                         .body(token),
                 ),
         );
-        let imds_client = ImdsClient::new_impl(base_uri).await.unwrap();
-        assert_eq!(imds_client.session_token, token);
+        let imds_client = ImdsClient::new_impl(base_uri);
+        imds_client.connect().await.unwrap();
+        let session = imds_client.session.read().await;
+        assert_eq!(session.as_ref().unwrap().token.value, token);
+    }
+
+    #[tokio::test]
+    async fn token_fetched_lazily() {
+        // the server expects no requests, so the test fails if creating the client sends one.
+        let server = Server::run();
+        let imds_client =
+            ImdsClient::new_impl(format!("http://localhost:{}", server.addr().port()));
+        assert!(imds_client.session.read().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn token_fetched_once_for_concurrent_requests() {
+        // the mock server hands out a token only once, though all of the requests start without
+        // one.
+        let (server, imds_client) = mock_imds("some+token").await;
+        server.expect(
+            Expectation::matching(all_of![
+                request::method("GET"),
+                request::headers(contains(("x-aws-ec2-metadata-token", "some+token"))),
+            ])
+            .times(4)
+            .respond_with(status_code(200).body("value")),
+        );
+        let responses = imds_client
+            .fetch_many(&[
+                ("meta-data/ami-id", "AMI ID"),
+                ("meta-data/instance-id", "instance ID"),
+                ("meta-data/instance-type", "instance type"),
+                ("meta-data/local-ipv4", "local IPv4 address"),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(responses.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn token_refreshed_before_expiry() {
+        let (mut server, imds_client) = mock_imds("old+token").await;
+        let mut imds_client = imds_client
+            .with_token_ttl(Duration::from_secs(120))
+            .unwrap();
+        imds_client.connect().await.unwrap();
+        // age the token past its 120 second lifetime.
+        {
+            let mut session = imds_client.session.write().await;
+            session.as_mut().unwrap().token.fetched = Instant::now()
+                .checked_sub(Duration::from_secs(121))
+                .unwrap();
+        }
+        server.verify_and_clear();
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("PUT", "/latest/api/token"),
+                request::headers(contains(("x-aws-ec2-metadata-token-ttl-seconds", "120"))),
+            ])
+            .times(1)
+            .respond_with(status_code(200).body("new+token")),
+        );
+        // only a request with the new token is expected.
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", format!("/{}/meta-data/instance-type", PINNED_SCHEMA)),
+                request::headers(contains(("x-aws-ec2-metadata-token", "new+token"))),
+            ])
+            .times(1)
+            .respond_with(status_code(200).body("m5.large")),
+        );
+        let instance_type = imds_client.fetch_metadata("instance-type").await.unwrap();
+        assert_eq!(instance_type, b"m5.large");
+        let session = imds_client.session.read().await;
+        let session_token = &session.as_ref().unwrap().token;
+        assert_eq!(session_token.value, "new+token");
+        assert_eq!(session_token.ttl, Duration::from_secs(120));
+    }
+
+    #[tokio::test]
+    async fn fresh_token_not_refreshed() {
+        // the mock server hands out a token only once.
+        let (server, mut imds_client) = mock_imds("some+token").await;
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", format!("/{}/meta-data/instance-type", PINNED_SCHEMA)),
+                request::headers(contains(("x-aws-ec2-metadata-token", "some+token"))),
+            ])
+            .times(2)
+            .respond_with(status_code(200).body("m5.large")),
+        );
+        for _ in 0..2 {
+            imds_client.fetch_metadata("instance-type").await.unwrap();
+        }
+    }
+
+    #[test]
+    fn token_ttl_out_of_range() {
+        for ttl in &[
+            Duration::from_secs(0),
+            Duration::from_millis(1500),
+            MAX_TOKEN_TTL + Duration::from_secs(1),
+        ] {
+            let imds_client = ImdsClient::new();
+            let error = imds_client.with_token_ttl(*ttl).err().unwrap();
+            assert!(matches!(error, Error::TokenTtl { .. }));
+        }
+        assert!(ImdsClient::new().with_token_ttl(MAX_TOKEN_TTL).is_ok());
     }
 
     #[tokio::test]
@@ -456,9 +1790,9 @@ mod test {
                     .body(response_body),
             ),
         );
-        let mut imds_client = ImdsClient::new_impl(base_uri).await.unwrap();
+        let imds_client = ImdsClient::new_impl(base_uri);
         let imds_data = imds_client
-            .fetch_imds(schema_version, target)
+            .fetch_imds(schema_version, target, imds_client.start_deadline())
             .await
             .unwrap();
         assert_eq!(imds_data, response_body.as_bytes().to_vec());
@@ -492,8 +1826,10 @@ mod test {
                 status_code(response_code).append_header("X-aws-ec2-metadata-token", token),
             ),
         );
-        let mut imds_client = ImdsClient::new_impl(base_uri).await.unwrap();
-        let result = imds_client.fetch_imds(schema_version, target).await;
+        let imds_client = ImdsClient::new_impl(base_uri);
+        let result = imds_client
+            .fetch_imds(schema_version, target, imds_client.start_deadline())
+            .await;
         assert!(matches!(result, Err(error::Error::NotFound { .. })));
     }
 
@@ -525,9 +1861,9 @@ mod test {
                 status_code(response_code).append_header("X-aws-ec2-metadata-token", token),
             ),
         );
-        let mut imds_client = ImdsClient::new_impl(base_uri).await.unwrap();
+        let imds_client = ImdsClient::new_impl(base_uri);
         assert!(imds_client
-            .fetch_imds(schema_version, target)
+            .fetch_imds(schema_version, target, imds_client.start_deadline())
             .await
             .is_err());
     }
@@ -560,9 +1896,9 @@ mod test {
                 status_code(response_code).append_header("X-aws-ec2-metadata-token", token),
             ),
         );
-        let mut imds_client = ImdsClient::new_impl(base_uri).await.unwrap();
+        let imds_client = ImdsClient::new_impl(base_uri);
         assert!(imds_client
-            .fetch_imds(schema_version, target)
+            .fetch_imds(schema_version, target, imds_client.start_deadline())
             .await
             .is_err());
     }
@@ -597,11 +1933,126 @@ mod test {
                     .body(response_body),
             ),
         );
-        let mut imds_client = ImdsClient::new_impl(base_uri).await.unwrap();
-        let imds_data = imds_client.fetch_string(end_target).await.unwrap();
+        let imds_client = ImdsClient::new_impl(base_uri);
+        let imds_data = imds_client
+            .fetch_string(end_target, imds_client.start_deadline())
+            .await
+            .unwrap();
         assert_eq!(imds_data, response_body.to_string());
     }
 
+    /// Expects one session token request, and a request for each of `targets`, with its status
+    /// and body.
+    fn expect_targets(server: &Server, targets: &[(&str, u16, &'static str)]) {
+        server.expect(
+            Expectation::matching(request::method_path("PUT", "/latest/api/token"))
+                .times(1)
+                .respond_with(status_code(200).body("some+token")),
+        );
+        for (target, status, body) in targets {
+            server.expect(
+                Expectation::matching(request::method_path(
+                    "GET",
+                    format!("/{}/{}", PINNED_SCHEMA, target),
+                ))
+                .times(1)
+                .respond_with(status_code(*status).body(*body)),
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn fetch_ipv6_addresses() {
+        let server = Server::run();
+        expect_targets(
+            &server,
+            &[(
+                "meta-data/network/interfaces/macs/0e:aa:bb:cc:dd:ee/ipv6s",
+                200,
+                "2600:1f14::1\n2600:1f14::2",
+            )],
+        );
+        let mut imds_client =
+            ImdsClient::new_impl(format!("http://localhost:{}", server.addr().port()));
+        let addresses = imds_client
+            .fetch_ipv6_addresses_for_mac("0e:aa:bb:cc:dd:ee")
+            .await
+            .unwrap();
+        assert_eq!(addresses, vec!["2600:1f14::1", "2600:1f14::2"]);
+    }
+
+    #[tokio::test]
+    async fn fetch_ipv6_addresses_none() {
+        let server = Server::run();
+        expect_targets(
+            &server,
+            &[(
+                "meta-data/network/interfaces/macs/0e:aa:bb:cc:dd:ee/ipv6s",
+                404,
+                "",
+            )],
+        );
+        let mut imds_client =
+            ImdsClient::new_impl(format!("http://localhost:{}", server.addr().port()));
+        let addresses = imds_client
+            .fetch_ipv6_addresses_for_mac("0e:aa:bb:cc:dd:ee")
+            .await
+            .unwrap();
+        assert!(addresses.is_empty());
+    }
+
+    #[tokio::test]
+    async fn fetch_primary_ipv6_address() {
+        let server = Server::run();
+        expect_targets(
+            &server,
+            &[
+                (
+                    "meta-data/network/interfaces/macs",
+                    200,
+                    "0e:aa:bb:cc:dd:ee\n0e:ff:ff:ff:ff:ff",
+                ),
+                (
+                    "meta-data/network/interfaces/macs/0e:aa:bb:cc:dd:ee/ipv6s",
+                    200,
+                    "2600:1f14::1\n2600:1f14::2",
+                ),
+            ],
+        );
+        let mut imds_client =
+            ImdsClient::new_impl(format!("http://localhost:{}", server.addr().port()));
+        assert_eq!(
+            imds_client.fetch_primary_ipv6_address().await.unwrap(),
+            Some("2600:1f14::1".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn fetch_primary_ipv6_address_none() {
+        let server = Server::run();
+        expect_targets(
+            &server,
+            &[
+                (
+                    "meta-data/network/interfaces/macs",
+                    200,
+                    "0e:aa:bb:cc:dd:ee",
+                ),
+                (
+                    "meta-data/network/interfaces/macs/0e:aa:bb:cc:dd:ee/ipv6s",
+                    404,
+                    "",
+                ),
+            ],
+        );
+        let mut imds_client =
+            ImdsClient::new_impl(format!("http://localhost:{}", server.addr().port()));
+        assert_eq!(
+            imds_client.fetch_primary_ipv6_address().await.unwrap(),
+            None
+        );
+    }
+
     #[tokio::test]
     async fn fetch_bytes() {
         let server = Server::run();
@@ -632,19 +2083,39 @@ mod test {
                     .body(response_body),
             ),
         );
-        let mut imds_client = ImdsClient::new_impl(base_uri).await.unwrap();
-        let imds_data = imds_client.fetch_bytes(end_target).await.unwrap();
+        let imds_client = ImdsClient::new_impl(base_uri);
+        let imds_data = imds_client
+            .fetch_bytes(end_target, imds_client.start_deadline())
+            .await
+            .unwrap();
         assert_eq!(imds_data, response_body.as_bytes().to_vec());
     }
 
+    #[test]
+    fn describe_targets() {
+        assert_eq!(
+            describe_target("dynamic/instance-identity/document"),
+            "instance identity document"
+        );
+        assert_eq!(
+            describe_target("meta-data/network/interfaces/macs"),
+            "interfaces macs"
+        );
+        assert_eq!(
+            describe_target("meta-data/public-keys/0/openssh-key/"),
+            "0 openssh key"
+        );
+        assert_eq!(describe_target("user-data"), "user data");
+        assert_eq!(describe_target(""), "");
+    }
+
     #[tokio::test]
-    async fn fetch_userdata() {
+    async fn fetch_metadata() {
         let server = Server::run();
         let port = server.addr().port();
         let base_uri = format!("http://localhost:{}", port);
         let token = "some+token";
-        let response_code = 200;
-        let response_body = r#"settings.motd = "Welcome to Bottlerocket!""#;
+        let response_body = "m5.large";
         server.expect(
             Expectation::matching(request::method_path("PUT", "/latest/api/token"))
                 .times(1)
@@ -657,28 +2128,701 @@ mod test {
         server.expect(
             Expectation::matching(request::method_path(
                 "GET",
-                format!("/{}/user-data", PINNED_SCHEMA),
+                format!("/{}/meta-data/instance-type", PINNED_SCHEMA),
             ))
-            .times(1)
-            .respond_with(
-                status_code(response_code)
-                    .append_header("X-aws-ec2-metadata-token", token)
-                    .body(response_body),
-            ),
+            .times(2)
+            .respond_with(status_code(200).body(response_body)),
         );
-        let mut imds_client = ImdsClient::new_impl(base_uri).await.unwrap();
-        let imds_data = imds_client.fetch_userdata().await.unwrap();
+        let mut imds_client = ImdsClient::new_impl(base_uri);
+        let imds_data = imds_client.fetch_metadata("instance-type").await.unwrap();
+        assert_eq!(imds_data, response_body.as_bytes().to_vec());
+        let imds_data = imds_client
+            .fetch_metadata_with_description("instance-type", "the instance type")
+            .await
+            .unwrap();
         assert_eq!(imds_data, response_body.as_bytes().to_vec());
     }
 
-    #[test]
-    fn printable_string_short() {
-        let input = "Hello".as_bytes();
-        let expected = "Hello".to_string();
-        let actual = printable_string(input);
-        assert_eq!(expected, actual);
-    }
-
+    #[tokio::test]
+    async fn fetch_placement() {
+        let (server, mut imds_client) = mock_imds("some+token").await;
+        for (target, body) in &[
+            ("availability-zone", "us-west-2a"),
+            ("availability-zone-id", "usw2-az1\n"),
+        ] {
+            server.expect(
+                Expectation::matching(request::method_path(
+                    "GET",
+                    format!("/{}/meta-data/placement/{}", PINNED_SCHEMA, target),
+                ))
+                .times(1)
+                .respond_with(status_code(200).body(*body)),
+            );
+        }
+        assert_eq!(
+            imds_client.fetch_availability_zone().await.unwrap(),
+            "us-west-2a"
+        );
+        assert_eq!(imds_client.fetch_zone_id().await.unwrap(), "usw2-az1");
+    }
+
+    #[tokio::test]
+    async fn fetch_instance_id() {
+        let (server, mut imds_client) = mock_imds("some+token").await;
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                format!("/{}/meta-data/instance-id", PINNED_SCHEMA),
+            ))
+            .times(1)
+            .respond_with(status_code(200).body("i-0123456789abcdef0\n")),
+        );
+        assert_eq!(
+            imds_client.fetch_instance_id().await.unwrap(),
+            "i-0123456789abcdef0"
+        );
+    }
+
+    #[tokio::test]
+    async fn fetch_placement_empty() {
+        let (server, mut imds_client) = mock_imds("some+token").await;
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                format!("/{}/meta-data/placement/availability-zone", PINNED_SCHEMA),
+            ))
+            .times(1)
+            .respond_with(status_code(200).body("")),
+        );
+        let error = imds_client.fetch_availability_zone().await.unwrap_err();
+        assert!(matches!(error, error::Error::EmptyResponse { .. }));
+        assert_eq!(error.kind(), ErrorKind::Parse);
+    }
+
+    #[tokio::test]
+    async fn fetch_placement_notfound() {
+        let (server, mut imds_client) = mock_imds("some+token").await;
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                format!(
+                    "/{}/meta-data/placement/availability-zone-id",
+                    PINNED_SCHEMA
+                ),
+            ))
+            .times(1)
+            .respond_with(status_code(404)),
+        );
+        let error = imds_client.fetch_zone_id().await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+    }
+
+    // Returns a client for a mock IMDS that serves each metadata option in `options`, given as its
+    // name and response, and returns 404 for the others.
+    async fn mock_metadata_options(options: &[(&str, &'static str)]) -> (Server, ImdsClient) {
+        let (server, imds_client) = mock_imds("some+token").await;
+        for name in &[
+            "http-tokens",
+            "http-put-response-hop-limit",
+            "http-endpoint",
+        ] {
+            let path = format!("/{}/meta-data/metadata-options/{}", PINNED_SCHEMA, name);
+            let response = match options.iter().find(|(option, _)| option == name) {
+                Some((_, body)) => status_code(200).body(*body),
+                None => status_code(404),
+            };
+            server.expect(
+                Expectation::matching(request::method_path("GET", path))
+                    .times(1)
+                    .respond_with(response),
+            );
+        }
+        (server, imds_client)
+    }
+
+    #[tokio::test]
+    async fn fetch_metadata_options_enforced() {
+        let (_server, mut imds_client) = mock_metadata_options(&[
+            ("http-tokens", "required"),
+            ("http-put-response-hop-limit", "1"),
+            ("http-endpoint", "enabled"),
+        ])
+        .await;
+        let options = imds_client.fetch_metadata_options().await.unwrap();
+        assert_eq!(
+            options,
+            MetadataOptions {
+                http_tokens: Some("required".to_string()),
+                http_put_response_hop_limit: Some(1),
+                http_endpoint: Some("enabled".to_string()),
+            }
+        );
+        assert!(options.tokens_required());
+    }
+
+    #[tokio::test]
+    async fn fetch_metadata_options_optional() {
+        let (_server, mut imds_client) = mock_metadata_options(&[
+            ("http-tokens", "optional\n"),
+            ("http-put-response-hop-limit", "2"),
+            ("http-endpoint", "enabled"),
+        ])
+        .await;
+        let options = imds_client.fetch_metadata_options().await.unwrap();
+        assert_eq!(options.http_tokens.as_deref(), Some("optional"));
+        assert_eq!(options.http_put_response_hop_limit, Some(2));
+        assert!(!options.tokens_required());
+    }
+
+    #[tokio::test]
+    async fn fetch_metadata_options_missing() {
+        let (_server, mut imds_client) = mock_metadata_options(&[]).await;
+        let options = imds_client.fetch_metadata_options().await.unwrap();
+        assert_eq!(options, MetadataOptions::default());
+        assert!(!options.tokens_required());
+    }
+
+    #[tokio::test]
+    async fn fetch_metadata_options_bad_hop_limit() {
+        let (_server, mut imds_client) =
+            mock_metadata_options(&[("http-put-response-hop-limit", "many")]).await;
+        let error = imds_client.fetch_metadata_options().await.unwrap_err();
+        assert!(matches!(error, error::Error::HopLimit { .. }));
+        assert_eq!(error.kind(), ErrorKind::Parse);
+    }
+
+    // Returns a client for a mock IMDS that serves `signature` as the signed identity document,
+    // and the certificates of the test signer.
+    async fn mock_signed_identity(signature: &'static str) -> (Server, ImdsClient) {
+        let (server, imds_client) = mock_imds("some+token").await;
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                format!("/{}/dynamic/instance-identity/rsa2048", PINNED_SCHEMA),
+            ))
+            .times(1)
+            .respond_with(status_code(200).body(signature)),
+        );
+        (server, imds_client)
+    }
+
+    fn test_signer() -> IdentityCertificates {
+        IdentityCertificates::empty()
+            .with_pem(
+                Partition::Aws,
+                include_str!("../tests/data/identity-signer.pem"),
+            )
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn fetch_verified_identity_document() {
+        let (_server, mut imds_client) =
+            mock_signed_identity(include_str!("../tests/data/identity-rsa2048")).await;
+        let document = imds_client
+            .fetch_identity_document_verified_by(&test_signer())
+            .await
+            .unwrap();
+        assert_eq!(document.region(), "us-west-2");
+        assert_eq!(document.instance_type(), "m5.large");
+    }
+
+    #[tokio::test]
+    async fn fetch_verified_identity_document_other_signer() {
+        let (_server, mut imds_client) =
+            mock_signed_identity(include_str!("../tests/data/identity-rsa2048")).await;
+        let certificates = IdentityCertificates::empty()
+            .with_pem(
+                Partition::Aws,
+                include_str!("../tests/data/other-signer.pem"),
+            )
+            .unwrap();
+        let error = imds_client
+            .fetch_identity_document_verified_by(&certificates)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidSignature);
+    }
+
+    #[tokio::test]
+    async fn fetch_verified_identity_document_wrong_partition() {
+        // the document is for a region in aws-cn, but the signer is only trusted for aws.
+        let (_server, mut imds_client) =
+            mock_signed_identity(include_str!("../tests/data/identity-rsa2048-cn-north-1")).await;
+        let error = imds_client
+            .fetch_identity_document_verified_by(&test_signer())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, error::Error::IdentitySignatureInvalid));
+        assert_eq!(error.kind(), ErrorKind::InvalidSignature);
+    }
+
+    // Starts a mock IMDS server that hands out `token` and returns a client for it, which fetches
+    // the token with its first request.
+    async fn mock_imds(token: &str) -> (Server, ImdsClient) {
+        let server = Server::run();
+        let base_uri = format!("http://localhost:{}", server.addr().port());
+        server.expect(
+            Expectation::matching(request::method_path("PUT", "/latest/api/token"))
+                .times(1)
+                .respond_with(status_code(200).body(token.to_string())),
+        );
+        let imds_client = ImdsClient::new_impl(base_uri);
+        (server, imds_client)
+    }
+
+    #[tokio::test]
+    async fn fetch_many() {
+        let (server, imds_client) = mock_imds("some+token").await;
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                format!("/{}/meta-data/instance-type", PINNED_SCHEMA),
+            ))
+            .times(1)
+            .respond_with(status_code(200).body("m5.large")),
+        );
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                format!("/{}/meta-data/local-ipv4", PINNED_SCHEMA),
+            ))
+            .times(1)
+            .respond_with(status_code(200).body("192.168.1.10")),
+        );
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                format!("/{}/meta-data/public-keys", PINNED_SCHEMA),
+            ))
+            .times(1)
+            .respond_with(status_code(404)),
+        );
+        let responses = imds_client
+            .fetch_many(&[
+                ("meta-data/instance-type", "instance type"),
+                ("meta-data/local-ipv4", "local IPv4 address"),
+                ("meta-data/public-keys", "public keys"),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(responses.len(), 3);
+        assert_eq!(
+            responses["meta-data/instance-type"].as_deref(),
+            Some("m5.large")
+        );
+        assert_eq!(
+            responses["meta-data/local-ipv4"].as_deref(),
+            Some("192.168.1.10")
+        );
+        assert_eq!(responses["meta-data/public-keys"], None);
+    }
+
+    #[tokio::test]
+    async fn fetch_many_error() {
+        let (server, imds_client) = mock_imds("some+token").await;
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                format!("/{}/meta-data/instance-type", PINNED_SCHEMA),
+            ))
+            .times(..=1)
+            .respond_with(status_code(200).body("m5.large")),
+        );
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                format!("/{}/meta-data/local-ipv4", PINNED_SCHEMA),
+            ))
+            .times(1)
+            .respond_with(status_code(500)),
+        );
+        let result = imds_client
+            .fetch_many(&[
+                ("meta-data/instance-type", "instance type"),
+                ("meta-data/local-ipv4", "local IPv4 address"),
+            ])
+            .await;
+        assert!(matches!(result, Err(error::Error::TransientServer { .. })));
+    }
+
+    #[tokio::test]
+    async fn fetch_imds_classifies_responses() {
+        // the status IMDS responds with, whether the error is the expected one, and its kind.
+        type Case = (u16, fn(&error::Error) -> bool, ErrorKind);
+        let cases: &[Case] = &[
+            (
+                403,
+                |e| matches!(e, error::Error::ImdsDisabled { .. }),
+                ErrorKind::Disabled,
+            ),
+            (
+                429,
+                |e| matches!(e, error::Error::Throttled { .. }),
+                ErrorKind::Transient,
+            ),
+            (
+                500,
+                |e| matches!(e, error::Error::TransientServer { .. }),
+                ErrorKind::Transient,
+            ),
+            (
+                503,
+                |e| matches!(e, error::Error::TransientServer { .. }),
+                ErrorKind::Transient,
+            ),
+            // other codes aren't classified.
+            (
+                400,
+                |e| matches!(e, error::Error::Response { .. }),
+                ErrorKind::Other,
+            ),
+        ];
+        for (code, is_variant, kind) in cases {
+            let (server, mut imds_client) = mock_imds("some+token").await;
+            server.expect(
+                Expectation::matching(request::method_path(
+                    "GET",
+                    format!("/{}/meta-data/instance-type", PINNED_SCHEMA),
+                ))
+                .times(1)
+                .respond_with(status_code(*code)),
+            );
+            let error = imds_client
+                .fetch_metadata("instance-type")
+                .await
+                .unwrap_err();
+            assert!(is_variant(&error), "{}: {}", code, error);
+            assert_eq!(error.kind(), *kind, "{}: {}", code, error);
+        }
+    }
+
+    #[tokio::test]
+    async fn fetch_imds_connection_refused_is_disabled() {
+        let mut imds_client = ImdsClient::new_impl(refusing_base_uri());
+        let error = imds_client
+            .fetch_metadata("instance-type")
+            .await
+            .unwrap_err();
+        assert!(matches!(error, error::Error::ImdsDisabled { .. }));
+        assert_eq!(error.kind(), ErrorKind::Disabled);
+    }
+
+    #[tokio::test]
+    async fn fetch_imds_token_forbidden_is_disabled() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("PUT", "/latest/api/token"))
+                .times(1)
+                .respond_with(status_code(403)),
+        );
+        let mut imds_client =
+            ImdsClient::new_impl(format!("http://localhost:{}", server.addr().port()));
+        let error = imds_client
+            .fetch_metadata("instance-type")
+            .await
+            .unwrap_err();
+        assert!(matches!(error, error::Error::ImdsDisabled { .. }));
+        assert_eq!(error.kind(), ErrorKind::Disabled);
+    }
+
+    #[tokio::test]
+    async fn fetch_many_concurrency() {
+        let (server, imds_client) = mock_imds("some+token").await;
+        let delay = Duration::from_millis(250);
+        let targets: Vec<String> = (0..8)
+            .map(|i| format!("meta-data/public-keys/{}/openssh-key", i))
+            .collect();
+        for target in &targets {
+            server.expect(
+                Expectation::matching(request::method_path(
+                    "GET",
+                    format!("/{}/{}", PINNED_SCHEMA, target),
+                ))
+                .times(1)
+                .respond_with(delay_and_then(delay, status_code(200).body("ssh-rsa"))),
+            );
+        }
+        let targets: Vec<(&str, &str)> = targets
+            .iter()
+            .map(|target| (target.as_str(), "public key"))
+            .collect();
+
+        let start = std::time::Instant::now();
+        let responses = imds_client.fetch_many(&targets).await.unwrap();
+        let elapsed = start.elapsed();
+
+        assert_eq!(responses.len(), 8);
+        assert!(responses
+            .values()
+            .all(|response| response.as_deref() == Some("ssh-rsa")));
+        // eight requests, four at a time, take at least two rounds of the delay, and well under
+        // the eight rounds they'd take one at a time.
+        assert!(elapsed >= delay * 2, "finished too quickly: {:?}", elapsed);
+        assert!(elapsed < delay * 6, "finished too slowly: {:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn fetch_userdata() {
+        let server = Server::run();
+        let port = server.addr().port();
+        let base_uri = format!("http://localhost:{}", port);
+        let token = "some+token";
+        let response_code = 200;
+        let response_body = r#"settings.motd = "Welcome to Bottlerocket!""#;
+        server.expect(
+            Expectation::matching(request::method_path("PUT", "/latest/api/token"))
+                .times(1)
+                .respond_with(
+                    status_code(200)
+                        .append_header("X-aws-ec2-metadata-token-ttl-seconds", "60")
+                        .body(token),
+                ),
+        );
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                format!("/{}/user-data", PINNED_SCHEMA),
+            ))
+            .times(1)
+            .respond_with(
+                status_code(response_code)
+                    .append_header("X-aws-ec2-metadata-token", token)
+                    .body(response_body),
+            ),
+        );
+        let mut imds_client = ImdsClient::new_impl(base_uri);
+        let imds_data = imds_client.fetch_userdata().await.unwrap();
+        assert_eq!(imds_data, response_body.as_bytes().to_vec());
+    }
+
+    #[tokio::test]
+    async fn cache_policies() {
+        let server = Server::run();
+        let port = server.addr().port();
+        let base_uri = format!("http://localhost:{}", port);
+        let token = "some+token";
+        let public_key = "ssh-rsa AAAAB3NzaC1yc2E my-key";
+        server.expect(
+            Expectation::matching(request::method_path("PUT", "/latest/api/token"))
+                .times(1)
+                .respond_with(
+                    status_code(200)
+                        .append_header("X-aws-ec2-metadata-token-ttl-seconds", "60")
+                        .body(token),
+                ),
+        );
+        // instance-type is immutable, so it's only requested once.
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                format!("/{}/meta-data/instance-type", PINNED_SCHEMA),
+            ))
+            .times(1)
+            .respond_with(status_code(200).body("m5.large")),
+        );
+        // the public key list isn't available at first, but appears later.
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                format!("/{}/meta-data/public-keys", PINNED_SCHEMA),
+            ))
+            .times(2)
+            .respond_with(httptest::cycle![
+                status_code(404),
+                status_code(200).body("0=my-key")
+            ]),
+        );
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                format!("/{}/meta-data/public-keys/0/openssh-key", PINNED_SCHEMA),
+            ))
+            .times(1)
+            .respond_with(status_code(200).body(public_key)),
+        );
+        let mut imds_client = ImdsClient::new_impl(base_uri).with_cache();
+
+        assert!(imds_client
+            .fetch_public_ssh_keys()
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            imds_client
+                .fetch_string("meta-data/instance-type", imds_client.start_deadline())
+                .await
+                .unwrap(),
+            "m5.large"
+        );
+
+        assert_eq!(
+            imds_client.fetch_public_ssh_keys().await.unwrap(),
+            vec![public_key.to_string()]
+        );
+        assert_eq!(
+            imds_client
+                .fetch_string("meta-data/instance-type", imds_client.start_deadline())
+                .await
+                .unwrap(),
+            "m5.large"
+        );
+    }
+
+    #[tokio::test]
+    async fn exists_without_reading_body() {
+        let (server, mut imds_client) = mock_imds("some+token").await;
+        let body = "x".repeat(64 * 1024);
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                format!("/{}/meta-data/spot/instance-action", PINNED_SCHEMA),
+            ))
+            .times(2)
+            .respond_with(status_code(200).body(body.clone())),
+        );
+        assert!(imds_client.exists("spot/instance-action").await.unwrap());
+        assert_eq!(imds_client.body_bytes_read.load(Ordering::SeqCst), 0);
+
+        // fetching the same target reads the body, which shows the accounting works.
+        imds_client
+            .fetch_metadata("spot/instance-action")
+            .await
+            .unwrap();
+        assert_eq!(
+            imds_client.body_bytes_read.load(Ordering::SeqCst),
+            body.len()
+        );
+    }
+
+    #[tokio::test]
+    async fn exists_not_found() {
+        let (server, mut imds_client) = mock_imds("some+token").await;
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                format!(
+                    "/{}/meta-data/autoscaling/target-lifecycle-state",
+                    PINNED_SCHEMA
+                ),
+            ))
+            .times(1)
+            .respond_with(status_code(404)),
+        );
+        assert!(!imds_client
+            .exists("autoscaling/target-lifecycle-state")
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn exists_error() {
+        let (server, mut imds_client) = mock_imds("some+token").await;
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                format!("/{}/meta-data/spot/instance-action", PINNED_SCHEMA),
+            ))
+            .times(4)
+            .respond_with(httptest::cycle![
+                status_code(400),
+                status_code(403),
+                status_code(429),
+                status_code(500)
+            ]),
+        );
+        let mut errors = Vec::new();
+        for _ in 0..4 {
+            errors.push(
+                imds_client
+                    .exists("spot/instance-action")
+                    .await
+                    .unwrap_err(),
+            );
+        }
+        assert!(matches!(errors[0], Error::Response { .. }));
+        assert!(matches!(errors[1], Error::ImdsDisabled { .. }));
+        assert!(matches!(errors[2], Error::Throttled { .. }));
+        assert!(matches!(errors[3], Error::TransientServer { .. }));
+    }
+
+    #[tokio::test]
+    async fn cached_responses_without_session() {
+        let server = Server::run();
+        let base_uri = format!("http://localhost:{}", server.addr().port());
+        server.expect(
+            Expectation::matching(request::method_path("PUT", "/latest/api/token"))
+                .times(1..)
+                .respond_with(status_code(200).body("some+token")),
+        );
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                format!("/{}/meta-data/instance-type", PINNED_SCHEMA),
+            ))
+            .times(1)
+            .respond_with(status_code(200).body("m5.large")),
+        );
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                format!("/{}/meta-data/ami-id", PINNED_SCHEMA),
+            ))
+            .times(1)
+            .respond_with(status_code(404)),
+        );
+        // tokens that are always about to expire make every request refresh the token first.
+        let mut imds_client = ImdsClient::new_impl(base_uri)
+            .with_cache()
+            .with_token_ttl(Duration::from_secs(1))
+            .unwrap();
+        imds_client.fetch_metadata("instance-type").await.unwrap();
+        assert!(!imds_client.exists("ami-id").await.unwrap());
+
+        // once IMDS is gone, the cached responses are still returned.
+        drop(server);
+        assert_eq!(
+            imds_client.fetch_metadata("instance-type").await.unwrap(),
+            b"m5.large".to_vec()
+        );
+        assert!(!imds_client.exists("ami-id").await.unwrap());
+        let error = imds_client.fetch_metadata("ami-id").await.unwrap_err();
+        assert!(matches!(error, Error::NotFound { .. }));
+    }
+
+    #[tokio::test]
+    async fn exists_refreshes_token() {
+        let server = Server::run();
+        let base_uri = format!("http://localhost:{}", server.addr().port());
+        server.expect(
+            Expectation::matching(request::method_path("PUT", "/latest/api/token"))
+                .times(2)
+                .respond_with(status_code(200).body("some+token")),
+        );
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                format!("/{}/meta-data/spot/instance-action", PINNED_SCHEMA),
+            ))
+            .times(2)
+            .respond_with(httptest::cycle![
+                status_code(401),
+                status_code(200).body("{}")
+            ]),
+        );
+        let mut imds_client = ImdsClient::new_impl(base_uri);
+        assert!(imds_client.exists("spot/instance-action").await.unwrap());
+        assert_eq!(imds_client.body_bytes_read.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn printable_string_short() {
+        let input = "Hello".as_bytes();
+        let expected = "Hello".to_string();
+        let actual = printable_string(input);
+        assert_eq!(expected, actual);
+    }
+
     #[test]
     fn printable_string_binary() {
         let input: [u8; 5] = [0, 254, 1, 0, 4];
@@ -713,6 +2857,494 @@ mod test {
         assert_eq!(expected, actual);
     }
 
+    const IDENTITY_DOCUMENT: &str = r#"{
+        "accountId" : "123456789012",
+        "availabilityZone" : "us-west-2a",
+        "instanceType" : "m5.large",
+        "region" : "us-west-2"
+    }"#;
+
+    #[test]
+    fn identity_document_from_bytes() {
+        let identity_document = IdentityDocument::try_from(IDENTITY_DOCUMENT.as_bytes()).unwrap();
+        assert_eq!(identity_document.region(), "us-west-2");
+        assert_eq!(identity_document.instance_type(), "m5.large");
+        assert_eq!(
+            identity_document,
+            IDENTITY_DOCUMENT.parse::<IdentityDocument>().unwrap()
+        );
+    }
+
+    #[test]
+    fn identity_document_from_bytes_missing_field() {
+        let result = IdentityDocument::try_from(r#"{"region": "us-west-2"}"#.as_bytes());
+        assert!(matches!(result, Err(error::Error::Serde { .. })));
+    }
+
+    #[test]
+    fn identity_document_debug() {
+        let identity_document: IdentityDocument = IDENTITY_DOCUMENT.parse().unwrap();
+        let debug = format!("{:?}", identity_document);
+        assert_eq!(
+            debug,
+            r#"IdentityDocument { region: "us-west-2", instance_type: "m5.large", other_fields: "<redacted>" }"#
+        );
+        assert!(!debug.contains("123456789012"));
+    }
+
+    #[test]
+    fn error_kinds() {
+        let cases = vec![
+            (
+                error::Error::NotFound {
+                    uri: "uri".to_string(),
+                },
+                ErrorKind::NotFound,
+            ),
+            (
+                error::Error::FailedFetch { attempt: 4 },
+                ErrorKind::Transport,
+            ),
+            (
+                error::Error::Response {
+                    method: "GET".to_string(),
+                    uri: "uri".to_string(),
+                    code: StatusCode::FORBIDDEN,
+                    response_body: String::new(),
+                },
+                ErrorKind::Unauthorized,
+            ),
+            (
+                error::Error::Response {
+                    method: "GET".to_string(),
+                    uri: "uri".to_string(),
+                    code: StatusCode::INTERNAL_SERVER_ERROR,
+                    response_body: String::new(),
+                },
+                ErrorKind::Other,
+            ),
+            (
+                IdentityDocument::try_from(b"{".as_ref()).unwrap_err(),
+                ErrorKind::Parse,
+            ),
+            (
+                String::from_utf8(vec![0xff])
+                    .context(error::NonUtf8Response)
+                    .unwrap_err(),
+                ErrorKind::Parse,
+            ),
+        ];
+        for (error, kind) in cases {
+            assert_eq!(error.kind(), kind, "{}", error);
+        }
+    }
+
+    #[test]
+    fn error_sources() {
+        use std::error::Error as _;
+        let error = IdentityDocument::try_from(b"{".as_ref()).unwrap_err();
+        assert!(error.source().unwrap().is::<serde_json::Error>());
+        let error = String::from_utf8(vec![0xff])
+            .context(error::NonUtf8Response)
+            .unwrap_err();
+        assert!(error.source().unwrap().is::<std::string::FromUtf8Error>());
+    }
+
+    #[tokio::test]
+    async fn error_kind_from_server() {
+        let (server, imds_client) = mock_imds("some+token").await;
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                format!("/{}/meta-data/instance-type", PINNED_SCHEMA),
+            ))
+            .times(1)
+            .respond_with(status_code(404)),
+        );
+        let error = imds_client
+            .fetch_string("meta-data/instance-type", imds_client.start_deadline())
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+
+        // nothing is listening here, so the request can't be sent.
+        drop(server);
+        let error = imds_client
+            .fetch_string("meta-data/local-ipv4", imds_client.start_deadline())
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Transport);
+    }
+
+    fn versions(listing: &[&str]) -> Vec<String> {
+        listing.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn select_schema_version_with_preferred() {
+        let available = versions(&["1.0", "2016-09-02", "2021-01-03", "2021-03-23", "latest"]);
+        assert_eq!(
+            select_schema_version(&available, "2021-01-03"),
+            Some("2021-01-03".to_string())
+        );
+    }
+
+    #[test]
+    fn select_schema_version_without_preferred() {
+        let available = versions(&["1.0", "2016-09-02", "2019-10-01", "2021-03-23", "latest"]);
+        assert_eq!(
+            select_schema_version(&available, "2021-01-03"),
+            Some("2019-10-01".to_string())
+        );
+        // only undated or newer versions.
+        let available = versions(&["1.0", "2021-03-23", "latest"]);
+        assert_eq!(select_schema_version(&available, "2021-01-03"), None);
+        assert_eq!(select_schema_version(&[], "2021-01-03"), None);
+    }
+
+    // Expects a request for the version listing at the root of IMDS.
+    fn expect_listing(server: &Server, listing: &str) {
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/"))
+                .times(1)
+                .respond_with(status_code(200).body(listing.to_string())),
+        );
+    }
+
+    #[tokio::test]
+    async fn negotiate_preferred_schema() {
+        let (server, imds_client) = mock_imds("some+token").await;
+        expect_listing(&server, "1.0\n2016-09-02\n2021-01-03\nlatest\n");
+        let imds_client = imds_client.with_schema_negotiation().await.unwrap();
+        assert_eq!(imds_client.schema_version(), PINNED_SCHEMA);
+    }
+
+    #[tokio::test]
+    async fn negotiate_older_schema() {
+        let (server, imds_client) = mock_imds("some+token").await;
+        expect_listing(&server, "1.0\n2016-09-02\n2019-10-01\nlatest\n");
+        let mut imds_client = imds_client.with_schema_negotiation().await.unwrap();
+        assert_eq!(imds_client.schema_version(), "2019-10-01");
+
+        // later requests use the negotiated version.
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                "/2019-10-01/meta-data/instance-type",
+            ))
+            .times(1)
+            .respond_with(status_code(200).body("m5.large")),
+        );
+        let imds_data = imds_client.fetch_metadata("instance-type").await.unwrap();
+        assert_eq!(imds_data, b"m5.large".to_vec());
+    }
+
+    #[tokio::test]
+    async fn negotiate_no_usable_schema() {
+        let (server, mut imds_client) = mock_imds("some+token").await;
+        expect_listing(&server, "1.0\nlatest\n");
+        let error = imds_client.negotiate_schema_version().await.unwrap_err();
+        assert!(matches!(error, Error::NoSchemaVersion { .. }));
+        assert_eq!(imds_client.schema_version(), PINNED_SCHEMA);
+    }
+
+    #[test]
+    fn default_schema_is_preferred() {
+        assert_eq!(ImdsClient::new().schema_version(), PINNED_SCHEMA);
+    }
+
+    #[test]
+    fn target_allow_list() {
+        let prefixes = versions(&["meta-data/public-keys", "meta-data/network"]);
+        for target in &[
+            "meta-data/public-keys",
+            "meta-data/public-keys/0/openssh-key",
+            "meta-data/network/interfaces/macs",
+        ] {
+            assert!(is_target_allowed(&prefixes, target), "{}", target);
+        }
+        for target in &[
+            "meta-data/iam/security-credentials/my-role",
+            "meta-data/public-keys-other",
+            "meta-data/public-keys/../iam/info",
+            "meta-data",
+            "user-data",
+            "dynamic/instance-identity/document",
+        ] {
+            assert!(!is_target_allowed(&prefixes, target), "{}", target);
+        }
+    }
+
+    #[tokio::test]
+    async fn allowed_target() {
+        let (server, imds_client) = mock_imds("some+token").await;
+        let mut imds_client = imds_client.allowed_prefixes(&["meta-data/network"]);
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                "/2021-01-03/meta-data/network/interfaces/macs",
+            ))
+            .times(1)
+            .respond_with(status_code(200).body("0e:00:00:00:00:01/")),
+        );
+        let imds_data = imds_client
+            .fetch_metadata("network/interfaces/macs")
+            .await
+            .unwrap();
+        assert_eq!(imds_data, b"0e:00:00:00:00:01/".to_vec());
+    }
+
+    #[tokio::test]
+    async fn denied_target() {
+        // the mock server fails the test if it receives a request it doesn't expect, including
+        // one for a session token.
+        let server = Server::run();
+        let mut imds_client =
+            ImdsClient::new_impl(format!("http://localhost:{}", server.addr().port()))
+                .allowed_prefixes(&["meta-data/public-keys"]);
+        let error = imds_client
+            .fetch_metadata("iam/security-credentials/my-role")
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::TargetNotAllowed { .. }));
+        assert_eq!(error.kind(), ErrorKind::NotAllowed);
+        let error = imds_client.exists("iam/info").await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotAllowed);
+        let error = imds_client.fetch_userdata().await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotAllowed);
+    }
+
+    #[tokio::test]
+    async fn unrestricted_by_default() {
+        let (server, mut imds_client) = mock_imds("some+token").await;
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                "/2021-01-03/meta-data/iam/info",
+            ))
+            .times(1)
+            .respond_with(status_code(200).body("{}")),
+        );
+        let imds_data = imds_client.fetch_metadata("iam/info").await.unwrap();
+        assert_eq!(imds_data, b"{}".to_vec());
+    }
+
+    // responds to GET requests for `meta-data/<target>` with `body`, once.
+    fn expect_metadata(server: &Server, target: &str, body: &str) {
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                format!("/{}/meta-data/{}", PINNED_SCHEMA, target),
+            ))
+            .times(1)
+            .respond_with(status_code(200).body(body.to_string())),
+        );
+    }
+
+    #[tokio::test]
+    async fn record_responses() {
+        let dir = TempDir::new().unwrap();
+        let recordings = dir.path().join("imds");
+        let (server, imds_client) = mock_imds("some+token").await;
+        let mut imds_client = imds_client.record_responses(&recordings);
+        expect_metadata(&server, "instance-type", "m5.large");
+        expect_metadata(
+            &server,
+            "network/interfaces/macs/06:aa:bb:cc:dd:ee/vpc-ipv4-cidr-blocks",
+            "192.168.0.0/16",
+        );
+        expect_metadata(
+            &server,
+            "iam/security-credentials/my-role",
+            "{\"Token\": \"x\"}",
+        );
+        let long_body = "a".repeat(recorder::MAX_RECORDED_BYTES + 10);
+        expect_metadata(&server, "public-keys", &long_body);
+
+        imds_client.fetch_metadata("instance-type").await.unwrap();
+        imds_client
+            .fetch_cidr_blocks_for_mac("06:aa:bb:cc:dd:ee")
+            .await
+            .unwrap();
+        // the credentials are returned, but not recorded.
+        let credentials = imds_client
+            .fetch_metadata("iam/security-credentials/my-role")
+            .await
+            .unwrap();
+        assert_eq!(credentials, b"{\"Token\": \"x\"}".to_vec());
+        let keys = imds_client.fetch_metadata("public-keys").await.unwrap();
+        assert_eq!(keys.len(), long_body.len());
+
+        let mut recorded: Vec<String> = std::fs::read_dir(&recordings)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        recorded.sort();
+        assert_eq!(
+            recorded,
+            vec![
+                "meta-data_instance-type",
+                "meta-data_network_interfaces_macs_06_aa_bb_cc_dd_ee_vpc-ipv4-cidr-blocks",
+                "meta-data_public-keys",
+            ]
+        );
+        assert_eq!(
+            std::fs::read(recordings.join("meta-data_instance-type")).unwrap(),
+            b"m5.large".to_vec()
+        );
+        assert_eq!(
+            std::fs::read(recordings.join("meta-data_public-keys"))
+                .unwrap()
+                .len(),
+            recorder::MAX_RECORDED_BYTES
+        );
+    }
+
+    #[tokio::test]
+    async fn recording_failure_is_ignored() {
+        // the recordings "directory" is a file, so nothing can be written to it.
+        let dir = TempDir::new().unwrap();
+        let recordings = dir.path().join("imds");
+        std::fs::write(&recordings, "").unwrap();
+        let (server, imds_client) = mock_imds("some+token").await;
+        let mut imds_client = imds_client.record_responses(&recordings);
+        expect_metadata(&server, "instance-type", "m5.large");
+        let imds_data = imds_client.fetch_metadata("instance-type").await.unwrap();
+        assert_eq!(imds_data, b"m5.large".to_vec());
+    }
+
+    // responds to GET requests for `target` with `body` after `delay`, `times` times.
+    fn expect_delayed(server: &Server, target: &str, body: &'static str, delay: u64, times: usize) {
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                format!("/{}/{}", PINNED_SCHEMA, target),
+            ))
+            .times(times)
+            .respond_with(delay_and_then(
+                Duration::from_millis(delay),
+                status_code(200).body(body),
+            )),
+        );
+    }
+
+    #[tokio::test]
+    async fn budget_exceeded_in_flight() {
+        let (server, imds_client) = mock_imds("some+token").await;
+        let mut imds_client = imds_client.with_budget(Duration::from_millis(200));
+        expect_delayed(&server, "meta-data/instance-type", "m5.large", 2000, 1);
+        let started = std::time::Instant::now();
+        let error = imds_client
+            .fetch_metadata("instance-type")
+            .await
+            .unwrap_err();
+        assert!(matches!(error, error::Error::BudgetExceeded { .. }));
+        assert_eq!(error.kind(), ErrorKind::Transport);
+        // the call returns when the budget runs out, not when the response arrives.
+        assert!(started.elapsed() < Duration::from_millis(1500));
+    }
+
+    #[tokio::test]
+    async fn budget_covers_all_requests_of_a_helper() {
+        let (server, imds_client) = mock_imds("some+token").await;
+        let mut imds_client = imds_client.with_budget(Duration::from_millis(500));
+        expect_delayed(&server, "meta-data/public-keys", "0=a\n1=b\n2=c", 0, 1);
+        expect_delayed(
+            &server,
+            "meta-data/public-keys/0/openssh-key",
+            "ssh-rsa a",
+            300,
+            1,
+        );
+        // the keys are fetched concurrently, and the budget runs out while the last ones are in
+        // flight.
+        expect_delayed(
+            &server,
+            "meta-data/public-keys/1/openssh-key",
+            "ssh-rsa b",
+            1000,
+            1,
+        );
+        expect_delayed(
+            &server,
+            "meta-data/public-keys/2/openssh-key",
+            "ssh-rsa c",
+            1000,
+            1,
+        );
+        let error = imds_client.fetch_public_ssh_keys().await.unwrap_err();
+        assert!(matches!(error, error::Error::BudgetExceeded { .. }));
+    }
+
+    #[tokio::test]
+    async fn budget_restarts_for_each_helper() {
+        let (server, imds_client) = mock_imds("some+token").await;
+        let mut imds_client = imds_client.with_budget(Duration::from_millis(500));
+        // each call fits in the budget, though both together don't.
+        expect_delayed(&server, "meta-data/instance-type", "m5.large", 300, 2);
+        for _ in 0..2 {
+            let response = imds_client.fetch_metadata("instance-type").await.unwrap();
+            assert_eq!(response, b"m5.large".to_vec());
+        }
+    }
+
+    #[tokio::test]
+    async fn unlimited_budget_by_default() {
+        let (server, mut imds_client) = mock_imds("some+token").await;
+        expect_delayed(&server, "meta-data/instance-type", "m5.large", 300, 1);
+        let response = imds_client.fetch_metadata("instance-type").await.unwrap();
+        assert_eq!(response, b"m5.large".to_vec());
+    }
+
+    #[tokio::test]
+    async fn fetch_public_ssh_keys_concurrently() {
+        let (server, mut imds_client) = mock_imds("some+token").await;
+        expect_delayed(
+            &server,
+            "meta-data/public-keys",
+            "0=first\n1=junk\n2=second\n3=third",
+            0,
+            1,
+        );
+        // the first key is the slowest, so the keys arrive out of order.
+        expect_delayed(
+            &server,
+            "meta-data/public-keys/0/openssh-key",
+            "ssh-rsa first\n",
+            400,
+            1,
+        );
+        expect_delayed(
+            &server,
+            "meta-data/public-keys/1/openssh-key",
+            "not a key",
+            0,
+            1,
+        );
+        expect_delayed(
+            &server,
+            "meta-data/public-keys/2/openssh-key",
+            "ssh-ed25519 second",
+            200,
+            1,
+        );
+        expect_delayed(
+            &server,
+            "meta-data/public-keys/3/openssh-key",
+            "ssh-rsa third",
+            0,
+            1,
+        );
+        let started = std::time::Instant::now();
+        let keys = imds_client.fetch_public_ssh_keys().await.unwrap();
+        assert_eq!(
+            keys,
+            vec!["ssh-rsa first", "ssh-ed25519 second", "ssh-rsa third"]
+        );
+        // one after another, the keys would take at least 600ms.
+        assert!(started.elapsed() < Duration::from_millis(590));
+    }
+
     #[test]
     fn parse_public_key_list() {
         let list = r#"0=zero
@@ -0,0 +1,138 @@
+//! Provides an optional recorder of IMDS responses, which writes the body of each fetched target
+//! to a file in a directory, so that users can attach what IMDS told their instance to a bug
+//! report rather than running requests by hand.  Targets that hold secrets, like IAM credentials
+//! and user data, are never recorded, and session tokens aren't fetched through the recorded path.
+//! Recording is best-effort: a failure to write a file is logged and never affects the fetch.
+
+use log::{debug, warn};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The most bytes of a response that are recorded; the rest of a longer response is dropped.
+pub(crate) const MAX_RECORDED_BYTES: usize = 64 * 1024;
+
+/// Targets whose responses are never recorded, because they hold secrets.  A target is excluded
+/// if it's one of these or under one.
+const UNRECORDED_PREFIXES: &[&str] = &[
+    "meta-data/iam/security-credentials",
+    "meta-data/identity-credentials",
+    "user-data",
+];
+
+/// Writes fetched responses to files in a directory, one per target.
+#[derive(Debug)]
+pub(crate) struct ResponseRecorder {
+    dir: PathBuf,
+}
+
+impl ResponseRecorder {
+    pub(crate) fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Records `body`, the response for `target`, unless the target may hold secrets.  The
+    /// directory is created if needed, and a file from an earlier response is replaced.
+    pub(crate) fn record(&self, target: &str, body: &[u8]) {
+        if !is_recordable(target) {
+            debug!("Not recording the response for {}", target);
+            return;
+        }
+        let path = self.dir.join(file_name(target));
+        let body = &body[..body.len().min(MAX_RECORDED_BYTES)];
+        if let Err(e) = write(&self.dir, &path, body) {
+            warn!(
+                "Unable to record the response for {} to '{}': {}",
+                target,
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+fn write(dir: &Path, path: &Path, body: &[u8]) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    fs::write(path, body)
+}
+
+/// Returns whether the response for `target` may be recorded.  Targets with `.` or `..` segments
+/// could resolve to anything, so they're never recorded.
+fn is_recordable(target: &str) -> bool {
+    let target = target.trim_matches('/');
+    if target
+        .split('/')
+        .any(|segment| segment == "." || segment == "..")
+    {
+        return false;
+    }
+    !crate::is_target_allowed(UNRECORDED_PREFIXES, target)
+}
+
+/// Returns the name of the file that the response for `target` is recorded in, which has every
+/// character other than ASCII letters, digits, `-`, and `_` replaced with `_`, e.g.
+/// `meta-data_network_interfaces_macs` for `meta-data/network/interfaces/macs`.
+fn file_name(target: &str) -> String {
+    let name: String = target
+        .trim_matches('/')
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    // the listing of schema versions is at the root, which has no name of its own.
+    if name.is_empty() {
+        "index".to_string()
+    } else {
+        name
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn file_names() {
+        for (target, name) in &[
+            ("meta-data/instance-type", "meta-data_instance-type"),
+            (
+                "meta-data/network/interfaces/macs/06:aa:bb:cc:dd:ee/vpc-ipv4-cidr-blocks",
+                "meta-data_network_interfaces_macs_06_aa_bb_cc_dd_ee_vpc-ipv4-cidr-blocks",
+            ),
+            (
+                "/dynamic/instance-identity/document/",
+                "dynamic_instance-identity_document",
+            ),
+            ("meta-data/../../etc/passwd", "meta-data_______etc_passwd"),
+            ("", "index"),
+        ] {
+            assert_eq!(file_name(target), *name, "{}", target);
+        }
+    }
+
+    #[test]
+    fn secrets_are_not_recordable() {
+        for target in &[
+            "meta-data/iam/security-credentials",
+            "meta-data/iam/security-credentials/my-role",
+            "/meta-data/identity-credentials/ec2/security-credentials/ec2-instance",
+            "user-data",
+            "meta-data/public-keys/../iam/security-credentials/my-role",
+            "meta-data/./iam/security-credentials/my-role",
+        ] {
+            assert!(!is_recordable(target), "{}", target);
+        }
+        for target in &[
+            "meta-data/iam/info",
+            "meta-data/instance-type",
+            "user-data-other",
+            "dynamic/instance-identity/document",
+        ] {
+            assert!(is_recordable(target), "{}", target);
+        }
+    }
+}
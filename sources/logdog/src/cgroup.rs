@@ -0,0 +1,238 @@
+//! Copies cgroup statistics into the tarball for `cgroup` log requests.
+//!
+//! A `cgroup` request names a cgroup relative to each controller's hierarchy, e.g.
+//! `system.slice/kubelet.service`, and the files of that cgroup are copied from every controller
+//! under `/sys/fs/cgroup` that has it.  The copy is bounded so that a deep or unexpected hierarchy
+//! can't blow up the size of the tarball: it only descends a few levels, and it skips symlinks and
+//! files that are too large.
+
+use crate::error::{self, Result};
+use snafu::ResultExt;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+use walkdir::WalkDir;
+
+/// Where the cgroup controller hierarchies are mounted.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// How many directory levels below a cgroup are copied; the cgroup's own files are at depth 1.
+const MAX_DEPTH: usize = 3;
+
+/// The size of the largest file that's copied.  cgroup files report a size of zero, so the size is
+/// only known once the file is read.
+const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+/// Limits on what `copy_tree` copies.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TreeLimits {
+    /// How many directory levels are copied; the files directly in the source are at depth 1.
+    pub(crate) max_depth: usize,
+    /// Files larger than this, in bytes, are skipped.
+    pub(crate) max_file_size: u64,
+}
+
+impl Default for TreeLimits {
+    fn default() -> Self {
+        Self {
+            max_depth: MAX_DEPTH,
+            max_file_size: MAX_FILE_SIZE,
+        }
+    }
+}
+
+/// Copies the cgroup at `cgroup`, relative to each controller's hierarchy, from every controller
+/// that has it to `<dest>/<controller>`.
+pub(crate) fn copy_cgroup<P: AsRef<Path>>(cgroup: &str, dest: P) -> Result<()> {
+    copy_cgroup_from(CGROUP_ROOT, cgroup, dest, TreeLimits::default())
+}
+
+fn copy_cgroup_from<P1, P2>(root: P1, cgroup: &str, dest: P2, limits: TreeLimits) -> Result<()>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let root = root.as_ref();
+    let dest = dest.as_ref();
+    let cgroup = cgroup.trim_start_matches('/');
+    for entry in fs::read_dir(root).context(error::CgroupRead { path: root })? {
+        let entry = entry.context(error::CgroupRead { path: root })?;
+        // each controller, e.g. `memory`, is a directory; skip the symlinks like `cpu` that point
+        // to combined controllers like `cpu,cpuacct`.
+        let file_type = entry
+            .file_type()
+            .context(error::CgroupRead { path: root })?;
+        if !file_type.is_dir() {
+            continue;
+        }
+        let source = entry.path().join(cgroup);
+        if source.is_dir() {
+            copy_tree(&source, dest.join(entry.file_name()), limits)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copies the files in `source` to `dest`, keeping their relative paths, within `limits`.
+/// Symlinks, and files that can't be read, e.g. the write-only cgroup control files, are skipped.
+pub(crate) fn copy_tree<P1, P2>(source: P1, dest: P2, limits: TreeLimits) -> Result<()>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let source = source.as_ref();
+    let dest = dest.as_ref();
+    let walker = WalkDir::new(source)
+        .follow_links(false)
+        .max_depth(limits.max_depth)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()));
+    for entry in walker {
+        let entry = entry.context(error::CgroupWalk { path: source })?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let contents = match read_limited(entry.path(), limits.max_file_size) {
+            Some(contents) => contents,
+            None => continue,
+        };
+        // walkdir only yields paths under `source`.
+        let relative = entry.path().strip_prefix(source).unwrap_or(entry.path());
+        let dest_path = dest.join(relative);
+        if let Some(parent) = dest_path.parent() {
+            fs::create_dir_all(parent).context(error::CreateOutputDirectory { path: parent })?;
+        }
+        fs::write(&dest_path, contents).context(error::CgroupWrite { path: &dest_path })?;
+    }
+    Ok(())
+}
+
+/// Reads the file at `path`, returning `None` if it can't be read or is larger than `max_size`.
+fn read_limited(path: &Path, max_size: u64) -> Option<Vec<u8>> {
+    let file = File::open(path).ok()?;
+    let mut contents = Vec::new();
+    file.take(max_size + 1).read_to_end(&mut contents).ok()?;
+    if contents.len() as u64 > max_size {
+        None
+    } else {
+        Some(contents)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    /// Creates a synthetic cgroup hierarchy in a tempdir:
+    ///
+    /// ```text
+    /// memory/system.slice/kubelet.service/memory.stat
+    /// memory/system.slice/kubelet.service/memory.huge
+    /// memory/system.slice/kubelet.service/memory.link -> memory.stat
+    /// memory/system.slice/kubelet.service/a/b/memory.deep
+    /// memory/system.slice/containerd.service/memory.stat
+    /// cpu,cpuacct/system.slice/kubelet.service/cpu.stat
+    /// cpu -> cpu,cpuacct
+    /// ```
+    fn create_cgroup_tree() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        let kubelet = dir.path().join("memory/system.slice/kubelet.service");
+        fs::create_dir_all(kubelet.join("a/b")).unwrap();
+        fs::write(kubelet.join("memory.stat"), "cache 1\n").unwrap();
+        fs::write(kubelet.join("memory.huge"), vec![b'x'; 64]).unwrap();
+        symlink("memory.stat", kubelet.join("memory.link")).unwrap();
+        fs::write(kubelet.join("a/b/memory.deep"), "deep\n").unwrap();
+        let containerd = dir.path().join("memory/system.slice/containerd.service");
+        fs::create_dir_all(&containerd).unwrap();
+        fs::write(containerd.join("memory.stat"), "cache 2\n").unwrap();
+        let cpu = dir.path().join("cpu,cpuacct/system.slice/kubelet.service");
+        fs::create_dir_all(&cpu).unwrap();
+        fs::write(cpu.join("cpu.stat"), "nr_periods 0\n").unwrap();
+        symlink("cpu,cpuacct", dir.path().join("cpu")).unwrap();
+        dir
+    }
+
+    fn limits(max_depth: usize) -> TreeLimits {
+        TreeLimits {
+            max_depth,
+            max_file_size: 32,
+        }
+    }
+
+    #[test]
+    fn copy_kubelet_cgroup() {
+        let root = create_cgroup_tree();
+        let dest = TempDir::new().unwrap();
+        copy_cgroup_from(
+            root.path(),
+            "system.slice/kubelet.service",
+            dest.path(),
+            limits(MAX_DEPTH),
+        )
+        .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dest.path().join("memory/memory.stat")).unwrap(),
+            "cache 1\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dest.path().join("memory/a/b/memory.deep")).unwrap(),
+            "deep\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dest.path().join("cpu,cpuacct/cpu.stat")).unwrap(),
+            "nr_periods 0\n"
+        );
+        // the containerd cgroup wasn't requested, and the `cpu` symlink isn't followed.
+        assert!(!dest.path().join("memory/containerd.service").exists());
+        assert!(!dest.path().join("cpu").exists());
+    }
+
+    #[test]
+    fn missing_cgroup() {
+        let root = create_cgroup_tree();
+        let dest = TempDir::new().unwrap();
+        copy_cgroup_from(
+            root.path(),
+            "system.slice/missing.service",
+            dest.path(),
+            limits(MAX_DEPTH),
+        )
+        .unwrap();
+        assert_eq!(fs::read_dir(dest.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn depth_limit() {
+        let root = create_cgroup_tree();
+        let source = root.path().join("memory/system.slice/kubelet.service");
+        let dest = TempDir::new().unwrap();
+        copy_tree(&source, dest.path(), limits(2)).unwrap();
+        assert!(dest.path().join("memory.stat").is_file());
+        assert!(!dest.path().join("a/b/memory.deep").exists());
+
+        let dest = TempDir::new().unwrap();
+        copy_tree(&source, dest.path(), limits(3)).unwrap();
+        assert!(dest.path().join("a/b/memory.deep").is_file());
+    }
+
+    #[test]
+    fn size_limit_and_symlinks() {
+        let root = create_cgroup_tree();
+        let source = root.path().join("memory/system.slice/kubelet.service");
+        let dest = TempDir::new().unwrap();
+        copy_tree(&source, dest.path(), limits(MAX_DEPTH)).unwrap();
+        assert!(dest.path().join("memory.stat").is_file());
+        assert!(!dest.path().join("memory.huge").exists());
+        assert!(!dest.path().join("memory.link").exists());
+
+        let dest = TempDir::new().unwrap();
+        let roomy = TreeLimits {
+            max_depth: MAX_DEPTH,
+            max_file_size: 64,
+        };
+        copy_tree(&source, dest.path(), roomy).unwrap();
+        assert!(dest.path().join("memory.huge").is_file());
+    }
+}
@@ -0,0 +1,213 @@
+//! Writes `clock.json`, a snapshot of the host's clocks taken by logdog itself, so that time skew,
+//! which shows up as TLS or TUF expiration failures, can be spotted from the bundle.  It has the
+//! wall-clock time, the time since boot, and the time of the hardware clock (RTC) read from sysfs,
+//! along with how far the wall clock is ahead of the RTC.
+//!
+//! A clock that can't be read, e.g. because the host has no RTC, is left out, and the reason is
+//! recorded in its place as `<name>-error`, so the rest of the snapshot is still written.
+
+use crate::error::{self, Result};
+use serde_json::{json, Map, Value};
+use snafu::ResultExt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub(crate) const CLOCK_FILENAME: &str = "clock.json";
+
+/// The seconds since boot, followed by the idle time of all CPUs.
+const UPTIME_PATH: &str = "/proc/uptime";
+/// The time of the first RTC in seconds since the Unix epoch, as the kernel reads it.
+const RTC_PATH: &str = "/sys/class/rtc/rtc0/since_epoch";
+
+/// The clocks that `clock.json` reports.  This allows tests to supply the times.
+pub(crate) trait ClockSources {
+    /// Returns the current wall-clock time.
+    fn wall_clock(&self) -> SystemTime;
+    /// Returns the contents of `/proc/uptime`.
+    fn uptime(&self) -> io::Result<String>;
+    /// Returns the contents of the RTC's `since_epoch` file.
+    fn rtc(&self) -> io::Result<String>;
+}
+
+/// Reads the clocks of the host.
+pub(crate) struct HostClock;
+
+impl ClockSources for HostClock {
+    fn wall_clock(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn uptime(&self) -> io::Result<String> {
+        fs::read_to_string(UPTIME_PATH)
+    }
+
+    fn rtc(&self) -> io::Result<String> {
+        fs::read_to_string(RTC_PATH)
+    }
+}
+
+/// Parses the seconds since boot from the contents of `/proc/uptime`, e.g. `350735.47 234388.90`.
+fn parse_uptime(contents: &str) -> std::result::Result<f64, String> {
+    let field = contents.split_whitespace().next().unwrap_or_default();
+    field
+        .parse::<f64>()
+        .map_err(|e| format!("invalid uptime '{}': {}", field, e))
+}
+
+/// Parses the seconds since the Unix epoch from the contents of the RTC's `since_epoch` file.
+fn parse_rtc(contents: &str) -> std::result::Result<u64, String> {
+    let field = contents.trim();
+    field
+        .parse::<u64>()
+        .map_err(|e| format!("invalid RTC time '{}': {}", field, e))
+}
+
+/// Returns the contents of `clock.json` for the clocks in `sources`.
+fn render_clock<S: ClockSources>(sources: &S) -> String {
+    let mut clock = Map::new();
+    // a wall clock before the epoch is a skew in itself, so it's reported as negative.
+    let wall_clock_ms = match sources.wall_clock().duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_millis() as i64,
+        Err(e) => -(e.duration().as_millis() as i64),
+    };
+    clock.insert("wall-clock-ms".to_string(), json!(wall_clock_ms));
+
+    match sources
+        .uptime()
+        .map_err(|e| e.to_string())
+        .and_then(|contents| parse_uptime(&contents))
+    {
+        Ok(uptime) => clock.insert("uptime-seconds".to_string(), json!(uptime)),
+        Err(e) => clock.insert("uptime-error".to_string(), json!(e)),
+    };
+
+    match sources
+        .rtc()
+        .map_err(|e| e.to_string())
+        .and_then(|contents| parse_rtc(&contents))
+    {
+        Ok(rtc) => {
+            clock.insert("rtc-seconds".to_string(), json!(rtc));
+            // the RTC only has whole seconds, so the offset does too.
+            let offset = wall_clock_ms.div_euclid(1000) - rtc as i64;
+            clock.insert("wall-clock-ahead-of-rtc-seconds".to_string(), json!(offset));
+        }
+        Err(e) => {
+            clock.insert("rtc-error".to_string(), json!(e));
+        }
+    }
+    format!("{:#}\n", Value::Object(clock))
+}
+
+/// Writes `clock.json` for the host's clocks to `outdir`.
+pub(crate) fn write_clock<P: AsRef<Path>>(outdir: P) -> Result<()> {
+    let path = outdir.as_ref().join(CLOCK_FILENAME);
+    fs::write(&path, render_clock(&HostClock)).context(error::ClockWrite { path })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    /// Supplies fixed clocks; a `None` clock fails to read as if its file were missing.
+    struct FakeClock {
+        wall_clock: SystemTime,
+        uptime: Option<&'static str>,
+        rtc: Option<&'static str>,
+    }
+
+    fn missing() -> io::Error {
+        io::Error::new(io::ErrorKind::NotFound, "No such file or directory")
+    }
+
+    impl ClockSources for FakeClock {
+        fn wall_clock(&self) -> SystemTime {
+            self.wall_clock
+        }
+
+        fn uptime(&self) -> io::Result<String> {
+            self.uptime.map(String::from).ok_or_else(missing)
+        }
+
+        fn rtc(&self) -> io::Result<String> {
+            self.rtc.map(String::from).ok_or_else(missing)
+        }
+    }
+
+    fn render(clock: &FakeClock) -> Value {
+        serde_json::from_str(&render_clock(clock)).unwrap()
+    }
+
+    #[test]
+    fn all_clocks() {
+        let clock = FakeClock {
+            wall_clock: UNIX_EPOCH + Duration::from_millis(1_600_000_123_456),
+            uptime: Some("350735.47 234388.90\n"),
+            rtc: Some("1600000000\n"),
+        };
+        assert_eq!(
+            render(&clock),
+            json!({
+                "wall-clock-ms": 1_600_000_123_456_i64,
+                "uptime-seconds": 350735.47,
+                "rtc-seconds": 1_600_000_000_u64,
+                "wall-clock-ahead-of-rtc-seconds": 123,
+            })
+        );
+    }
+
+    #[test]
+    fn wall_clock_behind_rtc() {
+        let clock = FakeClock {
+            wall_clock: UNIX_EPOCH + Duration::from_secs(1_000),
+            uptime: Some("5.00 1.00"),
+            rtc: Some("1600000000"),
+        };
+        assert_eq!(
+            render(&clock)["wall-clock-ahead-of-rtc-seconds"],
+            json!(1_000 - 1_600_000_000_i64)
+        );
+        let clock = FakeClock {
+            wall_clock: UNIX_EPOCH - Duration::from_millis(1_500),
+            ..clock
+        };
+        assert_eq!(render(&clock)["wall-clock-ms"], json!(-1_500));
+    }
+
+    #[test]
+    fn missing_clocks() {
+        let clock = FakeClock {
+            wall_clock: UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+            uptime: None,
+            rtc: None,
+        };
+        let rendered = render(&clock);
+        assert_eq!(rendered["wall-clock-ms"], json!(1_600_000_000_000_i64));
+        assert!(rendered["uptime-error"].is_string());
+        assert!(rendered["rtc-error"].is_string());
+        assert!(rendered.get("uptime-seconds").is_none());
+        assert!(rendered.get("rtc-seconds").is_none());
+        assert!(rendered.get("wall-clock-ahead-of-rtc-seconds").is_none());
+    }
+
+    #[test]
+    fn malformed_clocks() {
+        let clock = FakeClock {
+            wall_clock: UNIX_EPOCH,
+            uptime: Some(""),
+            rtc: Some("soon"),
+        };
+        let rendered = render(&clock);
+        assert_eq!(
+            rendered["rtc-error"],
+            json!("invalid RTC time 'soon': invalid digit found in string")
+        );
+        assert!(rendered["uptime-error"]
+            .as_str()
+            .unwrap()
+            .starts_with("invalid uptime ''"));
+    }
+}
@@ -0,0 +1,282 @@
+//! Copies files and directories into the tarball for `copy` log requests, so plain files keep
+//! their bytes, binary or not, and directories keep their structure.
+//!
+//! A file is copied to the request's output filename.  A directory is copied recursively to a
+//! directory with that name, keeping the relative paths of its files.  Symlinks inside a directory
+//! aren't followed.  Nothing is copied, and no error is recorded, if the source doesn't exist, so
+//! a directory that's only there on some hosts, like `/var/log/kdump`, can be requested on all.
+//!
+//! The copy is bounded so that an unexpectedly large file or directory can't blow up the size of
+//! the tarball: files larger than 10 MiB are skipped, as are files that would take the request past
+//! 100 MiB in all.  The skipped files are listed in the request's error in `logdog.errors`, after
+//! everything else is copied.
+
+use crate::error::{self, Result};
+use snafu::{ensure, ResultExt};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use walkdir::WalkDir;
+
+/// The size of the largest file that's copied.
+const MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+
+/// The most that's copied for one request, in bytes.
+const MAX_TOTAL_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Limits on what `copy_path` copies.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CopyLimits {
+    /// Files larger than this, in bytes, are skipped.
+    pub(crate) max_file_size: u64,
+    /// Files that would take the bytes copied past this are skipped.
+    pub(crate) max_total_size: u64,
+}
+
+impl Default for CopyLimits {
+    fn default() -> Self {
+        Self {
+            max_file_size: MAX_FILE_SIZE,
+            max_total_size: MAX_TOTAL_SIZE,
+        }
+    }
+}
+
+/// Copies the file or directory at `source` to `dest` within `limits`, and returns a description of
+/// each file that was skipped for being too large.
+pub(crate) fn copy_path<P1, P2>(source: P1, dest: P2, limits: CopyLimits) -> Result<Vec<String>>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let source = source.as_ref();
+    let dest = dest.as_ref();
+    // the source itself is followed if it's a symlink, like `/etc/resolv.conf` can be.
+    let metadata = match fs::metadata(source) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context(error::CopyRead { path: source }),
+    };
+    let mut copier = Copier {
+        limits,
+        copied: 0,
+        skipped: Vec::new(),
+    };
+    if metadata.is_dir() {
+        copier.copy_dir(source, dest)?;
+    } else {
+        copier.copy_file(source, dest)?;
+    }
+    Ok(copier.skipped)
+}
+
+/// Keeps track of what's been copied for one request.
+struct Copier {
+    limits: CopyLimits,
+    /// The bytes copied so far.
+    copied: u64,
+    skipped: Vec<String>,
+}
+
+impl Copier {
+    /// Copies the files in `source` to `dest`, keeping their relative paths.  Symlinks and other
+    /// files that aren't regular files are skipped.
+    fn copy_dir(&mut self, source: &Path, dest: &Path) -> Result<()> {
+        fs::create_dir_all(dest).context(error::CreateOutputDirectory { path: dest })?;
+        let walker = WalkDir::new(source)
+            .follow_links(false)
+            .sort_by(|a, b| a.file_name().cmp(b.file_name()));
+        for entry in walker {
+            let entry = entry.context(error::CopyWalk { path: source })?;
+            // walkdir only yields paths under `source`.
+            let relative = entry.path().strip_prefix(source).unwrap_or(entry.path());
+            let dest_path = dest.join(relative);
+            if entry.file_type().is_dir() {
+                fs::create_dir_all(&dest_path)
+                    .context(error::CreateOutputDirectory { path: &dest_path })?;
+            } else if entry.file_type().is_file() {
+                self.copy_file(entry.path(), &dest_path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies the file at `source` to `dest`, unless it's larger than the per-file limit or the
+    /// bytes left under the total limit.
+    fn copy_file(&mut self, source: &Path, dest: &Path) -> Result<()> {
+        let remaining = self.limits.max_total_size.saturating_sub(self.copied);
+        let max_size = self.limits.max_file_size.min(remaining);
+        match copy_limited(source, dest, max_size)? {
+            Some(size) => self.copied += size,
+            None if max_size == self.limits.max_file_size => self.skipped.push(format!(
+                "{} is larger than {} bytes",
+                source.display(),
+                self.limits.max_file_size
+            )),
+            None => self.skipped.push(format!(
+                "{} would exceed the total of {} bytes",
+                source.display(),
+                self.limits.max_total_size
+            )),
+        }
+        Ok(())
+    }
+}
+
+/// Copies the file at `source` to `dest` and returns its size, or returns `None`, leaving nothing
+/// at `dest`, if it's larger than `max_size`.  The size is counted as the file is read, since files
+/// like those in `/proc` report a size of zero.
+fn copy_limited(source: &Path, dest: &Path, max_size: u64) -> Result<Option<u64>> {
+    let file = File::open(source).context(error::CopyRead { path: source })?;
+    let mut out = File::create(dest).context(error::CopyWrite { path: dest })?;
+    let size = io::copy(&mut file.take(max_size + 1), &mut out).context(error::CopyFile {
+        from: source,
+        to: dest,
+    })?;
+    if size > max_size {
+        drop(out);
+        fs::remove_file(dest).context(error::CopyWrite { path: dest })?;
+        return Ok(None);
+    }
+    Ok(Some(size))
+}
+
+/// Copies `source` to `dest` as `copy_path` does for `request`, returning an error that lists the
+/// skipped files, if there were any, once the rest are copied.
+pub(crate) fn copy_request<P1, P2>(request: &str, source: P1, dest: P2) -> Result<()>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let skipped = copy_path(source, dest, CopyLimits::default())?;
+    ensure!(
+        skipped.is_empty(),
+        error::CopySkipped {
+            request,
+            skipped: skipped.join(", "),
+        }
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    /// Creates a directory to copy in a tempdir:
+    ///
+    /// ```text
+    /// source/top.conf         "top\n"
+    /// source/a/b/deep.conf    "deep\n"
+    /// source/a/binary         [0, 159, 146, 150, 255]
+    /// source/a/huge.log       64 bytes
+    /// source/empty/
+    /// source/link -> top.conf
+    /// ```
+    fn create_source_tree() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("a/b")).unwrap();
+        fs::create_dir_all(source.join("empty")).unwrap();
+        fs::write(source.join("top.conf"), "top\n").unwrap();
+        fs::write(source.join("a/b/deep.conf"), "deep\n").unwrap();
+        fs::write(source.join("a/binary"), [0u8, 159, 146, 150, 255]).unwrap();
+        fs::write(source.join("a/huge.log"), vec![b'x'; 64]).unwrap();
+        symlink("top.conf", source.join("link")).unwrap();
+        dir
+    }
+
+    fn limits(max_file_size: u64, max_total_size: u64) -> CopyLimits {
+        CopyLimits {
+            max_file_size,
+            max_total_size,
+        }
+    }
+
+    #[test]
+    fn copy_directory_structure() {
+        let root = create_source_tree();
+        let dest = TempDir::new().unwrap();
+        let out = dest.path().join("source-copy");
+        let skipped = copy_path(root.path().join("source"), &out, limits(1024, 1024)).unwrap();
+        assert!(skipped.is_empty());
+
+        assert_eq!(fs::read_to_string(out.join("top.conf")).unwrap(), "top\n");
+        assert_eq!(
+            fs::read_to_string(out.join("a/b/deep.conf")).unwrap(),
+            "deep\n"
+        );
+        assert_eq!(
+            fs::read(out.join("a/binary")).unwrap(),
+            vec![0u8, 159, 146, 150, 255]
+        );
+        assert_eq!(fs::read(out.join("a/huge.log")).unwrap().len(), 64);
+        assert!(out.join("empty").is_dir());
+        // symlinks inside the directory aren't followed.
+        assert!(!out.join("link").exists());
+    }
+
+    #[test]
+    fn copy_single_file() {
+        let root = create_source_tree();
+        let dest = TempDir::new().unwrap();
+        let out = dest.path().join("os-release");
+        let skipped = copy_path(root.path().join("source/link"), &out, limits(32, 32)).unwrap();
+        // a symlink given as the source is followed.
+        assert!(skipped.is_empty());
+        assert_eq!(fs::read_to_string(&out).unwrap(), "top\n");
+    }
+
+    #[test]
+    fn file_size_cap() {
+        let root = create_source_tree();
+        let dest = TempDir::new().unwrap();
+        let out = dest.path().join("source-copy");
+        let source = root.path().join("source");
+        let skipped = copy_path(&source, &out, limits(32, 1024)).unwrap();
+        assert_eq!(
+            skipped,
+            vec![format!(
+                "{} is larger than 32 bytes",
+                source.join("a/huge.log").display()
+            )]
+        );
+        assert!(!out.join("a/huge.log").exists());
+        assert!(out.join("a/b/deep.conf").is_file());
+        assert!(out.join("top.conf").is_file());
+    }
+
+    #[test]
+    fn total_size_cap() {
+        let root = create_source_tree();
+        let dest = TempDir::new().unwrap();
+        let out = dest.path().join("source-copy");
+        let source = root.path().join("source");
+        // files are copied in sorted order: a/b/deep.conf (5), a/binary (5), a/huge.log (64),
+        // then top.conf (4), which still fits.
+        let skipped = copy_path(&source, &out, limits(1024, 16)).unwrap();
+        assert_eq!(
+            skipped,
+            vec![format!(
+                "{} would exceed the total of 16 bytes",
+                source.join("a/huge.log").display()
+            )]
+        );
+        assert!(out.join("a/b/deep.conf").is_file());
+        assert!(out.join("a/binary").is_file());
+        assert!(!out.join("a/huge.log").exists());
+        assert!(out.join("top.conf").is_file());
+    }
+
+    #[test]
+    fn missing_source() {
+        let root = TempDir::new().unwrap();
+        let dest = TempDir::new().unwrap();
+        let out = dest.path().join("kdump");
+        let skipped = copy_path(root.path().join("kdump"), &out, limits(32, 32)).unwrap();
+        assert!(skipped.is_empty());
+        assert!(!out.exists());
+    }
+}
@@ -1,22 +1,113 @@
 //! Provides a function for compressing a directory's contents into a tarball.
+//!
+//! The tarball is first written to `<output>.partial` in the same directory as the output, then
+//! synced and renamed to its final name, so a crash never leaves a partial archive at the output
+//! path. Because the partial file is in the output directory rather than the tempdir, the rename
+//! never crosses filesystems and is atomic from a reader's perspective.
 
 use crate::error::{self, Result};
+use std::collections::HashSet;
+use std::ffi::OsString;
 use std::fs::{self, File};
-use std::path::Path;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use flate2::write::GzEncoder;
 use flate2::Compression;
 use snafu::{ensure, OptionExt, ResultExt};
+use tar::{Header, HeaderMode};
+use walkdir::WalkDir;
+
+/// The suffix added to the output filename while the tarball is being written.
+const PARTIAL_SUFFIX: &str = ".partial";
+
+/// Partial tarballs older than this are assumed to be left over from a crashed run.
+const STALE_PARTIAL_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Returns the path that the tarball is written to before it's renamed to `outfile`.
+fn partial_path(outfile: &Path) -> PathBuf {
+    let mut filename = outfile
+        .file_name()
+        .map(|name| name.to_os_string())
+        .unwrap_or_else(OsString::new);
+    filename.push(PARTIAL_SUFFIX);
+    outfile.with_file_name(filename)
+}
+
+/// Removes partial tarballs that were left in the directory of `outfile` by earlier runs and are
+/// older than a day, as of `now`. Only our own partial file, and other files ending in
+/// `.tar.gz.partial`, are considered.
+pub(crate) fn remove_stale_partials<P: AsRef<Path>>(outfile: P, now: SystemTime) -> Result<()> {
+    let outfile = outfile.as_ref();
+    let outdir = outfile.parent().context(error::RootAsFile)?;
+    if !outdir.is_dir() {
+        return Ok(());
+    }
+    let own_partial = partial_path(outfile);
+    for entry in fs::read_dir(outdir).context(error::PartialRead { path: outdir })? {
+        let entry = entry.context(error::PartialRead { path: outdir })?;
+        let path = entry.path();
+        let is_partial = path == own_partial
+            || entry
+                .file_name()
+                .to_string_lossy()
+                .ends_with(&format!(".tar.gz{}", PARTIAL_SUFFIX));
+        if !is_partial {
+            continue;
+        }
+        let metadata = entry
+            .metadata()
+            .context(error::PartialRead { path: &path })?;
+        let modified = metadata
+            .modified()
+            .context(error::PartialRead { path: &path })?;
+        let age = now.duration_since(modified).unwrap_or_default();
+        if metadata.is_file() && age > STALE_PARTIAL_AGE {
+            fs::remove_file(&path).context(error::PartialRemove { path: &path })?;
+        }
+    }
+    Ok(())
+}
 
 /// Creates a tarball with all the contents of directory `dir`.
-pub(crate) fn create_tarball<P1, P2>(indir: P1, outfile: P2) -> Result<()>
+///
+/// The output is deterministic for a given set of input files: entries are added in sorted path
+/// order, every entry's mtime is set to `mtime`, and ownership is set to `0/0`. This means that two
+/// tarballs created from identical input directories with the same `mtime` are byte-for-byte
+/// identical.
+pub(crate) fn create_tarball<P1, P2>(indir: P1, outfile: P2, mtime: SystemTime) -> Result<()>
 where
     P1: AsRef<Path>,
     P2: AsRef<Path>,
 {
-    let indir = indir.as_ref();
-    let outfile = outfile.as_ref();
+    write_tarball(indir.as_ref(), outfile.as_ref(), mtime, None)
+}
+
+/// Creates a tarball like `create_tarball`, but with only the given `files` from `indir`, given as
+/// paths relative to `indir`, and the directories that contain them.
+pub(crate) fn create_partial_tarball<P1, P2>(
+    indir: P1,
+    files: &[String],
+    outfile: P2,
+    mtime: SystemTime,
+) -> Result<()>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let files: HashSet<PathBuf> = files.iter().map(PathBuf::from).collect();
+    write_tarball(indir.as_ref(), outfile.as_ref(), mtime, Some(&files))
+}
 
+/// Writes the tarball of `indir` to `outfile`, with every file if `include` is `None`, or only the
+/// files in `include` and the directories that contain them.
+fn write_tarball(
+    indir: &Path,
+    outfile: &Path,
+    mtime: SystemTime,
+    include: Option<&HashSet<PathBuf>>,
+) -> Result<()> {
     // ensure the output directory exists.
     let outdir = outfile.parent().context(error::RootAsFile)?;
     fs::create_dir_all(outdir).context(error::CreateOutputDirectory { path: outdir })?;
@@ -27,16 +118,76 @@ where
         error::TarballOutputIsInInputDir { indir, outfile }
     );
 
-    // compress files and create the tarball.
-    let tarfile = File::create(outfile).context(error::TarballFileCreate { path: outfile })?;
+    // times before the epoch can't be represented in the tarball, so we clamp them to zero.
+    let mtime = mtime
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+
+    // compress files into the partial tarball.
+    let partial = partial_path(outfile);
+    let tarfile = File::create(&partial).context(error::TarballFileCreate { path: &partial })?;
     let encoder = GzEncoder::new(tarfile, Compression::default());
     let mut tarball = tar::Builder::new(encoder);
-    tarball
-        .append_dir_all(crate::TARBALL_DIRNAME, indir)
-        .context(error::TarballWrite { path: outfile })?;
-    tarball
-        .finish()
-        .context(error::TarballClose { path: indir })
+
+    // walk the input directory in sorted order so that the entry order doesn't depend on the
+    // order in which the filesystem happens to return directory entries.
+    let walker = WalkDir::new(indir).sort_by(|a, b| a.file_name().cmp(b.file_name()));
+    for entry in walker {
+        let entry = entry.context(error::TarballWalk { path: indir })?;
+        let path = entry.path();
+        let relative_path = path
+            .strip_prefix(indir)
+            .context(error::TarballStripPrefix {
+                path,
+                prefix: indir,
+            })?;
+        let archive_path = Path::new(crate::TARBALL_DIRNAME).join(relative_path);
+        let metadata = entry
+            .metadata()
+            .context(error::TarballWalk { path: indir })?;
+        if let Some(include) = include {
+            let included = if metadata.is_dir() {
+                include.iter().any(|file| file.starts_with(relative_path))
+            } else {
+                include.contains(relative_path)
+            };
+            if !included {
+                continue;
+            }
+        }
+
+        let mut header = Header::new_gnu();
+        // deterministic mode sets the ownership to 0/0 and normalizes the permissions.
+        header.set_metadata_in_mode(&metadata, HeaderMode::Deterministic);
+        header.set_mtime(mtime);
+
+        if metadata.is_dir() {
+            tarball
+                .append_data(&mut header, &archive_path, io::empty())
+                .context(error::TarballWrite { path: &partial })?;
+        } else if metadata.is_file() {
+            let file = File::open(path).context(error::TarballFileOpen { path })?;
+            tarball
+                .append_data(&mut header, &archive_path, file)
+                .context(error::TarballWrite { path: &partial })?;
+        }
+    }
+
+    // finish the tar and gzip streams and make sure the data is on disk before it's renamed.
+    let tarfile = tarball
+        .into_inner()
+        .and_then(|encoder| encoder.finish())
+        .context(error::TarballClose { path: &partial })?;
+    tarfile
+        .sync_all()
+        .context(error::TarballSync { path: &partial })?;
+    drop(tarfile);
+
+    fs::rename(&partial, outfile).context(error::TarballRename {
+        from: &partial,
+        to: outfile,
+    })
 }
 
 #[cfg(test)]
@@ -63,7 +214,7 @@ mod tests {
         let outfilepath = outdir.path().join("somefile.tar.gz");
 
         // run the function under test.
-        create_tarball(&indir.path().to_path_buf(), &outfilepath).unwrap();
+        create_tarball(&indir.path().to_path_buf(), &outfilepath, SystemTime::now()).unwrap();
 
         // assert that the output tarball exists.
         assert!(Path::new(&outfilepath).is_file());
@@ -84,4 +235,137 @@ mod tests {
         let expected_path = PathBuf::from(crate::TARBALL_DIRNAME).join("hello.txt");
         assert!(actual_path == expected_path);
     }
+
+    #[test]
+    fn tarball_is_deterministic() {
+        // create an input directory with several files, written out of alphabetical order.
+        let indir = TempDir::new().unwrap();
+        fs::create_dir(indir.path().join("subdir")).unwrap();
+        for (name, content) in &[
+            ("zebra.txt", "z"),
+            ("apple.txt", "a"),
+            ("subdir/mango.txt", "m"),
+            ("middle.txt", "m"),
+        ] {
+            fs::write(indir.path().join(name), content).unwrap();
+        }
+
+        let outdir = TempDir::new().unwrap();
+        let first = outdir.path().join("first.tar.gz");
+        let second = outdir.path().join("second.tar.gz");
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+
+        create_tarball(indir.path(), &first, mtime).unwrap();
+        // touch one of the files so that its mtime on disk changes between runs.
+        fs::write(indir.path().join("apple.txt"), "a").unwrap();
+        create_tarball(indir.path(), &second, mtime).unwrap();
+
+        assert_eq!(fs::read(&first).unwrap(), fs::read(&second).unwrap());
+
+        // check that the entries are sorted and have normalized metadata.
+        let tar = GzDecoder::new(File::open(&first).unwrap());
+        let mut archive = Archive::new(tar);
+        let mut paths = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let entry = entry.unwrap();
+            let header = entry.header();
+            assert_eq!(header.mtime().unwrap(), 1_600_000_000);
+            assert_eq!(header.uid().unwrap(), 0);
+            assert_eq!(header.gid().unwrap(), 0);
+            paths.push(PathBuf::from(entry.path().unwrap()));
+        }
+        let root = PathBuf::from(crate::TARBALL_DIRNAME);
+        assert_eq!(
+            paths,
+            vec![
+                root.clone(),
+                root.join("apple.txt"),
+                root.join("middle.txt"),
+                root.join("subdir"),
+                root.join("subdir/mango.txt"),
+                root.join("zebra.txt"),
+            ]
+        );
+    }
+
+    #[test]
+    fn tarball_is_renamed_from_partial() {
+        let indir = TempDir::new().unwrap();
+        fs::write(indir.path().join("hello.txt"), "Hello World!").unwrap();
+        let outdir = TempDir::new().unwrap();
+        let outfile = outdir.path().join("logs.tar.gz");
+
+        // a partial file from an earlier run is replaced rather than appended to.
+        let partial = outdir.path().join("logs.tar.gz.partial");
+        fs::write(&partial, "garbage").unwrap();
+
+        create_tarball(indir.path(), &outfile, SystemTime::now()).unwrap();
+        assert!(outfile.is_file());
+        assert!(!partial.exists());
+
+        let tar = GzDecoder::new(File::open(&outfile).unwrap());
+        assert_eq!(Archive::new(tar).entries().unwrap().count(), 2);
+    }
+
+    #[test]
+    fn partial_tarball_has_only_given_files() {
+        let indir = TempDir::new().unwrap();
+        fs::create_dir(indir.path().join("kept")).unwrap();
+        fs::create_dir(indir.path().join("left-out")).unwrap();
+        for name in &["a.txt", "b.txt", "kept/c.txt", "left-out/d.txt"] {
+            fs::write(indir.path().join(name), name).unwrap();
+        }
+        let outdir = TempDir::new().unwrap();
+        let outfile = outdir.path().join("part.tar.gz");
+        let files = vec!["b.txt".to_string(), "kept/c.txt".to_string()];
+        create_partial_tarball(indir.path(), &files, &outfile, SystemTime::now()).unwrap();
+
+        let tar = GzDecoder::new(File::open(&outfile).unwrap());
+        let paths: Vec<PathBuf> = Archive::new(tar)
+            .entries()
+            .unwrap()
+            .map(|entry| PathBuf::from(entry.unwrap().path().unwrap()))
+            .collect();
+        let root = PathBuf::from(crate::TARBALL_DIRNAME);
+        assert_eq!(
+            paths,
+            vec![
+                root.clone(),
+                root.join("b.txt"),
+                root.join("kept"),
+                root.join("kept/c.txt"),
+            ]
+        );
+    }
+
+    #[test]
+    fn stale_partials_are_removed() {
+        let outdir = TempDir::new().unwrap();
+        let outfile = outdir.path().join("logs.out");
+        let own_partial = outdir.path().join("logs.out.partial");
+        let other_partial = outdir.path().join("other.tar.gz.partial");
+        let unrelated = outdir.path().join("download.partial");
+        for path in &[&own_partial, &other_partial, &unrelated] {
+            fs::write(path, "partial").unwrap();
+        }
+
+        // nothing is old enough to remove yet.
+        remove_stale_partials(&outfile, SystemTime::now()).unwrap();
+        assert!(own_partial.exists());
+        assert!(other_partial.exists());
+
+        // pretend two days have passed.
+        let later = SystemTime::now() + Duration::from_secs(2 * 24 * 60 * 60);
+        remove_stale_partials(&outfile, later).unwrap();
+        assert!(!own_partial.exists());
+        assert!(!other_partial.exists());
+        assert!(unrelated.exists());
+    }
+
+    #[test]
+    fn stale_partials_missing_outdir() {
+        let outdir = TempDir::new().unwrap();
+        let outfile = outdir.path().join("not-yet").join("logs.tar.gz");
+        remove_stale_partials(&outfile, SystemTime::now()).unwrap();
+    }
 }
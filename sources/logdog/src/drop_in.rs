@@ -0,0 +1,215 @@
+//! Reads extra log requests from drop-in files, so that variants and users can collect more
+//! diagnostics, e.g. `crictl ps` or `nvidia-smi`, without rebuilding logdog.
+//!
+//! Every file in `/etc/logdog.d` whose name ends in `.conf` is read, in order of file name.  Each
+//! line is a log request in the same format as the built-in list, e.g.
+//! `exec nvidia-smi nvidia-smi -q`; empty lines and lines starting with `#` are ignored.  The
+//! requests run after the built-in ones.
+//!
+//! A line that isn't a valid log request, or whose output filename is already used by a built-in
+//! request or an earlier drop-in line, is skipped and noted in `logdog.errors`, as is a file that
+//! can't be read.  Either way, the rest of the requests still run.
+
+use crate::error::{self, Error, Result};
+use crate::log_request::{output_filename, validate_log_request};
+use snafu::ResultExt;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The directory that drop-in files are read from.
+pub(crate) const DROP_IN_DIR: &str = "/etc/logdog.d";
+/// The extension of the files in `DROP_IN_DIR` that are read; others are ignored.
+const DROP_IN_EXTENSION: &str = "conf";
+
+/// A drop-in line, or a whole drop-in file, that was skipped.
+#[derive(Debug)]
+pub(crate) struct Skipped {
+    /// Where the line is, as `<path>:<line number>`, or the path of a file that couldn't be read.
+    pub(crate) location: String,
+    /// Why it was skipped.
+    pub(crate) error: Error,
+}
+
+/// The log requests read from the drop-in files, and the lines that were skipped.
+#[derive(Debug, Default)]
+pub(crate) struct DropIns {
+    pub(crate) requests: Vec<String>,
+    pub(crate) skipped: Vec<Skipped>,
+}
+
+/// Reads the drop-in files in `dir`, checking each request against `builtin`, the built-in log
+/// requests, and the drop-in requests before it.  There are no drop-ins if `dir` doesn't exist.
+pub(crate) fn read_drop_ins<P: AsRef<Path>>(dir: P, builtin: &[&str]) -> DropIns {
+    let dir = dir.as_ref();
+    let mut drop_ins = DropIns::default();
+    let paths = match drop_in_paths(dir) {
+        Ok(paths) => paths,
+        Err(error) => {
+            drop_ins.skipped.push(Skipped {
+                location: dir.display().to_string(),
+                error,
+            });
+            return drop_ins;
+        }
+    };
+
+    let mut filenames: HashSet<String> = builtin
+        .iter()
+        .filter_map(|request| output_filename(request))
+        .map(String::from)
+        .collect();
+    for path in paths {
+        let contents = match fs::read_to_string(&path).context(error::DropInRead { path: &path }) {
+            Ok(contents) => contents,
+            Err(error) => {
+                drop_ins.skipped.push(Skipped {
+                    location: path.display().to_string(),
+                    error,
+                });
+                continue;
+            }
+        };
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match validate_log_request(line, &mut filenames) {
+                Ok(()) => drop_ins.requests.push(line.to_string()),
+                Err(error) => drop_ins.skipped.push(Skipped {
+                    location: format!("{}:{}", path.display(), index + 1),
+                    error,
+                }),
+            }
+        }
+    }
+    drop_ins
+}
+
+/// Returns the paths of the drop-in files in `dir`, sorted by file name.
+fn drop_in_paths(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context(error::DropInRead { path: dir }),
+    };
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry.context(error::DropInRead { path: dir })?.path();
+        if path.is_file()
+            && path
+                .extension()
+                .map_or(false, |ext| ext == DROP_IN_EXTENSION)
+        {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    const BUILTIN: &[&str] = &["exec df df -h", "file os-release /etc/os-release"];
+
+    fn locations(drop_ins: &DropIns) -> Vec<String> {
+        drop_ins
+            .skipped
+            .iter()
+            .map(|skipped| skipped.location.clone())
+            .collect()
+    }
+
+    #[test]
+    fn well_formed() {
+        let dir = TempDir::new().unwrap();
+        fs::write(
+            dir.path().join("20-nvidia.conf"),
+            "# the GPUs\n\nexec nvidia-smi nvidia-smi -q\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("10-crictl.conf"),
+            "exec crictl-ps crictl ps\n  glob /var/log/crictl*\n",
+        )
+        .unwrap();
+        // only .conf files are read.
+        fs::write(dir.path().join("30-extra.conf.bak"), "exec extra echo hi\n").unwrap();
+        fs::create_dir(dir.path().join("40-dir.conf")).unwrap();
+
+        let drop_ins = read_drop_ins(dir.path(), BUILTIN);
+        assert_eq!(
+            drop_ins.requests,
+            vec![
+                "exec crictl-ps crictl ps",
+                "glob /var/log/crictl*",
+                "exec nvidia-smi nvidia-smi -q",
+            ]
+        );
+        assert!(drop_ins.skipped.is_empty());
+    }
+
+    #[test]
+    fn malformed() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("extra.conf");
+        fs::write(
+            &path,
+            "exec crictl-ps\nexec ../escape cat /etc/shadow\nscp a /etc/a\n\
+             exec logdog.errors echo hi\nexec nvidia-smi nvidia-smi -q\n",
+        )
+        .unwrap();
+
+        let drop_ins = read_drop_ins(dir.path(), BUILTIN);
+        // the valid line still runs.
+        assert_eq!(drop_ins.requests, vec!["exec nvidia-smi nvidia-smi -q"]);
+        let want: Vec<String> = (1..=4)
+            .map(|line| format!("{}:{}", path.display(), line))
+            .collect();
+        assert_eq!(locations(&drop_ins), want);
+        assert!(matches!(
+            drop_ins.skipped[0].error,
+            Error::InstructionsMissing { .. }
+        ));
+        assert!(matches!(
+            drop_ins.skipped[2].error,
+            Error::UnhandledRequest { .. }
+        ));
+    }
+
+    #[test]
+    fn duplicate_filenames() {
+        let dir = TempDir::new().unwrap();
+        let first = dir.path().join("a.conf");
+        let second = dir.path().join("b.conf");
+        fs::write(&first, "exec df df -hi\nexec crictl-ps crictl ps\n").unwrap();
+        fs::write(&second, "exec crictl-ps crictl ps -a\n").unwrap();
+
+        let drop_ins = read_drop_ins(dir.path(), BUILTIN);
+        // a drop-in can't replace a built-in request, and the first drop-in to use a name wins.
+        assert_eq!(drop_ins.requests, vec!["exec crictl-ps crictl ps"]);
+        assert_eq!(
+            locations(&drop_ins),
+            vec![
+                format!("{}:1", first.display()),
+                format!("{}:1", second.display()),
+            ]
+        );
+        for skipped in &drop_ins.skipped {
+            assert!(matches!(skipped.error, Error::DuplicateFilename { .. }));
+        }
+    }
+
+    #[test]
+    fn missing_dir() {
+        let dir = TempDir::new().unwrap();
+        let drop_ins = read_drop_ins(dir.path().join("logdog.d"), BUILTIN);
+        assert!(drop_ins.requests.is_empty());
+        assert!(drop_ins.skipped.is_empty());
+    }
+}
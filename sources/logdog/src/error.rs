@@ -9,6 +9,47 @@ use snafu::{Backtrace, Snafu};
 #[derive(Debug, Snafu)]
 #[snafu(visibility = "pub(crate)")]
 pub(crate) enum Error {
+    #[snafu(display("Error writing the clock file '{}': {}", path.display(), source))]
+    ClockWrite {
+        source: io::Error,
+        path: PathBuf,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("Error writing the bundle info file '{}': {}", path.display(), source))]
+    BundleInfoWrite {
+        source: io::Error,
+        path: PathBuf,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("Error reading the cgroup hierarchy at '{}': {}", path.display(), source))]
+    CgroupRead {
+        source: io::Error,
+        path: PathBuf,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("Error walking the cgroup directory '{}': {}", path.display(), source))]
+    CgroupWalk {
+        source: walkdir::Error,
+        path: PathBuf,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("No cgroup given for request '{}'", request))]
+    CgroupMissing { request: String },
+
+    #[snafu(display("Error writing the cgroup file '{}': {}", path.display(), source))]
+    CgroupWrite {
+        source: io::Error,
+        path: PathBuf,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("More than one log request writes to '{}'", filename))]
+    DuplicateFilename { filename: String },
+
     #[snafu(display("Error creating the command stderr file '{}': {}", path.display(), source))]
     CommandErrFile {
         source: io::Error,
@@ -16,6 +57,13 @@ pub(crate) enum Error {
         backtrace: Backtrace,
     },
 
+    #[snafu(display("Error removing the empty command stderr file '{}': {}", path.display(), source))]
+    CommandErrRemove {
+        source: io::Error,
+        path: PathBuf,
+        backtrace: Backtrace,
+    },
+
     #[snafu(display("Error completing command '{}': {}", command, source))]
     CommandFinish {
         command: String,
@@ -29,6 +77,13 @@ pub(crate) enum Error {
         backtrace: Backtrace,
     },
 
+    #[snafu(display(
+        "Command '{}' timed out after {} seconds and was killed; its output until then was kept",
+        command,
+        seconds
+    ))]
+    CommandTimeout { command: String, seconds: u64 },
+
     #[snafu(display("Error creating the command stdout file '{}': {}", path.display(), source))]
     CommandOutputFile {
         source: io::Error,
@@ -50,6 +105,42 @@ pub(crate) enum Error {
         backtrace: Backtrace,
     },
 
+    #[snafu(display("Error copying '{}' to '{}': {}", from.display(), to.display(), source))]
+    CopyFile {
+        source: io::Error,
+        from: PathBuf,
+        to: PathBuf,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("Error reading '{}' to copy it: {}", path.display(), source))]
+    CopyRead {
+        source: io::Error,
+        path: PathBuf,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display(
+        "Skipped files that are too large for request '{}': {}",
+        request,
+        skipped
+    ))]
+    CopySkipped { request: String, skipped: String },
+
+    #[snafu(display("Error walking the directory '{}' to copy it: {}", path.display(), source))]
+    CopyWalk {
+        source: walkdir::Error,
+        path: PathBuf,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("Error writing the copy '{}': {}", path.display(), source))]
+    CopyWrite {
+        source: io::Error,
+        path: PathBuf,
+        backtrace: Backtrace,
+    },
+
     #[snafu(display("The output directory '{}' could not be created: {}.", path.display(), source))]
     CreateOutputDirectory {
         source: io::Error,
@@ -57,8 +148,8 @@ pub(crate) enum Error {
         backtrace: Backtrace,
     },
 
-    #[snafu(display("Error creating the error file '{}': {}", path.display(), source))]
-    ErrorFile {
+    #[snafu(display("Error reading the drop-in log requests in '{}': {}", path.display(), source))]
+    DropInRead {
         source: io::Error,
         path: PathBuf,
         backtrace: Backtrace,
@@ -121,6 +212,37 @@ pub(crate) enum Error {
         source: std::io::Error,
     },
 
+    #[snafu(display("Log request has no instructions: '{}'", request))]
+    InstructionsMissing { request: String },
+
+    #[snafu(display(
+        "Invalid output filename '{}' in request '{}'; it must be a single file name made of letters, digits, '.', '-', and '_', and not a name that logdog uses itself",
+        filename,
+        request
+    ))]
+    InvalidFilename { filename: String, request: String },
+
+    #[snafu(display(
+        "Invalid timeout '{}' in request '{}'; it must be a positive number of seconds",
+        timeout,
+        request
+    ))]
+    InvalidTimeout { timeout: String, request: String },
+
+    #[snafu(display("Error creating the index file '{}': {}", path.display(), source))]
+    IndexFile {
+        source: io::Error,
+        path: PathBuf,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("Error writing to the index file '{}': {}", path.display(), source))]
+    IndexWrite {
+        source: io::Error,
+        path: PathBuf,
+        backtrace: Backtrace,
+    },
+
     #[snafu(display("Empty command."))]
     ModeMissing {},
 
@@ -133,9 +255,30 @@ pub(crate) enum Error {
     #[snafu(display("The logdog configuration has a 'glob' line with no glob instructions."))]
     PatternMissing {},
 
+    #[snafu(display("Error reading '{}' to find stale partial tarballs: {}", path.display(), source))]
+    PartialRead {
+        source: io::Error,
+        path: PathBuf,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("Error removing stale partial tarball '{}': {}", path.display(), source))]
+    PartialRemove {
+        source: io::Error,
+        path: PathBuf,
+        backtrace: Backtrace,
+    },
+
     #[snafu(display("Cannot write to / as a file."))]
     RootAsFile { backtrace: Backtrace },
 
+    #[snafu(display("Error reading the sizes of the files in '{}' to split the bundle: {}", path.display(), source))]
+    SplitSizes {
+        source: io::Error,
+        path: PathBuf,
+        backtrace: Backtrace,
+    },
+
     #[snafu(display("Error closing the tarball '{}': {}", path.display(), source))]
     TarballClose {
         source: io::Error,
@@ -150,6 +293,28 @@ pub(crate) enum Error {
         backtrace: Backtrace,
     },
 
+    #[snafu(display("Error moving the tarball from '{}' to '{}': {}", from.display(), to.display(), source))]
+    TarballRename {
+        source: io::Error,
+        from: PathBuf,
+        to: PathBuf,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("Error syncing the tarball '{}' to disk: {}", path.display(), source))]
+    TarballSync {
+        source: io::Error,
+        path: PathBuf,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("Error opening '{}' to add it to the tarball: {}", path.display(), source))]
+    TarballFileOpen {
+        source: io::Error,
+        path: PathBuf,
+        backtrace: Backtrace,
+    },
+
     #[snafu(display(
     "Output file '{}' can not be written to the directory that is being compressed '{}'.",
     outfile.display(),
@@ -161,6 +326,21 @@ pub(crate) enum Error {
         backtrace: Backtrace,
     },
 
+    #[snafu(display("Path '{}' is not under '{}': {}", path.display(), prefix.display(), source))]
+    TarballStripPrefix {
+        source: std::path::StripPrefixError,
+        path: PathBuf,
+        prefix: PathBuf,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("Error walking the directory '{}' for the tarball: {}", path.display(), source))]
+    TarballWalk {
+        source: walkdir::Error,
+        path: PathBuf,
+        backtrace: Backtrace,
+    },
+
     #[snafu(display("Error writing to the tarball '{}': {}", path.display(), source))]
     TarballWrite {
         source: io::Error,
@@ -176,6 +356,27 @@ pub(crate) enum Error {
 
     #[snafu(display("Unknown request type '{}' in '{}'", mode, request))]
     UnhandledRequest { mode: String, request: String },
+
+    #[snafu(display("Error writing the watch output '{}': {}", path.display(), source))]
+    WatchFile {
+        source: io::Error,
+        path: PathBuf,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("Error starting '{}' for the watch: {}", command, source))]
+    WatchSpawn {
+        command: String,
+        source: io::Error,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("Error stopping '{}' at the end of the watch: {}", command, source))]
+    WatchStop {
+        command: String,
+        source: io::Error,
+        backtrace: Backtrace,
+    },
 }
 
 pub(crate) type Result<T> = std::result::Result<T, Error>;
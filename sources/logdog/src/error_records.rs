@@ -0,0 +1,235 @@
+//! Collects the errors from drop-in files, log requests, and the watch in memory as structured
+//! records, and writes them once at the end of the run.  Nothing writes to the error files while
+//! commands are running, so the records don't depend on the order in which commands finish, and
+//! the same records are rendered into `logdog.errors` for humans and `errors.json` for tools.
+
+use crate::error::{self, Result};
+use serde_json::json;
+use snafu::ResultExt;
+use std::fmt::{self, Write as _};
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The part of the run in which an error happened.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Phase {
+    /// Reading the drop-in log requests.
+    DropIn,
+    /// Running a log request.
+    Request,
+    /// Capturing live activity for `--watch-seconds`.
+    Watch,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::DropIn => write!(f, "drop-in"),
+            Phase::Request => write!(f, "request"),
+            Phase::Watch => write!(f, "watch"),
+        }
+    }
+}
+
+/// One error, with the command that failed and when the command started.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ErrorRecord {
+    pub(crate) command: String,
+    pub(crate) phase: Phase,
+    pub(crate) message: String,
+    pub(crate) started: SystemTime,
+}
+
+impl ErrorRecord {
+    /// Returns the start time in milliseconds since the Unix epoch.
+    fn started_ms(&self) -> u64 {
+        self.started
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_millis() as u64)
+            .unwrap_or(0)
+    }
+}
+
+/// The errors of a run, rendered in order of the time their commands started.
+#[derive(Debug, Default)]
+pub(crate) struct ErrorRecords {
+    records: Vec<ErrorRecord>,
+}
+
+impl ErrorRecords {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Notes that `command` failed with `message` in `phase`, after starting at `started`.
+    pub(crate) fn record<C, M>(&mut self, command: C, phase: Phase, message: M, started: SystemTime)
+    where
+        C: Into<String>,
+        M: fmt::Display,
+    {
+        self.records.push(ErrorRecord {
+            command: command.into(),
+            phase,
+            message: message.to_string(),
+            started,
+        });
+    }
+
+    /// Returns the records sorted by start time.  The sort is stable, so records with the same
+    /// start time stay in the order they were noted.
+    fn sorted(&self) -> Vec<&ErrorRecord> {
+        let mut sorted: Vec<&ErrorRecord> = self.records.iter().collect();
+        sorted.sort_by_key(|record| record.started);
+        sorted
+    }
+
+    /// Returns the contents of `logdog.errors`, which keeps the line format of earlier versions.
+    pub(crate) fn render_text(&self) -> String {
+        let mut text = String::new();
+        for record in self.sorted() {
+            // writing to a String can't fail.
+            let _ = match record.phase {
+                Phase::Request => writeln!(
+                    text,
+                    "Error running command '{}': '{}'",
+                    record.command, record.message
+                ),
+                Phase::DropIn => writeln!(
+                    text,
+                    "Skipped drop-in request at '{}': '{}'",
+                    record.command, record.message
+                ),
+                Phase::Watch => writeln!(text, "Error watching: '{}'", record.message),
+            };
+        }
+        text
+    }
+
+    /// Returns the contents of `errors.json`, a list with an object for each record.
+    pub(crate) fn render_json(&self) -> String {
+        let records: Vec<serde_json::Value> = self
+            .sorted()
+            .into_iter()
+            .map(|record| {
+                json!({
+                    "command": record.command,
+                    "phase": record.phase.to_string(),
+                    "error": record.message,
+                    "started-ms": record.started_ms(),
+                })
+            })
+            .collect();
+        // a list of JSON values can always be serialized.
+        format!("{:#}\n", serde_json::Value::Array(records))
+    }
+
+    /// Writes both renderings to `outdir`.  The files are written even if there are no errors, so
+    /// their absence doesn't have to be explained.
+    pub(crate) fn write<P: AsRef<Path>>(&self, outdir: P) -> Result<()> {
+        let outdir = outdir.as_ref();
+        let text_path = outdir.join(crate::ERROR_FILENAME);
+        fs::write(&text_path, self.render_text()).context(error::ErrorWrite { path: text_path })?;
+        let json_path = outdir.join(crate::ERROR_JSON_FILENAME);
+        fs::write(&json_path, self.render_json()).context(error::ErrorWrite { path: json_path })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn at(ms: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(ms)
+    }
+
+    /// Records noted out of order, as they would be if commands finished out of order.
+    fn records() -> ErrorRecords {
+        let mut records = ErrorRecords::new();
+        records.record("watch", Phase::Watch, "journalctl not found", at(3000));
+        records.record(
+            "/etc/logdog.d/extra.conf:2",
+            Phase::DropIn,
+            "bad request",
+            at(500),
+        );
+        records.record("exec df df -h", Phase::Request, "timed out", at(2000));
+        records.record(
+            "file os-release /etc/os-release",
+            Phase::Request,
+            "gone",
+            at(1000),
+        );
+        records
+    }
+
+    #[test]
+    fn sorted_by_start_time() {
+        let records = records();
+        let commands: Vec<&str> = records
+            .sorted()
+            .iter()
+            .map(|record| record.command.as_str())
+            .collect();
+        assert_eq!(
+            commands,
+            vec![
+                "/etc/logdog.d/extra.conf:2",
+                "file os-release /etc/os-release",
+                "exec df df -h",
+                "watch"
+            ]
+        );
+    }
+
+    #[test]
+    fn same_start_time_keeps_order() {
+        let mut records = ErrorRecords::new();
+        records.record("second", Phase::Request, "b", at(1000));
+        records.record("first", Phase::Request, "a", at(0));
+        records.record("third", Phase::Request, "c", at(1000));
+        let commands: Vec<&str> = records
+            .sorted()
+            .iter()
+            .map(|record| record.command.as_str())
+            .collect();
+        assert_eq!(commands, vec!["first", "second", "third"]);
+    }
+
+    #[test]
+    fn render_text() {
+        assert_eq!(
+            records().render_text(),
+            "Skipped drop-in request at '/etc/logdog.d/extra.conf:2': 'bad request'\n\
+             Error running command 'file os-release /etc/os-release': 'gone'\n\
+             Error running command 'exec df df -h': 'timed out'\n\
+             Error watching: 'journalctl not found'\n"
+        );
+    }
+
+    #[test]
+    fn render_json() {
+        let rendered: serde_json::Value = serde_json::from_str(&records().render_json()).unwrap();
+        assert_eq!(
+            rendered,
+            json!([
+                {"command": "/etc/logdog.d/extra.conf:2", "phase": "drop-in",
+                 "error": "bad request", "started-ms": 500},
+                {"command": "file os-release /etc/os-release", "phase": "request",
+                 "error": "gone", "started-ms": 1000},
+                {"command": "exec df df -h", "phase": "request",
+                 "error": "timed out", "started-ms": 2000},
+                {"command": "watch", "phase": "watch",
+                 "error": "journalctl not found", "started-ms": 3000},
+            ])
+        );
+    }
+
+    #[test]
+    fn render_empty() {
+        let records = ErrorRecords::new();
+        assert_eq!(records.render_text(), "");
+        assert_eq!(records.render_json(), "[]\n");
+    }
+}
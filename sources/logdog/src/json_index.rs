@@ -0,0 +1,102 @@
+//! Provides a post-processing step that checks whether the outputs that are expected to be JSON
+//! actually parse as JSON, and records the result in an index file in the output directory. This
+//! helps people reading the logs notice when a tool, e.g. `ip -j`, changes the shape of its output.
+
+use crate::error::{self, Result};
+use crate::layout::LAYOUT_VERSION;
+use crate::log_request::output_filename;
+use snafu::ResultExt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+/// Outputs with this extension are expected to contain JSON.
+const JSON_EXTENSION: &str = ".json";
+
+/// Returns the annotation for an output, `yes` if `data` parses as JSON and `no` otherwise.
+fn json_validity(data: &[u8]) -> &'static str {
+    if serde_json::from_slice::<serde_json::Value>(data).is_ok() {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+/// Checks each output of `log_requests` whose filename ends in `.json` and writes a line like
+/// `ip-addr.json valid-json: yes` for each to the index file in `outdir`, after a first line with
+/// the layout version. Outputs that are missing,
+/// e.g. because the request failed, are annotated with `no`.
+pub(crate) fn write_json_index<P: AsRef<Path>>(log_requests: &[&str], outdir: P) -> Result<()> {
+    let outdir = outdir.as_ref();
+    let index_path = outdir.join(crate::INDEX_FILENAME);
+    let mut index_file = File::create(&index_path).context(error::IndexFile {
+        path: index_path.clone(),
+    })?;
+
+    writeln!(&mut index_file, "layout-version: {}", LAYOUT_VERSION).context(error::IndexWrite {
+        path: index_path.clone(),
+    })?;
+
+    let mut filenames: Vec<&str> = log_requests
+        .iter()
+        .filter_map(|&request| output_filename(request))
+        .filter(|filename| filename.ends_with(JSON_EXTENSION))
+        .collect();
+    filenames.sort_unstable();
+    filenames.dedup();
+
+    for filename in filenames {
+        let validity = fs::read(outdir.join(filename))
+            .map(|data| json_validity(&data))
+            .unwrap_or("no");
+        writeln!(&mut index_file, "{} valid-json: {}", filename, validity).context(
+            error::IndexWrite {
+                path: index_path.clone(),
+            },
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn validity() {
+        assert_eq!(json_validity(br#"[{"ifname": "eth0"}]"#), "yes");
+        assert_eq!(json_validity(b"[]\n"), "yes");
+        assert_eq!(json_validity(b"1: lo: <LOOPBACK,UP,LOWER_UP>"), "no");
+        assert_eq!(json_validity(b""), "no");
+    }
+
+    #[test]
+    fn index() {
+        let outdir = TempDir::new().unwrap();
+        fs::write(outdir.path().join("good.json"), r#"[{"dst": "default"}]"#).unwrap();
+        fs::write(
+            outdir.path().join("bad.json"),
+            "default via 10.0.0.1 dev eth0",
+        )
+        .unwrap();
+        fs::write(outdir.path().join("plain"), "not checked").unwrap();
+        let requests = vec![
+            "exec good.json ip -j route",
+            "exec bad.json ip route",
+            "exec missing.json ip -j neigh",
+            "exec plain echo not checked",
+            "glob /var/log/*.json",
+        ];
+        write_json_index(&requests, outdir.path()).unwrap();
+        let index = fs::read_to_string(outdir.path().join(crate::INDEX_FILENAME)).unwrap();
+        assert_eq!(
+            index,
+            format!(
+                "layout-version: {}\n{}",
+                LAYOUT_VERSION,
+                "bad.json valid-json: no\ngood.json valid-json: yes\nmissing.json valid-json: no\n"
+            )
+        );
+    }
+}
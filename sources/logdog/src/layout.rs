@@ -0,0 +1,187 @@
+//! Describes the layout of the files in the tarball, so that tools that parse the tarball can tell
+//! which files to expect. The layout version is written to `bundle-info` at the root of the tarball
+//! and to the first line of `logdog.index`.
+//!
+//! When an output file is added, register it in `OUTPUT_FILES` with the current layout version, or
+//! bump `LAYOUT_VERSION` if files have already been added since the last release. When an output
+//! file is renamed, bump `LAYOUT_VERSION`, change its entry in `OUTPUT_FILES`, and record the old
+//! name in `RENAMES`.
+
+use crate::error::{self, Result};
+use snafu::ResultExt;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+/// The version of the tarball layout.
+pub(crate) const LAYOUT_VERSION: u32 = 2;
+
+/// Every file that logdog itself names in the tarball, with the layout version that introduced it.
+/// Files collected by `glob` requests keep their original names and aren't included, nor are the
+/// `.stderr` files of `exec` requests, which are named after their output files.  Only the tests
+/// check it, so it's only compiled for them.
+#[cfg(test)]
+const OUTPUT_FILES: &[(&str, u32)] = &[
+    ("containerd-config", 1),
+    ("containerd-config-host", 1),
+    ("df", 1),
+    ("df-inodes", 1),
+    ("dmesg", 1),
+    ("docker-daemon.json", 1),
+    ("docker-info", 1),
+    ("ecs-agent-state.json", 1),
+    ("ecs-config.json", 1),
+    ("ecs-tasks", 1),
+    ("ipamd.log", 1),
+    ("iptables-filter", 1),
+    ("iptables-nat", 1),
+    ("journalctl-boots", 1),
+    ("journalctl.errors", 1),
+    ("journalctl.log", 1),
+    ("kube-status", 1),
+    ("logdog.errors", 1),
+    ("os-release", 1),
+    ("plugin.log", 1),
+    ("proc-mounts", 1),
+    ("settings.json", 1),
+    ("signpost", 1),
+    ("wicked", 1),
+    ("bundle-info", 2),
+    ("cgroup-containerd", 2),
+    ("cgroup-kubelet", 2),
+    ("chronyc-tracking", 2),
+    ("clock.json", 2),
+    ("cni-config", 2),
+    ("docker-ps", 2),
+    ("ecs-agent-metadata.json", 2),
+    ("ecs-agent.log", 2),
+    ("ecs-settings.json", 2),
+    ("errors.json", 2),
+    ("ip-addr.json", 2),
+    ("ip-link-stats-watch", 2),
+    ("ip-neigh.json", 2),
+    ("ip-route.json", 2),
+    ("ip-route-ipv6.json", 2),
+    ("journalctl-watch.log", 2),
+    ("kdump", 2),
+    ("kernel-lockdown", 2),
+    ("logdog.index", 2),
+    ("meminfo", 2),
+    ("pressure-cpu", 2),
+    ("pressure-io", 2),
+    ("pressure-memory", 2),
+    ("resolv.conf", 2),
+    ("selinux-avc-denials", 2),
+    ("selinux-enforce", 2),
+    ("settings-journal", 2),
+    ("settings-provenance", 2),
+    ("timedatectl", 2),
+    ("top", 2),
+];
+
+/// A file that was renamed in a layout version.
+struct Rename {
+    /// The layout version in which the file was renamed.
+    layout_version: u32,
+    /// The name of the file in earlier layout versions.
+    old: &'static str,
+    /// The name of the file as of `layout_version`.
+    new: &'static str,
+}
+
+/// Files that have been renamed, oldest first. Layout 2 only added files, so this is empty so far.
+const RENAMES: &[Rename] = &[];
+
+/// Returns the contents of the `bundle-info` file.
+fn bundle_info() -> String {
+    let mut info = format!(
+        "layout-version: {}\nlogdog-version: {}\n",
+        LAYOUT_VERSION,
+        env!("CARGO_PKG_VERSION")
+    );
+    for rename in RENAMES {
+        // writing to a String can't fail.
+        let _ = writeln!(
+            info,
+            "renamed-in-layout-{}: {} -> {}",
+            rename.layout_version, rename.old, rename.new
+        );
+    }
+    info
+}
+
+/// Writes the `bundle-info` file, which describes the tarball for humans, to `outdir`.
+pub(crate) fn write_bundle_info<P: AsRef<Path>>(outdir: P) -> Result<()> {
+    let path = outdir.as_ref().join(crate::BUNDLE_INFO_FILENAME);
+    fs::write(&path, bundle_info()).context(error::BundleInfoWrite { path })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::log_request::output_filename;
+
+    /// The log requests for every variant; `log_request` only includes the current variant's.
+    const ALL_REQUESTS: &[&str] = &[
+        include_str!("../conf/logdog.common.conf"),
+        include_str!("../conf/aws-k8s.conf"),
+        include_str!("../conf/logdog.aws-dev.conf"),
+        include_str!("../conf/logdog.aws-ecs-1.conf"),
+        include_str!("../conf/vmware-k8s.conf"),
+    ];
+
+    fn registered(filename: &str) -> bool {
+        OUTPUT_FILES.iter().any(|(name, _)| *name == filename)
+    }
+
+    #[test]
+    fn every_output_file_is_registered() {
+        let mut filenames = vec![
+            crate::ERROR_FILENAME,
+            crate::ERROR_JSON_FILENAME,
+            crate::INDEX_FILENAME,
+            crate::BUNDLE_INFO_FILENAME,
+            crate::clock::CLOCK_FILENAME,
+            crate::watch::JOURNAL_FILENAME,
+            crate::watch::LINK_STATS_FILENAME,
+        ];
+        filenames.extend(
+            ALL_REQUESTS
+                .iter()
+                .flat_map(|requests| requests.lines())
+                .filter(|line| !line.is_empty() && !line.trim_start().starts_with('#'))
+                .filter_map(output_filename),
+        );
+        for filename in filenames {
+            assert!(
+                registered(filename),
+                "'{}' is not registered in the layout's OUTPUT_FILES",
+                filename
+            );
+        }
+    }
+
+    #[test]
+    fn layout_version_is_current() {
+        for (name, version) in OUTPUT_FILES {
+            assert!(
+                *version <= LAYOUT_VERSION,
+                "'{}' was added in layout {}, bump LAYOUT_VERSION",
+                name,
+                version
+            );
+        }
+        for rename in RENAMES {
+            assert!(rename.layout_version <= LAYOUT_VERSION);
+            assert!(registered(rename.new));
+            assert!(!registered(rename.old));
+        }
+    }
+
+    #[test]
+    fn bundle_info_contents() {
+        let info = bundle_info();
+        assert!(info.starts_with(&format!("layout-version: {}\n", LAYOUT_VERSION)));
+        assert!(info.contains(&format!("logdog-version: {}\n", env!("CARGO_PKG_VERSION"))));
+    }
+}
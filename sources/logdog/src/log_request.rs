@@ -7,16 +7,32 @@
 //! file which points to the log requests for the current variant. This file is named `logdog.conf`.
 //! We load `logdog.conf` and `logdog.common.conf` files into static strings at compile time, and
 //! these provide the list of log requests that `logdog` will run.
+//!
+//! The requests run in the order they appear in the files, common requests first, so the order is
+//! the same on every run.  The list is checked by `validate_log_requests` before anything runs,
+//! and by a unit test over every variant's list, so a duplicate output filename or an empty
+//! request is caught during development rather than on a host.
+//!
+//! # Drop-in Log Requests
+//!
+//! Requests can also be added on a host, without rebuilding, in drop-in files; see the `drop_in`
+//! module.  They're checked the same way, but one that fails is skipped rather than failing the
+//! run.
 
+use crate::cgroup::copy_cgroup;
+use crate::copy::copy_request;
 use crate::error::{self, Result};
+use crate::timeout::wait_with_timeout;
 use glob::glob;
 use reqwest::blocking::{Client, Response};
 use snafu::{ensure, OptionExt, ResultExt};
 use std::collections::HashSet;
 use std::fs;
 use std::fs::File;
+use std::io;
 use std::path::Path;
 use std::process::{Command, Stdio};
+use std::time::Duration;
 use url::Url;
 use walkdir::WalkDir;
 
@@ -36,6 +52,38 @@ pub(crate) fn log_requests() -> Vec<&'static str> {
         .collect()
 }
 
+/// How long the command of an `exec` request can run before it's killed, unless the request gives
+/// its own timeout.
+const DEFAULT_EXEC_TIMEOUT: Duration = Duration::from_secs(30);
+/// The suffix added to an `exec` request's output filename for the file its stderr is written to.
+const STDERR_SUFFIX: &str = ".stderr";
+/// The prefix of the optional first word of an `exec` request's instructions that gives its
+/// timeout in seconds, e.g. `timeout=120`.
+const TIMEOUT_PREFIX: &str = "timeout=";
+
+/// The modes that `handle_log_request` knows how to run.
+const MODES: &[&str] = &[
+    "exec",
+    "http",
+    "https",
+    "file",
+    "optional-file",
+    "glob",
+    "cgroup",
+    "copy",
+];
+
+/// The files that logdog writes itself, which log requests can't use as output filenames.
+const RESERVED_FILENAMES: &[&str] = &[
+    crate::BUNDLE_INFO_FILENAME,
+    crate::clock::CLOCK_FILENAME,
+    crate::ERROR_FILENAME,
+    crate::ERROR_JSON_FILENAME,
+    crate::INDEX_FILENAME,
+    crate::watch::JOURNAL_FILENAME,
+    crate::watch::LINK_STATS_FILENAME,
+];
+
 /// A logdog `LogRequest` represents a line from the config file. It starts with a "mode" that
 /// specifies what type of request it is, e.g. `exec ` for a command or `http` for an HTTP get
 /// request. Some modes then require a `filename` that determines where the data will be saved in
@@ -53,6 +101,17 @@ pub(crate) fn log_requests() -> Vec<&'static str> {
 /// exec hello.txt echo hello world
 /// ```
 ///
+/// The command's stderr is written to a separate file, named after the output file with `.stderr`
+/// added, e.g. `hello.txt.stderr`.  The stderr file is removed if the command wrote nothing to it.
+///
+/// A command that's still running after 30 seconds is killed, and the output it wrote until then is
+/// kept.  An `exec` request can give a different timeout in seconds as the first word of its
+/// instructions.  This request lets `journalctl` run for up to two minutes:
+///
+/// ```text
+/// exec journalctl.log timeout=120 journalctl -a --no-pager
+/// ```
+///
 /// This request will run an HTTP get request to the url `http://example.com` and write the response
 /// body to `example.txt`:
 ///
@@ -66,15 +125,38 @@ pub(crate) fn log_requests() -> Vec<&'static str> {
 /// file some-conf /etc/some/conf
 /// ```
 ///
+/// This request will copy `/var/lib/app/state.json`, if it exists, to `state.json` in a directory
+/// named `app-state`.  Nothing is written, and no error is recorded, if the file doesn't exist:
+///
+/// ```text
+/// optional-file app-state /var/lib/app/state.json
+/// ```
+///
 /// This request will copy files with a known prefix into the tarball; this can be useful for dated
 /// log files, for example.
 ///
 /// ```text
 /// glob /var/log/my-app.log*
 /// ```
+///
+/// This request will copy the statistics of the `system.slice/kubelet.service` cgroup from each
+/// cgroup controller into a directory named `cgroup-kubelet`:
+///
+/// ```text
+/// cgroup cgroup-kubelet system.slice/kubelet.service
+/// ```
+///
+/// This request will copy the directory `/var/log/kdump`, if it exists, with its subdirectories, to
+/// a directory named `kdump`.  Given a file instead, `copy` copies it to the output filename, like
+/// `file` does, but within the same size limits; see the `copy` module.
+///
+/// ```text
+/// copy kdump /var/log/kdump
+/// ```
 #[derive(Debug, Clone)]
 struct LogRequest<'a> {
-    /// The log request mode. For example `exec`, `http`, `file`, or `glob`.
+    /// The log request mode. For example `exec`, `http`, `file`, `optional-file`, `glob`,
+    /// `cgroup`, or `copy`.
     mode: &'a str,
     /// The filename that the logs will be written to, if appropriate for the mode.
     filename: &'a str,
@@ -95,13 +177,95 @@ impl ToString for LogRequest<'_> {
     }
 }
 
-/// Runs a `LogRequest` and writes its output to a file in `tempdir`.
-pub(crate) fn handle_log_request<S, P>(request: S, tempdir: P) -> Result<()>
-where
-    S: AsRef<str>,
-    P: AsRef<Path>,
-{
-    let request = request.as_ref();
+/// Returns the output filename of a log request, or `None` for requests like `glob` that don't have
+/// a single output file.
+pub(crate) fn output_filename(request: &str) -> Option<&str> {
+    let mut iter = request.splitn(3, ' ');
+    match iter.next() {
+        Some("glob") | None => None,
+        Some(_) => iter.next().filter(|filename| !filename.is_empty()),
+    }
+}
+
+/// Returns true if `filename` can be used as an output filename: a single path component that
+/// isn't hidden, made of ASCII letters, digits, `.`, `-`, and `_`.
+pub(crate) fn is_valid_filename(filename: &str) -> bool {
+    !filename.is_empty()
+        && !filename.starts_with('.')
+        && filename
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+}
+
+/// Checks that every request has a known mode and instructions, and that each output filename is
+/// valid and used by only one request.
+pub(crate) fn validate_log_requests(requests: &[&str]) -> Result<()> {
+    let mut filenames = HashSet::new();
+    for &request in requests {
+        validate_log_request(request, &mut filenames)?;
+    }
+    Ok(())
+}
+
+/// Checks that `request` has a known mode and instructions, and that its output filename is valid
+/// and not in `filenames`, the output filenames of the requests already checked.  The filename is
+/// added to `filenames` if the request is valid.
+pub(crate) fn validate_log_request(request: &str, filenames: &mut HashSet<String>) -> Result<()> {
+    let req = parse_log_request(request)?;
+    ensure!(
+        MODES.contains(&req.mode),
+        error::UnhandledRequest {
+            mode: req.mode,
+            request,
+        }
+    );
+    ensure!(
+        !req.instructions.trim().is_empty(),
+        error::InstructionsMissing { request }
+    );
+    // glob requests keep the names of the files they copy.
+    if req.mode == "glob" {
+        return Ok(());
+    }
+    if req.mode == "exec" {
+        exec_command(&req)?;
+    }
+    ensure!(
+        is_valid_filename(req.filename) && !RESERVED_FILENAMES.contains(&req.filename),
+        error::InvalidFilename {
+            filename: req.filename,
+            request,
+        }
+    );
+    ensure!(
+        !filenames.contains(req.filename),
+        error::DuplicateFilename {
+            filename: req.filename,
+        }
+    );
+    filenames.insert(req.filename.to_string());
+    // the stderr file of an exec request can't be another request's output file either.
+    if req.mode == "exec" {
+        let stderr_filename = stderr_filename(req.filename);
+        ensure!(
+            !filenames.contains(&stderr_filename),
+            error::DuplicateFilename {
+                filename: stderr_filename,
+            }
+        );
+        filenames.insert(stderr_filename);
+    }
+    Ok(())
+}
+
+/// Returns the name of the file that an `exec` request with output filename `filename` writes its
+/// stderr to.
+fn stderr_filename(filename: &str) -> String {
+    format!("{}{}", filename, STDERR_SUFFIX)
+}
+
+/// Splits a log request line into a `LogRequest`.
+fn parse_log_request(request: &str) -> Result<LogRequest<'_>> {
     let mut iter = request.splitn(3, ' ');
     let mode = iter.next().context(error::ModeMissing)?;
     let req = if mode == "glob" {
@@ -120,12 +284,26 @@ where
             instructions: iter.next().unwrap_or(""),
         }
     };
+    Ok(req)
+}
+
+/// Runs a `LogRequest` and writes its output to a file in `tempdir`.
+pub(crate) fn handle_log_request<S, P>(request: S, tempdir: P) -> Result<()>
+where
+    S: AsRef<str>,
+    P: AsRef<Path>,
+{
+    let request = request.as_ref();
+    let req = parse_log_request(request)?;
     // execute the log request with the correct handler based on the mode field.
     match req.mode {
         "exec" => handle_exec_request(&req, tempdir)?,
         "http" | "https" => handle_http_request(&req, tempdir)?,
         "file" => handle_file_request(&req, tempdir)?,
+        "optional-file" => handle_optional_file_request(&req, tempdir)?,
         "glob" => handle_glob_request(&req, tempdir)?,
+        "cgroup" => handle_cgroup_request(&req, tempdir)?,
+        "copy" => handle_copy_request(&req, tempdir)?,
         unmatched => {
             return Err(error::Error::UnhandledRequest {
                 mode: unmatched.into(),
@@ -136,34 +314,103 @@ where
     Ok(())
 }
 
-/// Runs an `exec` `LogRequest`'s `instructions` and writes its output to to `tempdir`.
+/// The command of an `exec` `LogRequest`, and how long it can run.
+#[derive(Debug, Clone, PartialEq)]
+struct ExecCommand {
+    /// The program and its arguments.
+    argv: Vec<String>,
+    timeout: Duration,
+}
+
+/// Splits an `exec` `LogRequest`'s `instructions` into its timeout, if it gives one, and the
+/// program and its arguments.  Commands are run directly rather than through a shell, so shell
+/// syntax like pipes is passed to the program as plain arguments; filtering has to be done with the
+/// program's own flags.
+fn exec_command(request: &LogRequest<'_>) -> Result<ExecCommand> {
+    let mut argv =
+        shell_words::split(request.instructions).with_context(|| error::CommandParse {
+            command: request.to_string(),
+        })?;
+    let timeout = match argv
+        .first()
+        .and_then(|word| word.strip_prefix(TIMEOUT_PREFIX))
+    {
+        Some(seconds) => match seconds.parse::<u64>() {
+            Ok(seconds) if seconds > 0 => Some(Duration::from_secs(seconds)),
+            _ => {
+                return error::InvalidTimeout {
+                    timeout: seconds,
+                    request: request.to_string(),
+                }
+                .fail()
+            }
+        },
+        None => None,
+    };
+    if timeout.is_some() {
+        argv.remove(0);
+    }
+    ensure!(
+        !argv.is_empty(),
+        error::CommandMissing {
+            request: request.to_string(),
+        }
+    );
+    Ok(ExecCommand {
+        argv,
+        timeout: timeout.unwrap_or(DEFAULT_EXEC_TIMEOUT),
+    })
+}
+
+/// Runs an `exec` `LogRequest`'s `instructions` and writes its stdout and stderr to separate files
+/// in `tempdir`.  The stderr file is removed if it's empty.
 fn handle_exec_request<P>(request: &LogRequest<'_>, tempdir: P) -> Result<()>
 where
     P: AsRef<Path>,
 {
-    let split = shell_words::split(request.instructions).with_context(|| error::CommandParse {
-        command: request.to_string(),
-    })?;
-    let (command, args) = split.split_first().with_context(|| error::CommandMissing {
-        request: request.to_string(),
-    })?;
+    let exec = exec_command(request)?;
+    let (command, args) = exec
+        .argv
+        .split_first()
+        .with_context(|| error::CommandMissing {
+            request: request.to_string(),
+        })?;
     let outpath = tempdir.as_ref().join(request.filename);
+    let errpath = tempdir.as_ref().join(stderr_filename(request.filename));
     let ofile = File::create(&outpath).context(error::CommandOutputFile { path: &outpath })?;
-    let stderr_file = ofile
-        .try_clone()
-        .context(error::CommandErrFile { path: &outpath })?;
-    Command::new(command)
+    let stderr_file = File::create(&errpath).context(error::CommandErrFile { path: &errpath })?;
+    // the output goes straight to the files, so whatever was written before a timeout is kept.
+    let finished = Command::new(command)
         .args(args)
         .stdout(Stdio::from(ofile))
         .stderr(Stdio::from(stderr_file))
         .spawn()
         .with_context(|| error::CommandSpawn {
             command: request.to_string(),
-        })?
-        .wait_with_output()
-        .with_context(|| error::CommandFinish {
+        })
+        .and_then(|mut child| {
+            wait_with_timeout(&mut child, exec.timeout).with_context(|| error::CommandFinish {
+                command: request.to_string(),
+            })
+        });
+    // most commands write nothing to stderr, and their empty stderr files would only clutter the
+    // tarball.
+    remove_if_empty(&errpath).context(error::CommandErrRemove { path: &errpath })?;
+    ensure!(
+        finished?,
+        error::CommandTimeout {
             command: request.to_string(),
-        })?;
+            seconds: exec.timeout.as_secs(),
+        }
+    );
+    Ok(())
+}
+
+/// Removes the file at `path` if it's empty.
+fn remove_if_empty(path: &Path) -> io::Result<()> {
+    if fs::metadata(path)?.len() == 0 {
+        fs::remove_file(path)?;
+    }
     Ok(())
 }
 
@@ -227,6 +474,33 @@ where
     Ok(())
 }
 
+/// Copies the file at the path given by `request.instructions`, if it exists, into a directory in
+/// the tempdir named by `request.filename`, keeping its file name.
+fn handle_optional_file_request<P>(request: &LogRequest<'_>, tempdir: P) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    ensure!(
+        !request.instructions.is_empty(),
+        error::FileFromEmpty {
+            request: request.to_string()
+        }
+    );
+    let source = Path::new(request.instructions);
+    if !source.exists() {
+        return Ok(());
+    }
+    let dest_dir = tempdir.as_ref().join(request.filename);
+    fs::create_dir_all(&dest_dir).context(error::CreateOutputDirectory { path: &dest_dir })?;
+    let dest = dest_dir.join(source.file_name().context(error::RootAsFile)?);
+    let _ = fs::copy(source, &dest).with_context(|| error::FileCopy {
+        request: request.to_string(),
+        from: request.instructions,
+        to: &dest,
+    })?;
+    Ok(())
+}
+
 /// Copies all files matching the glob pattern given by `request.instructions` to the tempdir with filename and path
 /// same as source file.
 fn handle_glob_request<P>(request: &LogRequest<'_>, tempdir: P) -> Result<()>
@@ -275,12 +549,55 @@ where
     Ok(())
 }
 
+/// Copies the cgroup given by `request.instructions` from each cgroup controller to a directory in
+/// the tempdir named by `request.filename`.
+fn handle_cgroup_request<P>(request: &LogRequest<'_>, tempdir: P) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    ensure!(
+        !request.instructions.is_empty(),
+        error::CgroupMissing {
+            request: request.to_string()
+        }
+    );
+    copy_cgroup(
+        request.instructions,
+        tempdir.as_ref().join(request.filename),
+    )
+}
+
+/// Copies the file or directory given by `request.instructions` to the tempdir with the name given
+/// by `request.filename`.
+fn handle_copy_request<P>(request: &LogRequest<'_>, tempdir: P) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    ensure!(
+        !request.instructions.is_empty(),
+        error::FileFromEmpty {
+            request: request.to_string()
+        }
+    );
+    copy_request(
+        &request.to_string(),
+        request.instructions,
+        tempdir.as_ref().join(request.filename),
+    )
+}
+
 #[cfg(test)]
 mod test {
-    use crate::log_request::handle_log_request;
+    use crate::error::Error;
+    use crate::log_request::{
+        exec_command, handle_log_request, log_requests, output_filename, parse_log_request,
+        validate_log_requests, COMMON_REQUESTS, DEFAULT_EXEC_TIMEOUT,
+    };
+    use httptest::{matchers::*, responders::*, Expectation, Server};
     use std::fs;
     use std::fs::write;
     use std::path::PathBuf;
+    use std::time::{Duration, Instant};
     use tempfile::TempDir;
 
     // adds a sub directory and some files to temp directory for file request tests
@@ -341,6 +658,69 @@ mod test {
         assert_eq!(got, want);
     }
 
+    #[test]
+    fn optional_file_request() {
+        let source_dir = TempDir::new().unwrap();
+        let source_filepath = source_dir.path().join("sources.json");
+        let want = "{}";
+        write(&source_filepath, want).unwrap();
+        let request = format!("optional-file provenance {}", source_filepath.display());
+        let outdir = TempDir::new().unwrap();
+        handle_log_request(&request, outdir.path()).unwrap();
+        let got = std::fs::read_to_string(outdir.path().join("provenance/sources.json")).unwrap();
+        assert_eq!(got, want);
+    }
+
+    #[test]
+    fn optional_file_request_missing() {
+        let source_dir = TempDir::new().unwrap();
+        let request = format!(
+            "optional-file provenance {}",
+            source_dir.path().join("sources.json").display()
+        );
+        let outdir = TempDir::new().unwrap();
+        handle_log_request(&request, outdir.path()).unwrap();
+        assert!(!outdir.path().join("provenance").exists());
+    }
+
+    #[test]
+    fn copy_dir_request() {
+        let source_dir = TempDir::new().unwrap();
+        create_source_dir(&source_dir);
+        let request = format!("copy source {}", source_dir.path().display());
+        let outdir = TempDir::new().unwrap();
+        handle_log_request(&request, outdir.path()).unwrap();
+        assert_file_match(&outdir, PathBuf::from("source/foo.source"), "1");
+        assert_file_match(
+            &outdir,
+            PathBuf::from("source/depth1/depth2/for-bar.log"),
+            "3",
+        );
+
+        let request = format!(
+            "copy foo {}",
+            source_dir.path().join("foo.source").display()
+        );
+        handle_log_request(&request, outdir.path()).unwrap();
+        assert_file_match(&outdir, PathBuf::from("foo"), "1");
+    }
+
+    #[test]
+    fn copy_request_too_large() {
+        let source_dir = TempDir::new().unwrap();
+        let huge = source_dir.path().join("huge.log");
+        fs::write(&huge, vec![b'x'; 10 * 1024 * 1024 + 1]).unwrap();
+        write(source_dir.path().join("small.log"), "small").unwrap();
+        let request = format!("copy logs {}", source_dir.path().display());
+        let outdir = TempDir::new().unwrap();
+        let err = handle_log_request(&request, outdir.path()).unwrap_err();
+        // the rest is still copied, and the error names what was skipped.
+        assert!(matches!(err, Error::CopySkipped { ref skipped, .. }
+            if skipped.contains(huge.to_str().unwrap())));
+        assert_file_match(&outdir, PathBuf::from("logs/small.log"), "small");
+        assert!(!outdir.path().join("logs/huge.log").exists());
+    }
+
     #[test]
     fn exec_request() {
         let want = "hello world! \"quoted\"\n";
@@ -350,6 +730,17 @@ mod test {
         let outfile = outdir.path().join("output-file.txt");
         let got = std::fs::read_to_string(&outfile).unwrap();
         assert_eq!(got, want);
+        // nothing was written to stderr, so there's no stderr file.
+        assert!(!outdir.path().join("output-file.txt.stderr").exists());
+    }
+
+    #[test]
+    fn exec_request_stderr() {
+        let request = "exec streams sh -c 'echo out; echo err >&2'";
+        let outdir = TempDir::new().unwrap();
+        handle_log_request(&request, outdir.path()).unwrap();
+        assert_file_match(&outdir, PathBuf::from("streams"), "out\n");
+        assert_file_match(&outdir, PathBuf::from("streams.stderr"), "err\n");
     }
 
     #[test]
@@ -447,6 +838,313 @@ mod test {
         );
     }
 
+    /// The common log requests, followed by each variant's.
+    fn all_variant_requests() -> Vec<(&'static str, Vec<&'static str>)> {
+        let common = include_str!("../conf/logdog.common.conf");
+        let variants = [
+            ("aws-k8s", include_str!("../conf/aws-k8s.conf")),
+            ("aws-dev", include_str!("../conf/logdog.aws-dev.conf")),
+            ("aws-ecs-1", include_str!("../conf/logdog.aws-ecs-1.conf")),
+            ("vmware-k8s", include_str!("../conf/vmware-k8s.conf")),
+        ];
+        variants
+            .iter()
+            .map(|(variant, requests)| {
+                let requests = common
+                    .lines()
+                    .chain(requests.lines())
+                    .filter(|line| !line.is_empty() && !line.trim_start().starts_with('#'))
+                    .collect();
+                (*variant, requests)
+            })
+            .collect()
+    }
+
+    /// Returns the output filenames of the log requests for `variant`.
+    fn variant_filenames(variant: &str) -> Vec<&'static str> {
+        all_variant_requests()
+            .into_iter()
+            .find(|(name, _)| *name == variant)
+            .unwrap_or_else(|| panic!("no log requests for {}", variant))
+            .1
+            .into_iter()
+            .filter_map(output_filename)
+            .collect()
+    }
+
+    const K8S_FILENAMES: &[&str] = &["kube-status", "cgroup-kubelet", "ipamd.log", "plugin.log"];
+    const ECS_FILENAMES: &[&str] = &[
+        "ecs-agent.log",
+        "ecs-settings.json",
+        "ecs-agent-metadata.json",
+        "docker-ps",
+    ];
+
+    #[test]
+    // ensures ECS variants collect ECS state and none of the Kubernetes logs
+    fn ecs_requests() {
+        let filenames = variant_filenames("aws-ecs-1");
+        for filename in ECS_FILENAMES {
+            assert!(filenames.contains(filename), "missing {}", filename);
+        }
+        for filename in K8S_FILENAMES {
+            assert!(!filenames.contains(filename), "unexpected {}", filename);
+        }
+    }
+
+    #[test]
+    // ensures Kubernetes variants don't collect ECS state
+    fn k8s_requests() {
+        for variant in &["aws-k8s", "vmware-k8s"] {
+            let filenames = variant_filenames(variant);
+            assert!(filenames.contains(&"kube-status"), "{}", variant);
+            for filename in ECS_FILENAMES {
+                assert!(!filenames.contains(filename), "{}: {}", variant, filename);
+            }
+        }
+    }
+
+    #[test]
+    fn http_request() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/v1/metadata"))
+                .times(1)
+                .respond_with(status_code(200).body(r#"{"Cluster":"default"}"#)),
+        );
+        let outdir = TempDir::new().unwrap();
+        let request = format!(
+            "http ecs-agent-metadata.json {}",
+            server.url("/v1/metadata")
+        );
+        handle_log_request(&request, outdir.path()).unwrap();
+        assert_file_match(
+            &outdir,
+            PathBuf::from("ecs-agent-metadata.json"),
+            r#"{"Cluster":"default"}"#,
+        );
+    }
+
+    #[test]
+    fn http_request_error_status() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/v1/metadata"))
+                .times(1)
+                .respond_with(status_code(500)),
+        );
+        let outdir = TempDir::new().unwrap();
+        let request = format!(
+            "http ecs-agent-metadata.json {}",
+            server.url("/v1/metadata")
+        );
+        let err = handle_log_request(&request, outdir.path()).unwrap_err();
+        assert!(matches!(err, Error::HttpResponse { .. }));
+        assert!(!outdir.path().join("ecs-agent-metadata.json").exists());
+    }
+
+    #[test]
+    fn http_request_unreachable() {
+        // nothing is listening once the server is dropped.
+        let url = Server::run().url("/v1/metadata");
+        let outdir = TempDir::new().unwrap();
+        let request = format!("http ecs-agent-metadata.json {}", url);
+        let err = handle_log_request(&request, outdir.path()).unwrap_err();
+        assert!(matches!(err, Error::HttpSend { .. }));
+    }
+
+    #[test]
+    // ensures every variant's list of log requests passes the startup checks
+    fn static_requests_are_valid() {
+        validate_log_requests(&log_requests()).unwrap();
+        for (variant, requests) in all_variant_requests() {
+            if let Err(e) = validate_log_requests(&requests) {
+                panic!("log requests for {} are invalid: {}", variant, e);
+            }
+        }
+    }
+
+    #[test]
+    fn duplicate_filename() {
+        let requests = [
+            "exec df df -h",
+            "file os-release /etc/os-release",
+            "exec df df -hi",
+        ];
+        let err = validate_log_requests(&requests).unwrap_err();
+        assert!(matches!(err, Error::DuplicateFilename { filename } if filename == "df"));
+
+        // an exec request's stderr file counts as one of its output files.
+        for requests in &[
+            ["exec df df -h", "file df.stderr /etc/df"],
+            ["file df.stderr /etc/df", "exec df df -h"],
+        ] {
+            let err = validate_log_requests(requests).unwrap_err();
+            assert!(
+                matches!(err, Error::DuplicateFilename { ref filename } if filename == "df.stderr")
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_requests() {
+        for request in &[
+            "exec df",
+            "exec df   ",
+            "file os-release",
+            "cgroup cgroup-kubelet",
+            "glob",
+            "exec ../df df -h",
+            "exec a/df df -h",
+            "exec .hidden df -h",
+            "exec logdog.index df -h",
+            "copy os-release",
+            "scp df /etc/df",
+            "exec df timeout=0 df -h",
+            "exec df timeout=5",
+        ] {
+            assert!(
+                validate_log_requests(&[request]).is_err(),
+                "'{}' should be invalid",
+                request
+            );
+        }
+        // globs don't have an output filename.
+        validate_log_requests(&["glob /var/log/*.log", "glob /var/log/*.log"]).unwrap();
+    }
+
+    /// Returns the argument vector of the common `exec` request that writes `filename`.
+    fn common_argv(filename: &str) -> Vec<String> {
+        let request = include_str!("../conf/logdog.common.conf")
+            .lines()
+            .filter(|line| !line.trim_start().starts_with('#'))
+            .map(|line| parse_log_request(line).unwrap())
+            .find(|req| req.mode == "exec" && req.filename == filename)
+            .unwrap_or_else(|| panic!("no exec request for {}", filename));
+        exec_command(&request).unwrap().argv
+    }
+
+    #[test]
+    // ensures the security status requests run the intended commands
+    fn security_status_argv() {
+        assert_eq!(
+            common_argv("selinux-enforce"),
+            vec!["cat", "/sys/fs/selinux/enforce"]
+        );
+        assert_eq!(
+            common_argv("kernel-lockdown"),
+            vec!["cat", "/sys/kernel/security/lockdown"]
+        );
+        assert_eq!(
+            common_argv("selinux-avc-denials"),
+            vec![
+                "journalctl",
+                "--no-pager",
+                "--lines=200",
+                "_TRANSPORT=audit",
+                "_AUDIT_TYPE_NAME=AVC",
+            ]
+        );
+    }
+
+    /// The units whose journals show where each setting came from.
+    const SETTINGS_UNITS: &[&str] = &["early-boot-config", "sundog", "settings-applier"];
+
+    #[test]
+    // ensures the settings journal covers every unit that generates settings
+    fn settings_journal_argv() {
+        let mut want = vec!["journalctl".to_string()];
+        for unit in SETTINGS_UNITS {
+            want.extend(vec!["-u".to_string(), unit.to_string()]);
+        }
+        want.extend(vec!["-a".to_string(), "--no-pager".to_string()]);
+        assert_eq!(common_argv("settings-journal"), want);
+    }
+
+    #[test]
+    // ensures no exec request relies on a shell, since commands aren't run through one
+    fn exec_requests_have_no_shell_syntax() {
+        let requests = include_str!("../conf/logdog.common.conf")
+            .lines()
+            .filter(|line| !line.is_empty() && !line.trim_start().starts_with('#'))
+            .map(|line| parse_log_request(line).unwrap())
+            .filter(|req| req.mode == "exec");
+        for request in requests {
+            for arg in exec_command(&request).unwrap().argv {
+                assert!(
+                    !["|", "&&", "||", ";", ">", "<"].contains(&arg.as_str()),
+                    "'{}' uses shell syntax",
+                    request.to_string()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn exec_command_missing_command() {
+        let request = parse_log_request("exec df   ").unwrap();
+        assert!(matches!(
+            exec_command(&request),
+            Err(Error::CommandMissing { .. })
+        ));
+    }
+
+    #[test]
+    fn exec_command_timeout() {
+        let request = parse_log_request("exec journalctl.log timeout=120 journalctl -a").unwrap();
+        let exec = exec_command(&request).unwrap();
+        assert_eq!(exec.timeout, Duration::from_secs(120));
+        assert_eq!(exec.argv, vec!["journalctl", "-a"]);
+
+        let request = parse_log_request("exec df df -h").unwrap();
+        assert_eq!(
+            exec_command(&request).unwrap().timeout,
+            DEFAULT_EXEC_TIMEOUT
+        );
+
+        for request in &[
+            "exec df timeout=0 df -h",
+            "exec df timeout=soon df -h",
+            "exec df timeout= df -h",
+        ] {
+            let request = parse_log_request(request).unwrap();
+            assert!(matches!(
+                exec_command(&request),
+                Err(Error::InvalidTimeout { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn common_journal_requests_timeout() {
+        // reading the whole journal can take longer than the default on a busy host.
+        for filename in &["journalctl.errors", "journalctl.log", "settings-journal"] {
+            let request = COMMON_REQUESTS
+                .lines()
+                .map(|line| parse_log_request(line).unwrap())
+                .find(|request| request.mode == "exec" && request.filename == *filename)
+                .unwrap();
+            assert_eq!(
+                exec_command(&request).unwrap().timeout,
+                Duration::from_secs(120),
+                "{}",
+                filename
+            );
+        }
+    }
+
+    #[test]
+    fn exec_request_timeout() {
+        let outdir = TempDir::new().unwrap();
+        let started = Instant::now();
+        let request = "exec sleepy timeout=1 sh -c 'echo started; exec sleep 300'";
+        let err = handle_log_request(request, outdir.path()).unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(matches!(err, Error::CommandTimeout { seconds: 1, .. }));
+        // the output from before the timeout is kept.
+        assert_file_match(&outdir, PathBuf::from("sleepy"), "started\n");
+    }
+
     #[test]
     // ensure if pattern is empty it should not panic
     fn glob_empty_pattern_request() {
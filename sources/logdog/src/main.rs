@@ -10,6 +10,15 @@ $ logdog
 logs are at: /tmp/bottlerocket-logs.tar.gz
 ```
 
+For intermittent issues, `--watch-seconds N` also captures the next N seconds of activity after
+the normal collection: the journal is followed into `journalctl-watch.log`, and `ip -s link` is
+sampled every 5 seconds into `ip-link-stats-watch`.
+Each of these commands is killed if it runs past the window, or for a sample, past the next one.
+
+When it's done, `logdog` prints a summary to stderr so the bundle can be checked before it's
+uploaded: how many log requests ran, succeeded, failed, or were skipped because their program isn't
+installed, the size of the tarball, and the three largest files in it.
+
 # Logs
 
 For the log requests used to gather logs, please see the following:
@@ -19,34 +28,126 @@ For the log requests used to gather logs, please see the following:
 * And the variant-specific files in [conf](conf/), one of which is selected by [build.rs](build.rs)
 based on the value of the `VARIANT` environment variable at build time.
 
+Kubernetes variants collect the kubelet's status and the CNI plugin logs, and ECS variants collect
+the ECS agent's journal, the ECS settings, the agent's metadata from its introspection endpoint,
+and the state of Docker containers instead.
+
+Every variant collects the journals of early-boot-config, sundog, and settings-applier into
+`settings-journal`, and, if early-boot-config recorded which source produced each setting, its
+`provenance/sources.json` under `settings-provenance/`, to show where each setting came from.
+
+Plain files and directories are collected with `copy` requests, which keep the bytes of binary
+files and the structure of directories, e.g. `/var/log/kdump` into `kdump/` if the kernel has
+crashed, and on Kubernetes variants, the CNI configuration in `/etc/cni/net.d` into `cni-config/`.
+Files larger than 10 MiB are skipped, as are files past 100 MiB in all for one request, and the
+skipped files are listed in `logdog.errors`.
+
+The log requests are checked before any are run: each must have a known mode and instructions,
+and each output filename must be a plain file name that no other request uses.
+
+The stdout and stderr of `exec` requests are written to separate files, so warnings aren't mixed
+into the output: stderr goes to the output filename with `.stderr` added, e.g.
+`iptables-filter.stderr`, which is only kept if the command wrote something to it.
+
+A command that's still running after 30 seconds is killed, so a hung command can't stall the
+collection, and its output until then is kept.  The timeout is noted in `logdog.errors`.  An `exec`
+request can give its own timeout as the first word of its command, e.g.
+`exec journalctl.log timeout=120 journalctl -a --no-pager`.
+
+More log requests can be added without rebuilding logdog, e.g. for `crictl ps` or `nvidia-smi`, in
+drop-in files in `/etc/logdog.d`.  Every file there whose name ends in `.conf` is read in order of
+file name, and each line is a log request in the same format as the built-in ones, like
+`exec nvidia-smi nvidia-smi -q`.  They run after the built-in requests.  A drop-in line that fails
+the checks, e.g. because its output filename is already used, is skipped and noted in
+`logdog.errors` rather than stopping the run.
+
+Errors from drop-in files, log requests, and the watch are collected as the run goes and written
+once at the end, in order of the time each command started: `logdog.errors` has a line for each, in
+the format of earlier versions, and `errors.json` has an object for each with the command, the
+phase (`drop-in`, `request`, or `watch`), the error, and the start time in milliseconds since the
+Unix epoch.
+For a drop-in line, the command is its location, like `/etc/logdog.d/extra.conf:2`.
+
+To help diagnose time skew, which shows up as TLS and TUF expiration failures, every variant
+collects `timedatectl status` and, if chrony is installed, `chronyc tracking`.  logdog also writes
+`clock.json` itself, with the wall-clock time in milliseconds since the Unix epoch, the seconds
+since boot, the time of the hardware clock (RTC) from `/sys/class/rtc/rtc0/since_epoch`, and how
+many seconds the wall clock is ahead of the RTC.  A clock that can't be read is replaced by an
+`-error` key with the reason.
+
+Outputs whose names end in `.json` are checked after collection, and the result is recorded in
+`logdog.index` as a line like `ip-addr.json valid-json: yes`.
+
+The layout of the tarball is versioned so that tools that parse it can tell which files to expect.
+The layout version is the first line of `logdog.index`, and `bundle-info` at the root of the
+tarball records the layout version and logdog version for humans.
+
+The tarball is written to `<output>.partial` and renamed to the output path once it's complete, so
+the output path never holds a partial archive.
+Partial tarballs older than a day that were left behind by an earlier run are removed at startup.
+
+For upload channels that limit the size of attachments, `--max-archive-size BYTES` splits the logs
+into several numbered tarballs if the collected files add up to more than `BYTES`, e.g.
+`bottlerocket-logs.1of3.tar.gz`, and prints the path of each.
+Files are grouped greedily by size, and `logdog.index` and `bundle-info` are copied into every part.
+A file that's larger than the limit by itself goes alone in its own part, with a warning.
+
 */
 
 #![deny(rust_2018_idioms)]
 
+mod cgroup;
+mod clock;
+mod copy;
 mod create_tarball;
+mod drop_in;
 mod error;
+mod error_records;
+mod json_index;
+mod layout;
 mod log_request;
+mod split;
+mod summary;
+mod timeout;
+mod watch;
 
-use create_tarball::create_tarball;
+use clock::write_clock;
+use create_tarball::{create_partial_tarball, create_tarball, remove_stale_partials};
+use drop_in::{read_drop_ins, DROP_IN_DIR};
 use error::Result;
-use log_request::{handle_log_request, log_requests};
+use error_records::{ErrorRecords, Phase};
+use json_index::write_json_index;
+use layout::write_bundle_info;
+use log_request::{handle_log_request, log_requests, validate_log_requests};
 use snafu::{ErrorCompat, ResultExt};
-use std::fs::File;
-use std::io::Write;
+use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 use std::{env, process};
+use summary::{file_sizes, format_summary, Outcome};
 use tempfile::TempDir;
 
+const BUNDLE_INFO_FILENAME: &str = "bundle-info";
 const ERROR_FILENAME: &str = "logdog.errors";
+const ERROR_JSON_FILENAME: &str = "errors.json";
+const INDEX_FILENAME: &str = "logdog.index";
 const OUTPUT_FILENAME: &str = "bottlerocket-logs.tar.gz";
 const TARBALL_DIRNAME: &str = "bottlerocket-logs";
+/// The files that are copied into every part of a split bundle, so each part can be read alone.
+const SHARED_FILES: &[&str] = &[BUNDLE_INFO_FILENAME, INDEX_FILENAME];
 
 /// Prints a usage message in the event a bad arg is passed.
 fn usage() -> ! {
     let program_name = env::args().next().unwrap_or_else(|| "program".to_string());
     eprintln!(
         r"Usage: {}
-            [ --output PATH ]       where to write archived logs
+            [ --output PATH ]           where to write archived logs
+            [ --watch-seconds N ]       also capture the next N seconds of the journal and
+                                        link statistics
+            [ --max-archive-size BYTES ]
+                                        split the logs into several archives if they're
+                                        larger than this
 ",
         program_name,
     );
@@ -59,9 +160,21 @@ fn usage_msg(msg: &str) -> ! {
     usage();
 }
 
+/// Stores user-supplied arguments.
+struct Args {
+    /// Where to write the tarball.
+    output: PathBuf,
+    /// How long to capture live activity for, if at all.
+    watch: Option<Duration>,
+    /// The size above which the logs are split into several archives, if any.
+    max_archive_size: Option<u64>,
+}
+
 /// Parses the command line arguments.
-fn parse_args(args: env::Args) -> PathBuf {
+fn parse_args(args: env::Args) -> Args {
     let mut output_arg = None;
+    let mut watch = None;
+    let mut max_archive_size = None;
     let mut iter = args.skip(1);
     while let Some(arg) = iter.next() {
         match arg.as_ref() {
@@ -71,59 +184,179 @@ fn parse_args(args: env::Args) -> PathBuf {
                         .unwrap_or_else(|| usage_msg("Did not give argument to --output")),
                 )
             }
+            "--watch-seconds" => {
+                let seconds = iter
+                    .next()
+                    .unwrap_or_else(|| usage_msg("Did not give argument to --watch-seconds"));
+                match seconds.parse::<u64>() {
+                    Ok(seconds) if seconds > 0 => watch = Some(Duration::from_secs(seconds)),
+                    _ => usage_msg("--watch-seconds must be a positive number of seconds"),
+                }
+            }
+            "--max-archive-size" => {
+                let bytes = iter
+                    .next()
+                    .unwrap_or_else(|| usage_msg("Did not give argument to --max-archive-size"));
+                match bytes.parse::<u64>() {
+                    Ok(bytes) if bytes > 0 => max_archive_size = Some(bytes),
+                    _ => usage_msg("--max-archive-size must be a positive number of bytes"),
+                }
+            }
             _ => usage(),
         }
     }
 
-    match output_arg {
+    let output = match output_arg {
         Some(path) => PathBuf::from(path),
         None => env::temp_dir().as_path().join(OUTPUT_FILENAME),
+    };
+    Args {
+        output,
+        watch,
+        max_archive_size,
     }
 }
 
-/// Runs a list of log requests and writes their output into files in `outdir`. Any failures are
-/// noted in the file named by `ERROR_FILENAME`. Note: In the case of `exec` log requests, non-zero
-/// exit codes are not considered errors and the command's stdout and stderr will be still be
-/// written.
-pub(crate) fn collect_logs<P: AsRef<Path>>(log_requests: &[&str], outdir: P) -> Result<()> {
-    // if a command fails, we will pipe its error here and continue.
+/// Runs a list of log requests and writes their output into files in `outdir`, returning the
+/// outcome of each. Any failures are noted in `errors`, which are written to the error files at the
+/// end of the run. Note: In the case of `exec` log requests, non-zero exit codes are not considered
+/// errors and the command's stdout and stderr will be still be written.
+pub(crate) fn collect_logs<P: AsRef<Path>>(
+    log_requests: &[&str],
+    outdir: P,
+    errors: &mut ErrorRecords,
+) -> Vec<Outcome> {
     let outdir = outdir.as_ref();
-    let error_path = outdir.join(crate::ERROR_FILENAME);
-    let mut error_file = File::create(&error_path).context(error::ErrorFile {
-        path: error_path.clone(),
-    })?;
-
+    let mut outcomes = Vec::with_capacity(log_requests.len());
     for &log_request in log_requests {
         // show the user what command we are running
         println!("Running: {}", log_request);
-        if let Err(e) = handle_log_request(log_request, &outdir) {
-            // ignore the error, but make note of it in the error file.
-            write!(
-                &mut error_file,
-                "Error running command '{}': '{}'\n",
-                log_request, e
-            )
-            .context(error::ErrorWrite {
-                path: error_path.clone(),
-            })?;
+        let started = SystemTime::now();
+        let result = handle_log_request(log_request, &outdir);
+        outcomes.push(Outcome::of(&result));
+        if let Err(e) = result {
+            // ignore the error, but make note of it for the error files.
+            errors.record(log_request, Phase::Request, e, started);
         }
     }
-    Ok(())
+    outcomes
+}
+
+/// Writes the tarball of `indir` to `outfile`, or if its files add up to more than
+/// `max_archive_size`, splits them into several tarballs named after `outfile`.  Returns the paths
+/// of the tarballs.
+fn write_archives(
+    indir: &Path,
+    outfile: &Path,
+    mtime: SystemTime,
+    max_archive_size: Option<u64>,
+) -> Result<Vec<PathBuf>> {
+    let (max_size, files) = match max_archive_size {
+        Some(max_size) => (
+            max_size,
+            file_sizes(indir).context(error::SplitSizes { path: indir })?,
+        ),
+        None => {
+            create_tarball(indir, outfile, mtime)?;
+            return Ok(vec![outfile.to_path_buf()]);
+        }
+    };
+    if !split::needs_split(&files, max_size) {
+        create_tarball(indir, outfile, mtime)?;
+        return Ok(vec![outfile.to_path_buf()]);
+    }
+
+    let plan = split::plan_parts(&files, SHARED_FILES, max_size);
+    for name in &plan.oversized {
+        eprintln!(
+            "Warning: '{}' is larger than --max-archive-size, so it's in a part of its own",
+            name
+        );
+    }
+    let count = plan.parts.len();
+    let mut paths = Vec::with_capacity(count);
+    for (index, part) in plan.parts.iter().enumerate() {
+        let path = split::part_path(outfile, index + 1, count);
+        let mut part_files = part.files.clone();
+        part_files.extend(SHARED_FILES.iter().map(|name| name.to_string()));
+        create_partial_tarball(indir, &part_files, &path, mtime)?;
+        paths.push(path);
+    }
+    Ok(paths)
 }
 
-/// Runs the bulk of the program's logic, main wraps this.
-fn run(outfile: &Path, commands: &[&str]) -> Result<()> {
+/// Runs the bulk of the program's logic, main wraps this.  The log requests in the drop-in files
+/// in `drop_in_dir` run after `commands`.  If `watch` is given, live activity is captured for that
+/// long after the log requests run.  If `max_archive_size` is given, the logs are split into
+/// several tarballs if they're larger than that.
+fn run(
+    outfile: &Path,
+    commands: &[&str],
+    drop_in_dir: &Path,
+    watch: Option<Duration>,
+    max_archive_size: Option<u64>,
+) -> Result<()> {
+    validate_log_requests(commands)?;
+    // every entry in the tarball is stamped with the time that collection started.
+    let start_time = SystemTime::now();
+    // a crashed run may have left a partial tarball behind; this isn't fatal if it fails.
+    if let Err(e) = remove_stale_partials(&outfile, start_time) {
+        eprintln!("Unable to remove stale partial tarballs: {}", e);
+    }
     let temp_dir = TempDir::new().context(error::TempDirCreate)?;
-    collect_logs(&commands, &temp_dir.path().to_path_buf())?;
-    create_tarball(&temp_dir.path().to_path_buf(), &outfile)?;
-    println!("logs are at: {}", outfile.display());
+    let mut errors = ErrorRecords::new();
+    // drop-in requests that can't run are skipped and noted, rather than failing the run.
+    let drop_ins = read_drop_ins(drop_in_dir, commands);
+    for skipped in drop_ins.skipped {
+        errors.record(skipped.location, Phase::DropIn, skipped.error, start_time);
+    }
+    let mut commands = commands.to_vec();
+    commands.extend(drop_ins.requests.iter().map(String::as_str));
+    // the clocks are read first, so they're close to the start time that stamps the tarball.
+    write_clock(temp_dir.path())?;
+    let outcomes = collect_logs(&commands, temp_dir.path(), &mut errors);
+    if let Some(window) = watch {
+        println!("Watching for {} seconds", window.as_secs());
+        // like a failed log request, a failed watch is noted and the bundle is still written.
+        let started = SystemTime::now();
+        if let Err(e) = watch::watch(temp_dir.path(), window) {
+            errors.record("watch", Phase::Watch, e, started);
+        }
+    }
+    errors.write(temp_dir.path())?;
+    write_json_index(&commands, temp_dir.path())?;
+    write_bundle_info(temp_dir.path())?;
+    // the summary is only informational, so it's left out if the sizes can't be read.
+    let files = file_sizes(temp_dir.path());
+    let archives = write_archives(temp_dir.path(), outfile, start_time, max_archive_size)?;
+    let archive_size = archives
+        .iter()
+        .map(|archive| fs::metadata(archive).map(|metadata| metadata.len()))
+        .sum::<io::Result<u64>>();
+    match (files, archive_size) {
+        (Ok(files), Ok(archive_size)) => {
+            eprint!("{}", format_summary(&outcomes, archive_size, &files))
+        }
+        (Err(e), _) => eprintln!("Unable to summarize bundle: {}", e),
+        (_, Err(e)) => eprintln!("Unable to summarize bundle: {}", e),
+    }
+    for archive in &archives {
+        println!("logs are at: {}", archive.display());
+    }
     Ok(())
 }
 
 fn main() -> ! {
-    let outpath = parse_args(env::args());
+    let args = parse_args(env::args());
     let log_requests = log_requests();
-    process::exit(match run(&outpath, &log_requests) {
+    let result = run(
+        &args.output,
+        &log_requests,
+        Path::new(DROP_IN_DIR),
+        args.watch,
+        args.max_archive_size,
+    );
+    process::exit(match result {
         Ok(()) => 0,
         Err(err) => {
             eprintln!("{}", err);
@@ -144,6 +377,7 @@ mod tests {
     use super::*;
     use flate2::read::GzDecoder;
     use std::fs::File;
+    use std::time::Instant;
     use tar::Archive;
 
     #[test]
@@ -151,9 +385,34 @@ mod tests {
         let output_tempdir = TempDir::new().unwrap();
         let outfile = output_tempdir.path().join("logstest");
 
+        // a stand-in for the provenance that early-boot-config records.
+        let provenance_dir = TempDir::new().unwrap();
+        let provenance = provenance_dir.path().join("sources.json");
+        fs::write(&provenance, "{}").unwrap();
+        let provenance_request =
+            format!("optional-file settings-provenance {}", provenance.display());
+        let missing_request = format!(
+            "optional-file missing-provenance {}",
+            provenance_dir.path().join("missing.json").display()
+        );
+
+        // a drop-in that adds a request, and one that clashes with a built-in request.
+        let drop_in_dir = provenance_dir.path().join("logdog.d");
+        fs::create_dir(&drop_in_dir).unwrap();
+        fs::write(
+            drop_in_dir.join("extra.conf"),
+            "exec extra.txt echo extra\nexec hello.txt echo clash\n",
+        )
+        .unwrap();
+
         // we assume that `echo` will not do something unexpected on the machine running this test.
-        let commands = vec!["exec hello.txt echo hello world"];
-        run(&outfile, &commands).unwrap();
+        let commands = vec![
+            "exec hello.txt echo hello world",
+            "exec streams.txt sh -c 'echo out; echo err >&2'",
+            &provenance_request,
+            &missing_request,
+        ];
+        run(&outfile, &commands, &drop_in_dir, None, None).unwrap();
 
         // this function will panic if the given path is not found in the tarball.
         let find = |path_to_find: &PathBuf| {
@@ -174,5 +433,106 @@ mod tests {
         // assert that the expected paths exist in the tarball
         find(&PathBuf::from(TARBALL_DIRNAME));
         find(&PathBuf::from(TARBALL_DIRNAME).join("hello.txt"));
+        find(&PathBuf::from(TARBALL_DIRNAME).join("settings-provenance/sources.json"));
+        find(&PathBuf::from(TARBALL_DIRNAME).join("extra.txt"));
+        // a command that writes to both streams has a file for each.
+        find(&PathBuf::from(TARBALL_DIRNAME).join("streams.txt"));
+        find(&PathBuf::from(TARBALL_DIRNAME).join("streams.txt.stderr"));
+
+        // the clashing drop-in request is skipped and noted, and the built-in request still runs.
+        let read = |name: &str| {
+            let tar = GzDecoder::new(File::open(&outfile).unwrap());
+            let mut archive = Archive::new(tar);
+            let mut entry = archive
+                .entries()
+                .unwrap()
+                .map(|entry| entry.unwrap())
+                .find(|entry| {
+                    PathBuf::from(entry.path().unwrap())
+                        == PathBuf::from(TARBALL_DIRNAME).join(name)
+                })
+                .unwrap();
+            let mut contents = String::new();
+            io::Read::read_to_string(&mut entry, &mut contents).unwrap();
+            contents
+        };
+        assert_eq!(read("hello.txt"), "hello world\n");
+        assert_eq!(read("streams.txt"), "out\n");
+        assert_eq!(read("streams.txt.stderr"), "err\n");
+        let errors = read(ERROR_FILENAME);
+        assert!(errors.contains("extra.conf:2"), "{}", errors);
+        assert!(errors.contains("More than one log request writes to 'hello.txt'"));
+
+        // a missing optional file leaves nothing behind.
+        let tar = GzDecoder::new(File::open(&outfile).unwrap());
+        let paths: Vec<PathBuf> = Archive::new(tar)
+            .entries()
+            .unwrap()
+            .map(|entry| PathBuf::from(entry.unwrap().path().unwrap()))
+            .collect();
+        let root = PathBuf::from(TARBALL_DIRNAME);
+        assert!(!paths
+            .iter()
+            .any(|path| path.starts_with(root.join("missing-provenance"))));
+        // a command that writes nothing to stderr has no stderr file.
+        assert!(!paths.contains(&root.join("hello.txt.stderr")));
+    }
+
+    #[test]
+    fn timeout_is_recorded() {
+        let outdir = TempDir::new().unwrap();
+        let mut errors = ErrorRecords::new();
+        let started = Instant::now();
+        let outcomes = collect_logs(
+            &[
+                "exec sleepy timeout=1 sleep 300",
+                "exec hello.txt echo hello",
+            ],
+            outdir.path(),
+            &mut errors,
+        );
+        // the hung command doesn't hold up the next one.
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(outcomes, vec![Outcome::Failed, Outcome::Succeeded]);
+        let text = errors.render_text();
+        assert!(text.contains("Error running command 'exec sleepy timeout=1 sleep 300'"));
+        assert!(text.contains("timed out after 1 seconds"), "{}", text);
+    }
+
+    #[test]
+    fn test_program_split() {
+        let output_tempdir = TempDir::new().unwrap();
+        let outfile = output_tempdir.path().join("logstest.tar.gz");
+
+        // two files that don't fit in one part together.
+        let commands = vec![
+            "exec a.txt head -c 3000 /dev/zero",
+            "exec b.txt head -c 3000 /dev/zero",
+        ];
+        let drop_in_dir = output_tempdir.path().join("logdog.d");
+        run(&outfile, &commands, &drop_in_dir, None, Some(4000)).unwrap();
+        assert!(!outfile.exists());
+
+        let root = PathBuf::from(TARBALL_DIRNAME);
+        let mut logs = Vec::new();
+        for part in &["logstest.1of2.tar.gz", "logstest.2of2.tar.gz"] {
+            let tar = GzDecoder::new(File::open(output_tempdir.path().join(part)).unwrap());
+            let paths: Vec<PathBuf> = Archive::new(tar)
+                .entries()
+                .unwrap()
+                .map(|entry| PathBuf::from(entry.unwrap().path().unwrap()))
+                .collect();
+            // every part has its own copy of the shared files.
+            for shared in SHARED_FILES {
+                assert!(paths.contains(&root.join(shared)));
+            }
+            logs.extend(
+                paths
+                    .into_iter()
+                    .filter(|path| path.extension().map_or(false, |ext| ext == "txt")),
+            );
+        }
+        logs.sort();
+        assert_eq!(logs, vec![root.join("a.txt"), root.join("b.txt")]);
     }
 }
@@ -0,0 +1,171 @@
+//! Plans how to split a bundle into several archives for upload channels that limit the size of
+//! attachments, e.g. support cases that reject files over about 25MB.  The planning is done on the
+//! sizes of the staged files before compression, so each part's files add up to no more than the
+//! limit, and its archive is usually much smaller.
+//!
+//! Files are grouped greedily: the largest files are placed first, each into the first part that
+//! still has room, and a new part is started when none does.  The shared files, like the index,
+//! are copied into every part so each can be read on its own, and count against every part's room.
+//! A file that's larger than the limit by itself can't be split, so it goes alone in its own part.
+
+use std::path::{Path, PathBuf};
+
+/// One archive of a split bundle.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct Part {
+    /// The files in the part, sorted by path, not including the shared files.
+    pub(crate) files: Vec<String>,
+    /// The total size of `files`.
+    pub(crate) size: u64,
+}
+
+/// How to split a bundle.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Plan {
+    pub(crate) parts: Vec<Part>,
+    /// The files that are larger than the limit by themselves, and so are alone in their parts.
+    pub(crate) oversized: Vec<String>,
+}
+
+/// Returns whether `files`, given as their paths and sizes, add up to more than `max_size`.
+pub(crate) fn needs_split(files: &[(String, u64)], max_size: u64) -> bool {
+    files.iter().map(|(_, size)| size).sum::<u64>() > max_size
+}
+
+/// Groups `files`, given as their paths and sizes, into parts of at most `max_size` bytes each,
+/// including the `shared` files, which are in every part.  There's always at least one part.
+pub(crate) fn plan_parts(files: &[(String, u64)], shared: &[&str], max_size: u64) -> Plan {
+    let (shared_files, mut files): (Vec<_>, Vec<_>) = files
+        .iter()
+        .partition(|(name, _)| shared.contains(&name.as_str()));
+    let room = max_size.saturating_sub(shared_files.iter().map(|(_, size)| size).sum());
+
+    // largest first; ties are placed by name so the plan is stable.
+    files.sort_by(|(a_name, a_size), (b_name, b_size)| {
+        b_size.cmp(a_size).then_with(|| a_name.cmp(b_name))
+    });
+
+    let mut parts: Vec<Part> = Vec::new();
+    let mut oversized = Vec::new();
+    for (name, size) in files {
+        if *size > room {
+            oversized.push(name.clone());
+            parts.push(Part {
+                files: vec![name.clone()],
+                size: *size,
+            });
+            continue;
+        }
+        // parts holding an oversized file are already over the limit, so nothing else fits.
+        match parts.iter_mut().find(|part| part.size + size <= room) {
+            Some(part) => {
+                part.files.push(name.clone());
+                part.size += size;
+            }
+            None => parts.push(Part {
+                files: vec![name.clone()],
+                size: *size,
+            }),
+        }
+    }
+
+    if parts.is_empty() {
+        parts.push(Part::default());
+    }
+    for part in &mut parts {
+        part.files.sort();
+    }
+    Plan { parts, oversized }
+}
+
+/// Returns the path of part `number` of `count` of the bundle at `outfile`, e.g.
+/// `bottlerocket-logs.1of3.tar.gz` for `bottlerocket-logs.tar.gz`.  The part is added before the
+/// `.tar.gz` extension if there is one, or at the end otherwise.
+pub(crate) fn part_path(outfile: &Path, number: usize, count: usize) -> PathBuf {
+    let filename = outfile
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let part = format!("{}of{}", number, count);
+    let filename = match filename.strip_suffix(".tar.gz") {
+        Some(stem) => format!("{}.{}.tar.gz", stem, part),
+        None => format!("{}.{}", filename, part),
+    };
+    outfile.with_file_name(filename)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn files(sizes: &[(&str, u64)]) -> Vec<(String, u64)> {
+        sizes
+            .iter()
+            .map(|(name, size)| (name.to_string(), *size))
+            .collect()
+    }
+
+    fn part(files: &[&str], size: u64) -> Part {
+        Part {
+            files: files.iter().map(|name| name.to_string()).collect(),
+            size,
+        }
+    }
+
+    #[test]
+    fn needs_split_at_limit() {
+        let files = files(&[("a", 60), ("b", 40)]);
+        assert!(!needs_split(&files, 100));
+        assert!(needs_split(&files, 99));
+    }
+
+    #[test]
+    fn greedy_by_size() {
+        let files = files(&[("a", 10), ("b", 60), ("c", 50), ("d", 40), ("e", 30)]);
+        let plan = plan_parts(&files, &[], 100);
+        assert_eq!(
+            plan.parts,
+            vec![part(&["b", "d"], 100), part(&["a", "c", "e"], 90)]
+        );
+        assert!(plan.oversized.is_empty());
+    }
+
+    #[test]
+    fn shared_files_take_room() {
+        let files = files(&[("index", 20), ("a", 50), ("b", 40), ("c", 30)]);
+        let plan = plan_parts(&files, &["index"], 100);
+        // each part has 80 bytes of room next to the index.
+        assert_eq!(plan.parts, vec![part(&["a", "c"], 80), part(&["b"], 40)]);
+    }
+
+    #[test]
+    fn oversized_file_alone() {
+        let files = files(&[("a", 30), ("huge", 500), ("b", 30)]);
+        let plan = plan_parts(&files, &[], 100);
+        assert_eq!(
+            plan.parts,
+            vec![part(&["huge"], 500), part(&["a", "b"], 60)]
+        );
+        assert_eq!(plan.oversized, vec!["huge".to_string()]);
+    }
+
+    #[test]
+    fn only_shared_files() {
+        let files = files(&[("index", 20)]);
+        let plan = plan_parts(&files, &["index"], 10);
+        assert_eq!(plan.parts, vec![Part::default()]);
+        assert!(plan.oversized.is_empty());
+    }
+
+    #[test]
+    fn part_paths() {
+        assert_eq!(
+            part_path(Path::new("/tmp/bottlerocket-logs.tar.gz"), 1, 3),
+            PathBuf::from("/tmp/bottlerocket-logs.1of3.tar.gz")
+        );
+        assert_eq!(
+            part_path(Path::new("/tmp/logs"), 2, 2),
+            PathBuf::from("/tmp/logs.2of2")
+        );
+    }
+}
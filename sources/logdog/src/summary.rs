@@ -0,0 +1,187 @@
+//! Provides the summary that's printed after collection, so users can check the bundle before
+//! uploading it: how many log requests ran and how they went, the size of the tarball, and the
+//! largest files in it.
+
+use crate::error::Error;
+use std::io;
+use std::path::Path;
+use walkdir::WalkDir;
+
+/// How many of the largest files are listed in the summary.
+const LARGEST_FILES: usize = 3;
+
+/// How a log request went.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Outcome {
+    Succeeded,
+    Failed,
+    /// The program for an `exec` request isn't installed, e.g. a tool that only some variants
+    /// include.
+    Skipped,
+}
+
+impl Outcome {
+    /// Classifies the result of handling a log request.
+    pub(crate) fn of(result: &Result<(), Error>) -> Self {
+        match result {
+            Ok(()) => Outcome::Succeeded,
+            Err(Error::CommandSpawn { source, .. }) if source.kind() == io::ErrorKind::NotFound => {
+                Outcome::Skipped
+            }
+            Err(_) => Outcome::Failed,
+        }
+    }
+}
+
+/// Returns the path relative to `dir`, and the size, of every file under `dir`.
+pub(crate) fn file_sizes<P: AsRef<Path>>(dir: P) -> io::Result<Vec<(String, u64)>> {
+    let dir = dir.as_ref();
+    let mut sizes = Vec::new();
+    for entry in WalkDir::new(dir) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let size = entry.metadata()?.len();
+        let name = entry
+            .path()
+            .strip_prefix(dir)
+            .unwrap_or_else(|_| entry.path())
+            .display()
+            .to_string();
+        sizes.push((name, size));
+    }
+    Ok(sizes)
+}
+
+/// Formats a size in bytes for humans, e.g. `512 B` or `1.5 MiB`.
+fn human_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Returns the summary of a run whose log requests had the given `outcomes`, which produced a
+/// tarball of `archive_size` bytes from `files`, given as their paths and sizes.
+pub(crate) fn format_summary(
+    outcomes: &[Outcome],
+    archive_size: u64,
+    files: &[(String, u64)],
+) -> String {
+    let count = |outcome| outcomes.iter().filter(|&&o| o == outcome).count();
+    let mut summary = format!(
+        "Collectors run: {}, succeeded: {}, failed: {}, skipped: {}\nArchive size: {}\n",
+        outcomes.len(),
+        count(Outcome::Succeeded),
+        count(Outcome::Failed),
+        count(Outcome::Skipped),
+        human_size(archive_size)
+    );
+
+    // largest first; ties are listed by name so the order is stable.
+    let mut largest: Vec<&(String, u64)> = files.iter().collect();
+    largest.sort_by(|(a_name, a_size), (b_name, b_size)| {
+        b_size.cmp(a_size).then_with(|| a_name.cmp(b_name))
+    });
+    largest.truncate(LARGEST_FILES);
+    if !largest.is_empty() {
+        summary.push_str("Largest files:\n");
+        for (name, size) in largest {
+            summary.push_str(&format!("  {:>10}  {}\n", human_size(*size), name));
+        }
+    }
+    summary
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn files(sizes: &[(&str, u64)]) -> Vec<(String, u64)> {
+        sizes
+            .iter()
+            .map(|(name, size)| (name.to_string(), *size))
+            .collect()
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1024), "1.0 KiB");
+        assert_eq!(human_size(1536 * 1024), "1.5 MiB");
+        assert_eq!(human_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+
+    #[test]
+    fn summary() {
+        use Outcome::*;
+        let outcomes = [Succeeded, Succeeded, Failed, Skipped, Succeeded];
+        let files = files(&[
+            ("df", 900),
+            ("journalctl.log", 5 * 1024 * 1024),
+            ("dmesg", 64 * 1024),
+            ("os-release", 300),
+            ("cgroup-kubelet/memory.stat", 2048),
+        ]);
+        assert_eq!(
+            format_summary(&outcomes, 1536 * 1024, &files),
+            "\
+Collectors run: 5, succeeded: 3, failed: 1, skipped: 1
+Archive size: 1.5 MiB
+Largest files:
+     5.0 MiB  journalctl.log
+    64.0 KiB  dmesg
+     2.0 KiB  cgroup-kubelet/memory.stat
+"
+        );
+    }
+
+    #[test]
+    fn ranking_ties_and_few_files() {
+        let files = files(&[("b", 10), ("a", 10)]);
+        assert_eq!(
+            format_summary(&[], 20, &files),
+            "\
+Collectors run: 0, succeeded: 0, failed: 0, skipped: 0
+Archive size: 20 B
+Largest files:
+        10 B  a
+        10 B  b
+"
+        );
+        assert!(!format_summary(&[], 0, &[]).contains("Largest files"));
+    }
+
+    #[test]
+    fn missing_program_is_skipped() {
+        let outdir = TempDir::new().unwrap();
+        let result = crate::log_request::handle_log_request(
+            "exec missing logdog-test-no-such-program --version",
+            outdir.path(),
+        );
+        assert_eq!(Outcome::of(&result), Outcome::Skipped);
+        assert_eq!(Outcome::of(&Ok(())), Outcome::Succeeded);
+    }
+
+    #[test]
+    fn walk_sizes() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("a"), "12345").unwrap();
+        fs::write(dir.path().join("sub").join("b"), "12").unwrap();
+        let mut sizes = file_sizes(dir.path()).unwrap();
+        sizes.sort();
+        assert_eq!(sizes, files(&[("a", 5), ("sub/b", 2)]));
+    }
+}
@@ -0,0 +1,29 @@
+//! Provides a wait with a timeout for child processes, since `std::process::Child` only has waits
+//! that block until the child exits.  Both the log requests and the watch use it, so a hung command
+//! can't stall collection.
+
+use std::io;
+use std::process::Child;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often a running command is checked to see if it has finished.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Waits for `child` to exit until `timeout` passes, killing it in the latter case.  Returns
+/// whether the child finished on its own.
+pub(crate) fn wait_with_timeout(child: &mut Child, timeout: Duration) -> io::Result<bool> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if child.try_wait()?.is_some() {
+            return Ok(true);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Ok(false);
+        }
+        thread::sleep(POLL_INTERVAL.min(deadline - now));
+    }
+}
@@ -0,0 +1,173 @@
+//! Provides the `--watch-seconds` live capture, for intermittent issues where the next minute of
+//! activity is more useful than the history collected by the log requests.
+//!
+//! While watching, the journal is followed into `journalctl-watch.log`, and `ip -s link` is sampled
+//! every `SAMPLE_INTERVAL` into `ip-link-stats-watch`, each sample under a header with its offset
+//! from the start of the window.  Every command has a hard timeout: the journal is stopped at the
+//! end of the window, and a sample that's still running when the next one is due is killed.
+
+use crate::error::{self, Result};
+use crate::timeout::wait_with_timeout;
+use snafu::ResultExt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The file that the journal is followed into.
+pub(crate) const JOURNAL_FILENAME: &str = "journalctl-watch.log";
+/// The file that the link statistics samples are written to.
+pub(crate) const LINK_STATS_FILENAME: &str = "ip-link-stats-watch";
+
+/// How often the link statistics are sampled.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Returns the offsets from the start of a `window` at which to take samples: one every
+/// `interval` starting at zero, and a last one at the end of the window so the final state is
+/// captured even if the window isn't a multiple of the interval.
+fn sample_offsets(window: Duration, interval: Duration) -> Vec<Duration> {
+    let mut offsets = Vec::new();
+    let mut offset = Duration::from_secs(0);
+    // a zero interval would never advance, so only the end of the window is sampled.
+    if interval > Duration::from_secs(0) {
+        while offset < window {
+            offsets.push(offset);
+            offset += interval;
+        }
+    }
+    offsets.push(window);
+    offsets
+}
+
+/// Runs `command` until it exits or `timeout` passes, killing it in the latter case.  Returns
+/// whether the command finished on its own.
+fn run_with_timeout(command: &mut Command, timeout: Duration) -> io::Result<bool> {
+    let mut child = command.spawn()?;
+    wait_with_timeout(&mut child, timeout)
+}
+
+/// Takes one sample of the link statistics, appending it to `stats_file`.
+fn sample_link_stats(stats_file: &mut File, offset: Duration) -> io::Result<()> {
+    writeln!(stats_file, "=== +{}s ===", offset.as_secs())?;
+    stats_file.flush()?;
+    let stdout = stats_file.try_clone()?;
+    let stderr = stats_file.try_clone()?;
+    let finished = run_with_timeout(
+        Command::new("ip")
+            .args(&["-s", "link"])
+            .stdout(Stdio::from(stdout))
+            .stderr(Stdio::from(stderr)),
+        SAMPLE_INTERVAL,
+    )?;
+    if !finished {
+        writeln!(
+            stats_file,
+            "=== sample killed after {}s ===",
+            SAMPLE_INTERVAL.as_secs()
+        )?;
+    }
+    Ok(())
+}
+
+/// Follows the journal and samples the link statistics for `window`, writing both to `outdir`.
+/// A failed sample is noted in the statistics file and doesn't stop the capture.
+pub(crate) fn watch<P: AsRef<Path>>(outdir: P, window: Duration) -> Result<()> {
+    let outdir = outdir.as_ref();
+    let journal_path = outdir.join(JOURNAL_FILENAME);
+    let journal_file = File::create(&journal_path).context(error::WatchFile {
+        path: &journal_path,
+    })?;
+    let journal_stderr = journal_file.try_clone().context(error::WatchFile {
+        path: &journal_path,
+    })?;
+    let stats_path = outdir.join(LINK_STATS_FILENAME);
+    let mut stats_file =
+        File::create(&stats_path).context(error::WatchFile { path: &stats_path })?;
+
+    // only entries written during the window are wanted, not the usual last ten lines.
+    let mut journal = Command::new("journalctl")
+        .args(&["--follow", "--lines=0", "--no-pager"])
+        .stdout(Stdio::from(journal_file))
+        .stderr(Stdio::from(journal_stderr))
+        .spawn()
+        .context(error::WatchSpawn {
+            command: "journalctl --follow",
+        })?;
+
+    let start = Instant::now();
+    for offset in sample_offsets(window, SAMPLE_INTERVAL) {
+        if let Some(wait) = (start + offset).checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+        if let Err(e) = sample_link_stats(&mut stats_file, offset) {
+            writeln!(stats_file, "=== sample failed: {} ===", e)
+                .context(error::WatchFile { path: &stats_path })?;
+        }
+    }
+
+    journal.kill().context(error::WatchStop {
+        command: "journalctl --follow",
+    })?;
+    journal.wait().context(error::WatchStop {
+        command: "journalctl --follow",
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn secs(offsets: &[u64]) -> Vec<Duration> {
+        offsets.iter().map(|&s| Duration::from_secs(s)).collect()
+    }
+
+    #[test]
+    fn offsets_multiple_of_interval() {
+        assert_eq!(
+            sample_offsets(Duration::from_secs(20), Duration::from_secs(5)),
+            secs(&[0, 5, 10, 15, 20])
+        );
+    }
+
+    #[test]
+    fn offsets_partial_interval() {
+        assert_eq!(
+            sample_offsets(Duration::from_secs(12), Duration::from_secs(5)),
+            secs(&[0, 5, 10, 12])
+        );
+    }
+
+    #[test]
+    fn offsets_short_window() {
+        assert_eq!(
+            sample_offsets(Duration::from_secs(3), Duration::from_secs(5)),
+            secs(&[0, 3])
+        );
+    }
+
+    #[test]
+    fn offsets_zero_interval() {
+        assert_eq!(
+            sample_offsets(Duration::from_secs(3), Duration::from_secs(0)),
+            secs(&[3])
+        );
+    }
+
+    #[test]
+    fn timeout_kills_command() {
+        let started = Instant::now();
+        let finished =
+            run_with_timeout(Command::new("sleep").arg("10"), Duration::from_millis(200)).unwrap();
+        assert!(!finished);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn command_finishes_before_timeout() {
+        let finished = run_with_timeout(&mut Command::new("true"), Duration::from_secs(5)).unwrap();
+        assert!(finished);
+    }
+}
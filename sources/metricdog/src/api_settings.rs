@@ -0,0 +1,98 @@
+//! Reads the settings that metricdog reports with every event from the Bottlerocket API, for
+//! configs that don't include them.  The values in the config are rendered when the config is
+//! written, so reading them from the API keeps them current when settings change later.
+
+use crate::error::{self, Result};
+use serde::Deserialize;
+use snafu::{OptionExt, ResultExt};
+use std::time::Duration;
+
+const DEFAULT_API_SOCKET: &str = "/run/api.sock";
+const API_SETTINGS_URI: &str = "/settings";
+const SETTINGS_KEYS: &[&str] = &[
+    "settings.aws.region",
+    "settings.updates.seed",
+    "settings.updates.version-lock",
+    "settings.updates.ignore-waves",
+];
+/// The API is local, so if it doesn't answer quickly it's not going to; metricdog shouldn't wait
+/// long to fall back to defaults.
+const API_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The settings metricdog reports, as far as they're set in the API.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub(crate) struct HostSettings {
+    #[serde(default)]
+    pub(crate) aws: AwsSettings,
+    #[serde(default)]
+    pub(crate) updates: UpdatesSettings,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub(crate) struct AwsSettings {
+    pub(crate) region: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct UpdatesSettings {
+    pub(crate) seed: Option<u32>,
+    pub(crate) version_lock: Option<String>,
+    pub(crate) ignore_waves: Option<bool>,
+}
+
+/// A source of the settings metricdog reports. This allows tests to supply the settings without
+/// an API server.
+pub(crate) trait SettingsSource {
+    fn host_settings(&self) -> Result<HostSettings>;
+}
+
+/// Gets settings from the Bottlerocket API.
+pub(crate) struct ApiSettings;
+
+impl SettingsSource for ApiSettings {
+    fn host_settings(&self) -> Result<HostSettings> {
+        let uri = format!("{}?keys={}", API_SETTINGS_URI, SETTINGS_KEYS.join(","));
+        // apiclient is async, and metricdog isn't, so run the request on a runtime of its own.
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .context(error::ApiRuntime)?;
+        let (_status, body) = runtime
+            .block_on(tokio::time::timeout(
+                API_TIMEOUT,
+                apiclient::raw_request(DEFAULT_API_SOCKET, &uri, "GET", None),
+            ))
+            .ok()
+            .context(error::ApiTimeout { uri: &uri })?
+            .context(error::ApiRequest { uri: &uri })?;
+        serde_json::from_str(&body).context(error::ApiResponse { uri })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_response() {
+        let settings: HostSettings = serde_json::from_str(
+            r#"{"aws": {"region": "us-west-2"},
+                "updates": {"seed": 1234, "version-lock": "latest", "ignore-waves": true}}"#,
+        )
+        .unwrap();
+        assert_eq!(settings.aws.region.as_deref(), Some("us-west-2"));
+        assert_eq!(settings.updates.seed, Some(1234));
+        assert_eq!(settings.updates.version_lock.as_deref(), Some("latest"));
+        assert_eq!(settings.updates.ignore_waves, Some(true));
+    }
+
+    #[test]
+    fn parse_partial_response() {
+        // variants without AWS settings don't return an `aws` section.
+        let settings: HostSettings = serde_json::from_str(r#"{"updates": {"seed": 42}}"#).unwrap();
+        assert_eq!(settings.aws.region, None);
+        assert_eq!(settings.updates.seed, Some(42));
+        assert_eq!(settings.updates.version_lock, None);
+    }
+}
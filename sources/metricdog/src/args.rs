@@ -1,3 +1,4 @@
+use crate::metricdog::UpdateStatus;
 use log::LevelFilter;
 use std::path::PathBuf;
 use structopt::StructOpt;
@@ -14,6 +15,18 @@ pub(crate) struct Arguments {
     /// Path to the os-release file [default: /etc/os-release]
     #[structopt(short = "o", long = "os-release")]
     pub(crate) os_release: Option<PathBuf>,
+    /// Path to the file recording the boot ID for which boot success was sent
+    /// [default: /var/lib/metricdog/boot-success]
+    #[structopt(long = "boot-success-state")]
+    pub(crate) boot_success_state: Option<PathBuf>,
+    /// Path to the file recording the last failure to send a health ping
+    /// [default: /var/lib/metricdog/send-failure]
+    #[structopt(long = "send-failure-state")]
+    pub(crate) send_failure_state: Option<PathBuf>,
+    /// Path to the file recording the consecutive failed health checks of each service
+    /// [default: /var/lib/metricdog/consecutive-failures]
+    #[structopt(long = "consecutive-failures-state")]
+    pub(crate) consecutive_failures_state: Option<PathBuf>,
     #[structopt(subcommand)]
     pub(crate) command: Command,
 }
@@ -21,7 +34,146 @@ pub(crate) struct Arguments {
 #[derive(Debug, StructOpt)]
 pub(crate) enum Command {
     /// report a successful boot.
-    SendBootSuccess,
+    SendBootSuccess {
+        /// send even if boot success was already sent during this boot.
+        #[structopt(long = "force")]
+        force: bool,
+    },
+    /// report a custom event, e.g. from a script.
+    SendEvent {
+        /// the name of the event.
+        #[structopt(long = "name")]
+        name: String,
+        /// a key-value pair to send with the event, as `key=value`; can be repeated.
+        #[structopt(long = "value", number_of_values = 1, parse(try_from_str = parse_value))]
+        values: Vec<(String, String)>,
+    },
     /// check services and report their health.
-    SendHealthPing,
+    SendHealthPing {
+        /// send immediately instead of waiting for this host's share of `ping_splay_seconds`.
+        #[structopt(long = "no-splay")]
+        no_splay: bool,
+    },
+    /// report the progress of an update, e.g. from updog or migrator.
+    SendUpdateEvent {
+        /// the status of the update.
+        #[structopt(long = "status", possible_values = &UpdateStatus::VALUES)]
+        status: UpdateStatus,
+        /// the version being updated to.
+        #[structopt(long = "target-version")]
+        target_version: Option<String>,
+    },
+}
+
+/// Parses a `key=value` pair given with `--value`. The value may be empty or contain `=`.
+fn parse_value(pair: &str) -> Result<(String, String), String> {
+    let mut parts = pair.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(key), Some(value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected key=value, got '{}'", pair)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Command, structopt::clap::Error> {
+        Arguments::from_iter_safe(args).map(|arguments| arguments.command)
+    }
+
+    #[test]
+    fn send_event_args() {
+        let command = parse(&[
+            "metricdog",
+            "send-event",
+            "--name",
+            "bootstrap_done",
+            "--value",
+            "duration=42",
+            "--value",
+            "query=a=b",
+            "--value",
+            "empty=",
+        ])
+        .unwrap();
+        match command {
+            Command::SendEvent { name, values } => {
+                assert_eq!(name, "bootstrap_done");
+                assert_eq!(
+                    values,
+                    vec![
+                        ("duration".to_string(), "42".to_string()),
+                        ("query".to_string(), "a=b".to_string()),
+                        ("empty".to_string(), "".to_string()),
+                    ]
+                );
+            }
+            _ => panic!("expected send-event, got {:?}", command),
+        }
+    }
+
+    #[test]
+    fn send_event_without_values() {
+        let command = parse(&["metricdog", "send-event", "--name", "x"]).unwrap();
+        assert!(matches!(command, Command::SendEvent { values, .. } if values.is_empty()));
+    }
+
+    #[test]
+    fn send_event_bad_args() {
+        // a name is required, and every value needs a key and an `=`.
+        assert!(parse(&["metricdog", "send-event"]).is_err());
+        for value in &["novalue", "=42"] {
+            assert!(
+                parse(&["metricdog", "send-event", "--name", "x", "--value", value]).is_err(),
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn send_update_event_args() {
+        let command = parse(&[
+            "metricdog",
+            "send-update-event",
+            "--status",
+            "success",
+            "--target-version",
+            "1.2.3",
+        ])
+        .unwrap();
+        match command {
+            Command::SendUpdateEvent {
+                status,
+                target_version,
+            } => {
+                assert_eq!(status, UpdateStatus::Success);
+                assert_eq!(target_version.as_deref(), Some("1.2.3"));
+            }
+            _ => panic!("expected send-update-event, got {:?}", command),
+        }
+
+        let command = parse(&["metricdog", "send-update-event", "--status", "started"]).unwrap();
+        assert!(matches!(
+            command,
+            Command::SendUpdateEvent {
+                status: UpdateStatus::Started,
+                target_version: None,
+            }
+        ));
+    }
+
+    #[test]
+    fn send_update_event_bad_args() {
+        // a status is required, and must be one of the known ones.
+        assert!(parse(&["metricdog", "send-update-event"]).is_err());
+        for status in &["staged", "Success", ""] {
+            assert!(
+                parse(&["metricdog", "send-update-event", "--status", status]).is_err(),
+                "{}",
+                status
+            );
+        }
+    }
 }
@@ -0,0 +1,115 @@
+//! Reads the ID of the current boot and how long the host has been up, which health pings send so
+//! that the pings of a host can be correlated across reboots.
+
+use crate::boot_success;
+use crate::error::{self, Result};
+use log::warn;
+use snafu::{OptionExt, ResultExt};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Where the kernel's process information filesystem is mounted.
+pub(crate) const DEFAULT_PROC_PATH: &str = "/proc";
+/// The file with the ID of the current boot, under the proc path.
+const BOOT_ID_FILE: &str = "sys/kernel/random/boot_id";
+/// The file with the seconds since boot and the idle time of all CPUs, under the proc path.
+const UPTIME_FILE: &str = "uptime";
+
+/// Returns the `boot_id` and `uptime_seconds` values of a health ping, read from the kernel's
+/// files under `proc_path`.  A value that can't be read is logged and left out, so that the health
+/// ping is still sent.
+pub(crate) fn boot_values<P: AsRef<Path>>(proc_path: P) -> HashMap<String, String> {
+    let proc_path = proc_path.as_ref();
+    let mut values = HashMap::new();
+    match boot_success::read_boot_id(proc_path.join(BOOT_ID_FILE)) {
+        Ok(boot_id) => {
+            values.insert(String::from("boot_id"), boot_id);
+        }
+        Err(e) => warn!("Unable to read the boot ID, leaving it out: {}", e),
+    }
+    match read_uptime(proc_path.join(UPTIME_FILE)) {
+        Ok(uptime) => {
+            values.insert(String::from("uptime_seconds"), uptime.to_string());
+        }
+        Err(e) => warn!("Unable to read the uptime, leaving it out: {}", e),
+    }
+    values
+}
+
+/// Reads the whole seconds since boot from the uptime file at `path`, e.g. `350735.47 234388.90`.
+fn read_uptime<P: AsRef<Path>>(path: P) -> Result<u64> {
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path).context(error::UptimeRead { path })?;
+    let field = contents.split_whitespace().next().unwrap_or_default();
+    // the uptime has two decimal places, which health pings don't need.
+    field
+        .split('.')
+        .next()
+        .and_then(|seconds| seconds.parse::<u64>().ok())
+        .context(error::UptimeParse { path, value: field })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Writes the kernel's files that are given under a temporary proc path.
+    fn proc_path(boot_id: Option<&str>, uptime: Option<&str>) -> TempDir {
+        let dir = TempDir::new().unwrap();
+        if let Some(boot_id) = boot_id {
+            let path = dir.path().join(BOOT_ID_FILE);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, boot_id).unwrap();
+        }
+        if let Some(uptime) = uptime {
+            fs::write(dir.path().join(UPTIME_FILE), uptime).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn both_values() {
+        let dir = proc_path(
+            Some("0f5b3c4e-6d1a-4c8e-9b1f-2a7d3e4f5a6b\n"),
+            Some("350735.47 234388.90\n"),
+        );
+        let values = boot_values(dir.path());
+        assert_eq!(values.len(), 2);
+        assert_eq!(values["boot_id"], "0f5b3c4e-6d1a-4c8e-9b1f-2a7d3e4f5a6b");
+        assert_eq!(values["uptime_seconds"], "350735");
+    }
+
+    #[test]
+    fn missing_files() {
+        let dir = proc_path(None, Some("12.00 10.00"));
+        let values = boot_values(dir.path());
+        assert!(!values.contains_key("boot_id"));
+        assert_eq!(values["uptime_seconds"], "12");
+
+        let dir = proc_path(Some("abcd"), None);
+        let values = boot_values(dir.path());
+        assert_eq!(values["boot_id"], "abcd");
+        assert!(!values.contains_key("uptime_seconds"));
+
+        assert!(boot_values(proc_path(None, None).path()).is_empty());
+    }
+
+    #[test]
+    fn malformed_uptime() {
+        for uptime in &["", "soon", "-5.00 1.00", ".50 1.00"] {
+            let dir = proc_path(None, Some(uptime));
+            assert!(
+                matches!(
+                    read_uptime(dir.path().join(UPTIME_FILE)),
+                    Err(error::Error::UptimeParse { .. })
+                ),
+                "{}",
+                uptime
+            );
+        }
+        let dir = proc_path(None, Some("7 1"));
+        assert_eq!(read_uptime(dir.path().join(UPTIME_FILE)).unwrap(), 7);
+    }
+}
@@ -0,0 +1,70 @@
+//! Keeps track of whether a boot success event has already been sent during the current boot, so
+//! that restarting the unit that sends it does not skew boot counts.
+
+use crate::error::{self, Result};
+use snafu::{OptionExt, ResultExt};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The kernel provides a random ID that is unique to each boot of the host.
+const BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+
+/// Where the boot ID is recorded after a boot success event is sent.
+pub(crate) const DEFAULT_STATE_PATH: &str = "/var/lib/metricdog/boot-success";
+
+/// Returns the ID of the current boot.
+pub(crate) fn current_boot_id() -> Result<String> {
+    read_boot_id(BOOT_ID_PATH)
+}
+
+pub(crate) fn read_boot_id<P: AsRef<Path>>(path: P) -> Result<String> {
+    let path = path.as_ref();
+    let boot_id = fs::read_to_string(path).context(error::BootIdRead { path })?;
+    Ok(boot_id.trim().to_string())
+}
+
+/// Returns true if the state file at `state_path` shows that boot success was already sent for
+/// `boot_id`. A missing state file means that boot success has not been sent yet.
+pub(crate) fn already_sent<P: AsRef<Path>>(state_path: P, boot_id: &str) -> Result<bool> {
+    let state_path = state_path.as_ref();
+    match fs::read_to_string(state_path) {
+        Ok(recorded) => Ok(recorded.trim() == boot_id),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).context(error::BootSuccessStateRead { path: state_path }),
+    }
+}
+
+/// Records `boot_id` in the state file at `state_path`, creating its parent directory if needed.
+pub(crate) fn record<P: AsRef<Path>>(state_path: P, boot_id: &str) -> Result<()> {
+    let state_path = state_path.as_ref();
+    let parent = state_path
+        .parent()
+        .context(error::BootSuccessStateParent { path: state_path })?;
+    fs::create_dir_all(parent).context(error::BootSuccessStateWrite { path: parent })?;
+    fs::write(state_path, boot_id).context(error::BootSuccessStateWrite { path: state_path })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn read_boot_id_trims() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("boot_id");
+        fs::write(&path, "1234-abcd\n").unwrap();
+        assert_eq!(read_boot_id(&path).unwrap(), "1234-abcd");
+    }
+
+    #[test]
+    fn record_then_check() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("state").join("boot-success");
+        assert!(!already_sent(&path, "1234-abcd").unwrap());
+        record(&path, "1234-abcd").unwrap();
+        assert!(already_sent(&path, "1234-abcd").unwrap());
+        assert!(!already_sent(&path, "5678-ef01").unwrap());
+    }
+}
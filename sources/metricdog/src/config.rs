@@ -1,10 +1,25 @@
+use crate::api_settings::{ApiSettings, HostSettings, SettingsSource};
 use crate::error::{self, Result};
+use crate::migration_debris::DEFAULT_DATASTORE_PATH;
+use crate::sampling;
+use crate::service_check::DEFAULT_COMMAND_TIMEOUT_SECONDS;
+use crate::url_limit::DEFAULT_MAX_URL_LENGTH;
+use log::warn;
 use serde::Deserialize;
-use snafu::ResultExt;
+use snafu::{ensure, ResultExt};
 use std::fs;
 use std::path::{Path, PathBuf};
+use toml::value::{Table, Value};
+use url::Url;
 
 const DEFAULT_CONFIG_PATH: &str = "/etc/metricdog.toml";
+const MACHINE_ID_PATH: &str = "/etc/machine-id";
+
+/// The region that's reported if it's neither in the config nor in the API.
+const UNKNOWN_REGION: &str = "unknown";
+/// The version lock that's reported if it's neither in the config nor in the API, which is the
+/// default of the `settings.updates.version-lock` setting.
+const DEFAULT_VERSION_LOCK: &str = "latest";
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct Config {
@@ -15,6 +30,50 @@ pub(crate) struct Config {
     pub(crate) seed: u32,
     pub(crate) version_lock: String,
     pub(crate) ignore_waves: bool,
+    /// The fraction of hosts, in (0.0, 1.0], that send health pings.
+    #[serde(default = "default_ping_sample_rate")]
+    pub(crate) ping_sample_rate: f64,
+    /// Whether a `degraded` system state makes the host unhealthy.
+    #[serde(default)]
+    pub(crate) degraded_is_unhealthy: bool,
+    /// How long, in seconds, a repeated failure to send a health ping is logged at debug level.
+    #[serde(default = "default_send_failure_window")]
+    pub(crate) send_failure_window: u64,
+    /// The directory holding the data stores, which is checked for migration debris.
+    #[serde(default = "default_datastore_path")]
+    pub(crate) datastore_path: PathBuf,
+    /// The window, in seconds, across which the fleet's health pings are spread.
+    #[serde(default)]
+    pub(crate) ping_splay_seconds: Option<u64>,
+    /// The longest URL, in bytes, that's sent; longer health pings are cut down to fit.
+    #[serde(default = "default_max_url_length")]
+    pub(crate) max_url_length: usize,
+    /// Whether the consecutive failure counts of services are kept across reboots.
+    #[serde(default)]
+    pub(crate) persist_across_boots: bool,
+    /// How long, in seconds, each command that checks a service may take before it's killed.
+    #[serde(default = "default_service_check_timeout_seconds")]
+    pub(crate) service_check_timeout_seconds: u64,
+}
+
+fn default_ping_sample_rate() -> f64 {
+    1.0
+}
+
+fn default_send_failure_window() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_max_url_length() -> usize {
+    DEFAULT_MAX_URL_LENGTH
+}
+
+fn default_datastore_path() -> PathBuf {
+    PathBuf::from(DEFAULT_DATASTORE_PATH)
+}
+
+fn default_service_check_timeout_seconds() -> u64 {
+    DEFAULT_COMMAND_TIMEOUT_SECONDS
 }
 
 impl Config {
@@ -23,18 +82,155 @@ impl Config {
     }
 
     pub(crate) fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_file_with_settings(path, &ApiSettings)
+    }
+
+    /// Reads the config file at `path`.  If it doesn't include the region, seed, version lock, or
+    /// ignore waves setting, those that are missing are read from `settings`, or given defaults if
+    /// `settings` doesn't have them either.  The metrics URL must parse unless metrics are opted
+    /// out of, and the seed must be in the range of `settings.updates.seed`.
+    pub(crate) fn from_file_with_settings<P: AsRef<Path>>(
+        path: P,
+        settings: &dyn SettingsSource,
+    ) -> Result<Self> {
         let path = path.as_ref();
         let s = fs::read_to_string(path).context(error::ConfigRead { path })?;
-        let config: Config = toml::from_str(&s).context(error::ConfigParse { path })?;
+        let mut table: Table = toml::from_str(&s).context(error::ConfigParse { path })?;
+        fill_missing_settings(&mut table, settings);
+        let config: Config = Value::Table(table)
+            .try_into()
+            .context(error::ConfigParse { path })?;
+        ensure!(
+            config.ping_sample_rate > 0.0 && config.ping_sample_rate <= 1.0,
+            error::PingSampleRate {
+                path,
+                rate: config.ping_sample_rate
+            }
+        );
+        // an opted-out config may leave the URL empty, since it's never used.
+        if config.send_metrics {
+            Url::parse(&config.metrics_url).context(error::ConfigUrl {
+                path,
+                url: &config.metrics_url,
+            })?;
+        }
+        ensure!(
+            config.seed < sampling::MAX_SEED,
+            error::ConfigSeed {
+                path,
+                seed: config.seed,
+                max: sampling::MAX_SEED
+            }
+        );
         Ok(config)
     }
 }
 
+/// Adds the region, seed, version lock, and ignore waves settings to `table` if it doesn't have
+/// them.  The API is only asked if one of them is missing, and if it can't be reached, the
+/// defaults are used: an unknown region, a seed derived from the machine ID, the latest version,
+/// and not ignoring waves.
+fn fill_missing_settings(table: &mut Table, settings: &dyn SettingsSource) {
+    const KEYS: &[&str] = &["region", "seed", "version_lock", "ignore_waves"];
+    if KEYS.iter().all(|key| table.contains_key(*key)) {
+        return;
+    }
+    let settings = settings.host_settings().unwrap_or_else(|e| {
+        warn!(
+            "Unable to get settings from the Bottlerocket API, using defaults: {}",
+            e
+        );
+        HostSettings::default()
+    });
+
+    let region = settings
+        .aws
+        .region
+        .unwrap_or_else(|| UNKNOWN_REGION.to_string());
+    table
+        .entry("region")
+        .or_insert_with(|| Value::String(region));
+    let seed = settings.updates.seed;
+    table
+        .entry("seed")
+        .or_insert_with(|| Value::Integer(i64::from(seed.unwrap_or_else(fallback_seed))));
+    let version_lock = settings
+        .updates
+        .version_lock
+        .unwrap_or_else(|| DEFAULT_VERSION_LOCK.to_string());
+    table
+        .entry("version_lock")
+        .or_insert_with(|| Value::String(version_lock));
+    let ignore_waves = settings.updates.ignore_waves.unwrap_or(false);
+    table
+        .entry("ignore_waves")
+        .or_insert_with(|| Value::Boolean(ignore_waves));
+}
+
+/// Returns the seed to report when it's neither in the config nor in the API.  It's derived from
+/// the machine ID so that it's stable across boots.
+fn fallback_seed() -> u32 {
+    let machine_id = fs::read_to_string(MACHINE_ID_PATH).unwrap_or_else(|e| {
+        warn!("Unable to read {}: {}", MACHINE_ID_PATH, e);
+        String::new()
+    });
+    sampling::derived_seed(machine_id.trim())
+}
+
 #[cfg(test)]
 mod test {
-    use crate::config::Config;
+    use crate::api_settings::{AwsSettings, HostSettings, SettingsSource, UpdatesSettings};
+    use crate::config::{fallback_seed, Config};
+    use crate::error::{self, Result};
+    use std::cell::Cell;
     use tempfile::TempDir;
 
+    /// Supplies `settings`, or an error if there are none, and counts how often it's asked.
+    struct MockSettings {
+        settings: Option<HostSettings>,
+        calls: Cell<usize>,
+    }
+
+    impl MockSettings {
+        fn new(settings: Option<HostSettings>) -> Self {
+            Self {
+                settings,
+                calls: Cell::new(0),
+            }
+        }
+    }
+
+    impl SettingsSource for MockSettings {
+        fn host_settings(&self) -> Result<HostSettings> {
+            self.calls.set(self.calls.get() + 1);
+            match &self.settings {
+                Some(settings) => Ok(settings.clone()),
+                None => error::ApiTimeout { uri: "/settings" }.fail(),
+            }
+        }
+    }
+
+    // This is what a config might look like if it doesn't include the settings that are in the
+    // API.
+    const MINIMAL_CONFIG: &str = r#"
+    metrics_url = "https://example.com"
+    send_metrics = true
+    service_checks = ["a", "b", "c",]
+    "#;
+
+    fn api_settings() -> HostSettings {
+        HostSettings {
+            aws: AwsSettings {
+                region: Some("eu-central-1".to_string()),
+            },
+            updates: UpdatesSettings {
+                seed: Some(42),
+                version_lock: Some("v1.2.3".to_string()),
+                ignore_waves: Some(true),
+            },
+        }
+    }
+
     // This is what most configs will look like.
     const STANDARD_CONFIG: &str = r#"
     metrics_url = "https://example.com"
@@ -73,6 +269,126 @@ mod test {
         assert_eq!(1234, config.seed);
         assert_eq!("v0.1.2", config.version_lock);
         assert!(!config.ignore_waves);
+        assert!((config.ping_sample_rate - 1.0).abs() < f64::EPSILON);
+        assert!(!config.degraded_is_unhealthy);
+        assert_eq!(86400, config.send_failure_window);
+        assert_eq!(
+            std::path::Path::new("/var/lib/bottlerocket/datastore"),
+            config.datastore_path
+        );
+        assert_eq!(None, config.ping_splay_seconds);
+        assert_eq!(8192, config.max_url_length);
+        assert_eq!(5, config.service_check_timeout_seconds);
+    }
+
+    #[test]
+    fn settings_from_config() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, STANDARD_CONFIG).unwrap();
+        let settings = MockSettings::new(Some(api_settings()));
+        let config = Config::from_file_with_settings(&path, &settings).unwrap();
+        assert_eq!(0, settings.calls.get());
+        assert_eq!("us-west-2", config.region);
+        assert_eq!(1234, config.seed);
+        assert_eq!("v0.1.2", config.version_lock);
+        assert!(!config.ignore_waves);
+    }
+
+    #[test]
+    fn settings_from_api() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, MINIMAL_CONFIG).unwrap();
+        let settings = MockSettings::new(Some(api_settings()));
+        let config = Config::from_file_with_settings(&path, &settings).unwrap();
+        assert_eq!(1, settings.calls.get());
+        assert_eq!("eu-central-1", config.region);
+        assert_eq!(42, config.seed);
+        assert_eq!("v1.2.3", config.version_lock);
+        assert!(config.ignore_waves);
+    }
+
+    #[test]
+    fn config_settings_take_precedence() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, format!("{}\nregion = \"us-west-2\"", MINIMAL_CONFIG)).unwrap();
+        let settings = MockSettings::new(Some(api_settings()));
+        let config = Config::from_file_with_settings(&path, &settings).unwrap();
+        assert_eq!("us-west-2", config.region);
+        assert_eq!(42, config.seed);
+    }
+
+    #[test]
+    fn settings_unavailable() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, MINIMAL_CONFIG).unwrap();
+        for settings in &[
+            MockSettings::new(None),
+            MockSettings::new(Some(HostSettings::default())),
+        ] {
+            let config = Config::from_file_with_settings(&path, settings).unwrap();
+            assert_eq!("unknown", config.region);
+            assert_eq!(fallback_seed(), config.seed);
+            assert!(config.seed < 2048);
+            assert_eq!("latest", config.version_lock);
+            assert!(!config.ignore_waves);
+        }
+    }
+
+    #[test]
+    fn ping_splay_seconds() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(
+            &path,
+            format!("{}\nping_splay_seconds = 300", STANDARD_CONFIG),
+        )
+        .unwrap();
+        let config = Config::from_file(&path).unwrap();
+        assert_eq!(Some(300), config.ping_splay_seconds);
+    }
+
+    #[test]
+    fn service_check_timeout_seconds() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(
+            &path,
+            format!("{}\nservice_check_timeout_seconds = 30", STANDARD_CONFIG),
+        )
+        .unwrap();
+        let config = Config::from_file(&path).unwrap();
+        assert_eq!(30, config.service_check_timeout_seconds);
+    }
+
+    #[test]
+    fn ping_sample_rate() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(
+            &path,
+            format!("{}\nping_sample_rate = 0.25", STANDARD_CONFIG),
+        )
+        .unwrap();
+        let config = Config::from_file(&path).unwrap();
+        assert!((config.ping_sample_rate - 0.25).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn ping_sample_rate_out_of_range() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        for rate in &["0.0", "1.5", "-0.5"] {
+            std::fs::write(
+                &path,
+                format!("{}\nping_sample_rate = {}", STANDARD_CONFIG, rate),
+            )
+            .unwrap();
+            assert!(Config::from_file(&path).is_err());
+        }
     }
 
     #[test]
@@ -92,4 +408,64 @@ mod test {
         assert_eq!("v0.1.2", config.version_lock);
         assert!(!config.ignore_waves);
     }
+
+    #[test]
+    fn malformed_config() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        let malformed_configs = vec![
+            // not TOML
+            "metrics_url = ".to_string(),
+            // a required key is missing
+            "send_metrics = true\nservice_checks = []".to_string(),
+            // values of the wrong type
+            format!("{}\nseed = \"1234\"", MINIMAL_CONFIG),
+            format!("{}\nseed = -1", MINIMAL_CONFIG),
+        ];
+        for malformed in &malformed_configs {
+            std::fs::write(&path, malformed).unwrap();
+            let result = Config::from_file_with_settings(&path, &MockSettings::new(None));
+            assert!(
+                matches!(result, Err(error::Error::ConfigParse { .. })),
+                "{:?}",
+                result
+            );
+        }
+        assert!(matches!(
+            Config::from_file(dir.path().join("missing.toml")),
+            Err(error::Error::ConfigRead { .. })
+        ));
+    }
+
+    #[test]
+    fn invalid_metrics_url() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(
+            &path,
+            STANDARD_CONFIG.replace("https://example.com", "example.com/metrics"),
+        )
+        .unwrap();
+        let result = Config::from_file(&path);
+        assert!(
+            matches!(result, Err(error::Error::ConfigUrl { .. })),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn seed_out_of_range() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, STANDARD_CONFIG.replace("1234", "2047")).unwrap();
+        assert_eq!(2047, Config::from_file(&path).unwrap().seed);
+        std::fs::write(&path, STANDARD_CONFIG.replace("1234", "2048")).unwrap();
+        let result = Config::from_file(&path);
+        assert!(
+            matches!(result, Err(error::Error::ConfigSeed { seed: 2048, .. })),
+            "{:?}",
+            result
+        );
+    }
 }
@@ -7,6 +7,45 @@ use url::Url;
 #[derive(Debug, Snafu)]
 #[snafu(visibility = "pub(crate)")]
 pub(crate) enum Error {
+    #[snafu(display("Error calling Bottlerocket API at {}: {}", uri, source))]
+    ApiRequest {
+        uri: String,
+        source: apiclient::Error,
+    },
+
+    #[snafu(display("Unable to parse Bottlerocket API response from {}: {}", uri, source))]
+    ApiResponse {
+        uri: String,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Unable to start runtime for Bottlerocket API request: {}", source))]
+    ApiRuntime { source: std::io::Error },
+
+    #[snafu(display("Timed out calling Bottlerocket API at {}", uri))]
+    ApiTimeout { uri: String },
+
+    #[snafu(display("Unable to read boot ID from {}: {}", path.display(), source))]
+    BootIdRead {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Boot success state path {} has no parent directory", path.display()))]
+    BootSuccessStateParent { path: PathBuf },
+
+    #[snafu(display("Unable to read boot success state from {}: {}", path.display(), source))]
+    BootSuccessStateRead {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Unable to write boot success state to {}: {}", path.display(), source))]
+    BootSuccessStateWrite {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
     #[snafu(display("Unable to load Bottlerocket release info: '{}'", source))]
     BottlerocketRelease { source: bottlerocket_release::Error },
 
@@ -17,6 +56,24 @@ pub(crate) enum Error {
         source: std::io::Error,
     },
 
+    #[snafu(display(
+        "Command '{}' with args '{:?}' timed out after {:?}",
+        command,
+        args,
+        timeout
+    ))]
+    CommandTimeout {
+        command: String,
+        args: Vec<String>,
+        timeout: std::time::Duration,
+    },
+
+    #[snafu(display("Too many event values, {} given, at most {} allowed", count, max))]
+    EventValueCount { count: usize, max: usize },
+
+    #[snafu(display("Event value key '{}' is reserved for the standard parameters", key))]
+    EventValueReserved { key: String },
+
     #[snafu(display("Failed to parse config file {}: {}", path.display(), source))]
     ConfigParse {
         path: PathBuf,
@@ -29,6 +86,40 @@ pub(crate) enum Error {
         source: std::io::Error,
     },
 
+    #[snafu(display(
+        "Invalid seed {} in {}, must be less than {}",
+        seed,
+        path.display(),
+        max
+    ))]
+    ConfigSeed { path: PathBuf, seed: u32, max: u32 },
+
+    #[snafu(display("Invalid metrics_url '{}' in {}: {}", url, path.display(), source))]
+    ConfigUrl {
+        path: PathBuf,
+        url: String,
+        source: url::ParseError,
+    },
+
+    #[snafu(display(
+        "Consecutive failures state path {} has no parent directory",
+        path.display()
+    ))]
+    FailureCountsStateParent { path: PathBuf },
+
+    #[snafu(display("Unable to serialize consecutive failures state: {}", source))]
+    FailureCountsStateSerialize { source: toml::ser::Error },
+
+    #[snafu(display(
+        "Unable to write consecutive failures state to {}: {}",
+        path.display(),
+        source
+    ))]
+    FailureCountsStateWrite {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
     #[snafu(display("Error building HTTP client for {}: {}", url.as_str(), source))]
     HttpClient { url: Url, source: reqwest::Error },
 
@@ -38,11 +129,65 @@ pub(crate) enum Error {
     #[snafu(display("Error receiving HTTP response {}: {}", url.as_str(), source))]
     HttpResponse { url: Url, source: reqwest::Error },
 
+    #[snafu(display(
+        "Unable to read the journal of service '{}': journalctl exited {}",
+        service,
+        exit_code
+    ))]
+    Journal { service: String, exit_code: i32 },
+
+    #[snafu(display(
+        "Invalid ping_sample_rate {} in {}, must be greater than 0.0 and at most 1.0",
+        rate,
+        path.display()
+    ))]
+    PingSampleRate { path: PathBuf, rate: f64 },
+
+    #[snafu(display("Send failure state path {} has no parent directory", path.display()))]
+    SendFailureStateParent { path: PathBuf },
+
+    #[snafu(display("Unable to serialize send failure state: {}", source))]
+    SendFailureStateSerialize { source: toml::ser::Error },
+
+    #[snafu(display("Unable to write send failure state to {}: {}", path.display(), source))]
+    SendFailureStateWrite {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Error sending request to {} over its Unix socket: {}", url.as_str(), source))]
+    UnixSend { url: Url, source: std::io::Error },
+
+    #[snafu(display("Error response from {}: '{}'", url.as_str(), status))]
+    UnixResponse { url: Url, status: String },
+
+    #[snafu(display(
+        "Invalid Unix socket URL {}, expected the form unix:///path/to/socket:/request/path",
+        url.as_str()
+    ))]
+    UnixUrl { url: Url },
+
+    #[snafu(display("Unable to parse uptime '{}' from {}", value, path.display()))]
+    UptimeParse { path: PathBuf, value: String },
+
+    #[snafu(display("Unable to read uptime from {}: {}", path.display(), source))]
+    UptimeRead {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
     #[snafu(display("Unable to parse URL {}: {}", url, source))]
     UrlParse {
         url: String,
         source: url::ParseError,
     },
+
+    #[snafu(display(
+        "Unsupported scheme '{}' in URL {}, expected http, https, or unix",
+        url.scheme(),
+        url.as_str()
+    ))]
+    UrlScheme { url: Url },
 }
 
 pub(crate) type Result<T> = std::result::Result<T, Error>;
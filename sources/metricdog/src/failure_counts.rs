@@ -0,0 +1,175 @@
+//! Counts how many health pings in a row have found each checked service unhealthy, so that a
+//! service that's been failing for hours can be told apart from one that just failed once.
+//!
+//! The counts are recorded in a state file along with the boot ID they were counted in.  By
+//! default the counts start over when the boot ID changes, since a reboot restarts every service;
+//! with `persist_across_boots` they're carried over.  A missing or unreadable state file is treated
+//! as if every count was zero.
+
+use crate::error::{self, Result};
+use crate::service_check;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use snafu::{OptionExt, ResultExt};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Where the consecutive failure counts are recorded.
+pub(crate) const DEFAULT_STATE_PATH: &str = "/var/lib/metricdog/consecutive-failures";
+
+/// The number of consecutive failed health checks of each service, as recorded in the state file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct FailureCounts {
+    /// The boot during which the counts were last updated.
+    #[serde(default)]
+    boot_id: Option<String>,
+    /// The counts by service name.
+    #[serde(default)]
+    counts: BTreeMap<String, u32>,
+}
+
+impl FailureCounts {
+    /// Reads the counts from `state_path` for the boot `boot_id`.  Counts from another boot are
+    /// dropped unless `persist_across_boots` is set.
+    pub(crate) fn load<P: AsRef<Path>>(
+        state_path: P,
+        boot_id: &str,
+        persist_across_boots: bool,
+    ) -> Self {
+        let mut counts = read(state_path.as_ref()).unwrap_or_default();
+        if !persist_across_boots && counts.boot_id.as_deref() != Some(boot_id) {
+            counts.counts.clear();
+        }
+        counts.boot_id = Some(boot_id.to_string());
+        counts
+    }
+
+    /// Writes the counts to `state_path`, creating its parent directory if needed.
+    pub(crate) fn save<P: AsRef<Path>>(&self, state_path: P) -> Result<()> {
+        let state_path = state_path.as_ref();
+        let parent = state_path
+            .parent()
+            .context(error::FailureCountsStateParent { path: state_path })?;
+        fs::create_dir_all(parent).context(error::FailureCountsStateWrite { path: parent })?;
+        let data = toml::to_string(self).context(error::FailureCountsStateSerialize)?;
+        fs::write(state_path, data).context(error::FailureCountsStateWrite { path: state_path })
+    }
+
+    /// Counts one more failure of `service` if it's unhealthy, or starts its count over if not.
+    pub(crate) fn record(&mut self, service: &str, is_healthy: bool) {
+        let count = self.counts.entry(service.to_string()).or_insert(0);
+        *count = if is_healthy { 0 } else { *count + 1 };
+    }
+
+    /// Drops the counts of services that aren't in `services`, e.g. because they're no longer
+    /// checked.
+    pub(crate) fn retain(&mut self, services: &[String]) {
+        self.counts.retain(|service, _| services.contains(service));
+    }
+
+    /// Returns the counts in the form they're sent, e.g. `containerd:0,kubelet:5`, sorted by
+    /// service name, with the names sanitized.
+    pub(crate) fn to_param(&self) -> String {
+        self.counts
+            .iter()
+            .map(|(service, count)| {
+                format!(
+                    "{}:{}",
+                    service_check::sanitize_service_name(service),
+                    count
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+}
+
+/// Reads the state file, treating a missing or corrupt file as if there were no counts.
+fn read(state_path: &Path) -> Option<FailureCounts> {
+    let data = match fs::read_to_string(state_path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            debug!(
+                "Unable to read consecutive failure counts from {}: {}",
+                state_path.display(),
+                e
+            );
+            return None;
+        }
+    };
+    toml::from_str(&data)
+        .map_err(|e| {
+            debug!(
+                "Ignoring corrupt consecutive failure counts in {}: {}",
+                state_path.display(),
+                e
+            )
+        })
+        .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn services(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn record_and_reset() {
+        let mut counts = FailureCounts::default();
+        counts.record("kubelet", false);
+        counts.record("containerd", true);
+        counts.record("kubelet", false);
+        assert_eq!(counts.to_param(), "containerd:0,kubelet:2");
+        counts.record("kubelet", true);
+        assert_eq!(counts.to_param(), "containerd:0,kubelet:0");
+    }
+
+    #[test]
+    fn retain_checked_services() {
+        let mut counts = FailureCounts::default();
+        counts.record("kubelet", false);
+        counts.record("old", false);
+        counts.retain(&services(&["kubelet", "containerd"]));
+        assert_eq!(counts.to_param(), "kubelet:1");
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("state").join("consecutive-failures");
+        let mut counts = FailureCounts::load(&path, "boot-a", false);
+        assert_eq!(counts.to_param(), "");
+        counts.record("kubelet", false);
+        counts.save(&path).unwrap();
+        assert_eq!(FailureCounts::load(&path, "boot-a", false), counts);
+    }
+
+    #[test]
+    fn new_boot_starts_over() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("consecutive-failures");
+        let mut counts = FailureCounts::load(&path, "boot-a", false);
+        counts.record("kubelet", false);
+        counts.save(&path).unwrap();
+        assert_eq!(FailureCounts::load(&path, "boot-b", false).to_param(), "");
+        assert_eq!(
+            FailureCounts::load(&path, "boot-b", true).to_param(),
+            "kubelet:1"
+        );
+    }
+
+    #[test]
+    fn corrupt_state_is_ignored() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("consecutive-failures");
+        fs::write(&path, "not = [valid").unwrap();
+        assert_eq!(FailureCounts::load(&path, "boot-a", false).to_param(), "");
+    }
+}
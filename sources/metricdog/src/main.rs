@@ -16,6 +16,29 @@ not its traffic should be proxied. These are set with the `network.http-proxy` a
 settings when Metricdog is invoked by systemd. If you run Metricdog manually, you would need to
 seed the environment with these variables manually.
 
+### Boot Success
+
+Boot success is only sent once per boot. The boot ID is recorded in
+`/var/lib/metricdog/boot-success` after it is sent, and later invocations of `send-boot-success`
+during the same boot are skipped. Pass `--force` to send it anyway.
+
+### Custom Events
+
+Scripts on the host can send their own events through metricdog, with the same endpoint and opt-out,
+using `send-event`. The event is named with `--name`, and each `--value key=value` adds a
+key-value pair to the standard set, e.g.
+`metricdog send-event --name bootstrap_done --value duration=42`. At most 20 values can be given,
+and their keys can't be those of the standard set. Unlike boot success and health pings, a failure
+to send the event makes metricdog exit with an error.
+
+### Update Events
+
+updog and migrator can report the progress of an update with `send-update-event`, giving
+`--status started`, `--status success`, or `--status failure`, and optionally the version being
+updated to with `--target-version`, e.g.
+`metricdog send-update-event --status success --target-version 1.2.3`. Like custom events, a
+failure to send the event makes metricdog exit with an error, which callers may ignore.
+
 # What it Sends
 
 ### The standard set of metrics:
@@ -29,62 +52,168 @@ seed the environment with these variables manually.
 * `seed`: the seed value used to roll-out updates.
 * `version_lock`: the optional setting that controls Bottlerocket update selection.
 * `ignore_waves`: an update setting that allows hosts to update before their seed is reached.
+* `metrics-schema-version`: the version of this set of parameters, which is bumped whenever a
+  parameter is added, removed, or renamed, so the metrics backend knows how to parse the request.
 
 ### Additionally, when `metricdog` sends a 'health ping', it adds:
 
 * `is_healthy`: true or false based on whether critical services are running.
-* `failed_services`: a list of critical services that have failed, if any.
+* `failed_services`: a list of critical services that have failed, if any. At most 10 are listed,
+  followed by a `+K more` marker that counts the rest, e.g. `a:1,b:2,+3 more`. Characters in service
+  names other than ASCII letters, digits, `-`, `_`, `.`, and `@` are replaced by `_`, here and in
+  the other values that name services.
+* `failed_services_count`: the number of critical services that have failed, including any that
+  aren't listed in `failed_services`.
+* `failure-signatures`: for each failed service, the first 12 hex characters of the SHA-256 hash of
+  its most recent journal line, e.g. `kubelet:ab12cd34ef56`, so identical failures can be grouped
+  without sending any log content. Services whose journal can't be read are left out.
+* `system-state`: the output of `systemctl is-system-running`, e.g. `running` or `degraded`. This is
+  omitted if the state can't be determined.
+* `pending-migration-debris`: the number of intermediate data stores left behind by settings
+  migrations that didn't finish, which can mean the host is stuck mid-update. This is omitted if
+  the data store directory can't be read.
+* `consecutive-failures`: for each checked service, the number of health pings in a row that found
+  it unhealthy, sorted by service name, e.g. `containerd:0,kubelet:5`.
+* `boot_id`: the ID that the kernel gave the current boot, so pings can be correlated across
+  reboots. This is omitted if it can't be read.
+* `uptime_seconds`: the whole seconds since the host booted. This is omitted if it can't be read.
+
+### Additionally, when `metricdog` sends an 'update' event, it adds:
+
+* `status`: the status of the update: `started`, `success`, or `failure`.
+* `target_version`: the version being updated to, if the caller gave it.
 
 # Configuration
 
 Configuration is read from a TOML file, which is generated from Bottlerocket settings:
 
 ```toml
-# the url to which metricdog will send metrics information
+# the url to which metricdog will send metrics information; a collector listening on a unix
+# socket can be given as "unix:///run/telemetry.sock:/metrics", i.e. the socket path, a colon,
+# and the request path
 metrics_url = "https://example.com/metrics"
 # whether or not metricdog will send metrics. opt-out by setting this to false
 send_metrics = true
 # a list of systemd service names that will be checked
 service_checks = ["apiserver", "containerd", "kubelet"]
-# the region
+# optional: the region (read from the API if absent)
 region = "us-west-2"
-# the update wave seed
+# optional: the update wave seed, from 0 to 2047 (read from the API if absent)
 seed = 1234
-# what version bottlerocket should stay on
+# optional: what version bottlerocket should stay on (read from the API if absent)
 version_lock = "latest"
-# whether bottlerocket should ignore update roll-out timing
+# optional: whether bottlerocket should ignore update roll-out timing (read from the API if absent)
 ignore_waves = false
+# optional: the fraction of hosts, in (0.0, 1.0], that send health pings (defaults to 1.0)
+ping_sample_rate = 1.0
+# optional: whether a 'degraded' system state makes the host unhealthy (defaults to false)
+degraded_is_unhealthy = false
+# optional: for how many seconds repeated, identical health ping failures are logged at debug
+# level (defaults to 86400)
+send_failure_window = 86400
+# optional: the directory holding the data stores, which is checked for leftover intermediate
+# data stores (defaults to "/var/lib/bottlerocket/datastore")
+datastore_path = "/var/lib/bottlerocket/datastore"
+# optional: the number of seconds, at most 600, across which the fleet's health pings are spread
+# (no delay by default)
+ping_splay_seconds = 300
+# optional: the longest URL, in bytes, that metricdog sends (defaults to 8192)
+max_url_length = 8192
+# optional: whether the consecutive failure counts of services are kept across reboots (defaults to
+# false)
+persist_across_boots = false
+# optional: how many seconds each systemctl or journalctl command that checks a service may take
+# before it's killed and the service is reported as failed with exit code 124 (defaults to 5)
+service_check_timeout_seconds = 5
 ```
+
+If `region`, `seed`, `version_lock`, or `ignore_waves` is absent, the missing values are read
+from the Bottlerocket API, so they stay current when settings change after the config is written.
+If the API can't be reached within a couple of seconds, or doesn't have a value, metricdog falls
+back to the region `unknown`, a seed derived from the machine ID, the version lock `latest`, and
+not ignoring waves.  A config whose seed is out of range, or whose `metrics_url` isn't a URL while
+`send_metrics` is true, is an error.
+
+When `ping_sample_rate` is less than 1.0, each host decides whether to send a health ping based
+on a hash of its seed and boot ID, so the decision is stable for the life of a boot.
+Boot success is always sent.
+
+When `ping_splay_seconds` is set, `send-health-ping` waits before sending for a delay in
+[0, `ping_splay_seconds`) derived from the seed, so that the pings of a fleet whose timers fire at
+the same time are spread out. The delay comes before the request, so it doesn't shorten the
+request's timeout. Pass `--no-splay` to send immediately, e.g. when running metricdog by hand.
+The splay is capped at 600 seconds, with a warning if the config asks for more, because
+metricdog.service only gives `send-health-ping` 630 seconds to finish: the longest delay, plus 30
+seconds for the health checks and the request.
+
+A health ping whose URL would be longer than `max_url_length` is cut down to fit, since some
+collectors reject long URLs: `failure-signatures` is emptied first, then `failed_services` is cut
+to its first entries followed by a `+K more` marker, e.g. `a:1,b:2,+3 more`. What was dropped is
+logged as a warning.
+
+### Consecutive Failures
+
+The number of health pings in a row that found each service unhealthy is recorded in
+`/var/lib/metricdog/consecutive-failures` along with the boot ID. A healthy check starts a service's
+count over. The counts also start over when the host reboots, unless `persist_across_boots` is set.
+The counts are updated even if the health ping can't be sent.
+
+### Send Failures
+
+When the metrics endpoint can't be reached, every health ping fails the same way. To keep these
+failures from flooding the journal, the last failure is recorded in
+`/var/lib/metricdog/send-failure`. A failure is logged as an error the first time it happens; if the
+same kind of failure against the same endpoint happens again within `send_failure_window` seconds,
+it's logged at debug level, with a reminder at warn level once an hour. A successful health ping
+clears the record. Only the logging is limited; metricdog still exits with an error each time.
+
+A request to an `http` or `https` endpoint that can't connect, or that gets a 5xx response, is
+retried twice, after 1 and then 2 seconds, so that a momentary failure, e.g. of DNS at boot, doesn't
+lose the event. The timeout applies to each attempt. Other responses, like a 404, aren't retried,
+and neither are requests that time out once connected.
 */
 
 #![deny(rust_2018_idioms)]
 
+mod api_settings;
 mod args;
+mod boot_info;
+mod boot_success;
 mod config;
 mod error;
+mod failure_counts;
 #[cfg(test)]
 mod main_test;
 mod metricdog;
 #[cfg(test)]
 mod metricdog_test;
+mod migration_debris;
+mod sampling;
+mod send_failure;
 mod service_check;
+mod unix_socket;
+mod url_limit;
 
 use crate::args::{Arguments, Command};
 use crate::config::Config;
 use crate::error::Result;
+use crate::failure_counts::FailureCounts;
 use crate::metricdog::Metricdog;
 use crate::service_check::{ServiceCheck, SystemdCheck};
 use bottlerocket_release::BottlerocketRelease;
-use log::error;
+use log::{error, info, warn};
 use simplelog::{Config as LogConfig, SimpleLogger};
 use snafu::ResultExt;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::thread;
+use std::time::Duration;
 use structopt::StructOpt;
 
 fn main() -> ! {
     let args = Arguments::from_args();
     SimpleLogger::init(args.log_level, LogConfig::default()).expect("unable to configure logger");
-    process::exit(match main_inner(args, Box::new(SystemdCheck {})) {
+    process::exit(match main_inner(args, Box::new(SystemdCheck::default())) {
         Ok(()) => 0,
         Err(err) => {
             eprintln!("{}", err);
@@ -93,8 +222,54 @@ fn main() -> ! {
     })
 }
 
+/// Sends boot success unless it was already sent during the current boot, which is determined by
+/// comparing the boot ID recorded in `state_path` to the current boot ID. `force` bypasses the
+/// check. We don't want to fail the boot if anything goes wrong here, so errors are only logged.
+fn send_boot_success_once(metricdog: &Metricdog, state_path: &Path, force: bool) {
+    let boot_id = match boot_success::current_boot_id() {
+        Ok(boot_id) => Some(boot_id),
+        Err(err) => {
+            warn!(
+                "Unable to determine boot ID, sending boot success anyway: {}",
+                err
+            );
+            None
+        }
+    };
+
+    if let Some(boot_id) = &boot_id {
+        if !force {
+            match boot_success::already_sent(state_path, boot_id) {
+                Ok(true) => {
+                    info!(
+                        "Boot success was already sent for boot ID '{}', skipping",
+                        boot_id
+                    );
+                    return;
+                }
+                Ok(false) => {}
+                Err(err) => warn!("Unable to check boot success state: {}", err),
+            }
+        }
+    }
+
+    if let Err(err) = metricdog.send_boot_success() {
+        error!("Error while reporting boot success: {}", err);
+        return;
+    }
+
+    if let Some(boot_id) = &boot_id {
+        if let Err(err) = boot_success::record(state_path, boot_id) {
+            error!("Unable to record boot success state: {}", err);
+        }
+    }
+}
+
 /// pub(crate) for testing.
-pub(crate) fn main_inner(arguments: Arguments, service_check: Box<dyn ServiceCheck>) -> Result<()> {
+pub(crate) fn main_inner(
+    arguments: Arguments,
+    mut service_check: Box<dyn ServiceCheck>,
+) -> Result<()> {
     // load the metricdog config file
     let config = match &arguments.config {
         None => Config::new()?,
@@ -114,20 +289,66 @@ pub(crate) fn main_inner(arguments: Arguments, service_check: Box<dyn ServiceChe
     }
     .context(error::BottlerocketRelease)?;
 
+    // keep what we need to decide whether this host sends health pings
+    let seed = config.seed;
+    let ping_sample_rate = config.ping_sample_rate;
+    let send_failure_window = config.send_failure_window;
+    let ping_splay_seconds = config.ping_splay_seconds;
+    let persist_across_boots = config.persist_across_boots;
+
     // instantiate the metricdog object
+    service_check.set_command_timeout(Duration::from_secs(config.service_check_timeout_seconds));
     let metricdog = Metricdog::from_parts(config, os_release, service_check)?;
 
     // execute the specified command
     match arguments.command {
-        Command::SendBootSuccess => {
-            if let Err(err) = metricdog.send_boot_success() {
-                // we don't want to fail the boot if there is a failure to send this message, so
-                // we log the error and return Ok(())
-                error!("Error while reporting boot success: {}", err);
-            }
+        Command::SendBootSuccess { force } => {
+            let state_path = arguments
+                .boot_success_state
+                .unwrap_or_else(|| PathBuf::from(boot_success::DEFAULT_STATE_PATH));
+            send_boot_success_once(&metricdog, &state_path, force);
+        }
+        Command::SendEvent { name, values } => {
+            metricdog.send_event(&name, &values)?;
         }
-        Command::SendHealthPing => {
-            metricdog.send_health_ping()?;
+        Command::SendUpdateEvent {
+            status,
+            target_version,
+        } => {
+            metricdog.send_update_event(status, target_version.as_deref())?;
+        }
+        Command::SendHealthPing { no_splay } => {
+            // the boot ID is only used to vary the sampling decision between boots, so if we can't
+            // read it we still make a stable decision based on the seed.
+            let boot_id = boot_success::current_boot_id().unwrap_or_default();
+            if sampling::should_send_health_ping(seed, &boot_id, ping_sample_rate) {
+                // wait before the request is built, so the delay doesn't use up its timeout.
+                if let (Some(splay_seconds), false) = (ping_splay_seconds, no_splay) {
+                    if splay_seconds > sampling::MAX_SPLAY_SECONDS {
+                        warn!(
+                            "ping_splay_seconds {} is more than the limit of {}, using the limit",
+                            splay_seconds,
+                            sampling::MAX_SPLAY_SECONDS
+                        );
+                    }
+                    let delay = sampling::splay_delay(seed, splay_seconds);
+                    info!("Waiting {:?} before sending health ping", delay);
+                    thread::sleep(delay);
+                }
+                let counts_path = arguments
+                    .consecutive_failures_state
+                    .unwrap_or_else(|| PathBuf::from(failure_counts::DEFAULT_STATE_PATH));
+                let mut counts = FailureCounts::load(&counts_path, &boot_id, persist_across_boots);
+                let result = metricdog.send_health_ping(&mut counts);
+                // the counts describe the health checks, so they're kept even if the send failed.
+                if let Err(err) = counts.save(&counts_path) {
+                    warn!("Unable to record consecutive failure counts: {}", err);
+                }
+                let state_path = arguments
+                    .send_failure_state
+                    .unwrap_or_else(|| PathBuf::from(send_failure::DEFAULT_STATE_PATH));
+                send_failure::report(result, &state_path, send_failure_window)?;
+            }
         }
     }
     Ok(())
@@ -1,12 +1,15 @@
 use crate::args::{Arguments, Command};
-use crate::error::Result;
+use crate::error::{Error, Result};
 use crate::main_inner;
+use crate::metricdog::{UpdateStatus, MAX_EVENT_VALUES};
 use crate::service_check::{ServiceCheck, ServiceHealth};
 use httptest::responders::status_code;
 use httptest::{matchers::*, Expectation, Server};
 use log::LevelFilter;
+use std::cell::Cell;
 use std::fs::write;
 use std::path::PathBuf;
+use std::rc::Rc;
 use tempfile::TempDir;
 
 const OS_RELEASE: &str = r#"PRETTY_NAME=Bottlerocket
@@ -31,6 +34,16 @@ impl ServiceCheck for MockCheck {
             })
         }
     }
+
+    fn system_state(&self) -> Option<String> {
+        Some(String::from("running"))
+    }
+
+    fn last_journal_line(&self, _service_name: &str) -> Result<Option<String>> {
+        Ok(Some(String::from(
+            "Main process exited, code=exited, status=1/FAILURE",
+        )))
+    }
 }
 
 // dynamically create a config file where we can set server port, list of services, and send_metrics
@@ -81,6 +94,21 @@ fn os_release_path(tempdir: &TempDir) -> PathBuf {
     tempdir.path().join("os-release").to_str().unwrap().into()
 }
 
+// create the path to the boot success state file in the tempdir
+fn boot_success_state_path(tempdir: &TempDir) -> PathBuf {
+    tempdir.path().join("state").join("boot-success")
+}
+
+// create the path to the send failure state file in the tempdir
+fn send_failure_state_path(tempdir: &TempDir) -> PathBuf {
+    tempdir.path().join("state").join("send-failure")
+}
+
+// create the path to the consecutive failures state file in the tempdir
+fn consecutive_failures_state_path(tempdir: &TempDir) -> PathBuf {
+    tempdir.path().join("state").join("consecutive-failures")
+}
+
 #[test]
 fn send_boot_success() {
     let server = Server::run();
@@ -94,7 +122,10 @@ fn send_boot_success() {
         config: Some(config_path(&tempdir)),
         log_level: LevelFilter::Off,
         os_release: Some(os_release_path(&tempdir)),
-        command: Command::SendBootSuccess,
+        boot_success_state: Some(boot_success_state_path(&tempdir)),
+        send_failure_state: Some(send_failure_state_path(&tempdir)),
+        consecutive_failures_state: Some(consecutive_failures_state_path(&tempdir)),
+        command: Command::SendBootSuccess { force: false },
     };
     main_inner(args, Box::new(MockCheck {})).unwrap();
 }
@@ -115,7 +146,10 @@ fn opt_out() {
         config: Some(config_path(&tempdir)),
         log_level: LevelFilter::Off,
         os_release: Some(os_release_path(&tempdir)),
-        command: Command::SendBootSuccess,
+        boot_success_state: Some(boot_success_state_path(&tempdir)),
+        send_failure_state: Some(send_failure_state_path(&tempdir)),
+        consecutive_failures_state: Some(consecutive_failures_state_path(&tempdir)),
+        command: Command::SendBootSuccess { force: false },
     };
     main_inner(args, Box::new(MockCheck {})).unwrap();
 }
@@ -129,7 +163,10 @@ fn send_boot_success_no_server() {
         config: Some(config_path(&tempdir)),
         log_level: LevelFilter::Off,
         os_release: Some(os_release_path(&tempdir)),
-        command: Command::SendBootSuccess,
+        boot_success_state: Some(boot_success_state_path(&tempdir)),
+        send_failure_state: Some(send_failure_state_path(&tempdir)),
+        consecutive_failures_state: Some(consecutive_failures_state_path(&tempdir)),
+        command: Command::SendBootSuccess { force: false },
     };
     main_inner(args, Box::new(MockCheck {})).unwrap();
 }
@@ -148,7 +185,10 @@ fn send_boot_success_404() {
         config: Some(config_path(&tempdir)),
         log_level: LevelFilter::Off,
         os_release: Some(os_release_path(&tempdir)),
-        command: Command::SendBootSuccess,
+        boot_success_state: Some(boot_success_state_path(&tempdir)),
+        send_failure_state: Some(send_failure_state_path(&tempdir)),
+        consecutive_failures_state: Some(consecutive_failures_state_path(&tempdir)),
+        command: Command::SendBootSuccess { force: false },
     };
     main_inner(args, Box::new(MockCheck {})).unwrap();
 }
@@ -161,6 +201,10 @@ fn send_health_ping() {
         request::method_path("GET", "/metrics"),
         request::query(url_decoded(contains(("is_healthy", "false")))),
         request::query(url_decoded(contains(("failed_services", "afailed:1")))),
+        request::query(url_decoded(contains((
+            "failure-signatures",
+            "afailed:ce631641636e"
+        )))),
     ];
     server.expect(Expectation::matching(matcher).respond_with(status_code(200)));
     let port = server.addr().port();
@@ -169,7 +213,317 @@ fn send_health_ping() {
         config: Some(config_path(&tempdir)),
         log_level: LevelFilter::Off,
         os_release: Some(os_release_path(&tempdir)),
-        command: Command::SendHealthPing,
+        boot_success_state: Some(boot_success_state_path(&tempdir)),
+        send_failure_state: Some(send_failure_state_path(&tempdir)),
+        consecutive_failures_state: Some(consecutive_failures_state_path(&tempdir)),
+        command: Command::SendHealthPing { no_splay: false },
     };
     main_inner(args, Box::new(MockCheck {})).unwrap();
 }
+
+#[test]
+/// assert that send-health-ping exits with an error, and records the failure, if the server sends
+/// a 404
+fn send_health_ping_404() {
+    let server = Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/metrics"))
+            .respond_with(status_code(404)),
+    );
+    let port = server.addr().port();
+    let tempdir = create_test_files(port, &[], true);
+    assert!(main_inner(health_ping_args(&tempdir), Box::new(MockCheck {})).is_err());
+    assert!(send_failure_state_path(&tempdir).is_file());
+}
+
+// build arguments for send-boot-success using the files in `tempdir`
+fn boot_success_args(tempdir: &TempDir, force: bool) -> Arguments {
+    Arguments {
+        config: Some(config_path(&tempdir)),
+        log_level: LevelFilter::Off,
+        os_release: Some(os_release_path(&tempdir)),
+        boot_success_state: Some(boot_success_state_path(&tempdir)),
+        send_failure_state: Some(send_failure_state_path(&tempdir)),
+        consecutive_failures_state: Some(consecutive_failures_state_path(&tempdir)),
+        command: Command::SendBootSuccess { force },
+    }
+}
+
+#[test]
+/// assert that the first send-boot-success of a boot is sent and recorded
+fn send_boot_success_first_send() {
+    let server = Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/metrics"))
+            .times(1)
+            .respond_with(status_code(200)),
+    );
+    let port = server.addr().port();
+    let tempdir = create_test_files(port, &[], true);
+    main_inner(boot_success_args(&tempdir, false), Box::new(MockCheck {})).unwrap();
+    assert!(boot_success_state_path(&tempdir).is_file());
+}
+
+#[test]
+/// assert that a second send-boot-success during the same boot is not sent
+fn send_boot_success_suppressed() {
+    let server = Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/metrics"))
+            .times(1)
+            .respond_with(status_code(200)),
+    );
+    let port = server.addr().port();
+    let tempdir = create_test_files(port, &[], true);
+    main_inner(boot_success_args(&tempdir, false), Box::new(MockCheck {})).unwrap();
+    main_inner(boot_success_args(&tempdir, false), Box::new(MockCheck {})).unwrap();
+}
+
+#[test]
+/// assert that --force sends boot success again during the same boot
+fn send_boot_success_forced() {
+    let server = Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/metrics"))
+            .times(2)
+            .respond_with(status_code(200)),
+    );
+    let port = server.addr().port();
+    let tempdir = create_test_files(port, &[], true);
+    main_inner(boot_success_args(&tempdir, false), Box::new(MockCheck {})).unwrap();
+    main_inner(boot_success_args(&tempdir, true), Box::new(MockCheck {})).unwrap();
+}
+
+/// A `ServiceCheck` for which `kubelet` is unhealthy during the pings listed in `failing_pings`,
+/// counting from 1, and every other service is healthy. The ping count is shared so it survives
+/// the check being moved into each run of `main_inner`.
+struct ToggleCheck {
+    ping: Rc<Cell<u32>>,
+    failing_pings: &'static [u32],
+}
+
+impl ServiceCheck for ToggleCheck {
+    fn check(&self, service_name: &str) -> Result<ServiceHealth> {
+        let is_healthy =
+            service_name != "kubelet" || !self.failing_pings.contains(&self.ping.get());
+        Ok(ServiceHealth {
+            is_healthy,
+            exit_code: if is_healthy { None } else { Some(1) },
+        })
+    }
+
+    fn system_state(&self) -> Option<String> {
+        Some(String::from("running"))
+    }
+
+    fn last_journal_line(&self, _service_name: &str) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+// build arguments for send-health-ping using the files in `tempdir`
+fn health_ping_args(tempdir: &TempDir) -> Arguments {
+    Arguments {
+        config: Some(config_path(&tempdir)),
+        log_level: LevelFilter::Off,
+        os_release: Some(os_release_path(&tempdir)),
+        boot_success_state: Some(boot_success_state_path(&tempdir)),
+        send_failure_state: Some(send_failure_state_path(&tempdir)),
+        consecutive_failures_state: Some(consecutive_failures_state_path(&tempdir)),
+        command: Command::SendHealthPing { no_splay: true },
+    }
+}
+
+#[test]
+/// assert that consecutive failures are counted across health pings and start over when the
+/// service is healthy again
+fn send_health_ping_consecutive_failures() {
+    let mut server = Server::run();
+    let port = server.addr().port();
+    let tempdir = create_test_files(port, &["kubelet", "containerd"], true);
+    let ping = Rc::new(Cell::new(0));
+    for expected in &[
+        "containerd:0,kubelet:1",
+        "containerd:0,kubelet:2",
+        "containerd:0,kubelet:0",
+    ] {
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", "/metrics"),
+                request::query(url_decoded(contains(("consecutive-failures", *expected)))),
+            ])
+            .respond_with(status_code(200)),
+        );
+        ping.set(ping.get() + 1);
+        let check = ToggleCheck {
+            ping: Rc::clone(&ping),
+            failing_pings: &[1, 2],
+        };
+        main_inner(health_ping_args(&tempdir), Box::new(check)).unwrap();
+        server.verify_and_clear();
+    }
+}
+
+// build arguments for send-event using the files in `tempdir`
+fn send_event_args(tempdir: &TempDir, values: &[(&str, &str)]) -> Arguments {
+    Arguments {
+        config: Some(config_path(&tempdir)),
+        log_level: LevelFilter::Off,
+        os_release: Some(os_release_path(&tempdir)),
+        boot_success_state: Some(boot_success_state_path(&tempdir)),
+        send_failure_state: Some(send_failure_state_path(&tempdir)),
+        consecutive_failures_state: Some(consecutive_failures_state_path(&tempdir)),
+        command: Command::SendEvent {
+            name: String::from("bootstrap_done"),
+            values: values
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        },
+    }
+}
+
+#[test]
+/// assert that send-event sends the event name and the caller's values with the standard set
+fn send_event() {
+    let server = Server::run();
+    let matcher = all_of![
+        request::method_path("GET", "/metrics"),
+        request::query(url_decoded(contains(("event", "bootstrap_done")))),
+        request::query(url_decoded(contains(("variant", "myvariant")))),
+        request::query(url_decoded(contains(("duration", "42")))),
+        request::query(url_decoded(contains(("query", "a=b")))),
+    ];
+    server.expect(
+        Expectation::matching(matcher)
+            .times(1)
+            .respond_with(status_code(200)),
+    );
+    let port = server.addr().port();
+    let tempdir = create_test_files(port, &[], true);
+    let args = send_event_args(&tempdir, &[("duration", "42"), ("query", "a=b")]);
+    main_inner(args, Box::new(MockCheck {})).unwrap();
+}
+
+#[test]
+/// assert that send-event sends nothing when the user sets `send_metrics` to false
+fn send_event_opt_out() {
+    let server = Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/metrics"))
+            .times(0)
+            .respond_with(status_code(200)),
+    );
+    let port = server.addr().port();
+    let tempdir = create_test_files(port, &[], false);
+    let args = send_event_args(&tempdir, &[("duration", "42")]);
+    main_inner(args, Box::new(MockCheck {})).unwrap();
+}
+
+#[test]
+/// assert that send-event fails without sending when a value's key is a standard parameter
+fn send_event_reserved_key() {
+    let server = Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/metrics"))
+            .times(0)
+            .respond_with(status_code(200)),
+    );
+    let port = server.addr().port();
+    let tempdir = create_test_files(port, &[], true);
+    for key in &["version", "metrics-schema-version", "event"] {
+        let args = send_event_args(&tempdir, &[("duration", "42"), (*key, "1")]);
+        let result = main_inner(args, Box::new(MockCheck {}));
+        assert!(
+            matches!(result, Err(Error::EventValueReserved { .. })),
+            "{}",
+            key
+        );
+    }
+}
+
+#[test]
+/// assert that send-event fails without sending when given too many values
+fn send_event_too_many_values() {
+    let server = Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/metrics"))
+            .times(1)
+            .respond_with(status_code(200)),
+    );
+    let port = server.addr().port();
+    let tempdir = create_test_files(port, &[], true);
+    let keys: Vec<String> = (0..=MAX_EVENT_VALUES)
+        .map(|i| format!("key{}", i))
+        .collect();
+    let values: Vec<(&str, &str)> = keys.iter().map(|key| (key.as_str(), "1")).collect();
+    // the most values that are allowed are sent.
+    let args = send_event_args(&tempdir, &values[..MAX_EVENT_VALUES]);
+    main_inner(args, Box::new(MockCheck {})).unwrap();
+    let args = send_event_args(&tempdir, &values);
+    let result = main_inner(args, Box::new(MockCheck {}));
+    assert!(matches!(result, Err(Error::EventValueCount { .. })));
+}
+
+// build arguments for send-update-event using the files in `tempdir`
+fn update_event_args(
+    tempdir: &TempDir,
+    status: UpdateStatus,
+    target_version: Option<&str>,
+) -> Arguments {
+    Arguments {
+        config: Some(config_path(&tempdir)),
+        log_level: LevelFilter::Off,
+        os_release: Some(os_release_path(&tempdir)),
+        boot_success_state: Some(boot_success_state_path(&tempdir)),
+        send_failure_state: Some(send_failure_state_path(&tempdir)),
+        consecutive_failures_state: Some(consecutive_failures_state_path(&tempdir)),
+        command: Command::SendUpdateEvent {
+            status,
+            target_version: target_version.map(String::from),
+        },
+    }
+}
+
+#[test]
+/// assert that send-update-event sends the status and target version with the standard set
+fn send_update_event() {
+    let server = Server::run();
+    let matcher = all_of![
+        request::method_path("GET", "/metrics"),
+        request::query(url_decoded(contains(("sender", "metricdog")))),
+        request::query(url_decoded(contains(("event", "update")))),
+        request::query(url_decoded(contains(("version", "1.2.3")))),
+        request::query(url_decoded(contains(("status", "success")))),
+        request::query(url_decoded(contains(("target_version", "1.3.0")))),
+    ];
+    server.expect(
+        Expectation::matching(matcher)
+            .times(1)
+            .respond_with(status_code(200)),
+    );
+    let port = server.addr().port();
+    let tempdir = create_test_files(port, &[], true);
+    let args = update_event_args(&tempdir, UpdateStatus::Success, Some("1.3.0"));
+    main_inner(args, Box::new(MockCheck {})).unwrap();
+}
+
+#[test]
+/// assert that send-update-event leaves out the target version if it isn't given
+fn send_update_event_without_target_version() {
+    let server = Server::run();
+    let matcher = all_of![
+        request::method_path("GET", "/metrics"),
+        request::query(url_decoded(contains(("status", "started")))),
+        request::query(url_decoded(not(contains(key("target_version"))))),
+    ];
+    server.expect(
+        Expectation::matching(matcher)
+            .times(1)
+            .respond_with(status_code(200)),
+    );
+    let port = server.addr().port();
+    let tempdir = create_test_files(port, &[], true);
+    let args = update_event_args(&tempdir, UpdateStatus::Started, None);
+    main_inner(args, Box::new(MockCheck {})).unwrap();
+}
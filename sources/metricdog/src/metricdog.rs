@@ -1,12 +1,29 @@
+//! Sends metrics as query parameters in GET requests to the configured metrics URL.
+//!
+//! Every request carries the standard parameters from `standard_parameters`, and health pings add
+//! the parameters from `health_ping_values`.  Update events add `status` and `target_version`.
+//! Every request also carries `metrics-schema-version`, which tells the metrics backend how to
+//! parse the request.  Whenever a parameter is added, removed, or renamed, bump
+//! `METRICS_SCHEMA_VERSION` and add a snapshot of the new parameter set to the schema test in
+//! `metricdog_test`; the test fails if the parameters change without a bump.  The strict tests
+//! there check every parameter and value that's actually sent, and need the same update.
+
+use crate::boot_info;
 use crate::config::Config;
 use crate::error::{self, Result};
-use crate::service_check::ServiceCheck;
+use crate::failure_counts::FailureCounts;
+use crate::migration_debris;
+use crate::service_check::{self, ServiceCheck, ServiceHealth};
+use crate::unix_socket::{self, UnixEndpoint, UNIX_SCHEME};
+use crate::url_limit;
 use bottlerocket_release::BottlerocketRelease;
-use log::debug;
+use log::{debug, warn};
 use reqwest::blocking::Client;
-use snafu::ResultExt;
+use snafu::{ensure, ResultExt};
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::thread;
 use std::time::Duration;
 use url::Url;
 
@@ -15,6 +32,55 @@ use url::Url;
 /// chosen and can be changed if the need arises.
 const DEFAULT_TIMEOUT_SECONDS: u64 = 20;
 
+/// The delays before each retry of a request that couldn't connect or got a server error.  They're
+/// short, so that retries don't hold up callers like mark-boot-success for long.
+const SEND_RETRY_DELAYS: &[Duration] = &[Duration::from_secs(1), Duration::from_secs(2)];
+
+/// The most key-value pairs that `send_event` sends for the caller.
+pub(crate) const MAX_EVENT_VALUES: usize = 20;
+
+/// The status of an update that's reported with `send_update_event`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum UpdateStatus {
+    Started,
+    Success,
+    Failure,
+}
+
+impl UpdateStatus {
+    /// The statuses as they're given on the command line and sent.
+    pub(crate) const VALUES: [&'static str; 3] = ["started", "success", "failure"];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            UpdateStatus::Started => "started",
+            UpdateStatus::Success => "success",
+            UpdateStatus::Failure => "failure",
+        }
+    }
+}
+
+impl FromStr for UpdateStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "started" => Ok(UpdateStatus::Started),
+            "success" => Ok(UpdateStatus::Success),
+            "failure" => Ok(UpdateStatus::Failure),
+            _ => Err(format!(
+                "invalid update status '{}', expected one of {}",
+                s,
+                UpdateStatus::VALUES.join(", ")
+            )),
+        }
+    }
+}
+
+/// The version of the set of parameters that metricdog sends; see the module docs for when to bump
+/// it.
+pub(crate) const METRICS_SCHEMA_VERSION: u32 = 6;
+
 /// Sends key-value pairs as query params to a URL configured in `config`. Also provides the ability
 /// to check the health of a list of services and send information about whether or not the services
 /// are running.
@@ -28,11 +94,18 @@ pub(crate) struct Metricdog {
     healthcheck: Box<dyn ServiceCheck>,
     /// The metrics_url, having been parsed during construction of the `Metricdog` object.
     metrics_url: Url,
+    /// The delays before each retry of a failed request, `SEND_RETRY_DELAYS` unless a test sets
+    /// them.
+    retry_delays: &'static [Duration],
+    /// Where the kernel's files with the boot ID and uptime are read from, `/proc` unless a test
+    /// sets it.
+    proc_path: PathBuf,
 }
 
 impl Metricdog {
     /// Create a new instance by passing in the `Config`, `BottlerocketRelease`, and `ServiceCheck`
-    /// objects.
+    /// objects. The metrics URL must be `http`, `https`, or `unix`; see the `unix_socket` module
+    /// for the form of `unix` URLs.
     pub(crate) fn from_parts(
         config: Config,
         os_release: BottlerocketRelease,
@@ -41,14 +114,37 @@ impl Metricdog {
         let metrics_url = Url::from_str(&config.metrics_url).context(error::UrlParse {
             url: &config.metrics_url,
         })?;
+        ensure!(
+            matches!(metrics_url.scheme(), "http" | "https" | UNIX_SCHEME),
+            error::UrlScheme { url: metrics_url }
+        );
+        if metrics_url.scheme() == UNIX_SCHEME {
+            UnixEndpoint::from_url(&metrics_url)?;
+        }
         Ok(Self {
             config,
             os_release,
             healthcheck,
             metrics_url,
+            retry_delays: SEND_RETRY_DELAYS,
+            proc_path: PathBuf::from(boot_info::DEFAULT_PROC_PATH),
         })
     }
 
+    /// Sets where the boot ID and uptime are read from, so that tests can supply them.
+    #[cfg(test)]
+    pub(crate) fn with_proc_path<P: Into<PathBuf>>(mut self, proc_path: P) -> Self {
+        self.proc_path = proc_path.into();
+        self
+    }
+
+    /// Sets the delays before each retry of a failed request, so that tests don't wait long.
+    #[cfg(test)]
+    pub(crate) fn with_retry_delays(mut self, retry_delays: &'static [Duration]) -> Self {
+        self.retry_delays = retry_delays;
+        self
+    }
+
     /// # Description
     ///
     /// Sends key-value pairs as query parameters in a GET request to the URL in `config`. A
@@ -62,7 +158,9 @@ impl Metricdog {
     /// * `event`:           The name of the type of metrics event that is being sent. For example
     ///                      `boot_success` or `health_ping`.
     /// * `values`:          The key-value pairs that you want to send. These will be sorted by key
-    ///                      before sending to ensure consistency of key-value ordering.
+    ///                      before sending to ensure consistency of key-value ordering. If the URL
+    ///                      would be longer than `config.max_url_length`, they're cut down as
+    ///                      described in the `url_limit` module.
     /// * `timeout_seconds`: The timeout setting for the HTTP client. Defaults to
     ///                      `DEFAULT_TIMEOUT_SECONDS` when `None` is passed.
     pub(crate) fn send<S1, S2>(
@@ -76,18 +174,45 @@ impl Metricdog {
         S1: AsRef<str>,
         S2: AsRef<str>,
     {
+        let standard = self.standard_parameters(sender.as_ref(), event.as_ref());
+        let url = match values {
+            None => self.build_url(&standard, None),
+            Some(map) => {
+                let max = self.config.max_url_length;
+                let (map, truncation) = url_limit::truncate(map, |values| {
+                    self.build_url(&standard, Some(values)).as_str().len() <= max
+                });
+                if truncation.dropped_signatures {
+                    warn!("URL longer than {} bytes, dropped failure-signatures", max);
+                }
+                if truncation.dropped_services > 0 {
+                    warn!(
+                        "URL longer than {} bytes, dropped {} failed services",
+                        max, truncation.dropped_services
+                    );
+                }
+                if !truncation.fits {
+                    warn!("URL is still longer than {} bytes, sending it anyway", max);
+                }
+                self.build_url(&standard, Some(&map))
+            }
+        };
+        self.send_get_request(url, timeout_seconds)?;
+        Ok(())
+    }
+
+    /// Returns the metrics URL with the `standard` parameters and `values` in its query string.
+    fn build_url(
+        &self,
+        standard: &[(&str, String)],
+        values: Option<&HashMap<String, String>>,
+    ) -> Url {
         let mut url = self.metrics_url.clone();
         {
             let mut q = url.query_pairs_mut();
-            q.append_pair("sender", sender.as_ref());
-            q.append_pair("event", event.as_ref());
-            q.append_pair("version", &self.os_release.version_id.to_string());
-            q.append_pair("variant", &self.os_release.variant_id);
-            q.append_pair("arch", &self.os_release.arch);
-            q.append_pair("region", &self.config.region);
-            q.append_pair("seed", &self.config.seed.to_string());
-            q.append_pair("version_lock", &self.config.version_lock);
-            q.append_pair("ignore_waves", &self.config.ignore_waves.to_string());
+            for (key, value) in standard {
+                q.append_pair(key, value);
+            }
             if let Some(map) = values {
                 let mut keys: Vec<&String> = map.keys().collect();
                 // sorted for consistency
@@ -99,8 +224,23 @@ impl Metricdog {
                 }
             }
         }
-        Self::send_get_request(url, timeout_seconds)?;
-        Ok(())
+        url
+    }
+
+    /// Returns the key-value pairs that are sent with every event, in the order they're sent.
+    pub(crate) fn standard_parameters(&self, sender: &str, event: &str) -> Vec<(&str, String)> {
+        vec![
+            ("sender", sender.to_string()),
+            ("event", event.to_string()),
+            ("version", self.os_release.version_id.to_string()),
+            ("variant", self.os_release.variant_id.clone()),
+            ("arch", self.os_release.arch.clone()),
+            ("region", self.config.region.clone()),
+            ("seed", self.config.seed.to_string()),
+            ("version_lock", self.config.version_lock.clone()),
+            ("ignore_waves", self.config.ignore_waves.to_string()),
+            ("metrics-schema-version", METRICS_SCHEMA_VERSION.to_string()),
+        ]
     }
 
     /// Sends a notification to the metrics url that boot succeeded.
@@ -110,51 +250,201 @@ impl Metricdog {
         Ok(())
     }
 
+    /// Sends the custom event `event` with the caller's key-value pairs in `values`, e.g. for a
+    /// script on the host. There may be at most `MAX_EVENT_VALUES` pairs, and their keys may not be
+    /// those of the standard parameters, which the backend relies on.
+    pub(crate) fn send_event(&self, event: &str, values: &[(String, String)]) -> Result<()> {
+        ensure!(
+            values.len() <= MAX_EVENT_VALUES,
+            error::EventValueCount {
+                count: values.len(),
+                max: MAX_EVENT_VALUES
+            }
+        );
+        let standard = self.standard_parameters("metricdog", event);
+        for (key, _) in values {
+            ensure!(
+                !standard.iter().any(|(reserved, _)| reserved == key),
+                error::EventValueReserved { key }
+            );
+        }
+        let values: HashMap<String, String> = values.iter().cloned().collect();
+        self.send("metricdog", event, Some(&values), None)
+    }
+
+    /// Sends an `update` event with the update's `status` and, if given, the `target_version`
+    /// being updated to, e.g. for updog or migrator.
+    pub(crate) fn send_update_event(
+        &self,
+        status: UpdateStatus,
+        target_version: Option<&str>,
+    ) -> Result<()> {
+        let mut values = HashMap::new();
+        values.insert(String::from("status"), status.as_str().to_string());
+        if let Some(target_version) = target_version {
+            values.insert(String::from("target_version"), target_version.to_string());
+        }
+        self.send("metricdog", "update", Some(&values), None)
+    }
+
     /// Checks the services listed in `config.service_checks` using `healthcheck`. Sends a
     /// notification to the metrics url reporting `is_healthy=true&failed_services=` if all services
     /// are healthy, or `is_healthy=false&failed_services=a:1,b:2` where `a` and `b` are the failed
-    /// services, and `1` and `2` are exit codes of the failed services.
-    pub(crate) fn send_health_ping(&self) -> Result<()> {
+    /// services, and `1` and `2` are exit codes of the failed services. At most
+    /// `url_limit::MAX_FAILED_SERVICES` are listed, and `failed_services_count` has the total.
+    /// Service names are sanitized with `service_check::sanitize_service_name`. Each failed
+    /// service's most recent journal line is hashed and sent as
+    /// `failure-signatures=a:<hash>,b:<hash>`, leaving out services whose journal can't be read;
+    /// the lines themselves are never sent. The overall
+    /// system state is sent as `system-state`, if it can be determined, and a `degraded` state is
+    /// only counted as unhealthy if `config.degraded_is_unhealthy` is set. The number of data
+    /// stores left behind by unfinished migrations is sent as `pending-migration-debris`, if the
+    /// data store directory can be read. The number of health pings in a row that found each
+    /// service unhealthy, as updated in `counts`, is sent as `consecutive-failures=a:2,b:0`. The
+    /// boot ID and the whole seconds since boot are sent as `boot_id` and `uptime_seconds`, if
+    /// they can be read.
+    pub(crate) fn send_health_ping(&self, counts: &mut FailureCounts) -> Result<()> {
+        let values = self.health_ping_values(counts)?;
+        self.send("metricdog", "health_ping", Some(&values), None)?;
+        Ok(())
+    }
+
+    /// Checks the services and the system, and returns the key-value pairs for a health ping.
+    /// The consecutive failure count of each checked service is updated in `counts`.
+    pub(crate) fn health_ping_values(
+        &self,
+        counts: &mut FailureCounts,
+    ) -> Result<HashMap<String, String>> {
         let mut is_healthy = true;
         let mut failed_services = Vec::new();
+        let mut failure_signatures = Vec::new();
         for service in &self.config.service_checks {
-            let service_status = self.healthcheck.check(service)?;
+            let service_status = match self.healthcheck.check(service) {
+                Ok(service_status) => service_status,
+                // a hung check usually means the service manager is in trouble, which is worth
+                // reporting rather than giving up on the health ping.
+                Err(e @ error::Error::CommandTimeout { .. }) => {
+                    warn!(
+                        "Checking service {} timed out, reporting it as failed: {}",
+                        service, e
+                    );
+                    ServiceHealth {
+                        is_healthy: false,
+                        exit_code: Some(service_check::TIMEOUT_EXIT_CODE),
+                    }
+                }
+                Err(e) => return Err(e),
+            };
+            counts.record(service, service_status.is_healthy);
             if !service_status.is_healthy {
                 is_healthy = false;
+                let name = service_check::sanitize_service_name(service);
                 match service_status.exit_code {
-                    None => failed_services.push(service.clone()),
-                    Some(exit_code) => {
-                        failed_services.push(format!("{}:{}", service.as_str(), exit_code))
-                    }
+                    None => failed_services.push(name.clone()),
+                    Some(exit_code) => failed_services.push(format!("{}:{}", name, exit_code)),
+                }
+                match self.healthcheck.last_journal_line(service) {
+                    Ok(Some(line)) => failure_signatures.push(format!(
+                        "{}:{}",
+                        name,
+                        service_check::failure_signature(&line)
+                    )),
+                    Ok(None) => debug!("No journal entries for failed service {}", service),
+                    Err(e) => debug!("Unable to read the journal of {}: {}", service, e),
                 }
             }
         }
+        counts.retain(&self.config.service_checks);
+        let system_state = self.healthcheck.system_state();
+        if self.config.degraded_is_unhealthy && system_state.as_deref() == Some("degraded") {
+            is_healthy = false;
+        }
         let mut values = HashMap::new();
         values.insert(String::from("is_healthy"), format!("{}", is_healthy));
+        if let Some(system_state) = system_state {
+            values.insert(String::from("system-state"), system_state);
+        }
+        match migration_debris::count_debris(&self.config.datastore_path) {
+            Ok(count) => {
+                values.insert(String::from("pending-migration-debris"), count.to_string());
+            }
+            Err(e) => debug!(
+                "Unable to check {} for migration debris: {}",
+                self.config.datastore_path.display(),
+                e
+            ),
+        }
         // consistent ordering of failed services could be helpful when viewing raw records.
         failed_services.sort();
-        values.insert(String::from("failed_services"), failed_services.join(","));
-        self.send("metricdog", "health_ping", Some(&values), None)?;
-        Ok(())
+        values.insert(
+            String::from("failed_services_count"),
+            failed_services.len().to_string(),
+        );
+        values.insert(
+            String::from("failed_services"),
+            url_limit::cap_services(&failed_services, url_limit::MAX_FAILED_SERVICES),
+        );
+        failure_signatures.sort();
+        values.insert(
+            String::from("failure-signatures"),
+            failure_signatures.join(","),
+        );
+        values.insert(String::from("consecutive-failures"), counts.to_param());
+        values.extend(boot_info::boot_values(&self.proc_path));
+        Ok(values)
     }
 
-    fn send_get_request(url: Url, timeout_sec: Option<u64>) -> Result<()> {
+    /// Sends a GET request to `url`. An HTTP request that couldn't connect or got a server error is
+    /// retried after each of `retry_delays`, and the timeout applies to each attempt.
+    fn send_get_request(&self, url: Url, timeout_sec: Option<u64>) -> Result<()> {
         debug!("sending: {}", url.as_str());
+        let timeout = Duration::from_secs(timeout_sec.unwrap_or(DEFAULT_TIMEOUT_SECONDS));
+        if url.scheme() == UNIX_SCHEME {
+            return unix_socket::send_get_request(&url, timeout);
+        }
         fix_https_proxy_env();
         let client = Client::builder()
-            .timeout(Duration::from_secs(
-                timeout_sec.unwrap_or(DEFAULT_TIMEOUT_SECONDS),
-            ))
+            .timeout(timeout)
             .build()
             .context(error::HttpClient { url: url.clone() })?;
-        let response = client
-            .get(url.clone())
-            .send()
-            .context(error::HttpSend { url: url.clone() })?;
-        response
-            .error_for_status()
-            .context(error::HttpResponse { url })?;
-        Ok(())
+        let mut retry_delays = self.retry_delays.iter();
+        loop {
+            match send_http_request(&client, &url) {
+                Err(e) if is_retryable(&e) => match retry_delays.next() {
+                    Some(delay) => {
+                        warn!("Failed to send metrics, retrying in {:?}: {}", delay, e);
+                        thread::sleep(*delay);
+                    }
+                    None => return Err(e),
+                },
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Sends one GET request to `url` with `client`, and fails if the response isn't a success.
+fn send_http_request(client: &Client, url: &Url) -> Result<()> {
+    let response = client
+        .get(url.clone())
+        .send()
+        .context(error::HttpSend { url: url.clone() })?;
+    response
+        .error_for_status()
+        .context(error::HttpResponse { url: url.clone() })?;
+    Ok(())
+}
+
+/// Returns whether a failed request may succeed if it's sent again: it couldn't connect, or the
+/// server had an error.  A request that timed out once connected isn't retried, since the
+/// endpoint is likely to be as slow again.
+fn is_retryable(e: &error::Error) -> bool {
+    match e {
+        error::Error::HttpSend { source, .. } => source.is_connect(),
+        error::Error::HttpResponse { source, .. } => source
+            .status()
+            .map_or(false, |status| status.is_server_error()),
+        _ => false,
     }
 }
 
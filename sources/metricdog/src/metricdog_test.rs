@@ -1,10 +1,20 @@
 use crate::config::Config;
-use crate::error::Result;
-use crate::metricdog::Metricdog;
+use crate::error::{self, Error, Result};
+use crate::failure_counts::FailureCounts;
+use crate::metricdog::{Metricdog, METRICS_SCHEMA_VERSION};
 use crate::service_check::{ServiceCheck, ServiceHealth};
 use bottlerocket_release::BottlerocketRelease;
 use httptest::{matchers::*, responders::*, Expectation, Server};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 use tempfile::TempDir;
+use url::Url;
 
 const OS_RELEASE: &str = r#"NAME=Bottlerocket
 ID=bottlerocket
@@ -21,10 +31,21 @@ fn os_release() -> BottlerocketRelease {
     BottlerocketRelease::from_file(&path).unwrap()
 }
 
-struct MockCheck {}
+struct MockCheck {
+    system_state: Option<&'static str>,
+}
 
 impl ServiceCheck for MockCheck {
+    // the checks of services ending in `hang` time out.
     fn check(&self, service_name: &str) -> Result<ServiceHealth> {
+        if service_name.ends_with("hang") {
+            return error::CommandTimeout {
+                command: "systemctl",
+                args: vec![String::from("is-failed"), service_name.to_string()],
+                timeout: Duration::from_secs(5),
+            }
+            .fail();
+        }
         if service_name.ends_with("fail1") {
             Ok(ServiceHealth {
                 is_healthy: false,
@@ -42,6 +63,22 @@ impl ServiceCheck for MockCheck {
             })
         }
     }
+
+    fn system_state(&self) -> Option<String> {
+        self.system_state.map(String::from)
+    }
+
+    // the journal of services ending in `fail2` can't be read.
+    fn last_journal_line(&self, service_name: &str) -> Result<Option<String>> {
+        if service_name.ends_with("fail2") {
+            return error::Journal {
+                service: service_name,
+                exit_code: 1,
+            }
+            .fail();
+        }
+        Ok(Some(format!("{} exited with status 1", service_name)))
+    }
 }
 
 #[test]
@@ -56,7 +93,9 @@ fn send_healthy_ping() {
         request::query(url_decoded(contains(("arch", "x86_64")))),
         request::query(url_decoded(contains(("region", "us-east-1")))),
         request::query(url_decoded(contains(("seed", "2041")))),
+        request::query(url_decoded(contains(("metrics-schema-version", "6")))),
         request::query(url_decoded(contains(("failed_services", "")))),
+        request::query(url_decoded(contains(("failure-signatures", "")))),
         request::query(url_decoded(contains(("is_healthy", "true")))),
     ];
     server.expect(Expectation::matching(matcher).respond_with(status_code(200)));
@@ -74,12 +113,24 @@ fn send_healthy_ping() {
             seed: 2041,
             version_lock: String::from("latest"),
             ignore_waves: false,
+            ping_sample_rate: 1.0,
+            degraded_is_unhealthy: false,
+            send_failure_window: 86400,
+            datastore_path: PathBuf::new(),
+            ping_splay_seconds: None,
+            max_url_length: 8192,
+            persist_across_boots: false,
+            service_check_timeout_seconds: 5,
         },
         os_release(),
-        Box::new(MockCheck {}),
+        Box::new(MockCheck {
+            system_state: Some("running"),
+        }),
     )
     .unwrap();
-    metricdog.send_health_ping().unwrap();
+    metricdog
+        .send_health_ping(&mut FailureCounts::default())
+        .unwrap();
 }
 
 #[test]
@@ -98,6 +149,15 @@ fn send_unhealthy_ping() {
             "failed_services",
             "service_afail2:2,service_cfail1:1"
         )))),
+        // the journal of service_afail2 can't be read, so it has no signature.
+        request::query(url_decoded(contains((
+            "failure-signatures",
+            "service_cfail1:cd5bbaaf6a85"
+        )))),
+        request::query(url_decoded(contains((
+            "consecutive-failures",
+            "service_afail2:1,service_b:0,service_cfail1:1"
+        )))),
         request::query(url_decoded(contains(("is_healthy", "false")))),
     ];
     server.expect(Expectation::matching(matcher).respond_with(status_code(200)));
@@ -117,12 +177,127 @@ fn send_unhealthy_ping() {
             seed: 2041,
             version_lock: String::from("latest"),
             ignore_waves: false,
+            ping_sample_rate: 1.0,
+            degraded_is_unhealthy: false,
+            send_failure_window: 86400,
+            datastore_path: PathBuf::new(),
+            ping_splay_seconds: None,
+            max_url_length: 8192,
+            persist_across_boots: false,
+            service_check_timeout_seconds: 5,
+        },
+        os_release(),
+        Box::new(MockCheck {
+            system_state: Some("running"),
+        }),
+    )
+    .unwrap();
+    metricdog
+        .send_health_ping(&mut FailureCounts::default())
+        .unwrap();
+}
+
+#[test]
+fn send_long_ping_truncated() {
+    let server = Server::run();
+    let matcher = all_of![
+        request::method_path("GET", "/metrics"),
+        request::query(url_decoded(contains((
+            "failed_services",
+            "service_afail1:1,+1 more"
+        )))),
+        request::query(url_decoded(contains(("failure-signatures", "")))),
+        request::query(url_decoded(contains(("is_healthy", "false")))),
+    ];
+    server.expect(Expectation::matching(matcher).respond_with(status_code(200)));
+    let port = server.addr().port();
+    let metrics_url = format!("http://localhost:{}/metrics", port);
+    let proc = fake_proc();
+    let metricdog = |max_url_length| {
+        Metricdog::from_parts(
+            Config {
+                metrics_url: metrics_url.clone(),
+                send_metrics: true,
+                service_checks: vec![
+                    String::from("service_afail1"),
+                    String::from("service_bfail1"),
+                ],
+                region: String::from("us-east-1"),
+                seed: 2041,
+                version_lock: String::from("latest"),
+                ignore_waves: false,
+                ping_sample_rate: 1.0,
+                degraded_is_unhealthy: false,
+                send_failure_window: 86400,
+                datastore_path: PathBuf::new(),
+                ping_splay_seconds: None,
+                max_url_length,
+                persist_across_boots: false,
+                service_check_timeout_seconds: 5,
+            },
+            os_release(),
+            Box::new(MockCheck { system_state: None }),
+        )
+        .unwrap()
+        // the uptime is fixed, so that both health pings have the same length.
+        .with_proc_path(proc.path())
+    };
+    // measure the URL with the signatures dropped, and allow one byte less, so that one of the
+    // services has to go too.
+    let full = metricdog(8192);
+    let mut values = full
+        .health_ping_values(&mut FailureCounts::default())
+        .unwrap();
+    values.insert(String::from("failure-signatures"), String::new());
+    let mut values: Vec<(String, String)> = values.into_iter().collect();
+    values.sort();
+    let mut url = Url::parse(&metrics_url).unwrap();
+    url.query_pairs_mut()
+        .extend_pairs(full.standard_parameters("metricdog", "health_ping"))
+        .extend_pairs(values);
+    metricdog(url.as_str().len() - 1)
+        .send_health_ping(&mut FailureCounts::default())
+        .unwrap();
+}
+
+#[test]
+fn failure_signatures_hide_journal_lines() {
+    let metricdog = Metricdog::from_parts(
+        Config {
+            metrics_url: String::from("https://example.com/metrics"),
+            send_metrics: true,
+            service_checks: vec![
+                String::from("service_cfail1"),
+                String::from("service_bfail1"),
+                String::from("service_afail2"),
+            ],
+            region: String::from("us-east-1"),
+            seed: 2041,
+            version_lock: String::from("latest"),
+            ignore_waves: false,
+            ping_sample_rate: 1.0,
+            degraded_is_unhealthy: false,
+            send_failure_window: 86400,
+            datastore_path: PathBuf::new(),
+            ping_splay_seconds: None,
+            max_url_length: 8192,
+            persist_across_boots: false,
+            service_check_timeout_seconds: 5,
         },
         os_release(),
-        Box::new(MockCheck {}),
+        Box::new(MockCheck {
+            system_state: Some("running"),
+        }),
     )
     .unwrap();
-    metricdog.send_health_ping().unwrap();
+    let values = metricdog
+        .health_ping_values(&mut FailureCounts::default())
+        .unwrap();
+    assert_eq!(
+        values["failure-signatures"],
+        "service_bfail1:5034dc97144e,service_cfail1:cd5bbaaf6a85"
+    );
+    assert!(values.values().all(|value| !value.contains("exited")));
 }
 
 #[test]
@@ -153,10 +328,788 @@ fn send_boot_success() {
             seed: 2041,
             version_lock: String::from("latest"),
             ignore_waves: false,
+            ping_sample_rate: 1.0,
+            degraded_is_unhealthy: false,
+            send_failure_window: 86400,
+            datastore_path: PathBuf::new(),
+            ping_splay_seconds: None,
+            max_url_length: 8192,
+            persist_across_boots: false,
+            service_check_timeout_seconds: 5,
         },
         os_release(),
-        Box::new(MockCheck {}),
+        Box::new(MockCheck {
+            system_state: Some("running"),
+        }),
     )
     .unwrap();
     metricdog.send_boot_success().unwrap();
 }
+
+#[test]
+fn send_migration_debris() {
+    let datastore = TempDir::new().unwrap();
+    std::fs::create_dir(datastore.path().join("v0.4.0_aaaa")).unwrap();
+    std::os::unix::fs::symlink("v0.4.0_aaaa", datastore.path().join("v0.4.0")).unwrap();
+    // two intermediate data stores from a migration that didn't finish.
+    std::fs::create_dir(datastore.path().join("v0.5.0_bbbb")).unwrap();
+    std::fs::create_dir(datastore.path().join("v0.5.0_cccc")).unwrap();
+
+    let server = Server::run();
+    let matcher = all_of![
+        request::method_path("GET", "/metrics"),
+        request::query(url_decoded(contains(("pending-migration-debris", "2")))),
+        request::query(url_decoded(contains(("is_healthy", "true")))),
+    ];
+    server.expect(Expectation::matching(matcher).respond_with(status_code(200)));
+    let metricdog = Metricdog::from_parts(
+        Config {
+            metrics_url: format!("http://localhost:{}/metrics", server.addr().port()),
+            send_metrics: true,
+            service_checks: vec![String::from("service_a")],
+            region: String::from("us-east-1"),
+            seed: 2041,
+            version_lock: String::from("latest"),
+            ignore_waves: false,
+            ping_sample_rate: 1.0,
+            degraded_is_unhealthy: false,
+            send_failure_window: 86400,
+            datastore_path: datastore.path().to_path_buf(),
+            ping_splay_seconds: None,
+            max_url_length: 8192,
+            persist_across_boots: false,
+            service_check_timeout_seconds: 5,
+        },
+        os_release(),
+        Box::new(MockCheck {
+            system_state: Some("running"),
+        }),
+    )
+    .unwrap();
+    metricdog
+        .send_health_ping(&mut FailureCounts::default())
+        .unwrap();
+}
+
+#[test]
+fn send_without_migration_debris() {
+    // the data store directory can't be read, so the parameter is left out.
+    let server = Server::run();
+    let matcher = all_of![
+        request::method_path("GET", "/metrics"),
+        request::query(url_decoded(not(contains(key("pending-migration-debris"))))),
+    ];
+    server.expect(Expectation::matching(matcher).respond_with(status_code(200)));
+    let metricdog = system_state_metricdog(server.addr().port(), Some("running"), false);
+    metricdog
+        .send_health_ping(&mut FailureCounts::default())
+        .unwrap();
+}
+
+// create a `Metricdog` with healthy services that reports `system_state` and sends to `port`.
+fn system_state_metricdog(
+    port: u16,
+    system_state: Option<&'static str>,
+    degraded_is_unhealthy: bool,
+) -> Metricdog {
+    Metricdog::from_parts(
+        Config {
+            metrics_url: format!("http://localhost:{}/metrics", port),
+            send_metrics: true,
+            service_checks: vec![String::from("service_a")],
+            region: String::from("us-east-1"),
+            seed: 2041,
+            version_lock: String::from("latest"),
+            ignore_waves: false,
+            ping_sample_rate: 1.0,
+            degraded_is_unhealthy,
+            send_failure_window: 86400,
+            datastore_path: PathBuf::new(),
+            ping_splay_seconds: None,
+            max_url_length: 8192,
+            persist_across_boots: false,
+            service_check_timeout_seconds: 5,
+        },
+        os_release(),
+        Box::new(MockCheck { system_state }),
+    )
+    .unwrap()
+}
+
+#[test]
+fn send_system_state_running() {
+    let server = Server::run();
+    let matcher = all_of![
+        request::method_path("GET", "/metrics"),
+        request::query(url_decoded(contains(("system-state", "running")))),
+        request::query(url_decoded(contains(("is_healthy", "true")))),
+    ];
+    server.expect(Expectation::matching(matcher).respond_with(status_code(200)));
+    let metricdog = system_state_metricdog(server.addr().port(), Some("running"), true);
+    metricdog
+        .send_health_ping(&mut FailureCounts::default())
+        .unwrap();
+}
+
+#[test]
+fn send_system_state_degraded() {
+    let server = Server::run();
+    let matcher = all_of![
+        request::method_path("GET", "/metrics"),
+        request::query(url_decoded(contains(("system-state", "degraded")))),
+        request::query(url_decoded(contains(("is_healthy", "true")))),
+    ];
+    server.expect(Expectation::matching(matcher).respond_with(status_code(200)));
+    let metricdog = system_state_metricdog(server.addr().port(), Some("degraded"), false);
+    metricdog
+        .send_health_ping(&mut FailureCounts::default())
+        .unwrap();
+}
+
+#[test]
+fn send_system_state_degraded_is_unhealthy() {
+    let server = Server::run();
+    let matcher = all_of![
+        request::method_path("GET", "/metrics"),
+        request::query(url_decoded(contains(("system-state", "degraded")))),
+        request::query(url_decoded(contains(("is_healthy", "false")))),
+        request::query(url_decoded(contains(("failed_services", "")))),
+    ];
+    server.expect(Expectation::matching(matcher).respond_with(status_code(200)));
+    let metricdog = system_state_metricdog(server.addr().port(), Some("degraded"), true);
+    metricdog
+        .send_health_ping(&mut FailureCounts::default())
+        .unwrap();
+}
+
+#[test]
+fn send_system_state_unknown() {
+    let server = Server::run();
+    let matcher = all_of![
+        request::method_path("GET", "/metrics"),
+        request::query(url_decoded(not(contains(key("system-state"))))),
+        request::query(url_decoded(contains(("is_healthy", "true")))),
+    ];
+    server.expect(Expectation::matching(matcher).respond_with(status_code(200)));
+    let metricdog = system_state_metricdog(server.addr().port(), None, true);
+    metricdog
+        .send_health_ping(&mut FailureCounts::default())
+        .unwrap();
+}
+
+#[test]
+fn health_ping_failed_services_capped() {
+    let mut service_checks: Vec<String> =
+        (0..12).map(|i| format!("service_{:02}fail1", i)).collect();
+    service_checks.push(String::from("service_a"));
+    service_checks.push(String::from("bad:name,fail1"));
+    let metricdog = Metricdog::from_parts(
+        Config {
+            metrics_url: String::from("https://example.com/metrics"),
+            send_metrics: true,
+            service_checks,
+            region: String::from("us-east-1"),
+            seed: 2041,
+            version_lock: String::from("latest"),
+            ignore_waves: false,
+            ping_sample_rate: 1.0,
+            degraded_is_unhealthy: false,
+            send_failure_window: 86400,
+            datastore_path: PathBuf::new(),
+            ping_splay_seconds: None,
+            max_url_length: 8192,
+            persist_across_boots: false,
+            service_check_timeout_seconds: 5,
+        },
+        os_release(),
+        Box::new(MockCheck {
+            system_state: Some("running"),
+        }),
+    )
+    .unwrap();
+    let values = metricdog
+        .health_ping_values(&mut FailureCounts::default())
+        .unwrap();
+    // the count has every failed service, though only the first ten, sorted, are listed.
+    assert_eq!(values["failed_services_count"], "13");
+    let failed_services: Vec<&str> = values["failed_services"].split(',').collect();
+    assert_eq!(failed_services.len(), 11);
+    assert_eq!(failed_services[0], "bad_name_fail1:1");
+    assert_eq!(failed_services[9], "service_08fail1:1");
+    assert_eq!(failed_services[10], "+3 more");
+    assert!(values["failure-signatures"].starts_with("bad_name_fail1:"));
+    assert!(values["consecutive-failures"].starts_with("bad_name_fail1:1,service_00fail1:1,"));
+}
+
+#[test]
+fn health_ping_failed_services_count() {
+    let metricdog = url_metricdog(String::from("https://example.com/metrics")).unwrap();
+    let values = metricdog
+        .health_ping_values(&mut FailureCounts::default())
+        .unwrap();
+    assert_eq!(values["failed_services"], "");
+    assert_eq!(values["failed_services_count"], "0");
+}
+
+#[test]
+fn health_ping_check_timeout() {
+    let metricdog = Metricdog::from_parts(
+        Config {
+            metrics_url: String::from("https://example.com/metrics"),
+            send_metrics: true,
+            service_checks: vec![String::from("service_a"), String::from("service_bhang")],
+            region: String::from("us-east-1"),
+            seed: 2041,
+            version_lock: String::from("latest"),
+            ignore_waves: false,
+            ping_sample_rate: 1.0,
+            degraded_is_unhealthy: false,
+            send_failure_window: 86400,
+            datastore_path: PathBuf::new(),
+            ping_splay_seconds: None,
+            max_url_length: 8192,
+            persist_across_boots: false,
+            service_check_timeout_seconds: 5,
+        },
+        os_release(),
+        Box::new(MockCheck {
+            system_state: Some("running"),
+        }),
+    )
+    .unwrap();
+    let mut counts = FailureCounts::default();
+    // the hung service is reported as failed with the timeout's exit code, and the ping goes on.
+    let values = metricdog.health_ping_values(&mut counts).unwrap();
+    assert_eq!(values["is_healthy"], "false");
+    assert_eq!(values["failed_services"], "service_bhang:124");
+    assert_eq!(
+        values["consecutive-failures"],
+        "service_a:0,service_bhang:1"
+    );
+}
+
+// create a `Metricdog` with healthy services that sends to `metrics_url`.
+fn url_metricdog(metrics_url: String) -> Result<Metricdog> {
+    Metricdog::from_parts(
+        Config {
+            metrics_url,
+            send_metrics: true,
+            service_checks: vec![String::from("service_a")],
+            region: String::from("us-east-1"),
+            seed: 2041,
+            version_lock: String::from("latest"),
+            ignore_waves: false,
+            ping_sample_rate: 1.0,
+            degraded_is_unhealthy: false,
+            send_failure_window: 86400,
+            datastore_path: PathBuf::new(),
+            ping_splay_seconds: None,
+            max_url_length: 8192,
+            persist_across_boots: false,
+            service_check_timeout_seconds: 5,
+        },
+        os_release(),
+        Box::new(MockCheck {
+            system_state: Some("running"),
+        }),
+    )
+}
+
+// listen on a unix socket in `tempdir`, answer one request with `status_line`, and return the
+// socket path and a handle that yields the request line that was received.
+fn unix_listener(tempdir: &TempDir, status_line: &'static str) -> (PathBuf, JoinHandle<String>) {
+    let socket = tempdir.path().join("telemetry.sock");
+    let listener = UnixListener::bind(&socket).unwrap();
+    let handle = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        // read the rest of the headers before responding.
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+            line.clear();
+        }
+        write!(
+            reader.get_mut(),
+            "{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            status_line
+        )
+        .unwrap();
+        request_line
+    });
+    (socket, handle)
+}
+
+#[test]
+fn send_over_unix_socket() {
+    let tempdir = TempDir::new().unwrap();
+    let (socket, handle) = unix_listener(&tempdir, "HTTP/1.1 200 OK");
+    let metricdog = url_metricdog(format!("unix://{}:/metrics", socket.display())).unwrap();
+    metricdog
+        .send_health_ping(&mut FailureCounts::default())
+        .unwrap();
+
+    let request_line = handle.join().unwrap();
+    let target = request_line
+        .strip_prefix("GET ")
+        .and_then(|rest| rest.strip_suffix(" HTTP/1.1\r\n"))
+        .unwrap();
+    let url = Url::parse(&format!("http://localhost{}", target)).unwrap();
+    assert_eq!(url.path(), "/metrics");
+    let query: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+    assert!(query.contains(&("event".to_string(), "health_ping".to_string())));
+    assert!(query.contains(&("is_healthy".to_string(), "true".to_string())));
+    assert!(query.contains(&("system-state".to_string(), "running".to_string())));
+}
+
+#[test]
+fn unix_socket_error_response() {
+    let tempdir = TempDir::new().unwrap();
+    let (socket, handle) = unix_listener(&tempdir, "HTTP/1.1 503 Service Unavailable");
+    let metricdog = url_metricdog(format!("unix://{}:/metrics", socket.display())).unwrap();
+    let result = metricdog.send_health_ping(&mut FailureCounts::default());
+    handle.join().unwrap();
+    assert!(matches!(result, Err(Error::UnixResponse { .. })));
+}
+
+/// Short retry delays, so that tests of retries don't wait long.
+const TEST_RETRY_DELAYS: &[Duration] = &[Duration::from_millis(10), Duration::from_millis(20)];
+
+fn retrying_metricdog(port: u16) -> Metricdog {
+    url_metricdog(format!("http://localhost:{}/metrics", port))
+        .unwrap()
+        .with_retry_delays(TEST_RETRY_DELAYS)
+}
+
+#[test]
+fn send_retries_server_error() {
+    let server = Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/metrics"))
+            .times(2)
+            .respond_with(httptest::cycle![status_code(503), status_code(200)]),
+    );
+    let metricdog = retrying_metricdog(server.addr().port());
+    metricdog.send_boot_success().unwrap();
+}
+
+#[test]
+fn send_retries_are_bounded() {
+    let server = Server::run();
+    // the first attempt and one retry for each delay.
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/metrics"))
+            .times(3)
+            .respond_with(status_code(500)),
+    );
+    let metricdog = retrying_metricdog(server.addr().port());
+    assert!(matches!(
+        metricdog.send_boot_success(),
+        Err(Error::HttpResponse { .. })
+    ));
+}
+
+#[test]
+fn send_does_not_retry_client_error() {
+    let server = Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/metrics"))
+            .times(1)
+            .respond_with(status_code(404)),
+    );
+    let metricdog = retrying_metricdog(server.addr().port());
+    assert!(matches!(
+        metricdog.send_boot_success(),
+        Err(Error::HttpResponse { .. })
+    ));
+}
+
+#[test]
+fn send_retries_connection_failure() {
+    // nothing listens on the port once the listener is dropped, so connections are refused.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    drop(listener);
+    let metricdog = retrying_metricdog(port);
+    assert!(matches!(
+        metricdog.send_boot_success(),
+        Err(Error::HttpSend { .. })
+    ));
+}
+
+#[test]
+fn metrics_url_scheme() {
+    assert!(url_metricdog(String::from("https://example.com/metrics")).is_ok());
+    assert!(url_metricdog(String::from("unix:///run/telemetry.sock:/metrics")).is_ok());
+    assert!(matches!(
+        url_metricdog(String::from("ftp://example.com/metrics")),
+        Err(Error::UrlScheme { .. })
+    ));
+    assert!(matches!(
+        url_metricdog(String::from("unix:///run/telemetry.sock")),
+        Err(Error::UnixUrl { .. })
+    ));
+}
+
+const BOOT_ID: &str = "0f5b3c4e-6d1a-4c8e-9b1f-2a7d3e4f5a6b";
+
+// write the kernel's boot ID and uptime files under a temporary proc path.
+fn fake_proc() -> TempDir {
+    let proc = TempDir::new().unwrap();
+    let boot_id_path = proc.path().join("sys/kernel/random/boot_id");
+    std::fs::create_dir_all(boot_id_path.parent().unwrap()).unwrap();
+    std::fs::write(&boot_id_path, format!("{}\n", BOOT_ID)).unwrap();
+    std::fs::write(proc.path().join("uptime"), "350735.47 234388.90\n").unwrap();
+    proc
+}
+
+/// The parameter set of each metrics schema version, oldest first.  Never edit an entry; when the
+/// parameters change, bump `METRICS_SCHEMA_VERSION` and add an entry for the new version.
+const SCHEMA_SNAPSHOTS: &[(u32, &[&str])] = &[
+    (
+        1,
+        &[
+            "arch",
+            "event",
+            "failed_services",
+            "ignore_waves",
+            "is_healthy",
+            "pending-migration-debris",
+            "region",
+            "seed",
+            "sender",
+            "system-state",
+            "variant",
+            "version",
+            "version_lock",
+        ],
+    ),
+    (
+        2,
+        &[
+            "arch",
+            "event",
+            "failed_services",
+            "ignore_waves",
+            "is_healthy",
+            "metrics-schema-version",
+            "pending-migration-debris",
+            "region",
+            "seed",
+            "sender",
+            "system-state",
+            "variant",
+            "version",
+            "version_lock",
+        ],
+    ),
+    (
+        3,
+        &[
+            "arch",
+            "event",
+            "failed_services",
+            "failure-signatures",
+            "ignore_waves",
+            "is_healthy",
+            "metrics-schema-version",
+            "pending-migration-debris",
+            "region",
+            "seed",
+            "sender",
+            "system-state",
+            "variant",
+            "version",
+            "version_lock",
+        ],
+    ),
+    (
+        4,
+        &[
+            "arch",
+            "consecutive-failures",
+            "event",
+            "failed_services",
+            "failure-signatures",
+            "ignore_waves",
+            "is_healthy",
+            "metrics-schema-version",
+            "pending-migration-debris",
+            "region",
+            "seed",
+            "sender",
+            "system-state",
+            "variant",
+            "version",
+            "version_lock",
+        ],
+    ),
+    (
+        5,
+        &[
+            "arch",
+            "boot_id",
+            "consecutive-failures",
+            "event",
+            "failed_services",
+            "failure-signatures",
+            "ignore_waves",
+            "is_healthy",
+            "metrics-schema-version",
+            "pending-migration-debris",
+            "region",
+            "seed",
+            "sender",
+            "system-state",
+            "uptime_seconds",
+            "variant",
+            "version",
+            "version_lock",
+        ],
+    ),
+    (
+        6,
+        &[
+            "arch",
+            "boot_id",
+            "consecutive-failures",
+            "event",
+            "failed_services",
+            "failed_services_count",
+            "failure-signatures",
+            "ignore_waves",
+            "is_healthy",
+            "metrics-schema-version",
+            "pending-migration-debris",
+            "region",
+            "seed",
+            "sender",
+            "system-state",
+            "uptime_seconds",
+            "variant",
+            "version",
+            "version_lock",
+        ],
+    ),
+];
+
+#[test]
+fn metrics_schema_version() {
+    // every optional parameter is sent: the system state is known, and the data store, boot ID,
+    // and uptime can be read.
+    let datastore = TempDir::new().unwrap();
+    let proc = fake_proc();
+    let metricdog = Metricdog::from_parts(
+        Config {
+            metrics_url: String::from("https://example.com/metrics"),
+            send_metrics: true,
+            service_checks: vec![String::from("service_a")],
+            region: String::from("us-east-1"),
+            seed: 2041,
+            version_lock: String::from("latest"),
+            ignore_waves: false,
+            ping_sample_rate: 1.0,
+            degraded_is_unhealthy: false,
+            send_failure_window: 86400,
+            datastore_path: datastore.path().to_path_buf(),
+            ping_splay_seconds: None,
+            max_url_length: 8192,
+            persist_across_boots: false,
+            service_check_timeout_seconds: 5,
+        },
+        os_release(),
+        Box::new(MockCheck {
+            system_state: Some("running"),
+        }),
+    )
+    .unwrap()
+    .with_proc_path(proc.path());
+    let mut parameters: Vec<String> = metricdog
+        .standard_parameters("metricdog", "health_ping")
+        .into_iter()
+        .map(|(key, _)| key.to_string())
+        .chain(
+            metricdog
+                .health_ping_values(&mut FailureCounts::default())
+                .unwrap()
+                .into_iter()
+                .map(|(key, _)| key),
+        )
+        .collect();
+    parameters.sort();
+
+    let (version, snapshot) = SCHEMA_SNAPSHOTS.last().unwrap();
+    assert_eq!(
+        *version, METRICS_SCHEMA_VERSION,
+        "add a snapshot for the current metrics schema version"
+    );
+    assert_eq!(
+        parameters,
+        snapshot.to_vec(),
+        "the parameters changed; bump METRICS_SCHEMA_VERSION and add a snapshot"
+    );
+    for pair in SCHEMA_SNAPSHOTS.windows(2) {
+        assert_eq!(pair[1].0, pair[0].0 + 1);
+        assert_ne!(pair[1].1, pair[0].1);
+    }
+}
+
+/// Matches any query string, and keeps the last one it sees so that a test can check all of it
+/// rather than only the parameters it thought to match.
+#[derive(Clone, Default)]
+struct CapturedQuery(Arc<Mutex<Option<String>>>);
+
+impl CapturedQuery {
+    /// Returns the captured query parameters by key.  Fails if no request was seen, or if a key
+    /// was sent more than once.
+    fn parameters(&self) -> BTreeMap<String, String> {
+        let query = self
+            .0
+            .lock()
+            .unwrap()
+            .clone()
+            .expect("no request was captured");
+        let pairs: Vec<(String, String)> = url::form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect();
+        let parameters: BTreeMap<String, String> = pairs.iter().cloned().collect();
+        assert_eq!(
+            parameters.len(),
+            pairs.len(),
+            "duplicate keys in '{}'",
+            query
+        );
+        parameters
+    }
+}
+
+impl Matcher<str> for CapturedQuery {
+    fn matches(&mut self, input: &str, _ctx: &mut ExecutionContext) -> bool {
+        *self.0.lock().unwrap() = Some(input.to_string());
+        true
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CapturedQuery")
+    }
+}
+
+// create a `Metricdog` for the strict tests, with one healthy and one failing service, and with
+// non-default values for the settings that are easy to forget.
+fn strict_metricdog(port: u16, datastore_path: &Path, proc_path: &Path) -> Metricdog {
+    Metricdog::from_parts(
+        Config {
+            metrics_url: format!("http://localhost:{}/metrics", port),
+            send_metrics: true,
+            service_checks: vec![String::from("service_a"), String::from("service_cfail1")],
+            region: String::from("us-east-1"),
+            seed: 2041,
+            version_lock: String::from("v1.2.3"),
+            ignore_waves: true,
+            ping_sample_rate: 1.0,
+            degraded_is_unhealthy: false,
+            send_failure_window: 86400,
+            datastore_path: datastore_path.to_path_buf(),
+            ping_splay_seconds: None,
+            max_url_length: 8192,
+            persist_across_boots: false,
+            service_check_timeout_seconds: 5,
+        },
+        os_release(),
+        Box::new(MockCheck {
+            system_state: Some("running"),
+        }),
+    )
+    .unwrap()
+    .with_proc_path(proc_path)
+}
+
+// the parameters that every event sends, given `strict_metricdog`.
+fn strict_standard_parameters(event: &str) -> Vec<(&'static str, String)> {
+    vec![
+        ("sender", "metricdog".to_string()),
+        ("event", event.to_string()),
+        ("version", "0.4.0".to_string()),
+        ("variant", "aws-k8s-1.16".to_string()),
+        ("arch", "x86_64".to_string()),
+        ("region", "us-east-1".to_string()),
+        ("seed", "2041".to_string()),
+        ("version_lock", "v1.2.3".to_string()),
+        ("ignore_waves", "true".to_string()),
+        // update this with METRICS_SCHEMA_VERSION, along with the expected parameters below.
+        ("metrics-schema-version", "6".to_string()),
+    ]
+}
+
+fn to_map(parameters: Vec<(&str, String)>) -> BTreeMap<String, String> {
+    parameters
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value))
+        .collect()
+}
+
+#[test]
+fn strict_boot_success_parameters() {
+    let server = Server::run();
+    let query = CapturedQuery::default();
+    server.expect(
+        Expectation::matching(all_of![
+            request::method_path("GET", "/metrics"),
+            request::query(query.clone()),
+        ])
+        .respond_with(status_code(200)),
+    );
+    let datastore = TempDir::new().unwrap();
+    let proc = fake_proc();
+    let metricdog = strict_metricdog(server.addr().port(), datastore.path(), proc.path());
+    metricdog.send_boot_success().unwrap();
+
+    assert_eq!(
+        query.parameters(),
+        to_map(strict_standard_parameters("boot_success"))
+    );
+}
+
+#[test]
+fn strict_health_ping_parameters() {
+    let server = Server::run();
+    let query = CapturedQuery::default();
+    server.expect(
+        Expectation::matching(all_of![
+            request::method_path("GET", "/metrics"),
+            request::query(query.clone()),
+        ])
+        .respond_with(status_code(200)),
+    );
+    let datastore = TempDir::new().unwrap();
+    let proc = fake_proc();
+    let metricdog = strict_metricdog(server.addr().port(), datastore.path(), proc.path());
+    metricdog
+        .send_health_ping(&mut FailureCounts::default())
+        .unwrap();
+
+    let mut expected = strict_standard_parameters("health_ping");
+    expected.extend(vec![
+        ("is_healthy", "false".to_string()),
+        ("system-state", "running".to_string()),
+        ("pending-migration-debris", "0".to_string()),
+        ("failed_services", "service_cfail1:1".to_string()),
+        ("failed_services_count", "1".to_string()),
+        (
+            "failure-signatures",
+            "service_cfail1:cd5bbaaf6a85".to_string(),
+        ),
+        (
+            "consecutive-failures",
+            "service_a:0,service_cfail1:1".to_string(),
+        ),
+        ("boot_id", BOOT_ID.to_string()),
+        ("uptime_seconds", "350735".to_string()),
+    ]);
+    assert_eq!(query.parameters(), to_map(expected));
+    assert_eq!(
+        METRICS_SCHEMA_VERSION, 6,
+        "update the strict tests' parameters"
+    );
+}
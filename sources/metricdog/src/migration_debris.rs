@@ -0,0 +1,118 @@
+//! Counts the intermediate data stores that a settings migration left behind, so that hosts stuck
+//! mid-update show up in health pings.
+//!
+//! migrator writes each data store it creates to a directory named like `v1.2.3_<random>` next to
+//! the data store's version links, and removes the intermediate ones once every migration succeeds.
+//! The data stores in use are the targets of the patch version links, e.g. `v1.2.3`, so any other
+//! `v1.2.3_<random>` directory is debris from a migration that didn't finish.
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The directory holding the data stores and their version links.
+pub(crate) const DEFAULT_DATASTORE_PATH: &str = "/var/lib/bottlerocket/datastore";
+
+/// Returns true if `name` looks like a data store directory created by migrator, `v1.2.3_<id>`.
+fn is_datastore_name(name: &str) -> bool {
+    let mut parts = name.splitn(2, '_');
+    let version = parts.next().unwrap_or_default();
+    let id = parts.next().unwrap_or_default();
+    if id.is_empty() || !version.starts_with('v') {
+        return false;
+    }
+    let components: Vec<&str> = version[1..].split('.').collect();
+    components.len() == 3
+        && components
+            .iter()
+            .all(|c| !c.is_empty() && c.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Counts the data store directories in `datastore_dir` that no version link points to, from a
+/// single listing of the directory.
+pub(crate) fn count_debris<P: AsRef<Path>>(datastore_dir: P) -> io::Result<usize> {
+    let mut linked: HashSet<OsString> = HashSet::new();
+    let mut datastores = Vec::new();
+    for entry in fs::read_dir(datastore_dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            // the links are relative, e.g. `v1.2.3 -> v1.2.3_abc`, so the file name is enough.
+            if let Some(target) = fs::read_link(entry.path())?.file_name() {
+                linked.insert(target.to_os_string());
+            }
+        } else if file_type.is_dir() {
+            let name = entry.file_name();
+            if name.to_str().map(is_datastore_name).unwrap_or(false) {
+                datastores.push(name);
+            }
+        }
+    }
+    Ok(datastores
+        .iter()
+        .filter(|name| !linked.contains(*name))
+        .count())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    /// Creates a data store directory that was migrated from 1.0.0 to 1.1.0.
+    fn create_datastore() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path();
+        for datastore in &["v1.0.0_aaaa", "v1.1.0_bbbb"] {
+            fs::create_dir(path.join(datastore)).unwrap();
+        }
+        for (link, target) in &[
+            ("v1.0.0", "v1.0.0_aaaa"),
+            ("v1.0", "v1.0.0"),
+            ("v1.1.0", "v1.1.0_bbbb"),
+            ("v1.1", "v1.1.0"),
+            ("v1", "v1.1"),
+            ("current", "v1"),
+        ] {
+            symlink(target, path.join(link)).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn datastore_names() {
+        assert!(is_datastore_name("v1.2.3_0123456789abcdef"));
+        assert!(!is_datastore_name("v1.2.3"));
+        assert!(!is_datastore_name("v1.2.3_"));
+        assert!(!is_datastore_name("v1.2_abc"));
+        assert!(!is_datastore_name("1.2.3_abc"));
+        assert!(!is_datastore_name("vX.2.3_abc"));
+        assert!(!is_datastore_name("current"));
+    }
+
+    #[test]
+    fn no_debris() {
+        let dir = create_datastore();
+        assert_eq!(count_debris(dir.path()).unwrap(), 0);
+    }
+
+    #[test]
+    fn debris() {
+        let dir = create_datastore();
+        fs::create_dir(dir.path().join("v1.1.0_cccc")).unwrap();
+        fs::create_dir(dir.path().join("v1.1.0_dddd")).unwrap();
+        // files and unrelated directories aren't data stores.
+        fs::write(dir.path().join("v1.1.0_eeee"), "").unwrap();
+        fs::create_dir(dir.path().join("lost+found")).unwrap();
+        assert_eq!(count_debris(dir.path()).unwrap(), 2);
+    }
+
+    #[test]
+    fn missing_datastore() {
+        let dir = TempDir::new().unwrap();
+        assert!(count_debris(dir.path().join("datastore")).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+//! Decides whether this host should send a health ping, so that very large fleets can send pings
+//! from only a fraction of their hosts, and how long to delay it, so that the hosts of a fleet
+//! don't all send their pings at the same moment.
+
+use log::info;
+use std::time::Duration;
+
+/// Returns true if a host with the given `seed` and `boot_id` falls inside the sampled fraction
+/// given by `rate`. The decision is stable for the life of a boot, and a `rate` of 1.0 or more
+/// always returns true.
+pub(crate) fn is_sampled(seed: u32, boot_id: &str, rate: f64) -> bool {
+    if rate >= 1.0 {
+        return true;
+    }
+    let hash = fnv1a(format!("{}:{}", seed, boot_id).as_bytes());
+    // map the hash onto [0.0, 1.0) and compare it to the rate.
+    (hash as f64 / (u64::MAX as f64 + 1.0)) < rate
+}
+
+/// Returns true if the health ping should be sent, logging the decision when it's skipped.
+pub(crate) fn should_send_health_ping(seed: u32, boot_id: &str, rate: f64) -> bool {
+    let sampled = is_sampled(seed, boot_id, rate);
+    if !sampled {
+        info!(
+            "Host is outside of the sampled fraction ({}) of hosts, not sending health ping",
+            rate
+        );
+    }
+    sampled
+}
+
+/// The longest splay, in seconds.  metricdog.service gives `send-health-ping` this long plus 30
+/// seconds to finish, so a longer delay would get the ping killed before it's sent.
+pub(crate) const MAX_SPLAY_SECONDS: u64 = 600;
+
+/// Returns how long a host with the given `seed` waits before sending a health ping, somewhere in
+/// [0, `splay_seconds`) with millisecond resolution, where `splay_seconds` is at most
+/// `MAX_SPLAY_SECONDS`.  The delay only depends on the seed, so each host keeps the same offset
+/// from the timer while the fleet's pings are spread across the splay.
+pub(crate) fn splay_delay(seed: u32, splay_seconds: u64) -> Duration {
+    let splay_millis = splay_seconds.min(MAX_SPLAY_SECONDS) * 1000;
+    if splay_millis == 0 {
+        return Duration::from_millis(0);
+    }
+    // hash a different input than `is_sampled`, so the delay doesn't depend on the sampling.
+    let hash = fnv1a(format!("splay:{}", seed).as_bytes());
+    Duration::from_millis(hash % splay_millis)
+}
+
+/// Seeds for update waves are less than this, as in `settings.updates.seed`.
+pub(crate) const MAX_SEED: u32 = 2048;
+
+/// Returns a seed for update waves, in the same range as `settings.updates.seed`, derived from
+/// `id`.  This stands in for the seed on hosts where it can't be read.
+pub(crate) fn derived_seed(id: &str) -> u32 {
+    (fnv1a(format!("seed:{}", id).as_bytes()) % u64::from(MAX_SEED)) as u32
+}
+
+/// The 64-bit FNV-1a hash. We use this rather than `DefaultHasher` because its output is
+/// guaranteed to be stable across Rust releases.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    bytes.iter().fold(OFFSET_BASIS, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BOOT_ID: &str = "0f5b3c4e-6d1a-4c8e-9b1f-2a7d3e4f5a6b";
+
+    #[test]
+    fn fnv1a_known_values() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn deterministic() {
+        for seed in 0..100 {
+            let first = is_sampled(seed, BOOT_ID, 0.5);
+            for _ in 0..10 {
+                assert_eq!(first, is_sampled(seed, BOOT_ID, 0.5));
+            }
+        }
+    }
+
+    #[test]
+    fn full_rate_always_sends() {
+        for seed in 0..1000 {
+            assert!(is_sampled(seed, BOOT_ID, 1.0));
+        }
+    }
+
+    #[test]
+    fn rate_is_roughly_respected() {
+        let sampled = (0..2048)
+            .filter(|&seed| is_sampled(seed, BOOT_ID, 0.25))
+            .count();
+        assert!(sampled > 256 && sampled < 768, "sampled {}", sampled);
+    }
+
+    #[test]
+    fn splay_is_deterministic() {
+        for seed in 0..100 {
+            assert_eq!(splay_delay(seed, 600), splay_delay(seed, 600));
+        }
+    }
+
+    #[test]
+    fn splay_is_in_range() {
+        for seed in 0..2048 {
+            assert!(splay_delay(seed, 30) < Duration::from_secs(30));
+        }
+    }
+
+    #[test]
+    fn splay_is_capped() {
+        for seed in 0..2048 {
+            assert!(splay_delay(seed, u64::MAX) < Duration::from_secs(MAX_SPLAY_SECONDS));
+            assert_eq!(
+                splay_delay(seed, MAX_SPLAY_SECONDS + 1),
+                splay_delay(seed, MAX_SPLAY_SECONDS)
+            );
+        }
+    }
+
+    #[test]
+    fn zero_splay_has_no_delay() {
+        for seed in 0..100 {
+            assert_eq!(splay_delay(seed, 0), Duration::from_millis(0));
+        }
+    }
+
+    #[test]
+    fn splay_spreads_hosts() {
+        // with a one-minute splay, the hosts should land in most of the ten-second buckets.
+        let mut buckets = [0; 6];
+        for seed in 0..2048 {
+            buckets[(splay_delay(seed, 60).as_secs() / 10) as usize] += 1;
+        }
+        for count in &buckets {
+            assert!(*count > 200, "buckets {:?}", buckets);
+        }
+    }
+
+    #[test]
+    fn derived_seed_is_stable_and_in_range() {
+        assert_eq!(derived_seed(BOOT_ID), derived_seed(BOOT_ID));
+        for i in 0..1000 {
+            assert!(derived_seed(&i.to_string()) < 2048);
+        }
+    }
+}
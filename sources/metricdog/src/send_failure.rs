@@ -0,0 +1,252 @@
+//! Rate limits the logging of repeated, identical failures to send health pings. When the metrics
+//! endpoint is down for hours, every cron-driven health ping fails the same way, and logging each
+//! failure as an error floods the journal.
+//!
+//! The last failure is recorded in a state file. A failure with the same signature as the recorded
+//! one, within the configured window, is logged at debug level, except for an hourly reminder at
+//! warn level. A missing or unreadable state file is treated as if there was no earlier failure.
+
+use crate::error::{self, Error, Result};
+use log::{debug, log, Level};
+use serde::{Deserialize, Serialize};
+use snafu::{OptionExt, ResultExt};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::Url;
+
+/// Where the last send failure is recorded.
+pub(crate) const DEFAULT_STATE_PATH: &str = "/var/lib/metricdog/send-failure";
+
+/// How often a repeated failure is logged at warn level while it's being suppressed.
+const REMINDER_INTERVAL_SECONDS: u64 = 60 * 60;
+
+/// The last send failure, as recorded in the state file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct FailureState {
+    /// Identifies the kind of failure and the endpoint, see `signature`.
+    signature: String,
+    /// When this failure last happened, in seconds since the epoch.
+    last_seen: u64,
+    /// When this failure was last logged at error or warn level, in seconds since the epoch.
+    last_logged: u64,
+}
+
+/// Returns a signature that identifies failures of the same kind against the same endpoint. The
+/// query is left out of the endpoint because it holds the values being sent, which can change
+/// between attempts.
+fn signature(kind: &str, url: &Url) -> String {
+    let mut endpoint = url.clone();
+    endpoint.set_query(None);
+    endpoint.set_fragment(None);
+    format!("{} {}", kind, endpoint)
+}
+
+/// Returns the signature of `err` if it's a failure to send to the metrics endpoint, or `None` for
+/// other errors, which aren't rate limited.
+fn failure_signature(err: &Error) -> Option<String> {
+    match err {
+        Error::HttpClient { url, .. } => Some(signature("client", url)),
+        Error::HttpSend { url, .. } => Some(signature("send", url)),
+        Error::HttpResponse { url, source } => {
+            let status = source
+                .status()
+                .map(|status| status.as_u16().to_string())
+                .unwrap_or_else(|| "unknown".to_string());
+            Some(signature(&format!("response {}", status), url))
+        }
+        Error::UnixSend { url, .. } => Some(signature("send", url)),
+        Error::UnixResponse { url, status } => {
+            Some(signature(&format!("response {}", status), url))
+        }
+        _ => None,
+    }
+}
+
+/// Decides the level at which to log a failure with `signature` that happened at `now`, given the
+/// `previous` failure, if any. Returns the level and the state to record.
+fn decide(
+    previous: Option<&FailureState>,
+    signature: &str,
+    now: u64,
+    window_seconds: u64,
+) -> (Level, FailureState) {
+    let mut state = FailureState {
+        signature: signature.to_string(),
+        last_seen: now,
+        last_logged: now,
+    };
+    let previous = match previous {
+        Some(previous)
+            if previous.signature == signature
+                && now.saturating_sub(previous.last_seen) <= window_seconds =>
+        {
+            previous
+        }
+        // a new kind of failure, or the first in a while.
+        _ => return (Level::Error, state),
+    };
+
+    if now.saturating_sub(previous.last_logged) >= REMINDER_INTERVAL_SECONDS {
+        (Level::Warn, state)
+    } else {
+        state.last_logged = previous.last_logged;
+        (Level::Debug, state)
+    }
+}
+
+/// Reads the state file, treating a missing or corrupt file as if there was no earlier failure.
+fn load<P: AsRef<Path>>(state_path: P) -> Option<FailureState> {
+    let state_path = state_path.as_ref();
+    let data = match fs::read_to_string(state_path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            debug!(
+                "Unable to read send failure state from {}: {}",
+                state_path.display(),
+                e
+            );
+            return None;
+        }
+    };
+    toml::from_str(&data)
+        .map_err(|e| {
+            debug!(
+                "Ignoring corrupt send failure state in {}: {}",
+                state_path.display(),
+                e
+            )
+        })
+        .ok()
+}
+
+fn save<P: AsRef<Path>>(state_path: P, state: &FailureState) -> Result<()> {
+    let state_path = state_path.as_ref();
+    let parent = state_path
+        .parent()
+        .context(error::SendFailureStateParent { path: state_path })?;
+    fs::create_dir_all(parent).context(error::SendFailureStateWrite { path: parent })?;
+    let data = toml::to_string(state).context(error::SendFailureStateSerialize)?;
+    fs::write(state_path, data).context(error::SendFailureStateWrite { path: state_path })
+}
+
+/// Logs the outcome of sending a health ping and passes it on. Failures to reach the metrics
+/// endpoint are logged at a level chosen by `decide`, so that an unreachable endpoint doesn't log
+/// an error from every run. A successful send clears the state.
+pub(crate) fn report<P: AsRef<Path>>(
+    result: Result<()>,
+    state_path: P,
+    window_seconds: u64,
+) -> Result<()> {
+    let state_path = state_path.as_ref();
+    let err = match result {
+        Ok(()) => {
+            if let Err(e) = fs::remove_file(state_path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    debug!("Unable to remove {}: {}", state_path.display(), e);
+                }
+            }
+            return Ok(());
+        }
+        Err(err) => err,
+    };
+    let signature = match failure_signature(&err) {
+        Some(signature) => signature,
+        None => return Err(err),
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    let previous = load(state_path);
+    let (level, state) = decide(previous.as_ref(), &signature, now, window_seconds);
+    log!(level, "Error while sending health ping: {}", err);
+    if let Err(e) = save(state_path, &state) {
+        debug!("Unable to record send failure state: {}", e);
+    }
+    Err(err)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+    use tempfile::TempDir;
+
+    const WINDOW: u64 = 24 * 60 * 60;
+
+    #[test]
+    fn signature_ignores_query() {
+        let a = Url::from_str("https://example.com/metrics?is_healthy=true").unwrap();
+        let b = Url::from_str("https://example.com/metrics?is_healthy=false").unwrap();
+        let c = Url::from_str("https://example.org/metrics?is_healthy=false").unwrap();
+        assert_eq!(signature("send", &a), "send https://example.com/metrics");
+        assert_eq!(signature("send", &a), signature("send", &b));
+        assert_ne!(signature("send", &a), signature("response 503", &a));
+        assert_ne!(signature("send", &b), signature("send", &c));
+    }
+
+    #[test]
+    fn first_failure_is_error() {
+        let (level, state) = decide(None, "send x", 1000, WINDOW);
+        assert_eq!(level, Level::Error);
+        assert_eq!(state.last_seen, 1000);
+        assert_eq!(state.last_logged, 1000);
+    }
+
+    #[test]
+    fn repeated_failure_is_suppressed() {
+        let (_, first) = decide(None, "send x", 1000, WINDOW);
+        let (level, second) = decide(Some(&first), "send x", 1300, WINDOW);
+        assert_eq!(level, Level::Debug);
+        assert_eq!(second.last_seen, 1300);
+        // the reminder is measured from the last time the failure was actually logged.
+        assert_eq!(second.last_logged, 1000);
+    }
+
+    #[test]
+    fn hourly_reminder() {
+        let (_, first) = decide(None, "send x", 1000, WINDOW);
+        let (_, second) = decide(Some(&first), "send x", 3000, WINDOW);
+        let (level, third) = decide(Some(&second), "send x", 4600, WINDOW);
+        assert_eq!(level, Level::Warn);
+        assert_eq!(third.last_logged, 4600);
+        let (level, _) = decide(Some(&third), "send x", 4900, WINDOW);
+        assert_eq!(level, Level::Debug);
+    }
+
+    #[test]
+    fn different_failure_is_error() {
+        let (_, first) = decide(None, "send x", 1000, WINDOW);
+        let (level, _) = decide(Some(&first), "response 503 x", 1300, WINDOW);
+        assert_eq!(level, Level::Error);
+    }
+
+    #[test]
+    fn failure_after_window_is_error() {
+        let (_, first) = decide(None, "send x", 1000, WINDOW);
+        let (level, _) = decide(Some(&first), "send x", 1001 + WINDOW, WINDOW);
+        assert_eq!(level, Level::Error);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("state").join("send-failure");
+        assert_eq!(load(&path), None);
+        let (_, state) = decide(None, "send x", 1000, WINDOW);
+        save(&path, &state).unwrap();
+        assert_eq!(load(&path), Some(state));
+    }
+
+    #[test]
+    fn corrupt_state_is_ignored() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("send-failure");
+        fs::write(&path, "not = [valid").unwrap();
+        assert_eq!(load(&path), None);
+    }
+}
@@ -1,7 +1,23 @@
 use crate::error::{self, Result};
-use log::trace;
-use snafu::ResultExt;
-use std::process::Command;
+use log::{debug, trace};
+use sha2::{Digest, Sha256};
+use snafu::{ensure, ResultExt};
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The number of hex characters of a journal line's hash that are kept in a failure signature.
+const SIGNATURE_LENGTH: usize = 12;
+
+/// How long each command that checks a service may take by default.
+pub(crate) const DEFAULT_COMMAND_TIMEOUT_SECONDS: u64 = 5;
+
+/// The exit code reported for a service whose check timed out, as the `timeout` utility exits.
+pub(crate) const TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// How often a running command is checked for having exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
 
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub(crate) struct ServiceHealth {
@@ -14,13 +30,37 @@ pub(crate) struct ServiceHealth {
 pub(crate) trait ServiceCheck {
     /// Checks the given service to see if it is healthy.
     fn check(&self, service_name: &str) -> Result<ServiceHealth>;
+
+    /// Returns the overall state of the system, e.g. `running` or `degraded`, or `None` if it can't
+    /// be determined.
+    fn system_state(&self) -> Option<String>;
+
+    /// Returns the most recent journal line of the given service, or `None` if it has no journal
+    /// entries.
+    fn last_journal_line(&self, service_name: &str) -> Result<Option<String>>;
+
+    /// Sets how long each command that the check runs may take before it's killed and fails with
+    /// `CommandTimeout`.  Checks that don't run commands ignore this.
+    fn set_command_timeout(&mut self, _timeout: Duration) {}
+}
+
+/// Checks services with `systemctl` and reads their journals with `journalctl`, killing either if
+/// it takes longer than `timeout`, e.g. because D-Bus is wedged.
+pub(crate) struct SystemdCheck {
+    timeout: Duration,
 }
 
-pub(crate) struct SystemdCheck {}
+impl Default for SystemdCheck {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(DEFAULT_COMMAND_TIMEOUT_SECONDS),
+        }
+    }
+}
 
 impl ServiceCheck for SystemdCheck {
     fn check(&self, service_name: &str) -> Result<ServiceHealth> {
-        if is_ok(service_name)? {
+        if is_ok(service_name, self.timeout)? {
             return Ok(ServiceHealth {
                 is_healthy: true,
                 exit_code: None,
@@ -28,9 +68,64 @@ impl ServiceCheck for SystemdCheck {
         }
         Ok(ServiceHealth {
             is_healthy: false,
-            exit_code: parse_service_exit_code(service_name)?,
+            exit_code: parse_service_exit_code(service_name, self.timeout)?,
         })
     }
+
+    fn system_state(&self) -> Option<String> {
+        // systemctl returns non-zero codes for states other than `running`, so we only look at
+        // stdout.
+        match systemctl(&["is-system-running"], self.timeout) {
+            Ok(outcome) => parse_system_state(&outcome.stdout),
+            Err(e) => {
+                debug!("unable to determine the system state: {}", e);
+                None
+            }
+        }
+    }
+
+    fn last_journal_line(&self, service_name: &str) -> Result<Option<String>> {
+        let args = ["-u", service_name, "-n", "1", "-o", "cat", "--no-pager"];
+        let outcome = run("journalctl", &args, self.timeout)?;
+        ensure!(
+            outcome.is_exit_true(),
+            error::Journal {
+                service: service_name,
+                exit_code: outcome.exit,
+            }
+        );
+        Ok(parse_journal_line(&outcome.stdout))
+    }
+
+    fn set_command_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+}
+
+/// Returns `service_name` with each character that isn't an ASCII letter or digit, `-`, `_`, `.`,
+/// or `@` replaced by `_`, so that it can't be confused with the separators of the values that
+/// services are reported in, like `:` and `,`.
+pub(crate) fn sanitize_service_name(service_name: &str) -> String {
+    service_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '@') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Returns the signature of a failure whose most recent journal line is `line`: the first
+/// characters of the line's SHA-256 hash, in hex.  Identical failures have identical signatures,
+/// without revealing what the line says.
+pub(crate) fn failure_signature(line: &str) -> String {
+    let digest = Sha256::digest(line.as_bytes());
+    let mut hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    hex.truncate(SIGNATURE_LENGTH);
+    hex
 }
 
 struct Outcome {
@@ -44,41 +139,82 @@ impl Outcome {
     }
 }
 
-fn systemctl(args: &[&str]) -> Result<Outcome> {
-    trace!("calling systemctl with '{:?}'", args);
-    let output = Command::new("systemctl")
+/// Runs `command` with `args` and returns its exit code and stdout.  If it hasn't exited within
+/// `timeout`, it's killed and a `CommandTimeout` error is returned.
+fn run(command: &str, args: &[&str], timeout: Duration) -> Result<Outcome> {
+    trace!("calling {} with '{:?}'", command, args);
+    let command_error = || error::Command {
+        command,
+        args: args.iter().map(|&s| s.to_owned()).collect::<Vec<String>>(),
+    };
+    let mut child = Command::new(command)
         .args(args)
-        .output()
-        .with_context(|| error::Command {
-            command: "systemctl",
-            args: args.iter().map(|&s| s.to_owned()).collect::<Vec<String>>(),
-        })?;
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(command_error)?;
+
+    // stdout is read on its own thread, so that a command with a lot of output doesn't block on a
+    // full pipe while we wait for it to exit.
+    let mut stdout = child.stdout.take();
+    let reader = thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(stdout) = stdout.as_mut() {
+            // whatever was read before an error is kept.
+            let _ = stdout.read_to_end(&mut buf);
+        }
+        buf
+    });
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait().with_context(command_error)? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            // the command may exit on its own before it's killed, which is fine.
+            let _ = child.kill();
+            let _ = child.wait();
+            return error::CommandTimeout {
+                command,
+                args: args.iter().map(|&s| s.to_owned()).collect::<Vec<String>>(),
+                timeout,
+            }
+            .fail();
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+    let stdout = reader.join().unwrap_or_default();
     Ok(Outcome {
-        exit: output.status.code().unwrap_or(-1),
-        stdout: String::from_utf8_lossy(output.stdout.as_slice()).into(),
+        exit: status.code().unwrap_or(-1),
+        stdout: String::from_utf8_lossy(&stdout).into(),
     })
 }
 
-fn is_active(service: &str) -> Result<bool> {
-    let outcome = systemctl(&["is-active", service])?;
+fn systemctl(args: &[&str], timeout: Duration) -> Result<Outcome> {
+    run("systemctl", args, timeout)
+}
+
+fn is_active(service: &str, timeout: Duration) -> Result<bool> {
+    let outcome = systemctl(&["is-active", service], timeout)?;
     Ok(outcome.is_exit_true())
 }
 
-fn is_failed(service: &str) -> Result<bool> {
-    let outcome = systemctl(&["is-failed", service])?;
+fn is_failed(service: &str, timeout: Duration) -> Result<bool> {
+    let outcome = systemctl(&["is-failed", service], timeout)?;
     Ok(outcome.is_exit_true())
 }
 
-fn is_ok(service: &str) -> Result<bool> {
-    Ok(!is_failed(service)? && is_active(service)?)
+fn is_ok(service: &str, timeout: Duration) -> Result<bool> {
+    Ok(!is_failed(service, timeout)? && is_active(service, timeout)?)
 }
 
 const STATUS_PROPERTY: &str = "ExecMainStatus";
 
-fn parse_service_exit_code(service: &str) -> Result<Option<i32>> {
+fn parse_service_exit_code(service: &str, timeout: Duration) -> Result<Option<i32>> {
     // we don't check the command's exit code because systemctl returns non-zero codes for various
     // non-exceptional execution outcomes.
-    let outcome = systemctl(&["show", "--property", STATUS_PROPERTY, service])?;
+    let outcome = systemctl(&["show", "--property", STATUS_PROPERTY, service], timeout)?;
     Ok(parse_stdout(&outcome.stdout))
 }
 
@@ -105,6 +241,66 @@ fn parse_stdout(stdout: &str) -> Option<i32> {
         .and_then(|exit_code| exit_code.trim_end().parse::<i32>().ok())
 }
 
+fn parse_journal_line(stdout: &str) -> Option<String> {
+    // with `-n 1` there's at most one line; it's trimmed so a trailing newline or carriage return
+    // doesn't change the signature.
+    let line = stdout.lines().last()?.trim();
+    if line.is_empty() {
+        return None;
+    }
+    Some(line.to_owned())
+}
+
+fn parse_system_state(stdout: &str) -> Option<String> {
+    // we expect the response to be a single word like this: degraded\n
+    let state = stdout.trim();
+    if state.is_empty() || state.contains(char::is_whitespace) {
+        return None;
+    }
+    Some(state.to_owned())
+}
+
+#[test]
+fn failure_signature_hash() {
+    // the first 12 hex characters of `printf 'kubelet failed to start' | sha256sum`
+    assert_eq!(failure_signature("kubelet failed to start"), "52b1f54384a5");
+    assert_eq!(failure_signature(""), "e3b0c44298fc");
+    assert_eq!(failure_signature("x").len(), SIGNATURE_LENGTH);
+}
+
+#[test]
+fn sanitize_service_names() {
+    assert_eq!(sanitize_service_name("kubelet"), "kubelet");
+    assert_eq!(
+        sanitize_service_name("getty@tty1.service"),
+        "getty@tty1.service"
+    );
+    assert_eq!(sanitize_service_name("host-ctr_1"), "host-ctr_1");
+    assert_eq!(sanitize_service_name("a:b,c d=é"), "a_b_c_d__");
+}
+
+#[test]
+fn parse_journal_line_trims() {
+    assert_eq!(
+        parse_journal_line("Failed to run kubelet\n").as_deref(),
+        Some("Failed to run kubelet")
+    );
+    assert!(parse_journal_line("").is_none());
+    assert!(parse_journal_line("\n").is_none());
+}
+
+#[test]
+fn parse_system_state_degraded() {
+    let got = parse_system_state("degraded\n").unwrap();
+    assert_eq!(got, "degraded");
+}
+
+#[test]
+fn parse_system_state_malformed() {
+    assert!(parse_system_state("").is_none());
+    assert!(parse_system_state("Failed to connect to bus\n").is_none());
+}
+
 #[test]
 fn parse_stdout_exit_0() {
     let got = parse_stdout(format!("{}=0", STATUS_PROPERTY).as_str()).unwrap();
@@ -162,3 +358,26 @@ fn parse_stdout_property_and_equals_only() {
     let got = parse_stdout(format!("{}=", STATUS_PROPERTY).as_str());
     assert!(got.is_none());
 }
+
+#[test]
+fn run_output() {
+    let outcome = run(
+        "sh",
+        &["-c", "echo running; exit 3"],
+        Duration::from_secs(5),
+    )
+    .unwrap();
+    assert_eq!(outcome.exit, 3);
+    assert_eq!(outcome.stdout, "running\n");
+}
+
+#[test]
+fn run_timeout() {
+    let start = Instant::now();
+    let result = run("sleep", &["300"], Duration::from_millis(200));
+    assert!(start.elapsed() < Duration::from_secs(10));
+    match &result {
+        Err(error::Error::CommandTimeout { command, .. }) => assert_eq!(command, "sleep"),
+        _ => panic!("expected CommandTimeout, got {:?}", result.as_ref().err()),
+    }
+}
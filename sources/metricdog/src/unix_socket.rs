@@ -0,0 +1,120 @@
+//! Sends metrics to a collector listening on a Unix domain socket, for hosts that run a local
+//! telemetry agent and don't allow direct egress.
+//!
+//! The metrics URL names both the socket and the request path, separated by a colon, e.g.
+//! `unix:///run/telemetry.sock:/metrics`. Query parameters are added to the URL just like they are
+//! for `http` and `https` URLs, and the request is written as a minimal HTTP/1.1 GET.
+
+use crate::error::{self, Result};
+use log::debug;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use url::Url;
+
+/// The URL scheme for collectors listening on a Unix domain socket.
+pub(crate) const UNIX_SCHEME: &str = "unix";
+
+/// Where to send requests for a `unix://` metrics URL.
+#[derive(Debug, PartialEq)]
+pub(crate) struct UnixEndpoint {
+    /// The path to the socket, e.g. `/run/telemetry.sock`.
+    pub(crate) socket: PathBuf,
+    /// The HTTP request path, e.g. `/metrics`.
+    pub(crate) request_path: String,
+}
+
+impl UnixEndpoint {
+    /// Splits the path of a `unix://` URL into the socket path and the HTTP request path.
+    pub(crate) fn from_url(url: &Url) -> Result<Self> {
+        let path = url.path();
+        let separator = path
+            .find(":/")
+            .context(error::UnixUrl { url: url.clone() })?;
+        let (socket, request_path) = (&path[..separator], &path[separator + 1..]);
+        ensure!(!socket.is_empty(), error::UnixUrl { url: url.clone() });
+        Ok(Self {
+            socket: PathBuf::from(socket),
+            request_path: request_path.to_string(),
+        })
+    }
+}
+
+/// Sends a GET request for `url`, a `unix://` URL, and checks that the response status is a
+/// success.
+pub(crate) fn send_get_request(url: &Url, timeout: Duration) -> Result<()> {
+    let endpoint = UnixEndpoint::from_url(url)?;
+    let mut request_target = endpoint.request_path;
+    if let Some(query) = url.query() {
+        request_target.push('?');
+        request_target.push_str(query);
+    }
+    debug!(
+        "sending GET {} over {}",
+        request_target,
+        endpoint.socket.display()
+    );
+
+    let status_line = exchange(&endpoint.socket, &request_target, timeout)
+        .context(error::UnixSend { url: url.clone() })?;
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok());
+    ensure!(
+        matches!(status, Some(200..=299)),
+        error::UnixResponse {
+            url: url.clone(),
+            status: status_line.trim_end().to_string(),
+        }
+    );
+    Ok(())
+}
+
+/// Writes the request to the socket and returns the status line of the response.
+fn exchange(socket: &Path, request_target: &str, timeout: Duration) -> std::io::Result<String> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nUser-Agent: metricdog\r\nConnection: close\r\n\r\n",
+        request_target
+    )?;
+    stream.flush()?;
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+    Ok(status_line)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn endpoint_from_url() {
+        let url = Url::from_str("unix:///run/telemetry.sock:/metrics?a=b").unwrap();
+        assert_eq!(
+            UnixEndpoint::from_url(&url).unwrap(),
+            UnixEndpoint {
+                socket: PathBuf::from("/run/telemetry.sock"),
+                request_path: String::from("/metrics"),
+            }
+        );
+    }
+
+    #[test]
+    fn endpoint_without_request_path() {
+        let url = Url::from_str("unix:///run/telemetry.sock").unwrap();
+        assert!(UnixEndpoint::from_url(&url).is_err());
+    }
+
+    #[test]
+    fn endpoint_without_socket() {
+        let url = Url::from_str("unix::/metrics").unwrap();
+        assert!(UnixEndpoint::from_url(&url).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+//! Keeps health pings under the URL length that collectors accept.  `failed_services` never lists
+//! more than `MAX_FAILED_SERVICES` services; the rest are counted by a `+K more` marker, e.g.
+//! `a:1,b:2,+3 more`, and `failed_services_count` has the total.  A host with many failed services
+//! can still build a query string longer than some collectors allow, around 8KB, and the request
+//! would fail, so the values are cut down until the URL fits:
+//!
+//! 1. `failure-signatures` is emptied, since the signatures only help group failures.
+//! 2. `failed_services` is cut to its first entries, followed by a `+K more` marker that counts all
+//!    of the services that were dropped, including those counted by an earlier marker.
+//!
+//! The keys are always kept, so the set of parameters doesn't change.
+
+use std::collections::HashMap;
+
+/// The default limit on the length of the URL of a request, in bytes.
+pub(crate) const DEFAULT_MAX_URL_LENGTH: usize = 8192;
+
+/// The most failed services that a health ping lists.
+pub(crate) const MAX_FAILED_SERVICES: usize = 10;
+
+const SIGNATURES_KEY: &str = "failure-signatures";
+const FAILED_SERVICES_KEY: &str = "failed_services";
+
+/// Returns `services` in the form they're sent in `failed_services`, with any past the first `max`
+/// counted by a `+K more` marker.
+pub(crate) fn cap_services(services: &[String], max: usize) -> String {
+    if services.len() <= max {
+        return services.join(",");
+    }
+    let mut kept = services[..max].to_vec();
+    kept.push(more_marker(services.len() - max));
+    kept.join(",")
+}
+
+fn more_marker(dropped: usize) -> String {
+    format!("+{} more", dropped)
+}
+
+/// Returns the number of services counted by `entry` if it's a `+K more` marker.
+fn parse_more_marker(entry: &str) -> Option<usize> {
+    entry.strip_prefix('+')?.strip_suffix(" more")?.parse().ok()
+}
+
+/// What was dropped from the values to make a request fit.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct Truncation {
+    /// Whether the failure signatures were dropped.
+    pub(crate) dropped_signatures: bool,
+    /// How many failed services were dropped.
+    pub(crate) dropped_services: usize,
+    /// Whether the request fits after truncation; if not, it's sent as short as it can be.
+    pub(crate) fits: bool,
+}
+
+/// Returns `values`, cut down according to the policy in the module docs until `fits` accepts
+/// them, and what was dropped.
+pub(crate) fn truncate<F>(
+    values: &HashMap<String, String>,
+    fits: F,
+) -> (HashMap<String, String>, Truncation)
+where
+    F: Fn(&HashMap<String, String>) -> bool,
+{
+    let mut values = values.clone();
+    let mut truncation = Truncation::default();
+    if fits(&values) {
+        truncation.fits = true;
+        return (values, truncation);
+    }
+
+    if let Some(signatures) = values.get_mut(SIGNATURES_KEY) {
+        if !signatures.is_empty() {
+            signatures.clear();
+            truncation.dropped_signatures = true;
+            if fits(&values) {
+                truncation.fits = true;
+                return (values, truncation);
+            }
+        }
+    }
+
+    let mut services: Vec<String> = match values.get(FAILED_SERVICES_KEY) {
+        Some(services) if !services.is_empty() => services.split(',').map(String::from).collect(),
+        _ => return (values, truncation),
+    };
+    let already_dropped = match services.last().and_then(|last| parse_more_marker(last)) {
+        Some(count) => {
+            services.pop();
+            count
+        }
+        None => 0,
+    };
+    for keep in (0..services.len()).rev() {
+        let dropped = services.len() - keep;
+        let mut kept = services[..keep].to_vec();
+        kept.push(more_marker(dropped + already_dropped));
+        values.insert(FAILED_SERVICES_KEY.to_string(), kept.join(","));
+        truncation.dropped_services = dropped;
+        if fits(&values) {
+            truncation.fits = true;
+            break;
+        }
+    }
+    (values, truncation)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn values(services: &str, signatures: &str) -> HashMap<String, String> {
+        let mut values = HashMap::new();
+        values.insert("is_healthy".to_string(), "false".to_string());
+        values.insert(FAILED_SERVICES_KEY.to_string(), services.to_string());
+        values.insert(SIGNATURES_KEY.to_string(), signatures.to_string());
+        values
+    }
+
+    /// Accepts values whose total length is at most `max`.
+    fn shorter_than(max: usize) -> impl Fn(&HashMap<String, String>) -> bool {
+        move |values| values.iter().map(|(k, v)| k.len() + v.len()).sum::<usize>() <= max
+    }
+
+    #[test]
+    fn fits_unchanged() {
+        let original = values("a:1,b:2", "a:52b1f54384a5");
+        let (truncated, truncation) = truncate(&original, |_| true);
+        assert_eq!(truncated, original);
+        assert_eq!(
+            truncation,
+            Truncation {
+                dropped_signatures: false,
+                dropped_services: 0,
+                fits: true,
+            }
+        );
+    }
+
+    #[test]
+    fn signatures_dropped_first() {
+        let original = values("a:1,b:2", "a:52b1f54384a5,b:ce631641636e");
+        // everything but the signatures fits.
+        let max = "is_healthyfalse".len() + "failed_servicesa:1,b:2".len() + SIGNATURES_KEY.len();
+        let (truncated, truncation) = truncate(&original, shorter_than(max));
+        assert_eq!(truncated, values("a:1,b:2", ""));
+        assert!(truncation.dropped_signatures);
+        assert_eq!(truncation.dropped_services, 0);
+        assert!(truncation.fits);
+    }
+
+    #[test]
+    fn services_truncated_with_marker() {
+        let original = values("a:1,b:2,c:3,d:4", "a:52b1f54384a5");
+        let max =
+            "is_healthyfalse".len() + "failed_servicesa:1,+3 more".len() + SIGNATURES_KEY.len();
+        let (truncated, truncation) = truncate(&original, shorter_than(max));
+        assert_eq!(truncated, values("a:1,+3 more", ""));
+        assert_eq!(
+            truncation,
+            Truncation {
+                dropped_signatures: true,
+                dropped_services: 3,
+                fits: true,
+            }
+        );
+    }
+
+    #[test]
+    fn nothing_fits() {
+        let original = values("a:1,b:2", "");
+        let (truncated, truncation) = truncate(&original, |_| false);
+        assert_eq!(truncated, values("+2 more", ""));
+        assert_eq!(
+            truncation,
+            Truncation {
+                dropped_signatures: false,
+                dropped_services: 2,
+                fits: false,
+            }
+        );
+    }
+
+    #[test]
+    fn no_failed_services() {
+        let original = values("", "");
+        let (truncated, truncation) = truncate(&original, |_| false);
+        assert_eq!(truncated, original);
+        assert_eq!(truncation, Truncation::default());
+    }
+
+    fn services(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("s{}:1", i)).collect()
+    }
+
+    #[test]
+    fn capped_services() {
+        assert_eq!(cap_services(&[], MAX_FAILED_SERVICES), "");
+        assert_eq!(cap_services(&services(2), 2), "s0:1,s1:1");
+        // the boundary: one more than the cap is one too many.
+        assert_eq!(cap_services(&services(3), 2), "s0:1,s1:1,+1 more");
+        assert_eq!(
+            cap_services(&services(MAX_FAILED_SERVICES), MAX_FAILED_SERVICES)
+                .split(',')
+                .count(),
+            MAX_FAILED_SERVICES
+        );
+        let capped = cap_services(&services(25), MAX_FAILED_SERVICES);
+        assert_eq!(capped.split(',').count(), MAX_FAILED_SERVICES + 1);
+        assert!(capped.ends_with(",s9:1,+15 more"));
+    }
+
+    #[test]
+    fn capped_services_truncated() {
+        // the marker from the cap is folded into the one from truncation.
+        let original = values(&cap_services(&services(5), 3), "");
+        let max =
+            "is_healthyfalse".len() + "failed_servicess0:1,+4 more".len() + SIGNATURES_KEY.len();
+        let (truncated, truncation) = truncate(&original, shorter_than(max));
+        assert_eq!(truncated, values("s0:1,+4 more", ""));
+        assert_eq!(truncation.dropped_services, 2);
+        assert!(truncation.fits);
+    }
+
+    #[test]
+    fn more_markers() {
+        assert_eq!(parse_more_marker(&more_marker(12)), Some(12));
+        assert_eq!(parse_more_marker("a:1"), None);
+        assert_eq!(parse_more_marker("+x more"), None);
+    }
+}